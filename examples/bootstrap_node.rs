@@ -21,8 +21,6 @@
 extern crate log;
 #[macro_use]
 extern crate unwrap;
-#[macro_use]
-extern crate serde_derive;
 
 mod common;
 use bincode;
@@ -30,21 +28,21 @@ use bytes::Bytes;
 use common::Rpc;
 use crossbeam_channel as mpmc;
 use env_logger;
-use quic_p2p::{Builder, Config, Event, Peer};
+use quic_p2p::{Builder, ConfigArgs, Event, Peer};
 use serde_json;
 use std::collections::HashMap;
 use std::io;
 use structopt::StructOpt;
 
 /// Configuration for the bootstrap node
-#[derive(Serialize, Deserialize, StructOpt)]
+#[derive(StructOpt)]
 pub struct BootstrapNodeConfig {
     /// A number of expected connections.
     /// Once this number is reached, we'll send a list of all connections to every connected peer.
     #[structopt(short, long)]
     expected_conns: usize,
     #[structopt(flatten)]
-    quic_p2p_opts: Config,
+    quic_p2p_opts: ConfigArgs,
 }
 
 fn main() -> Result<(), io::Error> {
@@ -57,7 +55,7 @@ fn main() -> Result<(), io::Error> {
     let (ev_tx, ev_rx) = mpmc::unbounded();
 
     let mut qp2p = unwrap!(Builder::new(ev_tx)
-        .with_config(bootstrap_node_config.quic_p2p_opts)
+        .with_config(unwrap!(bootstrap_node_config.quic_p2p_opts.into_config()))
         .build());
 
     let our_conn_info = unwrap!(qp2p.our_connection_info());
@@ -74,7 +72,7 @@ fn main() -> Result<(), io::Error> {
 
     for event in ev_rx.iter() {
         match event {
-            Event::ConnectedTo { peer } => {
+            Event::ConnectedTo { peer, .. } => {
                 let peer_addr = match &peer {
                     Peer::Node { node_info } => node_info.peer_addr,
                     Peer::Client { .. } => panic!("In this example only Node peers are expected"),
@@ -11,7 +11,7 @@
 
 use bytes::Bytes;
 use crossbeam_channel as mpmc;
-use quic_p2p::{Builder, Config, Event, Peer, QuicP2p};
+use quic_p2p::{Builder, ConfigArgs, Event, Peer, QuicP2p};
 use rand::{self, RngCore};
 use rustyline::config::Configurer;
 use rustyline::error::ReadlineError;
@@ -67,14 +67,15 @@ impl PeerList {
 #[derive(Debug, StructOpt)]
 struct CliArgs {
     #[structopt(flatten)]
-    quic_p2p_opts: Config,
+    quic_p2p_opts: ConfigArgs,
 }
 
 fn main() {
     let CliArgs { quic_p2p_opts } = CliArgs::from_args();
     let (ev_tx, ev_rx) = mpmc::unbounded();
 
-    let mut qp2p = unwrap!(Builder::new(ev_tx).with_config(quic_p2p_opts).build());
+    let cfg = unwrap!(quic_p2p_opts.into_config());
+    let mut qp2p = unwrap!(Builder::new(ev_tx).with_config(cfg).build());
 
     print_logo();
     println!("Type 'help' to get started.");
@@ -183,8 +184,8 @@ fn handle_qp2p_events(
     thread::spawn(move || {
         for event in event_rx.iter() {
             match event {
-                Event::ConnectedTo { peer } => unwrap!(peer_list.lock()).insert(peer),
-                Event::NewMessage { peer_addr, msg } => {
+                Event::ConnectedTo { peer, .. } => unwrap!(peer_list.lock()).insert(peer),
+                Event::NewMessage { peer_addr, msg, .. } => {
                     if msg.len() > 512 {
                         println!("[{}] received bytes: {}", peer_addr, msg.len());
                     } else {
@@ -28,7 +28,7 @@ use common::Rpc;
 use crc::crc32;
 use crossbeam_channel as mpmc;
 use env_logger;
-use quic_p2p::{Builder, Config, Event, NodeInfo, Peer, QuicP2p};
+use quic_p2p::{Builder, Config, ConfigArgs, Event, NodeInfo, Peer, QuicP2p};
 use rand::{self, seq::IteratorRandom, RngCore};
 use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
@@ -40,7 +40,7 @@ use structopt::StructOpt;
 #[derive(Debug, StructOpt)]
 struct CliArgs {
     #[structopt(flatten)]
-    quic_p2p_opts: Config,
+    quic_p2p_opts: ConfigArgs,
 }
 
 struct ClientNode {
@@ -68,7 +68,8 @@ fn main() {
     let config = CliArgs::from_args();
     println!("{:?}", config);
 
-    match ClientNode::new(config.quic_p2p_opts) {
+    let cfg = unwrap!(config.quic_p2p_opts.into_config());
+    match ClientNode::new(cfg) {
         Ok(mut c) => c.run(),
         Err(e) => eprintln!("{}", e),
     }
@@ -125,8 +126,8 @@ impl ClientNode {
     fn poll_qp2p_events(&mut self) {
         while let Ok(event) = self.event_rx.recv() {
             match event {
-                Event::ConnectedTo { peer } => self.on_connect(peer),
-                Event::NewMessage { peer_addr, msg } => self.on_msg_receive(peer_addr, msg),
+                Event::ConnectedTo { peer, .. } => self.on_connect(peer),
+                Event::NewMessage { peer_addr, msg, .. } => self.on_msg_receive(peer_addr, msg),
                 event => warn!("Unexpected event: {:?}", event),
             }
         }
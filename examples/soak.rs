@@ -0,0 +1,281 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Soak test running a mesh of peers within this process, each sending messages to the others at
+//! random for as long as asked. Throughput, latency and connection churn are printed every report
+//! interval. The run fails as soon as a send stays without outcome for too long, or connections
+//! pile up beyond one per pair of peers.
+//!
+//! Usage:
+//! ```
+//! $ cargo run --release --example soak -- --peers 8 --duration 7200 --churn-interval 30
+//! ```
+
+#[macro_use]
+extern crate unwrap;
+
+use bytes::Bytes;
+use crossbeam_channel as mpmc;
+use quic_p2p::{Builder, Config, Event, NodeInfo, QuicP2p, Token};
+use rand::Rng;
+use std::collections::HashMap;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct CliArgs {
+    /// Number of peers in the mesh
+    #[structopt(long = "peers", default_value = "5")]
+    peers: usize,
+    /// How long to run for, in seconds
+    #[structopt(long = "duration", default_value = "3600")]
+    duration: u64,
+    /// Messages each peer sends per second
+    #[structopt(long = "rate", default_value = "10")]
+    rate: u32,
+    /// Size of the messages, in bytes
+    #[structopt(long = "msg-size", default_value = "1024")]
+    msg_size: usize,
+    /// How often to print the stats, in seconds
+    #[structopt(long = "report-interval", default_value = "10")]
+    report_interval: u64,
+    /// How often to drop a connection at random, in seconds. Never if 0
+    #[structopt(long = "churn-interval", default_value = "0")]
+    churn_interval: u64,
+    /// How long a send may go without being reported sent or unsent, in seconds
+    #[structopt(long = "stuck-after", default_value = "60")]
+    stuck_after: u64,
+}
+
+struct SoakPeer {
+    qp2p: QuicP2p,
+    info: NodeInfo,
+    ev_rx: mpmc::Receiver<Event>,
+}
+
+impl SoakPeer {
+    fn new() -> Self {
+        let (ev_tx, ev_rx) = mpmc::unbounded();
+        let mut qp2p = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_sent_events(true)
+            .build());
+        let info = unwrap!(qp2p.our_connection_info());
+
+        Self { qp2p, info, ev_rx }
+    }
+}
+
+/// What happened since the last report, and what is still outstanding.
+#[derive(Default)]
+struct Tally {
+    sent: u64,
+    unsent: u64,
+    received: u64,
+    bytes_received: u64,
+    latencies: Vec<Duration>,
+    connects: u64,
+    disconnects: u64,
+    /// Sends without an outcome yet, by token.
+    outstanding: HashMap<Token, Instant>,
+}
+
+fn main() {
+    let args = CliArgs::from_args();
+    if args.peers < 2 || args.rate == 0 || args.report_interval == 0 {
+        eprintln!("At least 2 peers, a rate and a report interval are needed");
+        process::exit(2);
+    }
+
+    let mut peers: Vec<SoakPeer> = (0..args.peers).map(|_| SoakPeer::new()).collect();
+    println!(
+        "Soaking {} peers for {}s, each sending {} msgs/s of {} bytes",
+        args.peers, args.duration, args.rate, args.msg_size
+    );
+
+    let started = Instant::now();
+    let tick = Duration::from_secs(1) / args.rate;
+    let report_interval = Duration::from_secs(args.report_interval);
+    let churn_interval = Duration::from_secs(args.churn_interval);
+    let stuck_after = Duration::from_secs(args.stuck_after);
+    let mut rng = rand::thread_rng();
+    let mut tally = Tally::default();
+    let mut next_token: Token = 0;
+    let mut last_report = started;
+    let mut last_churn = started;
+
+    while started.elapsed() < Duration::from_secs(args.duration) {
+        let next_tick = Instant::now() + tick;
+        for from in 0..peers.len() {
+            let to = (from + rng.gen_range(1, peers.len())) % peers.len();
+            let msg = new_msg(started.elapsed(), args.msg_size);
+            let to_info = peers[to].info.clone();
+            peers[from]
+                .qp2p
+                .send_with_token(to_info.into(), msg, next_token);
+            let _ = tally.outstanding.insert(next_token, Instant::now());
+            next_token += 1;
+        }
+
+        if args.churn_interval > 0 && last_churn.elapsed() >= churn_interval {
+            last_churn = Instant::now();
+            let (from, to) = (rng.gen_range(0, peers.len()), rng.gen_range(0, peers.len()));
+            if from != to {
+                let peer_addr = peers[to].info.peer_addr;
+                peers[from].qp2p.disconnect_from(peer_addr);
+            }
+        }
+
+        if last_report.elapsed() >= report_interval {
+            let window = last_report.elapsed();
+            last_report = Instant::now();
+            report(&mut tally, &mut peers, started.elapsed(), window);
+            if let Err(failure) = check(&tally, &mut peers, stuck_after) {
+                eprintln!("FAILED after {}s: {}", started.elapsed().as_secs(), failure);
+                process::exit(1);
+            }
+        }
+
+        // Taking the events in as they come, for their latencies to be measured
+        while Instant::now() < next_tick {
+            for peer in &peers {
+                for event in peer.ev_rx.try_iter() {
+                    record(&mut tally, event, started.elapsed());
+                }
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    println!("Soak test passed after {}s", started.elapsed().as_secs());
+}
+
+/// Message stamped with the time it was sent at since the start, padded to `size`.
+fn new_msg(sent_at: Duration, size: usize) -> Bytes {
+    let nanos = sent_at.as_secs() * 1_000_000_000 + u64::from(sent_at.subsec_nanos());
+    let mut msg = nanos.to_be_bytes().to_vec();
+    msg.resize(size.max(msg.len()), 0);
+    Bytes::from(msg)
+}
+
+fn sent_at(msg: &[u8]) -> Option<Duration> {
+    let mut nanos = [0; 8];
+    nanos.copy_from_slice(msg.get(..8)?);
+    Some(Duration::from_nanos(u64::from_be_bytes(nanos)))
+}
+
+fn record(tally: &mut Tally, event: Event, now: Duration) {
+    match event {
+        Event::SentUserMessage { token, .. } => {
+            tally.sent += 1;
+            let _ = tally.outstanding.remove(&token);
+        }
+        Event::UnsentUserMessage { token, .. } => {
+            tally.unsent += 1;
+            let _ = tally.outstanding.remove(&token);
+        }
+        Event::NewMessage { msg, .. } => {
+            tally.received += 1;
+            tally.bytes_received += msg.len() as u64;
+            if let Some(sent_at) = sent_at(&msg) {
+                tally
+                    .latencies
+                    .push(now.checked_sub(sent_at).unwrap_or_default());
+            }
+        }
+        Event::ConnectedTo { .. } => tally.connects += 1,
+        Event::ConnectionFailure { .. } | Event::ConnectionClosed { .. } => tally.disconnects += 1,
+        _ => (),
+    }
+}
+
+/// Print the stats of the window just ended and start a new one.
+fn report(tally: &mut Tally, peers: &mut [SoakPeer], elapsed: Duration, window: Duration) {
+    let secs = window.as_secs_f64();
+    tally.latencies.sort();
+    let percentile = |p: usize| {
+        if tally.latencies.is_empty() {
+            Duration::from_secs(0)
+        } else {
+            tally.latencies[(tally.latencies.len() - 1) * p / 100]
+        }
+    };
+    let connections: usize = peers
+        .iter_mut()
+        .map(|peer| unwrap!(peer.qp2p.dump_state()).connections.len())
+        .sum();
+
+    println!(
+        "[{:>6}s] {:>8.1} msgs/s {:>8.1} KiB/s | latency p50 {:?} p99 {:?} max {:?} | \
+         {} sent {} unsent {} outstanding | {} connections, {} up {} down",
+        elapsed.as_secs(),
+        tally.received as f64 / secs,
+        tally.bytes_received as f64 / 1024.0 / secs,
+        percentile(50),
+        percentile(99),
+        percentile(100),
+        tally.sent,
+        tally.unsent,
+        tally.outstanding.len(),
+        connections,
+        tally.connects,
+        tally.disconnects,
+    );
+
+    let outstanding = mem::take(&mut tally.outstanding);
+    *tally = Tally {
+        outstanding,
+        ..Default::default()
+    };
+}
+
+/// Look for stuck sends and leaked connections.
+fn check(tally: &Tally, peers: &mut [SoakPeer], stuck_after: Duration) -> Result<(), String> {
+    if let Some(sent_at) = tally.outstanding.values().min() {
+        if sent_at.elapsed() > stuck_after {
+            return Err(format!(
+                "a send went without outcome for {}s",
+                sent_at.elapsed().as_secs()
+            ));
+        }
+    }
+
+    let peer_addrs: Vec<SocketAddr> = peers.iter().map(|peer| peer.info.peer_addr).collect();
+    for peer in peers.iter_mut() {
+        let state = unwrap!(peer.qp2p.dump_state());
+        // A connection each way with every other peer at most, which share an entry
+        if state.connections.len() >= peer_addrs.len() {
+            return Err(format!(
+                "{} holds {} connections in a mesh of {} peers",
+                peer.info.peer_addr,
+                state.connections.len(),
+                peer_addrs.len()
+            ));
+        }
+        if let Some(conn) = state
+            .connections
+            .iter()
+            .find(|conn| !peer_addrs.contains(&conn.peer_addr))
+        {
+            return Err(format!(
+                "{} holds a connection with stranger {}",
+                peer.info.peer_addr, conn.peer_addr
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! This example prints the wire test vectors of all encodings enabled in this build, or verifies
+//! the ones in a fixture file.
+//!
+//! Usage:
+//! ```
+//! $ cargo run --all-features --example wire_test_vectors > tests/fixtures/wire_test_vectors.txt
+//! $ cargo run --features test-utils --example wire_test_vectors -- --verify tests/fixtures/wire_test_vectors.txt
+//! ```
+
+#[macro_use]
+extern crate unwrap;
+
+use quic_p2p::test_vectors;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct CliArgs {
+    /// Verify the test vectors in this fixture file instead of printing ours
+    #[structopt(long = "verify", parse(from_os_str))]
+    verify: Option<PathBuf>,
+}
+
+fn main() {
+    let args = CliArgs::from_args();
+
+    let path = match args.verify {
+        Some(path) => path,
+        None => {
+            print!("{}", test_vectors::to_fixture(&test_vectors::generate()));
+            return;
+        }
+    };
+
+    let fixture = unwrap!(fs::read_to_string(&path));
+    let vectors = match test_vectors::from_fixture(&fixture) {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            process::exit(2);
+        }
+    };
+
+    match test_vectors::verify(&vectors) {
+        Ok(()) => println!("{} test vectors verified", vectors.len()),
+        Err(failures) => {
+            for failure in &failures {
+                eprintln!("{}", failure);
+            }
+            eprintln!(
+                "{} of {} test vectors failed",
+                failures.len(),
+                vectors.len()
+            );
+            process::exit(1);
+        }
+    }
+}
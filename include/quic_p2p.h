@@ -0,0 +1,116 @@
+/*
+ * Copyright 2019 MaidSafe.net limited.
+ *
+ * This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+ * http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+ * https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+ * modified, or distributed except according to those terms. Please review the Licences for the
+ * specific language governing permissions and limitations relating to use of the SAFE Network
+ * Software.
+ */
+
+/*
+ * C ABI of quic-p2p, offered by builds with the `ffi` feature. See the documentation of the
+ * `ffi` module for the conventions the functions follow.
+ */
+
+#ifndef QUIC_P2P_H
+#define QUIC_P2P_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define QUIC_P2P_OK 0
+#define QUIC_P2P_ERR (-1)
+
+typedef struct QuicP2pHandle QuicP2pHandle;
+
+typedef struct FfiBuffer {
+    uint8_t *data;
+    size_t len;
+} FfiBuffer;
+
+typedef enum FfiEventKind {
+    FFI_EVENT_BOOTSTRAP_FAILURE = 1,
+    FFI_EVENT_BOOTSTRAPPED_TO = 2,
+    FFI_EVENT_CONNECTION_FAILURE = 3,
+    FFI_EVENT_UNSENT_USER_MESSAGE = 4,
+    FFI_EVENT_CONNECTION_CLOSED = 5,
+    FFI_EVENT_CONNECTING = 6,
+    FFI_EVENT_HANDSHAKE_COMPLETED = 7,
+    FFI_EVENT_PEER_HEARTBEAT = 8,
+    FFI_EVENT_CONNECTED_TO = 9,
+    FFI_EVENT_NEW_MESSAGE = 10,
+    FFI_EVENT_EVENTS_DROPPED = 11,
+    FFI_EVENT_FINISH = 12,
+    FFI_EVENT_FORWARDED_MESSAGE = 13,
+    FFI_EVENT_QUORUM_REACHED = 14,
+    FFI_EVENT_QUORUM_FAILED = 15,
+    FFI_EVENT_DUPLICATE_CONNECTION_DROPPED = 16,
+    FFI_EVENT_PEER_ADDRESS_CHANGED = 17,
+    FFI_EVENT_CONNECTION_MIGRATED = 18,
+    FFI_EVENT_MIGRATION_FAILED = 19,
+    FFI_EVENT_REACHABLE = 20,
+    FFI_EVENT_UNREACHABLE = 21,
+    FFI_EVENT_SENT_USER_MESSAGE = 22,
+    FFI_EVENT_SENT_FILE = 23,
+    FFI_EVENT_UNSENT_FILE = 24,
+    FFI_EVENT_INCOMING_FILE = 25,
+    FFI_EVENT_SEND_PROGRESS = 26,
+    FFI_EVENT_RECEIVE_PROGRESS = 27,
+    FFI_EVENT_TOPIC_MESSAGE = 28,
+    FFI_EVENT_GOSSIP_MESSAGE = 29,
+    FFI_EVENT_PEER_SLOW = 30,
+    FFI_EVENT_PEER_RESUMED = 31,
+    FFI_EVENT_PEER_RTT = 32,
+    FFI_EVENT_SENDS_QUEUED = 33,
+    FFI_EVENT_QUEUED_SENDS_FLUSHED = 34,
+    FFI_EVENT_INCOMING_FILE_CANCELLED = 35,
+    FFI_EVENT_CONFIG_FILE_CHANGED = 36,
+    FFI_EVENT_CERTIFICATE_EXPIRING = 37,
+    FFI_EVENT_CERTIFICATE_RENEWED = 38,
+    FFI_EVENT_RECONNECTING = 39,
+    FFI_EVENT_RECONNECTED = 40
+} FfiEventKind;
+
+typedef struct FfiEvent {
+    FfiEventKind kind;
+    FfiBuffer peer_addr;
+    FfiBuffer peer_cert_der;
+    FfiBuffer payload;
+    uint64_t value;
+    FfiBuffer relay_addr;
+    FfiBuffer topic;
+} FfiEvent;
+
+QuicP2pHandle *quic_p2p_new(const uint8_t *cfg_json, size_t cfg_json_len);
+void quic_p2p_free(QuicP2pHandle *handle);
+
+int32_t quic_p2p_bootstrap(QuicP2pHandle *handle);
+int32_t quic_p2p_connect_to(QuicP2pHandle *handle, const char *peer_addr,
+                            const uint8_t *peer_cert_der, size_t peer_cert_der_len);
+int32_t quic_p2p_disconnect_from(QuicP2pHandle *handle, const char *peer_addr);
+int32_t quic_p2p_send(QuicP2pHandle *handle, const char *peer_addr,
+                      const uint8_t *peer_cert_der, size_t peer_cert_der_len,
+                      const uint8_t *msg, size_t msg_len);
+int32_t quic_p2p_forward(QuicP2pHandle *handle, const char *relay_addr, const char *peer_addr,
+                         const uint8_t *msg, size_t msg_len);
+int32_t quic_p2p_our_connection_info(QuicP2pHandle *handle, FfiBuffer *out_addr,
+                                     FfiBuffer *out_cert_der);
+
+/* Returns 1 if an event was written, 0 on timeout and QUIC_P2P_ERR on failure. */
+int32_t quic_p2p_poll_event(QuicP2pHandle *handle, uint64_t timeout_ms, FfiEvent *out_event);
+void quic_p2p_event_free(FfiEvent *event);
+void quic_p2p_buffer_free(FfiBuffer *buf);
+
+const char *quic_p2p_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* QUIC_P2P_H */
@@ -0,0 +1,180 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Acknowledgments of the user messages handed to the application of the peer, see
+//! `Builder::with_app_acks`.
+//!
+//! Each user message is sent as a `WireMsg::AckedUserMsg` with an ID of ours, which the peer
+//! sends back in a `WireMsg::UserMsgAck` once it fired `Event::NewMessage` for it. Only then is
+//! the message taken as delivered. Resends are given new IDs, and the acknowledgments of earlier
+//! sends of a message are no longer awaited once it is resent.
+
+use crate::context::ctx_mut;
+use crate::wire_msg::WireMsg;
+use std::net::SocketAddr;
+
+/// User messages written to their peer, awaiting its acknowledgment.
+#[derive(Default)]
+pub struct Acks {
+    enabled: bool,
+    next_id: u64,
+    awaited: Vec<Awaited>,
+}
+
+struct Awaited {
+    peer_addr: SocketAddr,
+    id: u64,
+    msg: bytes::Bytes,
+    /// Set once written to the peer in full.
+    written: bool,
+}
+
+impl Acks {
+    /// Ask for acknowledgments of the user messages sent from now on.
+    pub fn enable(&mut self, enable: bool) {
+        self.enabled = enable;
+    }
+
+    /// The user message, as one the peer is to acknowledge if enabled.
+    pub fn ask(&mut self, peer_addr: SocketAddr, wire_msg: WireMsg) -> WireMsg {
+        let msg = match wire_msg {
+            WireMsg::UserMsg(msg) if self.enabled => msg,
+            wire_msg => return wire_msg,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.awaited.push(Awaited {
+            peer_addr,
+            id,
+            msg: msg.clone(),
+            written: false,
+        });
+        WireMsg::AckedUserMsg { id, msg }
+    }
+
+    /// The user message resent, like `ask`, no longer awaiting the acknowledgment of the send
+    /// which failed.
+    pub fn ask_again(&mut self, peer_addr: SocketAddr, wire_msg: WireMsg) -> WireMsg {
+        if let Some(msg) = wire_msg.user_msg() {
+            let failed = self.awaited.iter().position(|awaited| {
+                awaited.peer_addr == peer_addr && awaited.msg == *msg && awaited.written
+            });
+            if let Some(i) = failed {
+                let _ = self.awaited.remove(i);
+            }
+        }
+        self.ask(peer_addr, wire_msg)
+    }
+
+    /// Whether an acknowledgment of the user message to the peer is awaited.
+    pub fn is_awaited(&self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> bool {
+        self.awaited
+            .iter()
+            .any(|awaited| awaited.peer_addr == peer_addr && awaited.msg == *msg)
+    }
+
+    /// Take note of the user message written to the peer in full, returning whether its
+    /// acknowledgment is awaited.
+    pub fn written(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> bool {
+        let awaited = self.awaited.iter_mut().find(|awaited| {
+            awaited.peer_addr == peer_addr && awaited.msg == *msg && !awaited.written
+        });
+        match awaited {
+            Some(awaited) => {
+                awaited.written = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The message the peer acknowledged, unless it was no longer awaited.
+    pub fn acked(&mut self, peer_addr: SocketAddr, id: u64) -> Option<bytes::Bytes> {
+        let i = self
+            .awaited
+            .iter()
+            .position(|awaited| awaited.peer_addr == peer_addr && awaited.id == id)?;
+        Some(self.awaited.remove(i).msg)
+    }
+
+    /// Stop awaiting acknowledgments of the message to the peer.
+    pub fn resolve(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) {
+        self.awaited
+            .retain(|awaited| awaited.peer_addr != peer_addr || awaited.msg != *msg);
+    }
+
+    /// Stop awaiting acknowledgments from the peer, returning the messages written to it which
+    /// weren't acknowledged. Those not written yet are reported once writing them fails.
+    pub fn fail_peer(&mut self, peer_addr: SocketAddr) -> Vec<bytes::Bytes> {
+        let mut failed = Vec::new();
+        let mut i = 0;
+        while i < self.awaited.len() {
+            if self.awaited[i].peer_addr == peer_addr {
+                let awaited = self.awaited.remove(i);
+                if awaited.written {
+                    failed.push(awaited.msg);
+                }
+            } else {
+                i += 1;
+            }
+        }
+        failed
+    }
+}
+
+/// The user message, as one the peer is to acknowledge if enabled.
+pub fn ask(peer_addr: SocketAddr, wire_msg: WireMsg) -> WireMsg {
+    ctx_mut(|c| c.acks.ask(peer_addr, wire_msg))
+}
+
+/// The user message resent, see `Acks::ask_again`.
+pub fn ask_again(peer_addr: SocketAddr, wire_msg: WireMsg) -> WireMsg {
+    ctx_mut(|c| c.acks.ask_again(peer_addr, wire_msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acks_are_awaited_until_they_arrive_or_the_peer_fails() {
+        let peer_addr = ([203, 0, 113, 1], 1000).into();
+        let other_peer = ([203, 0, 113, 2], 1000).into();
+        let msg = bytes::Bytes::from(&b"msg"[..]);
+        let mut acks = Acks::default();
+
+        match acks.ask(peer_addr, WireMsg::UserMsg(msg.clone())) {
+            WireMsg::UserMsg(_) => (),
+            wire_msg => panic!("Unexpected wire message: {:?}", wire_msg),
+        }
+        assert!(!acks.is_awaited(peer_addr, &msg));
+
+        acks.enable(true);
+        let id = match acks.ask(peer_addr, WireMsg::UserMsg(msg.clone())) {
+            WireMsg::AckedUserMsg { id, .. } => id,
+            wire_msg => panic!("Unexpected wire message: {:?}", wire_msg),
+        };
+        assert!(acks.is_awaited(peer_addr, &msg));
+        assert!(acks.written(peer_addr, &msg));
+        assert!(!acks.written(peer_addr, &msg));
+        assert_eq!(acks.acked(other_peer, id), None);
+        assert_eq!(acks.acked(peer_addr, id), Some(msg.clone()));
+        assert_eq!(acks.acked(peer_addr, id), None);
+        assert!(!acks.is_awaited(peer_addr, &msg));
+
+        // Only those written are failed, the others fail writing
+        let _ = acks.ask(peer_addr, WireMsg::UserMsg(msg.clone()));
+        let _ = acks.ask(peer_addr, WireMsg::UserMsg(msg.clone()));
+        let _ = acks.ask(other_peer, WireMsg::UserMsg(msg.clone()));
+        assert!(acks.written(peer_addr, &msg));
+        assert_eq!(acks.fail_peer(peer_addr), vec![msg.clone()]);
+        assert!(!acks.is_awaited(peer_addr, &msg));
+        assert!(acks.is_awaited(other_peer, &msg));
+    }
+}
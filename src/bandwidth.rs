@@ -0,0 +1,245 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Rates of the bytes exchanged with each peer over sliding windows, see `QuicP2p::peer_stats`.
+//!
+//! The byte counts of every connection are sampled each second. A rate is what was exchanged
+//! since the latest sample at least the window old, divided by the time since that sample, so it
+//! covers up to a sampling interval more than the window. Samples are kept every second for the
+//! last minute and every minute for the last 15 minutes.
+
+use crate::context::ctx_mut;
+use crate::stats::ConnectionStats;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const MINUTE: Duration = Duration::from_secs(60);
+const QUARTER_HOUR: Duration = Duration::from_secs(15 * 60);
+
+/// Bytes per second exchanged with a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRates {
+    /// Bytes per second written to the peer.
+    pub sent: u64,
+    /// Bytes per second read from the peer.
+    pub received: u64,
+}
+
+/// Rates of the bytes exchanged with a peer over the last second, minute and 15 minutes, or over
+/// the lifetime of the connection if it's younger.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bandwidth {
+    /// Rates over the last second.
+    pub last_second: ByteRates,
+    /// Rates over the last minute.
+    pub last_minute: ByteRates,
+    /// Rates over the last 15 minutes.
+    pub last_15_minutes: ByteRates,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    sent: u64,
+    received: u64,
+}
+
+/// Samples taken at least `period` apart, going `span` back.
+#[derive(Debug)]
+struct Window {
+    period: Duration,
+    span: Duration,
+    samples: VecDeque<Sample>,
+}
+
+impl Window {
+    fn new(period: Duration, span: Duration) -> Self {
+        Self {
+            period,
+            span,
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, sample: Sample) {
+        // Ticks can come in a bit early, which shouldn't make us skip a sample
+        let is_due = self.samples.back().map_or(true, |last| {
+            sample.at.duration_since(last.at) + SAMPLE_INTERVAL / 2 >= self.period
+        });
+        if is_due {
+            self.samples.push_back(sample);
+        }
+        // Keep the latest sample at least the span old, the baseline of the widest rate
+        while self.samples.len() >= 2 && self.samples[1].at + self.span <= sample.at {
+            let _ = self.samples.pop_front();
+        }
+    }
+
+    fn rates(&self, current: Sample, window: Duration) -> ByteRates {
+        let baseline = self
+            .samples
+            .iter()
+            .rev()
+            .find(|sample| sample.at + window <= current.at)
+            .or_else(|| self.samples.front());
+        let baseline = match baseline {
+            Some(baseline) => baseline,
+            None => return ByteRates::default(),
+        };
+        let elapsed = current.at.duration_since(baseline.at).as_nanos();
+        if elapsed == 0 {
+            return ByteRates::default();
+        }
+        // Byte counts shrink as superseded connections are forgotten
+        let per_sec = |bytes: u64| (u128::from(bytes) * 1_000_000_000 / elapsed) as u64;
+        ByteRates {
+            sent: per_sec(current.sent.saturating_sub(baseline.sent)),
+            received: per_sec(current.received.saturating_sub(baseline.received)),
+        }
+    }
+}
+
+/// Samples of the byte counts of a connection.
+#[derive(Debug)]
+pub struct BandwidthMeter {
+    seconds: Window,
+    minutes: Window,
+}
+
+impl Default for BandwidthMeter {
+    fn default() -> Self {
+        Self {
+            seconds: Window::new(SAMPLE_INTERVAL, MINUTE),
+            minutes: Window::new(MINUTE, QUARTER_HOUR),
+        }
+    }
+}
+
+impl BandwidthMeter {
+    /// Take note of the byte counts of the connection as of `now`.
+    pub fn sample(&mut self, now: Instant, stats: &ConnectionStats) {
+        let sample = Sample {
+            at: now,
+            sent: stats.bytes_sent,
+            received: stats.bytes_received,
+        };
+        self.seconds.record(sample);
+        self.minutes.record(sample);
+    }
+
+    /// Rates up to `now`, at which the connection had the given byte counts.
+    pub fn bandwidth(&self, now: Instant, stats: &ConnectionStats) -> Bandwidth {
+        let current = Sample {
+            at: now,
+            sent: stats.bytes_sent,
+            received: stats.bytes_received,
+        };
+        Bandwidth {
+            last_second: self.seconds.rates(current, SAMPLE_INTERVAL),
+            last_minute: self.seconds.rates(current, MINUTE),
+            last_15_minutes: self.minutes.rates(current, QUARTER_HOUR),
+        }
+    }
+}
+
+/// Sample the byte counts of every connection each second.
+pub fn start_sampling() {
+    let leaf = Interval::new(Instant::now() + SAMPLE_INTERVAL, SAMPLE_INTERVAL)
+        .map_err(|e| info!("Error in bandwidth sampling interval: {:?}", e))
+        .for_each(|_| {
+            ctx_mut(|c| {
+                let now = c.clock.now();
+                for conn in c.connections.values_mut() {
+                    let stats = conn.stats();
+                    conn.bandwidth.sample(now, &stats);
+                }
+            });
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(sent: u64, received: u64) -> ConnectionStats {
+        ConnectionStats {
+            bytes_sent: sent,
+            bytes_received: received,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rates_cover_their_window() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut meter = BandwidthMeter::default();
+        assert_eq!(meter.bandwidth(at(0), &bytes(10, 10)), Bandwidth::default());
+
+        // 1000 B/s sent for a minute, then 5000 B/s for 10 seconds, receiving 100 B/s throughout
+        for sec in 0..=60 {
+            meter.sample(at(sec), &bytes(sec * 1_000, sec * 100));
+        }
+        for sec in 61..=70 {
+            meter.sample(at(sec), &bytes(60_000 + (sec - 60) * 5_000, sec * 100));
+        }
+
+        let bandwidth = meter.bandwidth(at(70), &bytes(110_000, 7_000));
+        assert_eq!(
+            bandwidth.last_second,
+            ByteRates {
+                sent: 5_000,
+                received: 100,
+            }
+        );
+        // 50 seconds at 1000 B/s and 10 at 5000 B/s
+        assert_eq!(
+            bandwidth.last_minute,
+            ByteRates {
+                sent: 1_666,
+                received: 100,
+            }
+        );
+        // Younger than 15 minutes, so over the whole lifetime
+        assert_eq!(
+            bandwidth.last_15_minutes,
+            ByteRates {
+                sent: 1_571,
+                received: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn old_samples_are_dropped() {
+        let start = Instant::now();
+        let mut meter = BandwidthMeter::default();
+        for sec in 0..=20 * 60 {
+            meter.sample(start + Duration::from_secs(sec), &bytes(sec, 0));
+        }
+
+        assert_eq!(meter.seconds.samples.len(), 61);
+        assert_eq!(meter.minutes.samples.len(), 16);
+        let now = start + Duration::from_secs(20 * 60);
+        assert_eq!(
+            meter
+                .bandwidth(now, &bytes(20 * 60, 0))
+                .last_15_minutes
+                .sent,
+            1
+        );
+    }
+}
@@ -7,36 +7,245 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::config::BootstrapStrategy;
 use crate::connect;
 use crate::connection::BootstrapGroupMaker;
-use crate::context::ctx;
+use crate::context::ctx_mut;
+use crate::error::Error;
+use crate::wire_msg::PROTOCOL_VERSION_REFUSED_CODE;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Where a contact attempted during bootstrap came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactSource {
+    /// The contact was read from the bootstrap cache.
+    Cache,
+    /// The contact is one of the `hard_coded_contacts` in the config.
+    HardCoded,
+    /// The contact is one of the `bootstrap_only_contacts` in the config, so its connection is
+    /// closed once we bootstrapped off it.
+    BootstrapOnly,
+}
 
-pub fn start() {
-    let (proxies, event_tx): (Vec<_>, _) = ctx(|c| {
-        (
-            c.bootstrap_cache
+/// How a bootstrap attempt to a contact ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    /// We bootstrapped off this contact.
+    Succeeded,
+    /// Connecting to this contact failed.
+    Failed(ContactFailure),
+    /// The attempt was abandoned because we bootstrapped off another contact.
+    Cancelled,
+}
+
+/// Why connecting to a contact failed during bootstrap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContactFailure {
+    /// The contact didn't answer in time, if at all.
+    TimedOut,
+    /// The contact refused or closed the connection, e.g. with too many peers of our subnet
+    /// connected to it already.
+    Refused,
+    /// The contact didn't present the certificate we have for it.
+    CertificateMismatch,
+    /// The contact belongs to another network: it refused our version of the protocol or we
+    /// refused its.
+    WrongNetwork,
+    /// Connecting failed with the given error.
+    Other(String),
+}
+
+impl ContactFailure {
+    /// Classify the error an attempt to connect to a contact failed with.
+    pub(crate) fn from_err(e: &Error) -> Self {
+        match *e.root_cause() {
+            Error::ProtocolVersionRefused(_) => ContactFailure::WrongNetwork,
+            Error::TLS(rustls::TLSError::WebPKIError(_)) => ContactFailure::CertificateMismatch,
+            Error::Connection(ref e) => match *e {
+                quinn::ConnectionError::TimedOut => ContactFailure::TimedOut,
+                quinn::ConnectionError::VersionMismatch => ContactFailure::WrongNetwork,
+                quinn::ConnectionError::ApplicationClosed { ref reason }
+                    if reason.error_code == PROTOCOL_VERSION_REFUSED_CODE =>
+                {
+                    ContactFailure::WrongNetwork
+                }
+                // rustls describes certificates failing verification so
+                quinn::ConnectionError::TransportError(ref e)
+                    if e.reason.starts_with("invalid peer certificate") =>
+                {
+                    ContactFailure::CertificateMismatch
+                }
+                quinn::ConnectionError::ApplicationClosed { .. }
+                | quinn::ConnectionError::ConnectionClosed { .. }
+                | quinn::ConnectionError::Reset => ContactFailure::Refused,
+                ref e => ContactFailure::Other(e.to_string()),
+            },
+            Error::Io(ref e) => match e.kind() {
+                io::ErrorKind::TimedOut => ContactFailure::TimedOut,
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => {
+                    ContactFailure::Refused
+                }
+                _ => ContactFailure::Other(e.to_string()),
+            },
+            ref e => ContactFailure::Other(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ContactFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContactFailure::TimedOut => write!(f, "timed out"),
+            ContactFailure::Refused => write!(f, "refused"),
+            ContactFailure::CertificateMismatch => write!(f, "certificate mismatch"),
+            ContactFailure::WrongNetwork => write!(f, "wrong network"),
+            ContactFailure::Other(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// A single contact attempted during bootstrap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapAttempt {
+    /// Address of the contact.
+    pub peer_addr: SocketAddr,
+    /// Where the contact came from.
+    pub source: ContactSource,
+    /// How long ago a cached contact was added to or refreshed in the cache. `None` for
+    /// hard-coded contacts and for cached ones of unknown age, e.g. read from the cache file.
+    pub cache_age: Option<Duration>,
+    /// How the attempt ended.
+    pub outcome: AttemptOutcome,
+    /// Time from starting the attempt until it ended.
+    pub duration: Duration,
+}
+
+/// Report of a complete bootstrap run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapReport {
+    /// Every contact attempted, in the order the attempts were started.
+    pub attempts: Vec<BootstrapAttempt>,
+    /// Time from starting the bootstrap until all the attempts ended.
+    pub duration: Duration,
+}
+
+impl BootstrapReport {
+    /// Whether we bootstrapped off any of the contacts.
+    pub fn is_successful(&self) -> bool {
+        self.attempts
+            .iter()
+            .any(|attempt| attempt.outcome == AttemptOutcome::Succeeded)
+    }
+
+    /// Why connecting to each of the contacts failed, for those that did.
+    pub fn failures(&self) -> HashMap<SocketAddr, ContactFailure> {
+        self.attempts
+            .iter()
+            .filter_map(|attempt| match attempt.outcome {
+                AttemptOutcome::Failed(ref failure) => Some((attempt.peer_addr, failure.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Start bootstrapping. If `report_tx` is given, a `BootstrapReport` is sent through it once all
+/// the attempts have ended.
+pub fn start(report_tx: Option<mpsc::Sender<BootstrapReport>>) {
+    let (proxies, event_tx, stats) = ctx_mut(|c| {
+        let cache = &c.bootstrap_cache;
+        let cached = if c.bootstrap_strategy == BootstrapStrategy::FreshNetwork {
+            Vec::new()
+        } else {
+            cache
                 .peers()
                 .iter()
                 .rev()
-                .chain(c.bootstrap_cache.hard_coded_contacts().iter())
-                .cloned()
-                .collect(),
+                .map(|peer| {
+                    let age = cache.age_of(&peer.peer_addr);
+                    (peer.clone(), (ContactSource::Cache, age))
+                })
+                .collect()
+        };
+        // Held in sets, so taken in random order
+        let mut hard_coded: Vec<_> = cache
+            .hard_coded_contacts()
+            .iter()
+            .map(|peer| (peer.clone(), (ContactSource::HardCoded, None)))
+            .collect();
+        let mut bootstrap_only: Vec<_> = cache
+            .bootstrap_only_contacts()
+            .iter()
+            .map(|peer| (peer.clone(), (ContactSource::BootstrapOnly, None)))
+            .collect();
+        c.rng.shuffle(&mut hard_coded, |(peer, _)| peer.peer_addr);
+        c.rng
+            .shuffle(&mut bootstrap_only, |(peer, _)| peer.peer_addr);
+        let configured = hard_coded.into_iter().chain(bootstrap_only).collect();
+        (
+            order_sources(c.bootstrap_strategy, cached, configured),
             c.event_tx.clone(),
+            c.bootstrap_stats.clone(),
         )
     });
 
-    let maker = BootstrapGroupMaker::new(event_tx);
-    for proxy in proxies {
-        let _ = connect::connect_to(proxy, None, Some(&maker));
+    let mut contacts = HashMap::with_capacity(proxies.len());
+    for (proxy, contact) in &proxies {
+        let _ = contacts.entry(proxy.peer_addr).or_insert(*contact);
+    }
+
+    let maker = BootstrapGroupMaker::new(event_tx, contacts, stats, report_tx);
+    for (proxy, _) in proxies {
+        let peer_addr = proxy.peer_addr;
+        if let Err(e) = connect::connect_to(proxy, None, Some(&maker)) {
+            let e = Error::Bootstrap {
+                peer_addr,
+                source: Box::new(e),
+            };
+            debug!("{}", e);
+            maker.record_failure(peer_addr, &e);
+        }
+    }
+}
+
+/// The cached peers and the contacts of the config in the order the strategy tries them.
+fn order_sources<T>(strategy: BootstrapStrategy, cached: Vec<T>, configured: Vec<T>) -> Vec<T> {
+    match strategy {
+        BootstrapStrategy::CacheFirst => cached.into_iter().chain(configured).collect(),
+        BootstrapStrategy::HardCodedFirst => configured.into_iter().chain(cached).collect(),
+        BootstrapStrategy::FreshNetwork => configured,
+        BootstrapStrategy::Interleaved => {
+            let mut ordered = Vec::with_capacity(cached.len() + configured.len());
+            let (mut cached, mut configured) = (cached.into_iter(), configured.into_iter());
+            loop {
+                let (peer, contact) = (cached.next(), configured.next());
+                if peer.is_none() && contact.is_none() {
+                    break;
+                }
+                ordered.extend(peer);
+                ordered.extend(contact);
+            }
+            ordered
+        }
     }
 }
 
-#[cfg(test)]
+// Every test needs nodes to bootstrap off
+#[cfg(all(test, not(feature = "client-only")))]
 mod tests {
+    use super::{order_sources, AttemptOutcome, ContactFailure, ContactSource};
     use crate::test_utils::new_random_qp2p;
-    use crate::{Builder, Config, Event, NodeInfo, OurType, QuicP2p};
+    #[cfg(feature = "test-utils")]
+    use crate::test_utils::{new_qp2p_on, MemoryTransport};
+    use crate::{BootstrapStrategy, Builder, Config, Event, NodeInfo, OurType, QuicP2p};
     use crossbeam_channel as mpmc;
     use std::collections::{HashSet, VecDeque};
+    use std::iter;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
     use std::thread;
     use std::time::Duration;
@@ -141,6 +350,7 @@ mod tests {
                 our_type: OurType::Client,
                 ..Default::default()
             })
+            .with_persistent_identity(false)
             .with_proxies(bootstrap_cache.clone(), true,)
             .build());
 
@@ -163,6 +373,55 @@ mod tests {
         }
     }
 
+    #[test]
+    fn bootstrap_sources_are_ordered_by_the_strategy() {
+        let cached = || vec!["c0", "c1", "c2"];
+        let configured = || vec!["h0"];
+
+        for &(strategy, expected) in &[
+            (BootstrapStrategy::CacheFirst, &["c0", "c1", "c2", "h0"][..]),
+            (
+                BootstrapStrategy::HardCodedFirst,
+                &["h0", "c0", "c1", "c2"][..],
+            ),
+            (
+                BootstrapStrategy::Interleaved,
+                &["c0", "h0", "c1", "c2"][..],
+            ),
+            (BootstrapStrategy::FreshNetwork, &["h0"][..]),
+        ] {
+            assert_eq!(order_sources(strategy, cached(), configured()), expected);
+        }
+    }
+
+    #[test]
+    fn fresh_network_bootstrap_ignores_the_cache() {
+        let (mut cached_node, _) = test_node();
+        let (mut hcc_node, _) = test_node();
+        let cached: VecDeque<_> = iter::once(unwrap!(cached_node.our_connection_info())).collect();
+        let hcc_info = unwrap!(hcc_node.our_connection_info());
+
+        let (ev_tx, _ev_rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                hard_coded_contacts: iter::once(hcc_info.clone()).collect(),
+                bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+                our_type: OurType::Client,
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(cached, true)
+            .build());
+
+        let report = unwrap!(peer.bootstrap_with_report());
+        assert!(report.is_successful());
+        assert_eq!(report.attempts.len(), 1);
+        assert_eq!(report.attempts[0].peer_addr, hcc_info.peer_addr);
+        assert_eq!(report.attempts[0].source, ContactSource::HardCoded);
+    }
+
     // Test that bootstrap fails after a handshake timeout if none of the peers
     // that we're bootstrapping to have responded.
     #[test]
@@ -185,19 +444,178 @@ mod tests {
                 idle_timeout_msec: Some(30),
                 ..Default::default()
             })
+            .with_persistent_identity(false)
             .with_proxies(Default::default(), true)
             .build());
 
         bootstrap_client.bootstrap();
 
         match unwrap!(ev_rx.recv()) {
-            Event::BootstrapFailure => (),
+            Event::BootstrapFailure { failures } => {
+                assert_eq!(failures.len(), 1);
+                assert_eq!(
+                    failures.get(&bootstrap_ci.peer_addr),
+                    Some(&ContactFailure::TimedOut)
+                );
+            }
             ev => {
                 panic!("Unexpected event: {:?}", ev);
             }
         }
     }
 
+    #[cfg(feature = "test-utils")]
+    #[test]
+    fn bootstrap_failure_tells_why_each_contact_failed() {
+        let network = MemoryTransport::new();
+        let (mut node, _rx0) = new_qp2p_on(&network);
+        let (mut other_node, _rx1) = new_qp2p_on(&network);
+        let mut imposter = unwrap!(node.our_connection_info());
+        imposter.peer_cert_der = unwrap!(other_node.our_connection_info()).peer_cert_der;
+        let mut missing = imposter.clone();
+        missing.peer_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1));
+
+        let (ev_tx, ev_rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                hard_coded_contacts: vec![imposter.clone(), missing.clone()]
+                    .into_iter()
+                    .collect(),
+                our_type: OurType::Client,
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_memory_transport(network.clone())
+            .with_proxies(Default::default(), true)
+            .build());
+
+        peer.bootstrap();
+        for event in ev_rx.iter() {
+            if let Event::BootstrapFailure { failures } = event {
+                assert_eq!(failures.len(), 2);
+                assert_eq!(
+                    failures.get(&imposter.peer_addr),
+                    Some(&ContactFailure::CertificateMismatch)
+                );
+                assert_eq!(
+                    failures.get(&missing.peer_addr),
+                    Some(&ContactFailure::TimedOut)
+                );
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn bootstrap_report_lists_every_attempted_contact() {
+        let (mut cached_node, _rx0) = test_node();
+        let cached_info = unwrap!(cached_node.our_connection_info());
+
+        let invalid_info = NodeInfo {
+            peer_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 37693)),
+            peer_cert_der: vec![1, 2, 3],
+            alt_addrs: Vec::new(),
+        };
+        let mut hcc = HashSet::new();
+        assert!(hcc.insert(invalid_info.clone()));
+
+        let (ev_tx, _ev_rx) = mpmc::unbounded();
+        let mut bootstrapping_node = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                hard_coded_contacts: hcc,
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(iter::once(cached_info.clone()).collect(), true)
+            .build());
+
+        let report = unwrap!(bootstrapping_node.bootstrap_with_report());
+        assert!(report.is_successful());
+        assert_eq!(report.attempts.len(), 2);
+
+        let cached_attempt = unwrap!(report
+            .attempts
+            .iter()
+            .find(|attempt| attempt.peer_addr == cached_info.peer_addr));
+        assert_eq!(cached_attempt.source, ContactSource::Cache);
+        assert_eq!(cached_attempt.outcome, AttemptOutcome::Succeeded);
+
+        let invalid_attempt = unwrap!(report
+            .attempts
+            .iter()
+            .find(|attempt| attempt.peer_addr == invalid_info.peer_addr));
+        assert_eq!(invalid_attempt.source, ContactSource::HardCoded);
+        assert_ne!(invalid_attempt.outcome, AttemptOutcome::Succeeded);
+    }
+
+    #[test]
+    fn hard_coded_contacts_are_tried_in_the_order_of_the_seed() {
+        let hcc: HashSet<NodeInfo> = (0..8)
+            .map(|i| NodeInfo {
+                peer_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 37700 + i)),
+                peer_cert_der: vec![1, 2, 3],
+                alt_addrs: Vec::new(),
+            })
+            .collect();
+        let attempted = || {
+            let (ev_tx, _ev_rx) = mpmc::unbounded();
+            let mut peer = unwrap!(Builder::new(ev_tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    hard_coded_contacts: hcc.clone(),
+                    ..Default::default()
+                })
+                .with_persistent_identity(false)
+                .with_proxies(Default::default(), true)
+                .with_rng_seed(7)
+                .build());
+            let report = unwrap!(peer.bootstrap_with_report());
+            report
+                .attempts
+                .into_iter()
+                .map(|attempt| attempt.peer_addr)
+                .collect::<Vec<_>>()
+        };
+
+        let first = attempted();
+        assert_eq!(first.len(), hcc.len());
+        assert_eq!(attempted(), first);
+    }
+
+    #[test]
+    fn bootstrap_outcomes_are_counted_in_stats() {
+        let (mut cached_node, _rx0) = test_node();
+        let cached_info = unwrap!(cached_node.our_connection_info());
+
+        let (mut peer, _ev_rx) = test_peer_with_bootstrap_cache(vec![cached_info]);
+        assert!(unwrap!(peer.bootstrap_with_report()).is_successful());
+
+        let stats = unwrap!(peer.stats()).bootstrap;
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.hard_coded_hits, 0);
+        assert_eq!(stats.failures, 0);
+        // The cache was given to us up front so we can't know how old the entry is
+        assert_eq!(stats.cache_hit_ages.unknown, 1);
+
+        let (mut lonely_peer, _ev_rx) = test_node();
+        assert!(!unwrap!(lonely_peer.bootstrap_with_report()).is_successful());
+        assert_eq!(unwrap!(lonely_peer.stats()).bootstrap.failures, 1);
+    }
+
+    #[test]
+    fn bootstrap_report_is_empty_without_contacts() {
+        let (mut peer, _ev_rx) = test_node();
+
+        let report = unwrap!(peer.bootstrap_with_report());
+        assert!(!report.is_successful());
+        assert!(report.attempts.is_empty());
+    }
+
     #[test]
     fn node_will_attempt_hard_coded_contacts() {
         let (mut peer1, _) = test_node();
@@ -213,7 +631,7 @@ mod tests {
         let peer2_conn_info = unwrap!(peer2.our_connection_info());
 
         for event in ev_rx.iter() {
-            if let Event::BootstrappedTo { node } = event {
+            if let Event::BootstrappedTo { node, .. } = event {
                 assert_eq!(node, peer1_conn_info);
                 break;
             }
@@ -247,8 +665,16 @@ mod tests {
         let peer2_conn_info = unwrap!(peer2.our_connection_info());
 
         for event in ev_rx.iter() {
-            if let Event::BootstrappedTo { node } = event {
+            if let Event::BootstrappedTo {
+                node,
+                candidates_tried,
+                source,
+                ..
+            } = event
+            {
                 assert_eq!(node, peer1_conn_info);
+                assert_eq!(candidates_tried, 1);
+                assert_eq!(source, ContactSource::HardCoded);
                 break;
             }
         }
@@ -266,6 +692,72 @@ mod tests {
         assert!(is_peer2_state_valid);
     }
 
+    #[test]
+    fn connections_to_bootstrap_only_contacts_are_closed_after_bootstrapping() {
+        let (mut seed, _) = test_node();
+        let seed_info = unwrap!(seed.our_connection_info());
+
+        let (ev_tx, ev_rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                bootstrap_only_contacts: iter::once(seed_info.clone()).collect(),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .build());
+
+        peer.bootstrap();
+
+        for event in ev_rx.iter() {
+            if let Event::BootstrappedTo { node, source, .. } = event {
+                assert_eq!(node, seed_info);
+                assert_eq!(source, ContactSource::BootstrapOnly);
+                break;
+            }
+        }
+
+        assert!(unwrap!(peer.connections(|c| c.is_empty())));
+        assert!(unwrap!(peer.bootstrap_cache()).is_empty());
+    }
+
+    #[test]
+    fn contacts_added_at_runtime_are_attempted() {
+        let (mut seed, _) = test_node();
+        let seed_info = unwrap!(seed.our_connection_info());
+
+        let (ev_tx, ev_rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(ev_tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .build());
+
+        peer.add_contact(seed_info.clone());
+        assert!(peer.is_hard_coded_contact(&seed_info));
+        peer.bootstrap();
+        for event in ev_rx.iter() {
+            if let Event::BootstrappedTo { node, source, .. } = event {
+                assert_eq!(node, seed_info);
+                assert_eq!(source, ContactSource::HardCoded);
+                break;
+            }
+        }
+
+        assert!(peer.remove_contact(seed_info.peer_addr));
+        assert!(!peer.remove_contact(seed_info.peer_addr));
+        assert!(!peer.is_hard_coded_contact(&seed_info));
+        peer.disconnect_from(seed_info.peer_addr);
+        let report = unwrap!(peer.bootstrap_with_report());
+        assert!(report.attempts.is_empty());
+    }
+
     #[test]
     fn node_will_attempt_cached_peers() {
         let (mut peer1, _) = test_node();
@@ -276,8 +768,9 @@ mod tests {
         peer2.bootstrap();
 
         for event in ev_rx.iter() {
-            if let Event::BootstrappedTo { node } = event {
+            if let Event::BootstrappedTo { node, source, .. } = event {
                 assert_eq!(node, peer1_conn_info);
+                assert_eq!(source, ContactSource::Cache);
                 break;
             }
         }
@@ -290,7 +783,7 @@ mod tests {
         peer.bootstrap();
 
         for event in ev_rx.iter() {
-            if let Event::BootstrapFailure = event {
+            if let Event::BootstrapFailure { .. } = event {
                 break;
             }
         }
@@ -301,6 +794,7 @@ mod tests {
         let dummy_peer_info = NodeInfo {
             peer_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 37692)),
             peer_cert_der: vec![1, 2, 3],
+            alt_addrs: Vec::new(),
         };
         let (mut peer, ev_rx) = {
             let mut hcc = HashSet::new();
@@ -311,7 +805,7 @@ mod tests {
         peer.bootstrap();
 
         for event in ev_rx.iter() {
-            if let Event::BootstrapFailure = event {
+            if let Event::BootstrapFailure { .. } = event {
                 break;
             }
         }
@@ -328,6 +822,7 @@ mod tests {
                 ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
                 ..Default::default()
             })
+            .with_persistent_identity(false)
             .with_proxies(cached_peers, true);
         (unwrap!(builder.build()), ev_rx)
     }
@@ -351,6 +846,7 @@ mod tests {
                 our_type,
                 ..Default::default()
             })
+            .with_persistent_identity(false)
             // Make sure we start with an empty cache. Otherwise, we might get into unexpected state.
             .with_proxies(Default::default(), true);
         (unwrap!(builder.build()), ev_rx)
@@ -7,22 +7,47 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::clock::{Clock, SystemClock};
+use crate::config::SerialisableCertificate;
 use crate::dirs::Dirs;
+#[cfg(not(feature = "client-only"))]
+use crate::persistence;
+use crate::restore::StateBundle;
+#[cfg(not(feature = "client-only"))]
 use crate::utils;
-use crate::{Error, NodeInfo, R};
-use std::collections::{HashSet, VecDeque};
+#[cfg(not(feature = "client-only"))]
+use crate::Error;
+use crate::{NodeInfo, R};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+#[cfg(not(feature = "client-only"))]
 use std::{fs, io};
 
 /// Maximum peers in the cache.
 const MAX_CACHE_SIZE: usize = 200;
 
-/// A very simple LRU like struct that writes itself to disk every 10 entries added.
+/// A very simple LRU like struct that writes itself to disk every 10 entries added. Client-only
+/// builds and ephemeral instances keep it in memory.
 pub struct BootstrapCache {
     peers: VecDeque<NodeInfo>,
-    cache_path: PathBuf,
+    /// File the peers are synced to, none if they are kept in memory.
+    #[cfg(not(feature = "client-only"))]
+    cache_path: Option<PathBuf>,
+    #[cfg(not(feature = "client-only"))]
     add_count: u8,
+    /// Identity the peers are synced along with to the bundle at `cache_path`, see
+    /// `Builder::restore`.
+    #[cfg(not(feature = "client-only"))]
+    restored_identity: Option<SerialisableCertificate>,
     hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    /// When the peers were last added to the cache. Not persisted, so peers read from the cache
+    /// file have no entry.
+    cached_at: HashMap<SocketAddr, Instant>,
+    clock: Arc<dyn Clock>,
 }
 
 impl BootstrapCache {
@@ -33,6 +58,7 @@ impl BootstrapCache {
     ///
     /// - hard_coded_contacts: these peers are hard coded into the binary and should
     ///   not be cached upon successful connection.
+    #[cfg(not(feature = "client-only"))]
     pub fn new(
         hard_coded_contacts: HashSet<NodeInfo>,
         user_override: Option<&Dirs>,
@@ -48,7 +74,14 @@ impl BootstrapCache {
         )?;
 
         let peers: VecDeque<NodeInfo> = if cache_path.exists() {
-            utils::read_from_disk(&cache_path)?
+            match persistence::read_with_backup(&cache_path) {
+                Ok(peers) => peers,
+                Err(e @ Error::CorruptFile { .. }) | Err(e @ Error::Bincode(_)) => {
+                    warn!("Ignoring unreadable bootstrap cache: {}", e);
+                    Default::default()
+                }
+                Err(e) => return Err(e),
+            }
         } else {
             let cache_dir = cache_path
                 .parent()
@@ -60,12 +93,49 @@ impl BootstrapCache {
 
         Ok(BootstrapCache {
             peers,
-            cache_path,
+            cache_path: Some(cache_path),
             add_count: 0u8,
+            restored_identity: None,
+            hard_coded_contacts,
+            bootstrap_only_contacts: Default::default(),
+            cached_at: Default::default(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Constructs an empty bootstrap cache which is never written to disk.
+    #[cfg(feature = "client-only")]
+    pub fn new(
+        hard_coded_contacts: HashSet<NodeInfo>,
+        _user_override: Option<&Dirs>,
+    ) -> R<BootstrapCache> {
+        Ok(BootstrapCache {
+            peers: Default::default(),
             hard_coded_contacts,
+            bootstrap_only_contacts: Default::default(),
+            cached_at: Default::default(),
+            clock: Arc::new(SystemClock),
         })
     }
 
+    /// Constructs an empty bootstrap cache which is never written to disk, see
+    /// `Builder::ephemeral`.
+    pub fn in_memory(hard_coded_contacts: HashSet<NodeInfo>) -> BootstrapCache {
+        BootstrapCache {
+            peers: Default::default(),
+            #[cfg(not(feature = "client-only"))]
+            cache_path: None,
+            #[cfg(not(feature = "client-only"))]
+            add_count: 0u8,
+            #[cfg(not(feature = "client-only"))]
+            restored_identity: None,
+            hard_coded_contacts,
+            bootstrap_only_contacts: Default::default(),
+            cached_at: Default::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
     pub fn peers_mut(&mut self) -> &mut VecDeque<NodeInfo> {
         &mut self.peers
     }
@@ -78,12 +148,94 @@ impl BootstrapCache {
         &self.hard_coded_contacts
     }
 
-    /// Caches given peer if it's not in hard coded contacts.
+    /// Tell the time peers are added at by `clock`, see `Builder::with_clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Start from the peers of the restored bundle, which is then synced in place of the cache
+    /// file. Client-only builds keep to memory still.
+    pub fn restored_from(mut self, bundle: StateBundle, bundle_path: PathBuf) -> Self {
+        self.peers = bundle.bootstrap_cache;
+        #[cfg(not(feature = "client-only"))]
+        {
+            self.cache_path = Some(bundle_path);
+            self.restored_identity = Some(bundle.identity);
+        }
+        #[cfg(feature = "client-only")]
+        let _ = bundle_path;
+        self
+    }
+
+    /// Sync the peers along with the given identity from now on, if they are synced along with
+    /// the restored one, see `Builder::with_cert_expiry_monitoring`.
+    pub fn renew_identity(&mut self, identity: SerialisableCertificate) {
+        #[cfg(not(feature = "client-only"))]
+        {
+            if self.restored_identity.is_some() {
+                self.restored_identity = Some(identity);
+                self.sync_to_disk();
+            }
+        }
+        #[cfg(feature = "client-only")]
+        let _ = identity;
+    }
+
+    /// Contacts to bootstrap off but not to cache, see `Config::bootstrap_only_contacts`.
+    pub fn with_bootstrap_only_contacts(mut self, contacts: HashSet<NodeInfo>) -> Self {
+        self.bootstrap_only_contacts = contacts;
+        self
+    }
+
+    pub fn bootstrap_only_contacts(&self) -> &HashSet<NodeInfo> {
+        &self.bootstrap_only_contacts
+    }
+
+    /// Replace the contacts of the config, see `QuicP2p::apply_config`. Peers cached before are
+    /// kept.
+    pub fn set_contacts(
+        &mut self,
+        hard_coded_contacts: HashSet<NodeInfo>,
+        bootstrap_only_contacts: HashSet<NodeInfo>,
+    ) {
+        self.hard_coded_contacts = hard_coded_contacts;
+        self.bootstrap_only_contacts = bootstrap_only_contacts;
+    }
+
+    /// Bootstrap off the contact as one of the hard-coded ones from now on, see
+    /// `QuicP2p::add_contact`. It is no longer cached, nor bootstrap-only.
+    pub fn add_contact(&mut self, contact: NodeInfo) {
+        let _ = self.bootstrap_only_contacts.remove(&contact);
+        self.peers.retain(|peer| *peer != contact);
+        let _ = self.hard_coded_contacts.insert(contact);
+    }
+
+    /// Stop bootstrapping off the hard-coded or bootstrap-only contacts at the given address, see
+    /// `QuicP2p::remove_contact`.
+    pub fn remove_contact(&mut self, peer_addr: SocketAddr) {
+        self.hard_coded_contacts
+            .retain(|contact| contact.peer_addr != peer_addr);
+        self.bootstrap_only_contacts
+            .retain(|contact| contact.peer_addr != peer_addr);
+    }
+
+    /// How long ago the given peer was last added to the cache, if known.
+    pub fn age_of(&self, peer_addr: &SocketAddr) -> Option<Duration> {
+        let now = self.clock.now();
+        self.cached_at
+            .get(peer_addr)
+            .map(|cached_at| now.duration_since(*cached_at))
+    }
+
+    /// Caches given peer if it's not in hard coded or bootstrap-only contacts.
     pub fn add_peer(&mut self, peer: NodeInfo) {
-        if self.hard_coded_contacts.contains(&peer) {
+        if self.hard_coded_contacts.contains(&peer) || self.bootstrap_only_contacts.contains(&peer)
+        {
             return;
         }
 
+        let _ = self.cached_at.insert(peer.peer_addr, self.clock.now());
         if self.peers.contains(&peer) {
             self.move_to_cache_top(peer);
         } else {
@@ -91,12 +243,35 @@ impl BootstrapCache {
         }
     }
 
+    /// Update the entry of the peer cached at `old_addr` to its new address, keeping its place in
+    /// the cache. Does nothing if the peer isn't cached.
+    pub fn move_peer(&mut self, old_addr: SocketAddr, peer: NodeInfo) {
+        let cached = match self
+            .peers
+            .iter_mut()
+            .find(|p| p.peer_addr == old_addr && p.peer_cert_der == peer.peer_cert_der)
+        {
+            Some(cached) => cached,
+            None => return,
+        };
+        *cached = peer;
+        if let Some(cached_at) = self.cached_at.remove(&old_addr) {
+            let _ = self.cached_at.insert(cached.peer_addr, cached_at);
+        }
+    }
+
     fn insert_new(&mut self, peer: NodeInfo) {
         self.peers.push_back(peer);
-        self.add_count += 1;
+        #[cfg(not(feature = "client-only"))]
+        {
+            self.add_count += 1;
+        }
         if self.peers.len() > MAX_CACHE_SIZE {
-            let _ = self.peers.pop_front();
+            if let Some(evicted) = self.peers.pop_front() {
+                let _ = self.cached_at.remove(&evicted.peer_addr);
+            }
         }
+        #[cfg(not(feature = "client-only"))]
         self.try_sync_to_disk();
     }
 
@@ -108,12 +283,29 @@ impl BootstrapCache {
     }
 
     /// Write cached peers to disk every 10 inserted peers.
+    #[cfg(not(feature = "client-only"))]
     fn try_sync_to_disk(&mut self) {
         if self.add_count > 9 {
-            if let Err(e) = utils::write_to_disk(&self.cache_path, &self.peers) {
-                info!("Failed to write bootstrap cache to disk: {}", e);
-            }
-            self.add_count = 0;
+            self.sync_to_disk();
+        }
+    }
+
+    #[cfg(not(feature = "client-only"))]
+    fn sync_to_disk(&mut self) {
+        self.add_count = 0;
+        let cache_path = match self.cache_path {
+            Some(ref cache_path) => cache_path,
+            None => return,
+        };
+        let written = match self.restored_identity {
+            Some(ref identity) => persistence::write_with_backup(
+                cache_path,
+                &StateBundle::new(identity.clone(), self.peers.clone()),
+            ),
+            None => persistence::write_with_backup(cache_path, &self.peers),
+        };
+        if let Err(e) = written {
+            info!("Failed to write bootstrap cache to disk: {}", e);
         }
     }
 }
@@ -122,10 +314,36 @@ impl BootstrapCache {
 mod tests {
     use super::*;
     use crate::test_utils::{rand_node_info, test_dirs};
+    use std::iter;
 
     mod add_peer {
         use super::*;
+        use crate::test_utils::ManualClock;
+
+        #[test]
+        fn ages_are_told_by_the_clock() {
+            let clock = ManualClock::new();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&test_dirs())))
+                .with_clock(Arc::new(clock.clone()));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+
+            cache.add_peer(peer1.clone());
+            clock.advance(Duration::from_secs(3_600));
+            cache.add_peer(peer2.clone());
+            clock.advance(Duration::from_secs(60));
+
+            assert_eq!(
+                cache.age_of(&peer1.peer_addr),
+                Some(Duration::from_secs(3_660))
+            );
+            assert_eq!(
+                cache.age_of(&peer2.peer_addr),
+                Some(Duration::from_secs(60))
+            );
+        }
 
+        #[cfg(not(feature = "client-only"))]
         #[test]
         fn when_10_peers_are_added_they_are_synced_to_disk() {
             let dirs = test_dirs();
@@ -141,6 +359,44 @@ mod tests {
             assert_eq!(cache.peers.len(), 10);
         }
 
+        #[cfg(not(feature = "client-only"))]
+        #[test]
+        fn restored_peers_are_synced_to_the_bundle_with_the_identity() {
+            use crate::restore;
+
+            let dirs = test_dirs();
+            let bundle = unwrap!(restore::load(Some(&dirs)));
+            let bundle_path = unwrap!(restore::bundle_path(Some(&dirs)));
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)))
+                .restored_from(bundle.clone(), bundle_path);
+
+            for _ in 0..10 {
+                cache.add_peer(rand_node_info());
+            }
+
+            let restored = unwrap!(restore::load(Some(&dirs)));
+            assert!(restored.identity == bundle.identity);
+            assert_eq!(restored.bootstrap_cache, cache.peers);
+            // The separate cache file is left alone
+            assert!(!dirs.cache_dir().join("bootstrap_cache").exists());
+        }
+
+        #[cfg(not(feature = "client-only"))]
+        #[test]
+        fn renewed_identities_are_synced_to_the_bundle_at_once() {
+            use crate::restore;
+
+            let dirs = test_dirs();
+            let bundle = unwrap!(restore::load(Some(&dirs)));
+            let bundle_path = unwrap!(restore::bundle_path(Some(&dirs)));
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)))
+                .restored_from(bundle, bundle_path);
+
+            let renewed = SerialisableCertificate::default();
+            cache.renew_identity(renewed.clone());
+            assert!(unwrap!(restore::load(Some(&dirs))).identity == renewed);
+        }
+
         #[test]
         fn when_given_peer_is_in_hard_coded_contacts_it_is_not_cached() {
             let peer1 = rand_node_info();
@@ -158,6 +414,22 @@ mod tests {
             assert_eq!(peers, vec![peer2]);
         }
 
+        #[test]
+        fn when_given_peer_is_bootstrap_only_it_is_not_cached() {
+            let seed = rand_node_info();
+            let peer = rand_node_info();
+
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)))
+                .with_bootstrap_only_contacts(iter::once(seed.clone()).collect());
+
+            cache.add_peer(seed);
+            cache.add_peer(peer.clone());
+
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(peers, vec![peer]);
+        }
+
         #[test]
         fn it_caps_cache_size() {
             let dirs = test_dirs();
@@ -173,6 +445,51 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "client-only")]
+    #[test]
+    fn cache_is_not_written_to_disk() {
+        let dirs = test_dirs();
+        let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+        for _ in 0..10 {
+            cache.add_peer(rand_node_info());
+        }
+        assert_eq!(cache.peers.len(), 10);
+
+        assert!(!dirs.cache_dir().join("bootstrap_cache").exists());
+    }
+
+    #[test]
+    fn in_memory_cache_keeps_its_peers_without_a_file() {
+        let mut cache = BootstrapCache::in_memory(Default::default());
+        for _ in 0..10 {
+            cache.add_peer(rand_node_info());
+        }
+        assert_eq!(cache.peers.len(), 10);
+        #[cfg(not(feature = "client-only"))]
+        {
+            assert!(cache.cache_path.is_none());
+            assert_eq!(cache.add_count, 0);
+        }
+    }
+
+    #[cfg(not(feature = "client-only"))]
+    #[test]
+    fn corrupt_cache_file_is_replaced_by_an_empty_cache() {
+        let dirs = test_dirs();
+        let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+        for _ in 0..10 {
+            cache.add_peer(rand_node_info());
+        }
+
+        let cache_path = unwrap!(cache.cache_path.clone());
+        let mut data = unwrap!(fs::read(&cache_path));
+        data.truncate(data.len() / 2);
+        unwrap!(fs::write(&cache_path, data));
+
+        let cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+        assert!(cache.peers.is_empty());
+    }
+
     mod move_to_cache_top {
         use super::*;
 
@@ -193,4 +510,39 @@ mod tests {
             assert_eq!(peers, vec![peer1, peer3, peer2]);
         }
     }
+
+    mod move_peer {
+        use super::*;
+
+        #[test]
+        fn it_updates_the_address_of_the_cached_peer_in_place() {
+            let dirs = test_dirs();
+            let mut cache = unwrap!(BootstrapCache::new(Default::default(), Some(&dirs)));
+            let peer1 = rand_node_info();
+            let peer2 = rand_node_info();
+            cache.add_peer(peer1.clone());
+            cache.add_peer(peer2.clone());
+
+            let moved = NodeInfo {
+                peer_addr: ([10, 0, 0, 1], 5000).into(),
+                peer_cert_der: peer1.peer_cert_der.clone(),
+                alt_addrs: Vec::new(),
+            };
+            cache.move_peer(peer1.peer_addr, moved.clone());
+
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(peers, vec![moved.clone(), peer2.clone()]);
+            assert!(cache.age_of(&moved.peer_addr).is_some());
+            assert!(cache.age_of(&peer1.peer_addr).is_none());
+
+            // Peers are only taken to have moved if their certificate matches
+            let impostor = NodeInfo {
+                peer_addr: ([10, 0, 0, 2], 5000).into(),
+                ..rand_node_info()
+            };
+            cache.move_peer(peer2.peer_addr, impostor);
+            let peers: Vec<NodeInfo> = cache.peers.iter().cloned().collect();
+            assert_eq!(peers, vec![moved, peer2]);
+        }
+    }
 }
@@ -0,0 +1,283 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Watching certificates run towards their expiry, see `Builder::with_cert_expiry_monitoring`.
+//!
+//! Each check compares the end of validity of our certificate, of those of the hard-coded
+//! contacts and of those of the nodes we are connected to with the time of the system. Every
+//! certificate expiring within the warning period is reported once via
+//! `Event::CertificateExpiring`. Ours is then renewed and reported via
+//! `Event::CertificateRenewed`. The endpoint can't swap its certificate while it runs, so the
+//! renewed one replaces the persisted identity and is served from the next restart on.
+
+use crate::config::SerialisableCertificate;
+use crate::connection::ToPeer;
+use crate::context::ctx_mut;
+use crate::event::Event;
+use crate::persistence;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// Hook renewing our certificate ahead of its expiry, see `Builder::with_cert_expiry_monitoring`.
+///
+/// It's called from the event loop so it should return quickly.
+pub type CertRenewal = fn() -> SerialisableCertificate;
+
+/// Certificates reported as expiring already, by their DER encoding.
+#[derive(Default)]
+pub struct CertExpiry {
+    warned: HashSet<Vec<u8>>,
+}
+
+/// Check the certificates every `interval`, starting right away, reporting those expiring
+/// within `warn_ahead`. Our renewed certificate is written to `identity_path` if given.
+pub fn start(
+    interval: Duration,
+    warn_ahead: Duration,
+    renewal: Option<CertRenewal>,
+    identity_path: Option<PathBuf>,
+) {
+    let leaf = Interval::new(Instant::now(), interval)
+        .map_err(|e| info!("Error in certificate expiry interval: {:?}", e))
+        .for_each(move |_| {
+            check(
+                SystemTime::now(),
+                warn_ahead,
+                renewal,
+                identity_path.as_ref(),
+            );
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn check(
+    now: SystemTime,
+    warn_ahead: Duration,
+    renewal: Option<CertRenewal>,
+    identity_path: Option<&PathBuf>,
+) {
+    ctx_mut(|c| {
+        let ours = c.our_complete_cert.cert_der.clone();
+        let peers = c
+            .bootstrap_cache
+            .hard_coded_contacts()
+            .iter()
+            .map(|contact| (contact.peer_addr, contact.peer_cert_der.clone()))
+            .chain(
+                c.connections
+                    .iter()
+                    .filter_map(|(&peer_addr, conn)| match conn.to_peer {
+                        ToPeer::Established {
+                            ref peer_cert_der, ..
+                        } => Some((peer_addr, peer_cert_der.clone())),
+                        _ => None,
+                    }),
+            )
+            .collect::<Vec<_>>();
+
+        let mut renew = false;
+        let certs = Some((None, ours))
+            .into_iter()
+            .chain(peers.into_iter().map(|(addr, der)| (Some(addr), der)));
+        for (peer_addr, cert_der) in certs {
+            let when = match expiring(&cert_der, now, warn_ahead) {
+                Some(when) => when,
+                None => continue,
+            };
+            if !c.cert_expiry.warned.insert(cert_der) {
+                continue;
+            }
+            renew |= peer_addr.is_none();
+            let _ = c
+                .event_tx
+                .send(Event::CertificateExpiring { peer_addr, when });
+        }
+        if !renew {
+            return;
+        }
+
+        let cert = renewal.map_or_else(SerialisableCertificate::default, |renew| renew());
+        if let Some(path) = identity_path {
+            if let Err(e) = persistence::write_to_disk(path, &cert) {
+                info!("Failed to write the renewed identity to disk: {}", e);
+            }
+        }
+        c.bootstrap_cache.renew_identity(cert.clone());
+        let _ = c.event_tx.send(Event::CertificateRenewed { cert });
+    })
+}
+
+/// When the certificate expires, if it does within `warn_ahead` of `now` or did already.
+/// Certificates we can't read the validity of are taken to never expire.
+fn expiring(cert_der: &[u8], now: SystemTime, warn_ahead: Duration) -> Option<SystemTime> {
+    let when = not_after(cert_der)?;
+    match when.duration_since(now) {
+        Ok(left) if left > warn_ahead => None,
+        _ => Some(when),
+    }
+}
+
+/// End of the validity of the DER encoded X.509 certificate.
+pub fn not_after(cert_der: &[u8]) -> Option<SystemTime> {
+    let (_, cert, _) = tlv(cert_der).filter(|&(tag, ..)| tag == SEQUENCE)?;
+    let (_, tbs, _) = tlv(cert).filter(|&(tag, ..)| tag == SEQUENCE)?;
+
+    let mut rest = tbs;
+    // The version is optional, followed by the serial number, the signature algorithm and the
+    // issuer
+    let (tag, _, after_version) = tlv(rest)?;
+    if tag == EXPLICIT_VERSION {
+        rest = after_version;
+    }
+    for _ in 0..3 {
+        rest = tlv(rest)?.2;
+    }
+    let (_, validity, _) = tlv(rest).filter(|&(tag, ..)| tag == SEQUENCE)?;
+    let (_, _, after_not_before) = tlv(validity)?;
+    let (tag, time, _) = tlv(after_not_before)?;
+    parse_time(tag, time)
+}
+
+const SEQUENCE: u8 = 0x30;
+const EXPLICIT_VERSION: u8 = 0xa0;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Tag, contents and what follows of the first DER element of `der`.
+fn tlv(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, der) = der.split_first()?;
+    let (&len, mut der) = der.split_first()?;
+    let len = if len < 0x80 {
+        usize::from(len)
+    } else {
+        let octets = usize::from(len & 0x7f);
+        if octets == 0 || octets > 4 || der.len() < octets {
+            return None;
+        }
+        let (len, rest) = der.split_at(octets);
+        der = rest;
+        len.iter()
+            .fold(0usize, |acc, &octet| (acc << 8) | usize::from(octet))
+    };
+    if der.len() < len {
+        return None;
+    }
+    let (contents, rest) = der.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// `YYMMDDHHMMSSZ` for UTC times, of years 1950 to 2049, and `YYYYMMDDHHMMSSZ` for generalized
+/// ones.
+fn parse_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let time = std::str::from_utf8(time).ok()?;
+    let digits = time.strip_suffix('Z')?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (year, rest) = match (tag, digits.len()) {
+        (UTC_TIME, 12) => {
+            let yy: i64 = digits[..2].parse().ok()?;
+            (if yy < 50 { 2000 + yy } else { 1900 + yy }, &digits[2..])
+        }
+        (GENERALIZED_TIME, 14) => (digits[..4].parse().ok()?, &digits[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| rest[i..i + 2].parse::<i64>().ok();
+    let (month, day) = (field(0)?, field(2)?);
+    let (hour, minute, second) = (field(4)?, field(6)?, field(8)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3_600 + minute * 60 + second;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Days since 1970-01-01 of the given date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_validity_of_generated_certificates_is_read() {
+        let cert = SerialisableCertificate::default();
+        let when = unwrap!(not_after(&cert.cert_der));
+        // rcgen makes certificates valid until 4096
+        assert_eq!(
+            when,
+            UNIX_EPOCH + Duration::from_secs(days_from_civil(4096, 1, 1) as u64 * 86_400)
+        );
+
+        let now = SystemTime::now();
+        assert_eq!(
+            expiring(&cert.cert_der, now, Duration::from_secs(86_400)),
+            None
+        );
+        let until_then = unwrap!(when.duration_since(now));
+        assert_eq!(expiring(&cert.cert_der, now, until_then), Some(when));
+        assert_eq!(
+            expiring(
+                &cert.cert_der,
+                when + Duration::from_secs(1),
+                Duration::from_secs(0)
+            ),
+            Some(when)
+        );
+    }
+
+    #[test]
+    fn times_of_both_encodings_are_parsed() {
+        let epoch_day =
+            |y, m, d| UNIX_EPOCH + Duration::from_secs(days_from_civil(y, m, d) as u64 * 86_400);
+        assert_eq!(parse_time(UTC_TIME, b"700101000000Z"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_time(UTC_TIME, b"491231235959Z"),
+            Some(epoch_day(2050, 1, 1) - Duration::from_secs(1))
+        );
+        assert_eq!(
+            parse_time(GENERALIZED_TIME, b"20240229120000Z"),
+            Some(epoch_day(2024, 2, 29) + Duration::from_secs(12 * 3_600))
+        );
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+
+        for &(tag, time) in &[
+            (UTC_TIME, &b"20240229120000Z"[..]),
+            (GENERALIZED_TIME, b"20241329120000Z"),
+            (UTC_TIME, b"24022912000"),
+            (UTC_TIME, b"2402291200+0Z"),
+        ] {
+            assert_eq!(parse_time(tag, time), None);
+        }
+        assert_eq!(not_after(&[SEQUENCE, 0x83, 0xff]), None);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Faults injected at random for soak tests, see `Builder::with_chaos`. Enabled by the `chaos`
+//! feature.
+//!
+//! Faults hit where real ones would, so they are recovered from the same way: a failed send like
+//! a stream which failed writing, a delayed handshake like a slow network, a dropped event like
+//! one the application missed and a killed connection like one which timed out. The decisions
+//! are taken by a generator seeded from the one of `Builder::with_rng_seed`, so that a failing
+//! soak test can be replayed.
+//!
+//! The faults are kept apart from the `Context`, as sends and events are made with it borrowed.
+
+use crate::context::{ctx, ctx_mut};
+use crate::error::Error;
+use crate::rng::Rng;
+use crate::transport::Connecting;
+use crate::utils::R;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::{future, Future, Stream};
+use tokio::runtime::current_thread;
+use tokio::timer::{Delay, Interval};
+
+thread_local! {
+    static MONKEY: RefCell<Option<Monkey>> = RefCell::new(None);
+}
+
+/// Probabilities of the faults injected, from 0 to 1. No faults are injected by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Chaos {
+    /// Of a message failing to be sent with `Error::InjectedFault`, which drops its connection
+    /// like any failed send.
+    pub fail_sends: f64,
+    /// Of a connection attempt completing `handshake_delay` late.
+    pub delay_handshakes: f64,
+    /// How late the handshakes delayed complete.
+    pub handshake_delay: Duration,
+    /// Of an event never reaching the application.
+    pub drop_events: f64,
+    /// Of each complete connection being dropped with `Error::InjectedFault` every
+    /// `kill_interval`.
+    pub kill_connections: f64,
+    /// How often connections are picked to be killed.
+    pub kill_interval: Duration,
+}
+
+impl Chaos {
+    /// Reject probabilities outside 0 to 1, and connections to kill without an interval to kill
+    /// them at, as invalid values of `setting`.
+    pub(crate) fn validate(&self, setting: &'static str) -> R<()> {
+        let probabilities = [
+            ("fail_sends", self.fail_sends),
+            ("delay_handshakes", self.delay_handshakes),
+            ("drop_events", self.drop_events),
+            ("kill_connections", self.kill_connections),
+        ];
+        for &(field, probability) in &probabilities {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(Error::InvalidSetting {
+                    setting,
+                    reason: format!("`{}` must be a probability from 0 to 1", field),
+                });
+            }
+        }
+        if self.kill_connections > 0.0 && self.kill_interval == Duration::from_secs(0) {
+            return Err(Error::InvalidSetting {
+                setting,
+                reason: "`kill_interval` must be non-zero to kill connections".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+struct Monkey {
+    chaos: Chaos,
+    rng: Rng,
+    /// Tells the interval killing connections it was superseded.
+    round: u64,
+}
+
+/// Inject the faults of `chaos` from now on, in place of those injected before.
+pub fn set(chaos: Chaos) {
+    let seed = ctx_mut(|c| c.rng.next_u64());
+    let round = MONKEY.with(|monkey| {
+        let mut monkey = monkey.borrow_mut();
+        let round = monkey.as_ref().map_or(0, |m| m.round + 1);
+        *monkey = Some(Monkey {
+            chaos,
+            rng: Rng::seeded(seed),
+            round,
+        });
+        round
+    });
+
+    if chaos.kill_connections > 0.0 {
+        start_killing(round, chaos.kill_interval);
+    }
+}
+
+/// Fail the send at random.
+pub fn send(
+    sending: Box<dyn Future<Item = (), Error = Error>>,
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    if happens(|chaos| chaos.fail_sends) {
+        trace!("Failing a send on purpose");
+        Box::new(future::err(Error::InjectedFault("send failed")))
+    } else {
+        sending
+    }
+}
+
+/// Delay the handshake at random.
+pub fn connect(connecting: Connecting) -> Connecting {
+    let delay = match MONKEY.with(|monkey| {
+        let mut monkey = monkey.borrow_mut();
+        let monkey = monkey.as_mut()?;
+        if monkey.rng.happens(monkey.chaos.delay_handshakes) {
+            Some(monkey.chaos.handshake_delay)
+        } else {
+            None
+        }
+    }) {
+        Some(delay) => delay,
+        None => return connecting,
+    };
+
+    trace!("Delaying a handshake by {:?} on purpose", delay);
+    Box::new(Delay::new(Instant::now() + delay).then(move |_| connecting))
+}
+
+/// Whether to drop an event at random.
+pub fn drops_event() -> bool {
+    happens(|chaos| chaos.drop_events)
+}
+
+fn happens(probability: impl Fn(&Chaos) -> f64) -> bool {
+    MONKEY.with(|monkey| match *monkey.borrow_mut() {
+        Some(ref mut monkey) => {
+            let probability = probability(&monkey.chaos);
+            monkey.rng.happens(probability)
+        }
+        None => false,
+    })
+}
+
+fn start_killing(round: u64, interval: Duration) {
+    let leaf = Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| info!("Error in chaos interval: {:?}", e))
+        .for_each(move |_| if kill(round) { Ok(()) } else { Err(()) });
+
+    current_thread::spawn(leaf);
+}
+
+/// Kill complete connections at random. Returns `false` once the round was superseded.
+fn kill(round: u64) -> bool {
+    let mut peer_addrs: Vec<SocketAddr> = ctx(|c| {
+        c.connections
+            .iter()
+            .filter(|(_, conn)| conn.is_complete())
+            .map(|(&peer_addr, _)| peer_addr)
+            .collect()
+    });
+    // Decided in a stable order, for a seed to repeat the same kills
+    peer_addrs.sort();
+
+    let victims = MONKEY.with(|monkey| {
+        let mut monkey = monkey.borrow_mut();
+        let monkey = match *monkey {
+            Some(ref mut monkey) if monkey.round == round => monkey,
+            _ => return None,
+        };
+        let probability = monkey.chaos.kill_connections;
+        Some(
+            peer_addrs
+                .into_iter()
+                .filter(|_| monkey.rng.happens(probability))
+                .collect::<Vec<_>>(),
+        )
+    });
+    let victims = match victims {
+        Some(victims) => victims,
+        None => return false,
+    };
+
+    ctx_mut(|c| {
+        for peer_addr in victims {
+            if let Some(mut conn) = c.connections.remove(&peer_addr) {
+                debug!("Killing the connection with peer {} on purpose", peer_addr);
+                // Reported as the connection is dropped
+                conn.cancel_reason = Some(Error::InjectedFault("connection killed"));
+            }
+        }
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faults_must_be_probable() {
+        assert!(Chaos::default().validate("with_chaos").is_ok());
+
+        let chaos = Chaos {
+            drop_events: 1.5,
+            ..Default::default()
+        };
+        match chaos.validate("with_chaos") {
+            Err(Error::InvalidSetting { setting, .. }) => assert_eq!(setting, "with_chaos"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let chaos = Chaos {
+            kill_connections: 0.5,
+            ..Default::default()
+        };
+        assert!(chaos.validate("with_chaos").is_err());
+        let chaos = Chaos {
+            kill_interval: Duration::from_secs(1),
+            ..chaos
+        };
+        assert!(chaos.validate("with_chaos").is_ok());
+    }
+}
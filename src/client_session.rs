@@ -0,0 +1,151 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Sessions of the clients connected to us over QUIC, listed by `QuicP2p::clients` and optionally
+//! evicted once idle, see `Builder::with_client_idle_timeout`.
+//!
+//! Like heartbeats, activity is observed through the streams exchanged with a client: a change in
+//! their number since we last looked counts as activity at the time we look. QUIC keep-alives
+//! don't count, so a client that merely keeps its connection open is idle.
+
+use crate::context::{ctx_mut, Context};
+use crate::error::Error;
+use crate::stats::ConnectionStats;
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// A client connected to us, see `QuicP2p::clients`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientSession {
+    /// Address of the client.
+    pub peer_addr: SocketAddr,
+    /// When the client completed its handshake with us.
+    pub connected_at: Instant,
+    /// When we last noticed messages exchanged with the client, or `connected_at` if none were.
+    pub last_activity: Instant,
+    /// Bytes sent to the client so far.
+    pub bytes_sent: u64,
+    /// Bytes received from the client so far.
+    pub bytes_received: u64,
+}
+
+/// Session state kept in the `Connection` of a client.
+#[derive(Debug)]
+pub struct Session {
+    connected_at: Instant,
+    last_activity: Instant,
+    streams_seen: u64,
+}
+
+impl Session {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            connected_at: now,
+            last_activity: now,
+            streams_seen: 0,
+        }
+    }
+
+    /// Take note of the streams exchanged with the client since we last looked.
+    fn refresh(&mut self, stats: &ConnectionStats, now: Instant) {
+        let streams = stats.streams_sent + stats.streams_received + stats.streams_failed;
+        if streams != self.streams_seen {
+            self.streams_seen = streams;
+            self.last_activity = now;
+        }
+    }
+}
+
+/// Longest time between two looks for idle clients, so that timeouts told by a
+/// `test_utils::ManualClock` pass soon after the clock is advanced.
+const MAX_EVICTION_PERIOD: Duration = Duration::from_secs(1);
+
+/// Disconnect clients which exchanged no messages with us for `timeout`, looking every quarter of
+/// it but at least every `MAX_EVICTION_PERIOD`.
+pub fn start_eviction(timeout: Duration) {
+    let period = cmp::min(
+        cmp::max(timeout / 4, Duration::from_millis(1)),
+        MAX_EVICTION_PERIOD,
+    );
+    let leaf = Interval::new(Instant::now() + period, period)
+        .map_err(|e| info!("Error in client eviction interval: {:?}", e))
+        .for_each(move |_| {
+            ctx_mut(|c| {
+                let now = c.clock.now();
+                evict(c, timeout, now)
+            });
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn evict(c: &mut Context, timeout: Duration, now: Instant) {
+    let idle: Vec<_> = sessions(c, now)
+        .into_iter()
+        .filter(|session| now.duration_since(session.last_activity) >= timeout)
+        .map(|session| session.peer_addr)
+        .collect();
+
+    for peer_addr in idle {
+        if let Some(mut conn) = c.connections.remove(&peer_addr) {
+            debug!("Evicting client {} idle for {:?}", peer_addr, timeout);
+            // Reported as the connection is dropped
+            conn.cancel_reason = Some(Error::IdleClientEvicted);
+        }
+    }
+}
+
+/// Sessions of all the clients connected to us, refreshed as of `now`.
+pub fn sessions(c: &mut Context, now: Instant) -> Vec<ClientSession> {
+    c.connections
+        .iter_mut()
+        .filter_map(|(peer_addr, conn)| {
+            let stats = conn.stats();
+            let session = conn.client_session.as_mut()?;
+            session.refresh(&stats, now);
+            Some(ClientSession {
+                peer_addr: *peer_addr,
+                connected_at: session.connected_at,
+                last_activity: session.last_activity,
+                bytes_sent: stats.bytes_sent,
+                bytes_received: stats.bytes_received,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_new_streams_count_as_activity() {
+        let start = Instant::now();
+        let mut session = Session::new(start);
+        let mut stats = ConnectionStats::default();
+
+        let later = start + Duration::from_secs(1);
+        session.refresh(&stats, later);
+        assert_eq!(session.last_activity, start);
+
+        stats.streams_received = 1;
+        stats.bytes_received = 100;
+        session.refresh(&stats, later);
+        assert_eq!(session.last_activity, later);
+
+        session.refresh(&stats, later + Duration::from_secs(1));
+        assert_eq!(session.last_activity, later);
+        assert_eq!(session.connected_at, start);
+    }
+}
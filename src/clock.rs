@@ -0,0 +1,34 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Telling the time timeouts and ages are measured against, see `Builder::with_clock`.
+//!
+//! Timers still tick in real time. The clock only tells the time they check against: how long
+//! clients have been idle, how long peers have been quiet, how long ago peers were added to the
+//! bootstrap cache and how many messages were forwarded within the current second. A test driving
+//! a `test_utils::ManualClock` thus sees a timeout pass as soon as the next check runs, however
+//! long the timeout is.
+
+use std::time::Instant;
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+/// The clock of the system, used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
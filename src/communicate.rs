@@ -8,38 +8,81 @@
 // Software.
 
 use crate::bootstrap_cache::BootstrapCache;
+use crate::client_session::Session;
+use crate::config::OurType;
 use crate::connection::{Connection, FromPeer, QConn, ToPeer};
-use crate::context::{ctx, ctx_mut};
+use crate::context::{ctx, ctx_mut, Context};
 use crate::error::Error;
-use crate::event::Event;
+use crate::event::{Channel, ConnectionDirection, Event};
+use crate::event_sender::EventSender;
+use crate::expiry;
+use crate::forwarding;
+use crate::gossip;
+use crate::nat;
+use crate::outcome;
+use crate::probe;
+use crate::pubsub;
+use crate::reachability;
+use crate::send_queue::Queuing;
+use crate::stats::StatsHandle;
+use crate::transport::IncomingMsgs;
 use crate::utils;
-use crate::wire_msg::{Handshake, WireMsg};
-use crate::{connect, NodeInfo};
-use crate::{Peer, R};
-use crossbeam_channel as mpmc;
+use crate::wire_msg::{
+    self, Direction, Handshake, WireEncoding, WireMsg, PROTOCOL_VERSION,
+    PROTOCOL_VERSION_REFUSED_CODE,
+};
+use crate::Peer;
+use crate::{connect, Capabilities, NodeInfo};
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::mpsc;
-use tokio::prelude::{Future, Stream};
+use std::time::Instant;
+use tokio::prelude::{future, Future, Stream};
 use tokio::runtime::current_thread;
 
+/// How a user message is delivered, see `QuicP2p::send_with_delivery`.
+///
+/// Both modes are reliable. There is no unreliable mode sending messages as QUIC datagrams, as
+/// our QUIC implementation doesn't support the datagram extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Reliably, and in order with the other messages sent this way to the peer, on the stream
+    /// they share. A message held up by packet loss holds up those after it too.
+    Ordered,
+    /// Reliably, on a stream of its own, so that it may overtake and be overtaken by the others.
+    Unordered,
+}
+
 /// Send message to peer. If the peer is a node and is not connected, it will attempt to connect to
 /// it first and then send the message. For un-connected clients, it'll simply error out.
 pub fn try_write_to_peer(peer: Peer, msg: WireMsg) {
+    try_write_to_peer_with(peer, msg, None)
+}
+
+/// Send message to peer like `try_write_to_peer`, in the given mode rather than the one of the
+/// connection.
+pub fn try_write_to_peer_with(peer: Peer, msg: WireMsg, mode: Option<DeliveryMode>) {
     let node_info = match peer {
-        Peer::Client { peer_addr } => return write_to_peer(peer_addr, msg),
+        Peer::Client { peer_addr } => return write_to_peer_with(peer_addr, msg, mode),
         Peer::Node { node_info } => node_info,
     };
 
     let connect_and_send = ctx_mut(|c| {
         let peer_addr = node_info.peer_addr;
         let event_tx = c.event_tx.clone();
+        let ordered_delivery = c.ordered_delivery;
+        let queuing = Queuing {
+            limit: c.send_queue_limit,
+            lifecycle_events: c.lifecycle_events,
+            event_tx: &c.event_tx,
+        };
         let conn = c
             .connections
             .entry(peer_addr)
             .or_insert_with(|| Connection::new(peer_addr, event_tx, None));
 
-        match conn.to_peer {
-            ToPeer::NoConnection => Some(msg),
+        let rejected = match conn.to_peer {
+            ToPeer::NoConnection => return Some((msg, mode)),
             ToPeer::NotNeeded => {
                 warn!("TODO We normally can't get here - ignoring");
                 None
@@ -53,14 +96,18 @@ pub fn try_write_to_peer(peer: Peer, msg: WireMsg) {
                     info!("TODO Certificate we have for the peer already doesn't match with the \
                     one given - we should disconnect to such peers - something fishy going on.");
                 }
-                pending_sends.push(msg);
-                None
+                queuing.push(peer_addr, pending_sends, (msg, mode))
             }
             ToPeer::Established { ref q_conn, .. } => {
-                write_to_peer_connection(node_info.peer_addr, q_conn, msg);
+                let mode = delivery_mode(conn, ordered_delivery, &msg, mode);
+                write(node_info.peer_addr, q_conn, msg, mode);
                 None
             }
+        };
+        if let Some(msg) = rejected {
+            outcome::queue_full(c, peer_addr, msg);
         }
+        None
     });
 
     if connect_and_send.is_some() {
@@ -75,18 +122,30 @@ pub fn try_write_to_peer(peer: Peer, msg: WireMsg) {
 }
 
 /// This will fail if we don't have a connection to the peer or if the peer is in an invalid state
-/// to be sent a message to.
+/// to be sent a message to. User messages for unknown peers are reported as unsent.
 pub fn write_to_peer(peer_addr: SocketAddr, msg: WireMsg) {
-    ctx(|c| {
-        let conn = match c.connections.get(&peer_addr) {
-            Some(conn) => conn,
-            None => return trace!("Asked to communicate with an unknown peer: {}", peer_addr),
-        };
+    write_to_peer_with(peer_addr, msg, None)
+}
+
+/// Write to the peer like `write_to_peer`, in the given mode rather than the one of the
+/// connection.
+pub fn write_to_peer_with(peer_addr: SocketAddr, msg: WireMsg, mode: Option<DeliveryMode>) {
+    ctx_mut(|c| {
+        if !c.connections.contains_key(&peer_addr) {
+            trace!("Asked to communicate with an unknown peer: {}", peer_addr);
+            if let Some(msg) = msg.user_msg().cloned() {
+                outcome::unsent(c, peer_addr, msg);
+            }
+            return;
+        }
+        let conn = &c.connections[&peer_addr];
+
+        let mode = delivery_mode(conn, c.ordered_delivery, &msg, mode);
 
         match &conn.to_peer {
             ToPeer::NotNeeded => {
                 if let FromPeer::Established { ref q_conn, .. } = conn.from_peer {
-                    write_to_peer_connection(peer_addr, q_conn, msg);
+                    write(peer_addr, q_conn, msg, mode);
                 } else {
                     debug!(
                         "TODO We cannot communicate with someone we are not needing to connect to \
@@ -95,9 +154,7 @@ pub fn write_to_peer(peer_addr: SocketAddr, msg: WireMsg) {
                     );
                 }
             }
-            ToPeer::Established { ref q_conn, .. } => {
-                write_to_peer_connection(peer_addr, q_conn, msg)
-            }
+            ToPeer::Established { ref q_conn, .. } => write(peer_addr, q_conn, msg, mode),
             ToPeer::NoConnection | ToPeer::Initiated { .. } => {
                 return debug!(
                     "Peer {} is in invalid state {:?} to be communicated to",
@@ -108,93 +165,164 @@ pub fn write_to_peer(peer_addr: SocketAddr, msg: WireMsg) {
     })
 }
 
+/// Push a message to a client connected to us on the stream kept open for the pushes to it, see
+/// `QuicP2p::push`.
+pub fn push_to_client(peer_addr: SocketAddr, msg: WireMsg) {
+    let unsent_msg = ctx(|c| {
+        let conn = match c.connections.get(&peer_addr) {
+            Some(conn) => conn,
+            None => return Some(msg),
+        };
+        match (&conn.to_peer, &conn.from_peer) {
+            (ToPeer::NotNeeded, FromPeer::Established { ref q_conn, .. }) => {
+                write(peer_addr, q_conn, msg, DeliveryMode::Ordered);
+                None
+            }
+            _ => Some(msg),
+        }
+    });
+
+    if let Some(msg) = unsent_msg.as_ref().and_then(WireMsg::user_msg).cloned() {
+        debug!("Can't push to {} which is not a client of ours", peer_addr);
+        ctx_mut(|c| outcome::unsent(c, peer_addr, msg));
+    }
+}
+
 /// Write to the peer, given the QUIC connection to it
 pub fn write_to_peer_connection(peer_addr: SocketAddr, conn: &QConn, wire_msg: WireMsg) {
-    let user_msg = if let WireMsg::UserMsg(ref m) = wire_msg {
-        Some(m.clone())
-    } else {
-        None
-    };
-    let user_msg0 = user_msg.clone();
-    let user_msg1 = user_msg.clone();
-
-    let leaf = conn
-        .open_uni()
-        .map_err(move |e| {
-            utils::handle_communication_err(
-                peer_addr,
-                &From::from(e),
-                "Open-Unidirectional",
-                user_msg,
-            )
-        })
-        .and_then(move |o_stream| {
-            tokio::io::write_all(o_stream, wire_msg.into()).map_err(move |e| {
-                utils::handle_communication_err(peer_addr, &From::from(e), "Write-All", user_msg0)
-            })
-        })
-        .and_then(move |(o_stream, _): (_, bytes::Bytes)| {
-            tokio::io::shutdown(o_stream).map_err(move |e| {
-                utils::handle_communication_err(
-                    peer_addr,
-                    &From::from(e),
-                    "Shutdown-after-write",
-                    user_msg1,
-                )
-            })
-        })
-        .map(|_| ());
+    write(peer_addr, conn, wire_msg, DeliveryMode::Unordered)
+}
 
-    current_thread::spawn(leaf);
+/// Mode the message is delivered in: the given one for user messages, or else the one
+/// `Connection::ordered_delivery` or `ordered_by_default` asks for. Other messages go on streams
+/// of their own.
+pub fn delivery_mode(
+    conn: &Connection,
+    ordered_by_default: bool,
+    msg: &WireMsg,
+    mode: Option<DeliveryMode>,
+) -> DeliveryMode {
+    match (msg.user_msg(), mode) {
+        (Some(_), Some(mode)) => mode,
+        (Some(_), None) if conn.ordered_delivery.unwrap_or(ordered_by_default) => {
+            DeliveryMode::Ordered
+        }
+        _ => DeliveryMode::Unordered,
+    }
 }
 
-/// Listen for incoming streams containing peer messages and read them when available
-pub fn read_from_peer(peer_addr: SocketAddr, incoming_streams: quinn::IncomingStreams) {
-    let leaf = incoming_streams
+/// Write to the peer on a stream of its own, or on the push stream for ordered delivery if the
+/// encoding of the connection offers push streams.
+pub fn write(peer_addr: SocketAddr, conn: &QConn, wire_msg: WireMsg, mode: DeliveryMode) {
+    wire_msg::inspect(&Direction::Outgoing(peer_addr), &wire_msg);
+
+    let user_msg = wire_msg.user_msg().cloned();
+
+    let delivered_msg = user_msg.clone();
+    let push = mode == DeliveryMode::Ordered;
+    let channel = wire_msg.channel();
+    let raw = wire_msg.encode(conn.encoding());
+    let raw_len = raw.len() as u64;
+    let stats = conn.stats().clone();
+    #[cfg(feature = "otel")]
+    let span = crate::otel::send_span(peer_addr, raw_len);
+
+    let sending = if push && conn.encoding().offers_push_streams() {
+        conn.push(channel, raw)
+    } else {
+        conn.send(raw)
+    };
+    #[cfg(feature = "chaos")]
+    let sending = crate::chaos::send(sending);
+    let details = if push { "Push" } else { "Send" };
+
+    let leaf = expiry::guard(peer_addr, delivered_msg.clone(), sending)
         .map_err(move |e| {
-            utils::handle_communication_err(
-                peer_addr,
-                &From::from(e),
-                "Incoming streams failed",
-                None,
-            );
+            utils::handle_communication_err(peer_addr, &send_err(peer_addr, e), details, user_msg)
         })
-        .for_each(move |quic_stream| {
-            read_peer_stream(peer_addr, quic_stream).map_err(|e| {
-                debug!(
-                    "Error in Incoming-streams while reading from peer {}: {:?} - {}.",
-                    peer_addr, e, e
-                )
-            })
+        .then(move |r| {
+            #[cfg(feature = "otel")]
+            crate::otel::end_send_span(span, raw_len, r == Ok(true));
+
+            let mut stats = stats.borrow_mut();
+            match r {
+                Ok(true) => {
+                    stats.streams_sent += 1;
+                    stats.bytes_sent += raw_len;
+                    if let Some(ref msg) = delivered_msg {
+                        ctx_mut(|c| outcome::written(c, peer_addr, msg));
+                    }
+                }
+                // Expired, which was reported already
+                Ok(false) => (),
+                Err(()) => stats.streams_failed += 1,
+            }
+            Ok(())
         });
 
     current_thread::spawn(leaf);
 }
 
-fn read_peer_stream(peer_addr: SocketAddr, quic_stream: quinn::NewStream) -> R<()> {
-    let i_stream = match quic_stream {
-        quinn::NewStream::Bi(_, _) => {
-            let e = Error::BiDirectionalStreamAttempted(peer_addr);
-            utils::handle_communication_err(peer_addr, &e, "Receiving Stream", None);
-            return Err(e);
-        }
-        quinn::NewStream::Uni(uni) => uni,
-    };
+fn send_err(peer_addr: SocketAddr, source: Error) -> Error {
+    Error::Send {
+        peer_addr,
+        source: Box::new(source),
+    }
+}
 
-    let leaf = i_stream
-        .read_to_end(ctx(|c| c.max_msg_size_allowed))
+/// Read the messages the peer sends us on the connection as they arrive
+pub fn read_from_peer(
+    peer_addr: SocketAddr,
+    incoming_msgs: IncomingMsgs,
+    stats: StatsHandle,
+    encoding: WireEncoding,
+) {
+    let conn_stats = stats.clone();
+    let leaf = incoming_msgs
         .map_err(move |e| {
-            utils::handle_communication_err(peer_addr, &From::from(e), "Read-To-End", None)
+            let is_superseded = ctx_mut(|c| match c.connections.get_mut(&peer_addr) {
+                Some(conn) => conn.forget_superseded(&conn_stats),
+                None => false,
+            });
+            if is_superseded {
+                debug!(
+                    "Superseded connection from peer {} ended: {:?} - {}",
+                    peer_addr, e, e
+                );
+            } else {
+                utils::handle_communication_err(peer_addr, &e, "Incoming messages failed", None);
+            }
         })
-        .and_then(move |(_i_stream, raw)| {
-            WireMsg::from_raw(raw)
-                .map_err(|e| utils::handle_communication_err(peer_addr, &e, "Raw to WireMsg", None))
-                .map(|wire_msg| handle_wire_msg(peer_addr, wire_msg))
+        .for_each(move |raw| {
+            {
+                let mut stats = stats.borrow_mut();
+                stats.streams_received += 1;
+                stats.bytes_received += raw.len() as u64;
+            }
+            #[cfg(feature = "otel")]
+            crate::otel::record_received(raw.len() as u64);
+            match WireMsg::decode(raw, encoding) {
+                Ok(wire_msg) => {
+                    wire_msg::inspect(&Direction::Incoming(peer_addr), &wire_msg);
+                    handle_wire_msg(peer_addr, wire_msg);
+                    Ok(())
+                }
+                // Probably sent by a newer version of quic-p2p, which is no reason to drop the peer
+                Err(Error::UnknownWireMsg(msg_type)) => {
+                    debug!(
+                        "Ignoring wire message of unknown type {} from peer {}",
+                        msg_type, peer_addr
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    utils::handle_communication_err(peer_addr, &e, "Raw to WireMsg", None);
+                    Err(())
+                }
+            }
         });
 
     current_thread::spawn(leaf);
-
-    Ok(())
 }
 
 /// Handle wire messages from peer
@@ -213,6 +341,12 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                     }
                 };
 
+                if let WireMsg::IdentifiedUserMsg { id, .. } = wire_msg {
+                    if !conn.seen_msg_ids.insert(id) {
+                        return trace!("Dropping duplicate message {} from peer {}", id, peer_addr);
+                    }
+                }
+
                 match conn.from_peer {
                     // TODO see if repetition can be reduced
                     FromPeer::NotNeeded => match conn.to_peer {
@@ -230,6 +364,7 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                             let node_info = NodeInfo {
                                 peer_addr,
                                 peer_cert_der: peer_cert_der.clone(),
+                                alt_addrs: Vec::new(),
                             };
                             dispatch_wire_msg(
                                 node_info.into(),
@@ -265,6 +400,7 @@ pub fn handle_wire_msg(peer_addr: SocketAddr, wire_msg: WireMsg) {
                             let node_info = NodeInfo {
                                 peer_addr,
                                 peer_cert_der: peer_cert_der.clone(),
+                                alt_addrs: Vec::new(),
                             };
                             dispatch_wire_msg(
                                 node_info.into(),
@@ -293,26 +429,146 @@ pub fn dispatch_wire_msg(
     peer: Peer,
     q_conn: &QConn,
     inform_tx: Option<mpsc::Sender<SocketAddr>>,
-    event_tx: &mpmc::Sender<Event>,
+    event_tx: &EventSender,
     wire_msg: WireMsg,
     bootstrap_cache: &mut BootstrapCache,
     we_contacted_peer: bool,
 ) {
+    let channel = wire_msg.channel();
     match wire_msg {
-        WireMsg::UserMsg(m) => {
-            handle_user_msg(peer, event_tx, m, bootstrap_cache, we_contacted_peer)
+        WireMsg::UserMsg(m)
+        | WireMsg::IdentifiedUserMsg { msg: m, .. }
+        | WireMsg::ChannelMsg { msg: m, .. } => {
+            let _ = handle_user_msg(
+                peer,
+                event_tx,
+                m,
+                channel,
+                bootstrap_cache,
+                we_contacted_peer,
+            );
+        }
+        WireMsg::AckedUserMsg { id, msg } => {
+            let peer_addr = peer.peer_addr();
+            if handle_user_msg(
+                peer,
+                event_tx,
+                msg,
+                channel,
+                bootstrap_cache,
+                we_contacted_peer,
+            ) {
+                write_to_peer_connection(peer_addr, q_conn, WireMsg::UserMsgAck { id });
+            }
+        }
+        // We are called with the context borrowed, so take note of it once it is released
+        WireMsg::UserMsgAck { id } => outcome::acked_later(peer.peer_addr(), id),
+        WireMsg::Ping { nonce } => {
+            write_to_peer_connection(peer.peer_addr(), q_conn, WireMsg::Pong { nonce })
+        }
+        WireMsg::Pong { nonce } => {
+            let peer_addr = peer.peer_addr();
+            let at = Instant::now();
+            // We are called with the context borrowed, so take note of it once it is released
+            current_thread::spawn(future::lazy(move || {
+                probe::answered(peer_addr, nonce, at);
+                Ok(())
+            }));
         }
         WireMsg::EndpointEchoReq => handle_echo_req(peer.peer_addr(), q_conn),
-        WireMsg::EndpointEchoResp(our_addr) => handle_echo_resp(our_addr, inform_tx),
+        WireMsg::EndpointEchoResp(our_addr) => {
+            handle_echo_resp(peer.peer_addr(), our_addr, inform_tx)
+        }
+        WireMsg::ConnectBackReq(node_info) => handle_connect_back_req(peer.peer_addr(), node_info),
+        WireMsg::ForwardReq { to, msg } => {
+            let from = peer.peer_addr();
+            // We are called with the context borrowed, so forward once it is released
+            current_thread::spawn(future::lazy(move || {
+                forwarding::forward(from, to, msg);
+                Ok(())
+            }));
+        }
+        WireMsg::ForwardedMsg { from, msg } => {
+            let forwarded_msg = Event::ForwardedMessage {
+                relay_addr: peer.peer_addr(),
+                peer_addr: from,
+                msg,
+            };
+            if let Err(e) = event_tx.send(forwarded_msg) {
+                info!("Could not dispatch forwarded message to user: {:?}", e);
+            }
+        }
+        WireMsg::ReachabilityReq(node_info) => {
+            let requester = peer.peer_addr();
+            // We are called with the context borrowed, so dial once it is released
+            current_thread::spawn(future::lazy(move || {
+                reachability::dial_back(requester, node_info);
+                Ok(())
+            }));
+        }
+        WireMsg::ReachabilityResp { addr, reachable } => {
+            let event = Event::ReachabilityChecked {
+                via_peer: peer.peer_addr(),
+                addr,
+                reachable,
+            };
+            if let Err(e) = event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+        WireMsg::Subscription { topic, subscribed } => {
+            let peer_addr = peer.peer_addr();
+            // We are called with the context borrowed, so handle it once it is released
+            current_thread::spawn(future::lazy(move || {
+                pubsub::handle_subscription(peer_addr, topic, subscribed);
+                Ok(())
+            }));
+        }
+        WireMsg::TopicMsg {
+            topic,
+            id,
+            hops_left,
+            msg,
+        } => {
+            let peer_addr = peer.peer_addr();
+            current_thread::spawn(future::lazy(move || {
+                pubsub::handle_topic_msg(peer_addr, topic, id, hops_left, msg);
+                Ok(())
+            }));
+        }
+        WireMsg::Gossip { id, ttl, msg } => {
+            let peer_addr = peer.peer_addr();
+            current_thread::spawn(future::lazy(move || {
+                gossip::handle_gossip(peer_addr, id, ttl, msg);
+                Ok(())
+            }));
+        }
+        WireMsg::GossipDigest { ids, reply } => {
+            let peer_addr = peer.peer_addr();
+            current_thread::spawn(future::lazy(move || {
+                gossip::handle_digest(peer_addr, ids, reply);
+                Ok(())
+            }));
+        }
         WireMsg::Handshake(_) => unreachable!("Should have been handled already"),
     }
 }
 
 fn handle_rx_handshake(peer_addr: SocketAddr, handshake: Handshake) {
-    if let Handshake::Node { cert_der } = handshake {
-        return handle_rx_cert(peer_addr, cert_der);
+    let protocol_version = handshake.protocol_version();
+    if !ctx(|c| c.protocol_versions.contains(&protocol_version)) {
+        return refuse_protocol_version(peer_addr, protocol_version);
     }
 
+    let capabilities = match handshake {
+        Handshake::Node {
+            cert_der,
+            capabilities,
+            ..
+        } => return handle_rx_cert(peer_addr, cert_der, capabilities),
+        Handshake::Client { capabilities, .. } => capabilities,
+    };
+
     // Handshake from a client
     ctx_mut(|c| {
         let conn = match c.connections.get_mut(&peer_addr) {
@@ -327,6 +583,11 @@ fn handle_rx_handshake(peer_addr: SocketAddr, handshake: Handshake) {
 
         match conn.to_peer {
             ToPeer::NoConnection => (),
+            ToPeer::Established { .. } if conn.from_peer.is_not_needed() => {
+                // The node we connected to as a client telling us its version, accepted above
+                trace!("Rxd the handshake reply of node: {}", peer_addr);
+                return;
+            }
             ToPeer::NotNeeded | ToPeer::Initiated { .. } | ToPeer::Established { .. } => {
                 // TODO consider booting this peer out
                 debug!(
@@ -338,19 +599,68 @@ fn handle_rx_handshake(peer_addr: SocketAddr, handshake: Handshake) {
         }
 
         conn.to_peer = ToPeer::NotNeeded;
+        conn.client_session = Some(Session::new(c.clock.now()));
+        conn.peer_capabilities = capabilities.clone();
+
+        // Clients get no handshake of ours otherwise, as we don't connect back to them, so tell
+        // them our version for them to refuse it if they don't accept it
+        if let FromPeer::Established { ref q_conn, .. } = conn.from_peer {
+            write_to_peer_connection(
+                peer_addr,
+                q_conn,
+                WireMsg::Handshake(Handshake::Client {
+                    capabilities: c.our_capabilities.clone(),
+                    protocol_version: PROTOCOL_VERSION,
+                }),
+            );
+        }
 
         let peer = Peer::Client { peer_addr };
 
-        if let Err(e) = c.event_tx.send(Event::ConnectedTo { peer }) {
+        if let Err(e) = c.event_tx.send(Event::ConnectedTo {
+            peer,
+            direction: ConnectionDirection::Incoming,
+            capabilities,
+        }) {
             info!("ERROR in informing user about a new peer: {:?} - {}", e, e);
         }
+        connect::established_later(peer_addr);
+    })
+}
+
+/// Drop the peer which handshook with a version of the wire protocol we don't accept, telling it
+/// so with `PROTOCOL_VERSION_REFUSED_CODE` on whichever connection we have with it, be it one the
+/// peer made or, with nodes replying to the handshake of us as a client, one we made.
+fn refuse_protocol_version(peer_addr: SocketAddr, protocol_version: u16) {
+    debug!(
+        "Refusing peer {} with version {} of the wire protocol",
+        peer_addr, protocol_version
+    );
+    ctx_mut(|c| {
+        let mut conn = match c.connections.remove(&peer_addr) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let accepted = format!(
+            "{}-{}",
+            c.protocol_versions.start(),
+            c.protocol_versions.end()
+        );
+        if let ToPeer::Established { ref q_conn, .. } = conn.to_peer {
+            q_conn.close_with(PROTOCOL_VERSION_REFUSED_CODE, accepted.as_bytes());
+        }
+        if let FromPeer::Established { ref q_conn, .. } = conn.from_peer {
+            q_conn.close_with(PROTOCOL_VERSION_REFUSED_CODE, accepted.as_bytes());
+        }
+        conn.cancel_reason = Some(Error::ProtocolVersionRefused(protocol_version));
     })
 }
 
-fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
+fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>, capabilities: Capabilities) {
     let node_info = NodeInfo {
         peer_addr,
         peer_cert_der,
+        alt_addrs: Vec::new(),
     };
 
     let reverse_connect_to_peer = ctx_mut(|c| {
@@ -372,13 +682,26 @@ fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
         };
 
         match conn.to_peer {
-            ToPeer::NoConnection => true,
+            ToPeer::NoConnection => {
+                conn.peer_capabilities = capabilities;
+                true
+            }
             ToPeer::NotNeeded => {
-                info!(
-                    "TODO received a Node handshake from someone who has introduced oneself \
-                     as a client before."
-                );
-                false
+                // A client promoted to a node, see `QuicP2p::promote_to_node`. Without a
+                // certificate to reverse connect with it stays a client.
+                let peer_cert = rustls::Certificate(node_info.peer_cert_der.clone());
+                if rustls::RootCertStore::empty().add(&peer_cert).is_err() {
+                    debug!(
+                        "Ignoring Node handshake without a valid certificate from client {}",
+                        peer_addr
+                    );
+                    return false;
+                }
+                debug!("Client {} introduced itself as a node", peer_addr);
+                conn.to_peer = ToPeer::NoConnection;
+                conn.client_session = None;
+                conn.peer_capabilities = capabilities;
+                true
             }
             ToPeer::Initiated {
                 ref peer_cert_der, ..
@@ -406,24 +729,86 @@ fn handle_rx_cert(peer_addr: SocketAddr, peer_cert_der: Vec<u8>) {
     }
 }
 
+/// Once our connection to the node that reached us from `peer_addr` is established, and so the
+/// node proved to hold the certificate it introduced itself with, take it to have moved here if
+/// we are connected to it at another address, see `Event::PeerAddressChanged`.
+pub fn follow_moved_peer(c: &mut Context, peer_addr: SocketAddr) {
+    let peer_cert_der = match c.connections.get(&peer_addr) {
+        Some(conn) => match (&conn.to_peer, &conn.from_peer) {
+            (
+                ToPeer::Initiated {
+                    ref peer_cert_der, ..
+                },
+                FromPeer::Established { .. },
+            ) => peer_cert_der.clone(),
+            _ => return,
+        },
+        None => return,
+    };
+    let old_addr = match c.connections.iter().find(|&(&addr, conn)| {
+        addr != peer_addr
+            && match conn.to_peer {
+                ToPeer::Established {
+                    peer_cert_der: ref cert_der,
+                    ..
+                } => *cert_der == peer_cert_der,
+                _ => false,
+            }
+    }) {
+        Some((&addr, _)) => addr,
+        None => return,
+    };
+    debug!("Peer {} moved to {}", old_addr, peer_addr);
+
+    let mut old_conn = unwrap!(c.connections.remove(&old_addr));
+    old_conn.has_moved = true;
+    let we_contacted_peer = old_conn.we_contacted_peer;
+    let topics = mem::take(&mut old_conn.topics);
+    drop(old_conn);
+
+    c.bootstrap_cache.move_peer(
+        old_addr,
+        NodeInfo {
+            peer_addr,
+            peer_cert_der,
+            alt_addrs: Vec::new(),
+        },
+    );
+    if let Some(conn) = c.connections.get_mut(&peer_addr) {
+        conn.moved_from = Some(old_addr);
+        conn.we_contacted_peer |= we_contacted_peer;
+        conn.topics.extend(topics);
+    }
+}
+
 fn handle_user_msg(
     peer: Peer,
-    event_tx: &mpmc::Sender<Event>,
+    event_tx: &EventSender,
     msg: bytes::Bytes,
+    channel: Channel,
     bootstrap_cache: &mut BootstrapCache,
     we_contacted_peer: bool,
-) {
+) -> bool {
     let peer_addr = peer.peer_addr();
-    let new_msg = Event::NewMessage { peer_addr, msg };
-    if let Err(e) = event_tx.send(new_msg) {
-        info!("Could not dispatch incoming user message: {:?}", e);
-    }
+    let new_msg = Event::NewMessage {
+        peer_addr,
+        msg,
+        channel,
+    };
+    let delivered = match event_tx.deliver(new_msg) {
+        Ok(delivered) => delivered,
+        Err(e) => {
+            info!("Could not dispatch incoming user message: {:?}", e);
+            false
+        }
+    };
 
     if let Peer::Node { node_info } = peer {
         if we_contacted_peer {
             bootstrap_cache.add_peer(node_info);
         }
     }
+    delivered
 }
 
 fn handle_echo_req(peer_addr: SocketAddr, q_conn: &QConn) {
@@ -431,22 +816,70 @@ fn handle_echo_req(peer_addr: SocketAddr, q_conn: &QConn) {
     write_to_peer_connection(peer_addr, q_conn, msg);
 }
 
-fn handle_echo_resp(our_ext_addr: SocketAddr, inform_tx: Option<mpsc::Sender<SocketAddr>>) {
+fn handle_echo_resp(
+    peer_addr: SocketAddr,
+    our_ext_addr: SocketAddr,
+    inform_tx: Option<mpsc::Sender<SocketAddr>>,
+) {
     if let Some(tx) = inform_tx {
         if let Err(e) = tx.send(our_ext_addr) {
             info!("Error informing endpoint echo service response: {:?}", e);
         }
     }
+    // We are called with the context borrowed, so pass it on once it is released
+    current_thread::spawn(future::lazy(move || {
+        nat::echoed(peer_addr, our_ext_addr);
+        Ok(())
+    }));
+}
+
+fn handle_connect_back_req(peer_addr: SocketAddr, node_info: NodeInfo) {
+    // Otherwise anyone could have us connect to whoever they like
+    if node_info.peer_addr.ip() != peer_addr.ip() {
+        return info!(
+            "Ignoring request from {} to connect back to a different host: {}",
+            peer_addr, node_info.peer_addr
+        );
+    }
+
+    // We are called with the context borrowed, so connect once it is released
+    current_thread::spawn(future::lazy(move || {
+        if ctx(|c| c.our_type) == OurType::Client {
+            debug!(
+                "Ignoring request from {} to connect back as we are a client",
+                peer_addr
+            );
+        } else {
+            let target_addr = node_info.peer_addr;
+            match connect::connect_to(node_info, None, None) {
+                Ok(()) => ctx_mut(|c| {
+                    if let Some(conn) = c.connections.get_mut(&target_addr) {
+                        conn.we_contacted_peer = true;
+                    }
+                }),
+                Err(e) => debug!(
+                    "Could not connect back as requested by {}: {:?}",
+                    peer_addr, e
+                ),
+            }
+        }
+        Ok(())
+    }));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::{new_random_qp2p, rand_node_info, test_dirs, write_to_bi_stream};
+    use crate::event::DEFAULT_CHANNEL;
+    #[cfg(not(feature = "client-only"))]
+    use crate::test_utils::{new_random_qp2p, write_to_bi_stream};
+    use crate::test_utils::{rand_node_info, test_dirs};
+    use crossbeam_channel as mpmc;
+    #[cfg(not(feature = "client-only"))]
     use std::collections::HashSet;
-    use std::error::Error as std_err;
 
     // Test for the case of bi-directional stream usage attempt.
+    #[cfg(not(feature = "client-only"))]
     #[test]
     fn disallow_bidirectional_streams() {
         let (mut qp2p0, rx0) = new_random_qp2p(false, Default::default());
@@ -472,7 +905,7 @@ mod tests {
         match rx0.recv() {
             Ok(Event::ConnectionFailure { peer_addr, err }) => {
                 assert_eq!(peer_addr, qp2p1_info.peer_addr);
-                assert_eq!(err.description(), Error::ConnectionCancelled.description());
+                assert_eq!(err.code(), Error::ConnectionCancelled.code());
             }
             r => panic!("Unexpected result {:?}", r),
         }
@@ -484,6 +917,7 @@ mod tests {
         #[test]
         fn when_peer_is_node_and_we_contacted_it_before_it_is_moved_to_bootstrap_cache_top() {
             let (event_tx, _event_rx) = mpmc::unbounded();
+            let event_tx = EventSender::new(event_tx, Default::default());
             let peer1 = rand_node_info();
             let peer2 = rand_node_info();
             let peer = Peer::Node {
@@ -494,10 +928,11 @@ mod tests {
             bootstrap_cache.add_peer(peer1.clone());
             bootstrap_cache.add_peer(peer2.clone());
 
-            handle_user_msg(
+            let _ = handle_user_msg(
                 peer,
                 &event_tx,
                 bytes::Bytes::from(vec![]),
+                DEFAULT_CHANNEL,
                 &mut bootstrap_cache,
                 true,
             );
@@ -9,34 +9,162 @@
 
 use crate::dirs::Dirs;
 use crate::error::Error;
+use crate::listener::SubnetLimits;
+use crate::peer_config::DEFAULT_IDLE_TIMEOUT_MSEC;
+use crate::persistence::{self, Payload};
+use crate::probe::{LivenessThresholds, DEFAULT_LIVENESS_PROBES};
 use crate::utils;
 use crate::{NodeInfo, R};
 use base64;
 use bincode;
+use serde::de::{self, Deserialize, Deserializer};
 use std::collections::HashSet;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{fmt, fs, io};
+use std::time::Duration;
+use std::{env, fmt, fs, io};
+
+/// Version of the config schema this crate writes, stored alongside the fields of config files.
+///
+/// Bump it whenever `Config` changes in a way files of the previous version couldn't be read as
+/// and teach `Config::from_file` to upgrade them.
+pub const CONFIG_VERSION: u32 = 12;
+
+/// Start of the payload of binary configs carrying a version, followed by the version as a little
+/// endian `u32`. Payloads without it are of version 0, see `ConfigV0`.
+const BINARY_MAGIC: &[u8; 4] = b"QPCF";
+
+/// Prefix of the environment variables overriding config fields, see
+/// `Config::apply_env_overrides`.
+pub const ENV_PREFIX: &str = "QUIC_P2P_";
+
+/// Fields which can be overridden by environment variables.
+const ENV_FIELDS: &[&str] = &[
+    "hard_coded_contacts",
+    "bootstrap_only_contacts",
+    "bootstrap_strategy",
+    "port",
+    "ip",
+    "additional_listen_addrs",
+    "bind_interface",
+    "external_address",
+    "socks5_proxy",
+    #[cfg(feature = "websocket")]
+    "websocket_port",
+    "max_msg_size_allowed",
+    "idle_timeout_msec",
+    "keep_alive_interval_msec",
+    "our_complete_cert",
+    "our_type",
+    "duplicate_connection_policy",
+    "ordered_delivery",
+    "min_protocol_version",
+    "max_protocol_version",
+    "connect_timeout_msec",
+    "node_liveness_msec",
+    "client_liveness_msec",
+    "liveness_probes",
+    "max_incoming_per_v4_subnet",
+    "max_incoming_per_v6_subnet",
+];
+
+/// Fields `QuicP2p::apply_config` changes at runtime. The others only take effect on restart.
+const RELOADABLE_FIELDS: &[&str] = &[
+    "hard_coded_contacts",
+    "bootstrap_only_contacts",
+    "bootstrap_strategy",
+    "external_address",
+    "max_msg_size_allowed",
+    "idle_timeout_msec",
+    "keep_alive_interval_msec",
+    "duplicate_connection_policy",
+    "ordered_delivery",
+    "min_protocol_version",
+    "max_protocol_version",
+    "connect_timeout_msec",
+    "max_incoming_per_v4_subnet",
+    "max_incoming_per_v6_subnet",
+];
+
+/// Outcome of `QuicP2p::apply_config`, naming the fields which changed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConfigChanges {
+    /// Fields applied without restarting.
+    pub applied: Vec<&'static str>,
+    /// Fields left as they were, as they only take effect on restart.
+    pub needs_restart: Vec<&'static str>,
+}
 
 /// QuicP2p configurations
+///
+/// Fields left out of TOML and JSON config files take their default values. Files written by
+/// older versions of this crate are upgraded on reading, see `CONFIG_VERSION`.
 #[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq, StructOpt)]
+#[serde(default)]
 #[structopt(rename_all = "kebab-case")]
 pub struct Config {
-    /// Hard Coded contacts
+    /// Hard Coded contacts. On the command line either a JSON array or a comma separated list of
+    /// contacts in their compact form `<peer_addr>/<base64 encoded peer_cert_der>` or as
+    /// `quic-p2p://<host>:<port>?cert=<base64url encoded peer_cert_der>` URLs. With the
+    /// `multiaddr` feature `<peer_addr>` may be a multiaddr like `/ip4/127.0.0.1/udp/5000/quic`.
+    /// TOML and JSON config files accept these textual forms in place of `NodeInfo` tables too.
+    #[serde(deserialize_with = "deserialize_contacts")]
     #[structopt(
         short,
         long,
         default_value = "[]",
-        parse(try_from_str = "serde_json::from_str")
+        parse(try_from_str = "parse_contacts")
     )]
     pub hard_coded_contacts: HashSet<NodeInfo>,
+    /// Contacts to bootstrap off like `hard_coded_contacts` but not to keep as peers, e.g. seed
+    /// nodes which shouldn't carry traffic. Once we bootstrapped off one of them its connection
+    /// is closed right after `Event::BootstrappedTo` and it isn't added to the bootstrap cache.
+    /// They take the same forms as `hard_coded_contacts`.
+    #[serde(deserialize_with = "deserialize_contacts")]
+    #[structopt(long, default_value = "[]", parse(try_from_str = "parse_contacts"))]
+    pub bootstrap_only_contacts: HashSet<NodeInfo>,
+    /// Which of the bootstrap sources to try first, the bootstrap cache or the contacts of the
+    /// config, or whether to ignore the cache altogether.
+    #[structopt(long, default_value = "cache-first")]
+    pub bootstrap_strategy: BootstrapStrategy,
     /// Port we want to reserve for QUIC. If none supplied we'll use the OS given random port.
     #[structopt(short, long)]
     pub port: Option<u16>,
     /// IP address for the listener. If none supplied we'll use the default address (0.0.0.0).
+    /// Use `::` to listen on both IPv4 and IPv6.
     #[structopt(long)]
     pub ip: Option<IpAddr>,
+    /// Further addresses to listen on, e.g. the LAN interface of a node listening on its public
+    /// one at `ip`. Connections made to any of them end up with the same peers and events. Port 0
+    /// picks a random port, see `QuicP2p::our_listen_addrs`.
+    #[structopt(long)]
+    pub additional_listen_addrs: Vec<SocketAddr>,
+    /// Network interface to listen on by name, e.g. `eth1` or `wg0`, in place of `ip`. Its
+    /// addresses are looked up at startup: we listen on the first IPv4 one, or the first IPv6 one
+    /// if it has none, and on the others as on `additional_listen_addrs`. On Linux our sockets
+    /// moreover only take the traffic of the interface where we are allowed to restrict them.
+    #[structopt(long)]
+    pub bind_interface: Option<String>,
+    /// Address to advertise in our connection info as it is, e.g. the public address of a node
+    /// with a static IP or behind a port forward. If none supplied we ask the first of the
+    /// `hard_coded_contacts` for the address it sees us at, or else fall back to the address we
+    /// are bound to.
+    #[structopt(long)]
+    pub external_address: Option<SocketAddr>,
+    /// SOCKS5 proxy with UDP support to relay our outgoing connections through, e.g. Tor or a
+    /// corporate egress proxy. Connections peers make to us don't go through the proxy.
+    #[structopt(long)]
+    pub socks5_proxy: Option<SocketAddr>,
+    /// Port to accept WebSocket connections of browser clients on, at the IP address of the
+    /// listener. Only nodes accept them. If none supplied browser clients can't connect to us.
+    #[cfg(feature = "websocket")]
+    #[structopt(long)]
+    pub websocket_port: Option<u16>,
     /// This is the maximum message size we'll allow the peer to send to us. Any bigger message and
     /// we'll error out probably shutting down the connection to the peer. If none supplied we'll
     /// default to the documented constant.
@@ -61,17 +189,98 @@ pub struct Config {
     /// Specify if we are a client or a node
     #[structopt(short = "t", long, default_value = "node")]
     pub our_type: OurType,
+    /// Which connection to keep if a peer connects to us while its previous connection to us is
+    /// still up, e.g. after restarting on the same address.
+    #[structopt(long, default_value = "keep-existing")]
+    pub duplicate_connection_policy: DuplicateConnectionPolicy,
+    /// Send all user messages to a peer over a single stream kept open for them, so that they
+    /// arrive in the order they were sent in. Otherwise each message goes on a stream of its own
+    /// and may overtake those sent before it. Can be chosen per peer with
+    /// `QuicP2p::connect_to_with_delivery`. Connections with the `WireEncoding::Bincode` or
+    /// `WireEncoding::MessagePack` encodings have no such streams, so have their messages
+    /// reordered still.
+    #[structopt(long)]
+    pub ordered_delivery: bool,
+    /// Oldest version of the wire protocol accepted from peers, see `PROTOCOL_VERSION`. Peers
+    /// handshaking with an older one are refused with `PROTOCOL_VERSION_REFUSED_CODE`, be it those
+    /// connecting to us or the nodes we connect to, which reply to our handshake as a client with
+    /// theirs. If none supplied every version up to `max_protocol_version` is accepted.
+    #[structopt(long)]
+    pub min_protocol_version: Option<u16>,
+    /// Latest version of the wire protocol accepted from peers, refusing those handshaking with a
+    /// later one as `min_protocol_version` does older ones. If none supplied every version from
+    /// `min_protocol_version` on is accepted.
+    #[structopt(long)]
+    pub max_protocol_version: Option<u16>,
+    /// Time in milliseconds a connection we make has to be established in, so that dialing a
+    /// peer which doesn't answer fails without waiting for `idle_timeout_msec` to pass. If none
+    /// supplied the handshake times out with the idle timeout.
+    #[structopt(long)]
+    pub connect_timeout_msec: Option<u64>,
+    /// Time in milliseconds a node we are connected to may stay quiet, sending us nothing, before
+    /// we probe it, so that a peer gone without its connection closing, e.g. with its event loop
+    /// stuck while its transport still answers keep-alives, is noticed well before
+    /// `idle_timeout_msec` would be. Peers leaving `liveness_probes` probes unanswered are
+    /// disconnected. If none supplied nodes aren't probed. Only peers of this version answer
+    /// probes.
+    #[structopt(long)]
+    pub node_liveness_msec: Option<u64>,
+    /// Like `node_liveness_msec`, for the clients connected to us.
+    #[structopt(long)]
+    pub client_liveness_msec: Option<u64>,
+    /// Number of probes in a row a quiet peer has to leave unanswered to be disconnected, see
+    /// `node_liveness_msec`. The probes are a quarter of the liveness threshold apart. If none
+    /// supplied `DEFAULT_LIVENESS_PROBES` are sent.
+    #[structopt(long)]
+    pub liveness_probes: Option<u32>,
+    /// Most connections peers of the same /24 IPv4 subnet may have to us at once, so that a
+    /// single network can't take up all the connections of a public node. Further peers of the
+    /// subnet connecting to us are refused with `CONNECTION_LIMIT_CODE`, while connections we
+    /// make and reverse connections of the nodes we connect to aren't limited. If none supplied
+    /// peers aren't limited by their subnet.
+    #[structopt(long)]
+    pub max_incoming_per_v4_subnet: Option<u32>,
+    /// Like `max_incoming_per_v4_subnet`, for the peers of the same /48 IPv6 subnet.
+    #[structopt(long)]
+    pub max_incoming_per_v6_subnet: Option<u32>,
 }
 
 impl Config {
     /// Try and read the config off the disk first. If such a file-path doesn't exist it'll create
     /// a default one with random certificate and write that to the disk, eventually returning that
     /// config to the caller.
+    ///
+    /// A hand-written `config.toml` or `config.json` next to the default config file takes
+    /// precedence over it, in that order. The `QUIC_P2P_*` environment variables take precedence
+    /// over any file, see `apply_env_overrides`.
     pub fn read_or_construct_default(user_override: Option<&Dirs>) -> R<Config> {
+        let mut cfg = Self::read_or_construct_default_file(user_override)?;
+        cfg.apply_env_overrides()?;
+        Ok(cfg)
+    }
+
+    fn read_or_construct_default_file(user_override: Option<&Dirs>) -> R<Config> {
         let config_path = config_path(user_override)?;
 
+        for format in &[Format::Toml, Format::Json] {
+            let path = config_path.with_extension(unwrap!(format.extension()));
+            if path.exists() {
+                return Config::from_file(&path);
+            }
+        }
+
         if config_path.exists() {
-            Ok(utils::read_from_disk(&config_path)?)
+            let (cfg, version) = read_binary(&config_path)?;
+            if version < CONFIG_VERSION {
+                info!(
+                    "Upgrading config {} from version {} to {}",
+                    config_path.display(),
+                    version,
+                    CONFIG_VERSION
+                );
+                cfg.save_to(&config_path, Format::Binary)?;
+            }
+            Ok(cfg)
         } else {
             let config_dir = config_path
                 .parent()
@@ -80,12 +289,361 @@ impl Config {
             fs::create_dir_all(&config_dir)?;
 
             let cfg = Config::with_default_cert();
-            utils::write_to_disk(&config_path, &cfg)?;
+            cfg.save_to(&config_path, Format::Binary)?;
 
             Ok(cfg)
         }
     }
 
+    /// Read the config from the given file. The format is detected from the file extension, see
+    /// `Format::from_path`.
+    ///
+    /// Files of older versions are upgraded, files of newer ones are rejected with
+    /// `Error::Configuration`.
+    pub fn from_file(path: &Path) -> R<Config> {
+        match Format::from_path(path) {
+            Format::Binary => Ok(read_binary(path)?.0),
+            Format::Toml => {
+                let value: toml::Value = toml::from_str(&fs::read_to_string(path)?)?;
+                from_text(serde_json::to_value(value)?)
+            }
+            Format::Json => from_text(serde_json::from_reader(BufReader::new(File::open(path)?))?),
+        }
+    }
+
+    /// Write the config to the given file in the given format, marked with `CONFIG_VERSION`.
+    pub fn save_to(&self, path: &Path, format: Format) -> R<()> {
+        match format {
+            Format::Binary => {
+                let mut payload = BINARY_MAGIC.to_vec();
+                payload.extend_from_slice(&CONFIG_VERSION.to_le_bytes());
+                payload.extend_from_slice(&bincode::serialize(self)?);
+                persistence::write_payload(path, &payload)
+            }
+            Format::Toml => {
+                // Going through `toml::Value` orders plain values before tables, which TOML
+                // requires but serialising `Config` field by field wouldn't do.
+                let mut value = toml::Value::try_from(self)?;
+                if let Some(fields) = value.as_table_mut() {
+                    let _ = fields.insert("version".to_string(), i64::from(CONFIG_VERSION).into());
+                }
+                fs::write(path, toml::to_string_pretty(&value)?)?;
+                Ok(())
+            }
+            Format::Json => {
+                let mut value = serde_json::to_value(self)?;
+                if let Some(fields) = value.as_object_mut() {
+                    let _ = fields.insert("version".to_string(), CONFIG_VERSION.into());
+                }
+                serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &value)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Override fields with the values of the environment variables named after them, e.g.
+    /// `QUIC_P2P_PORT=5000` or `QUIC_P2P_OUR_TYPE=client`. The values take the forms of the
+    /// respective command line arguments, an empty value clears an optional field. Variables
+    /// with the `QUIC_P2P_` prefix not naming a field are ignored with a warning.
+    ///
+    /// `read_or_construct_default` applies them on top of the config file. Configs passed to
+    /// `Builder::with_config` are used as they are unless this is called on them first.
+    pub fn apply_env_overrides(&mut self) -> R<()> {
+        self.apply_overrides(env::vars())
+    }
+
+    fn apply_overrides<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) -> R<()> {
+        for (var, value) in vars {
+            let name = match var.strip_prefix(ENV_PREFIX) {
+                Some(name) => name.to_ascii_lowercase(),
+                None => continue,
+            };
+            let field = match ENV_FIELDS.iter().find(|field| **field == name) {
+                Some(field) => *field,
+                None => {
+                    warn!("Ignoring {} which doesn't name a config field", var);
+                    continue;
+                }
+            };
+
+            debug!("Config field {} overridden by {}", field, var);
+            self.apply_override(field, value.trim())
+                .map_err(|e| Error::Config {
+                    field,
+                    source: Box::new(e),
+                })?;
+        }
+        Ok(())
+    }
+
+    fn apply_override(&mut self, field: &str, value: &str) -> R<()> {
+        match field {
+            "hard_coded_contacts" => self.hard_coded_contacts = parse_contacts(value)?,
+            "bootstrap_only_contacts" => self.bootstrap_only_contacts = parse_contacts(value)?,
+            "bootstrap_strategy" => {
+                self.bootstrap_strategy = value.parse().map_err(Error::Configuration)?
+            }
+            "port" => self.port = parse_opt(value)?,
+            "ip" => self.ip = parse_opt(value)?,
+            "additional_listen_addrs" => self.additional_listen_addrs = parse_addrs(value)?,
+            "bind_interface" => self.bind_interface = parse_opt(value)?,
+            "external_address" => self.external_address = parse_opt(value)?,
+            "socks5_proxy" => self.socks5_proxy = parse_opt(value)?,
+            #[cfg(feature = "websocket")]
+            "websocket_port" => self.websocket_port = parse_opt(value)?,
+            "max_msg_size_allowed" => self.max_msg_size_allowed = parse_opt(value)?,
+            "idle_timeout_msec" => self.idle_timeout_msec = parse_opt(value)?,
+            "keep_alive_interval_msec" => self.keep_alive_interval_msec = parse_opt(value)?,
+            "our_complete_cert" => self.our_complete_cert = parse_opt(value)?,
+            "our_type" => self.our_type = value.parse().map_err(Error::Configuration)?,
+            "duplicate_connection_policy" => {
+                self.duplicate_connection_policy = value.parse().map_err(Error::Configuration)?
+            }
+            "ordered_delivery" => {
+                self.ordered_delivery = value
+                    .parse::<bool>()
+                    .map_err(|e| Error::Configuration(e.to_string()))?
+            }
+            "min_protocol_version" => self.min_protocol_version = parse_opt(value)?,
+            "max_protocol_version" => self.max_protocol_version = parse_opt(value)?,
+            "connect_timeout_msec" => self.connect_timeout_msec = parse_opt(value)?,
+            "node_liveness_msec" => self.node_liveness_msec = parse_opt(value)?,
+            "client_liveness_msec" => self.client_liveness_msec = parse_opt(value)?,
+            "liveness_probes" => self.liveness_probes = parse_opt(value)?,
+            "max_incoming_per_v4_subnet" => self.max_incoming_per_v4_subnet = parse_opt(value)?,
+            "max_incoming_per_v6_subnet" => self.max_incoming_per_v6_subnet = parse_opt(value)?,
+            _ => unreachable!("{} is not in ENV_FIELDS", field),
+        }
+        Ok(())
+    }
+
+    /// Check the fields are consistent with each other, rejecting settings which couldn't work
+    /// together with `Error::ConflictingSettings` and unusable values with
+    /// `Error::InvalidSetting`. `Builder::build` calls this before starting up.
+    pub fn validate(&self) -> R<()> {
+        if self.our_type == OurType::Client {
+            #[cfg(feature = "websocket")]
+            {
+                if self.websocket_port.is_some() {
+                    return Err(conflict(
+                        "websocket_port",
+                        "our_type",
+                        "only nodes accept browser clients",
+                    ));
+                }
+            }
+            if !self.additional_listen_addrs.is_empty() {
+                return Err(conflict(
+                    "additional_listen_addrs",
+                    "our_type",
+                    "clients don't accept connections, so have nothing to listen for",
+                ));
+            }
+        }
+        if self.bind_interface.is_some() && self.ip.is_some() {
+            return Err(conflict(
+                "bind_interface",
+                "ip",
+                "the addresses of the interface are listened on in place of `ip`",
+            ));
+        }
+        if let Some(keep_alive_interval_msec) = self.keep_alive_interval_msec {
+            let idle_timeout_msec = self.idle_timeout_msec.unwrap_or(DEFAULT_IDLE_TIMEOUT_MSEC);
+            if keep_alive_interval_msec != 0
+                && idle_timeout_msec != 0
+                && u64::from(keep_alive_interval_msec) >= idle_timeout_msec
+            {
+                return Err(conflict(
+                    "keep_alive_interval_msec",
+                    "idle_timeout_msec",
+                    format!(
+                        "keep-alives every {} ms can't keep connections timing out after {} ms \
+                         of idling open",
+                        keep_alive_interval_msec, idle_timeout_msec
+                    ),
+                ));
+            }
+        }
+        if !self
+            .hard_coded_contacts
+            .is_disjoint(&self.bootstrap_only_contacts)
+        {
+            return Err(conflict(
+                "bootstrap_only_contacts",
+                "hard_coded_contacts",
+                "a contact can't be both kept as a peer and not",
+            ));
+        }
+        if let Some(external_address) = self.external_address {
+            if external_address.port() == 0 || external_address.ip().is_unspecified() {
+                return Err(Error::InvalidSetting {
+                    setting: "external_address",
+                    reason: format!("peers can't reach us at {}", external_address),
+                });
+            }
+        }
+        if self.max_msg_size_allowed == Some(0) {
+            return Err(Error::InvalidSetting {
+                setting: "max_msg_size_allowed",
+                reason: "no message would be allowed".to_string(),
+            });
+        }
+        if self.connect_timeout_msec == Some(0) {
+            return Err(Error::InvalidSetting {
+                setting: "connect_timeout_msec",
+                reason: "no connection could be made in time".to_string(),
+            });
+        }
+        for &(setting, threshold) in &[
+            ("node_liveness_msec", self.node_liveness_msec),
+            ("client_liveness_msec", self.client_liveness_msec),
+        ] {
+            if threshold == Some(0) {
+                return Err(Error::InvalidSetting {
+                    setting,
+                    reason: "every peer would be probed all the time".to_string(),
+                });
+            }
+        }
+        if self.liveness_probes == Some(0) {
+            return Err(Error::InvalidSetting {
+                setting: "liveness_probes",
+                reason: "quiet peers would be disconnected unprobed".to_string(),
+            });
+        }
+        for &(setting, limit) in &[
+            (
+                "max_incoming_per_v4_subnet",
+                self.max_incoming_per_v4_subnet,
+            ),
+            (
+                "max_incoming_per_v6_subnet",
+                self.max_incoming_per_v6_subnet,
+            ),
+        ] {
+            if limit == Some(0) {
+                return Err(Error::InvalidSetting {
+                    setting,
+                    reason: "every peer of the address family would be refused".to_string(),
+                });
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_protocol_version, self.max_protocol_version) {
+            if min > max {
+                return Err(conflict(
+                    "min_protocol_version",
+                    "max_protocol_version",
+                    format!(
+                        "no protocol version is both at least {} and at most {}",
+                        min, max
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the fields of `new` which can change at runtime, see `QuicP2p::apply_config`.
+    pub(crate) fn reload(&mut self, new: Config) -> ConfigChanges {
+        let mut changes = ConfigChanges::default();
+        for field in self.changed_fields(&new) {
+            if RELOADABLE_FIELDS.contains(&field) {
+                changes.applied.push(field);
+            } else {
+                changes.needs_restart.push(field);
+            }
+        }
+
+        self.hard_coded_contacts = new.hard_coded_contacts;
+        self.bootstrap_only_contacts = new.bootstrap_only_contacts;
+        self.bootstrap_strategy = new.bootstrap_strategy;
+        self.external_address = new.external_address;
+        self.max_msg_size_allowed = new.max_msg_size_allowed;
+        self.idle_timeout_msec = new.idle_timeout_msec;
+        self.keep_alive_interval_msec = new.keep_alive_interval_msec;
+        self.duplicate_connection_policy = new.duplicate_connection_policy;
+        self.ordered_delivery = new.ordered_delivery;
+        self.min_protocol_version = new.min_protocol_version;
+        self.max_protocol_version = new.max_protocol_version;
+        self.connect_timeout_msec = new.connect_timeout_msec;
+        self.max_incoming_per_v4_subnet = new.max_incoming_per_v4_subnet;
+        self.max_incoming_per_v6_subnet = new.max_incoming_per_v6_subnet;
+        changes
+    }
+
+    /// Names of the fields which differ from those of `other`, in the order they are declared.
+    fn changed_fields(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field != other.$field {
+                        changed.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+
+        compare!(
+            hard_coded_contacts,
+            bootstrap_only_contacts,
+            bootstrap_strategy,
+            port,
+            ip,
+            additional_listen_addrs,
+            bind_interface,
+            external_address,
+            socks5_proxy
+        );
+        #[cfg(feature = "websocket")]
+        compare!(websocket_port);
+        compare!(
+            max_msg_size_allowed,
+            idle_timeout_msec,
+            keep_alive_interval_msec,
+            our_complete_cert,
+            our_type,
+            duplicate_connection_policy,
+            ordered_delivery,
+            min_protocol_version,
+            max_protocol_version,
+            connect_timeout_msec,
+            node_liveness_msec,
+            client_liveness_msec,
+            liveness_probes,
+            max_incoming_per_v4_subnet,
+            max_incoming_per_v6_subnet
+        );
+        changed
+    }
+
+    /// Time connections we make have to be established in, see `connect_timeout_msec`.
+    pub(crate) fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout_msec.map(Duration::from_millis)
+    }
+
+    /// How long peers may stay quiet before they are probed, see `node_liveness_msec`.
+    pub(crate) fn liveness(&self) -> LivenessThresholds {
+        LivenessThresholds {
+            node: self.node_liveness_msec.map(Duration::from_millis),
+            client: self.client_liveness_msec.map(Duration::from_millis),
+            probes: self.liveness_probes.unwrap_or(DEFAULT_LIVENESS_PROBES),
+        }
+    }
+
+    /// Most connections peers of a subnet may have to us, see `max_incoming_per_v4_subnet`.
+    pub(crate) fn subnet_limits(&self) -> SubnetLimits {
+        SubnetLimits {
+            v4: self.max_incoming_per_v4_subnet,
+            v6: self.max_incoming_per_v6_subnet,
+        }
+    }
+
+    /// Versions of the wire protocol accepted from peers, see `min_protocol_version`.
+    pub(crate) fn protocol_versions(&self) -> RangeInclusive<u16> {
+        self.min_protocol_version.unwrap_or(0)..=self.max_protocol_version.unwrap_or(u16::MAX)
+    }
+
     /// Create a default Config with random Certificate
     pub fn with_default_cert() -> Config {
         trace!("Constructing default Config");
@@ -97,13 +655,771 @@ impl Config {
     }
 }
 
+fn conflict<S: Into<String>>(
+    setting: &'static str,
+    conflicts_with: &'static str,
+    reason: S,
+) -> Error {
+    Error::ConflictingSettings {
+        setting,
+        conflicts_with,
+        reason: reason.into(),
+    }
+}
+
+/// Fields of binary configs written before `CONFIG_VERSION` was introduced, by quic-p2p 0.1.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV0 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+}
+
+impl From<ConfigV0> for Config {
+    fn from(v0: ConfigV0) -> Self {
+        Self {
+            hard_coded_contacts: v0.hard_coded_contacts,
+            port: v0.port,
+            ip: v0.ip,
+            max_msg_size_allowed: v0.max_msg_size_allowed,
+            idle_timeout_msec: v0.idle_timeout_msec,
+            keep_alive_interval_msec: v0.keep_alive_interval_msec,
+            our_complete_cert: v0.our_complete_cert,
+            our_type: v0.our_type,
+            ..Default::default()
+        }
+    }
+}
+
+/// Fields of binary configs of version 1, which predate `Config::duplicate_connection_policy`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV1 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+}
+
+impl From<ConfigV1> for Config {
+    fn from(v1: ConfigV1) -> Self {
+        Self {
+            hard_coded_contacts: v1.hard_coded_contacts,
+            bootstrap_only_contacts: HashSet::new(),
+            bootstrap_strategy: Default::default(),
+            port: v1.port,
+            ip: v1.ip,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: None,
+            socks5_proxy: v1.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v1.websocket_port,
+            max_msg_size_allowed: v1.max_msg_size_allowed,
+            idle_timeout_msec: v1.idle_timeout_msec,
+            keep_alive_interval_msec: v1.keep_alive_interval_msec,
+            our_complete_cert: v1.our_complete_cert,
+            our_type: v1.our_type,
+            duplicate_connection_policy: Default::default(),
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 2, which predate `Config::additional_listen_addrs`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV2 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+}
+
+impl From<ConfigV2> for Config {
+    fn from(v2: ConfigV2) -> Self {
+        Self {
+            hard_coded_contacts: v2.hard_coded_contacts,
+            bootstrap_only_contacts: HashSet::new(),
+            bootstrap_strategy: Default::default(),
+            port: v2.port,
+            ip: v2.ip,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: None,
+            socks5_proxy: v2.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v2.websocket_port,
+            max_msg_size_allowed: v2.max_msg_size_allowed,
+            idle_timeout_msec: v2.idle_timeout_msec,
+            keep_alive_interval_msec: v2.keep_alive_interval_msec,
+            our_complete_cert: v2.our_complete_cert,
+            our_type: v2.our_type,
+            duplicate_connection_policy: v2.duplicate_connection_policy,
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 3, which predate `Config::bind_interface`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV3 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+}
+
+impl From<ConfigV3> for Config {
+    fn from(v3: ConfigV3) -> Self {
+        Self {
+            hard_coded_contacts: v3.hard_coded_contacts,
+            bootstrap_only_contacts: HashSet::new(),
+            bootstrap_strategy: Default::default(),
+            port: v3.port,
+            ip: v3.ip,
+            additional_listen_addrs: v3.additional_listen_addrs,
+            bind_interface: None,
+            external_address: None,
+            socks5_proxy: v3.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v3.websocket_port,
+            max_msg_size_allowed: v3.max_msg_size_allowed,
+            idle_timeout_msec: v3.idle_timeout_msec,
+            keep_alive_interval_msec: v3.keep_alive_interval_msec,
+            our_complete_cert: v3.our_complete_cert,
+            our_type: v3.our_type,
+            duplicate_connection_policy: v3.duplicate_connection_policy,
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 4, which predate `Config::bootstrap_only_contacts`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV4 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+}
+
+impl From<ConfigV4> for Config {
+    fn from(v4: ConfigV4) -> Self {
+        Self {
+            hard_coded_contacts: v4.hard_coded_contacts,
+            bootstrap_only_contacts: HashSet::new(),
+            bootstrap_strategy: Default::default(),
+            port: v4.port,
+            ip: v4.ip,
+            additional_listen_addrs: v4.additional_listen_addrs,
+            bind_interface: v4.bind_interface,
+            external_address: None,
+            socks5_proxy: v4.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v4.websocket_port,
+            max_msg_size_allowed: v4.max_msg_size_allowed,
+            idle_timeout_msec: v4.idle_timeout_msec,
+            keep_alive_interval_msec: v4.keep_alive_interval_msec,
+            our_complete_cert: v4.our_complete_cert,
+            our_type: v4.our_type,
+            duplicate_connection_policy: v4.duplicate_connection_policy,
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 5, which predate `Config::bootstrap_strategy`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV5 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+}
+
+impl From<ConfigV5> for Config {
+    fn from(v5: ConfigV5) -> Self {
+        Self {
+            hard_coded_contacts: v5.hard_coded_contacts,
+            bootstrap_only_contacts: v5.bootstrap_only_contacts,
+            bootstrap_strategy: Default::default(),
+            port: v5.port,
+            ip: v5.ip,
+            additional_listen_addrs: v5.additional_listen_addrs,
+            bind_interface: v5.bind_interface,
+            external_address: None,
+            socks5_proxy: v5.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v5.websocket_port,
+            max_msg_size_allowed: v5.max_msg_size_allowed,
+            idle_timeout_msec: v5.idle_timeout_msec,
+            keep_alive_interval_msec: v5.keep_alive_interval_msec,
+            our_complete_cert: v5.our_complete_cert,
+            our_type: v5.our_type,
+            duplicate_connection_policy: v5.duplicate_connection_policy,
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 6, which predate `Config::external_address`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV6 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    bootstrap_strategy: BootstrapStrategy,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+}
+
+impl From<ConfigV6> for Config {
+    fn from(v6: ConfigV6) -> Self {
+        Self {
+            hard_coded_contacts: v6.hard_coded_contacts,
+            bootstrap_only_contacts: v6.bootstrap_only_contacts,
+            bootstrap_strategy: v6.bootstrap_strategy,
+            port: v6.port,
+            ip: v6.ip,
+            additional_listen_addrs: v6.additional_listen_addrs,
+            bind_interface: v6.bind_interface,
+            external_address: None,
+            socks5_proxy: v6.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v6.websocket_port,
+            max_msg_size_allowed: v6.max_msg_size_allowed,
+            idle_timeout_msec: v6.idle_timeout_msec,
+            keep_alive_interval_msec: v6.keep_alive_interval_msec,
+            our_complete_cert: v6.our_complete_cert,
+            our_type: v6.our_type,
+            duplicate_connection_policy: v6.duplicate_connection_policy,
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 7, which predate `Config::ordered_delivery`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV7 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    bootstrap_strategy: BootstrapStrategy,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    external_address: Option<SocketAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+}
+
+impl From<ConfigV7> for Config {
+    fn from(v7: ConfigV7) -> Self {
+        Self {
+            hard_coded_contacts: v7.hard_coded_contacts,
+            bootstrap_only_contacts: v7.bootstrap_only_contacts,
+            bootstrap_strategy: v7.bootstrap_strategy,
+            port: v7.port,
+            ip: v7.ip,
+            additional_listen_addrs: v7.additional_listen_addrs,
+            bind_interface: v7.bind_interface,
+            external_address: v7.external_address,
+            socks5_proxy: v7.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v7.websocket_port,
+            max_msg_size_allowed: v7.max_msg_size_allowed,
+            idle_timeout_msec: v7.idle_timeout_msec,
+            keep_alive_interval_msec: v7.keep_alive_interval_msec,
+            our_complete_cert: v7.our_complete_cert,
+            our_type: v7.our_type,
+            duplicate_connection_policy: v7.duplicate_connection_policy,
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 8, which predate `Config::min_protocol_version` and
+/// `Config::max_protocol_version`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV8 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    bootstrap_strategy: BootstrapStrategy,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    external_address: Option<SocketAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+    ordered_delivery: bool,
+}
+
+impl From<ConfigV8> for Config {
+    fn from(v8: ConfigV8) -> Self {
+        Self {
+            hard_coded_contacts: v8.hard_coded_contacts,
+            bootstrap_only_contacts: v8.bootstrap_only_contacts,
+            bootstrap_strategy: v8.bootstrap_strategy,
+            port: v8.port,
+            ip: v8.ip,
+            additional_listen_addrs: v8.additional_listen_addrs,
+            bind_interface: v8.bind_interface,
+            external_address: v8.external_address,
+            socks5_proxy: v8.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v8.websocket_port,
+            max_msg_size_allowed: v8.max_msg_size_allowed,
+            idle_timeout_msec: v8.idle_timeout_msec,
+            keep_alive_interval_msec: v8.keep_alive_interval_msec,
+            our_complete_cert: v8.our_complete_cert,
+            our_type: v8.our_type,
+            duplicate_connection_policy: v8.duplicate_connection_policy,
+            ordered_delivery: v8.ordered_delivery,
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 9, which predate `Config::connect_timeout_msec`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV9 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    bootstrap_strategy: BootstrapStrategy,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    external_address: Option<SocketAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+    ordered_delivery: bool,
+    min_protocol_version: Option<u16>,
+    max_protocol_version: Option<u16>,
+}
+
+impl From<ConfigV9> for Config {
+    fn from(v9: ConfigV9) -> Self {
+        Self {
+            hard_coded_contacts: v9.hard_coded_contacts,
+            bootstrap_only_contacts: v9.bootstrap_only_contacts,
+            bootstrap_strategy: v9.bootstrap_strategy,
+            port: v9.port,
+            ip: v9.ip,
+            additional_listen_addrs: v9.additional_listen_addrs,
+            bind_interface: v9.bind_interface,
+            external_address: v9.external_address,
+            socks5_proxy: v9.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v9.websocket_port,
+            max_msg_size_allowed: v9.max_msg_size_allowed,
+            idle_timeout_msec: v9.idle_timeout_msec,
+            keep_alive_interval_msec: v9.keep_alive_interval_msec,
+            our_complete_cert: v9.our_complete_cert,
+            our_type: v9.our_type,
+            duplicate_connection_policy: v9.duplicate_connection_policy,
+            ordered_delivery: v9.ordered_delivery,
+            min_protocol_version: v9.min_protocol_version,
+            max_protocol_version: v9.max_protocol_version,
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 10, which predate the liveness monitor of
+/// `Config::node_liveness_msec`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV10 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    bootstrap_strategy: BootstrapStrategy,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    external_address: Option<SocketAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+    ordered_delivery: bool,
+    min_protocol_version: Option<u16>,
+    max_protocol_version: Option<u16>,
+    connect_timeout_msec: Option<u64>,
+}
+
+impl From<ConfigV10> for Config {
+    fn from(v10: ConfigV10) -> Self {
+        Self {
+            hard_coded_contacts: v10.hard_coded_contacts,
+            bootstrap_only_contacts: v10.bootstrap_only_contacts,
+            bootstrap_strategy: v10.bootstrap_strategy,
+            port: v10.port,
+            ip: v10.ip,
+            additional_listen_addrs: v10.additional_listen_addrs,
+            bind_interface: v10.bind_interface,
+            external_address: v10.external_address,
+            socks5_proxy: v10.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v10.websocket_port,
+            max_msg_size_allowed: v10.max_msg_size_allowed,
+            idle_timeout_msec: v10.idle_timeout_msec,
+            keep_alive_interval_msec: v10.keep_alive_interval_msec,
+            our_complete_cert: v10.our_complete_cert,
+            our_type: v10.our_type,
+            duplicate_connection_policy: v10.duplicate_connection_policy,
+            ordered_delivery: v10.ordered_delivery,
+            min_protocol_version: v10.min_protocol_version,
+            max_protocol_version: v10.max_protocol_version,
+            connect_timeout_msec: v10.connect_timeout_msec,
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+/// Fields of binary configs of version 11, which predate the subnet limits of
+/// `Config::max_incoming_per_v4_subnet`.
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct ConfigV11 {
+    hard_coded_contacts: HashSet<NodeInfo>,
+    bootstrap_only_contacts: HashSet<NodeInfo>,
+    bootstrap_strategy: BootstrapStrategy,
+    port: Option<u16>,
+    ip: Option<IpAddr>,
+    additional_listen_addrs: Vec<SocketAddr>,
+    bind_interface: Option<String>,
+    external_address: Option<SocketAddr>,
+    socks5_proxy: Option<SocketAddr>,
+    #[cfg(feature = "websocket")]
+    websocket_port: Option<u16>,
+    max_msg_size_allowed: Option<u32>,
+    idle_timeout_msec: Option<u64>,
+    keep_alive_interval_msec: Option<u32>,
+    our_complete_cert: Option<SerialisableCertificate>,
+    our_type: OurType,
+    duplicate_connection_policy: DuplicateConnectionPolicy,
+    ordered_delivery: bool,
+    min_protocol_version: Option<u16>,
+    max_protocol_version: Option<u16>,
+    connect_timeout_msec: Option<u64>,
+    node_liveness_msec: Option<u64>,
+    client_liveness_msec: Option<u64>,
+    liveness_probes: Option<u32>,
+}
+
+impl From<ConfigV11> for Config {
+    fn from(v11: ConfigV11) -> Self {
+        Self {
+            hard_coded_contacts: v11.hard_coded_contacts,
+            bootstrap_only_contacts: v11.bootstrap_only_contacts,
+            bootstrap_strategy: v11.bootstrap_strategy,
+            port: v11.port,
+            ip: v11.ip,
+            additional_listen_addrs: v11.additional_listen_addrs,
+            bind_interface: v11.bind_interface,
+            external_address: v11.external_address,
+            socks5_proxy: v11.socks5_proxy,
+            #[cfg(feature = "websocket")]
+            websocket_port: v11.websocket_port,
+            max_msg_size_allowed: v11.max_msg_size_allowed,
+            idle_timeout_msec: v11.idle_timeout_msec,
+            keep_alive_interval_msec: v11.keep_alive_interval_msec,
+            our_complete_cert: v11.our_complete_cert,
+            our_type: v11.our_type,
+            duplicate_connection_policy: v11.duplicate_connection_policy,
+            ordered_delivery: v11.ordered_delivery,
+            min_protocol_version: v11.min_protocol_version,
+            max_protocol_version: v11.max_protocol_version,
+            connect_timeout_msec: v11.connect_timeout_msec,
+            node_liveness_msec: v11.node_liveness_msec,
+            client_liveness_msec: v11.client_liveness_msec,
+            liveness_probes: v11.liveness_probes,
+            max_incoming_per_v4_subnet: Default::default(),
+            max_incoming_per_v6_subnet: Default::default(),
+        }
+    }
+}
+
+fn check_version(version: u32) -> R<()> {
+    if version > CONFIG_VERSION {
+        return Err(Error::Configuration(format!(
+            "config version {} is newer than the supported {}",
+            version, CONFIG_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Read a binary config and the version it was written in.
+fn read_binary(path: &Path) -> R<(Config, u32)> {
+    let payload = match persistence::read_payload(path)? {
+        Payload::Checked(payload) | Payload::Legacy(payload) => payload,
+    };
+    if !payload.starts_with(BINARY_MAGIC) {
+        let v0: ConfigV0 = bincode::deserialize(&payload)?;
+        return Ok((v0.into(), 0));
+    }
+
+    let version = payload
+        .get(BINARY_MAGIC.len()..BINARY_MAGIC.len() + 4)
+        .map(|version| u32::from_le_bytes(unwrap!(version.try_into())))
+        .ok_or_else(|| Error::CorruptFile {
+            path: path.to_path_buf(),
+            reason: "truncated config version",
+        })?;
+    check_version(version)?;
+
+    let fields = &payload[BINARY_MAGIC.len() + 4..];
+    let cfg = match version {
+        1 => bincode::deserialize::<ConfigV1>(fields)?.into(),
+        2 => bincode::deserialize::<ConfigV2>(fields)?.into(),
+        3 => bincode::deserialize::<ConfigV3>(fields)?.into(),
+        4 => bincode::deserialize::<ConfigV4>(fields)?.into(),
+        5 => bincode::deserialize::<ConfigV5>(fields)?.into(),
+        6 => bincode::deserialize::<ConfigV6>(fields)?.into(),
+        7 => bincode::deserialize::<ConfigV7>(fields)?.into(),
+        8 => bincode::deserialize::<ConfigV8>(fields)?.into(),
+        9 => bincode::deserialize::<ConfigV9>(fields)?.into(),
+        10 => bincode::deserialize::<ConfigV10>(fields)?.into(),
+        11 => bincode::deserialize::<ConfigV11>(fields)?.into(),
+        _ => bincode::deserialize(fields)?,
+    };
+    Ok((cfg, version))
+}
+
+/// Read a TOML or JSON config, upgrading it from the version it was written in.
+fn from_text(mut value: serde_json::Value) -> R<Config> {
+    let version = match value.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| version.try_into().ok())
+            .ok_or_else(|| Error::Configuration(format!("invalid config version {}", version)))?,
+    };
+    check_version(version)?;
+
+    // Fields added since `version` are filled in with their defaults by `#[serde(default)]`.
+    // Fields renamed or changed since are to be converted here before deserialising.
+    if let Some(fields) = value.as_object_mut() {
+        let _ = fields.remove("version");
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Format of a config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The compact binary format of the default config file.
+    Binary,
+    /// TOML, suitable for editing by hand.
+    Toml,
+    /// JSON, suitable for editing by hand.
+    Json,
+}
+
+impl Format {
+    /// Detect the format of the given file from its extension: `.toml` and `.json` files are TOML
+    /// and JSON respectively, anything else is binary.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Binary,
+        }
+    }
+
+    /// File extension of the format, if it has one.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Format::Binary => None,
+            Format::Toml => Some("toml"),
+            Format::Json => Some("json"),
+        }
+    }
+}
+
 /// To be used to read and write our certificate and private key to disk esp. as a part of our
 /// configuration file
+///
+/// TOML and JSON config files hold both as base64 strings, which can be replaced by PEM text.
 #[derive(Serialize, Deserialize, Clone, Eq, PartialEq)]
 pub struct SerialisableCertificate {
     /// DER encoded certificate
+    #[serde(with = "crate::der_text")]
     pub cert_der: Vec<u8>,
     /// DER encoded private key
+    #[serde(with = "crate::der_text")]
     pub key_der: Vec<u8>,
 }
 
@@ -192,6 +1508,121 @@ impl Default for OurType {
     }
 }
 
+/// Which connection to keep if a peer connects to us while its previous connection to us is still
+/// up. The one dropped is reported via `Event::DuplicateConnectionDropped`.
+///
+/// A new connection replacing the existing one replaces our connection to the peer as well, the
+/// peer then being set up as if it connected to us for the first time.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum DuplicateConnectionPolicy {
+    /// Keep the existing connection and drop the new one.
+    KeepExisting,
+    /// Replace the existing connection with the new one.
+    KeepIncoming,
+    /// Replace the existing connection with the new one if the peer's address is lower than ours,
+    /// otherwise drop the new one. Peers of the same overlay using this policy agree on the
+    /// outcome whichever of them redials.
+    KeepLowestAddress,
+    /// Keep both connections, reading from either of them.
+    KeepBoth,
+}
+
+impl FromStr for DuplicateConnectionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "keep-existing" => Ok(DuplicateConnectionPolicy::KeepExisting),
+            "keep-incoming" => Ok(DuplicateConnectionPolicy::KeepIncoming),
+            "keep-lowest-address" => Ok(DuplicateConnectionPolicy::KeepLowestAddress),
+            "keep-both" => Ok(DuplicateConnectionPolicy::KeepBoth),
+            x => {
+                let err = format!("Unknown duplicate connection policy: {}", x);
+                warn!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Default for DuplicateConnectionPolicy {
+    fn default() -> Self {
+        DuplicateConnectionPolicy::KeepExisting
+    }
+}
+
+/// Order in which bootstrapping tries the bootstrap sources: the bootstrap cache, holding the
+/// peers we connected to in previous runs, and the `hard_coded_contacts` and
+/// `bootstrap_only_contacts` of the config. All attempts run at once, those started first
+/// standing the best chance to win.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum BootstrapStrategy {
+    /// Try the cached peers, most recent first, then the contacts of the config.
+    CacheFirst,
+    /// Try the contacts of the config, then the cached peers.
+    HardCodedFirst,
+    /// Alternate between cached peers and contacts of the config, starting with the most recent
+    /// cached peer.
+    Interleaved,
+    /// Try the contacts of the config only, ignoring the cache, e.g. for testnets whose caches
+    /// are left over from previous runs. Peers are still cached.
+    FreshNetwork,
+}
+
+impl FromStr for BootstrapStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "cache-first" => Ok(BootstrapStrategy::CacheFirst),
+            "hard-coded-first" => Ok(BootstrapStrategy::HardCodedFirst),
+            "interleaved" => Ok(BootstrapStrategy::Interleaved),
+            "fresh-network" => Ok(BootstrapStrategy::FreshNetwork),
+            x => {
+                let err = format!("Unknown bootstrap strategy: {}", x);
+                warn!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Default for BootstrapStrategy {
+    fn default() -> Self {
+        BootstrapStrategy::CacheFirst
+    }
+}
+
+/// Read our certificate and private key from the `identity` file in the project keys directory,
+/// generating them and writing them there if there is no such file yet. See
+/// `Builder::with_persistent_identity`.
+pub(crate) fn read_or_create_identity(user_override: Option<&Dirs>) -> R<SerialisableCertificate> {
+    let path = identity_path(user_override)?;
+    if path.exists() {
+        return persistence::read_from_disk(&path);
+    }
+
+    let keys_dir = path
+        .parent()
+        .ok_or_else(|| io::ErrorKind::NotFound.into())
+        .map_err(Error::Io)?;
+    fs::create_dir_all(keys_dir)?;
+
+    let cert = SerialisableCertificate::default();
+    persistence::write_to_disk(&path, &cert)?;
+    info!("Generated a new identity at {}", path.display());
+    Ok(cert)
+}
+
+/// Path of the `identity` file within the given directories or the project ones.
+pub(crate) fn identity_path(user_override: Option<&Dirs>) -> R<PathBuf> {
+    let path = |dir: &Dirs| dir.keys_dir().join("identity");
+    user_override.map_or_else(
+        || Ok::<_, Error>(path(&utils::project_dir()?)),
+        |d| Ok(path(d)),
+    )
+}
+
 fn config_path(user_override: Option<&Dirs>) -> R<PathBuf> {
     let path = |dir: &Dirs| {
         let path = dir.config_dir();
@@ -206,22 +1637,1111 @@ fn config_path(user_override: Option<&Dirs>) -> R<PathBuf> {
     Ok(cfg_path)
 }
 
+/// Parse contacts given either as a JSON array or as comma separated `NodeInfo`s in any of their
+/// textual forms.
+pub(crate) fn parse_contacts(s: &str) -> R<HashSet<NodeInfo>> {
+    if s.trim_start().starts_with('[') {
+        return serde_json::from_str::<Vec<Contact>>(s)?
+            .into_iter()
+            .map(Contact::into_node_info)
+            .collect();
+    }
+    s.split(',')
+        .filter(|contact| !contact.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Parse the value of an optional field, empty for `None`.
+fn parse_opt<T>(value: &str) -> R<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse::<T>()
+        .map(Some)
+        .map_err(|e| Error::Configuration(e.to_string()))
+}
+
+/// Parse a comma separated list of addresses, empty for none.
+fn parse_addrs(value: &str) -> R<Vec<SocketAddr>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            addr.parse()
+                .map_err(|e| Error::Configuration(format!("{}: {}", addr, e)))
+        })
+        .collect()
+}
+
+/// A contact in a human readable config, either a `NodeInfo` or one of its textual forms.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Contact {
+    Text(String),
+    NodeInfo(NodeInfo),
+}
+
+impl Contact {
+    fn into_node_info(self) -> R<NodeInfo> {
+        match self {
+            Contact::Text(s) => s.parse(),
+            Contact::NodeInfo(node_info) => Ok(node_info),
+        }
+    }
+}
+
+/// The binary config stores `NodeInfo`s only, human readable ones may mix in their textual forms.
+fn deserialize_contacts<'de, D: Deserializer<'de>>(d: D) -> Result<HashSet<NodeInfo>, D::Error> {
+    if !d.is_human_readable() {
+        return HashSet::deserialize(d);
+    }
+    Vec::<Contact>::deserialize(d)?
+        .into_iter()
+        .map(|contact| contact.into_node_info().map_err(de::Error::custom))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::test_dirs;
-    use crate::utils;
+    use crate::test_utils::{rand_node_info, test_dirs};
+    use std::iter;
+    use structopt::StructOpt;
 
     #[test]
     fn config_create_read_and_write() {
         let dir = test_dirs();
         let config_path = unwrap!(config_path(Some(&dir)));
 
-        assert!(utils::read_from_disk::<Config>(&config_path).is_err());
+        assert!(Config::from_file(&config_path).is_err());
 
         let cfg = unwrap!(Config::read_or_construct_default(Some(&dir)));
-        let read_cfg = unwrap!(utils::read_from_disk(&config_path));
+        let read_cfg = unwrap!(Config::from_file(&config_path));
 
         assert_eq!(cfg, read_cfg);
     }
+
+    #[test]
+    fn config_round_trips_through_all_formats() {
+        let dir = test_dirs();
+        let mut cfg = Config::with_default_cert();
+        cfg.hard_coded_contacts = iter::once(rand_node_info()).collect();
+        cfg.port = Some(1234);
+        cfg.our_type = OurType::Client;
+
+        for &(name, format) in &[
+            ("config", Format::Binary),
+            ("config.toml", Format::Toml),
+            ("config.json", Format::Json),
+        ] {
+            let path = dir.config_dir().join(name);
+            unwrap!(fs::create_dir_all(dir.config_dir()));
+            assert_eq!(Format::from_path(&path), format);
+
+            unwrap!(cfg.save_to(&path, format));
+            assert_eq!(unwrap!(Config::from_file(&path)), cfg);
+        }
+    }
+
+    #[test]
+    fn identity_is_generated_once() {
+        let dir = test_dirs();
+
+        let identity = unwrap!(read_or_create_identity(Some(&dir)));
+        assert!(dir.config_dir().join("identity").exists());
+        assert!(identity.obtain_priv_key_and_cert().is_ok());
+        assert_eq!(unwrap!(read_or_create_identity(Some(&dir))), identity);
+
+        let other = unwrap!(read_or_create_identity(Some(&test_dirs())));
+        assert_ne!(other, identity);
+    }
+
+    #[test]
+    fn hand_written_config_takes_precedence() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+        unwrap!(fs::write(
+            config_path.with_extension("toml"),
+            "port = 1234\nour_type = \"Client\"\n"
+        ));
+
+        let cfg = unwrap!(Config::read_or_construct_default(Some(&dir)));
+        assert_eq!(cfg.port, Some(1234));
+        assert_eq!(cfg.our_type, OurType::Client);
+        assert!(!config_path.exists());
+    }
+
+    #[test]
+    fn contacts_are_parsed_from_json_or_compact_form() {
+        let contacts: HashSet<_> = (0..2).map(|_| rand_node_info()).collect();
+
+        let json = unwrap!(serde_json::to_string(&contacts));
+        assert_eq!(unwrap!(parse_contacts(&json)), contacts);
+
+        let compact = contacts
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(unwrap!(parse_contacts(&compact)), contacts);
+
+        let cfg = Config::from_iter_safe(&["quic-p2p", "--hard-coded-contacts", &compact]);
+        assert_eq!(unwrap!(cfg).hard_coded_contacts, contacts);
+
+        let urls = contacts
+            .iter()
+            .map(|c| c.to_url())
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(unwrap!(parse_contacts(&urls)), contacts);
+    }
+
+    #[test]
+    fn config_files_accept_contacts_in_textual_form() {
+        let dir = test_dirs();
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+        let mut contacts = (0..3).map(|_| rand_node_info());
+        let (url, compact, node_info) = (
+            unwrap!(contacts.next()),
+            unwrap!(contacts.next()),
+            unwrap!(contacts.next()),
+        );
+        let expected: HashSet<_> = vec![url.clone(), compact.clone(), node_info.clone()]
+            .into_iter()
+            .collect();
+
+        let json_path = dir.config_dir().join("config.json");
+        let json = serde_json::json!({
+            "hard_coded_contacts": [url.to_url(), compact.to_string(), node_info],
+        });
+        unwrap!(fs::write(&json_path, json.to_string()));
+        assert_eq!(
+            unwrap!(Config::from_file(&json_path)).hard_coded_contacts,
+            expected
+        );
+
+        let toml_path = dir.config_dir().join("config.toml");
+        unwrap!(fs::write(
+            &toml_path,
+            format!(
+                "hard_coded_contacts = [{:?}, {:?}]\n",
+                url.to_url(),
+                compact.to_string()
+            )
+        ));
+        let cfg = unwrap!(Config::from_file(&toml_path));
+        assert_eq!(cfg.hard_coded_contacts.len(), 2);
+        assert!(cfg.hard_coded_contacts.contains(&url));
+        assert!(cfg.hard_coded_contacts.contains(&compact));
+
+        let invalid = serde_json::json!({ "hard_coded_contacts": ["quic-p2p://127.0.0.1:5000"] });
+        unwrap!(fs::write(&json_path, invalid.to_string()));
+        assert!(Config::from_file(&json_path).is_err());
+    }
+
+    #[test]
+    fn binary_configs_of_version_0_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v0 = ConfigV0 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            port: Some(1234),
+            ip: None,
+            max_msg_size_allowed: Some(1024),
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: Some(500),
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Client,
+        };
+        let expected = Config {
+            hard_coded_contacts: v0.hard_coded_contacts.clone(),
+            port: v0.port,
+            max_msg_size_allowed: v0.max_msg_size_allowed,
+            keep_alive_interval_msec: v0.keep_alive_interval_msec,
+            our_complete_cert: v0.our_complete_cert.clone(),
+            our_type: v0.our_type,
+            ..Default::default()
+        };
+
+        // Written by quic-p2p 0.1, without the header of `persistence` either.
+        unwrap!(fs::write(&config_path, unwrap!(bincode::serialize(&v0))));
+        assert_eq!(unwrap!(Config::from_file(&config_path)), expected);
+
+        unwrap!(persistence::write_to_disk(&config_path, &v0));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_1_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v1 = ConfigV1 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            port: Some(1234),
+            ip: None,
+            socks5_proxy: Some(([127, 0, 0, 1], 1080).into()),
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: Some(100),
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+        };
+        let expected = Config {
+            hard_coded_contacts: v1.hard_coded_contacts.clone(),
+            port: v1.port,
+            socks5_proxy: v1.socks5_proxy,
+            idle_timeout_msec: v1.idle_timeout_msec,
+            our_complete_cert: v1.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&1u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v1)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 1));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_2_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v2 = ConfigV2 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            port: Some(1234),
+            ip: None,
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: Some(100),
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: DuplicateConnectionPolicy::KeepBoth,
+        };
+        let expected = Config {
+            hard_coded_contacts: v2.hard_coded_contacts.clone(),
+            port: v2.port,
+            idle_timeout_msec: v2.idle_timeout_msec,
+            our_complete_cert: v2.our_complete_cert.clone(),
+            duplicate_connection_policy: v2.duplicate_connection_policy,
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&2u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v2)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 2));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_3_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v3 = ConfigV3 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: vec![([10, 0, 0, 1], 5000).into()],
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v3.hard_coded_contacts.clone(),
+            port: v3.port,
+            additional_listen_addrs: v3.additional_listen_addrs.clone(),
+            our_complete_cert: v3.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&3u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v3)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 3));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_4_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v4 = ConfigV4 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: Some("eth1".to_string()),
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v4.hard_coded_contacts.clone(),
+            port: v4.port,
+            bind_interface: v4.bind_interface.clone(),
+            our_complete_cert: v4.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&4u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v4)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 4));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_5_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v5 = ConfigV5 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: iter::once(rand_node_info()).collect(),
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v5.hard_coded_contacts.clone(),
+            bootstrap_only_contacts: v5.bootstrap_only_contacts.clone(),
+            port: v5.port,
+            our_complete_cert: v5.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&5u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v5)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 5));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_6_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v6 = ConfigV6 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: Default::default(),
+            bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v6.hard_coded_contacts.clone(),
+            bootstrap_strategy: v6.bootstrap_strategy,
+            port: v6.port,
+            our_complete_cert: v6.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&6u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v6)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 6));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_7_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v7 = ConfigV7 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: Default::default(),
+            bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: Some(([203, 0, 113, 7], 5000).into()),
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v7.hard_coded_contacts.clone(),
+            bootstrap_strategy: v7.bootstrap_strategy,
+            port: v7.port,
+            external_address: v7.external_address,
+            our_complete_cert: v7.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&7u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v7)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 7));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_8_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v8 = ConfigV8 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: Default::default(),
+            bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: Some(([203, 0, 113, 7], 5000).into()),
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+            ordered_delivery: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v8.hard_coded_contacts.clone(),
+            bootstrap_strategy: v8.bootstrap_strategy,
+            port: v8.port,
+            external_address: v8.external_address,
+            our_complete_cert: v8.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&8u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v8)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 8));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_9_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v9 = ConfigV9 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: Default::default(),
+            bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: Some(([203, 0, 113, 7], 5000).into()),
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v9.hard_coded_contacts.clone(),
+            bootstrap_strategy: v9.bootstrap_strategy,
+            port: v9.port,
+            external_address: v9.external_address,
+            our_complete_cert: v9.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&9u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v9)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 9));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_10_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v10 = ConfigV10 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: Default::default(),
+            bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: Some(([203, 0, 113, 7], 5000).into()),
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v10.hard_coded_contacts.clone(),
+            bootstrap_strategy: v10.bootstrap_strategy,
+            port: v10.port,
+            external_address: v10.external_address,
+            our_complete_cert: v10.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&10u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v10)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 10));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn binary_configs_of_version_11_are_upgraded() {
+        let dir = test_dirs();
+        let config_path = unwrap!(config_path(Some(&dir)));
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+
+        let v11 = ConfigV11 {
+            hard_coded_contacts: iter::once(rand_node_info()).collect(),
+            bootstrap_only_contacts: Default::default(),
+            bootstrap_strategy: BootstrapStrategy::FreshNetwork,
+            port: Some(1234),
+            ip: None,
+            additional_listen_addrs: Vec::new(),
+            bind_interface: None,
+            external_address: Some(([203, 0, 113, 7], 5000).into()),
+            socks5_proxy: None,
+            #[cfg(feature = "websocket")]
+            websocket_port: None,
+            max_msg_size_allowed: None,
+            idle_timeout_msec: None,
+            keep_alive_interval_msec: None,
+            our_complete_cert: Some(Default::default()),
+            our_type: OurType::Node,
+            duplicate_connection_policy: Default::default(),
+            ordered_delivery: Default::default(),
+            min_protocol_version: Default::default(),
+            max_protocol_version: Default::default(),
+            connect_timeout_msec: Default::default(),
+            node_liveness_msec: Default::default(),
+            client_liveness_msec: Default::default(),
+            liveness_probes: Default::default(),
+        };
+        let expected = Config {
+            hard_coded_contacts: v11.hard_coded_contacts.clone(),
+            bootstrap_strategy: v11.bootstrap_strategy,
+            port: v11.port,
+            external_address: v11.external_address,
+            our_complete_cert: v11.our_complete_cert.clone(),
+            ..Default::default()
+        };
+
+        let mut payload = BINARY_MAGIC.to_vec();
+        payload.extend_from_slice(&11u32.to_le_bytes());
+        payload.extend_from_slice(&unwrap!(bincode::serialize(&v11)));
+        unwrap!(persistence::write_payload(&config_path, &payload));
+
+        assert_eq!(unwrap!(read_binary(&config_path)), (expected.clone(), 11));
+        assert_eq!(
+            unwrap!(Config::read_or_construct_default(Some(&dir))),
+            expected
+        );
+        assert_eq!(
+            unwrap!(read_binary(&config_path)),
+            (expected, CONFIG_VERSION)
+        );
+    }
+
+    #[test]
+    fn text_configs_are_versioned() {
+        let dir = test_dirs();
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+        let cfg = Config {
+            port: Some(1234),
+            ..Default::default()
+        };
+
+        let json_path = dir.config_dir().join("config.json");
+        unwrap!(cfg.save_to(&json_path, Format::Json));
+        let json: serde_json::Value =
+            unwrap!(serde_json::from_slice(&unwrap!(fs::read(&json_path))));
+        assert_eq!(json["version"], CONFIG_VERSION);
+
+        let toml_path = dir.config_dir().join("config.toml");
+        unwrap!(cfg.save_to(&toml_path, Format::Toml));
+        assert!(unwrap!(fs::read_to_string(&toml_path))
+            .contains(&format!("version = {}", CONFIG_VERSION)));
+        assert_eq!(unwrap!(Config::from_file(&toml_path)), cfg);
+
+        // Unversioned files predate `CONFIG_VERSION`.
+        unwrap!(fs::write(&toml_path, "port = 1234\n"));
+        assert_eq!(unwrap!(Config::from_file(&toml_path)), cfg);
+
+        for newer in &[
+            format!("version = {}\nport = 1234\n", CONFIG_VERSION + 1),
+            "version = -1\n".to_string(),
+        ] {
+            unwrap!(fs::write(&toml_path, newer));
+            match Config::from_file(&toml_path) {
+                Err(Error::Configuration(_)) => (),
+                r => panic!("Unexpected result: {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn env_vars_override_config_fields() {
+        let contact = rand_node_info();
+        let url = contact.to_url();
+        let seed = rand_node_info();
+        let seed_compact = seed.to_string();
+        let mut cfg = Config {
+            port: Some(1234),
+            idle_timeout_msec: Some(100),
+            ..Default::default()
+        };
+        let vars = vec![
+            ("QUIC_P2P_PORT", "5000"),
+            ("QUIC_P2P_IP", "::"),
+            (
+                "QUIC_P2P_ADDITIONAL_LISTEN_ADDRS",
+                "10.0.0.1:5000, 192.168.0.1:0",
+            ),
+            ("QUIC_P2P_BIND_INTERFACE", "wg0"),
+            ("QUIC_P2P_EXTERNAL_ADDRESS", "203.0.113.7:5000"),
+            ("QUIC_P2P_IDLE_TIMEOUT_MSEC", ""),
+            ("QUIC_P2P_OUR_TYPE", "Client"),
+            (
+                "QUIC_P2P_DUPLICATE_CONNECTION_POLICY",
+                "keep_lowest_address",
+            ),
+            ("QUIC_P2P_HARD_CODED_CONTACTS", &url),
+            ("QUIC_P2P_BOOTSTRAP_ONLY_CONTACTS", &seed_compact),
+            ("QUIC_P2P_BOOTSTRAP_STRATEGY", "fresh_network"),
+            ("QUIC_P2P_ORDERED_DELIVERY", "true"),
+            ("QUIC_P2P_MIN_PROTOCOL_VERSION", "1"),
+            ("QUIC_P2P_CONNECT_TIMEOUT_MSEC", "2000"),
+            ("QUIC_P2P_CLIENT_LIVENESS_MSEC", "5000"),
+            ("QUIC_P2P_LIVENESS_PROBES", "2"),
+            ("QUIC_P2P_MAX_INCOMING_PER_V6_SUBNET", "8"),
+            ("QUIC_P2P_LOG", "trace"),
+            ("PORT", "6000"),
+        ];
+        unwrap!(cfg.apply_overrides(
+            vars.into_iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+        ));
+
+        assert_eq!(cfg.port, Some(5000));
+        assert_eq!(cfg.ip, Some(unwrap!("::".parse())));
+        assert_eq!(
+            cfg.additional_listen_addrs,
+            vec![
+                SocketAddr::from(([10, 0, 0, 1], 5000)),
+                SocketAddr::from(([192, 168, 0, 1], 0)),
+            ]
+        );
+        assert_eq!(cfg.bind_interface, Some("wg0".to_string()));
+        assert_eq!(
+            cfg.external_address,
+            Some(SocketAddr::from(([203, 0, 113, 7], 5000)))
+        );
+        assert_eq!(cfg.idle_timeout_msec, None);
+        assert_eq!(cfg.our_type, OurType::Client);
+        assert_eq!(
+            cfg.duplicate_connection_policy,
+            DuplicateConnectionPolicy::KeepLowestAddress
+        );
+        assert_eq!(cfg.hard_coded_contacts, iter::once(contact).collect());
+        assert_eq!(cfg.bootstrap_only_contacts, iter::once(seed).collect());
+        assert_eq!(cfg.bootstrap_strategy, BootstrapStrategy::FreshNetwork);
+        assert!(cfg.ordered_delivery);
+        assert_eq!(cfg.min_protocol_version, Some(1));
+        assert_eq!(cfg.max_protocol_version, None);
+        assert_eq!(cfg.connect_timeout_msec, Some(2_000));
+        assert_eq!(cfg.node_liveness_msec, None);
+        assert_eq!(cfg.client_liveness_msec, Some(5_000));
+        assert_eq!(cfg.liveness_probes, Some(2));
+        assert_eq!(cfg.max_incoming_per_v4_subnet, None);
+        assert_eq!(cfg.max_incoming_per_v6_subnet, Some(8));
+
+        for &(var, value, expected_field) in &[
+            ("QUIC_P2P_PORT", "70000", "port"),
+            ("QUIC_P2P_OUR_TYPE", "peer", "our_type"),
+            (
+                "QUIC_P2P_ADDITIONAL_LISTEN_ADDRS",
+                "10.0.0.1",
+                "additional_listen_addrs",
+            ),
+            (
+                "QUIC_P2P_DUPLICATE_CONNECTION_POLICY",
+                "keep-newest",
+                "duplicate_connection_policy",
+            ),
+            (
+                "QUIC_P2P_BOOTSTRAP_STRATEGY",
+                "random",
+                "bootstrap_strategy",
+            ),
+            (
+                "QUIC_P2P_HARD_CODED_CONTACTS",
+                "127.0.0.1:5000",
+                "hard_coded_contacts",
+            ),
+            ("QUIC_P2P_ORDERED_DELIVERY", "yes", "ordered_delivery"),
+            (
+                "QUIC_P2P_MAX_PROTOCOL_VERSION",
+                "-1",
+                "max_protocol_version",
+            ),
+        ] {
+            match cfg.apply_overrides(iter::once((var.to_string(), value.to_string()))) {
+                Err(Error::Config { field, .. }) => assert_eq!(field, expected_field),
+                r => panic!("Unexpected result for {}: {:?}", var, r),
+            }
+        }
+    }
+
+    #[test]
+    fn inconsistent_settings_are_rejected() {
+        assert!(Config::with_default_cert().validate().is_ok());
+
+        let contact = rand_node_info();
+        let conflicting = vec![
+            (
+                Config {
+                    our_type: OurType::Client,
+                    additional_listen_addrs: vec![([10, 0, 0, 1], 5000).into()],
+                    ..Default::default()
+                },
+                "additional_listen_addrs",
+                "our_type",
+            ),
+            (
+                Config {
+                    bind_interface: Some("wg0".to_string()),
+                    ip: Some(unwrap!("10.0.0.1".parse())),
+                    ..Default::default()
+                },
+                "bind_interface",
+                "ip",
+            ),
+            (
+                Config {
+                    keep_alive_interval_msec: Some(10_000),
+                    idle_timeout_msec: Some(5_000),
+                    ..Default::default()
+                },
+                "keep_alive_interval_msec",
+                "idle_timeout_msec",
+            ),
+            (
+                Config {
+                    hard_coded_contacts: iter::once(contact.clone()).collect(),
+                    bootstrap_only_contacts: iter::once(contact).collect(),
+                    ..Default::default()
+                },
+                "bootstrap_only_contacts",
+                "hard_coded_contacts",
+            ),
+            (
+                Config {
+                    min_protocol_version: Some(3),
+                    max_protocol_version: Some(2),
+                    ..Default::default()
+                },
+                "min_protocol_version",
+                "max_protocol_version",
+            ),
+        ];
+        for (cfg, expected_setting, expected_conflict) in conflicting {
+            match cfg.validate() {
+                Err(Error::ConflictingSettings {
+                    setting,
+                    conflicts_with,
+                    ..
+                }) => assert_eq!(
+                    (setting, conflicts_with),
+                    (expected_setting, expected_conflict)
+                ),
+                r => panic!("Unexpected result for {}: {:?}", expected_setting, r),
+            }
+        }
+
+        let invalid = vec![
+            (
+                Config {
+                    external_address: Some(([203, 0, 113, 7], 0).into()),
+                    ..Default::default()
+                },
+                "external_address",
+            ),
+            (
+                Config {
+                    max_msg_size_allowed: Some(0),
+                    ..Default::default()
+                },
+                "max_msg_size_allowed",
+            ),
+            (
+                Config {
+                    connect_timeout_msec: Some(0),
+                    ..Default::default()
+                },
+                "connect_timeout_msec",
+            ),
+            (
+                Config {
+                    node_liveness_msec: Some(0),
+                    ..Default::default()
+                },
+                "node_liveness_msec",
+            ),
+            (
+                Config {
+                    liveness_probes: Some(0),
+                    ..Default::default()
+                },
+                "liveness_probes",
+            ),
+            (
+                Config {
+                    max_incoming_per_v4_subnet: Some(0),
+                    ..Default::default()
+                },
+                "max_incoming_per_v4_subnet",
+            ),
+        ];
+        for (cfg, expected_setting) in invalid {
+            match cfg.validate() {
+                Err(Error::InvalidSetting { setting, .. }) => assert_eq!(setting, expected_setting),
+                r => panic!("Unexpected result for {}: {:?}", expected_setting, r),
+            }
+        }
+
+        // Disabling either of keep-alives or the idle timeout is fine
+        for &(keep_alive_interval_msec, idle_timeout_msec) in &[(0, 5_000), (10_000, 0)] {
+            let cfg = Config {
+                keep_alive_interval_msec: Some(keep_alive_interval_msec),
+                idle_timeout_msec: Some(idle_timeout_msec),
+                ..Default::default()
+            };
+            assert!(cfg.validate().is_ok());
+        }
+    }
+
+    #[test]
+    fn reloading_takes_only_the_fields_changeable_at_runtime() {
+        let mut cfg = Config {
+            port: Some(1234),
+            ..Default::default()
+        };
+        let contact = rand_node_info();
+        let new = Config {
+            hard_coded_contacts: iter::once(contact.clone()).collect(),
+            port: Some(5000),
+            idle_timeout_msec: Some(100),
+            our_type: OurType::Client,
+            node_liveness_msec: Some(5_000),
+            ..Default::default()
+        };
+
+        let changes = cfg.reload(new.clone());
+        assert_eq!(
+            changes,
+            ConfigChanges {
+                applied: vec!["hard_coded_contacts", "idle_timeout_msec"],
+                needs_restart: vec!["port", "our_type", "node_liveness_msec"],
+            }
+        );
+        assert_eq!(cfg.hard_coded_contacts, iter::once(contact).collect());
+        assert_eq!(cfg.idle_timeout_msec, Some(100));
+        assert_eq!(cfg.port, Some(1234));
+        assert_eq!(cfg.our_type, OurType::Node);
+        assert_eq!(cfg.node_liveness_msec, None);
+
+        assert_eq!(
+            cfg.reload(new),
+            ConfigChanges {
+                applied: Vec::new(),
+                needs_restart: vec!["port", "our_type", "node_liveness_msec"],
+            }
+        );
+    }
 }
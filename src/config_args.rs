@@ -0,0 +1,312 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::config::{
+    parse_contacts, BootstrapStrategy, Config, DuplicateConnectionPolicy, OurType,
+    SerialisableCertificate,
+};
+use crate::{NodeInfo, R};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// Command line arguments for the fields of `Config`, to be flattened into the arguments of
+/// binaries embedding quic-p2p:
+///
+/// ```ignore
+/// #[derive(StructOpt)]
+/// struct CliArgs {
+///     #[structopt(flatten)]
+///     quic_p2p: quic_p2p::ConfigArgs,
+/// }
+///
+/// let cfg = CliArgs::from_args().quic_p2p.into_config()?;
+/// ```
+///
+/// Unlike flattening `Config` itself, only the arguments given on the command line override the
+/// config they are merged with, see `apply_to`.
+#[derive(Clone, Debug, Default, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub struct ConfigArgs {
+    /// Config file to start from, in the format its extension implies. If none supplied the
+    /// default config file is used, see `Config::read_or_construct_default`.
+    #[structopt(long = "config", parse(from_os_str))]
+    pub config_file: Option<PathBuf>,
+    /// Hard Coded contacts, either a JSON array or a comma separated list of contacts in any of
+    /// the textual forms of `NodeInfo`.
+    #[structopt(short, long, parse(try_from_str = "parse_contacts"))]
+    pub hard_coded_contacts: Option<HashSet<NodeInfo>>,
+    /// Contacts to bootstrap off but not to keep as peers, in the forms of
+    /// `--hard-coded-contacts`.
+    #[structopt(long, parse(try_from_str = "parse_contacts"))]
+    pub bootstrap_only_contacts: Option<HashSet<NodeInfo>>,
+    /// Order to try the bootstrap sources in: `cache-first`, `hard-coded-first`, `interleaved`
+    /// or `fresh-network`.
+    #[structopt(long)]
+    pub bootstrap_strategy: Option<BootstrapStrategy>,
+    /// Port we want to reserve for QUIC.
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    /// IP address for the listener. Use `::` to listen on both IPv4 and IPv6.
+    #[structopt(long)]
+    pub ip: Option<IpAddr>,
+    /// Further addresses to listen on. If any are given they replace those of the config.
+    #[structopt(long)]
+    pub additional_listen_addrs: Vec<SocketAddr>,
+    /// Network interface to listen on by name, in place of `--ip`.
+    #[structopt(long)]
+    pub bind_interface: Option<String>,
+    /// Address to advertise in our connection info in place of the detected one.
+    #[structopt(long)]
+    pub external_address: Option<SocketAddr>,
+    /// SOCKS5 proxy with UDP support to relay our outgoing connections through.
+    #[structopt(long)]
+    pub socks5_proxy: Option<SocketAddr>,
+    /// Port to accept WebSocket connections of browser clients on.
+    #[cfg(feature = "websocket")]
+    #[structopt(long)]
+    pub websocket_port: Option<u16>,
+    /// Maximum message size we'll allow the peer to send to us.
+    #[structopt(long)]
+    pub max_msg_size_allowed: Option<u32>,
+    /// Interval in milliseconds after which a silent peer is declared offline, 0 to disable.
+    #[structopt(long)]
+    pub idle_timeout_msec: Option<u64>,
+    /// Interval in milliseconds to send keep-alives at while idling, 0 to disable.
+    #[structopt(long)]
+    pub keep_alive_interval_msec: Option<u32>,
+    /// Our TLS Certificate encoded in base64, see `SerialisableCertificate::to_string`.
+    #[structopt(long, parse(try_from_str))]
+    pub our_complete_cert: Option<SerialisableCertificate>,
+    /// Whether we are a `client` or a `node`.
+    #[structopt(short = "t", long)]
+    pub our_type: Option<OurType>,
+    /// Which connection to keep if a peer connects to us again: `keep-existing`, `keep-incoming`,
+    /// `keep-lowest-address` or `keep-both`.
+    #[structopt(long)]
+    pub duplicate_connection_policy: Option<DuplicateConnectionPolicy>,
+    /// Send all user messages to a peer over a single stream, so that they arrive in order.
+    #[structopt(long)]
+    pub ordered_delivery: bool,
+    /// Oldest version of the wire protocol accepted from peers.
+    #[structopt(long)]
+    pub min_protocol_version: Option<u16>,
+    /// Latest version of the wire protocol accepted from peers.
+    #[structopt(long)]
+    pub max_protocol_version: Option<u16>,
+    /// Time in milliseconds connections we make have to be established in.
+    #[structopt(long)]
+    pub connect_timeout_msec: Option<u64>,
+    /// Time in milliseconds a node may stay quiet before we probe it.
+    #[structopt(long)]
+    pub node_liveness_msec: Option<u64>,
+    /// Time in milliseconds a client connected to us may stay quiet before we probe it.
+    #[structopt(long)]
+    pub client_liveness_msec: Option<u64>,
+    /// Number of probes a quiet peer has to leave unanswered to be disconnected.
+    #[structopt(long)]
+    pub liveness_probes: Option<u32>,
+    /// Most connections peers of the same /24 IPv4 subnet may have to us at once.
+    #[structopt(long)]
+    pub max_incoming_per_v4_subnet: Option<u32>,
+    /// Most connections peers of the same /48 IPv6 subnet may have to us at once.
+    #[structopt(long)]
+    pub max_incoming_per_v6_subnet: Option<u32>,
+}
+
+impl ConfigArgs {
+    /// The config file given by `--config`, or else the default one, with the arguments given on
+    /// the command line applied on top.
+    ///
+    /// The precedence is thus: command line arguments, then the `QUIC_P2P_*` environment
+    /// variables, then the config file.
+    pub fn into_config(self) -> R<Config> {
+        let mut cfg = match self.config_file {
+            Some(ref path) => {
+                let mut cfg = Config::from_file(path)?;
+                cfg.apply_env_overrides()?;
+                cfg
+            }
+            None => Config::read_or_construct_default(None)?,
+        };
+        self.apply_to(&mut cfg);
+        Ok(cfg)
+    }
+
+    /// Override the fields of the given config whose arguments were given on the command line.
+    pub fn apply_to(self, cfg: &mut Config) {
+        fn set<T>(field: &mut T, arg: Option<T>) {
+            if let Some(value) = arg {
+                *field = value;
+            }
+        }
+        fn set_opt<T>(field: &mut Option<T>, arg: Option<T>) {
+            if arg.is_some() {
+                *field = arg;
+            }
+        }
+
+        set(&mut cfg.hard_coded_contacts, self.hard_coded_contacts);
+        set(
+            &mut cfg.bootstrap_only_contacts,
+            self.bootstrap_only_contacts,
+        );
+        set(&mut cfg.bootstrap_strategy, self.bootstrap_strategy);
+        set_opt(&mut cfg.port, self.port);
+        set_opt(&mut cfg.ip, self.ip);
+        if !self.additional_listen_addrs.is_empty() {
+            cfg.additional_listen_addrs = self.additional_listen_addrs;
+        }
+        set_opt(&mut cfg.bind_interface, self.bind_interface);
+        set_opt(&mut cfg.external_address, self.external_address);
+        set_opt(&mut cfg.socks5_proxy, self.socks5_proxy);
+        #[cfg(feature = "websocket")]
+        set_opt(&mut cfg.websocket_port, self.websocket_port);
+        set_opt(&mut cfg.max_msg_size_allowed, self.max_msg_size_allowed);
+        set_opt(&mut cfg.idle_timeout_msec, self.idle_timeout_msec);
+        set_opt(
+            &mut cfg.keep_alive_interval_msec,
+            self.keep_alive_interval_msec,
+        );
+        set_opt(&mut cfg.our_complete_cert, self.our_complete_cert);
+        set(&mut cfg.our_type, self.our_type);
+        set(
+            &mut cfg.duplicate_connection_policy,
+            self.duplicate_connection_policy,
+        );
+        if self.ordered_delivery {
+            cfg.ordered_delivery = true;
+        }
+        set_opt(&mut cfg.min_protocol_version, self.min_protocol_version);
+        set_opt(&mut cfg.max_protocol_version, self.max_protocol_version);
+        set_opt(&mut cfg.connect_timeout_msec, self.connect_timeout_msec);
+        set_opt(&mut cfg.node_liveness_msec, self.node_liveness_msec);
+        set_opt(&mut cfg.client_liveness_msec, self.client_liveness_msec);
+        set_opt(&mut cfg.liveness_probes, self.liveness_probes);
+        set_opt(
+            &mut cfg.max_incoming_per_v4_subnet,
+            self.max_incoming_per_v4_subnet,
+        );
+        set_opt(
+            &mut cfg.max_incoming_per_v6_subnet,
+            self.max_incoming_per_v6_subnet,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Format;
+    use crate::test_utils::{rand_node_info, test_dirs};
+    use std::fs;
+    use std::iter;
+    use structopt::StructOpt;
+
+    #[test]
+    fn only_given_args_override_the_config() {
+        let contact = rand_node_info();
+        let seed = rand_node_info();
+        let mut cfg = Config {
+            port: Some(1234),
+            idle_timeout_msec: Some(100),
+            our_type: OurType::Client,
+            ..Default::default()
+        };
+
+        let args = unwrap!(ConfigArgs::from_iter_safe(&[
+            "quic-p2p",
+            "--port",
+            "5000",
+            "--ip",
+            "::",
+            "-h",
+            &contact.to_url(),
+            "--additional-listen-addrs",
+            "10.0.0.1:5000",
+            "--bootstrap-only-contacts",
+            &seed.to_string(),
+            "--external-address",
+            "203.0.113.7:5000",
+            "--ordered-delivery",
+            "--max-protocol-version",
+            "3",
+            "--connect-timeout-msec",
+            "1500",
+            "--node-liveness-msec",
+            "20000",
+            "--max-incoming-per-v4-subnet",
+            "16",
+        ]));
+        args.apply_to(&mut cfg);
+
+        assert_eq!(cfg.port, Some(5000));
+        assert_eq!(cfg.ip, Some(unwrap!("::".parse())));
+        assert_eq!(
+            cfg.additional_listen_addrs,
+            vec![SocketAddr::from(([10, 0, 0, 1], 5000))]
+        );
+        assert_eq!(cfg.hard_coded_contacts, iter::once(contact).collect());
+        assert_eq!(cfg.bootstrap_only_contacts, iter::once(seed).collect());
+        assert_eq!(
+            cfg.external_address,
+            Some(SocketAddr::from(([203, 0, 113, 7], 5000)))
+        );
+        assert!(cfg.ordered_delivery);
+        assert_eq!(cfg.max_protocol_version, Some(3));
+        assert_eq!(cfg.connect_timeout_msec, Some(1_500));
+        assert_eq!(cfg.node_liveness_msec, Some(20_000));
+        assert_eq!(cfg.liveness_probes, None);
+        assert_eq!(cfg.max_incoming_per_v4_subnet, Some(16));
+        assert_eq!(cfg.idle_timeout_msec, Some(100));
+        assert_eq!(cfg.our_type, OurType::Client);
+
+        unwrap!(ConfigArgs::from_iter_safe(&["quic-p2p", "-t", "node"])).apply_to(&mut cfg);
+        assert_eq!(cfg.our_type, OurType::Node);
+
+        unwrap!(ConfigArgs::from_iter_safe(&[
+            "quic-p2p",
+            "--duplicate-connection-policy",
+            "keep-both",
+            "--bootstrap-strategy",
+            "hard-coded-first",
+        ]))
+        .apply_to(&mut cfg);
+        assert_eq!(
+            cfg.duplicate_connection_policy,
+            DuplicateConnectionPolicy::KeepBoth
+        );
+        assert_eq!(cfg.bootstrap_strategy, BootstrapStrategy::HardCodedFirst);
+    }
+
+    #[test]
+    fn args_are_merged_with_the_given_config_file() {
+        let dir = test_dirs();
+        unwrap!(fs::create_dir_all(dir.config_dir()));
+        let path = dir.config_dir().join("config.toml");
+        let file_cfg = Config {
+            port: Some(1234),
+            keep_alive_interval_msec: Some(500),
+            ..Default::default()
+        };
+        unwrap!(file_cfg.save_to(&path, Format::Toml));
+
+        let args = unwrap!(ConfigArgs::from_iter_safe(&[
+            "quic-p2p",
+            "--config",
+            unwrap!(path.to_str()),
+            "--port",
+            "5000",
+        ]));
+        let cfg = unwrap!(args.into_config());
+
+        assert_eq!(cfg.port, Some(5000));
+        assert_eq!(cfg.keep_alive_interval_msec, Some(500));
+    }
+}
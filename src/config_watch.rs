@@ -0,0 +1,55 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Watching a config file for changes, see `Builder::with_config_watch`.
+//!
+//! The file is polled rather than watched through the notifications of the OS, which differ from
+//! platform to platform. It is only read by the application, as the config it holds has to be
+//! applied through its `QuicP2p` anyway.
+
+use crate::context::ctx;
+use crate::event::Event;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// Fire `Event::ConfigFileChanged` whenever the file at `path` is written to, looking every
+/// `interval`. A file removed is nothing to apply, so isn't reported.
+pub fn start(path: PathBuf, interval: Duration) {
+    let mut last_seen = stamp(&path);
+    let leaf = Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| info!("Error in config watch interval: {:?}", e))
+        .for_each(move |_| {
+            let seen = stamp(&path);
+            if seen != last_seen {
+                last_seen = seen;
+                if seen.is_some() {
+                    debug!("Config file {} changed", path.display());
+                    let event = Event::ConfigFileChanged { path: path.clone() };
+                    ctx(|c| {
+                        if let Err(e) = c.event_tx.send(event) {
+                            info!("Could not fire event: {:?}", e);
+                        }
+                    });
+                }
+            }
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+/// Modification time and length of the file, unless it doesn't exist.
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
@@ -9,41 +9,54 @@
 
 use crate::config::OurType;
 use crate::connection::{
-    BootstrapGroupMaker, BootstrapGroupRef, Connection, FromPeer, QConn, ToPeer,
+    BootstrapGroupMaker, BootstrapGroupRef, Connection, FromPeer, QConn, Race, ToPeer,
 };
-use crate::context::ctx_mut;
+use crate::context::{ctx, ctx_mut};
+use crate::drain_order;
 use crate::error::Error;
-use crate::event::Event;
-use crate::peer_config;
+use crate::event::{ConnectionDirection, Event};
+use crate::outcome;
+use crate::pubsub;
+use crate::reconnect;
+use crate::send_queue::Queuing;
+use crate::transport::{Connecting, NewConn};
 use crate::utils;
-use crate::wire_msg::{Handshake, WireMsg};
-use crate::{communicate, NodeInfo, Peer, R};
+use crate::wire_msg::{Handshake, WireMsg, PROTOCOL_VERSION};
+use crate::{communicate, DeliveryMode, NodeInfo, Peer, R};
+use std::io;
 use std::mem;
 use std::net::SocketAddr;
-use tokio::prelude::{Future, Stream};
+use std::time::{Duration, Instant};
+use tokio::prelude::{Future, FutureExt, Stream};
 use tokio::runtime::current_thread;
 
-/// Connect to the given peer
+/// Connect to the given peer. Should we be connecting to it already, the message to send after
+/// connecting is queued and the bootstrap group joined on the attempt in flight instead.
 pub fn connect_to(
     peer_info: NodeInfo,
-    send_after_connect: Option<WireMsg>,
+    send_after_connect: Option<(WireMsg, Option<DeliveryMode>)>,
     bootstrap_group_maker: Option<&BootstrapGroupMaker>,
 ) -> R<()> {
     let peer_addr = peer_info.peer_addr;
 
-    let peer_cfg = match peer_config::new_client_cfg(&peer_info.peer_cert_der) {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            handle_connect_err(peer_addr, &e);
-            return Err(e);
-        }
-    };
+    let transport = ctx(|c| c.transport.clone());
+    if !transport.can_dial(peer_addr) {
+        let e = Error::UnreachableAddressFamily(peer_addr);
+        handle_connect_err(peer_addr, &e);
+        return Err(e);
+    }
 
     let r = ctx_mut(|c| {
         let event_tx = c.event_tx.clone();
 
         let (terminator, rx) = utils::connect_terminator();
+        let queuing = Queuing {
+            limit: c.send_queue_limit,
+            lifecycle_events: c.lifecycle_events,
+            event_tx: &c.event_tx,
+        };
 
+        let mut rejected = None;
         let conn = c.connections.entry(peer_addr).or_insert_with(|| {
             Connection::new(
                 peer_addr,
@@ -53,7 +66,34 @@ pub fn connect_to(
             )
         });
 
-        if conn.to_peer.is_no_connection() {
+        let res = if let ToPeer::Initiated {
+            ref terminator,
+            ref peer_cert_der,
+            ref mut pending_sends,
+            ..
+        } = conn.to_peer
+        {
+            // Rather than racing a handshake of our own against the one in flight
+            trace!(
+                "Joining the attempt to connect to peer {} in flight",
+                peer_addr
+            );
+            if *peer_cert_der != peer_info.peer_cert_der {
+                info!(
+                    "Certificate given for peer {} doesn't match the one we are connecting \
+                     with",
+                    peer_addr
+                );
+            }
+            if let Some(pending_send) = send_after_connect {
+                rejected = queuing.push(peer_addr, pending_sends, pending_send);
+            }
+            if conn.bootstrap_group_ref.is_none() {
+                conn.bootstrap_group_ref = bootstrap_group_maker
+                    .map(|b| b.add_member_and_get_group_ref(peer_addr, terminator.clone()));
+            }
+            Ok(None)
+        } else if conn.to_peer.is_no_connection() {
             // TODO see if this can be the default from-peer for OurType::Client
             if c.our_type == OurType::Client {
                 if !conn.from_peer.is_no_connection() {
@@ -70,41 +110,55 @@ pub fn connect_to(
 
             let mut pending_sends: Vec<_> = Default::default();
             if let Some(pending_send) = send_after_connect {
-                pending_sends.push(pending_send);
+                rejected = queuing.push(peer_addr, &mut pending_sends, pending_send);
             }
             conn.to_peer = ToPeer::Initiated {
                 terminator: terminator.clone(),
-                peer_cert_der: peer_info.peer_cert_der,
+                peer_cert_der: peer_info.peer_cert_der.clone(),
                 pending_sends,
+                initiated_at: Instant::now(),
             };
-            c.quic_ep()
-                .connect_with(peer_cfg, &peer_addr, "MaidSAFE.net")
-                .map_err(Error::from)
-                .and_then(move |new_client_conn_fut| {
-                    let terminator_leaf = rx
-                        .map_err(move |_| {
-                            handle_connect_err(peer_addr, &Error::ConnectionCancelled)
-                        })
-                        .for_each(move |_| {
-                            handle_connect_err(peer_addr, &Error::ConnectionCancelled);
-                            Err(())
-                        });
-                    let handle_new_connection_res_leaf =
-                        new_client_conn_fut.then(move |new_peer_conn_res| {
-                            handle_new_connection_res(peer_addr, new_peer_conn_res);
-                            Ok::<_, ()>(())
-                        });
-                    let leaf = terminator_leaf
-                        .select(handle_new_connection_res_leaf)
-                        .then(|_| Ok(()));
-
-                    current_thread::spawn(leaf);
-
-                    Ok(())
-                })
+            if c.lifecycle_events {
+                if let Err(e) = c.event_tx.send(Event::Connecting { peer_addr }) {
+                    info!("Could not fire event: {:?}", e);
+                }
+            }
+            Ok(Some(rx))
         } else {
             Err(Error::DuplicateConnectionToPeer(peer_addr))
+        };
+        if let Some(msg) = rejected {
+            outcome::queue_full(c, peer_addr, msg);
         }
+        res
+    })
+    .and_then(|rx| {
+        let rx = match rx {
+            Some(rx) => rx,
+            None => return Ok(()),
+        };
+        let connecting = transport.connect(peer_addr, &peer_info.peer_cert_der)?;
+        #[cfg(feature = "chaos")]
+        let connecting = crate::chaos::connect(connecting);
+        let connecting = with_connect_timeout(connecting, ctx(|c| c.connect_timeout));
+
+        let terminator_leaf = rx
+            .map_err(move |_| handle_connect_err(peer_addr, &Error::ConnectionCancelled))
+            .for_each(move |_| {
+                handle_connect_err(peer_addr, &Error::ConnectionCancelled);
+                Err(())
+            });
+        let handle_new_connection_res_leaf = connecting.then(move |new_peer_conn_res| {
+            handle_new_connection_res(peer_addr, new_peer_conn_res);
+            Ok::<_, ()>(())
+        });
+        let leaf = terminator_leaf
+            .select(handle_new_connection_res_leaf)
+            .then(|_| Ok(()));
+
+        current_thread::spawn(leaf);
+
+        Ok(())
     });
 
     if let Err(e) = r.as_ref() {
@@ -114,33 +168,112 @@ pub fn connect_to(
     r
 }
 
-fn handle_new_connection_res(
-    peer_addr: SocketAddr,
-    new_peer_conn_res: Result<
-        (
-            quinn::ConnectionDriver,
-            quinn::Connection,
-            quinn::IncomingStreams,
+/// Fail connecting once `timeout` passes without the connection being established, if given.
+fn with_connect_timeout(connecting: Connecting, timeout: Option<Duration>) -> Connecting {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return connecting,
+    };
+    Box::new(connecting.timeout(timeout).map_err(move |e| {
+        e.into_inner().unwrap_or_else(|| {
+            Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Connecting timed out after {:?}", timeout),
+            ))
+        })
+    }))
+}
+
+/// Abort the attempt to connect to the peer in flight, if any. The messages waiting for the
+/// connection are reported as aborted rather than as failed.
+pub fn abort(peer_addr: SocketAddr) {
+    ctx_mut(|c| {
+        let to_peer = c
+            .connections
+            .get_mut(&peer_addr)
+            .map(|conn| &mut conn.to_peer);
+        let aborted: Vec<_> = if let Some(&mut ToPeer::Initiated {
+            ref mut terminator,
+            ref mut pending_sends,
+            ..
+        }) = to_peer
+        {
+            let _ = terminator.try_send(());
+            pending_sends
+                .drain(..)
+                .filter_map(|(wire_msg, _)| wire_msg.user_msg().cloned())
+                .collect()
+        } else {
+            debug!("No attempt to connect to peer {} to abort", peer_addr);
+            Vec::new()
+        };
+        for msg in aborted {
+            outcome::aborted(c, peer_addr, msg);
+        }
+    })
+}
+
+/// Introduce ourselves as a node to the peers we connected to as a client, after we were promoted
+/// to one. They reverse connect to us then, which our listener accepts.
+#[cfg(not(feature = "client-only"))]
+pub fn introduce_as_node() {
+    ctx_mut(|c| {
+        let our_cert_der = c.our_complete_cert.cert_der.clone();
+        let our_capabilities = c.our_capabilities.clone();
+        for (peer_addr, conn) in c.connections.iter() {
+            if !conn.from_peer.is_not_needed() {
+                continue;
+            }
+            if let ToPeer::Established { ref q_conn, .. } = conn.to_peer {
+                communicate::write_to_peer_connection(
+                    *peer_addr,
+                    q_conn,
+                    WireMsg::Handshake(Handshake::Node {
+                        cert_der: our_cert_der.clone(),
+                        capabilities: our_capabilities.clone(),
+                        protocol_version: PROTOCOL_VERSION,
+                    }),
+                );
+            }
+        }
+    })
+}
+
+fn handle_new_connection_res(peer_addr: SocketAddr, new_peer_conn_res: R<NewConn>) {
+    let (conn_driver, q_conn, incoming_msgs) = match new_peer_conn_res {
+        Ok(new_conn) => (
+            new_conn.driver,
+            QConn::from(new_conn.conn),
+            new_conn.incoming_msgs,
         ),
-        quinn::ConnectionError,
-    >,
-) {
-    let (conn_driver, q_conn, incoming_streams) = match new_peer_conn_res {
-        Ok((conn_driver, q_conn, incoming_streams)) => {
-            (conn_driver, QConn::from(q_conn), incoming_streams)
+        Err(e) => {
+            let e = match e {
+                // We never got as far as the handshake
+                e @ Error::Socks5Proxy(_) => e,
+                e => Error::Handshake {
+                    peer_addr,
+                    source: Box::new(e),
+                },
+            };
+            if let Some(race) = remove_failed_conn(peer_addr, &e) {
+                race.lost(peer_addr, e);
+            }
+            return;
         }
-        Err(e) => return handle_connect_err(peer_addr, &From::from(e)),
     };
-    current_thread::spawn(
-        conn_driver.map_err(move |e| handle_connect_err(peer_addr, &From::from(e))),
-    );
+    current_thread::spawn(conn_driver.map_err(move |e| handle_connect_err(peer_addr, &e)));
 
     trace!("Successfully connected to peer: {}", peer_addr);
 
+    let stats = q_conn.stats().clone();
+    let encoding = q_conn.encoding();
     let mut should_accept_incoming = false;
     let mut terminate_bootstrap_group: Option<BootstrapGroupRef> = None;
+    let mut race = None;
 
     ctx_mut(|c| {
+        communicate::follow_moved_peer(c, peer_addr);
+
         let conn = match c.connections.get_mut(&peer_addr) {
             Some(conn) => conn,
             None => {
@@ -153,15 +286,19 @@ fn handle_new_connection_res(
             }
         };
 
+        race = conn.race.take();
+
         let mut to_peer_prev = mem::replace(&mut conn.to_peer, Default::default());
-        let (peer_cert_der, pending_sends) = match to_peer_prev {
+        let (peer_cert_der, mut pending_sends, initiated_at) = match to_peer_prev {
             ToPeer::Initiated {
                 ref mut peer_cert_der,
                 ref mut pending_sends,
+                initiated_at,
                 ..
             } => (
                 mem::replace(peer_cert_der, Default::default()),
                 mem::replace(pending_sends, Default::default()),
+                initiated_at,
             ),
             // TODO analyse if this is actually reachable in some wierd case where things were in
             // the event loop and resolving now etc
@@ -172,9 +309,22 @@ fn handle_new_connection_res(
             ),
         };
 
+        let handshake_duration = initiated_at.elapsed();
+        conn.handshake_duration = Some(handshake_duration);
+        if c.lifecycle_events {
+            let event = Event::HandshakeCompleted {
+                peer_addr,
+                duration: handshake_duration,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+
         let node_info = NodeInfo {
             peer_addr,
             peer_cert_der: peer_cert_der.clone(),
+            alt_addrs: Vec::new(),
         };
         if conn.we_contacted_peer {
             c.bootstrap_cache.add_peer(node_info.clone());
@@ -187,6 +337,8 @@ fn handle_new_connection_res(
                     &q_conn,
                     WireMsg::Handshake(Handshake::Node {
                         cert_der: c.our_complete_cert.cert_der.clone(),
+                        capabilities: c.our_capabilities.clone(),
+                        protocol_version: PROTOCOL_VERSION,
                     }),
                 );
             }
@@ -194,21 +346,30 @@ fn handle_new_connection_res(
                 communicate::write_to_peer_connection(
                     peer_addr,
                     &q_conn,
-                    WireMsg::Handshake(Handshake::Client),
+                    WireMsg::Handshake(Handshake::Client {
+                        capabilities: c.our_capabilities.clone(),
+                        protocol_version: PROTOCOL_VERSION,
+                    }),
                 );
 
                 let event = if let Some(bootstrap_group_ref) = conn.bootstrap_group_ref.take() {
+                    let event = bootstrap_group_ref.bootstrapped_to(node_info, handshake_duration);
                     terminate_bootstrap_group = Some(bootstrap_group_ref);
-                    Event::BootstrappedTo { node: node_info }
+                    event
                 } else {
                     Event::ConnectedTo {
                         peer: node_info.into(),
+                        direction: ConnectionDirection::from_we_contacted_peer(
+                            conn.we_contacted_peer,
+                        ),
+                        capabilities: conn.peer_capabilities.clone(),
                     }
                 };
 
                 if let Err(e) = c.event_tx.send(event) {
                     info!("Could not fire event: {:?}", e);
                 }
+                established_later(peer_addr);
 
                 should_accept_incoming = true;
             }
@@ -217,19 +378,38 @@ fn handle_new_connection_res(
                 ..
             } => {
                 let event = if let Some(bootstrap_group_ref) = conn.bootstrap_group_ref.take() {
+                    let event =
+                        bootstrap_group_ref.bootstrapped_to(node_info.clone(), handshake_duration);
                     terminate_bootstrap_group = Some(bootstrap_group_ref);
-                    Event::BootstrappedTo {
-                        node: node_info.clone(),
+                    event
+                } else if let Some(old) = conn.moved_from.take() {
+                    Event::PeerAddressChanged {
+                        old,
+                        new: peer_addr,
                     }
                 } else {
                     Event::ConnectedTo {
                         peer: node_info.clone().into(),
+                        direction: ConnectionDirection::from_we_contacted_peer(
+                            conn.we_contacted_peer,
+                        ),
+                        capabilities: conn.peer_capabilities.clone(),
                     }
                 };
+                let moved_from = match event {
+                    Event::PeerAddressChanged { old, .. } => Some(old),
+                    _ => None,
+                };
 
                 if let Err(e) = c.event_tx.send(event) {
                     info!("Could not fire event: {:?}", e);
                 }
+                if let Some(old) = moved_from {
+                    pubsub::announce_later(peer_addr);
+                    outcome::connection_ended_later(old, true);
+                } else {
+                    established_later(peer_addr);
+                }
 
                 let peer = Peer::Node { node_info };
 
@@ -247,8 +427,16 @@ fn handle_new_connection_res(
             }
         }
 
-        for pending_send in pending_sends {
-            communicate::write_to_peer_connection(peer_addr, &q_conn, pending_send);
+        drain_order::sort(&mut pending_sends, c.drain_order);
+        let queuing = Queuing {
+            limit: c.send_queue_limit,
+            lifecycle_events: c.lifecycle_events,
+            event_tx: &c.event_tx,
+        };
+        queuing.flushed(peer_addr, &pending_sends);
+        for (pending_send, mode) in pending_sends {
+            let mode = communicate::delivery_mode(conn, c.ordered_delivery, &pending_send, mode);
+            communicate::write(peer_addr, &q_conn, pending_send, mode);
         }
 
         conn.to_peer = ToPeer::Established {
@@ -260,31 +448,51 @@ fn handle_new_connection_res(
     if let Some(bootstrap_group_ref) = terminate_bootstrap_group {
         bootstrap_group_ref.terminate_group(true);
     }
+    if let Some(race) = race {
+        race.won(peer_addr);
+    }
 
     if should_accept_incoming {
-        communicate::read_from_peer(peer_addr, incoming_streams);
+        communicate::read_from_peer(peer_addr, incoming_msgs, stats, encoding);
     }
 }
 
+/// Take note of the connection to the peer being established, telling it our topics, once the
+/// context is released.
+pub fn established_later(peer_addr: SocketAddr) {
+    pubsub::announce_later(peer_addr);
+    reconnect::connected_later(peer_addr);
+}
+
 fn handle_connect_err(peer_addr: SocketAddr, e: &Error) {
+    let _ = remove_failed_conn(peer_addr, e);
+}
+
+/// Forget the connection we failed to establish, returning the `Race` it was part of if any.
+fn remove_failed_conn(peer_addr: SocketAddr, e: &Error) -> Option<Race> {
     debug!(
         "Error connecting to peer {}: {:?} - Details: {}",
         peer_addr, e, e
     );
 
     if let Error::DuplicateConnectionToPeer(_) = e {
-        return;
+        return None;
     }
 
     ctx_mut(|c| {
-        if let Some(conn) = c.connections.remove(&peer_addr) {
-            if !conn.from_peer.is_no_connection() {
-                info!(
-                    "Peer {} has a connection to us but we couldn't connect to it. \
-                     All connections to this peer will now be severed.",
-                    peer_addr
-                );
-            }
+        reconnect::dial_failed(c, peer_addr);
+        let mut conn = c.connections.remove(&peer_addr)?;
+        conn.failed_by(e);
+        if let Some(ref bootstrap_group_ref) = conn.bootstrap_group_ref {
+            bootstrap_group_ref.record_failure(e);
+        }
+        if !conn.from_peer.is_no_connection() {
+            info!(
+                "Peer {} has a connection to us but we couldn't connect to it. \
+                 All connections to this peer will now be severed.",
+                peer_addr
+            );
         }
+        conn.race.take()
     })
 }
@@ -14,15 +14,23 @@
 //! currently being made by the members of the group and thus an eventual destruction of all such
 //! members to not continue to use resources as we no longer require them.
 
+use crate::bootstrap::{
+    AttemptOutcome, BootstrapAttempt, BootstrapReport, ContactFailure, ContactSource,
+};
 use crate::context::ctx_mut;
+use crate::error::Error;
 use crate::event::Event;
+use crate::event_sender::EventSender;
+use crate::peer::NodeInfo;
+use crate::stats::BootstrapStats;
 use crate::utils::ConnectTerminator;
-use crossbeam_channel as mpmc;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::mem;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 /// Creator of a `BootstrapGroup`. Use this to obtain the reference to the undelying group.
 ///
@@ -34,29 +42,53 @@ pub struct BootstrapGroupMaker {
 
 impl BootstrapGroupMaker {
     /// Create a handle that refers to a newly created underlying group.
-    pub fn new(event_tx: mpmc::Sender<Event>) -> Self {
+    ///
+    /// `contacts` tells where each of the contacts came from and, for cached ones, how long ago
+    /// they were cached. The outcome of the bootstrap is recorded into `stats` when the group is
+    /// destroyed. If `report_tx` is given, a `BootstrapReport` is sent through it as well.
+    pub fn new(
+        event_tx: EventSender,
+        contacts: HashMap<SocketAddr, (ContactSource, Option<Duration>)>,
+        stats: Rc<RefCell<BootstrapStats>>,
+        report_tx: Option<mpsc::Sender<BootstrapReport>>,
+    ) -> Self {
         Self {
             group: Rc::new(RefCell::new(BootstrapGroup {
                 is_bootstrap_successful_yet: false,
                 // TODO remove magic number
                 terminators: HashMap::with_capacity(300),
                 event_tx,
+                started: Instant::now(),
+                contacts,
+                attempts: Vec::new(),
+                stats,
+                report_tx,
             })),
         }
     }
 
+    /// Record that the attempt to bootstrap off the given peer failed before it joined the group.
+    pub fn record_failure(&self, peer_addr: SocketAddr, e: &Error) {
+        if let Error::DuplicateConnectionToPeer(_) = *e.root_cause() {
+            return;
+        }
+        let mut group = self.group.borrow_mut();
+        group.start_attempt(peer_addr);
+        group.end_attempt(
+            peer_addr,
+            AttemptOutcome::Failed(ContactFailure::from_err(e)),
+        );
+    }
+
     /// Add member to the underlying `BootstrapGroup` and get a reference to it.
     pub fn add_member_and_get_group_ref(
         &self,
         peer_addr: SocketAddr,
         terminator: ConnectTerminator,
     ) -> BootstrapGroupRef {
-        if let Some(mut terminator) = self
-            .group
-            .borrow_mut()
-            .terminators
-            .insert(peer_addr, terminator)
-        {
+        let mut group = self.group.borrow_mut();
+        group.start_attempt(peer_addr);
+        if let Some(mut terminator) = group.terminators.insert(peer_addr, terminator) {
             let _ = terminator.try_send(());
         }
 
@@ -71,7 +103,7 @@ impl BootstrapGroupMaker {
 ///
 /// Once all references are dropped (and the `BootstrapGroupMaker` was also dropped) the
 /// underlying group will also be destroyed. If the bootstrap was not yet successful by the time
-/// this happened, `BootstrapFailure` event will be fired.
+/// this happened, `BootstrapFailure` event will be fired with the failure of each member.
 pub struct BootstrapGroupRef {
     peer_addr: SocketAddr,
     group: Rc<RefCell<BootstrapGroup>>,
@@ -81,11 +113,21 @@ impl BootstrapGroupRef {
     /// Prematurely terminate all members of the underlying `BootstrapGroup`. Also indicate if this
     /// is because the bootstrapping was successful (in which case no failure event will be
     /// auto-fired).
+    ///
+    /// The connection of a successful member bootstrapping off a bootstrap-only contact is closed
+    /// as well.
     pub fn terminate_group(&self, is_due_to_success: bool) {
+        let mut is_bootstrap_only = false;
         let mut terminators = {
             let mut group = self.group.borrow_mut();
             if is_due_to_success {
                 group.is_bootstrap_successful_yet = true;
+                group.end_attempt(self.peer_addr, AttemptOutcome::Succeeded);
+                is_bootstrap_only = group
+                    .contacts
+                    .get(&self.peer_addr)
+                    .map(|&(source, _)| source)
+                    == Some(ContactSource::BootstrapOnly);
             }
 
             // We use a `mem::replace` here because `self.group` can be mutably borrowed
@@ -100,32 +142,141 @@ impl BootstrapGroupRef {
                 let _ = terminator.try_send(());
                 let _conn = c.connections.remove(&peer_addr);
             }
+            if is_bootstrap_only {
+                debug!(
+                    "Closing our connection to bootstrap-only contact {}",
+                    self.peer_addr
+                );
+                let _conn = c.connections.remove(&self.peer_addr);
+            }
         });
     }
 
+    #[cfg(not(feature = "client-only"))]
     pub fn is_bootstrap_successful_yet(&self) -> bool {
         self.group.borrow().is_bootstrap_successful_yet
     }
+
+    /// Build the `BootstrappedTo` event to fire for this member bootstrapping off the given node.
+    pub fn bootstrapped_to(&self, node: NodeInfo, handshake_duration: Duration) -> Event {
+        let group = self.group.borrow();
+        let source = group
+            .contacts
+            .get(&self.peer_addr)
+            .map_or(ContactSource::HardCoded, |&(source, _)| source);
+
+        Event::BootstrappedTo {
+            node,
+            handshake_duration,
+            candidates_tried: group.attempts.len(),
+            source,
+        }
+    }
+
+    /// Record that the attempt of this member failed.
+    pub fn record_failure(&self, e: &Error) {
+        self.group.borrow_mut().end_attempt(
+            self.peer_addr,
+            AttemptOutcome::Failed(ContactFailure::from_err(e)),
+        );
+    }
 }
 
 impl Drop for BootstrapGroupRef {
     fn drop(&mut self) {
-        let _ = self.group.borrow_mut().terminators.remove(&self.peer_addr);
+        let mut group = self.group.borrow_mut();
+        let _ = group.terminators.remove(&self.peer_addr);
+        // Members which neither succeeded nor failed were dropped in favour of another member
+        group.end_attempt(self.peer_addr, AttemptOutcome::Cancelled);
     }
 }
 
 struct BootstrapGroup {
     is_bootstrap_successful_yet: bool,
     terminators: HashMap<SocketAddr, ConnectTerminator>,
-    event_tx: mpmc::Sender<Event>,
+    event_tx: EventSender,
+    started: Instant,
+    contacts: HashMap<SocketAddr, (ContactSource, Option<Duration>)>,
+    attempts: Vec<Attempt>,
+    stats: Rc<RefCell<BootstrapStats>>,
+    report_tx: Option<mpsc::Sender<BootstrapReport>>,
+}
+
+impl BootstrapGroup {
+    fn start_attempt(&mut self, peer_addr: SocketAddr) {
+        if self.attempts.iter().any(|a| a.peer_addr == peer_addr) {
+            return;
+        }
+        let (source, cache_age) = self
+            .contacts
+            .get(&peer_addr)
+            .cloned()
+            .unwrap_or((ContactSource::HardCoded, None));
+        self.attempts.push(Attempt {
+            peer_addr,
+            source,
+            cache_age,
+            started: Instant::now(),
+            ended: None,
+        });
+    }
+
+    /// Only the first outcome of an attempt is kept.
+    fn end_attempt(&mut self, peer_addr: SocketAddr, outcome: AttemptOutcome) {
+        if let Some(attempt) = self
+            .attempts
+            .iter_mut()
+            .find(|a| a.peer_addr == peer_addr && a.ended.is_none())
+        {
+            attempt.ended = Some((outcome, Instant::now()));
+        }
+    }
 }
 
 impl Drop for BootstrapGroup {
     fn drop(&mut self) {
+        let now = Instant::now();
+        let attempts = self
+            .attempts
+            .drain(..)
+            .map(|a| {
+                let (outcome, ended) = a.ended.unwrap_or((AttemptOutcome::Cancelled, now));
+                BootstrapAttempt {
+                    peer_addr: a.peer_addr,
+                    source: a.source,
+                    cache_age: a.cache_age,
+                    outcome,
+                    duration: ended.duration_since(a.started),
+                }
+            })
+            .collect();
+        let report = BootstrapReport {
+            attempts,
+            duration: now.duration_since(self.started),
+        };
+
         if !self.is_bootstrap_successful_yet {
-            if let Err(e) = self.event_tx.send(Event::BootstrapFailure) {
+            let failures = report.failures();
+            if let Err(e) = self.event_tx.send(Event::BootstrapFailure { failures }) {
                 info!("Failed informing about bootstrap failure: {:?}", e);
             }
         }
+
+        self.stats.borrow_mut().record(&report);
+
+        if let Some(report_tx) = self.report_tx.take() {
+            if let Err(e) = report_tx.send(report) {
+                info!("Failed sending the bootstrap report: {:?}", e);
+            }
+        }
     }
 }
+
+/// Bookkeeping of a single member's attempt for the `BootstrapReport`.
+struct Attempt {
+    peer_addr: SocketAddr,
+    source: ContactSource,
+    cache_age: Option<Duration>,
+    started: Instant,
+    ended: Option<(AttemptOutcome, Instant)>,
+}
@@ -15,6 +15,8 @@ use std::fmt;
 pub enum FromPeer {
     NoConnection,
     NotNeeded,
+    // Only made by the listener
+    #[cfg_attr(feature = "client-only", allow(dead_code))]
     Established {
         q_conn: QConn,
         pending_reads: Vec<WireMsg>,
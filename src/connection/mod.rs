@@ -10,15 +10,27 @@
 pub use self::bootstrap_group::{BootstrapGroupMaker, BootstrapGroupRef};
 pub use self::from_peer::FromPeer;
 pub use self::q_conn::QConn;
+pub use self::race::Race;
 pub use self::to_peer::ToPeer;
 
+use crate::bandwidth::BandwidthMeter;
+use crate::client_session::Session;
 use crate::context::ctx_mut;
+use crate::dedup::SeenMsgIds;
 use crate::error::Error;
-use crate::event::Event;
-use crossbeam_channel as mpmc;
+use crate::event::{CloseReason, Event};
+use crate::event_sender::EventSender;
+use crate::outcome;
+use crate::peer::Capabilities;
+use crate::probe::Probe;
+use crate::reconnect;
+use crate::stats::{ConnectionStats, PeerStats, StatsHandle};
+use crate::wire_msg::{PROTOCOL_VERSION, PROTOCOL_VERSION_REFUSED_CODE};
 use std::collections::hash_map::Entry;
+use std::collections::HashSet;
 use std::fmt;
 use std::net::SocketAddr;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 use tokio::prelude::Future;
 use tokio::runtime::current_thread;
@@ -27,6 +39,7 @@ use tokio::timer::Delay;
 mod bootstrap_group;
 mod from_peer;
 mod q_conn;
+mod race;
 mod to_peer;
 
 const KILL_INCOMPLETE_CONN_SEC: u64 = 60;
@@ -39,23 +52,62 @@ pub struct Connection {
     pub to_peer: ToPeer,
     /// Connection from the peer to us
     pub from_peer: FromPeer,
+    /// Further connections from the peer to us, kept following
+    /// `DuplicateConnectionPolicy::KeepBoth`.
+    pub duplicate_from_peer: Vec<QConn>,
     /// If this connection belongs to a bootstap group of connection attempts
     pub bootstrap_group_ref: Option<BootstrapGroupRef>,
+    /// If this connection is one of several attempts to reach a node, see `Race`
+    pub race: Option<Race>,
     /// quic-p2p won't validate incoming peers, it will simply pass them to the upper layer.
     /// Until we know that these peers are useful/valid for the upper layers, we might refrain
     /// ourselves from taking specific actions: e.g. putting these peers into the bootstrap cache.
     /// This flag indicates whether upper layer attempted to connect/send something to the other
     /// end of this connection.
     pub we_contacted_peer: bool,
+    /// Set if the connection was ended by the peer or the network rather than by us.
+    pub close_reason: Option<CloseReason>,
+    /// Time it took to establish our connection to the peer, if we initiated one.
+    pub handshake_duration: Option<Duration>,
+    /// Number of streams exchanged with the peer when the last heartbeat tick happened.
+    pub streams_at_last_heartbeat: u64,
+    /// Set if the peer is a client connected to us.
+    pub client_session: Option<Session>,
+    /// Capabilities the peer advertised in its handshake, if it sent us one.
+    pub peer_capabilities: Capabilities,
+    /// Error reported via `Event::ConnectionFailure` once we drop the connection, if the reason
+    /// is more specific than `Error::ConnectionCancelled`.
+    pub cancel_reason: Option<Error>,
+    /// Address the peer was known at before it reached us from this one, see
+    /// `Event::PeerAddressChanged`.
+    pub moved_from: Option<SocketAddr>,
+    /// Set once the peer moved on to another address, in which case dropping the connection isn't
+    /// reported.
+    pub has_moved: bool,
+    /// Whether user messages to the peer go on a single stream, keeping their order. `None`
+    /// leaves it to `Config::ordered_delivery`.
+    pub ordered_delivery: Option<bool>,
+    /// IDs of the latest messages the peer sent with one, to suppress duplicates of them.
+    pub seen_msg_ids: SeenMsgIds,
+    /// Topics the peer told us it is subscribed to, see `QuicP2p::subscribe`.
+    pub topics: HashSet<String>,
+    /// Set once the peer was reported via `Event::PeerSlow`, until `Event::PeerResumed`.
+    pub is_slow: bool,
+    /// Probes of the peer, see `Builder::with_probes` and `Config::node_liveness_msec`.
+    pub probe: Probe,
+    /// Samples of the bytes exchanged with the peer, see `QuicP2p::peer_stats`.
+    pub bandwidth: BandwidthMeter,
     peer_addr: SocketAddr,
-    event_tx: mpmc::Sender<Event>,
+    event_tx: EventSender,
+    #[cfg(feature = "otel")]
+    otel_span: opentelemetry::global::BoxedSpan,
 }
 
 impl Connection {
     /// New Connection with defaults
     pub fn new(
         peer_addr: SocketAddr,
-        event_tx: mpmc::Sender<Event>,
+        event_tx: EventSender,
         bootstrap_group_ref: Option<BootstrapGroupRef>,
     ) -> Self {
         spawn_incomplete_conn_killer(peer_addr);
@@ -63,25 +115,196 @@ impl Connection {
         Self {
             to_peer: Default::default(),
             from_peer: Default::default(),
+            duplicate_from_peer: Vec::new(),
             bootstrap_group_ref,
+            race: None,
             we_contacted_peer: false,
+            close_reason: None,
+            handshake_duration: None,
+            streams_at_last_heartbeat: 0,
+            client_session: None,
+            peer_capabilities: Default::default(),
+            cancel_reason: None,
+            moved_from: None,
+            has_moved: false,
+            ordered_delivery: None,
+            seen_msg_ids: Default::default(),
+            topics: Default::default(),
+            is_slow: false,
+            probe: Default::default(),
+            bandwidth: Default::default(),
             peer_addr,
             event_tx,
+            #[cfg(feature = "otel")]
+            otel_span: crate::otel::connection_span(peer_addr),
+        }
+    }
+}
+
+impl Connection {
+    /// Whether all the connections required between us and the peer are established.
+    pub fn is_complete(&self) -> bool {
+        (self.to_peer.is_established() || self.to_peer.is_not_needed())
+            && (self.from_peer.is_established() || self.from_peer.is_not_needed())
+    }
+
+    /// Statistics accumulated over the connections to and from the peer.
+    pub fn stats(&self) -> ConnectionStats {
+        let mut stats = ConnectionStats::default();
+        if let ToPeer::Established { ref q_conn, .. } = self.to_peer {
+            stats.merge(&q_conn.stats().borrow());
+        }
+        if let FromPeer::Established { ref q_conn, .. } = self.from_peer {
+            stats.merge(&q_conn.stats().borrow());
+        }
+        for q_conn in &self.duplicate_from_peer {
+            stats.merge(&q_conn.stats().borrow());
+        }
+        stats
+    }
+
+    /// Statistics of the connections to and from the peer as of `now`, with the byte rates.
+    pub fn peer_stats(&self, now: Instant) -> PeerStats {
+        let connection = self.stats();
+        PeerStats {
+            connection,
+            bandwidth: self.bandwidth.bandwidth(now, &connection),
+        }
+    }
+
+    /// When writing to the peer got held up the longest over the connections to and from it, if
+    /// it is, see `Builder::with_stall_detection`.
+    pub fn stalled_since(&self) -> Option<Instant> {
+        let to_peer = match self.to_peer {
+            ToPeer::Established { ref q_conn, .. } => Some(q_conn),
+            _ => None,
+        };
+        let from_peer = match self.from_peer {
+            FromPeer::Established { ref q_conn, .. } => Some(q_conn),
+            FromPeer::NoConnection | FromPeer::NotNeeded => None,
+        };
+        to_peer
+            .into_iter()
+            .chain(from_peer)
+            .chain(&self.duplicate_from_peer)
+            .filter_map(|q_conn| q_conn.stalled_since())
+            .min()
+    }
+
+    /// The quinn connection we made to the peer, else the one it made to us, if either is
+    /// established over QUIC, see `QuicP2p::with_quinn_connection`.
+    #[cfg(feature = "unstable-quinn")]
+    pub fn quinn_connection(&self) -> Option<quinn::Connection> {
+        let to_peer = match self.to_peer {
+            ToPeer::Established { ref q_conn, .. } => q_conn.quinn_connection(),
+            _ => None,
+        };
+        let from_peer = || match self.from_peer {
+            FromPeer::Established { ref q_conn, .. } => q_conn.quinn_connection(),
+            FromPeer::NoConnection | FromPeer::NotNeeded => None,
+        };
+        to_peer.or_else(from_peer).cloned()
+    }
+
+    /// Record the error the connection ended with, reported once it is dropped. Only connections
+    /// which were established are reported as closed, those we never made are reported as
+    /// failures. A peer refusing our version of the wire protocol is reported as
+    /// `Error::ProtocolVersionRefused` rather than as it closing the connection.
+    pub fn ended_by(&mut self, e: &Error) {
+        match CloseReason::from_err(e) {
+            Some(CloseReason::PeerClosed { error_code, .. })
+                if error_code == PROTOCOL_VERSION_REFUSED_CODE =>
+            {
+                self.cancel_reason = Some(Error::ProtocolVersionRefused(PROTOCOL_VERSION));
+            }
+            close_reason => {
+                if self.to_peer.is_established() || self.from_peer.is_established() {
+                    self.close_reason = close_reason;
+                }
+            }
+        }
+    }
+
+    /// Record the error we failed to connect to the peer with. Any connection from the peer is
+    /// severed by us, so this is reported as a failure rather than as the connection closing.
+    pub fn failed_by(&mut self, e: &Error) {
+        if let Some(CloseReason::PeerClosed { error_code, .. }) = CloseReason::from_err(e) {
+            if error_code == PROTOCOL_VERSION_REFUSED_CODE {
+                self.cancel_reason = Some(Error::ProtocolVersionRefused(PROTOCOL_VERSION));
+            }
+        }
+    }
+
+    /// Whether the connection with the given statistics is no longer the one we read from the peer
+    /// over, having been superseded by another connection from the peer. The connection is
+    /// forgotten if it was one of `duplicate_from_peer`. Failures of such connections are no
+    /// reason to drop the peer.
+    pub fn forget_superseded(&mut self, stats: &StatsHandle) -> bool {
+        let is_conn = |q_conn: &QConn| Rc::ptr_eq(q_conn.stats(), stats);
+
+        if let Some(pos) = self.duplicate_from_peer.iter().position(is_conn) {
+            let _ = self.duplicate_from_peer.remove(pos);
+            return true;
+        }
+        if let ToPeer::Established { ref q_conn, .. } = self.to_peer {
+            if is_conn(q_conn) {
+                return false;
+            }
+        }
+        match self.from_peer {
+            FromPeer::Established { ref q_conn, .. } => !is_conn(q_conn),
+            FromPeer::NoConnection | FromPeer::NotNeeded => false,
         }
     }
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        if (self.to_peer.is_established() || self.to_peer.is_not_needed())
-            && (self.from_peer.is_established() || self.from_peer.is_not_needed())
+        let is_complete = self.is_complete();
+        #[cfg(feature = "otel")]
+        crate::otel::end_connection_span(
+            &mut self.otel_span,
+            is_complete,
+            self.close_reason.as_ref(),
+        );
+
+        // Messages waiting for the connection to be made never will be sent on it
+        if let ToPeer::Initiated {
+            ref mut pending_sends,
+            ..
+        } = self.to_peer
         {
+            let unsent = pending_sends
+                .drain(..)
+                .filter_map(|(wire_msg, _)| wire_msg.user_msg().cloned())
+                .collect();
+            outcome::unsent_later(self.peer_addr, unsent);
+        }
+
+        if is_complete && !self.has_moved {
             // No need to log these as this will fire even when the QuicP2p handle is dropped and at
             // that point there might be no one listening so sender will error out
-            let _ = self.event_tx.send(Event::ConnectionFailure {
-                peer_addr: self.peer_addr,
-                err: Error::ConnectionCancelled,
-            });
+            let (event, forget) = if let Some(reason) = self.close_reason.take() {
+                let event = Event::ConnectionClosed {
+                    peer_addr: self.peer_addr,
+                    reason,
+                };
+                (event, false)
+            } else {
+                let err = self
+                    .cancel_reason
+                    .take()
+                    .unwrap_or(Error::ConnectionCancelled);
+                let forget = outcome::is_ended_by_us(&err);
+                let event = Event::ConnectionFailure {
+                    peer_addr: self.peer_addr,
+                    err,
+                };
+                (event, forget)
+            };
+            let _ = self.event_tx.send(event);
+            outcome::connection_ended_later(self.peer_addr, forget);
+            reconnect::dropped_later(self.peer_addr);
         }
     }
 }
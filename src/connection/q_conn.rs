@@ -7,35 +7,51 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use std::ops::{Deref, DerefMut};
+use crate::stats::StatsHandle;
+use crate::transport::TransportConn;
+use crate::wire_msg::WireEncoding;
+use std::ops::Deref;
 
-/// A quic-connection wrapper that will destroy the connection on drop
+/// A wrapper around the connection of our transport that will destroy the connection on drop
 pub struct QConn {
-    q_conn: quinn::Connection,
+    q_conn: Box<dyn TransportConn>,
+    stats: StatsHandle,
+    encoding: WireEncoding,
 }
 
-impl From<quinn::Connection> for QConn {
-    fn from(q_conn: quinn::Connection) -> Self {
-        Self { q_conn }
+impl QConn {
+    /// Statistics of this connection.
+    pub fn stats(&self) -> &StatsHandle {
+        &self.stats
     }
-}
 
-impl Deref for QConn {
-    type Target = quinn::Connection;
+    /// Encoding of the wire messages negotiated for this connection.
+    pub fn encoding(&self) -> WireEncoding {
+        self.encoding
+    }
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.q_conn
+impl From<Box<dyn TransportConn>> for QConn {
+    fn from(q_conn: Box<dyn TransportConn>) -> Self {
+        let encoding = WireEncoding::from_alpn_protocol(q_conn.protocol().as_deref());
+        Self {
+            q_conn,
+            stats: Default::default(),
+            encoding,
+        }
     }
 }
 
-impl DerefMut for QConn {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.q_conn
+impl Deref for QConn {
+    type Target = dyn TransportConn;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.q_conn
     }
 }
 
 impl Drop for QConn {
     fn drop(&mut self) {
-        self.q_conn.close(0, &[]);
+        self.q_conn.close();
     }
 }
@@ -0,0 +1,270 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A `Race` connects to a node reachable at several addresses the Happy Eyeballs way (RFC 8305).
+//! Attempts alternate between address families and each starts `CONNECTION_ATTEMPT_DELAY` after
+//! the previous one, or as soon as the previous one failed. The first attempt to succeed wins and
+//! the others are cancelled, so that a broken address family costs at most a fraction of a second.
+
+use crate::connect;
+use crate::context::ctx_mut;
+use crate::error::Error;
+use crate::event::Event;
+use crate::outcome;
+use crate::peer::NodeInfo;
+use crate::sockets;
+use crate::utils::ConnectTerminator;
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::prelude::Future;
+use tokio::runtime::current_thread;
+use tokio::timer::Delay;
+
+/// Time to give an attempt before starting the next one, as recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Shared state of the connection attempts to a node. Every attempt's `Connection` holds a clone.
+#[derive(Clone)]
+pub struct Race(Rc<RefCell<Inner>>);
+
+struct Inner {
+    peer_cert_der: Vec<u8>,
+    /// Address reported in the `ConnectionFailure` event if all attempts fail.
+    first_addr: SocketAddr,
+    pending: VecDeque<SocketAddr>,
+    in_flight: HashSet<SocketAddr>,
+    last_err: Option<Error>,
+    is_finished: bool,
+}
+
+impl Race {
+    /// Start racing connection attempts to the node at the given addresses.
+    pub fn start(peer_addrs: Vec<SocketAddr>, peer_cert_der: Vec<u8>) {
+        let pending = interleave_families(peer_addrs);
+        if let Some(&first_addr) = pending.front() {
+            Self::start_ordered(pending, first_addr, peer_cert_der);
+        }
+    }
+
+    /// Start racing connection attempts to the node at its alternate addresses on one of our
+    /// subnets first, then at its main address and then at its other alternate addresses. If
+    /// all fail the `ConnectionFailure` event is fired for the main address.
+    pub fn start_preferring_lan(node_info: NodeInfo) {
+        let pending = lan_first(&node_info, sockets::on_local_subnet);
+        Self::start_ordered(pending, node_info.peer_addr, node_info.peer_cert_der);
+    }
+
+    fn start_ordered(
+        pending: VecDeque<SocketAddr>,
+        first_addr: SocketAddr,
+        peer_cert_der: Vec<u8>,
+    ) {
+        Race(Rc::new(RefCell::new(Inner {
+            peer_cert_der,
+            first_addr,
+            pending,
+            in_flight: Default::default(),
+            last_err: None,
+            is_finished: false,
+        })))
+        .start_next_attempt();
+    }
+
+    /// The attempt to the given address succeeded: cancel all others.
+    pub fn won(&self, peer_addr: SocketAddr) {
+        let losers = {
+            let mut inner = self.0.borrow_mut();
+            inner.is_finished = true;
+            inner.pending.clear();
+            let _ = inner.in_flight.remove(&peer_addr);
+            mem::take(&mut inner.in_flight)
+        };
+
+        // Removing the connections drops their `Race` clones, so `self.0` mustn't be borrowed
+        ctx_mut(|c| {
+            for loser in losers {
+                if let Some(conn) = c.connections.remove(&loser) {
+                    if let Some(mut terminator) = initiated_terminator(&conn.to_peer) {
+                        let _ = terminator.try_send(());
+                    }
+                }
+            }
+        });
+    }
+
+    /// The attempt to the given address failed: move on to the next address straight away.
+    pub fn lost(&self, peer_addr: SocketAddr, e: Error) {
+        {
+            let mut inner = self.0.borrow_mut();
+            let _ = inner.in_flight.remove(&peer_addr);
+            inner.last_err = Some(e);
+        }
+        self.start_next_attempt();
+    }
+
+    fn start_next_attempt(&self) {
+        loop {
+            let (peer_addr, peer_cert_der) = {
+                let mut inner = self.0.borrow_mut();
+                if inner.is_finished {
+                    return;
+                }
+                match inner.pending.pop_front() {
+                    Some(peer_addr) => (peer_addr, inner.peer_cert_der.clone()),
+                    None => {
+                        if inner.in_flight.is_empty() {
+                            inner.is_finished = true;
+                            let peer_addr = inner.first_addr;
+                            let err = inner.last_err.take().unwrap_or(Error::ConnectionCancelled);
+                            drop(inner);
+                            let forget = outcome::is_ended_by_us(&err);
+                            ctx_mut(|c| {
+                                let _ =
+                                    c.event_tx.send(Event::ConnectionFailure { peer_addr, err });
+                                outcome::connection_ended(c, peer_addr, forget);
+                            });
+                        }
+                        return;
+                    }
+                }
+            };
+
+            let node_info = NodeInfo {
+                peer_addr,
+                peer_cert_der,
+                alt_addrs: Vec::new(),
+            };
+            if let Err(e) = connect::connect_to(node_info, None, None) {
+                debug!("Could not start connecting to {}: {}", peer_addr, e);
+                self.0.borrow_mut().last_err = Some(e);
+                continue;
+            }
+
+            let _ = self.0.borrow_mut().in_flight.insert(peer_addr);
+            ctx_mut(|c| {
+                if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                    conn.race = Some(self.clone());
+                    conn.we_contacted_peer = true;
+                }
+            });
+            self.spawn_attempt_timer(peer_addr);
+            return;
+        }
+    }
+
+    /// Start the next attempt if the one to the given address is still in flight by then.
+    fn spawn_attempt_timer(&self, peer_addr: SocketAddr) {
+        let race = Rc::downgrade(&self.0);
+        let leaf = Delay::new(Instant::now() + CONNECTION_ATTEMPT_DELAY).then(move |r| {
+            if let Err(e) = r {
+                info!("Error in connection attempt delay: {:?}", e);
+            }
+            if let Some(race) = race.upgrade().map(Race) {
+                let is_in_flight = race.0.borrow().in_flight.contains(&peer_addr);
+                if is_in_flight {
+                    race.start_next_attempt();
+                }
+            }
+            Ok(())
+        });
+
+        current_thread::spawn(leaf);
+    }
+}
+
+fn initiated_terminator(to_peer: &super::ToPeer) -> Option<ConnectTerminator> {
+    if let super::ToPeer::Initiated { ref terminator, .. } = *to_peer {
+        Some(terminator.clone())
+    } else {
+        None
+    }
+}
+
+/// Order the addresses alternating between families, starting with the family of the first one.
+fn interleave_families(peer_addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_is_ipv6 = match peer_addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return Default::default(),
+    };
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = peer_addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut ordered = VecDeque::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+/// The node's addresses for which `is_local` holds first, then its main address and then the
+/// rest, each tried once.
+fn lan_first<F: Fn(IpAddr) -> bool>(node_info: &NodeInfo, is_local: F) -> VecDeque<SocketAddr> {
+    let (local, remote): (Vec<_>, Vec<_>) = node_info
+        .alt_addrs
+        .iter()
+        .cloned()
+        .partition(|addr| is_local(addr.ip()));
+
+    let mut ordered = VecDeque::with_capacity(node_info.alt_addrs.len() + 1);
+    for addr in local
+        .into_iter()
+        .chain(Some(node_info.peer_addr))
+        .chain(remote)
+    {
+        if !ordered.contains(&addr) {
+            ordered.push_back(addr);
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn families_are_interleaved() {
+        let v6_0: SocketAddr = unwrap!("[::1]:1".parse());
+        let v6_1: SocketAddr = unwrap!("[::1]:2".parse());
+        let v6_2: SocketAddr = unwrap!("[::1]:3".parse());
+        let v4_0: SocketAddr = unwrap!("127.0.0.1:1".parse());
+
+        assert_eq!(
+            interleave_families(vec![v6_0, v6_1, v6_2, v4_0]),
+            vec![v6_0, v4_0, v6_1, v6_2]
+        );
+        assert_eq!(
+            interleave_families(vec![v4_0, v6_0, v6_1]),
+            vec![v4_0, v6_0, v6_1]
+        );
+        assert!(interleave_families(vec![]).is_empty());
+    }
+
+    #[test]
+    fn alternates_on_our_subnets_go_first() {
+        let public: SocketAddr = unwrap!("192.0.2.1:5000".parse());
+        let lan: SocketAddr = unwrap!("10.0.0.2:5000".parse());
+        let other: SocketAddr = unwrap!("198.51.100.1:5000".parse());
+        let node_info = NodeInfo {
+            peer_addr: public,
+            peer_cert_der: vec![],
+            alt_addrs: vec![other, lan, public],
+        };
+        let is_lan = |ip: IpAddr| ip == lan.ip();
+
+        assert_eq!(lan_first(&node_info, is_lan), vec![lan, public, other]);
+        assert_eq!(lan_first(&node_info, |_| false), vec![public, other, lan]);
+    }
+}
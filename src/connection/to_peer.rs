@@ -10,7 +10,9 @@
 use crate::connection::QConn;
 use crate::utils::ConnectTerminator;
 use crate::wire_msg::WireMsg;
+use crate::DeliveryMode;
 use std::fmt;
+use std::time::Instant;
 
 /// Represent various stages of connection from us to the peer.
 pub enum ToPeer {
@@ -19,7 +21,8 @@ pub enum ToPeer {
     Initiated {
         terminator: ConnectTerminator,
         peer_cert_der: Vec<u8>,
-        pending_sends: Vec<WireMsg>,
+        pending_sends: Vec<(WireMsg, Option<DeliveryMode>)>,
+        initiated_at: Instant,
     },
     Established {
         peer_cert_der: Vec<u8>,
@@ -7,15 +7,40 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::app_ack::Acks;
 use crate::bootstrap_cache::BootstrapCache;
-use crate::config::{OurType, SerialisableCertificate};
+use crate::cert_expiry::CertExpiry;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{
+    BootstrapStrategy, DuplicateConnectionPolicy, OurType, SerialisableCertificate,
+};
 use crate::connection::Connection;
-use crate::event::Event;
-use crossbeam_channel as mpmc;
+use crate::dead_letter::DeadLetters;
+use crate::drain_order::DrainOrder;
+use crate::event_sender::EventSender;
+use crate::expiry::Expiries;
+use crate::file_transfer::{FileId, IncomingFile, OutgoingFile};
+use crate::forwarding::Forwarder;
+use crate::gossip::Gossip;
+use crate::listener::SubnetLimits;
+use crate::outcome::Outcomes;
+use crate::peer::Capabilities;
+use crate::pubsub::Topics;
+use crate::quorum::Quorums;
+use crate::reconnect::Reconnects;
+use crate::resend::{ResendPolicy, Resends};
+use crate::rng::Rng;
+use crate::stats::BootstrapStats;
+use crate::transport::Transport;
+use crate::wire_msg::WireEncoding;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::mpsc;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
 
 thread_local! {
     pub static CTX: RefCell<Option<Context>> = RefCell::new(None);
@@ -78,46 +103,140 @@ where
 /// The context to the event loop. This holds all the states that are necessary to be persistant
 /// between calls to poll the event loop for the next event.
 pub struct Context {
-    pub event_tx: mpmc::Sender<Event>,
+    pub event_tx: EventSender,
     pub connections: HashMap<SocketAddr, Connection>,
     pub our_ext_addr_tx: Option<mpsc::Sender<SocketAddr>>,
+    /// Set while `QuicP2p::detect_nat` waits for peers to tell which address they see us at.
+    pub nat_echo_tx: Option<mpsc::Sender<(SocketAddr, SocketAddr)>>,
     pub our_complete_cert: SerialisableCertificate,
     pub max_msg_size_allowed: usize,
     pub idle_timeout_msec: u64,
     pub keep_alive_interval_msec: u32,
     pub our_type: OurType,
+    pub duplicate_connection_policy: DuplicateConnectionPolicy,
+    pub bootstrap_strategy: BootstrapStrategy,
+    /// Default for `Connection::ordered_delivery`, see `Config::ordered_delivery`.
+    pub ordered_delivery: bool,
+    /// Versions of the wire protocol we accept from peers, see `Config::min_protocol_version`.
+    pub protocol_versions: RangeInclusive<u16>,
+    /// Time connections we make have to be established in, see `Config::connect_timeout_msec`.
+    pub connect_timeout: Option<Duration>,
+    /// Most connections peers of a subnet may have to us, see
+    /// `Config::max_incoming_per_v4_subnet`.
+    pub subnet_limits: SubnetLimits,
+    /// Default for the messages the user sends, see `Builder::with_resend_policy`.
+    pub resend_policy: Option<ResendPolicy>,
+    /// The user messages to send again should they fail, see `QuicP2p::send_with_resend`.
+    pub resends: Resends,
+    /// Peer and token of the user messages sent, awaiting their outcome.
+    pub outcomes: Outcomes,
+    /// See `QuicP2p::send_to_quorum`.
+    pub quorums: Quorums,
+    /// See `QuicP2p::send_with_ttl`.
+    pub expiries: Expiries,
+    /// See `Builder::with_app_acks`.
+    pub acks: Acks,
+    /// See `Builder::with_sent_events`.
+    pub sent_events: bool,
+    /// Set if we collect the user messages which couldn't be sent, see
+    /// `Builder::with_dead_letters`.
+    pub dead_letters: Option<DeadLetters>,
+    /// The peers to reconnect to, see `QuicP2p::make_sticky`.
+    pub reconnects: Reconnects,
     pub bootstrap_cache: BootstrapCache,
-    pub(crate) quic_ep: quinn::Endpoint,
+    pub bootstrap_stats: Rc<RefCell<BootstrapStats>>,
+    pub lifecycle_events: bool,
+    pub dump_state_on_panic: bool,
+    pub wire_encoding: WireEncoding,
+    /// Set if we relay messages between our clients, see `Builder::with_client_forwarding`.
+    pub forwarder: Option<Forwarder>,
+    /// Advertised in our handshakes, see `Builder::with_capabilities`.
+    pub our_capabilities: Capabilities,
+    /// Files peers started sending us, see `QuicP2p::receive_file`.
+    pub incoming_files: HashMap<FileId, IncomingFile>,
+    /// Numbers the files we send too, to tell them apart in `outgoing_files`.
+    pub next_file_id: u64,
+    /// Files we are sending, see `QuicP2p::cancel_stream`.
+    pub outgoing_files: HashMap<u64, OutgoingFile>,
+    /// See `Builder::with_progress_events`.
+    pub progress_events_above: Option<u64>,
+    /// See `QuicP2p::subscribe`.
+    pub topics: Topics,
+    /// See `QuicP2p::gossip`.
+    pub gossip: Gossip,
+    /// See `Builder::with_drain_order`.
+    pub drain_order: DrainOrder,
+    /// See `Builder::with_cert_expiry_monitoring`.
+    pub cert_expiry: CertExpiry,
+    /// Most user messages and bytes queued per peer, see `Builder::with_send_queue_limit`.
+    pub send_queue_limit: Option<(usize, usize)>,
+    /// State dumps go here in place of the project logs directory, see
+    /// `Builder::with_dir_overrides`.
+    pub logs_dir: Option<PathBuf>,
+    /// Tells the time timeouts and ages are measured against, see `Builder::with_clock`.
+    pub clock: Arc<dyn Clock>,
+    /// Takes the random decisions, see `Builder::with_rng_seed`.
+    pub rng: Rng,
+    pub(crate) transport: Rc<dyn Transport>,
 }
 
 impl Context {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        event_tx: mpmc::Sender<Event>,
+        event_tx: EventSender,
         our_complete_cert: SerialisableCertificate,
         max_msg_size_allowed: usize,
         idle_timeout_msec: u64,
         keep_alive_interval_msec: u32,
         our_type: OurType,
         bootstrap_cache: BootstrapCache,
-        quic_ep: quinn::Endpoint,
+        transport: Rc<dyn Transport>,
     ) -> Self {
         Self {
             event_tx,
             connections: Default::default(),
             our_ext_addr_tx: Default::default(),
+            nat_echo_tx: None,
             our_complete_cert,
             max_msg_size_allowed,
             idle_timeout_msec,
             keep_alive_interval_msec,
             our_type,
+            duplicate_connection_policy: Default::default(),
+            bootstrap_strategy: Default::default(),
+            ordered_delivery: false,
+            protocol_versions: 0..=u16::MAX,
+            connect_timeout: None,
+            subnet_limits: Default::default(),
+            resend_policy: None,
+            resends: Default::default(),
+            outcomes: Default::default(),
+            quorums: Default::default(),
+            expiries: Default::default(),
+            acks: Default::default(),
+            sent_events: false,
+            dead_letters: None,
+            reconnects: Default::default(),
             bootstrap_cache,
-            quic_ep,
+            bootstrap_stats: Default::default(),
+            lifecycle_events: false,
+            dump_state_on_panic: false,
+            wire_encoding: Default::default(),
+            forwarder: None,
+            our_capabilities: Default::default(),
+            incoming_files: Default::default(),
+            next_file_id: 0,
+            outgoing_files: Default::default(),
+            progress_events_above: None,
+            topics: Default::default(),
+            gossip: Default::default(),
+            drain_order: Default::default(),
+            cert_expiry: Default::default(),
+            send_queue_limit: None,
+            logs_dir: None,
+            clock: Arc::new(SystemClock),
+            rng: Default::default(),
+            transport,
         }
     }
-
-    #[cfg(not(test))]
-    pub fn quic_ep(&self) -> &quinn::Endpoint {
-        &self.quic_ep
-    }
 }
@@ -0,0 +1,143 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Collecting the user messages which couldn't be sent for the application to reconcile later,
+//! see `Builder::with_dead_letters`.
+
+use crate::event::Token;
+use crate::persistence;
+use crate::utils::R;
+use std::collections::VecDeque;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// User message which couldn't be sent, as returned by `QuicP2p::drain_dead_letters`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// Peer the message was meant for.
+    pub peer_addr: SocketAddr,
+    /// The message.
+    pub msg: bytes::Bytes,
+    /// Token the message was sent with, see `Event::UnsentUserMessage`.
+    pub token: Token,
+}
+
+/// Dead letters kept in memory up to a capacity, beyond which they are spilled to a file if
+/// given, else the oldest is dropped.
+pub struct DeadLetters {
+    capacity: usize,
+    spill_path: Option<PathBuf>,
+    letters: VecDeque<DeadLetter>,
+}
+
+impl DeadLetters {
+    pub fn new(capacity: usize, spill_path: Option<PathBuf>) -> Self {
+        Self {
+            capacity,
+            spill_path,
+            letters: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Queue the message, making room for it first if the queue is full.
+    pub fn push(&mut self, letter: DeadLetter) {
+        if self.letters.len() >= self.capacity && !self.spill() {
+            if let Some(dropped) = self.letters.pop_front() {
+                debug!("Dropping the dead letter to {}", dropped.peer_addr);
+            }
+        }
+        if self.capacity > 0 {
+            self.letters.push_back(letter);
+        }
+    }
+
+    /// Take all the messages queued, oldest first, including those spilled to the file.
+    pub fn drain(&mut self) -> R<Vec<DeadLetter>> {
+        let mut drained = self.read_spilled()?;
+        if let Some(ref path) = self.spill_path {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        drained.extend(self.letters.drain(..));
+        Ok(drained)
+    }
+
+    /// Append the messages queued to the spill file, returning whether they were.
+    fn spill(&mut self) -> bool {
+        let path = match self.spill_path {
+            Some(ref path) => path.clone(),
+            None => return false,
+        };
+        let res = self.read_spilled().and_then(|mut spilled| {
+            spilled.extend(self.letters.iter().cloned());
+            persistence::write_to_disk(&path, &spilled)
+        });
+        match res {
+            Ok(()) => {
+                self.letters.clear();
+                true
+            }
+            Err(e) => {
+                warn!("Could not spill dead letters to {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    fn read_spilled(&self) -> R<Vec<DeadLetter>> {
+        match self.spill_path {
+            Some(ref path) if path.exists() => persistence::read_from_disk(path),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_rand_dir;
+
+    fn letter(n: u8) -> DeadLetter {
+        DeadLetter {
+            peer_addr: ([203, 0, 113, 1], 1000).into(),
+            msg: bytes::Bytes::from(vec![n]),
+            token: n.into(),
+        }
+    }
+
+    #[test]
+    fn oldest_letters_are_dropped_without_a_spill_file() {
+        let mut letters = DeadLetters::new(2, None);
+        for n in 0..3 {
+            letters.push(letter(n));
+        }
+        assert_eq!(unwrap!(letters.drain()), vec![letter(1), letter(2)]);
+        assert!(unwrap!(letters.drain()).is_empty());
+    }
+
+    #[test]
+    fn letters_beyond_the_capacity_are_spilled() {
+        let dir = tmp_rand_dir();
+        unwrap!(fs::create_dir_all(&dir));
+        let path = dir.join("dead_letters");
+
+        let mut letters = DeadLetters::new(2, Some(path.clone()));
+        for n in 0..5 {
+            letters.push(letter(n));
+        }
+        assert!(path.exists());
+        assert_eq!(
+            unwrap!(letters.drain()),
+            (0..5).map(letter).collect::<Vec<_>>()
+        );
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Suppressing duplicates of the user messages a peer sends with an ID, see
+//! `QuicP2p::send_with_id`.
+
+use std::collections::{HashSet, VecDeque};
+
+/// Number of the latest message IDs remembered for each peer.
+pub const WINDOW: usize = 1024;
+
+/// IDs of the latest messages received from a peer, the oldest forgotten once `WINDOW` of them
+/// are remembered.
+#[derive(Default)]
+pub struct SeenMsgIds {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl SeenMsgIds {
+    /// Remember the ID of a message received, returning whether it wasn't seen before.
+    pub fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                let _ = self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Whether the ID is remembered.
+    pub fn contains(&self, id: u64) -> bool {
+        self.ids.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_remembered_within_the_window() {
+        let mut seen = SeenMsgIds::default();
+        assert!(seen.insert(7));
+        assert!(!seen.insert(7));
+
+        for id in 100..100 + WINDOW as u64 - 1 {
+            assert!(seen.insert(id));
+        }
+        assert!(!seen.insert(7));
+
+        // Pushes 7 out of the window
+        assert!(seen.insert(1));
+        assert!(seen.insert(7));
+        assert_eq!(seen.order.len(), WINDOW);
+        assert_eq!(seen.ids.len(), WINDOW);
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Serde of DER encoded certificates and keys, for use with `#[serde(with = "der_text")]`.
+//!
+//! Human readable formats like TOML and JSON get a base64 string and accept a base64 string, PEM
+//! text or an array of bytes. Binary formats, including the wire encodings, keep the raw bytes.
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serializer};
+use std::fmt;
+
+const PEM_BEGIN: &str = "-----BEGIN ";
+const PEM_END: &str = "-----END ";
+
+pub fn serialize<S: Serializer>(der: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&base64::encode(der))
+    } else {
+        serializer.collect_seq(der)
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(DerVisitor)
+    } else {
+        Vec::deserialize(deserializer)
+    }
+}
+
+struct DerVisitor;
+
+impl<'de> Visitor<'de> for DerVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DER bytes as a base64 string, PEM text or an array of bytes"
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        if s.contains(PEM_BEGIN) {
+            from_pem(s).map_err(E::custom)
+        } else {
+            base64::decode(s.trim()).map_err(E::custom)
+        }
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut der = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(b) = seq.next_element()? {
+            der.push(b);
+        }
+        Ok(der)
+    }
+}
+
+/// PEM text of the given DER bytes with the given label, e.g. `CERTIFICATE` or `PRIVATE KEY`.
+#[cfg(test)]
+fn to_pem(der: &[u8], label: &str) -> String {
+    let b64 = base64::encode(der);
+    let mut pem = format!("{}{}-----\n", PEM_BEGIN, label);
+    for line in b64.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("{}{}-----\n", PEM_END, label));
+    pem
+}
+
+/// DER bytes of the first PEM block in the given text, whatever its label.
+fn from_pem(pem: &str) -> Result<Vec<u8>, String> {
+    let (_, body) = pem
+        .split_once(PEM_BEGIN)
+        .and_then(|(_, rest)| rest.split_once('\n'))
+        .ok_or("missing PEM header")?;
+    let (body, _) = body.split_once(PEM_END).ok_or("missing PEM footer")?;
+    let b64: String = body.split_whitespace().collect();
+    base64::decode(&b64).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Cert {
+        #[serde(with = "super")]
+        der: Vec<u8>,
+    }
+
+    #[test]
+    fn der_is_text_in_human_readable_formats_only() {
+        let cert = Cert {
+            der: (0..100).collect(),
+        };
+
+        let json = unwrap!(serde_json::to_string(&cert));
+        assert_eq!(
+            json,
+            format!("{{\"der\":\"{}\"}}", base64::encode(&cert.der))
+        );
+        assert_eq!(unwrap!(serde_json::from_str::<Cert>(&json)), cert);
+
+        let toml = unwrap!(toml::to_string(&cert));
+        assert_eq!(unwrap!(toml::from_str::<Cert>(&toml)), cert);
+
+        let bin = unwrap!(bincode::serialize(&cert));
+        assert_eq!(
+            bin,
+            unwrap!(bincode::serialize(&(0..100).collect::<Vec<u8>>()))
+        );
+        assert_eq!(unwrap!(bincode::deserialize::<Cert>(&bin)), cert);
+    }
+
+    #[test]
+    fn der_is_read_from_pem_base64_or_bytes() {
+        let der: Vec<u8> = (0..100).collect();
+        let pem = to_pem(&der, "CERTIFICATE");
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----\n"));
+        assert!(pem.lines().all(|line| line.len() <= 64));
+
+        for json in &[
+            serde_json::json!({ "der": pem }),
+            serde_json::json!({ "der": base64::encode(&der) }),
+            serde_json::json!({ "der": der }),
+        ] {
+            assert_eq!(
+                unwrap!(serde_json::from_value::<Cert>(json.clone())).der,
+                der
+            );
+        }
+
+        let toml = format!("der = '''\n{}'''\n", pem);
+        assert_eq!(unwrap!(toml::from_str::<Cert>(&toml)).der, der);
+
+        for bad in &["-----BEGIN CERTIFICATE-----\nAAEC\n", "not*base64"] {
+            assert!(serde_json::from_value::<Cert>(serde_json::json!({ "der": bad })).is_err());
+        }
+    }
+}
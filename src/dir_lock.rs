@@ -0,0 +1,137 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Advisory locks keeping a second instance off the directories overridden for another one, see
+//! `Builder::with_dir_overrides`.
+//!
+//! Each directory holds a `.lock` file locked for as long as the instance lives. The lock is
+//! released by the OS should the process die, so a crashed instance never keeps a directory
+//! locked. The default project directories are shared by every instance of a user and so are
+//! never locked.
+
+use crate::dirs::DirOverrides;
+use crate::error::Error;
+use crate::utils::R;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Locks of the overridden directories, released on drop.
+pub struct DirLock {
+    #[allow(unused)]
+    files: Vec<File>,
+}
+
+impl DirLock {
+    /// Lock every directory overridden which holds our state, failing with
+    /// `Error::AlreadyRunning` should another instance hold one of them.
+    pub fn acquire(overrides: &DirOverrides) -> R<Self> {
+        let mut dirs: Vec<&PathBuf> = [
+            &overrides.config_dir,
+            &overrides.cache_dir,
+            &overrides.keys_dir,
+        ]
+        .iter()
+        .filter_map(|dir| dir.as_ref())
+        .collect();
+        dirs.sort();
+        dirs.dedup();
+
+        let files = dirs
+            .into_iter()
+            .map(|dir| lock(dir))
+            .collect::<R<Vec<_>>>()?;
+        Ok(Self { files })
+    }
+}
+
+fn lock(dir: &Path) -> R<File> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(".lock");
+    match lock_file(&path) {
+        Ok(file) => Ok(file),
+        Err(ref e) if is_contended(e) => Err(Error::AlreadyRunning(dir.to_path_buf())),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn lock_file(path: &Path) -> io::Result<File> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new().create(true).write(true).open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(windows)]
+fn lock_file(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // Opening without sharing keeps every other handle off the file until ours is closed
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .share_mode(0)
+        .open(path)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn lock_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).write(true).open(path)
+}
+
+#[cfg(unix)]
+fn is_contended(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EWOULDBLOCK)
+}
+
+#[cfg(windows)]
+fn is_contended(e: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    e.raw_os_error() == Some(ERROR_SHARING_VIOLATION)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_contended(_e: &io::Error) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_rand_dir;
+
+    #[test]
+    fn overridden_dirs_are_locked_until_dropped() {
+        let root = tmp_rand_dir();
+        let overrides = DirOverrides {
+            cache_dir: Some(root.join("cache")),
+            keys_dir: Some(root.join("keys")),
+            ..Default::default()
+        };
+
+        let lock = unwrap!(DirLock::acquire(&overrides));
+        // Sharing but one of the directories is enough to be refused
+        let sharing_keys = DirOverrides {
+            keys_dir: Some(root.join("keys")),
+            ..Default::default()
+        };
+        match DirLock::acquire(&sharing_keys) {
+            Err(Error::AlreadyRunning(dir)) => assert_eq!(dir, root.join("keys")),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        drop(lock);
+        let _ = unwrap!(DirLock::acquire(&sharing_keys));
+    }
+}
@@ -7,6 +7,8 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::utils;
+use crate::R;
 #[cfg(any(
     all(
         unix,
@@ -33,9 +35,53 @@ impl OverRide {
     }
 }
 
+/// Directories to keep files of each kind in, in place of those of the platform, see
+/// `Builder::with_dir_overrides`. Those left out are the defaults of the platform, which on Linux
+/// and the BSDs are under `XDG_CONFIG_HOME` and `XDG_CACHE_HOME` if set. Platforms without
+/// default directories, e.g. mobile ones, need all of them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DirOverrides {
+    /// Directory of the config file.
+    pub config_dir: Option<PathBuf>,
+    /// Directory of the bootstrap cache.
+    pub cache_dir: Option<PathBuf>,
+    /// Directory of our persistent identity, see `Builder::with_persistent_identity`.
+    pub keys_dir: Option<PathBuf>,
+    /// Directory state dumps are written to, see `Builder::with_state_dump_on_panic`.
+    pub logs_dir: Option<PathBuf>,
+}
+
+impl DirOverrides {
+    /// The directories to use, unless none is overridden in which case the project directories
+    /// are looked up as they are needed.
+    pub(crate) fn dirs(&self) -> R<Option<Dirs>> {
+        if *self == Self::default() {
+            return Ok(None);
+        }
+        let base = match (utils::project_dir(), self.is_complete()) {
+            (Ok(base), _) => base,
+            // Never looked at, as every kind is overridden
+            (Err(_), true) => Dirs::Overide(OverRide {
+                path: PathBuf::new(),
+            }),
+            (Err(e), false) => return Err(e),
+        };
+        Ok(Some(Dirs::Custom(Box::new(base), self.clone())))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.config_dir.is_some()
+            && self.cache_dir.is_some()
+            && self.keys_dir.is_some()
+            && self.logs_dir.is_some()
+    }
+}
+
 pub enum Dirs {
     #[allow(unused)]
     Overide(OverRide),
+    /// The directories of the first, but those overridden.
+    Custom(Box<Dirs>, DirOverrides),
     #[cfg(any(
         all(
             unix,
@@ -60,6 +106,7 @@ impl Dirs {
                 windows
             ))]
             Desktop(ref x) => x.config_dir(),
+            Custom(ref base, ref overrides) => or_base(&overrides.config_dir, || base.config_dir()),
         }
     }
 
@@ -76,6 +123,27 @@ impl Dirs {
                 windows
             ))]
             Desktop(ref x) => x.cache_dir(),
+            Custom(ref base, ref overrides) => or_base(&overrides.cache_dir, || base.cache_dir()),
+        }
+    }
+
+    /// Location of our persistent identity, by default the config directory.
+    pub(crate) fn keys_dir(&self) -> &Path {
+        match *self {
+            Dirs::Custom(ref base, ref overrides) => {
+                or_base(&overrides.keys_dir, || base.keys_dir())
+            }
+            _ => self.config_dir(),
+        }
+    }
+
+    /// Location state dumps are written to, by default the cache directory.
+    pub(crate) fn logs_dir(&self) -> &Path {
+        match *self {
+            Dirs::Custom(ref base, ref overrides) => {
+                or_base(&overrides.logs_dir, || base.logs_dir())
+            }
+            _ => self.cache_dir(),
         }
     }
 
@@ -93,6 +161,40 @@ impl Dirs {
                 windows
             ))]
             Desktop(ref x) => x.data_dir(),
+            Custom(ref base, _) => base.data_dir(),
         }
     }
 }
+
+fn or_base<'a, F: FnOnce() -> &'a Path>(dir: &'a Option<PathBuf>, base: F) -> &'a Path {
+    match *dir {
+        Some(ref dir) => dir.as_path(),
+        None => base(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overridden_dirs_fall_back_to_the_base() {
+        let base = Dirs::Overide(OverRide::new("/base"));
+        assert_eq!(base.keys_dir(), Path::new("/base"));
+        assert_eq!(base.logs_dir(), Path::new("/base"));
+
+        let dirs = Dirs::Custom(
+            Box::new(base),
+            DirOverrides {
+                cache_dir: Some(PathBuf::from("/cache")),
+                keys_dir: Some(PathBuf::from("/keys")),
+                ..Default::default()
+            },
+        );
+        assert_eq!(dirs.config_dir(), Path::new("/base"));
+        assert_eq!(dirs.cache_dir(), Path::new("/cache"));
+        assert_eq!(dirs.keys_dir(), Path::new("/keys"));
+        // Each kind on its own, logs don't follow the cache
+        assert_eq!(dirs.logs_dir(), Path::new("/base"));
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::communicate::DeliveryMode;
+use crate::wire_msg::WireMsg;
+use std::cmp::Reverse;
+use std::mem;
+
+/// Priority of a user message for `DrainOrder::Priority`, given its payload. Higher goes first.
+pub type MsgPriority = fn(&bytes::Bytes) -> u8;
+
+/// Order in which the messages queued while connecting to a peer are sent once connected, see
+/// `Builder::with_drain_order`.
+///
+/// Whichever the order, our own messages, e.g. those telling the peer our topics, go ahead of the
+/// user messages and keep their order.
+#[derive(Debug, Clone, Copy)]
+pub enum DrainOrder {
+    /// Oldest first, as they were sent.
+    Fifo,
+    /// Newest first, for protocols which would rather deliver the latest state than replay a
+    /// backlog.
+    Lifo,
+    /// Highest priority first, oldest first among those of the same priority.
+    Priority(MsgPriority),
+}
+
+impl Default for DrainOrder {
+    fn default() -> Self {
+        DrainOrder::Fifo
+    }
+}
+
+/// Put the queued messages in the given order for sending.
+pub fn sort(pending_sends: &mut Vec<(WireMsg, Option<DeliveryMode>)>, order: DrainOrder) {
+    match order {
+        DrainOrder::Fifo => (),
+        DrainOrder::Lifo => {
+            let (ours, users): (Vec<_>, Vec<_>) = mem::take(pending_sends)
+                .into_iter()
+                .partition(|(wire_msg, _)| wire_msg.user_msg().is_none());
+            *pending_sends = ours;
+            pending_sends.extend(users.into_iter().rev());
+        }
+        // Stable, and `None` sorts ahead of any priority
+        DrainOrder::Priority(priority) => pending_sends
+            .sort_by_key(|(wire_msg, _)| wire_msg.user_msg().map(|msg| Reverse(priority(msg)))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(msgs: &[&'static [u8]]) -> Vec<(WireMsg, Option<DeliveryMode>)> {
+        msgs.iter()
+            .map(|&msg| (WireMsg::UserMsg(msg.into()), None))
+            .chain(Some((WireMsg::EndpointEchoReq, None)))
+            .collect()
+    }
+
+    fn user_msgs(pending_sends: &[(WireMsg, Option<DeliveryMode>)]) -> Vec<&[u8]> {
+        pending_sends
+            .iter()
+            .map(|(wire_msg, _)| wire_msg.user_msg().map_or(&b"ours"[..], |msg| &msg[..]))
+            .collect()
+    }
+
+    #[test]
+    fn queued_msgs_are_sorted_in_the_order_asked_for() {
+        let mut pending_sends = queue(&[b"a1", b"b2", b"c1", b"d3"]);
+        sort(&mut pending_sends, DrainOrder::Fifo);
+        assert_eq!(
+            user_msgs(&pending_sends),
+            vec![&b"a1"[..], b"b2", b"c1", b"d3", b"ours"]
+        );
+
+        let mut pending_sends = queue(&[b"a1", b"b2", b"c1", b"d3"]);
+        sort(&mut pending_sends, DrainOrder::Lifo);
+        assert_eq!(
+            user_msgs(&pending_sends),
+            vec![&b"ours"[..], b"d3", b"c1", b"b2", b"a1"]
+        );
+
+        let mut pending_sends = queue(&[b"a1", b"b2", b"c1", b"d3"]);
+        sort(&mut pending_sends, DrainOrder::Priority(|msg| msg[1]));
+        assert_eq!(
+            user_msgs(&pending_sends),
+            vec![&b"ours"[..], b"d3", b"b2", b"a1", b"c1"]
+        );
+    }
+}
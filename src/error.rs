@@ -7,94 +7,433 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use std::io;
+use std::error::Error as StdError;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::mpsc;
+use std::{fmt, io};
 
-quick_error! {
-    #[derive(Debug)]
-     /// Error types encountered during the operation of this library.
-     pub enum Error {
-         /// Error type associated with I/O operations.
-         Io(e: io::Error) {
-             display("IO Error: {}", e)
-             from()
-         }
-         /// Errors encountered while reading from a QUIC stream.
-         Read(e: quinn::ReadError) {
-             display("Read Error: {}", e)
-             from()
-         }
-         /// Error returned when a Bi-directional stream is attempted by a peer.
-         BiDirectionalStreamAttempted(peer_addr: SocketAddr) {
-             display("Bi-directional stream attempted by peer {}", peer_addr)
-         }
-         /// Errors encountered while creating a new connection.
-         Connect(e: quinn::ConnectError) {
-             display("Connection Error: {}", e)
-             from()
-         }
-         /// Errors explaining why an established connection might be lost.
-         Connection(e: quinn::ConnectionError) {
-             display("Connection Error: {}", e)
-             from()
-         }
-         /// Errors encountered while creating a new endpoint.
-         Endpoint(e: quinn::EndpointError) {
-             display("Endpoint error: {}", e)
-             from()
-         }
-         /// Error encountered while parsing a certificate or key.
-         CertificateParseError(e: quinn::tls::ParseError) {
-             display("Certificate Parse Error: {}", e)
-             from()
-         }
-         /// Error returned when a duplicate connection to a peer is attempted.
-         DuplicateConnectionToPeer(peer_addr: SocketAddr) {
-             display("Duplicate connection attempted to peer {}", peer_addr)
-         }
-         /// Error returned when an endpoint echo server is not found.
-         NoEndpointEchoServerFound {
-             display("There's no endpoint echo server to ask. Current network configuration")
-         }
-         /// Error returned when a receive operation on a channel fails.
-         OneShotRx(e: tokio::sync::oneshot::error::RecvError) {
-             display("Oneshot Receiver error: {}", e)
-             from()
-         }
-         /// Errors encountered while establishing TLS.
-         TLS(e: rustls::TLSError) {
-             display("TLE error: {}", e)
-             from()
-         }
-         /// Error produced when (de)serialisation is unsuccessful.
-         Bincode(e: bincode::Error) {
-             display("Bincode error: {}", e)
-             from()
-         }
-         /// Errors encountered while decoding Base64 values.
-         Base64(e: base64::DecodeError) {
-             display("Base64 decoding error: {}", e)
-             from()
-         }
-         /// Error produced when configuration is not understood.
-         Configuration(e: String) {
-             display("Configuration error: {}", e)
-         }
-         /// Error encountered while creating a new connection
-         OperationNotAllowed {
-             display("This operation is not allowed for us")
-         }
-        /// Connection Cancelled
-        ConnectionCancelled {
-            display("Connection was actively cancelled")
-            from()
+/// Error types encountered during the operation of this library.
+///
+/// Match on the variants (or their `source()`) to find the cause of a failure. Every variant has
+/// a stable numeric code, see `Error::code`. More variants may be added in the future.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Error type associated with I/O operations.
+    Io(io::Error),
+    /// Errors encountered while reading from a QUIC stream.
+    Read(quinn::ReadError),
+    /// Error returned when a Bi-directional stream is attempted by a peer.
+    BiDirectionalStreamAttempted(SocketAddr),
+    /// Errors encountered while creating a new connection.
+    Connect(quinn::ConnectError),
+    /// Errors explaining why an established connection might be lost.
+    Connection(quinn::ConnectionError),
+    /// Errors encountered while creating a new endpoint.
+    Endpoint(quinn::EndpointError),
+    /// Error encountered while parsing a certificate or key.
+    CertificateParseError(quinn::tls::ParseError),
+    /// Error returned when a duplicate connection to a peer is attempted.
+    DuplicateConnectionToPeer(SocketAddr),
+    /// Error returned when an endpoint echo server is not found.
+    NoEndpointEchoServerFound,
+    /// Error returned when a receive operation on a channel fails.
+    OneShotRx(tokio::sync::oneshot::error::RecvError),
+    /// Errors encountered while establishing TLS.
+    TLS(rustls::TLSError),
+    /// Error produced when (de)serialisation is unsuccessful.
+    Bincode(bincode::Error),
+    /// Error produced when JSON (de)serialisation is unsuccessful.
+    Json(serde_json::Error),
+    /// Error produced when parsing TOML is unsuccessful.
+    TomlDe(toml::de::Error),
+    /// Error produced when serialising to TOML is unsuccessful.
+    TomlSer(toml::ser::Error),
+    /// Error produced when MessagePack deserialisation is unsuccessful.
+    MessagePack(rmp_serde::decode::Error),
+    /// Error produced when a CBOR encoded wire message is invalid.
+    Cbor(String),
+    /// Error produced when a protobuf encoded wire message is invalid.
+    Protobuf(String),
+    /// A wire message of a type we don't know of was received, probably from a newer version of
+    /// quic-p2p.
+    UnknownWireMsg(u8),
+    /// Error produced when a tagged wire message is invalid.
+    Tagged(String),
+    /// Error produced when parsing a `NodeInfo` from its textual form fails.
+    InvalidNodeInfo(&'static str),
+    /// The given multiaddr is malformed or doesn't point to a QUIC endpoint.
+    InvalidMultiaddr(String),
+    /// The peer's address is of a family our endpoint can't reach, e.g. an IPv6 address while we
+    /// are bound to an IPv4 one.
+    UnreachableAddressFamily(SocketAddr),
+    /// The SOCKS5 proxy we relay our connections through refused or failed to relay a connection.
+    Socks5Proxy(String),
+    /// The WebSocket connection of a browser client failed.
+    WebSocket(String),
+    /// A file we wrote earlier is corrupted or truncated.
+    CorruptFile {
+        /// Path of the file.
+        path: PathBuf,
+        /// What is wrong with the file.
+        reason: &'static str,
+    },
+    /// Errors encountered while decoding Base64 values.
+    Base64(base64::DecodeError),
+    /// Error produced when configuration is not understood.
+    Configuration(String),
+    /// Error encountered while creating a new connection
+    OperationNotAllowed,
+    /// Connection Cancelled
+    ConnectionCancelled,
+    /// We disconnected a client which exchanged no messages with us for too long, see
+    /// `Builder::with_client_idle_timeout`.
+    IdleClientEvicted,
+    /// We disconnected a peer which missed the given number of probes in a row, see
+    /// `Builder::with_probes` and `Config::node_liveness_msec`.
+    ProbesUnanswered(u32),
+    /// The number of deliveries required by `QuicP2p::send_to_quorum` is zero or more than the
+    /// number of peers given.
+    InvalidQuorum {
+        /// Deliveries required.
+        required: usize,
+        /// Distinct peers given.
+        peers: usize,
+    },
+    /// Sending or receiving a file failed, see `QuicP2p::send_file`.
+    FileTransfer(String),
+    /// The sender of the file cancelled sending it, see `QuicP2p::cancel_stream`.
+    TransferCancelled,
+    /// Failed receiving from an `mpsc::channel`.
+    ChannelRecv(mpsc::RecvError),
+    /// An attempt to bootstrap off the given contact failed.
+    Bootstrap {
+        /// Address of the contact.
+        peer_addr: SocketAddr,
+        /// Why the attempt failed.
+        source: Box<Error>,
+    },
+    /// The QUIC handshake with the given peer failed.
+    Handshake {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Why the handshake failed.
+        source: Box<Error>,
+    },
+    /// Sending a message to the given peer failed.
+    Send {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Why sending failed.
+        source: Box<Error>,
+    },
+    /// The given `Config` field holds an unusable value.
+    Config {
+        /// Name of the field.
+        field: &'static str,
+        /// Why the value is unusable.
+        source: Box<Error>,
+    },
+    /// Two settings of the `Config` or `Builder` can't be used together.
+    ConflictingSettings {
+        /// Name of the setting rejected.
+        setting: &'static str,
+        /// Name of the setting it conflicts with.
+        conflicts_with: &'static str,
+        /// Why they can't be used together.
+        reason: String,
+    },
+    /// A setting of the `Config` or `Builder` holds a value which can't work, e.g. a zero
+    /// interval.
+    InvalidSetting {
+        /// Name of the setting.
+        setting: &'static str,
+        /// Why the value can't work.
+        reason: String,
+    },
+    /// A fault injected on purpose, see `Builder::with_chaos`.
+    InjectedFault(&'static str),
+    /// Another instance holds the lock of the given directory, see `Builder::with_dir_overrides`.
+    AlreadyRunning(PathBuf),
+    /// The connection was refused for the version of the wire protocol given, ours if the peer
+    /// refused us, see `Config::min_protocol_version`.
+    ProtocolVersionRefused(u16),
+}
+
+impl Error {
+    /// Stable numeric code of the error, e.g. for logs and metrics. Codes of existing variants
+    /// never change.
+    pub fn code(&self) -> u16 {
+        match *self {
+            Error::Io(_) => 1,
+            Error::Read(_) => 2,
+            Error::BiDirectionalStreamAttempted(_) => 3,
+            Error::Connect(_) => 4,
+            Error::Connection(_) => 5,
+            Error::Endpoint(_) => 6,
+            Error::CertificateParseError(_) => 7,
+            Error::DuplicateConnectionToPeer(_) => 8,
+            Error::NoEndpointEchoServerFound => 9,
+            Error::OneShotRx(_) => 10,
+            Error::TLS(_) => 11,
+            Error::Bincode(_) => 12,
+            Error::Base64(_) => 13,
+            Error::Configuration(_) => 14,
+            Error::OperationNotAllowed => 15,
+            Error::ConnectionCancelled => 16,
+            Error::ChannelRecv(_) => 17,
+            Error::Bootstrap { .. } => 18,
+            Error::Handshake { .. } => 19,
+            Error::Send { .. } => 20,
+            Error::Config { .. } => 21,
+            Error::Json(_) => 22,
+            Error::TomlDe(_) => 23,
+            Error::TomlSer(_) => 24,
+            Error::CorruptFile { .. } => 25,
+            Error::MessagePack(_) => 26,
+            Error::Cbor(_) => 27,
+            Error::Protobuf(_) => 28,
+            Error::UnknownWireMsg(_) => 29,
+            Error::Tagged(_) => 30,
+            Error::InvalidNodeInfo(_) => 31,
+            Error::InvalidMultiaddr(_) => 32,
+            Error::UnreachableAddressFamily(_) => 33,
+            Error::Socks5Proxy(_) => 34,
+            Error::WebSocket(_) => 35,
+            Error::IdleClientEvicted => 36,
+            Error::InvalidQuorum { .. } => 37,
+            Error::FileTransfer(_) => 38,
+            Error::ProbesUnanswered(_) => 39,
+            Error::TransferCancelled => 40,
+            Error::ConflictingSettings { .. } => 41,
+            Error::InvalidSetting { .. } => 42,
+            Error::InjectedFault(_) => 43,
+            Error::AlreadyRunning(_) => 44,
+            Error::ProtocolVersionRefused(_) => 45,
+        }
+    }
+
+    /// The innermost error of this library wrapped by `Bootstrap`, `Handshake`, `Send` or `Config`
+    /// errors, or this error itself if it doesn't wrap one.
+    pub fn root_cause(&self) -> &Error {
+        match *self {
+            Error::Bootstrap { ref source, .. }
+            | Error::Handshake { ref source, .. }
+            | Error::Send { ref source, .. }
+            | Error::Config { ref source, .. } => source.root_cause(),
+            ref e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "IO Error: {}", e),
+            Error::Read(ref e) => write!(f, "Read Error: {}", e),
+            Error::BiDirectionalStreamAttempted(ref peer_addr) => {
+                write!(f, "Bi-directional stream attempted by peer {}", peer_addr)
+            }
+            Error::Connect(ref e) => write!(f, "Connection Error: {}", e),
+            Error::Connection(ref e) => write!(f, "Connection Error: {}", e),
+            Error::Endpoint(ref e) => write!(f, "Endpoint error: {}", e),
+            Error::CertificateParseError(ref e) => write!(f, "Certificate Parse Error: {}", e),
+            Error::DuplicateConnectionToPeer(ref peer_addr) => {
+                write!(f, "Duplicate connection attempted to peer {}", peer_addr)
+            }
+            Error::NoEndpointEchoServerFound => write!(
+                f,
+                "There's no endpoint echo server to ask. Current network configuration"
+            ),
+            Error::OneShotRx(ref e) => write!(f, "Oneshot Receiver error: {}", e),
+            Error::TLS(ref e) => write!(f, "TLS error: {}", e),
+            Error::Bincode(ref e) => write!(f, "Bincode error: {}", e),
+            Error::Json(ref e) => write!(f, "JSON error: {}", e),
+            Error::TomlDe(ref e) => write!(f, "TOML parsing error: {}", e),
+            Error::TomlSer(ref e) => write!(f, "TOML serialisation error: {}", e),
+            Error::MessagePack(ref e) => write!(f, "MessagePack error: {}", e),
+            Error::Cbor(ref e) => write!(f, "Invalid CBOR wire message: {}", e),
+            Error::Protobuf(ref e) => write!(f, "Invalid protobuf wire message: {}", e),
+            Error::UnknownWireMsg(ref msg_type) => {
+                write!(f, "Wire message of unknown type {}", msg_type)
+            }
+            Error::Tagged(ref e) => write!(f, "Invalid tagged wire message: {}", e),
+            Error::InvalidNodeInfo(ref e) => write!(f, "Invalid node info: {}", e),
+            Error::InvalidMultiaddr(ref e) => write!(f, "Invalid QUIC multiaddr: {}", e),
+            Error::UnreachableAddressFamily(ref peer_addr) => write!(
+                f,
+                "Can't reach {} from an endpoint of the other address family",
+                peer_addr
+            ),
+            Error::Socks5Proxy(ref e) => write!(f, "SOCKS5 proxy error: {}", e),
+            Error::WebSocket(ref e) => write!(f, "WebSocket error: {}", e),
+            Error::CorruptFile {
+                ref path,
+                ref reason,
+            } => write!(f, "Corrupt file {}: {}", path.display(), reason),
+            Error::Base64(ref e) => write!(f, "Base64 decoding error: {}", e),
+            Error::Configuration(ref e) => write!(f, "Configuration error: {}", e),
+            Error::OperationNotAllowed => write!(f, "This operation is not allowed for us"),
+            Error::ConnectionCancelled => write!(f, "Connection was actively cancelled"),
+            Error::IdleClientEvicted => write!(f, "Client was evicted for being idle"),
+            Error::ProbesUnanswered(misses) => {
+                write!(f, "Peer left {} probes in a row unanswered", misses)
+            }
+            Error::InvalidQuorum { required, peers } => write!(
+                f,
+                "Can't require {} deliveries out of {} peers",
+                required, peers
+            ),
+            Error::FileTransfer(ref e) => write!(f, "File transfer error: {}", e),
+            Error::TransferCancelled => write!(f, "File transfer was cancelled by its sender"),
+            Error::ChannelRecv(ref e) => write!(f, "Channel receive error: {}", e),
+            Error::Bootstrap {
+                ref peer_addr,
+                ref source,
+            } => write!(f, "Bootstrapping off {} failed: {}", peer_addr, source),
+            Error::Handshake {
+                ref peer_addr,
+                ref source,
+            } => write!(f, "Handshake with {} failed: {}", peer_addr, source),
+            Error::Send {
+                ref peer_addr,
+                ref source,
+            } => write!(f, "Sending to {} failed: {}", peer_addr, source),
+            Error::Config {
+                ref field,
+                ref source,
+            } => write!(f, "Invalid config field `{}`: {}", field, source),
+            Error::ConflictingSettings {
+                ref setting,
+                ref conflicts_with,
+                ref reason,
+            } => write!(
+                f,
+                "Setting `{}` conflicts with `{}`: {}",
+                setting, conflicts_with, reason
+            ),
+            Error::InvalidSetting {
+                ref setting,
+                ref reason,
+            } => write!(f, "Invalid setting `{}`: {}", setting, reason),
+            Error::InjectedFault(ref fault) => write!(f, "Injected fault: {}", fault),
+            Error::AlreadyRunning(ref dir) => write!(
+                f,
+                "Another instance is running off the directory {}",
+                dir.display()
+            ),
+            Error::ProtocolVersionRefused(version) => {
+                write!(f, "Version {} of the wire protocol was refused", version)
+            }
         }
-        /// Failed receiving from an `mpsc::channel`.
-        ChannelRecv(e: mpsc::RecvError) {
-            display("Channel receive error: {}", e)
-            cause(e)
-            from()
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Read(ref e) => Some(e),
+            Error::Connect(ref e) => Some(e),
+            Error::Connection(ref e) => Some(e),
+            Error::Endpoint(ref e) => Some(e),
+            Error::CertificateParseError(ref e) => Some(e),
+            Error::OneShotRx(ref e) => Some(e),
+            Error::TLS(ref e) => Some(e),
+            Error::Bincode(ref e) => Some(e),
+            Error::Json(ref e) => Some(e),
+            Error::TomlDe(ref e) => Some(e),
+            Error::TomlSer(ref e) => Some(e),
+            Error::MessagePack(ref e) => Some(e),
+            Error::Base64(ref e) => Some(e),
+            Error::ChannelRecv(ref e) => Some(e),
+            Error::Bootstrap { ref source, .. }
+            | Error::Handshake { ref source, .. }
+            | Error::Send { ref source, .. }
+            | Error::Config { ref source, .. } => Some(&**source),
+            Error::BiDirectionalStreamAttempted(_)
+            | Error::DuplicateConnectionToPeer(_)
+            | Error::NoEndpointEchoServerFound
+            | Error::CorruptFile { .. }
+            | Error::Cbor(_)
+            | Error::Protobuf(_)
+            | Error::UnknownWireMsg(_)
+            | Error::Tagged(_)
+            | Error::InvalidNodeInfo(_)
+            | Error::InvalidMultiaddr(_)
+            | Error::UnreachableAddressFamily(_)
+            | Error::Socks5Proxy(_)
+            | Error::WebSocket(_)
+            | Error::Configuration(_)
+            | Error::OperationNotAllowed
+            | Error::ConnectionCancelled
+            | Error::IdleClientEvicted
+            | Error::ProbesUnanswered(_)
+            | Error::InvalidQuorum { .. }
+            | Error::FileTransfer(_)
+            | Error::TransferCancelled
+            | Error::ConflictingSettings { .. }
+            | Error::InvalidSetting { .. }
+            | Error::InjectedFault(_)
+            | Error::AlreadyRunning(_)
+            | Error::ProtocolVersionRefused(_) => None,
         }
-     }
+    }
+}
+
+macro_rules! impl_from {
+    ($($ty:ty => $variant:ident),* $(,)*) => {
+        $(
+            impl From<$ty> for Error {
+                fn from(e: $ty) -> Self {
+                    Error::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+impl_from! {
+    io::Error => Io,
+    quinn::ReadError => Read,
+    quinn::ConnectError => Connect,
+    quinn::ConnectionError => Connection,
+    quinn::EndpointError => Endpoint,
+    quinn::tls::ParseError => CertificateParseError,
+    tokio::sync::oneshot::error::RecvError => OneShotRx,
+    rustls::TLSError => TLS,
+    bincode::Error => Bincode,
+    serde_json::Error => Json,
+    toml::de::Error => TomlDe,
+    toml::ser::Error => TomlSer,
+    rmp_serde::decode::Error => MessagePack,
+    base64::DecodeError => Base64,
+    mpsc::RecvError => ChannelRecv,
+}
+
+impl From<()> for Error {
+    fn from(_: ()) -> Self {
+        Error::ConnectionCancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_errors_are_chained_via_source() {
+        let e = Error::Send {
+            peer_addr: ([127, 0, 0, 1], 1000).into(),
+            source: Box::new(Error::Io(io::ErrorKind::BrokenPipe.into())),
+        };
+
+        assert_eq!(e.code(), 20);
+        assert_eq!(e.root_cause().code(), 1);
+
+        let source = unwrap!(e.source());
+        let io_err = unwrap!(unwrap!(source.source()).downcast_ref::<io::Error>());
+        assert_eq!(io_err.kind(), io::ErrorKind::BrokenPipe);
+    }
 }
@@ -1,17 +1,46 @@
+use crate::bootstrap::{ContactFailure, ContactSource};
+use crate::config::SerialisableCertificate;
 use crate::error::Error;
-use crate::{utils, NodeInfo, Peer};
+use crate::file_transfer::FileId;
+use crate::quorum::QuorumSendId;
+use crate::{utils, Capabilities, NodeInfo, Peer};
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// Value the application sends a user message with, to tell which of its sends the
+/// `Event::SentUserMessage` or `Event::UnsentUserMessage` of the message is about. See
+/// `QuicP2p::send_with_token`; messages sent otherwise carry 0.
+pub type Token = u64;
+
+/// Logical channel of a connection, for applications running independent protocols over one
+/// connection to a peer. See `QuicP2p::send_on_channel`; messages sent otherwise are on
+/// `DEFAULT_CHANNEL`.
+pub type Channel = u16;
+
+/// The channel messages are sent on unless another one is given.
+pub const DEFAULT_CHANNEL: Channel = 0;
 
 /// QuicP2p Events to the user
 #[derive(Debug)]
 pub enum Event {
     /// Network bootstrap failed.
-    BootstrapFailure,
+    BootstrapFailure {
+        /// Why connecting failed, for each contact attempted.
+        failures: HashMap<SocketAddr, ContactFailure>,
+    },
     /// Bootstrap connection to this node was successful.
     BootstrappedTo {
         /// Node information.
         node: NodeInfo,
+        /// Time it took to establish our connection to the node.
+        handshake_duration: Duration,
+        /// Number of contacts attempted by the time the bootstrap succeeded, including this node.
+        candidates_tried: usize,
+        /// Where we got the node's contact from.
+        source: ContactSource,
     },
     /// Connection to this peer failed.
     ConnectionFailure {
@@ -20,17 +49,207 @@ pub enum Event {
         /// Error explaining connection failure.
         err: Error,
     },
-    /// The given message was not sent to this peer.
+    /// The given message was not sent to this peer, including when no connection to it could be
+    /// made.
     UnsentUserMessage {
-        /// Peer address.
-        peer_addr: SocketAddr,
+        /// Peer the message was sent to, as given to `QuicP2p::send`. `Peer::Client` for the
+        /// messages sent by address, e.g. with `QuicP2p::push`.
+        peer: Peer,
         /// Unsent message.
         msg: bytes::Bytes,
+        /// Token the message was sent with.
+        token: Token,
+        /// Why it wasn't sent.
+        reason: UnsentReason,
+    },
+    /// The given message was written to this peer in full, or handed to its application with
+    /// `Builder::with_app_acks`. Not fired for messages relayed for a client of ours.
+    ///
+    /// Only fired if enabled via `Builder::with_sent_events`.
+    SentUserMessage {
+        /// Peer the message was sent to, as for `UnsentUserMessage`.
+        peer: Peer,
+        /// Sent message.
+        msg: bytes::Bytes,
+        /// Token the message was sent with.
+        token: Token,
+    },
+    /// The file sent with `QuicP2p::send_file` was written to this peer in full.
+    SentFile {
+        /// Peer the file was sent to.
+        peer: Peer,
+        /// Path of the file.
+        path: PathBuf,
+        /// Token the file was sent with.
+        token: Token,
+    },
+    /// The file sent with `QuicP2p::send_file` was not sent to this peer in full.
+    UnsentFile {
+        /// Peer the file was sent to.
+        peer: Peer,
+        /// Path of the file.
+        path: PathBuf,
+        /// Token the file was sent with.
+        token: Token,
+        /// Why the file was not sent.
+        err: Error,
+    },
+    /// This peer started sending us a file, which it is held up sending until the file is taken
+    /// with `QuicP2p::receive_file` or dropped with `QuicP2p::reject_file`.
+    IncomingFile {
+        /// Sending peer address.
+        peer_addr: SocketAddr,
+        /// The id to receive or reject the file by.
+        id: FileId,
+        /// Name of the file at the sending peer, without its directories.
+        name: String,
+        /// Length of the file in bytes.
+        len: u64,
+    },
+    /// This peer cancelled sending us the file, see `QuicP2p::cancel_stream`. A file yet to be
+    /// taken is dropped, one being received has `QuicP2p::receive_file` fail with
+    /// `Error::TransferCancelled`.
+    IncomingFileCancelled {
+        /// Sending peer address.
+        peer_addr: SocketAddr,
+        /// The id of the file, as given by `IncomingFile`.
+        id: FileId,
+    },
+    /// Part of the file sent with `QuicP2p::send_file` was written to the peer.
+    ///
+    /// Only fired if enabled via `Builder::with_progress_events`.
+    SendProgress {
+        /// Token the file was sent with.
+        token: Token,
+        /// Bytes of the file written so far.
+        bytes_sent: u64,
+        /// Length of the file in bytes.
+        total: u64,
+    },
+    /// Part of the file taken with `QuicP2p::receive_file` was received.
+    ///
+    /// Only fired if enabled via `Builder::with_progress_events`.
+    ReceiveProgress {
+        /// The id of the file, as given by `IncomingFile`.
+        id: FileId,
+        /// Bytes of the file received so far.
+        bytes_received: u64,
+        /// Length of the file in bytes.
+        total: u64,
+    },
+    /// An established connection to this peer was ended by the peer or the network.
+    ///
+    /// Connections we tear down ourselves are reported via `ConnectionFailure` instead.
+    ConnectionClosed {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Why the connection ended.
+        reason: CloseReason,
+    },
+    /// We started queuing user messages to this peer, sent once we are connected to it. The
+    /// messages are flushed with `QueuedSendsFlushed`, or else reported via `UnsentUserMessage`.
+    ///
+    /// Only fired if enabled via `Builder::with_lifecycle_events`.
+    SendsQueued {
+        /// Peer address.
+        peer_addr: SocketAddr,
+    },
+    /// We connected to this peer and sent it the user messages queued since `SendsQueued`.
+    ///
+    /// Only fired if enabled via `Builder::with_lifecycle_events`.
+    QueuedSendsFlushed {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Number of user messages sent.
+        count: usize,
+    },
+    /// We started connecting to this peer.
+    ///
+    /// Only fired if enabled via `Builder::with_lifecycle_events`.
+    Connecting {
+        /// Peer address.
+        peer_addr: SocketAddr,
+    },
+    /// The QUIC handshake of our connection to this peer completed. `ConnectedTo` or
+    /// `BootstrappedTo` follows once the connection is fully set up.
+    ///
+    /// Only fired if enabled via `Builder::with_lifecycle_events`.
+    HandshakeCompleted {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Time since we started connecting to the peer.
+        duration: Duration,
+    },
+    /// The connection to this peer is alive although no messages were exchanged over it for a
+    /// heartbeat interval. Only fired if enabled via `Builder::with_heartbeat_interval`.
+    PeerHeartbeat {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Time it took to establish our connection to the peer, if we initiated one. Not a live
+        /// round trip time, see `Builder::with_probes` for measuring that with `PeerRtt`.
+        handshake_duration: Option<Duration>,
+    },
+    /// Writing to this peer has been held up by its flow control, or by the congestion control
+    /// of the connection, for longer than the threshold. `PeerResumed` follows once writing
+    /// resumes. Only fired if enabled via `Builder::with_stall_detection`.
+    PeerSlow {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Time writing has been held up for.
+        stalled_for: Duration,
+    },
+    /// Writing to this peer, reported via `PeerSlow`, resumed.
+    PeerResumed {
+        /// Peer address.
+        peer_addr: SocketAddr,
+    },
+    /// The event loop of this peer answered our probe. Only fired if enabled via
+    /// `Builder::with_probes`, or for quiet peers via `Config::node_liveness_msec`.
+    PeerRtt {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Time it took the answer to arrive, including the time the peer took to get to it.
+        rtt: Duration,
     },
     /// Successfully connected to this peer.
     ConnectedTo {
         /// Peer information.
         peer: Peer,
+        /// Which side set up the connection.
+        direction: ConnectionDirection,
+        /// Capabilities the peer advertised in its handshake, see `Builder::with_capabilities`.
+        /// Only the side setting up a connection introduces itself, so they are empty for nodes
+        /// we connected to first and for nodes we are a client of.
+        capabilities: Capabilities,
+    },
+    /// This peer connected to us while its previous connection to us was still up, and one of the
+    /// two connections was dropped following `Config::duplicate_connection_policy`.
+    DuplicateConnectionDropped {
+        /// Peer address.
+        peer_addr: SocketAddr,
+        /// Which of the two connections was dropped.
+        dropped: DroppedConnection,
+    },
+    /// A node we are connected to reached us from a new address with the certificate we know it
+    /// by, e.g. after a DHCP lease or a mobile network changed. Its connections at the old address
+    /// were dropped and it is connected at the new one, fired in place of `ConnectedTo`.
+    PeerAddressChanged {
+        /// Address the peer was known at.
+        old: SocketAddr,
+        /// Address the peer is known at from now on.
+        new: SocketAddr,
+    },
+    /// Our connection to this peer carries on from the local address we moved to, see
+    /// `QuicP2p::migrate`.
+    ConnectionMigrated {
+        /// Peer address.
+        peer_addr: SocketAddr,
+    },
+    /// Our connection to this peer did not survive the move to a new local address, see
+    /// `QuicP2p::migrate`. The connection is dropped, so it has to be made anew.
+    MigrationFailed {
+        /// Peer address.
+        peer_addr: SocketAddr,
     },
     /// A new message was received from this peer.
     NewMessage {
@@ -38,23 +257,210 @@ pub enum Event {
         peer_addr: SocketAddr,
         /// The new message.
         msg: bytes::Bytes,
+        /// Channel the peer sent the message on.
+        channel: Channel,
+    },
+    /// A message was published to a topic we are subscribed to, see `QuicP2p::subscribe`.
+    TopicMessage {
+        /// The topic.
+        topic: String,
+        /// Address of the peer which sent or forwarded the message to us, not necessarily its
+        /// publisher.
+        peer_addr: SocketAddr,
+        /// The message.
+        msg: bytes::Bytes,
+    },
+    /// A message was gossiped to us, see `QuicP2p::gossip`. Fired once for each message, however
+    /// many peers pass it on to us.
+    GossipMessage {
+        /// Address of the peer which passed the message on to us, not necessarily its originator.
+        peer_addr: SocketAddr,
+        /// The message.
+        msg: bytes::Bytes,
+    },
+    /// A message relayed by a node from another client of it, see `QuicP2p::forward`.
+    ForwardedMessage {
+        /// Address of the node which relayed the message.
+        relay_addr: SocketAddr,
+        /// Address of the sending client as seen by the node.
+        peer_addr: SocketAddr,
+        /// The message.
+        msg: bytes::Bytes,
+    },
+    /// Outcome of `QuicP2p::check_reachability`.
+    ReachabilityChecked {
+        /// Peer which dialed us.
+        via_peer: SocketAddr,
+        /// Address it dialed us on.
+        addr: SocketAddr,
+        /// Whether it reached us there.
+        reachable: bool,
+    },
+    /// The message sent with `QuicP2p::send_to_quorum` was delivered to as many peers as required,
+    /// or failed to so many that it can't be anymore. Deliveries still pending by then are no
+    /// longer tracked.
+    QuorumSendResolved {
+        /// The id `send_to_quorum` returned for the message.
+        id: QuorumSendId,
+        /// Whether the message was delivered to the required number of peers.
+        reached: bool,
+        /// Peers the message was delivered to, in the order they were confirmed.
+        delivered: Vec<SocketAddr>,
+        /// Peers delivering the message to failed.
+        failed: Vec<SocketAddr>,
+    },
+    /// Events were dropped because the event channel was full. Only fired with the
+    /// `EventPolicy::Drop` and `EventPolicy::DropMessages` policies, as soon as there is room
+    /// again.
+    EventsDropped {
+        /// Number of events dropped since the last such notification.
+        count: u64,
+    },
+    /// The config file watched since `Builder::with_config_watch` was written to. Read it with
+    /// `Config::from_file` and pass it to `QuicP2p::apply_config` to apply it.
+    ConfigFileChanged {
+        /// Path of the file.
+        path: PathBuf,
+    },
+    /// A certificate expires within the warning period of
+    /// `Builder::with_cert_expiry_monitoring`, or has expired already. Fired once per
+    /// certificate.
+    CertificateExpiring {
+        /// The hard-coded contact or connected node whose certificate it is, `None` for ours.
+        peer_addr: Option<SocketAddr>,
+        /// End of the validity of the certificate.
+        when: SystemTime,
+    },
+    /// Our certificate was renewed ahead of its expiry, see
+    /// `Builder::with_cert_expiry_monitoring`. Peers keep being served the old one until we
+    /// restart with the new one.
+    CertificateRenewed {
+        /// The new certificate and its private key.
+        cert: SerialisableCertificate,
+    },
+    /// The connection to a sticky peer dropped, or dialing it again failed, so it is dialed again
+    /// after the given backoff. See `QuicP2p::make_sticky`.
+    Reconnecting {
+        /// Address of the sticky peer.
+        peer_addr: SocketAddr,
+        /// Number of the attempt about to be made since the connection dropped, counting from 1.
+        attempt: u32,
+        /// Time until the peer is dialed.
+        after: Duration,
+    },
+    /// We are connected to a sticky peer again after its connection dropped, whoever dialed.
+    Reconnected {
+        /// Address of the sticky peer.
+        peer_addr: SocketAddr,
+        /// Number of attempts made until then.
+        attempts: u32,
     },
     /// No more messages will be fired after this
     // TODO Currently used only for testing
     Finish,
 }
 
+/// Which side of a connection dialed the other.
+///
+/// Nodes hold a connection in either direction to each other, so the direction reported for a
+/// node is that of the side which contacted the other first, the other side merely reverse
+/// connecting. If both nodes dial each other at once, both report `Outgoing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDirection {
+    /// We dialed the peer, through bootstrapping or `QuicP2p::connect_to`.
+    Outgoing,
+    /// The peer dialed us.
+    Incoming,
+}
+
+impl ConnectionDirection {
+    /// The direction of a connection given whether we contacted the peer.
+    pub(crate) fn from_we_contacted_peer(we_contacted_peer: bool) -> Self {
+        if we_contacted_peer {
+            ConnectionDirection::Outgoing
+        } else {
+            ConnectionDirection::Incoming
+        }
+    }
+}
+
+/// Which of two connections from a peer was dropped, see `Event::DuplicateConnectionDropped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DroppedConnection {
+    /// The connection the peer had with us already. It is replaced by the new one, which the peer
+    /// is set up over anew, `ConnectedTo` following once that is done.
+    Existing,
+    /// The connection the peer just made.
+    New,
+}
+
+/// Why a user message wasn't sent, see `Event::UnsentUserMessage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnsentReason {
+    /// It couldn't be written to the peer, e.g. because the connection failed.
+    Failed,
+    /// Its deadline passed before it was written, see `QuicP2p::send_with_ttl`.
+    Expired,
+    /// Too many messages were queued already while we connect to the peer, see
+    /// `Builder::with_send_queue_limit`.
+    QueueFull,
+    /// The attempt to connect to the peer it was waiting for was aborted, see
+    /// `QuicP2p::abort_connect`.
+    Aborted,
+}
+
+/// Why an established connection to a peer came to an end.
+#[derive(Debug, Clone)]
+pub enum CloseReason {
+    /// The peer closed the connection gracefully.
+    PeerClosed {
+        /// Application close code given by the peer.
+        error_code: u16,
+        /// Reason bytes given by the peer.
+        reason: bytes::Bytes,
+    },
+    /// We heard nothing from the peer within the idle timeout.
+    TimedOut,
+    /// The connection was aborted due to a transport level error on either side.
+    TransportError(quinn::ConnectionError),
+}
+
+impl CloseReason {
+    /// Obtain the reason the connection ended from the error that ended it. Returns `None` if the
+    /// error doesn't originate from the QUIC connection ending, e.g. if we closed it ourselves.
+    pub(crate) fn from_err(e: &Error) -> Option<Self> {
+        let e = match *e.root_cause() {
+            Error::Connection(ref e) => e,
+            _ => return None,
+        };
+
+        match *e {
+            quinn::ConnectionError::ApplicationClosed { ref reason } => {
+                Some(CloseReason::PeerClosed {
+                    error_code: reason.error_code,
+                    reason: reason.reason.clone(),
+                })
+            }
+            quinn::ConnectionError::TimedOut => Some(CloseReason::TimedOut),
+            quinn::ConnectionError::LocallyClosed => None,
+            ref e => Some(CloseReason::TransportError(e.clone())),
+        }
+    }
+}
+
 impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Event::NewMessage {
                 ref peer_addr,
                 ref msg,
+                channel,
             } => write!(
                 f,
-                "Event::NewMessage {{ peer_addr: {}, msg: {} }}",
+                "Event::NewMessage {{ peer_addr: {}, msg: {}, channel: {} }}",
                 peer_addr,
-                utils::bin_data_format(&*msg)
+                utils::bin_data_format(&*msg),
+                channel
             ),
             ref blah => write!(f, "{}", blah),
         }
@@ -0,0 +1,309 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::event::Event;
+use crossbeam_channel as mpmc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::prelude::Future;
+use tokio::runtime::current_thread::TaskExecutor;
+use tokio::timer::Delay;
+
+/// Most events `EventPolicy::DropMessages` holds back, beyond which they are dropped too.
+const MAX_PENDING: usize = 4096;
+/// How soon the events held back by a full channel are offered to the application again.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What to do with events when the application doesn't drain a bounded event channel fast
+/// enough. This has no effect on unbounded channels as they never fill up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventPolicy {
+    /// Wait for the application to make room in the channel. This stalls the event loop, and so
+    /// all the connections, until it does.
+    Block,
+    /// Drop every event that doesn't fit into the channel. The number of events dropped is
+    /// reported via `Event::EventsDropped` as soon as there is room again.
+    Drop,
+    /// Drop only the messages (`NewMessage`, `TopicMessage`, `GossipMessage`, `SentUserMessage`
+    /// and `UnsentUserMessage`) and the progress of files (`SendProgress` and `ReceiveProgress`)
+    /// that don't fit into the channel, reporting them via `Event::EventsDropped`. All other
+    /// events are queued up internally and delivered in order once there is room, so the
+    /// application never misses a change in the state of its connections. Should it stop draining
+    /// the channel altogether, events beyond the 4096 queued up are dropped as well.
+    DropMessages,
+}
+
+impl Default for EventPolicy {
+    fn default() -> Self {
+        EventPolicy::Block
+    }
+}
+
+/// Sends events to the application according to the configured `EventPolicy`.
+#[derive(Clone)]
+pub struct EventSender {
+    tx: mpmc::Sender<Event>,
+    policy: EventPolicy,
+    backlog: Rc<RefCell<Backlog>>,
+}
+
+#[derive(Default)]
+struct Backlog {
+    dropped: u64,
+    pending: VecDeque<Event>,
+    /// Set while a flush is scheduled, see `EventSender::flush_later`.
+    is_flushing: bool,
+}
+
+/// The application dropped the receiver of the events.
+#[derive(Debug)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The receiver of the events is gone")
+    }
+}
+
+impl EventSender {
+    pub fn new(tx: mpmc::Sender<Event>, policy: EventPolicy) -> Self {
+        Self {
+            tx,
+            policy,
+            backlog: Default::default(),
+        }
+    }
+
+    /// Send the event. Only errors if the application has dropped the receiver.
+    pub fn send(&self, event: Event) -> Result<(), Disconnected> {
+        self.offer(event).map(|_| ())
+    }
+
+    /// Send a user message received, returning whether it was handed to the application or queued
+    /// for it rather than dropped.
+    pub fn deliver(&self, event: Event) -> Result<bool, Disconnected> {
+        self.offer(event)
+    }
+
+    fn offer(&self, event: Event) -> Result<bool, Disconnected> {
+        #[cfg(feature = "chaos")]
+        {
+            if crate::chaos::drops_event() {
+                trace!("Dropping event {:?} on purpose", event);
+                return Ok(false);
+            }
+        }
+
+        if self.policy == EventPolicy::Block {
+            return self.tx.send(event).map(|()| true).map_err(|_| Disconnected);
+        }
+
+        let mut backlog = self.backlog.borrow_mut();
+        let event = if self.flush(&mut backlog)? {
+            event
+        } else {
+            match self.tx.try_send(event) {
+                Ok(()) => return Ok(true),
+                Err(mpmc::TrySendError::Full(event)) => event,
+                Err(mpmc::TrySendError::Disconnected(_)) => return Err(Disconnected),
+            }
+        };
+
+        let is_queued = match (self.policy, event) {
+            (EventPolicy::DropMessages, Event::NewMessage { .. })
+            | (EventPolicy::DropMessages, Event::SentUserMessage { .. })
+            | (EventPolicy::DropMessages, Event::UnsentUserMessage { .. })
+            | (EventPolicy::DropMessages, Event::SendProgress { .. })
+            | (EventPolicy::DropMessages, Event::ReceiveProgress { .. })
+            | (EventPolicy::DropMessages, Event::TopicMessage { .. })
+            | (EventPolicy::DropMessages, Event::GossipMessage { .. })
+            | (EventPolicy::Drop, _) => false,
+            (_, event) => {
+                if backlog.pending.len() < MAX_PENDING {
+                    backlog.pending.push_back(event);
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        if !is_queued {
+            backlog.dropped += 1;
+        }
+        self.flush_later(&mut backlog);
+        Ok(is_queued)
+    }
+
+    /// Hand the events held back and the number of those dropped to the application, returning
+    /// whether the channel is still full.
+    fn flush(&self, backlog: &mut Backlog) -> Result<bool, Disconnected> {
+        while let Some(pending) = backlog.pending.pop_front() {
+            match self.tx.try_send(pending) {
+                Ok(()) => (),
+                Err(mpmc::TrySendError::Full(pending)) => {
+                    backlog.pending.push_front(pending);
+                    return Ok(true);
+                }
+                Err(mpmc::TrySendError::Disconnected(_)) => return Err(Disconnected),
+            }
+        }
+
+        if backlog.dropped > 0 {
+            match self.tx.try_send(Event::EventsDropped {
+                count: backlog.dropped,
+            }) {
+                Ok(()) => backlog.dropped = 0,
+                Err(mpmc::TrySendError::Full(_)) => return Ok(true),
+                Err(mpmc::TrySendError::Disconnected(_)) => return Err(Disconnected),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Flush the backlog once `FLUSH_INTERVAL` passes, and so on until it is empty, rather than
+    /// waiting for the next event to be sent, which may never come.
+    fn flush_later(&self, backlog: &mut Backlog) {
+        if backlog.is_flushing || (backlog.pending.is_empty() && backlog.dropped == 0) {
+            return;
+        }
+        let event_tx = self.clone();
+        let leaf = Delay::new(Instant::now() + FLUSH_INTERVAL)
+            .map_err(|e| debug!("Error in event flush timer: {:?}", e))
+            .map(move |()| {
+                let mut backlog = event_tx.backlog.borrow_mut();
+                backlog.is_flushing = false;
+                // Unless the application is gone
+                if event_tx.flush(&mut backlog).is_ok() {
+                    event_tx.flush_later(&mut backlog);
+                }
+            });
+        // Outside of the event loop, e.g. in tests, the backlog is flushed by the next event only
+        backlog.is_flushing = TaskExecutor::current().spawn_local(Box::new(leaf)).is_ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::DEFAULT_CHANNEL;
+    use tokio::prelude::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    fn new_msg(n: u8) -> Event {
+        Event::NewMessage {
+            peer_addr: ([127, 0, 0, 1], 1000).into(),
+            msg: bytes::Bytes::from(vec![n]),
+            channel: DEFAULT_CHANNEL,
+        }
+    }
+
+    #[test]
+    fn drop_policy_reports_the_number_of_dropped_events() {
+        let (tx, rx) = mpmc::bounded(1);
+        let sender = EventSender::new(tx, EventPolicy::Drop);
+
+        unwrap!(sender.send(new_msg(0)));
+        unwrap!(sender.send(new_msg(1)));
+        unwrap!(sender.send(Event::BootstrapFailure {
+            failures: Default::default(),
+        }));
+
+        match unwrap!(rx.try_recv()) {
+            Event::NewMessage { msg, .. } => assert_eq!(msg[..], [0]),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        unwrap!(sender.send(new_msg(2)));
+        match unwrap!(rx.try_recv()) {
+            Event::EventsDropped { count } => assert_eq!(count, 2),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn drop_messages_policy_only_drops_messages() {
+        let (tx, rx) = mpmc::bounded(1);
+        let sender = EventSender::new(tx, EventPolicy::DropMessages);
+
+        unwrap!(sender.send(new_msg(0)));
+        unwrap!(sender.send(new_msg(1)));
+        unwrap!(sender.send(Event::BootstrapFailure {
+            failures: Default::default(),
+        }));
+
+        match unwrap!(rx.try_recv()) {
+            Event::NewMessage { msg, .. } => assert_eq!(msg[..], [0]),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        unwrap!(sender.send(new_msg(2)));
+        match unwrap!(rx.try_recv()) {
+            Event::BootstrapFailure { .. } => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        unwrap!(sender.send(Event::Finish));
+        match unwrap!(rx.try_recv()) {
+            Event::EventsDropped { count } => assert_eq!(count, 2),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        unwrap!(sender.send(new_msg(3)));
+        match unwrap!(rx.try_recv()) {
+            Event::Finish => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn held_back_events_are_flushed_without_another_event() {
+        let (tx, rx) = mpmc::bounded(1);
+        let sender = EventSender::new(tx, EventPolicy::DropMessages);
+        let mut runtime = unwrap!(Runtime::new());
+
+        unwrap!(runtime.block_on(future::lazy(|| {
+            unwrap!(sender.send(new_msg(0)));
+            sender.send(Event::Finish)
+        })));
+        match unwrap!(rx.try_recv()) {
+            Event::NewMessage { msg, .. } => assert_eq!(msg[..], [0]),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        // Runs the flush timer, which stops once the backlog is flushed
+        unwrap!(runtime.run());
+
+        unwrap!(runtime.block_on(future::lazy(|| sender.send(new_msg(1)))));
+        match unwrap!(rx.try_recv()) {
+            Event::Finish => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        unwrap!(runtime.run());
+        match unwrap!(rx.try_recv()) {
+            Event::EventsDropped { count } => assert_eq!(count, 1),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn drop_messages_policy_holds_back_only_so_many_events() {
+        let (tx, _rx) = mpmc::bounded(1);
+        let sender = EventSender::new(tx, EventPolicy::DropMessages);
+
+        for _ in 0..MAX_PENDING + 2 {
+            unwrap!(sender.send(Event::Finish));
+        }
+        let backlog = sender.backlog.borrow();
+        assert_eq!(backlog.pending.len(), MAX_PENDING);
+        assert_eq!(backlog.dropped, 1);
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Giving up on the user messages which couldn't be sent before a deadline, see
+//! `QuicP2p::send_with_ttl`.
+//!
+//! Once its deadline passes, a message still queued while we connect to the peer, waiting to be
+//! resent or awaiting the acknowledgment of the peer, is dropped. Writing it is abandoned if the
+//! deadline passes first. Either way it is reported via `Event::UnsentUserMessage` with
+//! `UnsentReason::Expired`.
+
+use crate::connection::ToPeer;
+use crate::context::{ctx, ctx_mut};
+use crate::error::Error;
+use crate::outcome;
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::future::{self, Either, Loop};
+use tokio::prelude::Future;
+use tokio::runtime::current_thread;
+use tokio::timer::Delay;
+
+/// Longest time between two looks at the clock while waiting for a deadline, so that deadlines
+/// told by a `test_utils::ManualClock` pass soon after the clock is advanced.
+const MAX_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// User messages sent with a deadline, awaiting their outcome.
+#[derive(Default)]
+pub struct Expiries {
+    sends: Vec<Expiry>,
+}
+
+struct Expiry {
+    peer_addr: SocketAddr,
+    msg: bytes::Bytes,
+    deadline: Instant,
+}
+
+impl Expiries {
+    /// Give up on the message to the peer once the deadline passes.
+    pub fn start(&mut self, peer_addr: SocketAddr, msg: bytes::Bytes, deadline: Instant) {
+        self.sends.push(Expiry {
+            peer_addr,
+            msg,
+            deadline,
+        });
+    }
+
+    /// Deadline of the message to the peer, if it was sent with one.
+    pub fn deadline(&self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> Option<Instant> {
+        self.find(peer_addr, msg).map(|i| self.sends[i].deadline)
+    }
+
+    /// Stop tracking the message now that its outcome is known, returning whether it was.
+    pub fn resolve(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> bool {
+        match self.find(peer_addr, msg) {
+            Some(i) => {
+                let _ = self.sends.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn find(&self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> Option<usize> {
+        self.sends
+            .iter()
+            .position(|send| send.peer_addr == peer_addr && send.msg == *msg)
+    }
+}
+
+/// Drop the message once the deadline passes should it still be queued, waiting to be resent or
+/// awaiting its acknowledgment by then.
+pub fn schedule(peer_addr: SocketAddr, msg: bytes::Bytes, deadline: Instant) {
+    let leaf = passed(deadline).map(move |()| {
+        ctx_mut(|c| {
+            let was_queued = match c.connections.get_mut(&peer_addr) {
+                Some(conn) => match conn.to_peer {
+                    ToPeer::Initiated {
+                        ref mut pending_sends,
+                        ..
+                    } => match pending_sends
+                        .iter()
+                        .position(|(wire_msg, _)| wire_msg.user_msg() == Some(&msg))
+                    {
+                        Some(i) => {
+                            let _ = pending_sends.remove(i);
+                            true
+                        }
+                        None => false,
+                    },
+                    _ => false,
+                },
+                None => false,
+            };
+            if was_queued
+                || c.resends.is_waiting(peer_addr, &msg)
+                || c.acks.is_awaited(peer_addr, &msg)
+            {
+                outcome::expired(c, peer_addr, msg);
+            }
+        })
+    });
+    current_thread::spawn(leaf);
+}
+
+/// Write the user message with `sending` unless its deadline passes first, in which case it is
+/// reported as expired. Yields whether it was written.
+pub fn guard<F>(
+    peer_addr: SocketAddr,
+    msg: Option<bytes::Bytes>,
+    sending: F,
+) -> impl Future<Item = bool, Error = Error>
+where
+    F: Future<Item = (), Error = Error>,
+{
+    // Spawned rather than called right away, as we are written to with the context borrowed
+    future::lazy(move || {
+        let deadline = msg
+            .as_ref()
+            .and_then(|msg| ctx(|c| c.expiries.deadline(peer_addr, msg)));
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Either::A(sending.map(|()| true)),
+        };
+        Either::B(sending.select2(passed(deadline)).then(move |r| match r {
+            Ok(Either::A(((), _))) => Ok(true),
+            Err(Either::A((e, _))) => Err(e),
+            Ok(Either::B(_)) | Err(Either::B(_)) => {
+                if let Some(msg) = msg {
+                    ctx_mut(|c| outcome::expired(c, peer_addr, msg));
+                }
+                Ok(false)
+            }
+        }))
+    })
+}
+
+/// Resolves once the deadline passes by the clock of the context, see `Builder::with_clock`.
+fn passed(deadline: Instant) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn((), move |()| {
+        let now = ctx(|c| c.clock.now());
+        if now >= deadline {
+            return Either::A(future::ok(Loop::Break(())));
+        }
+        let wait = cmp::min(deadline - now, MAX_CHECK_PERIOD);
+        Either::B(
+            Delay::new(Instant::now() + wait)
+                .map_err(|e| debug!("Error in expiry timer: {:?}", e))
+                .map(|()| Loop::Continue(())),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn deadlines_are_tracked_until_resolved() {
+        let peer_addr = ([203, 0, 113, 1], 1000).into();
+        let msg = bytes::Bytes::from(&b"msg"[..]);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut expiries = Expiries::default();
+
+        assert_eq!(expiries.deadline(peer_addr, &msg), None);
+        expiries.start(peer_addr, msg.clone(), deadline);
+        assert_eq!(expiries.deadline(peer_addr, &msg), Some(deadline));
+        let other_peer = ([203, 0, 113, 2], 1000).into();
+        assert_eq!(expiries.deadline(other_peer, &msg), None);
+
+        assert!(expiries.resolve(peer_addr, &msg));
+        assert!(!expiries.resolve(peer_addr, &msg));
+        assert_eq!(expiries.deadline(peer_addr, &msg), None);
+    }
+}
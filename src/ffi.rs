@@ -0,0 +1,970 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! C ABI for embedding quic-p2p into applications not written in Rust, e.g. iOS and Android apps.
+//!
+//! The functions are declared in `include/quic_p2p.h`. To obtain a library to link against, build
+//! the crate with the `ffi` feature as a static or dynamic library:
+//!
+//! ```text
+//! cargo rustc --release --features ffi -- --crate-type staticlib
+//! ```
+//!
+//! An instance is created with `quic_p2p_new` and used through the opaque handle it returns until
+//! it is passed to `quic_p2p_free`. Events are not delivered by callbacks but taken one at a time
+//! with `quic_p2p_poll_event`, from whichever thread suits the application.
+//!
+//! Functions which can fail return `QUIC_P2P_OK` or `QUIC_P2P_ERR`, in which case
+//! `quic_p2p_last_error` describes the failure. Buffers and events handed out by the library are
+//! owned by the caller and have to be released with `quic_p2p_buffer_free` and
+//! `quic_p2p_event_free` respectively. Buffers passed into the library are only borrowed for the
+//! duration of the call.
+
+#![allow(unsafe_code)]
+
+use crate::{
+    Builder, Config, ConnectionDirection, DroppedConnection, Event, NodeInfo, Peer, QuicP2p,
+};
+use crossbeam_channel as mpmc;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::net::SocketAddr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// The call succeeded.
+pub const QUIC_P2P_OK: i32 = 0;
+/// The call failed, see `quic_p2p_last_error`.
+pub const QUIC_P2P_ERR: i32 = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// A `QuicP2p` instance along with the events it fires.
+pub struct QuicP2pHandle {
+    qp2p: QuicP2p,
+    event_rx: mpmc::Receiver<Event>,
+}
+
+/// Bytes owned by the caller once handed out by the library.
+#[repr(C)]
+pub struct FfiBuffer {
+    /// Start of the bytes, null if there are none.
+    pub data: *mut u8,
+    /// Number of bytes.
+    pub len: usize,
+}
+
+impl FfiBuffer {
+    fn empty() -> Self {
+        Self {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    fn new(bytes: Vec<u8>) -> Self {
+        if bytes.is_empty() {
+            return Self::empty();
+        }
+        let bytes = bytes.into_boxed_slice();
+        let len = bytes.len();
+        Self {
+            data: Box::into_raw(bytes) as *mut u8,
+            len,
+        }
+    }
+
+    unsafe fn free(&mut self) {
+        if !self.data.is_null() {
+            let _ = Box::from_raw(ptr::slice_from_raw_parts_mut(self.data, self.len));
+        }
+        *self = Self::empty();
+    }
+}
+
+/// Kind of an `FfiEvent`, mirroring the variants of `Event`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FfiEventKind {
+    /// See `Event::BootstrapFailure`.
+    BootstrapFailure = 1,
+    /// See `Event::BootstrappedTo`.
+    BootstrappedTo = 2,
+    /// See `Event::ConnectionFailure`.
+    ConnectionFailure = 3,
+    /// See `Event::UnsentUserMessage`.
+    UnsentUserMessage = 4,
+    /// See `Event::ConnectionClosed`.
+    ConnectionClosed = 5,
+    /// See `Event::Connecting`.
+    Connecting = 6,
+    /// See `Event::HandshakeCompleted`.
+    HandshakeCompleted = 7,
+    /// See `Event::PeerHeartbeat`.
+    PeerHeartbeat = 8,
+    /// See `Event::ConnectedTo`.
+    ConnectedTo = 9,
+    /// See `Event::NewMessage`.
+    NewMessage = 10,
+    /// See `Event::EventsDropped`.
+    EventsDropped = 11,
+    /// See `Event::Finish`.
+    Finish = 12,
+    /// See `Event::ForwardedMessage`.
+    ForwardedMessage = 13,
+    /// `Event::QuorumSendResolved` with the quorum reached.
+    QuorumReached = 14,
+    /// `Event::QuorumSendResolved` with the quorum failed.
+    QuorumFailed = 15,
+    /// See `Event::DuplicateConnectionDropped`.
+    DuplicateConnectionDropped = 16,
+    /// See `Event::PeerAddressChanged`.
+    PeerAddressChanged = 17,
+    /// See `Event::ConnectionMigrated`.
+    ConnectionMigrated = 18,
+    /// See `Event::MigrationFailed`.
+    MigrationFailed = 19,
+    /// `Event::ReachabilityChecked` with us reached.
+    Reachable = 20,
+    /// `Event::ReachabilityChecked` with us not reached.
+    Unreachable = 21,
+    /// See `Event::SentUserMessage`.
+    SentUserMessage = 22,
+    /// See `Event::SentFile`.
+    SentFile = 23,
+    /// See `Event::UnsentFile`.
+    UnsentFile = 24,
+    /// See `Event::IncomingFile`.
+    IncomingFile = 25,
+    /// See `Event::SendProgress`.
+    SendProgress = 26,
+    /// See `Event::ReceiveProgress`.
+    ReceiveProgress = 27,
+    /// See `Event::TopicMessage`.
+    TopicMessage = 28,
+    /// See `Event::GossipMessage`.
+    GossipMessage = 29,
+    /// See `Event::PeerSlow`.
+    PeerSlow = 30,
+    /// See `Event::PeerResumed`.
+    PeerResumed = 31,
+    /// See `Event::PeerRtt`.
+    PeerRtt = 32,
+    /// See `Event::SendsQueued`.
+    SendsQueued = 33,
+    /// See `Event::QueuedSendsFlushed`.
+    QueuedSendsFlushed = 34,
+    /// See `Event::IncomingFileCancelled`.
+    IncomingFileCancelled = 35,
+    /// See `Event::ConfigFileChanged`.
+    ConfigFileChanged = 36,
+    /// See `Event::CertificateExpiring`.
+    CertificateExpiring = 37,
+    /// See `Event::CertificateRenewed`.
+    CertificateRenewed = 38,
+    /// See `Event::Reconnecting`.
+    Reconnecting = 39,
+    /// See `Event::Reconnected`.
+    Reconnected = 40,
+}
+
+/// An event flattened for C. Fields which don't apply to the kind of event are empty.
+#[repr(C)]
+pub struct FfiEvent {
+    /// What happened.
+    pub kind: FfiEventKind,
+    /// Address of the peer the event is about as `ip:port`, the sending client for
+    /// `ForwardedMessage`, the new address for `PeerAddressChanged` and the dialing peer for
+    /// `Reachable` and `Unreachable`, the peer which passed on a `TopicMessage` or
+    /// `GossipMessage` and the peer whose certificate it is for `CertificateExpiring`, empty if
+    /// it is ours.
+    pub peer_addr: FfiBuffer,
+    /// Certificate of the node for `BootstrappedTo`, and for `ConnectedTo`, `SentUserMessage`,
+    /// `UnsentUserMessage`, `SentFile` and `UnsentFile` of nodes, and our renewed certificate for
+    /// `CertificateRenewed`.
+    pub peer_cert_der: FfiBuffer,
+    /// The message for `NewMessage`, `ForwardedMessage`, `TopicMessage`, `GossipMessage`,
+    /// `SentUserMessage` and `UnsentUserMessage`, the human readable error or close reason for
+    /// `ConnectionFailure` and `ConnectionClosed`, the comma separated `ip:port` addresses of the
+    /// peers that failed for `QuorumReached` and `QuorumFailed`, the old `ip:port` address for
+    /// `PeerAddressChanged`, the `ip:port` address we were dialed at for `Reachable` and
+    /// `Unreachable`, the path of the file for `SentFile`, the path and the human readable error
+    /// separated by a newline for `UnsentFile`, the name of the file for `IncomingFile`, the
+    /// bytes done and the length of the file as `done/total` for `SendProgress` and
+    /// `ReceiveProgress`, the path of the config file for `ConfigFileChanged` and the `ip:port`
+    /// address and the failure of each contact, one per line, for `BootstrapFailure`.
+    pub payload: FfiBuffer,
+    /// Number of events dropped for `EventsDropped`, milliseconds for `BootstrappedTo`,
+    /// `HandshakeCompleted`, `PeerSlow`, `PeerRtt` and `PeerHeartbeat` (0 if the handshake wasn't
+    /// timed), 0 for `ConnectedTo` of peers we dialed and 1 for peers which dialed us, the id of
+    /// the quorum send for `QuorumReached` and `QuorumFailed`, 0 for `DuplicateConnectionDropped`
+    /// if the existing connection was dropped and 1 if the new one was, the token for
+    /// `SentUserMessage`, `UnsentUserMessage`, `SentFile`, `UnsentFile` and `SendProgress`, the id
+    /// of the file for `IncomingFile`, `IncomingFileCancelled` and `ReceiveProgress`, the number of
+    /// messages sent for `QueuedSendsFlushed`, the channel for `NewMessage`, the Unix time in
+    /// seconds the certificate expires at for `CertificateExpiring`, the number of the attempt for
+    /// `Reconnecting` and the number of attempts made for `Reconnected`.
+    pub value: u64,
+    /// Address of the node which relayed a `ForwardedMessage` as `ip:port`.
+    pub relay_addr: FfiBuffer,
+    /// Topic of a `TopicMessage`.
+    pub topic: FfiBuffer,
+}
+
+impl FfiEvent {
+    fn new(kind: FfiEventKind) -> Self {
+        Self {
+            kind,
+            peer_addr: FfiBuffer::empty(),
+            peer_cert_der: FfiBuffer::empty(),
+            payload: FfiBuffer::empty(),
+            value: 0,
+            relay_addr: FfiBuffer::empty(),
+            topic: FfiBuffer::empty(),
+        }
+    }
+
+    fn with_peer_addr(mut self, peer_addr: SocketAddr) -> Self {
+        self.peer_addr = FfiBuffer::new(peer_addr.to_string().into_bytes());
+        self
+    }
+
+    fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = FfiBuffer::new(payload);
+        self
+    }
+
+    fn with_node(mut self, node_info: NodeInfo) -> Self {
+        self = self.with_peer_addr(node_info.peer_addr);
+        self.peer_cert_der = FfiBuffer::new(node_info.peer_cert_der);
+        self
+    }
+
+    fn with_peer(self, peer: Peer) -> Self {
+        match peer {
+            Peer::Node { node_info } => self.with_node(node_info),
+            Peer::Client { peer_addr } => self.with_peer_addr(peer_addr),
+        }
+    }
+
+    fn with_value(mut self, value: u64) -> Self {
+        self.value = value;
+        self
+    }
+}
+
+impl From<Event> for FfiEvent {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::BootstrapFailure { failures } => {
+                let mut failures: Vec<_> = failures
+                    .into_iter()
+                    .map(|(peer_addr, failure)| format!("{} {}", peer_addr, failure))
+                    .collect();
+                failures.sort();
+                FfiEvent::new(FfiEventKind::BootstrapFailure)
+                    .with_payload(failures.join("\n").into_bytes())
+            }
+            Event::BootstrappedTo {
+                node,
+                handshake_duration,
+                ..
+            } => FfiEvent::new(FfiEventKind::BootstrappedTo)
+                .with_node(node)
+                .with_value(millis(handshake_duration)),
+            Event::ConnectionFailure { peer_addr, err } => {
+                FfiEvent::new(FfiEventKind::ConnectionFailure)
+                    .with_peer_addr(peer_addr)
+                    .with_payload(err.to_string().into_bytes())
+            }
+            Event::UnsentUserMessage {
+                peer, msg, token, ..
+            } => FfiEvent::new(FfiEventKind::UnsentUserMessage)
+                .with_peer(peer)
+                .with_payload(msg.to_vec())
+                .with_value(token),
+            Event::SentUserMessage { peer, msg, token } => {
+                FfiEvent::new(FfiEventKind::SentUserMessage)
+                    .with_peer(peer)
+                    .with_payload(msg.to_vec())
+                    .with_value(token)
+            }
+            Event::SentFile { peer, path, token } => FfiEvent::new(FfiEventKind::SentFile)
+                .with_peer(peer)
+                .with_payload(path.display().to_string().into_bytes())
+                .with_value(token),
+            Event::UnsentFile {
+                peer,
+                path,
+                token,
+                err,
+            } => FfiEvent::new(FfiEventKind::UnsentFile)
+                .with_peer(peer)
+                .with_payload(format!("{}\n{}", path.display(), err).into_bytes())
+                .with_value(token),
+            Event::IncomingFile {
+                peer_addr,
+                id,
+                name,
+                ..
+            } => FfiEvent::new(FfiEventKind::IncomingFile)
+                .with_peer_addr(peer_addr)
+                .with_payload(name.into_bytes())
+                .with_value(id.as_u64()),
+            Event::IncomingFileCancelled { peer_addr, id } => {
+                FfiEvent::new(FfiEventKind::IncomingFileCancelled)
+                    .with_peer_addr(peer_addr)
+                    .with_value(id.as_u64())
+            }
+            Event::SendProgress {
+                token,
+                bytes_sent,
+                total,
+            } => FfiEvent::new(FfiEventKind::SendProgress)
+                .with_payload(format!("{}/{}", bytes_sent, total).into_bytes())
+                .with_value(token),
+            Event::ReceiveProgress {
+                id,
+                bytes_received,
+                total,
+            } => FfiEvent::new(FfiEventKind::ReceiveProgress)
+                .with_payload(format!("{}/{}", bytes_received, total).into_bytes())
+                .with_value(id.as_u64()),
+            Event::ConnectionClosed { peer_addr, reason } => {
+                FfiEvent::new(FfiEventKind::ConnectionClosed)
+                    .with_peer_addr(peer_addr)
+                    .with_payload(format!("{:?}", reason).into_bytes())
+            }
+            Event::Connecting { peer_addr } => {
+                FfiEvent::new(FfiEventKind::Connecting).with_peer_addr(peer_addr)
+            }
+            Event::SendsQueued { peer_addr } => {
+                FfiEvent::new(FfiEventKind::SendsQueued).with_peer_addr(peer_addr)
+            }
+            Event::QueuedSendsFlushed { peer_addr, count } => {
+                FfiEvent::new(FfiEventKind::QueuedSendsFlushed)
+                    .with_peer_addr(peer_addr)
+                    .with_value(count as u64)
+            }
+            Event::HandshakeCompleted {
+                peer_addr,
+                duration,
+            } => FfiEvent::new(FfiEventKind::HandshakeCompleted)
+                .with_peer_addr(peer_addr)
+                .with_value(millis(duration)),
+            Event::PeerHeartbeat {
+                peer_addr,
+                handshake_duration,
+            } => FfiEvent::new(FfiEventKind::PeerHeartbeat)
+                .with_peer_addr(peer_addr)
+                .with_value(handshake_duration.map(millis).unwrap_or(0)),
+            Event::PeerSlow {
+                peer_addr,
+                stalled_for,
+            } => FfiEvent::new(FfiEventKind::PeerSlow)
+                .with_peer_addr(peer_addr)
+                .with_value(millis(stalled_for)),
+            Event::PeerResumed { peer_addr } => {
+                FfiEvent::new(FfiEventKind::PeerResumed).with_peer_addr(peer_addr)
+            }
+            Event::PeerRtt { peer_addr, rtt } => FfiEvent::new(FfiEventKind::PeerRtt)
+                .with_peer_addr(peer_addr)
+                .with_value(millis(rtt)),
+            Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                direction,
+                ..
+            } => FfiEvent::new(FfiEventKind::ConnectedTo)
+                .with_node(node_info)
+                .with_value(direction_value(direction)),
+            Event::ConnectedTo {
+                peer: Peer::Client { peer_addr },
+                direction,
+                ..
+            } => FfiEvent::new(FfiEventKind::ConnectedTo)
+                .with_peer_addr(peer_addr)
+                .with_value(direction_value(direction)),
+            Event::DuplicateConnectionDropped { peer_addr, dropped } => {
+                FfiEvent::new(FfiEventKind::DuplicateConnectionDropped)
+                    .with_peer_addr(peer_addr)
+                    .with_value(match dropped {
+                        DroppedConnection::Existing => 0,
+                        DroppedConnection::New => 1,
+                    })
+            }
+            Event::PeerAddressChanged { old, new } => {
+                FfiEvent::new(FfiEventKind::PeerAddressChanged)
+                    .with_peer_addr(new)
+                    .with_payload(old.to_string().into_bytes())
+            }
+            Event::ConnectionMigrated { peer_addr } => {
+                FfiEvent::new(FfiEventKind::ConnectionMigrated).with_peer_addr(peer_addr)
+            }
+            Event::MigrationFailed { peer_addr } => {
+                FfiEvent::new(FfiEventKind::MigrationFailed).with_peer_addr(peer_addr)
+            }
+            Event::NewMessage {
+                peer_addr,
+                msg,
+                channel,
+            } => FfiEvent::new(FfiEventKind::NewMessage)
+                .with_peer_addr(peer_addr)
+                .with_payload(msg.to_vec())
+                .with_value(u64::from(channel)),
+            Event::ForwardedMessage {
+                relay_addr,
+                peer_addr,
+                msg,
+            } => {
+                let mut event = FfiEvent::new(FfiEventKind::ForwardedMessage)
+                    .with_peer_addr(peer_addr)
+                    .with_payload(msg.to_vec());
+                event.relay_addr = FfiBuffer::new(relay_addr.to_string().into_bytes());
+                event
+            }
+            Event::GossipMessage { peer_addr, msg } => FfiEvent::new(FfiEventKind::GossipMessage)
+                .with_peer_addr(peer_addr)
+                .with_payload(msg.to_vec()),
+            Event::TopicMessage {
+                topic,
+                peer_addr,
+                msg,
+            } => {
+                let mut event = FfiEvent::new(FfiEventKind::TopicMessage)
+                    .with_peer_addr(peer_addr)
+                    .with_payload(msg.to_vec());
+                event.topic = FfiBuffer::new(topic.into_bytes());
+                event
+            }
+            Event::ReachabilityChecked {
+                via_peer,
+                addr,
+                reachable,
+            } => {
+                let kind = if reachable {
+                    FfiEventKind::Reachable
+                } else {
+                    FfiEventKind::Unreachable
+                };
+                FfiEvent::new(kind)
+                    .with_peer_addr(via_peer)
+                    .with_payload(addr.to_string().into_bytes())
+            }
+            Event::QuorumSendResolved {
+                id,
+                reached,
+                failed,
+                ..
+            } => {
+                let kind = if reached {
+                    FfiEventKind::QuorumReached
+                } else {
+                    FfiEventKind::QuorumFailed
+                };
+                let failed: Vec<_> = failed.iter().map(SocketAddr::to_string).collect();
+                FfiEvent::new(kind)
+                    .with_payload(failed.join(",").into_bytes())
+                    .with_value(id.as_u64())
+            }
+            Event::EventsDropped { count } => {
+                FfiEvent::new(FfiEventKind::EventsDropped).with_value(count)
+            }
+            Event::ConfigFileChanged { path } => FfiEvent::new(FfiEventKind::ConfigFileChanged)
+                .with_payload(path.to_string_lossy().into_owned().into_bytes()),
+            Event::CertificateExpiring { peer_addr, when } => {
+                let secs = when
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0);
+                let event = FfiEvent::new(FfiEventKind::CertificateExpiring).with_value(secs);
+                match peer_addr {
+                    Some(peer_addr) => event.with_peer_addr(peer_addr),
+                    None => event,
+                }
+            }
+            Event::CertificateRenewed { cert } => {
+                let mut event = FfiEvent::new(FfiEventKind::CertificateRenewed);
+                event.peer_cert_der = FfiBuffer::new(cert.cert_der);
+                event
+            }
+            Event::Reconnecting {
+                peer_addr, attempt, ..
+            } => FfiEvent::new(FfiEventKind::Reconnecting)
+                .with_peer_addr(peer_addr)
+                .with_value(u64::from(attempt)),
+            Event::Reconnected {
+                peer_addr,
+                attempts,
+            } => FfiEvent::new(FfiEventKind::Reconnected)
+                .with_peer_addr(peer_addr)
+                .with_value(u64::from(attempts)),
+            Event::Finish => FfiEvent::new(FfiEventKind::Finish),
+        }
+    }
+}
+
+/// Create an instance from the given JSON encoded `Config`, or from the config file if
+/// `cfg_json` is null. Returns null on failure.
+///
+/// # Safety
+///
+/// `cfg_json` must be null or point to `cfg_json_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_new(
+    cfg_json: *const u8,
+    cfg_json_len: usize,
+) -> *mut QuicP2pHandle {
+    let mut handle = ptr::null_mut();
+    let _ = catch(|| {
+        let (tx, event_rx) = mpmc::unbounded();
+        let mut builder = Builder::new(tx);
+        if !cfg_json.is_null() {
+            let cfg: Config = serde_json::from_slice(bytes(cfg_json, cfg_json_len))
+                .map_err(|e| format!("Invalid config: {}", e))?;
+            builder = builder.with_config(cfg);
+        }
+        let qp2p = builder.build().map_err(|e| e.to_string())?;
+        handle = Box::into_raw(Box::new(QuicP2pHandle { qp2p, event_rx }));
+        Ok(())
+    });
+
+    handle
+}
+
+/// Shut the instance down and release it. Null is ignored.
+///
+/// # Safety
+///
+/// `handle` must be null or returned by `quic_p2p_new` and not freed before. It must not be
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_free(handle: *mut QuicP2pHandle) {
+    if !handle.is_null() {
+        let _ = catch(|| {
+            drop(Box::from_raw(handle));
+            Ok(())
+        });
+    }
+}
+
+/// See `QuicP2p::bootstrap`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle returned by `quic_p2p_new`.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_bootstrap(handle: *mut QuicP2pHandle) -> i32 {
+    catch(|| {
+        handle_mut(handle)?.qp2p.bootstrap();
+        Ok(())
+    })
+}
+
+/// Connect to the node at the `ip:port` address, authenticating it by its DER encoded
+/// certificate. See `QuicP2p::connect_to`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle, `peer_addr` null or a NUL terminated string and
+/// `peer_cert_der` null or pointing to `peer_cert_der_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_connect_to(
+    handle: *mut QuicP2pHandle,
+    peer_addr: *const c_char,
+    peer_cert_der: *const u8,
+    peer_cert_der_len: usize,
+) -> i32 {
+    catch(|| {
+        let node_info = NodeInfo {
+            peer_addr: socket_addr(peer_addr)?,
+            peer_cert_der: bytes(peer_cert_der, peer_cert_der_len).to_vec(),
+            alt_addrs: Vec::new(),
+        };
+        handle_mut(handle)?.qp2p.connect_to(node_info);
+        Ok(())
+    })
+}
+
+/// See `QuicP2p::disconnect_from`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle and `peer_addr` null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_disconnect_from(
+    handle: *mut QuicP2pHandle,
+    peer_addr: *const c_char,
+) -> i32 {
+    catch(|| {
+        let peer_addr = socket_addr(peer_addr)?;
+        handle_mut(handle)?.qp2p.disconnect_from(peer_addr);
+        Ok(())
+    })
+}
+
+/// Send the message to the peer at the `ip:port` address. The peer is a node authenticated by
+/// the DER encoded certificate, or a client connected to us if `peer_cert_der` is null. See
+/// `QuicP2p::send`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle, `peer_addr` null or a NUL terminated string, and
+/// `peer_cert_der` and `msg` null or pointing to as many readable bytes as their lengths say.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_send(
+    handle: *mut QuicP2pHandle,
+    peer_addr: *const c_char,
+    peer_cert_der: *const u8,
+    peer_cert_der_len: usize,
+    msg: *const u8,
+    msg_len: usize,
+) -> i32 {
+    catch(|| {
+        let peer_addr = socket_addr(peer_addr)?;
+        let peer = if peer_cert_der.is_null() {
+            Peer::Client { peer_addr }
+        } else {
+            Peer::Node {
+                node_info: NodeInfo {
+                    peer_addr,
+                    peer_cert_der: bytes(peer_cert_der, peer_cert_der_len).to_vec(),
+                    alt_addrs: Vec::new(),
+                },
+            }
+        };
+        let msg = From::from(bytes(msg, msg_len));
+        handle_mut(handle)?.qp2p.send(peer, msg);
+        Ok(())
+    })
+}
+
+/// Have the node at the `relay_addr` address relay the message to its client at the `peer_addr`
+/// address, both `ip:port`. See `QuicP2p::forward`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle, `relay_addr` and `peer_addr` null or NUL terminated
+/// strings, and `msg` null or pointing to `msg_len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_forward(
+    handle: *mut QuicP2pHandle,
+    relay_addr: *const c_char,
+    peer_addr: *const c_char,
+    msg: *const u8,
+    msg_len: usize,
+) -> i32 {
+    catch(|| {
+        let relay_addr = socket_addr(relay_addr)?;
+        let peer_addr = socket_addr(peer_addr)?;
+        let msg = From::from(bytes(msg, msg_len));
+        handle_mut(handle)?.qp2p.forward(relay_addr, peer_addr, msg);
+        Ok(())
+    })
+}
+
+/// Write our `ip:port` address and DER encoded certificate into the buffers. See
+/// `QuicP2p::our_connection_info`.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle, and the output buffers null or writable. Whatever
+/// they held before is overwritten without being released.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_our_connection_info(
+    handle: *mut QuicP2pHandle,
+    out_addr: *mut FfiBuffer,
+    out_cert_der: *mut FfiBuffer,
+) -> i32 {
+    catch(|| {
+        if out_addr.is_null() || out_cert_der.is_null() {
+            return Err("Null output buffer".to_string());
+        }
+        let our_info = handle_mut(handle)?
+            .qp2p
+            .our_connection_info()
+            .map_err(|e| e.to_string())?;
+        ptr::write(
+            out_addr,
+            FfiBuffer::new(our_info.peer_addr.to_string().into_bytes()),
+        );
+        ptr::write(out_cert_der, FfiBuffer::new(our_info.peer_cert_der));
+        Ok(())
+    })
+}
+
+/// Take the next event, waiting up to `timeout_ms` milliseconds for one. Returns 1 if an event was
+/// written into `out_event`, 0 if there was none in time and `QUIC_P2P_ERR` on failure.
+///
+/// # Safety
+///
+/// `handle` must be null or a live handle, and `out_event` null or writable. Whatever it held
+/// before is overwritten without being released.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_poll_event(
+    handle: *mut QuicP2pHandle,
+    timeout_ms: u64,
+    out_event: *mut FfiEvent,
+) -> i32 {
+    let mut code = 0;
+    let r = catch(|| {
+        if out_event.is_null() {
+            return Err("Null output event".to_string());
+        }
+        let event_rx = &handle_mut(handle)?.event_rx;
+        match event_rx.recv_timeout(Duration::from_millis(timeout_ms)) {
+            Ok(event) => {
+                ptr::write(out_event, FfiEvent::from(event));
+                code = 1;
+                Ok(())
+            }
+            Err(mpmc::RecvTimeoutError::Timeout) => Ok(()),
+            Err(mpmc::RecvTimeoutError::Disconnected) => Err("Event loop is gone".to_string()),
+        }
+    });
+
+    if r == QUIC_P2P_OK {
+        code
+    } else {
+        r
+    }
+}
+
+/// Release the buffers of an event taken with `quic_p2p_poll_event`. The event itself is owned by
+/// the caller.
+///
+/// # Safety
+///
+/// `event` must be null or an event written by `quic_p2p_poll_event`.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_event_free(event: *mut FfiEvent) {
+    if let Some(event) = event.as_mut() {
+        event.peer_addr.free();
+        event.peer_cert_der.free();
+        event.payload.free();
+        event.relay_addr.free();
+        event.topic.free();
+    }
+}
+
+/// Release a buffer handed out by the library.
+///
+/// # Safety
+///
+/// `buf` must be null or a buffer written by the library.
+#[no_mangle]
+pub unsafe extern "C" fn quic_p2p_buffer_free(buf: *mut FfiBuffer) {
+    if let Some(buf) = buf.as_mut() {
+        buf.free();
+    }
+}
+
+/// Description of the last failure of a call on this thread, or null if there was none. Remains
+/// valid until the next failure on this thread.
+#[no_mangle]
+pub extern "C" fn quic_p2p_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map(|e| e.as_ptr())
+            .unwrap_or_else(ptr::null)
+    })
+}
+
+/// Run the body of a call, turning failures and panics into `QUIC_P2P_ERR`.
+fn catch<F>(f: F) -> i32
+where
+    F: FnOnce() -> Result<(), String>,
+{
+    let e = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return QUIC_P2P_OK,
+        Ok(Err(e)) => e,
+        Err(_) => "quic-p2p panicked".to_string(),
+    };
+    let e = CString::new(e).unwrap_or_else(|_| unwrap!(CString::new("Invalid error message")));
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(e));
+
+    QUIC_P2P_ERR
+}
+
+unsafe fn handle_mut<'a>(handle: *mut QuicP2pHandle) -> Result<&'a mut QuicP2pHandle, String> {
+    handle.as_mut().ok_or_else(|| "Null handle".to_string())
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if data.is_null() || len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+unsafe fn socket_addr(addr: *const c_char) -> Result<SocketAddr, String> {
+    if addr.is_null() {
+        return Err("Null peer address".to_string());
+    }
+    let addr = CStr::from_ptr(addr)
+        .to_str()
+        .map_err(|e| format!("Invalid peer address: {}", e))?;
+    addr.parse()
+        .map_err(|e| format!("Invalid peer address {}: {}", addr, e))
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn direction_value(direction: ConnectionDirection) -> u64 {
+    match direction {
+        ConnectionDirection::Outgoing => 0,
+        ConnectionDirection::Incoming => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::os::raw::c_char;
+
+    fn new_handle() -> *mut QuicP2pHandle {
+        let cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Config::with_default_cert()
+        };
+        let cfg_json = unwrap!(serde_json::to_vec(&cfg));
+        let handle = unsafe { quic_p2p_new(cfg_json.as_ptr(), cfg_json.len()) };
+        assert!(!handle.is_null());
+        handle
+    }
+
+    fn poll_event(handle: *mut QuicP2pHandle) -> FfiEvent {
+        let mut event = FfiEvent::new(FfiEventKind::Finish);
+        assert_eq!(
+            unsafe { quic_p2p_poll_event(handle, 10_000, &mut event) },
+            1
+        );
+        event
+    }
+
+    unsafe fn buffer(buf: &FfiBuffer) -> &[u8] {
+        bytes(buf.data, buf.len)
+    }
+
+    #[test]
+    fn peers_exchange_messages_through_the_c_abi() {
+        let node = new_handle();
+        let peer = new_handle();
+
+        unsafe {
+            let mut node_addr = FfiBuffer::empty();
+            let mut node_cert = FfiBuffer::empty();
+            assert_eq!(
+                quic_p2p_our_connection_info(node, &mut node_addr, &mut node_cert),
+                QUIC_P2P_OK
+            );
+            let node_addr_c = unwrap!(CString::new(buffer(&node_addr)));
+
+            let msg = b"ping";
+            assert_eq!(
+                quic_p2p_send(
+                    peer,
+                    node_addr_c.as_ptr(),
+                    node_cert.data,
+                    node_cert.len,
+                    msg.as_ptr(),
+                    msg.len()
+                ),
+                QUIC_P2P_OK
+            );
+
+            let mut event = poll_event(peer);
+            assert_eq!(event.kind, FfiEventKind::ConnectedTo);
+            assert_eq!(buffer(&event.peer_addr), buffer(&node_addr));
+            assert_eq!(buffer(&event.peer_cert_der), buffer(&node_cert));
+            assert_eq!(event.value, 0);
+            quic_p2p_event_free(&mut event);
+
+            let mut event = poll_event(node);
+            assert_eq!(event.kind, FfiEventKind::ConnectedTo);
+            assert_eq!(event.value, 1);
+            quic_p2p_event_free(&mut event);
+            let mut event = poll_event(node);
+            assert_eq!(event.kind, FfiEventKind::NewMessage);
+            assert_eq!(buffer(&event.payload), msg);
+            quic_p2p_event_free(&mut event);
+            assert!(event.payload.data.is_null());
+
+            quic_p2p_buffer_free(&mut node_addr);
+            quic_p2p_buffer_free(&mut node_cert);
+            quic_p2p_free(peer);
+            quic_p2p_free(node);
+        }
+    }
+
+    #[test]
+    fn forwarded_messages_carry_the_relay_addr() {
+        let relay_addr: SocketAddr = ([10, 0, 0, 1], 5000).into();
+        let peer_addr: SocketAddr = ([10, 0, 0, 2], 6000).into();
+        let mut event = FfiEvent::from(Event::ForwardedMessage {
+            relay_addr,
+            peer_addr,
+            msg: From::from(&b"hi"[..]),
+        });
+
+        assert_eq!(event.kind, FfiEventKind::ForwardedMessage);
+        unsafe {
+            assert_eq!(buffer(&event.relay_addr), b"10.0.0.1:5000");
+            assert_eq!(buffer(&event.peer_addr), b"10.0.0.2:6000");
+            assert_eq!(buffer(&event.payload), b"hi");
+            quic_p2p_event_free(&mut event);
+        }
+        assert!(event.relay_addr.data.is_null());
+    }
+
+    #[test]
+    fn topic_messages_carry_the_topic() {
+        let mut event = FfiEvent::from(Event::TopicMessage {
+            topic: "chunks".to_string(),
+            peer_addr: ([10, 0, 0, 2], 6000).into(),
+            msg: From::from(&b"hi"[..]),
+        });
+
+        assert_eq!(event.kind, FfiEventKind::TopicMessage);
+        unsafe {
+            assert_eq!(buffer(&event.topic), b"chunks");
+            assert_eq!(buffer(&event.peer_addr), b"10.0.0.2:6000");
+            assert_eq!(buffer(&event.payload), b"hi");
+            quic_p2p_event_free(&mut event);
+        }
+        assert!(event.topic.data.is_null());
+    }
+
+    #[test]
+    fn failures_are_described_by_the_last_error() {
+        let handle = new_handle();
+        let invalid_addr = b"not an address\0";
+        let r = unsafe { quic_p2p_disconnect_from(handle, invalid_addr.as_ptr() as *const c_char) };
+        assert_eq!(r, QUIC_P2P_ERR);
+        let e = unsafe { CStr::from_ptr(quic_p2p_last_error()) };
+        assert!(unwrap!(e.to_str()).starts_with("Invalid peer address not an address"));
+
+        assert_eq!(unsafe { quic_p2p_bootstrap(ptr::null_mut()) }, QUIC_P2P_ERR);
+        let e = unsafe { CStr::from_ptr(quic_p2p_last_error()) };
+        assert_eq!(unwrap!(e.to_str()), "Null handle");
+
+        unsafe { quic_p2p_free(handle) };
+    }
+}
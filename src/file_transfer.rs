@@ -0,0 +1,478 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Streaming files between peers straight from and to disk, see `QuicP2p::send_file` and
+//! `QuicP2p::receive_file`.
+//!
+//! A file travels on a stream of its own: `FILE_STREAM_MAGIC`, the length of the file name as a
+//! big endian `u16`, the name, the length of the file as a big endian `u64` and then its contents.
+//! Only connections whose encoding offers push streams carry files, as only their streams are
+//! looked at for a magic. At most `READ_AHEAD` chunks of a file are held in memory at either end.
+//!
+//! Cancelling a file being sent drops its stream unfinished, which resets it. The receiver tells
+//! that apart from a lost connection by the error it reads. Incoming files are read ahead from the
+//! moment they are announced, so that a file cancelled before it is taken is noticed too.
+
+use crate::connection::{FromPeer, ToPeer};
+use crate::context::{ctx, ctx_mut};
+use crate::event::{Event, Token};
+use crate::utils::R;
+use crate::{Error, Peer};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+use tokio::prelude::future::{self, Either, Loop};
+use tokio::prelude::{Future, Sink, Stream};
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc;
+
+/// First bytes of a file stream. Same length as the magic of push streams, so that both are
+/// told apart by the same prefix.
+pub const FILE_STREAM_MAGIC: &[u8] = b"\xffqp2p-file";
+
+/// Bytes read from or written to disk at a time.
+const CHUNK_LEN: usize = 64 * 1024;
+/// Chunks read ahead of the stream, or of the disk when receiving.
+const READ_AHEAD: usize = 4;
+/// Least time between two progress events of a file.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Chunks of a file on their way between the disk and the event loop.
+type Chunks = mpsc::Receiver<io::Result<Vec<u8>>>;
+
+/// Identifies a file a peer sends us in the `Event::IncomingFile` fired for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId(pub(crate) u64);
+
+impl FileId {
+    /// The id as a number, unique among the files received by a `QuicP2p` instance.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// A file a peer started sending us, held until `QuicP2p::receive_file` or
+/// `QuicP2p::reject_file`.
+pub struct IncomingFile {
+    len: u64,
+    chunks: Chunks,
+}
+
+/// A file being sent, which `QuicP2p::cancel_stream` may stop.
+pub struct OutgoingFile {
+    token: Token,
+    cancel: mpsc::UnboundedSender<()>,
+}
+
+/// Send the file at `path` to the peer we are connected to, firing `Event::SentFile` or
+/// `Event::UnsentFile` with the token once done.
+pub fn send(peer: Peer, path: PathBuf, token: Token) {
+    let (cancel, cancelled) = mpsc::unbounded_channel();
+    let id = ctx_mut(|c| {
+        let id = c.next_file_id;
+        c.next_file_id += 1;
+        let _ = c.outgoing_files.insert(id, OutgoingFile { token, cancel });
+        id
+    });
+
+    let leaf = future::result(start_sending(peer.peer_addr(), &path, token))
+        .flatten()
+        .select2(cancelled.into_future())
+        .then(move |r| {
+            let _ = ctx_mut(|c| c.outgoing_files.remove(&id));
+            let r = match r {
+                Ok(Either::A(_)) => Ok(()),
+                Err(Either::A((err, _))) => Err(err),
+                // Dropping what is left of sending resets the stream
+                Ok(Either::B(((Some(()), _), _))) => Err(Error::TransferCancelled),
+                Ok(Either::B(((None, _), _))) | Err(Either::B(_)) => {
+                    Err(Error::ConnectionCancelled)
+                }
+            };
+            let event = match r {
+                Ok(()) => Event::SentFile { peer, path, token },
+                Err(err) => Event::UnsentFile {
+                    peer,
+                    path,
+                    token,
+                    err,
+                },
+            };
+            fire(event);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn start_sending(
+    peer_addr: SocketAddr,
+    path: &Path,
+    token: Token,
+) -> R<Box<dyn Future<Item = (), Error = Error>>> {
+    let opening = ctx(|c| {
+        let conn = c
+            .connections
+            .get(&peer_addr)
+            .ok_or_else(|| Error::FileTransfer(format!("Not connected to {}", peer_addr)))?;
+        let q_conn = match (&conn.to_peer, &conn.from_peer) {
+            (ToPeer::Established { ref q_conn, .. }, _)
+            | (ToPeer::NotNeeded, FromPeer::Established { ref q_conn, .. }) => q_conn,
+            _ => {
+                return Err(Error::FileTransfer(format!(
+                    "Connection to {} is not established",
+                    peer_addr
+                )))
+            }
+        };
+        if !q_conn.encoding().offers_push_streams() {
+            return Err(Error::FileTransfer(format!(
+                "The {:?} encoding of the connection to {} carries no files",
+                q_conn.encoding(),
+                peer_addr
+            )));
+        }
+        Ok(q_conn.open_stream())
+    })?;
+
+    let name = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| Error::FileTransfer(format!("No file name in {}", path.display())))?;
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let header = header(name, len)?;
+    let chunks = read_ahead(file);
+    let progress = Progress::new(len, ctx(|c| c.progress_events_above));
+
+    let leaf = opening
+        .and_then(move |o_stream| tokio::io::write_all(o_stream, header).map_err(Error::from))
+        .and_then(move |(o_stream, _)| {
+            chunks.map_err(|_| Error::ConnectionCancelled).fold(
+                (o_stream, progress),
+                move |(o_stream, mut progress), chunk| {
+                    future::result(chunk.map_err(Error::from)).and_then(move |chunk| {
+                        tokio::io::write_all(o_stream, chunk)
+                            .map(move |(o_stream, chunk)| {
+                                if let Some(bytes_sent) = progress.advance(chunk.len()) {
+                                    fire(Event::SendProgress {
+                                        token,
+                                        bytes_sent,
+                                        total: len,
+                                    });
+                                }
+                                (o_stream, progress)
+                            })
+                            .map_err(Error::from)
+                    })
+                },
+            )
+        })
+        .and_then(move |(o_stream, progress)| {
+            if progress.done != len {
+                return Err(Error::FileTransfer(format!(
+                    "File changed from {} to {} bytes while being sent",
+                    len, progress.done
+                )));
+            }
+            Ok(o_stream)
+        })
+        .and_then(|o_stream| tokio::io::shutdown(o_stream).map_err(Error::from))
+        .map(|_| ());
+
+    Ok(Box::new(leaf))
+}
+
+/// The start of a file stream, up to the contents of the file.
+fn header(name: &str, len: u64) -> R<Vec<u8>> {
+    if name.len() > u16::MAX as usize {
+        return Err(Error::FileTransfer(format!(
+            "File name {} is too long",
+            name
+        )));
+    }
+    let mut header = Vec::with_capacity(FILE_STREAM_MAGIC.len() + 2 + name.len() + 8);
+    header.extend_from_slice(FILE_STREAM_MAGIC);
+    header.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&len.to_be_bytes());
+    Ok(header)
+}
+
+/// Read the file a chunk at a time on a thread of its own, which waits while `READ_AHEAD` chunks
+/// are yet to be taken. Stops at the end of the file, the first error or once the chunks are no
+/// longer taken.
+fn read_ahead(mut file: File) -> Chunks {
+    let (mut chunks_tx, chunks_rx) = mpsc::channel(READ_AHEAD);
+    let _ = thread::spawn(move || loop {
+        let mut chunk = vec![0; CHUNK_LEN];
+        let chunk = match file.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let is_err = chunk.is_err();
+        chunks_tx = match chunks_tx.send(chunk).wait() {
+            Ok(chunks_tx) if !is_err => chunks_tx,
+            _ => return,
+        };
+    });
+
+    chunks_rx
+}
+
+/// Read the header of a file stream past its magic and hold on to the stream, firing
+/// `Event::IncomingFile` for the application to receive or reject the file.
+pub fn accept<S: AsyncRead + 'static>(
+    peer_addr: SocketAddr,
+    i_stream: S,
+) -> impl Future<Item = (), Error = ()> {
+    tokio::io::read_exact(i_stream, [0; 2])
+        .and_then(|(i_stream, name_len)| {
+            let name_len = u16::from_be_bytes(name_len) as usize;
+            tokio::io::read_exact(i_stream, vec![0; name_len])
+        })
+        .and_then(|(i_stream, name)| {
+            tokio::io::read_exact(i_stream, [0; 8])
+                .map(|(i_stream, len)| (i_stream, name, u64::from_be_bytes(len)))
+        })
+        .map_err(move |e| debug!("Error reading the file header from {}: {}", peer_addr, e))
+        .and_then(move |(i_stream, name, len)| {
+            let name = String::from_utf8(name)
+                .map_err(|_| debug!("File name from {} is not UTF-8", peer_addr))?;
+            let id = ctx_mut(|c| {
+                let id = FileId(c.next_file_id);
+                c.next_file_id += 1;
+                id
+            });
+            let chunks = read_in(peer_addr, id, len, i_stream);
+            ctx_mut(|c| {
+                let _ = c.incoming_files.insert(id, IncomingFile { len, chunks });
+            });
+            fire(Event::IncomingFile {
+                peer_addr,
+                id,
+                name,
+                len,
+            });
+            Ok(())
+        })
+}
+
+/// Take the incoming file out of the event loop, returning its length and the chunks of its
+/// contents as they arrive, see `write_out`.
+pub fn stream_out(id: FileId) -> R<(u64, Chunks)> {
+    let IncomingFile { len, chunks } = ctx_mut(|c| c.incoming_files.remove(&id))
+        .ok_or_else(|| Error::FileTransfer(format!("No incoming file {}", id.0)))?;
+    Ok((len, chunks))
+}
+
+/// Read the contents of the incoming file a chunk at a time as they arrive, at most `READ_AHEAD`
+/// chunks ahead of those taken. Should the peer cancel sending the file,
+/// `Event::IncomingFileCancelled` is fired and the file is dropped if it is yet to be taken.
+fn read_in<S: AsyncRead + 'static>(
+    peer_addr: SocketAddr,
+    id: FileId,
+    len: u64,
+    contents: S,
+) -> Chunks {
+    let (chunks_tx, chunks_rx) = mpsc::channel(READ_AHEAD);
+    let progress = Progress::new(len, ctx(|c| c.progress_events_above));
+    let leaf = future::loop_fn(
+        (contents, chunks_tx, progress),
+        move |(contents, chunks_tx, mut progress)| {
+            tokio::io::read(contents, vec![0; CHUNK_LEN]).then(move |r| match r {
+                Ok((_, _, 0)) => Either::A(future::ok(Loop::Break(()))),
+                Ok((contents, mut chunk, n)) => {
+                    chunk.truncate(n);
+                    if let Some(bytes_received) = progress.advance(n) {
+                        fire(Event::ReceiveProgress {
+                            id,
+                            bytes_received,
+                            total: len,
+                        });
+                    }
+                    Either::B(Either::A(
+                        chunks_tx
+                            .send(Ok(chunk))
+                            .map(|chunks_tx| Loop::Continue((contents, chunks_tx, progress)))
+                            .map_err(|_| ()),
+                    ))
+                }
+                Err(e) => {
+                    if is_reset(&e) {
+                        debug!("Peer {} cancelled sending file {}", peer_addr, id.0);
+                        let _ = ctx_mut(|c| c.incoming_files.remove(&id));
+                        fire(Event::IncomingFileCancelled { peer_addr, id });
+                    }
+                    Either::B(Either::B(
+                        chunks_tx.send(Err(e)).then(|_| Ok(Loop::Break(()))),
+                    ))
+                }
+            })
+        },
+    );
+    current_thread::spawn(leaf);
+
+    chunks_rx
+}
+
+/// Bytes of a file done so far, telling when to fire its progress events, see
+/// `Builder::with_progress_events`.
+struct Progress {
+    len: u64,
+    done: u64,
+    enabled: bool,
+    last_fired: Instant,
+}
+
+impl Progress {
+    fn new(len: u64, events_above: Option<u64>) -> Self {
+        Self {
+            len,
+            done: 0,
+            enabled: events_above.map_or(false, |min_len| len > min_len),
+            last_fired: Instant::now(),
+        }
+    }
+
+    /// Count the bytes done, returning the bytes done so far if they are to be reported: once
+    /// `PROGRESS_INTERVAL` passed since the last report and once the whole file is done.
+    fn advance(&mut self, n: usize) -> Option<u64> {
+        self.done += n as u64;
+        if !self.enabled {
+            return None;
+        }
+        let now = Instant::now();
+        if self.done < self.len && now.duration_since(self.last_fired) < PROGRESS_INTERVAL {
+            return None;
+        }
+        self.last_fired = now;
+        Some(self.done)
+    }
+}
+
+fn fire(event: Event) {
+    ctx(|c| {
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+    });
+}
+
+/// Stop sending the files sent with the token, returning whether there were any.
+pub fn cancel(token: Token) -> bool {
+    ctx_mut(|c| {
+        let mut any = false;
+        for file in c.outgoing_files.values_mut() {
+            if file.token == token {
+                any |= file.cancel.try_send(()).is_ok();
+            }
+        }
+        any
+    })
+}
+
+/// Whether reading the file failed as its sender reset the stream, see `cancel`.
+fn is_reset(e: &io::Error) -> bool {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<quinn::ReadError>())
+        .map_or(false, |e| match e {
+            quinn::ReadError::Reset { .. } => true,
+            _ => false,
+        })
+}
+
+/// Drop the incoming file, which stops the peer sending it once the next of its chunks is read.
+/// Returns whether there was such a file.
+pub fn reject(id: FileId) -> bool {
+    ctx_mut(|c| c.incoming_files.remove(&id)).is_some()
+}
+
+/// Write the chunks of an incoming file to `dest`, checking it is as long as announced. Returns
+/// the length of the file. Should that fail, what was written of the file is removed.
+pub fn write_out(dest: &Path, len: u64, chunks: Chunks) -> R<u64> {
+    let written = (|| {
+        let mut file = File::create(dest)?;
+        let mut written = 0;
+        for chunk in chunks.wait() {
+            let chunk = chunk
+                .map_err(|_| Error::ConnectionCancelled)?
+                .map_err(|e| {
+                    if is_reset(&e) {
+                        Error::TransferCancelled
+                    } else {
+                        Error::from(e)
+                    }
+                })?;
+            file.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        file.sync_all()?;
+        if written != len {
+            return Err(Error::FileTransfer(format!(
+                "Received {} of the {} bytes of the file",
+                written, len
+            )));
+        }
+        Ok(written)
+    })();
+
+    if written.is_err() {
+        let _ = fs::remove_file(dest);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_lays_out_the_name_and_length() {
+        let header = unwrap!(header("chunk-1", 3));
+        let (magic, header) = header.split_at(FILE_STREAM_MAGIC.len());
+        assert_eq!(magic, FILE_STREAM_MAGIC);
+        assert_eq!(header[..2], 7u16.to_be_bytes());
+        assert_eq!(&header[2..9], b"chunk-1");
+        assert_eq!(header[9..], 3u64.to_be_bytes());
+    }
+
+    #[test]
+    fn progress_is_reported_at_intervals_and_once_done() {
+        let mut progress = Progress::new(3, Some(2));
+        assert_eq!(progress.advance(1), None);
+        progress.last_fired -= PROGRESS_INTERVAL;
+        assert_eq!(progress.advance(1), Some(2));
+        assert_eq!(progress.advance(1), Some(3));
+
+        let mut progress = Progress::new(3, Some(3));
+        progress.last_fired -= PROGRESS_INTERVAL;
+        assert_eq!(progress.advance(3), None);
+        assert_eq!(progress.done, 3);
+    }
+
+    #[test]
+    fn overlong_names_are_refused() {
+        let name = "x".repeat(u16::MAX as usize + 1);
+        match header(&name, 0) {
+            Err(Error::FileTransfer(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Relaying of messages between the clients connected to a node, so that clients which can't be
+//! reached directly can still talk to each other. Nodes only relay if enabled via
+//! `Builder::with_client_forwarding`; clients ask for it with `QuicP2p::forward`.
+
+use crate::communicate;
+use crate::connection::FromPeer;
+use crate::context::ctx_mut;
+use crate::wire_msg::WireMsg;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Length of the windows the messages relayed between a pair of clients are counted in.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Rate limits of the pairs of clients we relay messages between.
+#[derive(Debug)]
+pub struct Forwarder {
+    max_msgs_per_sec: u32,
+    windows: HashMap<(SocketAddr, SocketAddr), Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started_at: Instant,
+    msgs: u32,
+}
+
+impl Forwarder {
+    pub fn new(max_msgs_per_sec: u32) -> Self {
+        Self {
+            max_msgs_per_sec,
+            windows: Default::default(),
+        }
+    }
+
+    /// Whether another message may be relayed from `from` to `to` as of `now`, counting it if so.
+    fn allow(&mut self, from: SocketAddr, to: SocketAddr, now: Instant) -> bool {
+        // Forget the pairs whose window is over rather than tracking disconnects
+        self.windows
+            .retain(|_, window| now.duration_since(window.started_at) < WINDOW);
+        let window = self.windows.entry((from, to)).or_insert(Window {
+            started_at: now,
+            msgs: 0,
+        });
+        if window.msgs >= self.max_msgs_per_sec {
+            return false;
+        }
+        window.msgs += 1;
+        true
+    }
+}
+
+/// Relay the message from our client `from` to our client `to`, unless forwarding is disabled or
+/// the pair exceeded its rate limit.
+pub fn forward(from: SocketAddr, to: SocketAddr, msg: bytes::Bytes) {
+    ctx_mut(|c| {
+        let connections = &c.connections;
+        let is_client = |peer_addr| {
+            connections
+                .get(peer_addr)
+                .and_then(|conn| conn.client_session.as_ref())
+                .is_some()
+        };
+        if !is_client(&from) || !is_client(&to) {
+            return debug!(
+                "Not forwarding message from {} to {} as both need to be our clients",
+                from, to
+            );
+        }
+
+        let allowed = match c.forwarder {
+            Some(ref mut forwarder) => forwarder.allow(from, to, c.clock.now()),
+            None => return debug!("Not forwarding message from {} as it's disabled", from),
+        };
+        if !allowed {
+            return debug!(
+                "Dropping message forwarded from {} to {} over the rate limit",
+                from, to
+            );
+        }
+
+        if let FromPeer::Established { ref q_conn, .. } = connections[&to].from_peer {
+            communicate::write_to_peer_connection(to, q_conn, WireMsg::ForwardedMsg { from, msg });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_are_rate_limited_per_window() {
+        let a: SocketAddr = ([10, 0, 0, 1], 1).into();
+        let b: SocketAddr = ([10, 0, 0, 2], 2).into();
+        let mut forwarder = Forwarder::new(2);
+        let start = Instant::now();
+
+        assert!(forwarder.allow(a, b, start));
+        assert!(forwarder.allow(a, b, start));
+        assert!(!forwarder.allow(a, b, start));
+        // Each direction has a limit of its own
+        assert!(forwarder.allow(b, a, start));
+
+        assert!(forwarder.allow(a, b, start + WINDOW));
+    }
+}
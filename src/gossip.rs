@@ -0,0 +1,216 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Epidemic broadcast of messages to all the peers reachable through our connections, see
+//! `QuicP2p::gossip`.
+//!
+//! Each message is sent to a few random peers we are connected to, each of which gossips it on
+//! to a few random peers of its own in turn until its TTL runs out. Every message is given a
+//! random ID by its originator, so peers drop the copies reaching them on other paths. Should the
+//! gossip miss some peers, they catch up through anti-entropy: every so often each peer sends one
+//! of its peers the IDs of the latest messages it holds, and the two send each other those the
+//! other lacks.
+
+use crate::communicate;
+use crate::context::{ctx_mut, Context};
+use crate::dedup::SeenMsgIds;
+use crate::event::Event;
+use crate::wire_msg::WireMsg;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// Number of peers a message is gossiped to by each peer unless set via `Builder::with_gossip`.
+pub const DEFAULT_FANOUT: usize = 3;
+/// Number of times a message gossiped is passed on at most.
+pub const TTL: u8 = 6;
+/// Number of the latest messages held for anti-entropy.
+pub const HELD: usize = 256;
+
+/// Messages gossiped to us or by us.
+pub struct Gossip {
+    fanout: usize,
+    seen: SeenMsgIds,
+    held: VecDeque<(u64, bytes::Bytes)>,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self {
+            fanout: DEFAULT_FANOUT,
+            seen: Default::default(),
+            held: VecDeque::with_capacity(HELD),
+        }
+    }
+}
+
+impl Gossip {
+    /// Remember the message, returning whether it wasn't seen before.
+    fn insert(&mut self, id: u64, msg: &bytes::Bytes) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        if self.held.len() >= HELD {
+            let _ = self.held.pop_front();
+        }
+        self.held.push_back((id, msg.clone()));
+        true
+    }
+
+    fn ids(&self) -> Vec<u64> {
+        self.held.iter().map(|&(id, _)| id).collect()
+    }
+}
+
+/// Gossip to the given number of peers from now on, and reconcile the messages held with a
+/// random peer every `interval`.
+pub fn start(fanout: usize, interval: Duration) {
+    ctx_mut(|c| c.gossip.fanout = fanout);
+
+    let leaf = Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| info!("Error in anti-entropy interval: {:?}", e))
+        .for_each(|_| {
+            reconcile();
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+/// Gossip a message of our own.
+pub fn gossip(msg: bytes::Bytes) {
+    let id = ctx_mut(|c| {
+        let id = c.rng.next_u64();
+        let _ = c.gossip.insert(id, &msg);
+        id
+    });
+    spread(None, id, TTL, msg);
+}
+
+/// Deliver the message if it wasn't seen before and gossip it on while it has TTL left.
+pub fn handle_gossip(peer_addr: SocketAddr, id: u64, ttl: u8, msg: bytes::Bytes) {
+    let is_new = ctx_mut(|c| {
+        if !c.gossip.insert(id, &msg) {
+            return false;
+        }
+        let event = Event::GossipMessage {
+            peer_addr,
+            msg: msg.clone(),
+        };
+        if let Err(e) = c.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+        true
+    });
+    if !is_new {
+        return trace!("Dropping duplicate gossip {} from peer {}", id, peer_addr);
+    }
+    if ttl > 0 {
+        spread(Some(peer_addr), id, ttl - 1, msg);
+    }
+}
+
+/// Send the peer the messages we hold and its digest lacks, and, unless the digest answers ours,
+/// our digest if it holds messages we haven't seen.
+pub fn handle_digest(peer_addr: SocketAddr, ids: Vec<u64>, reply: bool) {
+    let theirs: HashSet<u64> = ids.iter().cloned().collect();
+    let (missing, ours) = ctx_mut(|c| {
+        let missing: Vec<(u64, bytes::Bytes)> = c
+            .gossip
+            .held
+            .iter()
+            .filter(|&&(id, _)| !theirs.contains(&id))
+            .cloned()
+            .collect();
+        let we_lack = ids.iter().any(|&id| !c.gossip.seen.contains(id));
+        (missing, if we_lack { Some(c.gossip.ids()) } else { None })
+    });
+
+    // Passed on no further, as the gossip reached the peers around already
+    for (id, msg) in missing {
+        communicate::write_to_peer(peer_addr, WireMsg::Gossip { id, ttl: 0, msg });
+    }
+    if let (Some(ids), false) = (ours, reply) {
+        communicate::write_to_peer(peer_addr, WireMsg::GossipDigest { ids, reply: true });
+    }
+}
+
+fn reconcile() {
+    let (peer_addr, ids) = match ctx_mut(|c| {
+        let peers = connected_peers(c, None);
+        let peer_addr = *c.rng.pick(peers, 1).first()?;
+        Some((peer_addr, c.gossip.ids()))
+    }) {
+        Some(reconciliation) => reconciliation,
+        None => return,
+    };
+    communicate::write_to_peer(peer_addr, WireMsg::GossipDigest { ids, reply: false });
+}
+
+fn spread(from: Option<SocketAddr>, id: u64, ttl: u8, msg: bytes::Bytes) {
+    let peers = ctx_mut(|c| {
+        let peers = connected_peers(c, from);
+        let fanout = c.gossip.fanout;
+        c.rng.pick(peers, fanout)
+    });
+    for peer_addr in peers {
+        let msg = WireMsg::Gossip {
+            id,
+            ttl,
+            msg: msg.clone(),
+        };
+        communicate::write_to_peer(peer_addr, msg);
+    }
+}
+
+fn connected_peers(c: &Context, except: Option<SocketAddr>) -> Vec<SocketAddr> {
+    c.connections
+        .iter()
+        .filter(|&(&addr, conn)| Some(addr) != except && conn.is_complete())
+        .map(|(&addr, _)| addr)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn peers_are_picked_up_to_the_count() {
+        let mut rng = Rng::default();
+        let peers: Vec<SocketAddr> = (1..=5)
+            .map(|port| ([203, 0, 113, 1], port).into())
+            .collect();
+
+        let picked = rng.pick(peers.clone(), 3);
+        assert_eq!(picked.len(), 3);
+        assert!(picked.iter().all(|peer| peers.contains(peer)));
+        assert_eq!(rng.pick(peers.clone(), 10).len(), peers.len());
+        assert!(rng.pick(Vec::new(), 3).is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_messages_are_held() {
+        let mut gossip = Gossip::default();
+        let msg = bytes::Bytes::from(&b"msg"[..]);
+        assert!(gossip.insert(0, &msg));
+        assert!(!gossip.insert(0, &msg));
+
+        for id in 1..=HELD as u64 {
+            assert!(gossip.insert(id, &msg));
+        }
+        assert_eq!(gossip.ids(), (1..=HELD as u64).collect::<Vec<_>>());
+        // Still seen though no longer held
+        assert!(!gossip.insert(0, &msg));
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::context::ctx_mut;
+use crate::event::Event;
+use std::time::{Duration, Instant};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// Fire `Event::PeerHeartbeat` every `interval` for each complete connection that had no streams
+/// exchanged over it since the previous tick.
+///
+/// Such connections are kept alive by QUIC keep-alives alone - were the peer gone, the idle timeout
+/// would have closed the connection and `Event::ConnectionClosed` would have been fired instead.
+pub fn start(interval: Duration) {
+    let leaf = Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| info!("Error in heartbeat interval: {:?}", e))
+        .for_each(|_| {
+            beat();
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn beat() {
+    ctx_mut(|c| {
+        for (peer_addr, conn) in c.connections.iter_mut() {
+            if !conn.is_complete() {
+                continue;
+            }
+
+            let stats = conn.stats();
+            let streams = stats.streams_sent + stats.streams_received + stats.streams_failed;
+            if streams != conn.streams_at_last_heartbeat {
+                conn.streams_at_last_heartbeat = streams;
+                continue;
+            }
+
+            let event = Event::PeerHeartbeat {
+                peer_addr: *peer_addr,
+                handshake_duration: conn.handshake_duration,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+    })
+}
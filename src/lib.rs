@@ -9,8 +9,6 @@
 
 //! quic-p2p enables communication within a peer to peer network over the QUIC protocol.
 
-// Required for the quick_error! macro
-#![recursion_limit = "128"]
 // For explanation of lint checks, run `rustc -W help`
 #![forbid(
     exceeding_bitshifts,
@@ -54,51 +52,165 @@
 #[macro_use]
 extern crate log;
 #[macro_use]
-extern crate quick_error;
-#[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate structopt;
 #[macro_use]
 extern crate unwrap;
 
-pub use config::{Config, OurType, SerialisableCertificate};
+#[cfg(all(feature = "client-only", feature = "websocket"))]
+compile_error!("The `websocket` feature serves browser clients, which client-only builds can't do");
+
+pub use bandwidth::{Bandwidth, ByteRates};
+pub use bootstrap::{
+    AttemptOutcome, BootstrapAttempt, BootstrapReport, ContactFailure, ContactSource,
+};
+pub use cert_expiry::CertRenewal;
+#[cfg(feature = "chaos")]
+pub use chaos::Chaos;
+pub use client_session::ClientSession;
+pub use clock::{Clock, SystemClock};
+pub use communicate::DeliveryMode;
+pub use config::{
+    BootstrapStrategy, Config, ConfigChanges, DuplicateConnectionPolicy, Format, OurType,
+    SerialisableCertificate, CONFIG_VERSION, ENV_PREFIX,
+};
+pub use config_args::ConfigArgs;
+pub use dead_letter::DeadLetter;
+pub use dirs::DirOverrides;
+pub use drain_order::{DrainOrder, MsgPriority};
 pub use error::Error;
-pub use event::Event;
-pub use peer::{NodeInfo, Peer};
+pub use event::{
+    Channel, CloseReason, ConnectionDirection, DroppedConnection, Event, Token, UnsentReason,
+    DEFAULT_CHANNEL,
+};
+pub use event_sender::EventPolicy;
+pub use file_transfer::FileId;
+#[cfg(feature = "multiaddr")]
+pub use multi_addr::{from_multiaddr, to_multiaddr};
+#[cfg(feature = "multiaddr")]
+pub use multiaddr::Multiaddr;
+pub use nat::{NatMapping, NatReport};
+pub use peer::{Capabilities, NodeInfo, Peer};
 pub use peer_config::{DEFAULT_IDLE_TIMEOUT_MSEC, DEFAULT_KEEP_ALIVE_INTERVAL_MSEC};
+pub use probe::DEFAULT_LIVENESS_PROBES;
+#[cfg(feature = "unstable-quinn")]
+pub use quinn;
+pub use quorum::QuorumSendId;
+pub use reconnect::ReconnectPolicy;
+pub use resend::ResendPolicy;
+pub use send_options::SendOptions;
+pub use state_dump::{BootstrapCacheDump, ConnectionDump, StateDump};
+pub use stats::{AgeHistogram, BootstrapStats, ConnectionStats, PeerStats, Stats};
 pub use utils::R;
+#[cfg(any(feature = "test-utils", feature = "msg-inspector"))]
+pub use wire_msg::{Direction, Handshake, MsgInspector, WireMsg};
+pub use wire_msg::{
+    WireEncoding, CONNECTION_LIMIT_CODE, PROTOCOL_VERSION, PROTOCOL_VERSION_REFUSED_CODE,
+};
 
-use crate::wire_msg::WireMsg;
 use bootstrap_cache::BootstrapCache;
+use connection::Race;
 use context::{ctx, ctx_mut, initialise_ctx, Context};
 use crossbeam_channel as mpmc;
+use dead_letter::DeadLetters;
+use dir_lock::DirLock;
+use dirs::Dirs;
 use event_loop::EventLoop;
-use std::collections::VecDeque;
+use event_sender::EventSender;
+use forwarding::Forwarder;
+use restore::StateBundle;
+use rng::Rng;
+use std::collections::{HashSet, VecDeque};
+use std::io;
 use std::mem;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::mpsc;
-use tokio::prelude::Future;
-use tokio::runtime::current_thread;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+#[cfg(feature = "test-utils")]
+use test_utils::MemoryTransport;
+#[cfg(feature = "tcp-fallback")]
+use transport::TcpFallback;
+#[cfg(feature = "websocket")]
+use transport::WebSocketBridge;
+use transport::{QuicTransport, Transport};
+#[cfg(not(any(feature = "test-utils", feature = "msg-inspector")))]
+use wire_msg::{MsgInspector, WireMsg};
 
+mod app_ack;
+mod bandwidth;
 mod bootstrap;
 mod bootstrap_cache;
+mod cert_expiry;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod client_session;
+mod clock;
 mod communicate;
 mod config;
+mod config_args;
+#[cfg(feature = "config-watch")]
+mod config_watch;
 mod connect;
 mod connection;
 mod context;
+mod dead_letter;
+mod dedup;
+mod der_text;
+mod dir_lock;
 mod dirs;
+mod drain_order;
 mod error;
 mod event;
 mod event_loop;
+mod event_sender;
+mod expiry;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod file_transfer;
+mod forwarding;
+mod gossip;
+mod heartbeat;
+#[cfg(not(feature = "client-only"))]
 mod listener;
+mod migration;
+#[cfg(feature = "multiaddr")]
+mod multi_addr;
+mod nat;
+#[cfg(feature = "otel")]
+mod otel;
+mod outcome;
 mod peer;
 mod peer_config;
-#[cfg(test)]
-mod test_utils;
+mod persistence;
+mod probe;
+mod pubsub;
+mod quorum;
+mod reachability;
+mod reconnect;
+mod resend;
+mod restore;
+mod rng;
+mod send_options;
+mod send_queue;
+mod sockets;
+mod socks5;
+mod stall;
+mod state_dump;
+mod stats;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_vectors;
+mod transport;
 mod utils;
+mod wire_cbor;
 mod wire_msg;
+#[cfg(feature = "proto")]
+mod wire_proto;
+mod wire_tagged;
 
 /// Default maximum allowed message size. We'll error out on any bigger messages and probably
 /// shutdown the connection. This value can be overridden via the `Config` option.
@@ -113,6 +225,40 @@ pub struct Builder {
     cfg: Option<Config>,
     proxies: VecDeque<NodeInfo>,
     use_proxies_exclusively: bool,
+    msg_inspector: Option<MsgInspector>,
+    lifecycle_events: bool,
+    sent_events: bool,
+    app_acks: bool,
+    event_policy: EventPolicy,
+    dump_state_on_panic: bool,
+    heartbeat_interval: Option<Duration>,
+    stall_threshold: Option<Duration>,
+    probes: Option<(Duration, u32)>,
+    network_change_interval: Option<Duration>,
+    client_idle_timeout: Option<Duration>,
+    client_forwarding: Option<u32>,
+    capabilities: Capabilities,
+    wire_encoding: WireEncoding,
+    persistent_identity: bool,
+    restore: bool,
+    ephemeral: bool,
+    resend_policy: Option<ResendPolicy>,
+    reconnect_policy: Option<ReconnectPolicy>,
+    dead_letters: Option<(usize, Option<PathBuf>)>,
+    progress_events_above: Option<u64>,
+    gossip: Option<(usize, Duration)>,
+    drain_order: DrainOrder,
+    send_queue_limit: Option<(usize, usize)>,
+    dir_overrides: DirOverrides,
+    clock: Arc<dyn Clock>,
+    rng_seed: Option<u64>,
+    cert_expiry: Option<(Duration, Duration, Option<CertRenewal>)>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
+    #[cfg(feature = "config-watch")]
+    config_watch: Option<(PathBuf, Duration)>,
+    #[cfg(feature = "test-utils")]
+    memory_transport: Option<MemoryTransport>,
 }
 
 impl Builder {
@@ -123,6 +269,40 @@ impl Builder {
             cfg: Default::default(),
             proxies: Default::default(),
             use_proxies_exclusively: Default::default(),
+            msg_inspector: Default::default(),
+            lifecycle_events: Default::default(),
+            sent_events: Default::default(),
+            app_acks: Default::default(),
+            event_policy: Default::default(),
+            dump_state_on_panic: Default::default(),
+            heartbeat_interval: Default::default(),
+            stall_threshold: Default::default(),
+            probes: Default::default(),
+            network_change_interval: Default::default(),
+            client_idle_timeout: Default::default(),
+            client_forwarding: Default::default(),
+            capabilities: Default::default(),
+            wire_encoding: Default::default(),
+            persistent_identity: true,
+            restore: false,
+            ephemeral: false,
+            resend_policy: Default::default(),
+            reconnect_policy: Default::default(),
+            dead_letters: Default::default(),
+            progress_events_above: Default::default(),
+            gossip: Default::default(),
+            drain_order: Default::default(),
+            send_queue_limit: Default::default(),
+            dir_overrides: Default::default(),
+            clock: Arc::new(SystemClock),
+            rng_seed: None,
+            cert_expiry: None,
+            #[cfg(feature = "chaos")]
+            chaos: Default::default(),
+            #[cfg(feature = "config-watch")]
+            config_watch: Default::default(),
+            #[cfg(feature = "test-utils")]
+            memory_transport: Default::default(),
         }
     }
 
@@ -142,7 +322,8 @@ impl Builder {
         self
     }
 
-    /// Configuration for `QuicP2p`.
+    /// Configuration for `QuicP2p`, used as given. See `Config::apply_env_overrides` for applying
+    /// the `QUIC_P2P_*` environment variables to it.
     ///
     /// If not specified it'll call `Config::read_or_construct_default()`
     pub fn with_config(mut self, cfg: Config) -> Self {
@@ -150,39 +331,623 @@ impl Builder {
         self
     }
 
+    /// Debug hook invoked for every wire message we encode or decode.
+    ///
+    /// Allows protocol fuzzers, conformance tests and traffic recorders to observe the messages
+    /// exchanged with peers. Only with the `msg-inspector` feature.
+    #[cfg(any(test, feature = "msg-inspector"))]
+    pub fn with_msg_inspector(mut self, inspector: MsgInspector) -> Self {
+        self.msg_inspector = Some(inspector);
+        self
+    }
+
+    /// Fire `Event::Connecting` and `Event::HandshakeCompleted` for our outgoing connections.
+    ///
+    /// Disabled by default.
+    pub fn with_lifecycle_events(mut self, enable: bool) -> Self {
+        self.lifecycle_events = enable;
+        self
+    }
+
+    /// Fire `Event::SentUserMessage` for the user messages written to their peer in full, e.g.
+    /// to tell those sent with `QuicP2p::send_with_token` apart from those still in flight.
+    ///
+    /// Disabled by default.
+    pub fn with_sent_events(mut self, enable: bool) -> Self {
+        self.sent_events = enable;
+        self
+    }
+
+    /// Have peers acknowledge the user messages we send them once they handed them to their
+    /// application, taking the messages as delivered, e.g. for `Event::SentUserMessage`, only
+    /// then rather than once written to the peer. A message whose peer fails before
+    /// acknowledging it is reported via `Event::UnsentUserMessage`, or resent if its policy asks.
+    ///
+    /// Applies to the messages sent with `QuicP2p::send` and its variants, other than
+    /// `QuicP2p::send_with_id`, and with `QuicP2p::send_to_quorum`. Messages to browser clients
+    /// are taken as delivered once written. Peers of older versions ignore these messages, and
+    /// peers drop those which don't fit into their event channel under `EventPolicy::Drop` or
+    /// `EventPolicy::DropMessages` without acknowledging them: send with
+    /// `QuicP2p::send_with_ttl` to learn about those.
+    ///
+    /// Disabled by default.
+    pub fn with_app_acks(mut self, enable: bool) -> Self {
+        self.app_acks = enable;
+        self
+    }
+
+    /// Fire `Event::SendProgress` and `Event::ReceiveProgress` for the files sent and received
+    /// which are longer than `min_len` bytes, e.g. for progress bars of chunk uploads. The events
+    /// follow each other at least 250ms apart, the last coming once the file is done.
+    ///
+    /// Disabled by default.
+    pub fn with_progress_events(mut self, min_len: u64) -> Self {
+        self.progress_events_above = Some(min_len);
+        self
+    }
+
+    /// What to do with events if the application doesn't drain a bounded event channel fast
+    /// enough.
+    ///
+    /// If not specified it'll default to `EventPolicy::Block`.
+    pub fn with_event_policy(mut self, policy: EventPolicy) -> Self {
+        self.event_policy = policy;
+        self
+    }
+
+    /// Write a `StateDump` into the project logs directory if the event loop panics, see
+    /// `with_dir_overrides`.
+    ///
+    /// Disabled by default.
+    pub fn with_state_dump_on_panic(mut self, enable: bool) -> Self {
+        self.dump_state_on_panic = enable;
+        self
+    }
+
+    /// Fire `Event::PeerHeartbeat` every `interval` for each connection no messages were exchanged
+    /// over since the previous heartbeat.
+    ///
+    /// Disabled by default.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Fire `Event::PeerSlow` for each peer writing to has been held up by flow control for
+    /// longer than `threshold`, and `Event::PeerResumed` once writing to it resumes, e.g. to route
+    /// around peers which are persistently slow.
+    ///
+    /// Disabled by default.
+    pub fn with_stall_detection(mut self, threshold: Duration) -> Self {
+        self.stall_threshold = Some(threshold);
+        self
+    }
+
+    /// Probe the event loop of each connected peer every `interval`, firing `Event::PeerRtt` as it
+    /// answers. A probe not answered before the next one is sent counts as missed, and a peer
+    /// missing `max_misses` probes in a row, e.g. with its event loop stuck while QUIC keep-alives
+    /// still hold its connection open, is disconnected and reported via
+    /// `Event::ConnectionFailure` with `Error::ProbesUnanswered`.
+    ///
+    /// Only peers of this version answer probes, so enable them only for networks of such peers.
+    ///
+    /// Disabled by default.
+    pub fn with_probes(mut self, interval: Duration, max_misses: u32) -> Self {
+        self.probes = Some((interval, max_misses));
+        self
+    }
+
+    /// Gossip messages to `fanout` random peers, see `QuicP2p::gossip`, and reconcile the
+    /// messages gossiped with a random peer every `anti_entropy_interval`, for those the gossip
+    /// missed to catch up.
+    ///
+    /// Without it messages are gossiped to 3 peers and never reconciled.
+    pub fn with_gossip(mut self, fanout: usize, anti_entropy_interval: Duration) -> Self {
+        self.gossip = Some((fanout, anti_entropy_interval));
+        self
+    }
+
+    /// Check every `interval` whether the network we reach the nodes through changed, e.g. from
+    /// Wi-Fi to cellular, and migrate our connections once it did, see `QuicP2p::migrate`. Only
+    /// clients migrate.
+    ///
+    /// Disabled by default.
+    pub fn with_network_change_detection(mut self, interval: Duration) -> Self {
+        self.network_change_interval = Some(interval);
+        self
+    }
+
+    /// Disconnect clients no messages were exchanged with for `timeout`, firing
+    /// `Event::ConnectionFailure` with `Error::IdleClientEvicted` for them. Clients are checked
+    /// every quarter of the timeout, or every second for timeouts beyond 4 seconds, so they may
+    /// linger for that much longer.
+    ///
+    /// Disabled by default.
+    pub fn with_client_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.client_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Relay messages between the clients connected to us, at most `max_msgs_per_sec` a second
+    /// from one client to another, see `QuicP2p::forward`. Clients are told who sent the messages
+    /// they receive, so this is no anonymity service.
+    ///
+    /// Disabled by default.
+    pub fn with_client_forwarding(mut self, max_msgs_per_sec: u32) -> Self {
+        self.client_forwarding = Some(max_msgs_per_sec);
+        self
+    }
+
+    /// Capabilities to advertise in our handshakes, reported to the peers we connect to in their
+    /// `Event::ConnectedTo`. Only the tagged, CBOR and protobuf encodings carry them, and peers of
+    /// older versions talking CBOR reject handshakes with capabilities.
+    ///
+    /// If not specified we advertise none.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Encoding of the wire messages we prefer. Whichever encoding the accepting side of a
+    /// connection prefers is used for that connection, see `WireEncoding`.
+    ///
+    /// If not specified it'll default to `WireEncoding::Tagged`. Earlier versions defaulted to
+    /// `WireEncoding::Bincode`, which is still used with peers not negotiating an encoding.
+    pub fn with_wire_encoding(mut self, encoding: WireEncoding) -> Self {
+        self.wire_encoding = encoding;
+        self
+    }
+
+    /// If the config has no `our_complete_cert`, use the certificate and private key stored in the
+    /// project keys directory, generating and storing them on first use. A restarted node is
+    /// then known by the same `NodeInfo`, so peers recognise it and their cached entries for it
+    /// stay valid. Otherwise a new certificate is generated every time.
+    ///
+    /// Enabled by default. Instances running side by side off the same directories share the
+    /// identity, so they need to disable it or be given an `our_complete_cert` each.
+    pub fn with_persistent_identity(mut self, enable: bool) -> Self {
+        self.persistent_identity = enable;
+        self
+    }
+
+    /// Check every `interval` whether a certificate expires within `warn_ahead`: ours, those of
+    /// the hard-coded contacts and those of the nodes we are connected to, firing
+    /// `Event::CertificateExpiring` once for each. Ours is then renewed by `renewal`, or by
+    /// generating another self-signed certificate if none given, and handed out via
+    /// `Event::CertificateRenewed`.
+    ///
+    /// The endpoint keeps serving the old certificate, so the renewed one only takes over once we
+    /// restart with it. It replaces the identity of `with_persistent_identity` or `restore` on
+    /// disk, so a restart picks it up by itself. An `our_complete_cert` of the config has to be
+    /// replaced by hand.
+    ///
+    /// Disabled by default.
+    pub fn with_cert_expiry_monitoring(
+        mut self,
+        interval: Duration,
+        warn_ahead: Duration,
+        renewal: Option<CertRenewal>,
+    ) -> Self {
+        self.cert_expiry = Some((interval, warn_ahead, renewal));
+        self
+    }
+
+    /// Resume with the identity and bootstrap cache of the previous run, loaded together from a
+    /// single versioned `state` file of the project keys directory rather than from separate
+    /// files which could have been written by different runs. The bootstrap cache is then synced
+    /// to that file along with the identity. On first use it is put together from the separate
+    /// identity and bootstrap cache files, generating an identity if there is none.
+    ///
+    /// The restored identity takes the place of `with_persistent_identity`. A config holding
+    /// another `our_complete_cert` is rejected with `Error::ConflictingSettings`, and a file saved
+    /// by a newer version of the crate with `Error::CorruptFile`.
+    ///
+    /// Disabled by default.
+    pub fn restore(mut self) -> Self {
+        self.restore = true;
+        self
+    }
+
+    /// Keep the config, our identity and the bootstrap cache in memory only, never reading or
+    /// writing a file nor looking up the project directories. Meant for short-lived tools, tests
+    /// and sandboxes without a writable file system.
+    ///
+    /// Without `with_config`, the default config is taken along with the `QUIC_P2P_*`
+    /// environment variables. Each instance has an identity of its own, taking the place of
+    /// `with_persistent_identity`, and the peers it learns of are forgotten as it is dropped.
+    /// `restore`, `with_dir_overrides` and `with_state_dump_on_panic` are rejected with
+    /// `Error::ConflictingSettings`. Paths given explicitly, e.g. the spill file of
+    /// `with_dead_letters`, are still written to.
+    ///
+    /// Disabled by default.
+    pub fn ephemeral(mut self) -> Self {
+        self.ephemeral = true;
+        self
+    }
+
+    /// Send the user messages which couldn't be sent again as the policy asks, before reporting
+    /// them via `Event::UnsentUserMessage`. `QuicP2p::send_with_resend` chooses another policy
+    /// for a single message.
+    ///
+    /// Disabled by default.
+    pub fn with_resend_policy(mut self, policy: ResendPolicy) -> Self {
+        self.resend_policy = Some(policy);
+        self
+    }
+
+    /// Dial sticky peers again as the policy asks once their connection dropped, see
+    /// `QuicP2p::make_sticky`.
+    ///
+    /// `ReconnectPolicy::default()` by default.
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Collect the user messages which couldn't be sent, resends included, for
+    /// `QuicP2p::drain_dead_letters` instead of firing `Event::UnsentUserMessage` for them. Up to
+    /// `capacity` of them are kept in memory, beyond which they are spilled to the file if given,
+    /// else the oldest is dropped.
+    ///
+    /// Disabled by default.
+    pub fn with_dead_letters(mut self, capacity: usize, spill_path: Option<PathBuf>) -> Self {
+        self.dead_letters = Some((capacity, spill_path));
+        self
+    }
+
+    /// Send the messages queued while connecting to a peer in the given order once connected, e.g.
+    /// newest first so that the peer gets the latest state rather than a long backlog.
+    ///
+    /// `DrainOrder::Fifo` by default.
+    pub fn with_drain_order(mut self, order: DrainOrder) -> Self {
+        self.drain_order = order;
+        self
+    }
+
+    /// Queue no more than `max_msgs` user messages, of no more than `max_bytes` together, per peer
+    /// while connecting to it. Messages beyond are reported right away via
+    /// `Event::UnsentUserMessage` with `UnsentReason::QueueFull`, rather than held on to for as
+    /// long as the connection attempt takes. See `Event::SendsQueued` for telling when messages
+    /// are queued.
+    ///
+    /// Unlimited by default.
+    pub fn with_send_queue_limit(mut self, max_msgs: usize, max_bytes: usize) -> Self {
+        self.send_queue_limit = Some((max_msgs, max_bytes));
+        self
+    }
+
+    /// Keep the config file, the bootstrap cache, our persistent identity and state dumps in the
+    /// directories given, each in place of the respective project directory, see
+    /// `DirOverrides`.
+    ///
+    /// The directories overridden are locked for as long as the instance lives, so that another
+    /// instance given any of them fails to build with `Error::AlreadyRunning` rather than
+    /// overwriting our files. The default project directories are shared and never locked.
+    ///
+    /// All of them are under the project directories of the platform by default.
+    pub fn with_dir_overrides(mut self, overrides: DirOverrides) -> Self {
+        self.dir_overrides = overrides;
+        self
+    }
+
+    /// Tell the time client idle timeouts, the ages of bootstrap cache entries, the rate limits of
+    /// `with_client_forwarding` and the TTLs of `QuicP2p::send_with_ttl` are measured against by
+    /// `clock`, e.g. a `test_utils::ManualClock` to test them without waiting. Timers still tick
+    /// in real time.
+    ///
+    /// The clock of the system by default.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Take the random decisions, i.e. the order hard-coded contacts are bootstrapped off, the IDs
+    /// and peers of the gossip and the IDs of the messages published, with a generator seeded with
+    /// `seed`, so that a run can be reproduced with the same version of the crate. Together with
+    /// `with_memory_transport` and `MemoryTransport::with_seed` failing network simulations can
+    /// be replayed exactly, save for the random parts of QUIC, e.g. its connection IDs.
+    ///
+    /// Seeded at random by default.
+    pub fn with_rng_seed(mut self, seed: u64) -> Self {
+        self.rng_seed = Some(seed);
+        self
+    }
+
+    /// Inject the faults of `chaos` at random: failed sends, delayed handshakes, dropped events and
+    /// killed connections, to soak test how the application recovers from them. The faults are
+    /// decided by a generator seeded from `with_rng_seed`, so that a run can be replayed. They can
+    /// be changed at runtime with `QuicP2p::set_chaos`.
+    ///
+    /// No faults are injected by default.
+    #[cfg(feature = "chaos")]
+    pub fn with_chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// Look for changes of the config file at `path` every `interval`, firing
+    /// `Event::ConfigFileChanged` once it was written to. The file isn't applied by itself, read
+    /// it with `Config::from_file` and pass it to `QuicP2p::apply_config` for that.
+    ///
+    /// Disabled by default.
+    #[cfg(feature = "config-watch")]
+    pub fn with_config_watch(mut self, path: PathBuf, interval: Duration) -> Self {
+        self.config_watch = Some((path, interval));
+        self
+    }
+
+    /// Connect to peers through the given in-process network instead of UDP sockets. Only
+    /// instances sharing the network can reach each other, see `MemoryTransport`.
+    #[cfg(feature = "test-utils")]
+    pub fn with_memory_transport(mut self, transport: MemoryTransport) -> Self {
+        self.memory_transport = Some(transport);
+        self
+    }
+
     /// Construct `QuicP2p` with supplied parameters earlier, ready to be used.
-    pub fn build(self) -> R<QuicP2p> {
-        let mut qp2p = if let Some(cfg) = self.cfg {
-            QuicP2p::with_config(self.event_tx, cfg)
+    ///
+    /// Settings which can't work, alone or together, are rejected with
+    /// `Error::ConflictingSettings` or `Error::InvalidSetting` before anything is started, see
+    /// `Config::validate`.
+    pub fn build(mut self) -> R<QuicP2p> {
+        self.validate_ephemeral()?;
+        let (dirs, dir_lock) = if self.ephemeral {
+            (None, None)
+        } else {
+            (
+                self.dir_overrides.dirs()?,
+                Some(DirLock::acquire(&self.dir_overrides)?),
+            )
+        };
+        let mut qp2p = if let Some(cfg) = self.cfg.take() {
+            QuicP2p::with_config(self.event_tx.clone(), cfg)
+        } else if self.ephemeral {
+            let mut cfg = Config::with_default_cert();
+            cfg.apply_env_overrides()?;
+            QuicP2p::with_config(self.event_tx.clone(), cfg)
         } else {
-            QuicP2p::new(self.event_tx)?
+            QuicP2p::new(self.event_tx.clone(), dirs.as_ref())?
         };
+        qp2p.cfg.validate()?;
+        self.validate(qp2p.cfg.our_type)?;
 
+        // Where a renewed certificate is written to, see `with_cert_expiry_monitoring`
+        let mut identity_path = None;
+        if self.restore {
+            let bundle = restore::load(dirs.as_ref())?;
+            match qp2p.cfg.our_complete_cert {
+                Some(ref cert) if *cert != bundle.identity => {
+                    return Err(Error::ConflictingSettings {
+                        setting: "restore",
+                        conflicts_with: "our_complete_cert",
+                        reason: "the identity restored would be replaced".to_string(),
+                    });
+                }
+                _ => qp2p.cfg.our_complete_cert = Some(bundle.identity.clone()),
+            }
+            qp2p.restored = Some(bundle);
+        } else if self.persistent_identity
+            && !self.ephemeral
+            && qp2p.cfg.our_complete_cert.is_none()
+        {
+            qp2p.cfg.our_complete_cert = Some(config::read_or_create_identity(dirs.as_ref())?);
+            identity_path = Some(config::identity_path(dirs.as_ref())?);
+        }
+        qp2p.dirs = dirs;
+        qp2p.dir_lock = dir_lock;
+        qp2p.ephemeral = self.ephemeral;
+        qp2p.event_policy = self.event_policy;
+        qp2p.wire_encoding = self.wire_encoding;
+        qp2p.clock = self.clock.clone();
+        qp2p.rng_seed = self.rng_seed;
+        #[cfg(feature = "test-utils")]
+        {
+            qp2p.memory_transport = self.memory_transport;
+        }
         qp2p.activate()?;
 
         let use_proxies_exclusively = self.use_proxies_exclusively;
         let proxies = self.proxies;
+        let msg_inspector = self.msg_inspector;
+        let lifecycle_events = self.lifecycle_events;
+        let sent_events = self.sent_events;
+        let app_acks = self.app_acks;
+        let dump_state_on_panic = self.dump_state_on_panic;
+        let heartbeat_interval = self.heartbeat_interval;
+        let stall_threshold = self.stall_threshold;
+        let probes = self.probes;
+        let liveness = qp2p.cfg.liveness();
+        let network_change_interval = self.network_change_interval;
+        let client_idle_timeout = self.client_idle_timeout;
+        let client_forwarding = self.client_forwarding;
+        let capabilities = self.capabilities;
+        let resend_policy = self.resend_policy;
+        let reconnect_policy = self.reconnect_policy;
+        let dead_letters = self.dead_letters;
+        let progress_events_above = self.progress_events_above;
+        let anti_entropy = self.gossip;
+        let drain_order = self.drain_order;
+        let send_queue_limit = self.send_queue_limit;
+        let cert_expiry = self.cert_expiry;
+        #[cfg(feature = "chaos")]
+        let chaos = self.chaos;
+        #[cfg(feature = "config-watch")]
+        let config_watch = self.config_watch;
 
         qp2p.el.post(move || {
+            wire_msg::set_msg_inspector(msg_inspector);
+            if dump_state_on_panic {
+                state_dump::install_panic_hook();
+            }
+            if let Some(interval) = heartbeat_interval {
+                heartbeat::start(interval);
+            }
+            if let Some(threshold) = stall_threshold {
+                stall::start(threshold);
+            }
+            probe::start(probe::Schedule {
+                every: probes,
+                quiet: liveness,
+            });
+            if let Some(interval) = network_change_interval {
+                migration::start_detection(interval);
+            }
+            if let Some(timeout) = client_idle_timeout {
+                client_session::start_eviction(timeout);
+            }
+            if let Some((fanout, interval)) = anti_entropy {
+                gossip::start(fanout, interval);
+            }
+            if let Some((interval, warn_ahead, renewal)) = cert_expiry {
+                cert_expiry::start(interval, warn_ahead, renewal, identity_path);
+            }
+            #[cfg(feature = "config-watch")]
+            {
+                if let Some((path, interval)) = config_watch {
+                    config_watch::start(path, interval);
+                }
+            }
             ctx_mut(|c| {
+                c.lifecycle_events = lifecycle_events;
+                c.sent_events = sent_events;
+                c.acks.enable(app_acks);
+                c.dump_state_on_panic = dump_state_on_panic;
+                c.forwarder = client_forwarding.map(Forwarder::new);
+                c.our_capabilities = capabilities;
+                c.resend_policy = resend_policy;
+                if let Some(policy) = reconnect_policy {
+                    c.reconnects.set_policy(policy);
+                }
+                c.progress_events_above = progress_events_above;
+                c.drain_order = drain_order;
+                c.send_queue_limit = send_queue_limit;
+                c.dead_letters = dead_letters
+                    .map(|(capacity, spill_path)| DeadLetters::new(capacity, spill_path));
                 if use_proxies_exclusively {
                     let _ = mem::replace(c.bootstrap_cache.peers_mut(), proxies);
                 } else {
                     c.bootstrap_cache.peers_mut().extend(proxies.into_iter());
                 }
-            })
+            });
+            // Seeded from the generator of the context, so once it is set up
+            #[cfg(feature = "chaos")]
+            {
+                if let Some(chaos) = chaos {
+                    chaos::set(chaos);
+                }
+            }
         });
 
         Ok(qp2p)
     }
+
+    fn validate(&self, our_type: OurType) -> R<()> {
+        if our_type == OurType::Client {
+            if self.client_forwarding.is_some() {
+                return Err(Error::ConflictingSettings {
+                    setting: "with_client_forwarding",
+                    conflicts_with: "our_type",
+                    reason: "only nodes have clients to relay messages between".to_string(),
+                });
+            }
+            if self.client_idle_timeout.is_some() {
+                return Err(Error::ConflictingSettings {
+                    setting: "with_client_idle_timeout",
+                    conflicts_with: "our_type",
+                    reason: "only nodes have clients to disconnect".to_string(),
+                });
+            }
+        }
+
+        let intervals = [
+            ("with_heartbeat_interval", self.heartbeat_interval),
+            ("with_stall_detection", self.stall_threshold),
+            ("with_probes", self.probes.map(|(interval, _)| interval)),
+            (
+                "with_network_change_detection",
+                self.network_change_interval,
+            ),
+            ("with_client_idle_timeout", self.client_idle_timeout),
+            ("with_gossip", self.gossip.map(|(_, interval)| interval)),
+            (
+                "with_cert_expiry_monitoring",
+                self.cert_expiry.map(|(interval, ..)| interval),
+            ),
+            ("with_config_watch", self.config_watch_interval()),
+        ];
+        for &(setting, interval) in &intervals {
+            if interval == Some(Duration::from_secs(0)) {
+                return Err(Error::InvalidSetting {
+                    setting,
+                    reason: "the duration must be non-zero".to_string(),
+                });
+            }
+        }
+        #[cfg(feature = "chaos")]
+        {
+            if let Some(ref chaos) = self.chaos {
+                chaos.validate("with_chaos")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Settings which would have us read or write the project directories, see `ephemeral`.
+    fn validate_ephemeral(&self) -> R<()> {
+        if !self.ephemeral {
+            return Ok(());
+        }
+        let conflicts = [
+            ("restore", self.restore),
+            (
+                "with_dir_overrides",
+                self.dir_overrides != DirOverrides::default(),
+            ),
+            ("with_state_dump_on_panic", self.dump_state_on_panic),
+        ];
+        for &(conflicts_with, is_set) in &conflicts {
+            if is_set {
+                return Err(Error::ConflictingSettings {
+                    setting: "ephemeral",
+                    conflicts_with,
+                    reason: "ephemeral instances keep nothing on disk".to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn config_watch_interval(&self) -> Option<Duration> {
+        #[cfg(feature = "config-watch")]
+        {
+            self.config_watch.as_ref().map(|&(_, interval)| interval)
+        }
+        #[cfg(not(feature = "config-watch"))]
+        {
+            None
+        }
+    }
 }
 
 /// Main QuicP2p instance to communicate with QuicP2p
 pub struct QuicP2p {
     event_tx: mpmc::Sender<Event>,
+    event_policy: EventPolicy,
+    wire_encoding: WireEncoding,
+    #[cfg(feature = "test-utils")]
+    memory_transport: Option<MemoryTransport>,
     cfg: Config,
+    dirs: Option<Dirs>,
+    clock: Arc<dyn Clock>,
+    rng_seed: Option<u64>,
+    restored: Option<StateBundle>,
+    /// Whether the bootstrap cache is kept in memory, see `Builder::ephemeral`.
+    ephemeral: bool,
     us: Option<NodeInfo>,
+    next_quorum_send_id: u64,
     el: EventLoop,
+    // Dropped after the event loop, which may still write to the directories as it stops
+    dir_lock: Option<DirLock>,
 }
 
 impl QuicP2p {
@@ -192,24 +957,54 @@ impl QuicP2p {
     /// connect to all peers which are specified in the config (`hard_coded_contacts`) or were
     /// previously cached. If one bootstrap connection succeeds, all other connections will be dropped.
     ///
-    /// In case of success `Event::BootstrapedTo` will be fired. On error quic-p2p will fire `Event::BootstrapFailure`.
+    /// In case of success `Event::BootstrapedTo` will be fired. On error quic-p2p will fire
+    /// `Event::BootstrapFailure` telling why each contact failed.
     pub fn bootstrap(&mut self) {
-        self.el.post(|| {
-            bootstrap::start();
+        self.post(|| {
+            bootstrap::start(None);
         })
     }
 
-    /// Connect to the given peer. This will error out if the peer is already in the process of
-    /// being connected to OR for any other connection failure reasons.
-    pub fn connect_to(&mut self, peer_info: NodeInfo) {
-        self.el.post(move || {
+    /// Bootstrap to the network and report on every contact attempted.
+    ///
+    /// This behaves exactly like `bootstrap()`, including the events fired, but blocks until all
+    /// the bootstrap attempts have ended. Attempts to unresponsive contacts only end once the
+    /// connection times out.
+    pub fn bootstrap_with_report(&mut self) -> R<BootstrapReport> {
+        let (tx, rx) = mpsc::channel();
+        self.post(move || {
+            bootstrap::start(Some(tx));
+        });
+        let report = rx.recv()?;
+
+        Ok(report)
+    }
+
+    /// Connect to the given peer. This will error out if we are connected to the peer already OR
+    /// for any other connection failure reasons. Should we be in the process of connecting to it,
+    /// e.g. to send it a message, this joins the attempt in flight rather than starting another.
+    ///
+    /// If the peer has alternate addresses, those on one of our subnets are tried before its
+    /// main address and the rest after it, racing like the attempts of `connect_to_any`.
+    pub fn connect_to(&mut self, mut peer_info: NodeInfo) {
+        peer_info.peer_addr = utils::normalise_addr(peer_info.peer_addr);
+        if !peer_info.alt_addrs.is_empty() {
+            for alt_addr in &mut peer_info.alt_addrs {
+                *alt_addr = utils::normalise_addr(*alt_addr);
+            }
+            self.post(move || Race::start_preferring_lan(peer_info));
+            return;
+        }
+        self.post(move || {
             let peer_addr = peer_info.peer_addr;
             if let Err(e) = connect::connect_to(peer_info, None, None) {
                 info!("Could not connect to the asked peer: {}", e);
+                let forget = outcome::is_ended_by_us(&e);
                 ctx_mut(|c| {
                     let _ = c
                         .event_tx
                         .send(Event::ConnectionFailure { peer_addr, err: e });
+                    outcome::connection_ended(c, peer_addr, forget);
                 });
             } else {
                 Self::set_we_contacted_peer(&peer_addr);
@@ -217,10 +1012,83 @@ impl QuicP2p {
         });
     }
 
-    /// Disconnect from the given peer
+    /// Connect to the given peer like `connect_to`, choosing whether our user messages to it keep
+    /// their order regardless of `Config::ordered_delivery`.
+    ///
+    /// Ordered messages all go on a single stream, so one held up by packet loss holds up those
+    /// after it too. The choice applies to the connection at the peer's main address.
+    pub fn connect_to_with_delivery(&mut self, peer_info: NodeInfo, ordered: bool) {
+        let peer_addr = utils::normalise_addr(peer_info.peer_addr);
+        self.connect_to(peer_info);
+        self.post(move || {
+            ctx_mut(|c| {
+                if let Some(conn) = c.connections.get_mut(&peer_addr) {
+                    conn.ordered_delivery = Some(ordered);
+                }
+            })
+        });
+    }
+
+    /// Connect to a node reachable at any of the given addresses, e.g. `"example.com:5000"` or
+    /// `&[v6_addr, v4_addr][..]`.
+    ///
+    /// Names are resolved on the calling thread. The attempts race Happy Eyeballs style,
+    /// alternating between address families with each started 250ms after the previous one
+    /// unless that one failed sooner. The first to succeed is kept and fires
+    /// `Event::ConnectedTo`, the others are cancelled. If all fail, `Event::ConnectionFailure` is
+    /// fired for the first address.
+    pub fn connect_to_any<A: ToSocketAddrs>(&mut self, addrs: A, peer_cert_der: Vec<u8>) -> R<()> {
+        let peer_addrs: Vec<_> = addrs
+            .to_socket_addrs()?
+            .map(utils::normalise_addr)
+            .collect();
+        if peer_addrs.is_empty() {
+            return Err(Error::Io(io::ErrorKind::AddrNotAvailable.into()));
+        }
+
+        self.post(move || Race::start(peer_addrs, peer_cert_der));
+        Ok(())
+    }
+
+    /// Abort connecting to the given peer, e.g. once it's known to be gone. The messages waiting
+    /// for the connection are reported via `Event::UnsentUserMessage` with
+    /// `UnsentReason::Aborted`. Established connections are left alone, see `disconnect_from` for
+    /// those. A sticky peer is no longer reconnected to.
+    pub fn abort_connect(&mut self, peer_addr: SocketAddr) {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        self.post(move || {
+            let _ = ctx_mut(|c| c.reconnects.unstick(&peer_addr));
+            connect::abort(peer_addr)
+        });
+    }
+
+    /// Reconnect to the given node whenever its connection drops, dialing it again with the
+    /// backoffs of `Builder::with_reconnect_policy` until connected. Each attempt is announced
+    /// via `Event::Reconnecting` and connecting again via `Event::Reconnected`.
+    ///
+    /// Connecting to the node in the first place is left to the caller, e.g. with `connect_to`.
+    /// `unstick`, `disconnect_from` and `abort_connect` stop reconnecting to it.
+    pub fn make_sticky(&mut self, node_info: NodeInfo) {
+        let node_info = node_info.normalised();
+        self.post(move || ctx_mut(|c| c.reconnects.stick(node_info)));
+    }
+
+    /// Stop reconnecting to the given peer, see `make_sticky`.
+    pub fn unstick(&mut self, peer_addr: SocketAddr) {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        self.post(move || {
+            if !ctx_mut(|c| c.reconnects.unstick(&peer_addr)) {
+                debug!("Asked to unstick a peer which isn't sticky");
+            }
+        });
+    }
+
+    /// Disconnect from the given peer. A sticky peer is no longer reconnected to.
     pub fn disconnect_from(&mut self, peer_addr: SocketAddr) {
-        self.el.post(move || {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        self.post(move || {
             ctx_mut(|c| {
+                let _ = c.reconnects.unstick(&peer_addr);
                 if c.connections.remove(&peer_addr).is_none() {
                     debug!("Asked to disconnect from an unknown peer");
                 }
@@ -234,56 +1102,449 @@ impl QuicP2p {
     /// and then send the message. This can be called multiple times while the peer is still being
     /// connected to - all the sends will be buffered until the peer is connected to.
     pub fn send(&mut self, peer: Peer, msg: bytes::Bytes) {
-        self.el.post(move || {
-            let peer_addr = peer.peer_addr();
-            communicate::try_write_to_peer(peer, WireMsg::UserMsg(msg));
-            Self::set_we_contacted_peer(&peer_addr);
-        });
+        self.send_with_token(peer, msg, 0);
     }
 
-    /// Get our connection info to give to others for them to connect to us
-    ///
-    /// Will use hard coded contacts to ask for our endpoint. If no contact is given then we'll
-    /// simply build our connection info by querying the underlying bound socket for our address.
-    /// Note that if such an obtained address is of unspecified category we will ignore that as
-    /// such an address cannot be reached and hence not useful.
-    // FIXME calling this mutliple times concurrently just now could have it hanging as only one tx
-    // is registered and that replaces any previous tx registered. Fix by using a vec of txs
-    pub fn our_connection_info(&mut self) -> R<NodeInfo> {
-        if let Some(ref us) = self.us {
-            return Ok(us.clone());
-        }
-
-        let our_addr = match self.query_ip_echo_service() {
-            Ok(addr) => addr,
-            Err(e @ Error::NoEndpointEchoServerFound) => {
-                let (tx, rx) = mpsc::channel();
-                self.el.post(move || {
-                    let local_addr_res = ctx(|c| c.quic_ep().local_addr());
-                    unwrap!(tx.send(local_addr_res));
+    /// Send message to peer like `send`, as the options ask, which can be combined freely but for
+    /// an ID on a channel other than the default one. That is rejected with
+    /// `Error::ConflictingSettings`, with nothing sent.
+    pub fn send_with(&mut self, peer: Peer, msg: bytes::Bytes, opts: SendOptions) -> R<()> {
+        let peer = peer.normalised();
+        let wire_msg = opts.wire_msg(msg)?;
+        self.post(move || {
+            if let Some(ttl) = opts.ttl() {
+                let peer_addr = peer.peer_addr();
+                let msg = unwrap!(wire_msg.user_msg()).clone();
+                let deadline = ctx_mut(|c| {
+                    let deadline = c.clock.now() + ttl;
+                    c.expiries.start(peer_addr, msg.clone(), deadline);
+                    deadline
                 });
-                let addr = unwrap!(rx.recv())?;
-                if addr.ip().is_unspecified() {
-                    return Err(e);
-                } else {
-                    addr
-                }
+                expiry::schedule(peer_addr, msg, deadline);
             }
-            Err(e) => return Err(e),
-        };
+            Self::send_user_msg(
+                peer,
+                wire_msg,
+                opts.delivery(),
+                opts.resend_policy(),
+                opts.token(),
+            )
+        });
+        Ok(())
+    }
 
-        let our_cert_der = self.our_certificate_der();
+    /// Send message to peer like `send`, with a token the `Event::SentUserMessage` or
+    /// `Event::UnsentUserMessage` of the message carries, e.g. to map it back to the
+    /// application's own bookkeeping. Tokens needn't be unique.
+    pub fn send_with_token(&mut self, peer: Peer, msg: bytes::Bytes, token: Token) {
+        self.send_with_opts(peer, msg, SendOptions::default().with_token(token));
+    }
 
-        let us = NodeInfo {
-            peer_addr: our_addr,
-            peer_cert_der: our_cert_der,
-        };
+    /// Send message to peer like `send`, resending it as the given policy asks rather than the
+    /// one of `Builder::with_resend_policy` should it fail.
+    pub fn send_with_resend(&mut self, peer: Peer, msg: bytes::Bytes, policy: ResendPolicy) {
+        self.send_with_opts(peer, msg, SendOptions::default().with_resend_policy(policy));
+    }
 
-        self.us = Some(us.clone());
+    /// Send message to peer like `send`, in the given mode rather than the one the connection
+    /// defaults to, see `connect_to_with_delivery`. This lets control messages overtake bulk data
+    /// sent to the same peer, or keep their order among themselves.
+    pub fn send_with_delivery(&mut self, peer: Peer, msg: bytes::Bytes, mode: DeliveryMode) {
+        self.send_with_opts(peer, msg, SendOptions::default().with_delivery(mode));
+    }
+
+    /// Send message to peer like `send_with_token`, giving up on it once `ttl` passes, e.g. for
+    /// consensus messages which are of no use to the peer past a round. Should it still be queued
+    /// while we connect to the peer, be waiting to be resent or be being written by then, it is
+    /// dropped and `Event::UnsentUserMessage` is fired with `UnsentReason::Expired`.
+    ///
+    /// Messages delivered in order, see `DeliveryMode::Ordered`, share a stream with the others,
+    /// so they may still reach the peer once their write has started.
+    pub fn send_with_ttl(&mut self, peer: Peer, msg: bytes::Bytes, token: Token, ttl: Duration) {
+        let opts = SendOptions::default().with_token(token).with_ttl(ttl);
+        self.send_with_opts(peer, msg, opts);
+    }
+
+    /// Send message to peer like `send`, with an ID which is to be unique among the messages we
+    /// send to it. The peer fires `Event::NewMessage` only for the first message with a given ID
+    /// among the last 1024 IDs it got from us, so that a message can be sent again when unsure it
+    /// arrived.
+    ///
+    /// IDs are only remembered as long as the connection to the peer lasts. Peers of older
+    /// versions ignore these messages with the tagged encoding.
+    pub fn send_with_id(&mut self, peer: Peer, msg: bytes::Bytes, id: u64) {
+        self.send_with_opts(peer, msg, SendOptions::default().with_id(id));
+    }
+
+    /// Send message to peer like `send_with_token`, on the given channel of the connection, for
+    /// applications running independent protocols over one connection. The peer gets
+    /// `Event::NewMessage` naming the channel. Messages delivered in order, see
+    /// `DeliveryMode::Ordered`, are kept in order with those on the same channel only, so a
+    /// message held up on one channel doesn't hold up the others.
+    ///
+    /// Sending on `DEFAULT_CHANNEL` is the same as `send_with_token`. Peers of older versions
+    /// ignore messages on other channels with the tagged encoding, browser clients can't be sent
+    /// them and `Builder::with_app_acks` doesn't cover them.
+    pub fn send_on_channel(
+        &mut self,
+        peer: Peer,
+        channel: Channel,
+        msg: bytes::Bytes,
+        token: Token,
+    ) {
+        let opts = SendOptions::default()
+            .with_token(token)
+            .with_channel(channel);
+        self.send_with_opts(peer, msg, opts);
+    }
+
+    /// Send the message to each of the peers like `send` does, firing
+    /// `Event::QuorumSendResolved` with the returned id once it has been delivered to `required`
+    /// of them, or once it failed for so many that it can't be anymore.
+    ///
+    /// The event lists the peers delivering to failed so far, which are also reported via
+    /// `Event::UnsentUserMessage` or the failure of their connection as usual. Fails with
+    /// `Error::InvalidQuorum` if `required` is zero or more than the number of distinct peers.
+    pub fn send_to_quorum(
+        &mut self,
+        peers: Vec<Peer>,
+        msg: bytes::Bytes,
+        required: usize,
+    ) -> R<QuorumSendId> {
+        let mut peer_addrs = HashSet::new();
+        let peers: Vec<_> = peers
+            .into_iter()
+            .map(Peer::normalised)
+            .filter(|peer| peer_addrs.insert(peer.peer_addr()))
+            .collect();
+        if required == 0 || required > peers.len() {
+            return Err(Error::InvalidQuorum {
+                required,
+                peers: peers.len(),
+            });
+        }
+
+        let id = QuorumSendId(self.next_quorum_send_id);
+        self.next_quorum_send_id += 1;
+        self.post(move || {
+            ctx_mut(|c| c.quorums.start(id, peer_addrs, msg.clone(), required));
+            for peer in peers {
+                let peer_addr = peer.peer_addr();
+                let wire_msg = WireMsg::UserMsg(msg.clone());
+                ctx_mut(|c| c.outcomes.start(&peer, &wire_msg, 0));
+                communicate::try_write_to_peer(peer, app_ack::ask(peer_addr, wire_msg));
+                Self::set_we_contacted_peer(&peer_addr);
+            }
+        });
+        Ok(id)
+    }
+
+    /// Push a message to a client connected to us, for notifications it didn't ask for.
+    ///
+    /// Unlike with `send`, the messages pushed to a client travel on a single stream kept open for
+    /// them and arrive in the order they were pushed in. If the client isn't connected or the push
+    /// fails, `Event::UnsentUserMessage` is fired for the message. Connections with the
+    /// `WireEncoding::Bincode` or `WireEncoding::MessagePack` encodings have no push streams, so
+    /// messages pushed over them are sent like any other.
+    pub fn push(&mut self, peer_addr: SocketAddr, msg: bytes::Bytes) {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        self.post(move || {
+            let wire_msg = WireMsg::UserMsg(msg);
+            ctx_mut(|c| c.outcomes.start(&Peer::Client { peer_addr }, &wire_msg, 0));
+            communicate::push_to_client(peer_addr, wire_msg)
+        });
+    }
+
+    /// Have the node at `relay_addr`, which we are connected to, relay the message to its client at
+    /// `peer_addr`, for clients to talk to each other without either being reachable.
+    ///
+    /// Only nodes which enabled `Builder::with_client_forwarding` relay messages, and only between
+    /// their clients. The receiving client gets `Event::ForwardedMessage` naming us by the address
+    /// the node sees for us. Messages the node doesn't relay, e.g. over its rate limit, are dropped
+    /// without notice.
+    pub fn forward(&mut self, relay_addr: SocketAddr, peer_addr: SocketAddr, msg: bytes::Bytes) {
+        let relay_addr = utils::normalise_addr(relay_addr);
+        let peer_addr = utils::normalise_addr(peer_addr);
+        self.post(move || {
+            communicate::write_to_peer(relay_addr, WireMsg::ForwardReq { to: peer_addr, msg })
+        });
+    }
+
+    /// Send the file at `path` to the peer, e.g. a chunk replicated between storage nodes,
+    /// streaming it from disk on a stream of its own rather than reading it into memory first.
+    /// `Event::SentFile` or `Event::UnsentFile` carrying the token is fired once done, and the
+    /// peer gets `Event::IncomingFile` to take the file with `receive_file`.
+    ///
+    /// Unlike with `send`, the peer has to be connected already, over QUIC with an encoding
+    /// offering push streams, i.e. neither `WireEncoding::Bincode` nor
+    /// `WireEncoding::MessagePack`.
+    pub fn send_file(&mut self, peer: Peer, path: PathBuf, token: Token) {
+        let peer = peer.normalised();
+        self.post(move || file_transfer::send(peer, path, token));
+    }
+
+    /// Stop sending the files sent with `send_file` and the given token, e.g. once superseded by a
+    /// newer version, for them to stop taking up bandwidth right away. Their streams are reset,
+    /// each firing `Event::UnsentFile` with `Error::TransferCancelled`, and the peer gets
+    /// `Event::IncomingFileCancelled`. Fails with `Error::FileTransfer` if no file is being sent
+    /// with the token.
+    pub fn cancel_stream(&mut self, token: Token) -> R<()> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(file_transfer::cancel(token));
+        });
+        if !rx.recv()? {
+            return Err(Error::FileTransfer(format!(
+                "No file being sent with token {}",
+                token
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Take the file announced by `Event::IncomingFile` with the given id, streaming it to `dest`
+    /// as it arrives. Blocks until the file is received in full, returning its length. Should that
+    /// fail, what was written of it is removed again.
+    pub fn receive_file(&mut self, id: FileId, dest: &Path) -> R<u64> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(file_transfer::stream_out(id));
+        });
+        let (len, chunks) = rx.recv()??;
+
+        file_transfer::write_out(dest, len, chunks)
+    }
+
+    /// Drop the file announced by `Event::IncomingFile` with the given id, stopping the peer
+    /// sending it, which gets `Event::UnsentFile` unless it finished writing the file already.
+    /// Fails with `Error::FileTransfer` if there is no such file, e.g. because it was taken
+    /// already.
+    pub fn reject_file(&mut self, id: FileId) -> R<()> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(file_transfer::reject(id));
+        });
+        if !rx.recv()? {
+            return Err(Error::FileTransfer(format!(
+                "No incoming file {}",
+                id.as_u64()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Subscribe to the topic, to get `Event::TopicMessage` for the messages published to it. The
+    /// peers we are connected to, and those we connect to later, are told about it, forwarding us
+    /// what is published to the topic from then on.
+    pub fn subscribe(&mut self, topic: String) {
+        self.post(move || pubsub::subscribe(topic, true));
+    }
+
+    /// Undo `subscribe`, telling the peers we are connected to. Does nothing if we aren't
+    /// subscribed to the topic.
+    pub fn unsubscribe(&mut self, topic: String) {
+        self.post(move || pubsub::subscribe(topic, false));
+    }
+
+    /// Publish the message to the topic. It is sent to the peers we are connected to which are
+    /// subscribed to it, each forwarding it to the subscribers it is connected to in turn, up to
+    /// 6 hops away from us. Subscribers get it once only however many paths it reaches them on.
+    /// We don't get `Event::TopicMessage` for our own messages.
+    pub fn publish(&mut self, topic: String, msg: bytes::Bytes) {
+        self.post(move || pubsub::publish(topic, msg));
+    }
+
+    /// Gossip the message to all the peers reachable through our connections, e.g. to announce
+    /// membership changes. It is sent to a few random peers we are connected to, which pass it on
+    /// to a few random peers of theirs in turn, up to 6 hops away from us. Every peer gets
+    /// `Event::GossipMessage` once however many copies reach it, we don't get it for our own
+    /// messages. Peers the gossip misses catch up if enabled via `Builder::with_gossip`.
+    pub fn gossip(&mut self, msg: bytes::Bytes) {
+        self.post(move || gossip::gossip(msg));
+    }
+
+    /// Get our connection info to give to others for them to connect to us
+    ///
+    /// Uses `Config::external_address` as our endpoint if set. Otherwise will use hard coded
+    /// contacts to ask for our endpoint. If no contact is given then we'll simply build our
+    /// connection info by querying the underlying bound socket for our address. Note that if such
+    /// an obtained address is of unspecified category we will ignore that as such an address
+    /// cannot be reached and hence not useful.
+    // FIXME calling this mutliple times concurrently just now could have it hanging as only one tx
+    // is registered and that replaces any previous tx registered. Fix by using a vec of txs
+    pub fn our_connection_info(&mut self) -> R<NodeInfo> {
+        if let Some(ref us) = self.us {
+            return Ok(us.clone());
+        }
+
+        let addr_res = match self.cfg.external_address {
+            Some(addr) => Ok(addr),
+            None => self.query_ip_echo_service(),
+        };
+        let our_addr = match addr_res {
+            Ok(addr) => addr,
+            Err(e @ Error::NoEndpointEchoServerFound) => {
+                let (tx, rx) = mpsc::channel();
+                self.el.post(move || {
+                    let local_addr_res = ctx(|c| c.transport.local_addr());
+                    unwrap!(tx.send(local_addr_res));
+                });
+                let addr = unwrap!(rx.recv())?;
+                if addr.ip().is_unspecified() {
+                    return Err(e);
+                } else {
+                    addr
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        let our_cert_der = self.our_certificate_der();
+
+        let us = NodeInfo {
+            peer_addr: our_addr,
+            peer_cert_der: our_cert_der,
+            alt_addrs: Vec::new(),
+        };
+
+        self.us = Some(us.clone());
 
         Ok(us)
     }
 
+    /// Addresses we listen on as bound locally: the one we are bound to for `our_connection_info`
+    /// followed by those of `Config::additional_listen_addrs`, with any random ports assigned.
+    pub fn our_listen_addrs(&mut self) -> R<Vec<SocketAddr>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(ctx(|c| c.transport.clone()).listen_addrs());
+        });
+        rx.recv()?
+    }
+
+    /// Promote us from a client to a node without dropping our connections.
+    ///
+    /// We start accepting connections on our endpoint and introduce ourselves as a node to the
+    /// nodes we are connected to. They reverse connect to us, seeing us as `Peer::Node` from then
+    /// on and firing `Event::ConnectedTo` for that. Does nothing if we are a node already.
+    #[cfg(not(feature = "client-only"))]
+    pub fn promote_to_node(&mut self) -> R<()> {
+        if self.cfg.our_type == OurType::Node {
+            return Ok(());
+        }
+        self.cfg.our_type = OurType::Node;
+
+        self.post(move || {
+            ctx_mut(|c| c.our_type = OurType::Node);
+            serve();
+            connect::introduce_as_node();
+        });
+
+        Ok(())
+    }
+
+    /// Ask `via_peer`, which we are connected to, to connect to us at `my_info`, e.g. when we
+    /// can't be dialed directly but know which address a connection attempt would reach us on.
+    ///
+    /// A cheap alternative to hole punching. The peer only obliges if `my_info` is on the IP
+    /// address it sees us connecting from, and fires `Event::ConnectedTo` for us once connected as
+    /// we do for it. Only nodes accept the connection back, so this is not allowed for clients.
+    #[cfg(not(feature = "client-only"))]
+    pub fn request_connect_back(&mut self, via_peer: SocketAddr, my_info: NodeInfo) -> R<()> {
+        if self.cfg.our_type != OurType::Node {
+            return Err(Error::OperationNotAllowed);
+        }
+        let via_peer = utils::normalise_addr(via_peer);
+        self.post(move || communicate::write_to_peer(via_peer, WireMsg::ConnectBackReq(my_info)));
+        Ok(())
+    }
+
+    /// Ask `via_peer`, which we are connected to, to check whether we can be dialed at the address
+    /// of `our_connection_info`, e.g. to find out if port forwarding works before advertising
+    /// ourselves to the network. `Event::ReachabilityChecked` tells the outcome.
+    ///
+    /// The peer dials us from a socket of its own which we never sent anything to, so that a NAT
+    /// letting in only the peers we contacted can't fake a success. It only obliges if that
+    /// address is on the IP address it sees us connecting from. Peers of older versions ignore the
+    /// request with the tagged encoding and drop the connection with the others. Only nodes accept
+    /// connections, so this is not allowed for clients.
+    #[cfg(not(feature = "client-only"))]
+    pub fn check_reachability(&mut self, via_peer: SocketAddr) -> R<()> {
+        if self.cfg.our_type != OurType::Node {
+            return Err(Error::OperationNotAllowed);
+        }
+        let our_info = self.our_connection_info()?;
+        let via_peer = utils::normalise_addr(via_peer);
+        self.post(move || communicate::write_to_peer(via_peer, WireMsg::ReachabilityReq(our_info)));
+        Ok(())
+    }
+
+    /// Find out how the NAT we are behind, if any, maps our endpoint, e.g. to decide whether peers
+    /// can reach us by hole punching or only through a relay. Each of `peers`, which we are
+    /// connected to, is asked which address it sees us at, waiting up to 5 seconds for the
+    /// answers.
+    ///
+    /// Peers on two IP addresses at least are needed to tell the mappings apart, and two sharing
+    /// an IP address to tell whether the port matters. Nodes also check whether they can reach
+    /// themselves at an address a peer sees, which takes up to 10 more seconds. See `NatReport`.
+    pub fn detect_nat(&mut self, peers: &[SocketAddr]) -> R<NatReport> {
+        let peers: Vec<_> = peers.iter().map(|&p| utils::normalise_addr(p)).collect();
+        let (tx, rx) = mpsc::channel();
+        let asked = peers.clone();
+        self.post(move || {
+            ctx_mut(|c| c.nat_echo_tx = Some(tx));
+            for peer_addr in asked {
+                communicate::write_to_peer(peer_addr, WireMsg::EndpointEchoReq);
+            }
+        });
+
+        let deadline = Instant::now() + nat::ECHO_TIMEOUT;
+        let mut observed: Vec<(SocketAddr, SocketAddr)> = Vec::new();
+        while observed.len() < peers.len() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match rx.recv_timeout(deadline - now) {
+                Ok((peer_addr, seen)) => {
+                    if peers.contains(&peer_addr) && observed.iter().all(|&(p, _)| p != peer_addr) {
+                        observed.push((peer_addr, seen));
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        let our_cert_der = if self.cfg.our_type == OurType::Node {
+            Some(self.our_certificate_der())
+        } else {
+            None
+        };
+        let (tx, rx) = mpsc::channel();
+        self.post(move || nat::report(observed, our_cert_der, tx));
+        Ok(rx.recv()?)
+    }
+
+    /// Move our connections over to a fresh local socket, e.g. after our network changed from
+    /// Wi-Fi to cellular. Returns the address we are bound to from then on.
+    ///
+    /// The connections we made carry on where the nodes let them, firing
+    /// `Event::ConnectionMigrated`. The others fire `Event::MigrationFailed` and are dropped, so
+    /// the nodes have to be connected to anew. Nodes can't migrate, as their peers know them by
+    /// their address, so this is only allowed for clients.
+    pub fn migrate(&mut self) -> R<SocketAddr> {
+        if self.cfg.our_type != OurType::Client {
+            return Err(Error::OperationNotAllowed);
+        }
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(migration::migrate(None));
+        });
+        rx.recv()?
+    }
+
     /// Retrieves current node bootstrap cache.
     pub fn bootstrap_cache(&mut self) -> R<Vec<NodeInfo>> {
         let (tx, rx) = mpsc::channel();
@@ -296,15 +1557,290 @@ impl QuicP2p {
         Ok(cache)
     }
 
+    /// Retrieves statistics of our connections.
+    pub fn stats(&mut self) -> R<Stats> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let stats = ctx(|c| {
+                let mut bootstrap_cache_ages = AgeHistogram::default();
+                for peer in c.bootstrap_cache.peers() {
+                    bootstrap_cache_ages.record(c.bootstrap_cache.age_of(&peer.peer_addr));
+                }
+                let now = c.clock.now();
+                let peers: Vec<_> = c
+                    .connections
+                    .iter()
+                    .map(|(peer_addr, conn)| (*peer_addr, conn.peer_stats(now)))
+                    .collect();
+                Stats {
+                    connections: peers
+                        .iter()
+                        .map(|(peer_addr, stats)| (*peer_addr, stats.connection))
+                        .collect(),
+                    bandwidth: peers
+                        .iter()
+                        .map(|(peer_addr, stats)| (*peer_addr, stats.bandwidth))
+                        .collect(),
+                    bootstrap: *c.bootstrap_stats.borrow(),
+                    bootstrap_cache_ages,
+                }
+            });
+            let _ = tx.send(stats);
+        });
+        let stats = rx.recv()?;
+
+        Ok(stats)
+    }
+
+    /// Statistics of our connections to the given peer, including the rates of the bytes exchanged
+    /// with it over the last second, minute and 15 minutes, or `None` if we don't know of it.
+    ///
+    /// The byte counts are sampled every second, so a rate may cover up to a second more than its
+    /// window.
+    pub fn peer_stats(&mut self, peer_addr: SocketAddr) -> R<Option<PeerStats>> {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let stats = ctx(|c| {
+                let now = c.clock.now();
+                c.connections
+                    .get(&peer_addr)
+                    .map(|conn| conn.peer_stats(now))
+            });
+            let _ = tx.send(stats);
+        });
+        let stats = rx.recv()?;
+
+        Ok(stats)
+    }
+
+    /// Run `f` on the quinn endpoint of our main socket, returning what it returns, for what the
+    /// API doesn't offer, e.g. quinn's own view of the endpoint. Fails with
+    /// `Error::OperationNotAllowed` when running over another transport, see
+    /// `Builder::with_memory_transport`.
+    ///
+    /// `f` is run on the event loop, so it should return quickly. quinn's handles are bound to
+    /// that thread and can't be taken out of `f`, but futures using them may be spawned onto the
+    /// event loop with `tokio::runtime::current_thread::spawn`. The endpoint is shared with our
+    /// connections: connections made or accepted behind our back are unknown to us, and rebinding
+    /// it breaks `QuicP2p::migrate`. The endpoints of `Config::additional_listen_addrs`, and the
+    /// IPv4 one kept next to an IPv6 socket which isn't dual-stack, aren't reachable this way.
+    ///
+    /// Only with the `unstable-quinn` feature, which follows the quinn version we depend on
+    /// rather than semver.
+    #[cfg(feature = "unstable-quinn")]
+    pub fn with_quinn_endpoint<F, T>(&mut self, f: F) -> R<T>
+    where
+        F: FnOnce(&quinn::Endpoint) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let transport = ctx(|c| c.transport.clone());
+            let r = transport
+                .quinn_endpoint()
+                .map(f)
+                .ok_or(Error::OperationNotAllowed);
+            let _ = tx.send(r);
+        });
+
+        rx.recv()?
+    }
+
+    /// Run `f` on the quinn connection with the peer, returning what it returns, or `None` if
+    /// there's no QUIC connection with it established. The connection we made to the peer is
+    /// taken over the one it made to us.
+    ///
+    /// The caveats of `with_quinn_endpoint` hold here as well. Beyond those, every stream the
+    /// peer opens is read by us, so only the streams `f` opens are its own: a unidirectional one
+    /// is read by the peer as a wire message and a bidirectional one gets the connection dropped
+    /// by it. A connection closed through quinn is reported as any connection lost.
+    ///
+    /// Only with the `unstable-quinn` feature, which follows the quinn version we depend on
+    /// rather than semver.
+    #[cfg(feature = "unstable-quinn")]
+    pub fn with_quinn_connection<F, T>(&mut self, peer_addr: SocketAddr, f: F) -> R<Option<T>>
+    where
+        F: FnOnce(&quinn::Connection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let q_conn = ctx(|c| {
+                c.connections
+                    .get(&peer_addr)
+                    .and_then(|conn| conn.quinn_connection())
+            });
+            let _ = tx.send(q_conn.as_ref().map(f));
+        });
+        let r = rx.recv()?;
+
+        Ok(r)
+    }
+
+    /// Sessions of the clients currently connected to us over QUIC.
+    pub fn clients(&mut self) -> R<Vec<ClientSession>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let clients = ctx_mut(|c| {
+                let now = c.clock.now();
+                client_session::sessions(c, now)
+            });
+            let _ = tx.send(clients);
+        });
+        let clients = rx.recv()?;
+
+        Ok(clients)
+    }
+
+    /// Takes the user messages which couldn't be sent, oldest first, if collected as
+    /// `Builder::with_dead_letters` asks.
+    pub fn drain_dead_letters(&mut self) -> R<Vec<DeadLetter>> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(ctx_mut(|c| match c.dead_letters {
+                Some(ref mut dead_letters) => dead_letters.drain(),
+                None => Ok(Vec::new()),
+            }));
+        });
+        rx.recv()?
+    }
+
+    /// Captures the internal state for debugging, e.g. of a node that appears to be stuck.
+    pub fn dump_state(&mut self) -> R<StateDump> {
+        let (tx, rx) = mpsc::channel();
+        self.el.post(move || {
+            let _ = tx.send(ctx(state_dump::dump));
+        });
+        let dump = rx.recv()?;
+
+        Ok(dump)
+    }
+
+    /// Apply the fields of `cfg` which can change without restarting, returning which fields
+    /// changed and which of them were left as they were as they only take effect on restart, e.g.
+    /// `port` or `our_type` (see `promote_to_node` for the latter).
+    ///
+    /// The contacts, `bootstrap_strategy` and `external_address` apply from the next bootstrap or
+    /// `our_connection_info` on. `max_msg_size_allowed`, `idle_timeout_msec`,
+    /// `keep_alive_interval_msec`, `connect_timeout_msec` and `ordered_delivery` apply to the
+    /// connections we make from now on, where connections peers make to us keep the timeouts we
+    /// started with.
+    /// `min_protocol_version` and `max_protocol_version` apply to the handshakes of peers from
+    /// now on, and `max_incoming_per_v4_subnet` and `max_incoming_per_v6_subnet` to the peers
+    /// connecting to us from now on.
+    ///
+    /// The config we would end up with is validated first, see `Config::validate`, and nothing is
+    /// applied if it is rejected.
+    pub fn apply_config(&mut self, cfg: Config) -> R<ConfigChanges> {
+        let mut reloaded = self.cfg.clone();
+        let changes = reloaded.reload(cfg);
+        reloaded.validate()?;
+        if reloaded.external_address != self.cfg.external_address {
+            self.us = None;
+        }
+        self.cfg = reloaded;
+
+        let hard_coded_contacts = self.cfg.hard_coded_contacts.clone();
+        let bootstrap_only_contacts = self.cfg.bootstrap_only_contacts.clone();
+        let bootstrap_strategy = self.cfg.bootstrap_strategy;
+        let max_msg_size_allowed = self
+            .cfg
+            .max_msg_size_allowed
+            .map(|size| size as usize)
+            .unwrap_or(DEFAULT_MAX_ALLOWED_MSG_SIZE);
+        let idle_timeout_msec = self
+            .cfg
+            .idle_timeout_msec
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT_MSEC);
+        let keep_alive_interval_msec = self
+            .cfg
+            .keep_alive_interval_msec
+            .unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL_MSEC);
+        let duplicate_connection_policy = self.cfg.duplicate_connection_policy;
+        let ordered_delivery = self.cfg.ordered_delivery;
+        let protocol_versions = self.cfg.protocol_versions();
+        let connect_timeout = self.cfg.connect_timeout();
+        let subnet_limits = self.cfg.subnet_limits();
+
+        self.post(move || {
+            ctx_mut(|c| {
+                c.bootstrap_cache
+                    .set_contacts(hard_coded_contacts, bootstrap_only_contacts);
+                c.bootstrap_strategy = bootstrap_strategy;
+                c.max_msg_size_allowed = max_msg_size_allowed;
+                c.idle_timeout_msec = idle_timeout_msec;
+                c.keep_alive_interval_msec = keep_alive_interval_msec;
+                c.duplicate_connection_policy = duplicate_connection_policy;
+                c.ordered_delivery = ordered_delivery;
+                c.protocol_versions = protocol_versions;
+                c.connect_timeout = connect_timeout;
+                c.subnet_limits = subnet_limits;
+            })
+        });
+
+        Ok(changes)
+    }
+
+    /// Inject the faults of `chaos` from now on, in place of those of `Builder::with_chaos`. Pass
+    /// `Chaos::default()` to stop injecting faults.
+    ///
+    /// Probabilities outside 0 to 1 are rejected with `Error::InvalidSetting`.
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos(&mut self, chaos: Chaos) -> R<()> {
+        chaos.validate("set_chaos")?;
+        self.post(move || chaos::set(chaos));
+        Ok(())
+    }
+
+    /// Bootstrap off the contact from now on, as one of `Config::hard_coded_contacts`, e.g. a seed
+    /// node published since we started. A cached peer or bootstrap-only contact of the same
+    /// `NodeInfo` becomes a hard-coded contact instead.
+    ///
+    /// The contact is kept until we restart. Write the config out with `save_config` to keep it
+    /// for good.
+    pub fn add_contact(&mut self, contact: NodeInfo) {
+        let _ = self.cfg.bootstrap_only_contacts.remove(&contact);
+        let _ = self.cfg.hard_coded_contacts.insert(contact.clone());
+        let contact = contact.normalised();
+        self.post(move || ctx_mut(|c| c.bootstrap_cache.add_contact(contact)));
+    }
+
+    /// Stop bootstrapping off the hard-coded and bootstrap-only contacts at the given address,
+    /// returning whether there were any. Connections to them are left alone and peers cached are
+    /// kept, see `disconnect_from`.
+    ///
+    /// As with `add_contact`, write the config out with `save_config` to keep this on restart.
+    pub fn remove_contact(&mut self, peer_addr: SocketAddr) -> bool {
+        let peer_addr = utils::normalise_addr(peer_addr);
+        // The config keeps the contacts as given, only those of the cache are normalised
+        let is_other = |contact: &NodeInfo| utils::normalise_addr(contact.peer_addr) != peer_addr;
+        let before = self.cfg.hard_coded_contacts.len() + self.cfg.bootstrap_only_contacts.len();
+        self.cfg.hard_coded_contacts.retain(is_other);
+        self.cfg.bootstrap_only_contacts.retain(is_other);
+        let removed =
+            self.cfg.hard_coded_contacts.len() + self.cfg.bootstrap_only_contacts.len() != before;
+
+        self.post(move || ctx_mut(|c| c.bootstrap_cache.remove_contact(peer_addr)));
+        removed
+    }
+
+    /// Write the config we run with to the given file, see `Config::save_to`. It includes the
+    /// changes made at runtime by `apply_config`, `add_contact` and `remove_contact`.
+    pub fn save_config(&self, path: &Path, format: Format) -> R<()> {
+        self.cfg.save_to(path, format)
+    }
+
     /// Checks whether the given contact is hard-coded.
     pub fn is_hard_coded_contact(&self, node_info: &NodeInfo) -> bool {
         self.cfg.hard_coded_contacts.contains(node_info)
     }
 
-    fn new(event_tx: mpmc::Sender<Event>) -> R<Self> {
+    fn new(event_tx: mpmc::Sender<Event>, dirs: Option<&Dirs>) -> R<Self> {
         Ok(Self::with_config(
             event_tx,
-            Config::read_or_construct_default(None)?,
+            Config::read_or_construct_default(dirs)?,
         ))
     }
 
@@ -312,9 +1848,20 @@ impl QuicP2p {
         let el = EventLoop::spawn();
         Self {
             event_tx,
+            event_policy: Default::default(),
+            wire_encoding: Default::default(),
+            #[cfg(feature = "test-utils")]
+            memory_transport: None,
             cfg,
+            dirs: None,
+            clock: Arc::new(SystemClock),
+            rng_seed: None,
+            restored: None,
+            ephemeral: false,
             us: None,
+            next_quorum_send_id: 0,
             el,
+            dir_lock: None,
         }
     }
 
@@ -325,10 +1872,34 @@ impl QuicP2p {
             .port
             .map(|p| (p, true))
             .unwrap_or((DEFAULT_PORT_TO_TRY, false));
-        let ip = self
-            .cfg
-            .ip
-            .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let bind_interface = self.cfg.bind_interface.clone();
+        let (ip, interface_ips) = match bind_interface {
+            Some(ref name) => {
+                let mut ips = sockets::interface_addrs(name)
+                    .and_then(|ips| {
+                        if ips.is_empty() {
+                            Err(io::Error::new(
+                                io::ErrorKind::NotFound,
+                                format!("network interface {} has no addresses", name),
+                            ))
+                        } else {
+                            Ok(ips)
+                        }
+                    })
+                    .map_err(|e| Error::Config {
+                        field: "bind_interface",
+                        source: Box::new(From::from(e)),
+                    })?;
+                let ip = ips.remove(0);
+                (ip, ips)
+            }
+            None => (
+                self.cfg
+                    .ip
+                    .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                Vec::new(),
+            ),
+        };
         let max_msg_size_allowed = self
             .cfg
             .max_msg_size_allowed
@@ -343,9 +1914,60 @@ impl QuicP2p {
             .keep_alive_interval_msec
             .unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL_MSEC);
         let our_type = self.cfg.our_type;
-        let hard_coded_contacts = self.cfg.hard_coded_contacts.clone();
+        let duplicate_connection_policy = self.cfg.duplicate_connection_policy;
+        let bootstrap_strategy = self.cfg.bootstrap_strategy;
+        let ordered_delivery = self.cfg.ordered_delivery;
+        let protocol_versions = self.cfg.protocol_versions();
+        let connect_timeout = self.cfg.connect_timeout();
+        let subnet_limits = self.cfg.subnet_limits();
+        #[cfg(feature = "client-only")]
+        {
+            if our_type != OurType::Client {
+                return Err(Error::Config {
+                    field: "our_type",
+                    source: Box::new(Error::Configuration(
+                        "Client-only builds can't run nodes".to_string(),
+                    )),
+                });
+            }
+        }
+        let additional_sockets = self
+            .cfg
+            .additional_listen_addrs
+            .iter()
+            .map(|addr| sockets::bind(addr.ip(), addr.port()))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| Error::Config {
+                field: "additional_listen_addrs",
+                source: Box::new(From::from(e)),
+            })?;
+        let socks5_proxy = self.cfg.socks5_proxy;
+        #[cfg(feature = "websocket")]
+        let websocket_port = self.cfg.websocket_port;
+        let hard_coded_contacts = self
+            .cfg
+            .hard_coded_contacts
+            .iter()
+            .cloned()
+            .map(|mut contact| {
+                contact.peer_addr = utils::normalise_addr(contact.peer_addr);
+                contact
+            })
+            .collect();
+        let bootstrap_only_contacts = self
+            .cfg
+            .bootstrap_only_contacts
+            .iter()
+            .cloned()
+            .map(|mut contact| {
+                contact.peer_addr = utils::normalise_addr(contact.peer_addr);
+                contact
+            })
+            .collect();
 
         let tx = self.event_tx.clone();
+        let event_policy = self.event_policy;
+        let wire_encoding = self.wire_encoding;
 
         let ((key, cert), our_complete_cert) = {
             let our_complete_cert = self
@@ -354,61 +1976,136 @@ impl QuicP2p {
                 .clone()
                 .unwrap_or_else(Default::default);
             (
-                our_complete_cert.obtain_priv_key_and_cert()?,
+                our_complete_cert
+                    .obtain_priv_key_and_cert()
+                    .map_err(|e| Error::Config {
+                        field: "our_complete_cert",
+                        source: Box::new(e),
+                    })?,
                 our_complete_cert,
             )
         };
-        let bootstrap_cache = BootstrapCache::new(hard_coded_contacts, None)?;
+        let bootstrap_cache = if self.ephemeral {
+            BootstrapCache::in_memory(hard_coded_contacts)
+        } else {
+            BootstrapCache::new(hard_coded_contacts, self.dirs.as_ref())?
+        };
+        let mut bootstrap_cache = bootstrap_cache
+            .with_bootstrap_only_contacts(bootstrap_only_contacts)
+            .with_clock(self.clock.clone());
+        if let Some(bundle) = self.restored.take() {
+            let bundle_path = restore::bundle_path(self.dirs.as_ref())?;
+            bootstrap_cache = bootstrap_cache.restored_from(bundle, bundle_path);
+        }
+        let clock = self.clock.clone();
+        let rng = self.rng_seed.map(Rng::seeded).unwrap_or_default();
+        let logs_dir = self.dirs.as_ref().map(|dirs| dirs.logs_dir().to_path_buf());
+        #[cfg(feature = "test-utils")]
+        let memory_transport = self.memory_transport.clone();
+        #[cfg(feature = "test-utils")]
+        let our_cert_der = our_complete_cert.cert_der.clone();
+
+        // Bound up front, so that failing to bind is returned rather than panicking the event loop
+        #[cfg(feature = "test-utils")]
+        let bind_udp = memory_transport.is_none();
+        #[cfg(not(feature = "test-utils"))]
+        let bind_udp = true;
+        let sockets = if bind_udp {
+            Some(bind_sockets(
+                ip,
+                port,
+                is_user_supplied,
+                bind_interface
+                    .as_ref()
+                    .map(|name| (name.as_str(), interface_ips)),
+                additional_sockets,
+            )?)
+        } else {
+            None
+        };
 
+        let (started_tx, started_rx) = mpsc::channel();
         self.el.post(move || {
-            let our_cfg = unwrap!(peer_config::new_our_cfg(
-                idle_timeout_msec,
-                keep_alive_interval_msec,
-                cert,
-                key
-            ));
-
-            let mut ep_builder = quinn::Endpoint::builder();
-            let _ = ep_builder.listen(our_cfg);
-            let (dr, ep, incoming_connections) = {
-                match UdpSocket::bind(&(ip, port)) {
-                    Ok(udp) => unwrap!(ep_builder.with_socket(udp)),
-                    Err(e) => {
-                        if is_user_supplied {
-                            panic!(
-                                "Could not bind to the user supplied port: {}! Error: {:?}- {}",
-                                port, e, e
-                            );
-                        }
-                        info!(
-                            "Failed to bind to port: {} - Error: {:?} - {}. Trying random port.",
-                            DEFAULT_PORT_TO_TRY, e, e
-                        );
-                        unwrap!(ep_builder.bind(&(ip, 0)))
+            let ep_builder = || {
+                let our_cfg = unwrap!(peer_config::new_our_cfg(
+                    idle_timeout_msec,
+                    keep_alive_interval_msec,
+                    cert.clone(),
+                    key.clone(),
+                    wire_encoding
+                ));
+
+                let mut ep_builder = quinn::Endpoint::builder();
+                let _ = ep_builder.listen(our_cfg);
+                ep_builder
+            };
+            let bind_quic = |(sockets, additional_sockets): (sockets::Sockets, Vec<_>)| {
+                let quic =
+                    QuicTransport::new(sockets, additional_sockets, ep_builder, socks5_proxy)?;
+                #[allow(unused_mut)]
+                let mut transport: Rc<dyn Transport> = Rc::new(quic);
+                #[cfg(feature = "tcp-fallback")]
+                {
+                    transport = Rc::new(TcpFallback::new(transport));
+                }
+                #[cfg(feature = "websocket")]
+                {
+                    if let Some(port) = websocket_port {
+                        let websocket_addr = SocketAddr::new(ip, port);
+                        transport = Rc::new(WebSocketBridge::new(transport, websocket_addr));
                     }
                 }
+                Ok::<_, Error>(transport)
             };
-
-            let ctx = Context::new(
-                tx,
-                our_complete_cert,
+            #[cfg(feature = "test-utils")]
+            let transport: R<Rc<dyn Transport>> = match memory_transport {
+                Some(network) => Ok(Rc::new(network.bind(ip, port, our_cert_der, wire_encoding))),
+                None => bind_quic(unwrap!(sockets)),
+            };
+            #[cfg(not(feature = "test-utils"))]
+            let transport: R<Rc<dyn Transport>> = bind_quic(unwrap!(sockets));
+            let transport = match transport {
+                Ok(transport) => transport,
+                Err(e) => {
+                    let _ = started_tx.send(Err(e));
+                    return;
+                }
+            };
+
+            #[allow(unused_mut)]
+            let mut ctx = Context::new(
+                EventSender::new(tx, event_policy),
+                our_complete_cert,
                 max_msg_size_allowed,
                 idle_timeout_msec,
                 keep_alive_interval_msec,
                 our_type,
                 bootstrap_cache,
-                ep,
+                transport.clone(),
             );
+            ctx.wire_encoding = wire_encoding;
+            ctx.duplicate_connection_policy = duplicate_connection_policy;
+            ctx.bootstrap_strategy = bootstrap_strategy;
+            ctx.ordered_delivery = ordered_delivery;
+            ctx.protocol_versions = protocol_versions;
+            ctx.connect_timeout = connect_timeout;
+            ctx.subnet_limits = subnet_limits;
+            ctx.logs_dir = logs_dir;
+            ctx.clock = clock;
+            ctx.rng = rng;
             initialise_ctx(ctx);
+            bandwidth::start_sampling();
 
-            current_thread::spawn(dr.map_err(|e| warn!("Error in quinn Driver: {:?}", e)));
-
-            if our_type != OurType::Client {
-                listener::listen(incoming_connections);
+            #[cfg(not(feature = "client-only"))]
+            {
+                if our_type != OurType::Client {
+                    serve();
+                }
             }
+            let _ = started_tx.send(Ok(()));
         });
 
-        Ok(())
+        started_rx.recv()?
     }
 
     fn our_certificate_der(&mut self) -> Vec<u8> {
@@ -442,6 +2139,45 @@ impl QuicP2p {
         Ok(unwrap!(rx.recv()))
     }
 
+    /// Post `f` to the event loop on behalf of the user. With the `otel` feature, the caller's
+    /// current OpenTelemetry context is made current while `f` runs, so that the spans `f` starts
+    /// belong to the caller's trace.
+    fn post<F>(&mut self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        #[cfg(feature = "otel")]
+        let f = {
+            let cx = opentelemetry::Context::current();
+            move || {
+                let _guard = cx.attach();
+                f()
+            }
+        };
+
+        self.el.post(f)
+    }
+
+    /// Send the user message with options which can't conflict, as those of the `send_*`
+    /// wrappers of `send_with` can't.
+    fn send_with_opts(&mut self, peer: Peer, msg: bytes::Bytes, opts: SendOptions) {
+        unwrap!(self.send_with(peer, msg, opts));
+    }
+
+    /// Send the user message from the event loop, reporting its outcome with the token.
+    fn send_user_msg(
+        peer: Peer,
+        wire_msg: WireMsg,
+        mode: Option<DeliveryMode>,
+        policy: Option<ResendPolicy>,
+        token: Token,
+    ) {
+        let peer_addr = peer.peer_addr();
+        ctx_mut(|c| c.outcomes.start(&peer, &wire_msg, token));
+        resend::send(peer, wire_msg, mode, policy);
+        Self::set_we_contacted_peer(&peer_addr);
+    }
+
     #[inline]
     fn set_we_contacted_peer(peer_addr: &SocketAddr) {
         ctx_mut(|c| {
@@ -452,15 +2188,70 @@ impl QuicP2p {
     }
 }
 
-#[cfg(test)]
+/// Bind the sockets to listen on at `ip` and `port`, falling back to a random port unless the
+/// port was supplied by the user. When bound to the given network interface, its other addresses
+/// are listened on as well, in addition to the sockets of `additional`.
+fn bind_sockets(
+    ip: IpAddr,
+    port: u16,
+    is_user_supplied: bool,
+    interface: Option<(&str, Vec<IpAddr>)>,
+    mut additional: Vec<sockets::Sockets>,
+) -> R<(sockets::Sockets, Vec<sockets::Sockets>)> {
+    let mut sockets = match sockets::bind(ip, port) {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            if is_user_supplied {
+                return Err(Error::Config {
+                    field: "port",
+                    source: Box::new(From::from(e)),
+                });
+            }
+            info!(
+                "Failed to bind to port: {} - Error: {:?} - {}. Trying random port.",
+                DEFAULT_PORT_TO_TRY, e, e
+            );
+            sockets::bind(ip, 0)?
+        }
+    };
+    if let Some((name, interface_ips)) = interface {
+        let port = sockets.main.local_addr()?.port();
+        sockets = sockets.bind_to_device(name).map_err(|e| Error::Config {
+            field: "bind_interface",
+            source: Box::new(From::from(e)),
+        })?;
+        for ip in interface_ips {
+            match sockets::bind(ip, port).and_then(|s| s.bind_to_device(name)) {
+                Ok(s) => additional.push(s),
+                Err(e) => warn!("Could not listen on {} of {}: {}", ip, name, e),
+            }
+        }
+    }
+    Ok((sockets, additional))
+}
+
+/// Accept connections from peers on our transport. Only nodes do.
+#[cfg(not(feature = "client-only"))]
+fn serve() {
+    let transport = ctx(|c| c.transport.clone());
+    if let Some(incoming_connections) = transport.incoming() {
+        listener::listen(incoming_connections);
+    }
+}
+
+#[cfg(all(test, not(feature = "client-only")))]
 mod tests {
     use super::*;
+    use crate::connection::{Connection, ToPeer};
     use crate::wire_msg::{Handshake, WireMsg};
     use crossbeam_channel as mpmc;
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
     use std::iter;
-    use std::time::Duration;
-    use test_utils::{new_random_qp2p, rand_node_info};
+    use std::net::{Ipv6Addr, UdpSocket};
+    use std::time::{Duration, Instant, SystemTime};
+    #[cfg(feature = "test-utils")]
+    use test_utils::{new_qp2p_on, LinkConditions};
+    use test_utils::{new_random_qp2p, rand_node_info, tmp_rand_dir, ManualClock};
 
     #[test]
     fn dropping_qp2p_handle_gracefully_shutsdown_event_loop() {
@@ -468,6 +2259,391 @@ mod tests {
         let _qp2p = unwrap!(Builder::new(tx).build());
     }
 
+    #[test]
+    fn external_address_is_advertised_as_it_is() {
+        let (mut echo_node, _rx) = new_random_qp2p(false, Default::default());
+        let echo_info = unwrap!(echo_node.our_connection_info());
+        let external_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)), 5000);
+
+        let (tx, _rx) = mpmc::unbounded();
+        let mut qp2p = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                hard_coded_contacts: iter::once(echo_info).collect(),
+                external_address: Some(external_address),
+                ..Config::with_default_cert()
+            })
+            .build());
+
+        let our_info = unwrap!(qp2p.our_connection_info());
+        assert_eq!(our_info.peer_addr, external_address);
+        assert_eq!(our_info.peer_cert_der, qp2p.our_certificate_der());
+    }
+
+    #[test]
+    fn inconsistent_settings_fail_the_build() {
+        let client_cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            our_type: OurType::Client,
+            ..Config::with_default_cert()
+        };
+
+        let (tx, _rx) = mpmc::unbounded();
+        let built = Builder::new(tx)
+            .with_config(Config {
+                keep_alive_interval_msec: Some(1_000),
+                idle_timeout_msec: Some(1_000),
+                ..client_cfg.clone()
+            })
+            .build();
+        match built {
+            Err(Error::ConflictingSettings { setting, .. }) => {
+                assert_eq!(setting, "keep_alive_interval_msec")
+            }
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        let (tx, _rx) = mpmc::unbounded();
+        let built = Builder::new(tx)
+            .with_config(client_cfg.clone())
+            .with_client_forwarding(10)
+            .build();
+        match built {
+            Err(Error::ConflictingSettings {
+                setting,
+                conflicts_with,
+                ..
+            }) => assert_eq!(
+                (setting, conflicts_with),
+                ("with_client_forwarding", "our_type")
+            ),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        let (tx, _rx) = mpmc::unbounded();
+        let built = Builder::new(tx)
+            .with_config(client_cfg)
+            .with_probes(Duration::from_secs(0), 3)
+            .build();
+        match built {
+            Err(Error::InvalidSetting { setting, .. }) => assert_eq!(setting, "with_probes"),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        #[cfg(feature = "chaos")]
+        {
+            let (tx, _rx) = mpmc::unbounded();
+            let built = Builder::new(tx)
+                .with_chaos(Chaos {
+                    fail_sends: 1.5,
+                    ..Default::default()
+                })
+                .build();
+            match built {
+                Err(Error::InvalidSetting { setting, .. }) => assert_eq!(setting, "with_chaos"),
+                r => panic!("Unexpected result {:?}", r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn config_changes_are_applied_at_runtime() {
+        let (mut node, _node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            external_address: Some(([203, 0, 113, 7], 5000).into()),
+            ..Config::with_default_cert()
+        };
+        let (tx, _rx) = mpmc::unbounded();
+        let mut qp2p = unwrap!(Builder::new(tx)
+            .with_config(cfg.clone())
+            .with_proxies(Default::default(), true)
+            .build());
+        let our_info = unwrap!(qp2p.our_connection_info());
+        assert!(!unwrap!(qp2p.bootstrap_with_report()).is_successful());
+
+        let external_address = ([203, 0, 113, 8], 5000).into();
+        let changes = unwrap!(qp2p.apply_config(Config {
+            hard_coded_contacts: iter::once(node_info.clone()).collect(),
+            port: Some(5000),
+            external_address: Some(external_address),
+            ..cfg.clone()
+        }));
+        assert_eq!(
+            changes,
+            ConfigChanges {
+                applied: vec!["hard_coded_contacts", "external_address"],
+                needs_restart: vec!["port"],
+            }
+        );
+        assert!(qp2p.is_hard_coded_contact(&node_info));
+        assert_eq!(
+            unwrap!(qp2p.our_connection_info()),
+            NodeInfo {
+                peer_addr: external_address,
+                ..our_info
+            }
+        );
+        assert!(unwrap!(qp2p.bootstrap_with_report()).is_successful());
+
+        // Nothing is applied if the outcome is rejected
+        match qp2p.apply_config(Config {
+            max_msg_size_allowed: Some(0),
+            ..cfg
+        }) {
+            Err(Error::InvalidSetting { setting, .. }) => {
+                assert_eq!(setting, "max_msg_size_allowed")
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(qp2p.is_hard_coded_contact(&node_info));
+    }
+
+    #[cfg(feature = "config-watch")]
+    #[test]
+    fn config_file_changes_are_reported() {
+        let dir = tmp_rand_dir();
+        unwrap!(std::fs::create_dir_all(&dir));
+        let path = dir.join("config.json");
+        let cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Config::with_default_cert()
+        };
+        unwrap!(cfg.save_to(&path, Format::Json));
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut qp2p = unwrap!(Builder::new(tx)
+            .with_config(cfg.clone())
+            .with_config_watch(path.clone(), Duration::from_millis(20))
+            .build());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+
+        unwrap!(Config {
+            ordered_delivery: true,
+            ..cfg
+        }
+        .save_to(&path, Format::Json));
+        let changed = match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::ConfigFileChanged { path: changed }) => changed,
+            r => panic!("Unexpected result {:?}", r),
+        };
+        assert_eq!(changed, path);
+
+        let changes = unwrap!(qp2p.apply_config(unwrap!(Config::from_file(&changed))));
+        assert_eq!(changes.applied, vec!["ordered_delivery"]);
+        assert!(changes.needs_restart.is_empty());
+    }
+
+    #[test]
+    fn files_are_kept_in_the_dirs_overridden() {
+        let root = tmp_rand_dir();
+        let overrides = DirOverrides {
+            config_dir: Some(root.join("config")),
+            cache_dir: Some(root.join("cache")),
+            keys_dir: Some(root.join("keys")),
+            logs_dir: Some(root.join("logs")),
+        };
+        // Without a certificate, so that the persistent identity is used
+        unwrap!(std::fs::create_dir_all(root.join("config")));
+        let cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        };
+        unwrap!(cfg.save_to(&root.join("config").join("config.json"), Format::Json));
+        let build = || {
+            let (tx, _rx) = mpmc::unbounded();
+            unwrap!(Builder::new(tx)
+                .with_dir_overrides(overrides.clone())
+                .build())
+        };
+
+        let mut qp2p = build();
+        assert!(root.join("cache").exists());
+        assert!(root.join("keys").join("identity").exists());
+        let cert_der = qp2p.our_certificate_der();
+        drop(qp2p);
+
+        assert_eq!(build().our_certificate_der(), cert_der);
+    }
+
+    #[test]
+    fn ephemeral_instances_keep_nothing_on_disk() {
+        // Without a certificate, so that a persistent identity would be used
+        let build = || {
+            let (tx, rx) = mpmc::unbounded();
+            let qp2p = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Default::default()
+                })
+                .ephemeral()
+                .build());
+            (qp2p, rx)
+        };
+
+        let (mut node, node_rx) = build();
+        let (mut peer, _peer_rx) = build();
+        assert_ne!(node.our_certificate_der(), peer.our_certificate_der());
+        assert!(node.dirs.is_none() && node.dir_lock.is_none());
+
+        let peer_info = unwrap!(peer.our_connection_info());
+        peer.send(
+            unwrap!(node.our_connection_info()).into(),
+            From::from(&b"ping"[..]),
+        );
+        let from = node_rx.iter().find_map(|event| match event {
+            Event::NewMessage { peer_addr, .. } => Some(peer_addr),
+            _ => None,
+        });
+        assert_eq!(from, Some(peer_info.peer_addr));
+
+        let root = tmp_rand_dir();
+        let (tx, _rx) = mpmc::unbounded();
+        let built = Builder::new(tx)
+            .with_dir_overrides(DirOverrides {
+                cache_dir: Some(root.join("cache")),
+                ..Default::default()
+            })
+            .ephemeral()
+            .build();
+        match built {
+            Err(Error::ConflictingSettings {
+                setting,
+                conflicts_with,
+                ..
+            }) => assert_eq!(
+                (setting, conflicts_with),
+                ("ephemeral", "with_dir_overrides")
+            ),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+        assert!(!root.join("cache").exists());
+    }
+
+    #[test]
+    fn expiring_certificates_are_renewed_for_the_next_restart() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static RENEWED: AtomicBool = AtomicBool::new(false);
+        fn renew() -> SerialisableCertificate {
+            RENEWED.store(true, Ordering::SeqCst);
+            SerialisableCertificate::default()
+        }
+
+        let root = tmp_rand_dir();
+        let overrides = DirOverrides {
+            config_dir: Some(root.join("config")),
+            cache_dir: Some(root.join("cache")),
+            keys_dir: Some(root.join("keys")),
+            ..Default::default()
+        };
+        let build = |warn_ahead| {
+            let (tx, rx) = mpmc::unbounded();
+            let qp2p = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Default::default()
+                })
+                .with_dir_overrides(overrides.clone())
+                .with_cert_expiry_monitoring(Duration::from_secs(1), warn_ahead, Some(renew))
+                .build());
+            (qp2p, rx)
+        };
+
+        // Generated certificates are valid for millennia, so only warn that far ahead
+        let millennia = Duration::from_secs(3_000 * 365 * 24 * 3_600);
+        let (mut qp2p, rx) = build(millennia);
+        let cert_der = qp2p.our_certificate_der();
+        match rx.recv() {
+            Ok(Event::CertificateExpiring { peer_addr, when }) => {
+                assert_eq!(peer_addr, None);
+                assert!(when > SystemTime::now());
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let renewed = match rx.recv() {
+            Ok(Event::CertificateRenewed { cert }) => cert,
+            r => panic!("Unexpected result {:?}", r),
+        };
+        assert!(RENEWED.load(Ordering::SeqCst));
+        assert_ne!(renewed.cert_der, cert_der);
+        // Served until we restart
+        assert_eq!(qp2p.our_certificate_der(), cert_der);
+        drop(qp2p);
+
+        let (mut qp2p, rx) = build(Duration::from_secs(60));
+        assert_eq!(qp2p.our_certificate_der(), renewed.cert_der);
+        match rx.recv_timeout(Duration::from_millis(500)) {
+            Err(mpmc::RecvTimeoutError::Timeout) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[cfg(not(feature = "client-only"))]
+    #[test]
+    fn identity_and_bootstrap_cache_are_restored_together() {
+        let root = tmp_rand_dir();
+        let overrides = DirOverrides {
+            config_dir: Some(root.join("config")),
+            cache_dir: Some(root.join("cache")),
+            keys_dir: Some(root.join("keys")),
+            logs_dir: Some(root.join("logs")),
+        };
+        let cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        };
+        // Left behind by a run which didn't restore
+        let cached_peer = rand_node_info();
+        unwrap!(std::fs::create_dir_all(root.join("cache")));
+        unwrap!(persistence::write_to_disk(
+            &root.join("cache").join("bootstrap_cache"),
+            &iter::once(cached_peer.clone()).collect::<VecDeque<_>>()
+        ));
+        let build = |cfg: Config| {
+            let (tx, _rx) = mpmc::unbounded();
+            Builder::new(tx)
+                .with_config(cfg)
+                .with_dir_overrides(overrides.clone())
+                .restore()
+                .build()
+        };
+
+        let mut qp2p = unwrap!(build(cfg.clone()));
+        assert!(root.join("keys").join("state").exists());
+        assert_eq!(unwrap!(qp2p.bootstrap_cache()), vec![cached_peer.clone()]);
+        let cert_der = qp2p.our_certificate_der();
+        drop(qp2p);
+
+        unwrap!(std::fs::remove_file(
+            root.join("cache").join("bootstrap_cache")
+        ));
+        let mut qp2p = unwrap!(build(cfg.clone()));
+        assert_eq!(qp2p.our_certificate_der(), cert_der);
+        assert_eq!(unwrap!(qp2p.bootstrap_cache()), vec![cached_peer]);
+        drop(qp2p);
+
+        match build(Config::with_default_cert()) {
+            Err(Error::ConflictingSettings {
+                setting,
+                conflicts_with,
+                ..
+            }) => assert_eq!((setting, conflicts_with), ("restore", "our_complete_cert")),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
     #[test]
     fn echo_service() {
         let (mut qp2p0, _rx) = new_random_qp2p(false, Default::default());
@@ -511,7 +2687,7 @@ mod tests {
         qp2p2.send(qp2p1_info.into(), data.clone());
 
         match unwrap!(rx1.recv()) {
-            Event::ConnectedTo { peer } => assert_eq!(
+            Event::ConnectedTo { peer, .. } => assert_eq!(
                 peer,
                 Peer::Node {
                     node_info: qp2p2_info.clone()
@@ -520,7 +2696,7 @@ mod tests {
             x => panic!("Received unexpected event: {:?}", x),
         }
         match unwrap!(rx1.recv()) {
-            Event::NewMessage { peer_addr, msg } => {
+            Event::NewMessage { peer_addr, msg, .. } => {
                 assert_eq!(peer_addr, qp2p2_info.peer_addr);
                 assert_eq!(msg, data);
             }
@@ -563,6 +2739,7 @@ mod tests {
                 match rx0.recv() {
                     Ok(Event::ConnectedTo {
                         peer: Peer::Node { node_info },
+                        ..
                     }) => assert_eq!(node_info.peer_addr, qp2p1_addr),
                     Ok(x) => panic!("Expected Event::ConnectedTo - got {:?}", x),
                     Err(e) => panic!(
@@ -572,7 +2749,7 @@ mod tests {
                 };
                 for i in 0..3 {
                     match rx0.recv() {
-                        Ok(Event::NewMessage { peer_addr, msg }) => {
+                        Ok(Event::NewMessage { peer_addr, msg, .. }) => {
                             assert_eq!(peer_addr, qp2p1_addr);
                             if i != 2 {
                                 assert!(
@@ -599,6 +2776,7 @@ mod tests {
                 match rx1.recv() {
                     Ok(Event::ConnectedTo {
                         peer: Peer::Node { node_info },
+                        ..
                     }) => assert_eq!(node_info.peer_addr, qp2p0_addr),
                     Ok(x) => panic!("Expected Event::ConnectedTo - got {:?}", x),
                     Err(e) => panic!(
@@ -607,7 +2785,7 @@ mod tests {
                     ),
                 };
                 match rx1.recv() {
-                    Ok(Event::NewMessage { peer_addr, msg }) => {
+                    Ok(Event::NewMessage { peer_addr, msg, .. }) => {
                         assert_eq!(peer_addr, qp2p0_addr);
                         assert_eq!(msg, msg_to_qp2p1_clone);
                     }
@@ -649,10 +2827,14 @@ mod tests {
                 ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
                 ..Default::default()
             })
+            .with_persistent_identity(false)
             .build());
         malicious_client.send_wire_msg(
             qp2p0_info.clone().into(),
-            WireMsg::Handshake(Handshake::Client),
+            WireMsg::Handshake(Handshake::Client {
+                capabilities: Default::default(),
+                protocol_version: PROTOCOL_VERSION,
+            }),
         );
 
         let malicious_client_info = unwrap!(malicious_client.our_connection_info());
@@ -660,6 +2842,7 @@ mod tests {
         match rx0.recv() {
             Ok(Event::ConnectedTo {
                 peer: Peer::Node { node_info },
+                ..
             }) => {
                 assert_eq!(node_info.peer_addr, malicious_client_info.peer_addr);
             }
@@ -668,6 +2851,7 @@ mod tests {
         match rx1.recv() {
             Ok(Event::ConnectedTo {
                 peer: Peer::Node { node_info },
+                ..
             }) => {
                 assert_eq!(node_info.peer_addr, qp2p0_info.peer_addr);
             }
@@ -709,10 +2893,15 @@ mod tests {
                 ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
                 ..Default::default()
             })
+            .with_persistent_identity(false)
             .build());
         malicious_client.send_wire_msg(
             qp2p0_info.clone().into(),
-            WireMsg::Handshake(Handshake::Node { cert_der: vec![] }),
+            WireMsg::Handshake(Handshake::Node {
+                cert_der: vec![],
+                capabilities: Default::default(),
+                protocol_version: PROTOCOL_VERSION,
+            }),
         );
 
         let malicious_client_info = unwrap!(malicious_client.our_connection_info());
@@ -720,6 +2909,7 @@ mod tests {
         match rx0.recv() {
             Ok(Event::ConnectedTo {
                 peer: Peer::Client { .. },
+                ..
             }) => {}
             r => panic!("Unexpected result {:?}", r),
         }
@@ -746,6 +2936,7 @@ mod tests {
         peer.connect_to(NodeInfo {
             peer_addr: invalid_socket_addr,
             peer_cert_der: Default::default(),
+            alt_addrs: Vec::new(),
         });
 
         match rx.recv() {
@@ -757,38 +2948,3501 @@ mod tests {
     }
 
     #[test]
-    fn connect_to_marks_that_we_attempted_to_contact_the_peer() {
-        let (mut peer1, _) = new_random_qp2p(false, Default::default());
-        let peer1_conn_info = unwrap!(peer1.our_connection_info());
-        let peer1_addr = peer1_conn_info.peer_addr;
+    fn dual_stack_node_serves_both_address_families() {
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let (tx, rx) = mpsc::channel();
+        node.el.post(move || {
+            let port = ctx(|c| unwrap!(c.transport.local_addr()).port());
+            unwrap!(tx.send(port));
+        });
+        let node_port = unwrap!(rx.recv());
+        let node_cert_der = node.our_certificate_der();
 
-        let (mut peer2, ev_rx) = new_random_qp2p(false, Default::default());
-        peer2.connect_to(peer1_conn_info);
+        for &ip in &[
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ] {
+            let (tx, peer_rx) = mpmc::unbounded();
+            let mut peer = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(ip),
+                    ..Config::with_default_cert()
+                })
+                .build());
+            let peer_info = unwrap!(peer.our_connection_info());
+            let node_info = NodeInfo {
+                peer_addr: SocketAddr::new(ip, node_port),
+                peer_cert_der: node_cert_der.clone(),
+                alt_addrs: Vec::new(),
+            };
 
-        for event in ev_rx.iter() {
-            if let Event::ConnectedTo { .. } = event {
-                break;
+            peer.send(node_info.clone().into(), From::from(&b"ping"[..]));
+            // The node knows IPv4 peers by their plain IPv4 address, not an IPv4-mapped one
+            let from = node_rx
+                .iter()
+                .find_map(|event| match event {
+                    Event::NewMessage { peer_addr, msg, .. } => Some((peer_addr, msg)),
+                    _ => None,
+                })
+                .map(|(peer_addr, msg)| (peer_addr, msg.to_vec()));
+            assert_eq!(from, Some((peer_info.peer_addr, b"ping".to_vec())));
+
+            node.send(peer_info.into(), From::from(&b"pong"[..]));
+            let from = peer_rx
+                .iter()
+                .find_map(|event| match event {
+                    Event::NewMessage { peer_addr, msg, .. } => Some((peer_addr, msg)),
+                    _ => None,
+                })
+                .map(|(peer_addr, msg)| (peer_addr, msg.to_vec()));
+            assert_eq!(from, Some((node_info.peer_addr, b"pong".to_vec())));
+        }
+    }
+
+    #[test]
+    fn mapped_addresses_are_normalised() {
+        let mapped: SocketAddr = unwrap!("[::ffff:127.0.0.1]:5000".parse());
+        let v4: SocketAddr = unwrap!("127.0.0.1:5000".parse());
+        let v6: SocketAddr = unwrap!("[::1]:5000".parse());
+
+        assert_eq!(utils::normalise_addr(mapped), v4);
+        assert_eq!(utils::normalise_addr(v4), v4);
+        assert_eq!(utils::normalise_addr(v6), v6);
+    }
+
+    #[test]
+    fn connect_to_any_falls_back_to_the_working_address_family() {
+        let (mut node, _node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+        // Nothing listens on IPv6, so that attempt hangs until it's cancelled
+        let broken_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1);
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let started = Instant::now();
+        unwrap!(peer.connect_to_any(
+            &[broken_addr, node_info.peer_addr][..],
+            node_info.peer_cert_der.clone()
+        ));
+
+        for event in rx.iter() {
+            match event {
+                Event::ConnectedTo {
+                    peer: Peer::Node { node_info: info },
+                    ..
+                } => {
+                    assert_eq!(info, node_info);
+                    break;
+                }
+                Event::ConnectionFailure { .. } => panic!("Unexpected event {:?}", event),
+                _ => (),
             }
         }
+        assert!(started.elapsed() < Duration::from_secs(5));
 
-        let (tx, rx) = mpsc::channel();
-        peer2.el.post(move || {
-            let contacted = ctx(|c| unwrap!(c.connections.get(&peer1_addr)).we_contacted_peer);
-            let _ = tx.send(contacted);
-        });
-        let we_contacted_peer = unwrap!(rx.recv());
+        let node_addr = node_info.peer_addr;
+        let connected_to = unwrap!(peer.connections(|c| c.keys().cloned().collect::<Vec<_>>()));
+        assert_eq!(connected_to, vec![node_addr]);
+        let contacted = unwrap!(peer.connections(move |c| c[&node_addr].we_contacted_peer));
+        assert!(contacted);
+    }
 
-        assert!(we_contacted_peer);
+    #[test]
+    fn nodes_are_reached_at_their_lan_address_first() {
+        let (mut node, _node_rx) = new_random_qp2p(false, Default::default());
+        let lan_info = unwrap!(node.our_connection_info());
+        // Nothing is reachable at TEST-NET-3, so an attempt there hangs until it's cancelled
+        let node_info = NodeInfo {
+            peer_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)), 5000),
+            peer_cert_der: lan_info.peer_cert_der.clone(),
+            alt_addrs: vec![lan_info.peer_addr],
+        };
+
+        let (mut peer, rx) = new_random_qp2p(false, Default::default());
+        let started = Instant::now();
+        peer.connect_to(node_info);
+
+        for event in rx.iter() {
+            match event {
+                Event::ConnectedTo {
+                    peer: Peer::Node { node_info: info },
+                    ..
+                } => {
+                    assert_eq!(info.peer_addr, lan_info.peer_addr);
+                    break;
+                }
+                Event::ConnectionFailure { .. } => panic!("Unexpected event {:?}", event),
+                _ => (),
+            }
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let lan_addr = lan_info.peer_addr;
+        let connected_to = unwrap!(peer.connections(|c| c.keys().cloned().collect::<Vec<_>>()));
+        assert_eq!(connected_to, vec![lan_addr]);
+        let contacted = unwrap!(peer.connections(move |c| c[&lan_addr].we_contacted_peer));
+        assert!(contacted);
     }
 
     #[test]
-    fn is_hard_coded_contact() {
-        let contact0 = rand_node_info();
-        let contact1 = rand_node_info();
+    fn connect_to_any_reports_the_failure_of_all_attempts() {
+        let addrs = [
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 1),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1),
+        ];
+        let (mut peer, rx) = new_random_qp2p(false, Default::default());
+        // Connecting fails straight away without a certificate to trust
+        unwrap!(peer.connect_to_any(&addrs[..], vec![]));
 
-        let (peer, _) = new_random_qp2p(false, iter::once(contact0.clone()).collect());
+        match rx.recv() {
+            Ok(Event::ConnectionFailure { peer_addr, .. }) => assert_eq!(peer_addr, addrs[0]),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(unwrap!(peer.connections(|c| c.is_empty())));
 
-        assert!(peer.is_hard_coded_contact(&contact0));
-        assert!(!peer.is_hard_coded_contact(&contact1));
+        let no_addrs: &[SocketAddr] = &[];
+        match peer.connect_to_any(no_addrs, vec![]) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::AddrNotAvailable => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn connections_are_relayed_through_the_socks5_proxy() {
+        let (mut node, node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                socks5_proxy: Some(test_utils::spawn_socks5_proxy()),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let client_addr = unwrap!(client.our_connection_info()).peer_addr;
+
+        client.send(node_info.clone().into(), From::from(&b"ping"[..]));
+        // The node hears from the proxy's relay rather than from the client itself
+        let relay_addr = unwrap!(node_rx.iter().find_map(|event| match event {
+            Event::NewMessage { peer_addr, msg, .. } => {
+                assert_eq!(&msg[..], b"ping");
+                Some(peer_addr)
+            }
+            _ => None,
+        }));
+        assert_ne!(relay_addr, client_addr);
+
+        node.send(
+            Peer::Client {
+                peer_addr: relay_addr,
+            },
+            From::from(&b"pong"[..]),
+        );
+        let from = client_rx
+            .iter()
+            .find_map(|event| match event {
+                Event::NewMessage { peer_addr, msg, .. } => Some((peer_addr, msg)),
+                _ => None,
+            })
+            .map(|(peer_addr, msg)| (peer_addr, msg.to_vec()));
+        assert_eq!(from, Some((node_info.peer_addr, b"pong".to_vec())));
+    }
+
+    #[test]
+    fn connected_to_tells_who_dialed() {
+        let (mut node0, rx0) = new_random_qp2p(false, Default::default());
+        let node0_info = unwrap!(node0.our_connection_info());
+        let (mut node1, rx1) = new_random_qp2p(false, Default::default());
+        let node1_info = unwrap!(node1.our_connection_info());
+
+        node0.connect_to(node1_info.clone());
+        match rx0.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                direction,
+                ..
+            }) => {
+                assert_eq!(node_info, node1_info);
+                assert_eq!(direction, ConnectionDirection::Outgoing);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // node1 reverse connects to node0, which doesn't make it the initiator
+        match rx1.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                direction,
+                ..
+            }) => {
+                assert_eq!(node_info, node0_info);
+                assert_eq!(direction, ConnectionDirection::Incoming);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+        client.connect_to(node0_info.clone());
+        match client_rx.recv() {
+            Ok(Event::ConnectedTo { direction, .. }) => {
+                assert_eq!(direction, ConnectionDirection::Outgoing)
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rx0.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Client { .. },
+                direction,
+                ..
+            }) => assert_eq!(direction, ConnectionDirection::Incoming),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn connected_to_reports_advertised_capabilities() {
+        let archive = Capabilities {
+            flags: 0b101,
+            role: "archive".to_string(),
+        };
+        let (tx0, rx0) = mpmc::unbounded();
+        let mut node0 = unwrap!(Builder::new(tx0)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_capabilities(archive.clone())
+            .build());
+        let (mut node1, rx1) = new_random_qp2p(false, Default::default());
+        let node1_info = unwrap!(node1.our_connection_info());
+
+        node0.connect_to(node1_info.clone());
+        match rx1.recv() {
+            Ok(Event::ConnectedTo { capabilities, .. }) => assert_eq!(capabilities, archive),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // node1 only introduces itself on the connection it made back to node0
+        match rx0.recv() {
+            Ok(Event::ConnectedTo { capabilities, .. }) => assert!(capabilities.is_empty()),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let light = Capabilities {
+            flags: 0,
+            role: "light".to_string(),
+        };
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .with_capabilities(light.clone())
+            .build());
+        client.connect_to(node1_info);
+        match client_rx.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rx1.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Client { .. },
+                capabilities,
+                ..
+            }) => {
+                assert_eq!(capabilities, light);
+                assert!(!capabilities.has_flags(0b1));
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn restarted_peer_replaces_its_connection_under_keep_incoming() {
+        let (mut node0, rx0, node1_addr, mut node1, rx1) =
+            redial_from_restarted_peer(DuplicateConnectionPolicy::KeepIncoming);
+        let node1_info = unwrap!(node1.our_connection_info());
+
+        match rx0.recv() {
+            Ok(Event::DuplicateConnectionDropped { peer_addr, dropped }) => {
+                assert_eq!(peer_addr, node1_addr);
+                assert_eq!(dropped, DroppedConnection::Existing);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rx0.recv() {
+            Ok(Event::ConnectionFailure { peer_addr, .. }) => assert_eq!(peer_addr, node1_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // The restarted peer is set up anew, certificate and all
+        match rx0.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                ..
+            }) => assert_eq!(node_info, node1_info),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rx1.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        node1.send(
+            unwrap!(node0.our_connection_info()).into(),
+            From::from(&b"hi"[..]),
+        );
+        match rx0.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, node1_addr);
+                assert_eq!(&msg[..], b"hi");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn restarted_peer_is_turned_away_under_keep_existing() {
+        let (_node0, rx0, node1_addr, _node1, rx1) =
+            redial_from_restarted_peer(DuplicateConnectionPolicy::KeepExisting);
+
+        match rx0.recv() {
+            Ok(Event::DuplicateConnectionDropped { peer_addr, dropped }) => {
+                assert_eq!(peer_addr, node1_addr);
+                assert_eq!(dropped, DroppedConnection::New);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(rx1.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn peers_are_followed_to_their_new_address() {
+        let (mut node0, rx0) = new_random_qp2p(false, Default::default());
+        let node0_info = unwrap!(node0.our_connection_info());
+        let node1_cfg = Config {
+            port: Some(0),
+            ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            ..Config::with_default_cert()
+        };
+        let (tx1, rx1) = mpmc::unbounded();
+        let mut node1 = unwrap!(Builder::new(tx1).with_config(node1_cfg.clone()).build());
+        let old_info = unwrap!(node1.our_connection_info());
+
+        node0.connect_to(old_info.clone());
+        for rx in &[&rx0, &rx1] {
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+        drop(node1);
+
+        // Same certificate, another port
+        let (tx1, rx1) = mpmc::unbounded();
+        let mut node1 = unwrap!(Builder::new(tx1).with_config(node1_cfg).build());
+        let new_info = unwrap!(node1.our_connection_info());
+        assert_ne!(new_info.peer_addr, old_info.peer_addr);
+        node1.connect_to(node0_info.clone());
+
+        match rx0.recv() {
+            Ok(Event::PeerAddressChanged { old, new }) => {
+                assert_eq!(old, old_info.peer_addr);
+                assert_eq!(new, new_info.peer_addr);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rx1.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        node1.send(node0_info.into(), From::from(&b"moved"[..]));
+        match rx0.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, new_info.peer_addr);
+                assert_eq!(&msg[..], b"moved");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let cache = unwrap!(node0.bootstrap_cache());
+        assert!(cache.contains(&new_info));
+        assert!(!cache.contains(&old_info));
+    }
+
+    #[test]
+    fn peers_claiming_the_certificate_of_another_are_not_followed() {
+        let (mut node0, rx0) = new_random_qp2p(false, Default::default());
+        let node0_info = unwrap!(node0.our_connection_info());
+        let (mut node1, rx1) = new_random_qp2p(false, Default::default());
+        let node1_info = unwrap!(node1.our_connection_info());
+
+        node0.connect_to(node1_info.clone());
+        for rx in &[&rx0, &rx1] {
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        // The certificate of a node is public, but the impostor can't prove to hold it
+        let (tx, _rx) = mpmc::unbounded();
+        let mut impostor = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                our_type: OurType::Client,
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .build());
+        impostor.send_wire_msg(
+            node0_info.into(),
+            WireMsg::Handshake(Handshake::Node {
+                cert_der: node1_info.peer_cert_der.clone(),
+                capabilities: Default::default(),
+                protocol_version: PROTOCOL_VERSION,
+            }),
+        );
+        match rx0.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Client { .. },
+                ..
+            }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        std::thread::sleep(Duration::from_millis(500));
+
+        while let Ok(event) = rx0.try_recv() {
+            if let Event::PeerAddressChanged { .. } = event {
+                panic!("Unexpected event {:?}", event);
+            }
+        }
+        let node1_addr = node1_info.peer_addr;
+        let is_connected = unwrap!(node0.connections(move |c| c.contains_key(&node1_addr)));
+        assert!(is_connected);
+        assert!(unwrap!(node0.bootstrap_cache()).contains(&node1_info));
+    }
+
+    #[test]
+    fn nodes_accept_connections_on_all_their_listen_addrs() {
+        let (tx0, rx0) = mpmc::unbounded();
+        let mut node0 = unwrap!(Builder::new(tx0)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                additional_listen_addrs: vec![(Ipv4Addr::LOCALHOST, 0).into()],
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node0_info = unwrap!(node0.our_connection_info());
+        let listen_addrs = unwrap!(node0.our_listen_addrs());
+        assert_eq!(listen_addrs.len(), 2);
+        assert_eq!(listen_addrs[0], node0_info.peer_addr);
+        assert_ne!(listen_addrs[1].port(), 0);
+
+        // The peers only complete their connections if we connect back to them from the address
+        // they dialed
+        let mut peers = Vec::new();
+        for listen_addr in listen_addrs {
+            let (mut peer, peer_rx) = new_random_qp2p(false, Default::default());
+            let peer_addr = unwrap!(peer.our_connection_info()).peer_addr;
+            let contact = NodeInfo {
+                peer_addr: listen_addr,
+                peer_cert_der: node0_info.peer_cert_der.clone(),
+                alt_addrs: Vec::new(),
+            };
+            peer.connect_to(contact.clone());
+            for rx in &[&peer_rx, &rx0] {
+                match rx.recv() {
+                    Ok(Event::ConnectedTo { .. }) => (),
+                    r => panic!("Unexpected result {:?}", r),
+                }
+            }
+
+            peer.send(contact.into(), From::from(&b"hello"[..]));
+            match rx0.recv() {
+                Ok(Event::NewMessage {
+                    peer_addr: from,
+                    msg,
+                    ..
+                }) => {
+                    assert_eq!(from, peer_addr);
+                    assert_eq!(&msg[..], b"hello");
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+            peers.push((peer, peer_rx));
+        }
+
+        // Both peers end up in the same connection table
+        let connected =
+            unwrap!(node0.connections(|c| c.values().filter(|c| c.is_complete()).count()));
+        assert_eq!(connected, 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn nodes_listen_on_the_interface_named() {
+        let (tx0, rx0) = mpmc::unbounded();
+        let mut node0 = unwrap!(Builder::new(tx0)
+            .with_config(Config {
+                port: Some(0),
+                bind_interface: Some("lo".to_string()),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node0_info = unwrap!(node0.our_connection_info());
+        assert_eq!(node0_info.peer_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(unwrap!(node0.our_listen_addrs())
+            .iter()
+            .all(|addr| addr.ip().is_loopback()));
+
+        let (mut node1, rx1) = new_random_qp2p(false, Default::default());
+        node1.connect_to(node0_info.clone());
+        for rx in &[&rx1, &rx0] {
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+
+        let (tx, _rx) = mpmc::unbounded();
+        let built = Builder::new(tx)
+            .with_config(Config {
+                bind_interface: Some("no-such-interface".to_string()),
+                ..Config::with_default_cert()
+            })
+            .build();
+        match built {
+            Err(Error::Config { field, .. }) => assert_eq!(field, "bind_interface"),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn binding_to_a_taken_port_fails_to_build() {
+        let (mut node, _rx) = new_random_qp2p(false, Default::default());
+        let taken = unwrap!(node.our_listen_addrs())[0];
+
+        let (tx, _rx) = mpmc::unbounded();
+        let built = Builder::new(tx)
+            .with_config(Config {
+                ip: Some(taken.ip()),
+                port: Some(taken.port()),
+                ..Config::with_default_cert()
+            })
+            .build();
+        match built {
+            Err(Error::Config { field, .. }) => assert_eq!(field, "port"),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    /// A node and a client connected to it.
+    fn connected_client() -> (
+        QuicP2p,
+        mpmc::Receiver<Event>,
+        NodeInfo,
+        QuicP2p,
+        mpmc::Receiver<Event>,
+    ) {
+        let (mut node, node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+
+        client.connect_to(node_info.clone());
+        for rx in &[&client_rx, &node_rx] {
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+        (node, node_rx, node_info, client, client_rx)
+    }
+
+    #[test]
+    fn clients_carry_on_from_a_new_socket_after_migrating() {
+        let (mut node, node_rx, node_info, mut client, client_rx) = connected_client();
+        let old_addr = unwrap!(client.our_connection_info()).peer_addr;
+
+        let new_addr = unwrap!(client.migrate());
+        assert_ne!(new_addr, old_addr);
+        assert_eq!(new_addr.ip(), old_addr.ip());
+        match client_rx.recv() {
+            Ok(Event::ConnectionMigrated { peer_addr }) => {
+                assert_eq!(peer_addr, node_info.peer_addr)
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        client.send(node_info.clone().into(), From::from(&b"migrated"[..]));
+        match node_rx.recv() {
+            // The node keeps knowing the client by the address it connected from
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, old_addr);
+                assert_eq!(&msg[..], b"migrated");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        node.send(
+            Peer::Client {
+                peer_addr: old_addr,
+            },
+            From::from(&b"welcome"[..]),
+        );
+        match client_rx.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, node_info.peer_addr);
+                assert_eq!(&msg[..], b"welcome");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match node.migrate() {
+            Err(Error::OperationNotAllowed) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn connections_to_vanished_nodes_fail_to_migrate() {
+        let (node, _node_rx, node_info, mut client, client_rx) = connected_client();
+        // Gone without closing the connection
+        drop(node);
+
+        let _ = unwrap!(client.migrate());
+        match client_rx.recv() {
+            Ok(Event::MigrationFailed { peer_addr }) => assert_eq!(peer_addr, node_info.peer_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match client_rx.recv() {
+            Ok(Event::ConnectionFailure { peer_addr, .. }) => {
+                assert_eq!(peer_addr, node_info.peer_addr)
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peers_of_refused_protocol_versions_are_told_so() {
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                min_protocol_version: Some(PROTOCOL_VERSION + 1),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node_info = unwrap!(node.our_connection_info());
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+
+        client.connect_to(node_info.clone());
+        loop {
+            match client_rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                Ok(Event::ConnectionFailure { peer_addr, err }) => {
+                    assert_eq!(peer_addr, node_info.peer_addr);
+                    match err {
+                        Error::ProtocolVersionRefused(version) => {
+                            assert_eq!(version, PROTOCOL_VERSION)
+                        }
+                        e => panic!("Unexpected error {:?}", e),
+                    }
+                    break;
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+        // The node never took the client on
+        match node_rx.try_recv() {
+            Err(mpmc::TryRecvError::Empty) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn nodes_of_refused_protocol_versions_are_told_so_by_clients() {
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node_info = unwrap!(node.our_connection_info());
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                min_protocol_version: Some(PROTOCOL_VERSION + 1),
+                ..Config::with_default_cert()
+            })
+            .build());
+
+        // Refused once the node replies to our handshake with its version
+        client.connect_to(node_info.clone());
+        let refused = client_rx.iter().find_map(|event| match event {
+            Event::ConnectionFailure { peer_addr, err } => Some((peer_addr, err)),
+            _ => None,
+        });
+        match refused {
+            Some((peer_addr, Error::ProtocolVersionRefused(version))) => {
+                assert_eq!(peer_addr, node_info.peer_addr);
+                assert_eq!(version, PROTOCOL_VERSION);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // The node is told so
+        let refused = node_rx.iter().find_map(|event| match event {
+            Event::ConnectionFailure { err, .. } => Some(err),
+            _ => None,
+        });
+        match refused {
+            Some(Error::ProtocolVersionRefused(version)) => assert_eq!(version, PROTOCOL_VERSION),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peers_of_a_full_subnet_are_refused() {
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                max_incoming_per_v4_subnet: Some(1),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node_info = unwrap!(node.our_connection_info());
+        let new_client = || {
+            let (tx, rx) = mpmc::unbounded();
+            let client = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    our_type: OurType::Client,
+                    ..Config::with_default_cert()
+                })
+                .build());
+            (client, rx)
+        };
+
+        let (mut client1, client1_rx) = new_client();
+        client1.connect_to(node_info.clone());
+        match unwrap!(node_rx.recv()) {
+            Event::ConnectedTo { .. } => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        for event in client1_rx.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        // All of 127.0.0.0/24 is taken up by the first client
+        let (mut client2, client2_rx) = new_client();
+        client2.connect_to(node_info.clone());
+        loop {
+            match unwrap!(client2_rx.recv()) {
+                Event::ConnectedTo { .. } => (),
+                Event::ConnectionClosed {
+                    peer_addr,
+                    reason: CloseReason::PeerClosed { error_code, .. },
+                } => {
+                    assert_eq!(peer_addr, node_info.peer_addr);
+                    assert_eq!(error_code, CONNECTION_LIMIT_CODE);
+                    break;
+                }
+                ev => panic!("Unexpected event: {:?}", ev),
+            }
+        }
+        match node_rx.try_recv() {
+            Err(mpmc::TryRecvError::Empty) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // There's room again once the first client is gone
+        client1.disconnect_from(node_info.peer_addr);
+        match unwrap!(node_rx.recv()) {
+            Event::ConnectionClosed { .. } => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        client2.connect_to(node_info.clone());
+        match unwrap!(node_rx.recv()) {
+            Event::ConnectedTo { .. } => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+
+    /// Connect a peer to a node with the given policy, then restart the peer on the same address
+    /// without it closing its connection and connect it to the node again.
+    fn redial_from_restarted_peer(
+        policy: DuplicateConnectionPolicy,
+    ) -> (
+        QuicP2p,
+        mpmc::Receiver<Event>,
+        SocketAddr,
+        QuicP2p,
+        mpmc::Receiver<Event>,
+    ) {
+        let (tx0, rx0) = mpmc::unbounded();
+        let mut node0 = unwrap!(Builder::new(tx0)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                duplicate_connection_policy: policy,
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node0_info = unwrap!(node0.our_connection_info());
+
+        let (mut node1, rx1) = new_random_qp2p(false, Default::default());
+        let node1_addr = unwrap!(node1.our_connection_info()).peer_addr;
+        node1.connect_to(node0_info.clone());
+        for rx in &[&rx1, &rx0] {
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        }
+        drop(node1);
+
+        let (tx1, rx1) = mpmc::unbounded();
+        let mut node1 = unwrap!(Builder::new(tx1)
+            .with_config(Config {
+                port: Some(node1_addr.port()),
+                ip: Some(node1_addr.ip()),
+                ..Config::with_default_cert()
+            })
+            .build());
+        node1.connect_to(node0_info);
+
+        (node0, rx0, node1_addr, node1, rx1)
+    }
+
+    #[test]
+    fn client_promoted_to_node_keeps_its_connections() {
+        let (mut node, node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+        let client_info = NodeInfo {
+            peer_addr: unwrap!(client.our_connection_info()).peer_addr,
+            peer_cert_der: client.our_certificate_der(),
+            alt_addrs: Vec::new(),
+        };
+
+        client.connect_to(node_info.clone());
+        match client_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info: peer },
+                ..
+            }) => assert_eq!(peer, node_info),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match node_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Client { peer_addr },
+                ..
+            }) => assert_eq!(peer_addr, client_info.peer_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        unwrap!(client.promote_to_node());
+        match node_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info: peer },
+                ..
+            }) => assert_eq!(peer, client_info),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let node_addr = node_info.peer_addr;
+        assert!(unwrap!(
+            client.connections(move |c| c[&node_addr].from_peer.is_established())
+        ));
+
+        // Messages keep flowing both ways
+        node.send(client_info.clone().into(), From::from(&b"ping"[..]));
+        match client_rx.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, node_info.peer_addr);
+                assert_eq!(&msg[..], b"ping");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        client.send(node_info.clone().into(), From::from(&b"pong"[..]));
+        match node_rx.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, client_info.peer_addr);
+                assert_eq!(&msg[..], b"pong");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // and others can connect to the former client now
+        let (mut other, other_rx) = new_random_qp2p(false, Default::default());
+        other.connect_to(client_info.clone());
+        match other_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info: peer },
+                ..
+            }) => assert_eq!(peer, client_info),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Nothing more is told about the node we were connected to already
+        match client_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info: peer },
+                ..
+            }) => assert_ne!(peer.peer_addr, node_info.peer_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peers_connect_back_on_request() {
+        let (mut requester, requester_rx) = new_random_qp2p(false, Default::default());
+        let (mut via, via_rx) = new_random_qp2p(false, Default::default());
+        let via_info = unwrap!(via.our_connection_info());
+
+        requester.connect_to(via_info.clone());
+        match requester_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                ..
+            }) => assert_eq!(node_info, via_info),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match via_rx.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Any endpoint on the requester's host will do
+        let (mut target, target_rx) = new_random_qp2p(false, Default::default());
+        let target_info = unwrap!(target.our_connection_info());
+        unwrap!(requester.request_connect_back(via_info.peer_addr, target_info.clone()));
+
+        match via_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                direction,
+                ..
+            }) => {
+                assert_eq!(node_info, target_info);
+                assert_eq!(direction, ConnectionDirection::Outgoing);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match target_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info },
+                direction,
+                ..
+            }) => {
+                assert_eq!(node_info, via_info);
+                assert_eq!(direction, ConnectionDirection::Incoming);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let (tx, _rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+        match client.request_connect_back(via_info.peer_addr, target_info) {
+            Err(Error::OperationNotAllowed) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peers_check_our_reachability_on_request() {
+        let (mut requester, requester_rx) = new_random_qp2p(false, Default::default());
+        let requester_info = unwrap!(requester.our_connection_info());
+        let (mut via, via_rx) = new_random_qp2p(false, Default::default());
+        let via_info = unwrap!(via.our_connection_info());
+
+        requester.connect_to(via_info.clone());
+        match requester_rx.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match via_rx.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        unwrap!(requester.check_reachability(via_info.peer_addr));
+        match requester_rx.recv() {
+            Ok(Event::ReachabilityChecked {
+                via_peer,
+                addr,
+                reachable,
+            }) => {
+                assert_eq!(via_peer, via_info.peer_addr);
+                assert_eq!(addr, requester_info.peer_addr);
+                assert!(reachable);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Nothing listens on a port freed again
+        let closed_port = unwrap!(unwrap!(UdpSocket::bind("127.0.0.1:0")).local_addr()).port();
+        let (tx, unreachable_rx) = mpmc::unbounded();
+        let mut unreachable = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                external_address: Some(SocketAddr::from((Ipv4Addr::LOCALHOST, closed_port))),
+                ..Config::with_default_cert()
+            })
+            .build());
+        unreachable.connect_to(via_info.clone());
+        match unreachable_rx.recv() {
+            Ok(Event::ConnectedTo { .. }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        unwrap!(unreachable.check_reachability(via_info.peer_addr));
+        match unreachable_rx.recv() {
+            Ok(Event::ReachabilityChecked {
+                addr, reachable, ..
+            }) => {
+                assert_eq!(addr.port(), closed_port);
+                assert!(!reachable);
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn peers_on_our_host_see_no_nat() {
+        let (mut qp2p, rx) = new_random_qp2p(false, Default::default());
+        let our_addr = unwrap!(qp2p.our_connection_info()).peer_addr;
+        let mut peers = Vec::new();
+        let mut peer_addrs = Vec::new();
+        for _ in 0..2 {
+            let (mut peer, peer_rx) = new_random_qp2p(false, Default::default());
+            let peer_info = unwrap!(peer.our_connection_info());
+            qp2p.connect_to(peer_info.clone());
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+            match peer_rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+            peer_addrs.push(peer_info.peer_addr);
+            peers.push(peer);
+        }
+
+        let report = unwrap!(qp2p.detect_nat(&peer_addrs));
+        assert_eq!(report.mapping, NatMapping::NoNat);
+        assert_eq!(report.observed.len(), 2);
+        assert!(report.observed.iter().all(|&(_, seen)| seen == our_addr));
+        assert_eq!(report.hairpinning, None);
+        assert!(!report.needs_relay());
+        assert!(!report.needs_hole_punching());
+    }
+
+    #[test]
+    fn nodes_forward_messages_between_their_clients() {
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_client_forwarding(10)
+            .build());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let new_client = || {
+            let (tx, rx) = mpmc::unbounded();
+            let mut client = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    our_type: OurType::Client,
+                    ..Config::with_default_cert()
+                })
+                .build());
+            client.connect_to(node_info.clone());
+            match rx.recv() {
+                Ok(Event::ConnectedTo { .. }) => (),
+                r => panic!("Unexpected result {:?}", r),
+            }
+            let client_addr = match node_rx.recv() {
+                Ok(Event::ConnectedTo {
+                    peer: Peer::Client { peer_addr },
+                    ..
+                }) => peer_addr,
+                r => panic!("Unexpected result {:?}", r),
+            };
+            (client, rx, client_addr)
+        };
+        let (mut alice, _alice_rx, alice_addr) = new_client();
+        let (_bob, bob_rx, bob_addr) = new_client();
+
+        alice.forward(node_info.peer_addr, bob_addr, From::from(&b"hi bob"[..]));
+        match bob_rx.recv() {
+            Ok(Event::ForwardedMessage {
+                relay_addr,
+                peer_addr,
+                msg,
+            }) => {
+                assert_eq!(relay_addr, node_info.peer_addr);
+                assert_eq!(peer_addr, alice_addr);
+                assert_eq!(&msg[..], b"hi bob");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn quorum_sends_report_failed_peers() {
+        let (mut qp2p, rx) = new_random_qp2p(false, Default::default());
+        let (mut peer0, _rx0) = new_random_qp2p(false, Default::default());
+        let (mut peer1, _rx1) = new_random_qp2p(false, Default::default());
+        let gone_client: SocketAddr = ([127, 0, 0, 1], 1).into();
+        let peers = vec![
+            unwrap!(peer0.our_connection_info()).into(),
+            unwrap!(peer1.our_connection_info()).into(),
+            Peer::Client {
+                peer_addr: gone_client,
+            },
+        ];
+
+        match qp2p.send_to_quorum(peers.clone(), From::from(&b"msg"[..]), 4) {
+            Err(Error::InvalidQuorum {
+                required: 4,
+                peers: 3,
+            }) => (),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let id = unwrap!(qp2p.send_to_quorum(peers.clone(), From::from(&b"msg"[..]), 2));
+        let resolved = rx.iter().find_map(|event| match event {
+            Event::QuorumSendResolved {
+                id,
+                reached,
+                delivered,
+                failed,
+            } => Some((id, reached, delivered, failed)),
+            _ => None,
+        });
+        match resolved {
+            Some((resolved_id, reached, mut delivered, failed)) => {
+                assert_eq!(resolved_id, id);
+                assert!(reached);
+                delivered.sort();
+                let mut expected = vec![peers[0].peer_addr(), peers[1].peer_addr()];
+                expected.sort();
+                assert_eq!(delivered, expected);
+                assert_eq!(failed, vec![gone_client]);
+            }
+            None => panic!("Quorum send wasn't resolved"),
+        }
+    }
+
+    #[test]
+    fn socks5_proxy_failures_are_reported() {
+        let proxy_addr = {
+            let listener = unwrap!(std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)));
+            unwrap!(listener.local_addr())
+        };
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                socks5_proxy: Some(proxy_addr),
+                ..Config::with_default_cert()
+            })
+            .build());
+        let node_info = rand_node_info();
+
+        peer.connect_to(node_info.clone());
+        match rx.recv() {
+            Ok(Event::ConnectionFailure {
+                peer_addr,
+                err: Error::Socks5Proxy(_),
+            }) => assert_eq!(peer_addr, node_info.peer_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(unwrap!(peer.connections(|c| c.is_empty())));
+    }
+
+    #[cfg(feature = "tcp-fallback")]
+    #[test]
+    fn clients_fall_back_to_tcp_when_udp_is_blocked() {
+        use crate::connection::FromPeer;
+        use crate::transport::tcp::TcpConn;
+
+        let (mut node, node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let (tx, client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                idle_timeout_msec: Some(500),
+                ..Config::with_default_cert()
+            })
+            .build());
+        client.block_udp();
+
+        client.send(node_info.clone().into(), From::from(&b"ping"[..]));
+        match client_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Node { node_info: peer },
+                ..
+            }) => assert_eq!(peer, node_info),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let client_addr = match node_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Client { peer_addr },
+                ..
+            }) => peer_addr,
+            r => panic!("Unexpected result {:?}", r),
+        };
+        match node_rx.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, client_addr);
+                assert_eq!(&msg[..], b"ping");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        node.send(
+            Peer::Client {
+                peer_addr: client_addr,
+            },
+            From::from(&b"pong"[..]),
+        );
+        match client_rx.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, node_info.peer_addr);
+                assert_eq!(&msg[..], b"pong");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // Nothing went over QUIC
+        let is_over_tcp = unwrap!(node.connections(move |c| match c[&client_addr].from_peer {
+            FromPeer::Established { ref q_conn, .. } => q_conn.as_any().is::<TcpConn>(),
+            _ => false,
+        }));
+        assert!(is_over_tcp);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    fn browser_clients_connect_over_websocket() {
+        use tokio_tungstenite::tungstenite::{
+            self,
+            protocol::{frame::coding::CloseCode, CloseFrame},
+            Message,
+        };
+
+        let websocket_port = {
+            let listener = unwrap!(std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)));
+            unwrap!(listener.local_addr()).port()
+        };
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                websocket_port: Some(websocket_port),
+                ..Config::with_default_cert()
+            })
+            .build());
+        // Make sure the node is listening by now
+        let _ = unwrap!(node.our_connection_info());
+
+        let url = unwrap!(url::Url::parse(&format!(
+            "ws://127.0.0.1:{}/",
+            websocket_port
+        )));
+        let stream = unwrap!(std::net::TcpStream::connect((
+            Ipv4Addr::LOCALHOST,
+            websocket_port
+        )));
+        let browser_addr = unwrap!(stream.local_addr());
+        let (mut ws, _) = unwrap!(tungstenite::client(url, stream));
+
+        match node_rx.recv() {
+            Ok(Event::ConnectedTo {
+                peer: Peer::Client { peer_addr },
+                ..
+            }) => assert_eq!(peer_addr, browser_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        unwrap!(ws.write_message(Message::Binary(b"ping".to_vec())));
+        match node_rx.recv() {
+            Ok(Event::NewMessage { peer_addr, msg, .. }) => {
+                assert_eq!(peer_addr, browser_addr);
+                assert_eq!(&msg[..], b"ping");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        node.send(
+            Peer::Client {
+                peer_addr: browser_addr,
+            },
+            From::from(&b"pong"[..]),
+        );
+        assert_eq!(
+            unwrap!(ws.read_message()),
+            Message::Binary(b"pong".to_vec())
+        );
+
+        unwrap!(ws.close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        })));
+        while ws.read_message().is_ok() {}
+        match node_rx.recv() {
+            Ok(Event::ConnectionClosed {
+                peer_addr,
+                reason: CloseReason::PeerClosed { error_code, reason },
+            }) => {
+                assert_eq!(peer_addr, browser_addr);
+                assert_eq!(error_code, 1000);
+                assert_eq!(&reason[..], b"bye");
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    fn peers_communicate_over_the_memory_transport() {
+        let network = MemoryTransport::new();
+        let (mut peer1, ev_rx1) = new_qp2p_on(&network);
+        let peer1_info = unwrap!(peer1.our_connection_info());
+        let (mut peer2, ev_rx2) = new_qp2p_on(&network);
+        let peer2_addr = unwrap!(peer2.our_connection_info()).peer_addr;
+        assert_ne!(peer1_info.peer_addr, peer2_addr);
+
+        peer2.send(peer1_info.clone().into(), From::from(&b"ping"[..]));
+        match unwrap!(ev_rx2.recv()) {
+            Event::ConnectedTo { peer, .. } => assert_eq!(peer.peer_addr(), peer1_info.peer_addr),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(ev_rx1.recv()) {
+            Event::ConnectedTo { peer, .. } => assert_eq!(peer.peer_addr(), peer2_addr),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(ev_rx1.recv()) {
+            Event::NewMessage { peer_addr, msg, .. } => {
+                assert_eq!(peer_addr, peer2_addr);
+                assert_eq!(&msg[..], b"ping");
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        // Held back messages arrive only once they are let through
+        network.pause();
+        peer2.send(peer1_info.clone().into(), From::from(&b"pong"[..]));
+        assert!(ev_rx1.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(network.pending_msgs(), 1);
+        assert!(network.deliver_next());
+        match unwrap!(ev_rx1.recv()) {
+            Event::NewMessage { peer_addr, msg, .. } => {
+                assert_eq!(peer_addr, peer2_addr);
+                assert_eq!(&msg[..], b"pong");
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        network.resume();
+
+        peer2.disconnect_from(peer1_info.peer_addr);
+        match unwrap!(ev_rx1.recv()) {
+            Event::ConnectionClosed {
+                peer_addr,
+                reason: CloseReason::PeerClosed { error_code, .. },
+            } => {
+                assert_eq!(peer_addr, peer2_addr);
+                assert_eq!(error_code, 0);
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    fn link_conditions_are_simulated_on_the_memory_transport() {
+        let network = MemoryTransport::with_seed(7);
+        let (mut peer1, ev_rx1) = new_qp2p_on(&network);
+        let peer1_info = unwrap!(peer1.our_connection_info());
+        let (mut peer2, ev_rx2) = new_qp2p_on(&network);
+        let peer2_addr = unwrap!(peer2.our_connection_info()).peer_addr;
+
+        peer2.connect_to(peer1_info.clone());
+        for event in ev_rx2.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+        for event in ev_rx1.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        let mut send = |msgs: &[u8]| {
+            for &msg in msgs {
+                peer2.send(peer1_info.clone().into(), From::from(vec![msg]));
+            }
+        };
+        let recv = |count: usize| -> Vec<u8> {
+            (0..count)
+                .map(|_| match unwrap!(ev_rx1.recv()) {
+                    Event::NewMessage { peer_addr, msg, .. } => {
+                        assert_eq!(peer_addr, peer2_addr);
+                        msg[0]
+                    }
+                    ev => panic!("Unexpected event: {:?}", ev),
+                })
+                .collect()
+        };
+        let set_conditions =
+            |conditions| network.set_link_conditions(peer2_addr, peer1_info.peer_addr, conditions);
+        let msgs: Vec<u8> = (0..10).collect();
+
+        set_conditions(LinkConditions {
+            latency: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let sent_at = Instant::now();
+        send(&[0]);
+        assert_eq!(recv(1), [0]);
+        assert!(sent_at.elapsed() >= Duration::from_millis(300));
+
+        // Jitter only reorders messages which are allowed to overtake those sent before them
+        set_conditions(LinkConditions {
+            jitter: Duration::from_millis(200),
+            ..Default::default()
+        });
+        send(&msgs);
+        assert_eq!(recv(msgs.len()), msgs);
+        set_conditions(LinkConditions {
+            jitter: Duration::from_millis(200),
+            reordering: 1.0,
+            ..Default::default()
+        });
+        send(&msgs);
+        let mut received = recv(msgs.len());
+        assert_ne!(received, msgs);
+        received.sort();
+        assert_eq!(received, msgs);
+
+        set_conditions(LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        });
+        send(&[0]);
+        assert!(ev_rx1.recv_timeout(Duration::from_millis(200)).is_err());
+
+        set_conditions(Default::default());
+        network.partition(&[peer2_addr], &[peer1_info.peer_addr]);
+        send(&[0]);
+        assert!(ev_rx1.recv_timeout(Duration::from_millis(200)).is_err());
+        network.heal();
+        send(&[1]);
+        assert_eq!(recv(1), [1]);
+    }
+
+    #[test]
+    fn connect_to_marks_that_we_attempted_to_contact_the_peer() {
+        let (mut peer1, _) = new_random_qp2p(false, Default::default());
+        let peer1_conn_info = unwrap!(peer1.our_connection_info());
+        let peer1_addr = peer1_conn_info.peer_addr;
+
+        let (mut peer2, ev_rx) = new_random_qp2p(false, Default::default());
+        peer2.connect_to(peer1_conn_info);
+
+        for event in ev_rx.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        peer2.el.post(move || {
+            let contacted = ctx(|c| unwrap!(c.connections.get(&peer1_addr)).we_contacted_peer);
+            let _ = tx.send(contacted);
+        });
+        let we_contacted_peer = unwrap!(rx.recv());
+
+        assert!(we_contacted_peer);
+    }
+
+    #[test]
+    fn lifecycle_events_report_connection_progress() {
+        let (mut peer1, _rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .with_lifecycle_events(true)
+            .build());
+        peer2.connect_to(peer1_info.clone());
+
+        match unwrap!(rx.recv()) {
+            Event::Connecting { peer_addr } => assert_eq!(peer_addr, peer1_info.peer_addr),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(rx.recv()) {
+            Event::HandshakeCompleted { peer_addr, .. } => {
+                assert_eq!(peer_addr, peer1_info.peer_addr)
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(rx.recv()) {
+            Event::ConnectedTo { peer, .. } => assert_eq!(peer.peer_addr(), peer1_info.peer_addr),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn concurrent_connects_to_a_peer_share_the_attempt_in_flight() {
+        let (mut peer1, ev_rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_lifecycle_events(true)
+            .build());
+        peer2.connect_to(peer1_info.clone());
+        peer2.send(peer1_info.clone().into(), From::from(&b"queued"[..]));
+        peer2.connect_to(peer1_info.clone());
+
+        let received = ev_rx1.iter().find_map(|event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(received, Some(From::from(&b"queued"[..])));
+
+        let mut connecting = 0;
+        let mut connected = 0;
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(500)) {
+            match event {
+                Event::Connecting { .. } => connecting += 1,
+                Event::ConnectedTo { .. } => connected += 1,
+                Event::ConnectionFailure { .. } => panic!("Unexpected event {:?}", event),
+                _ => (),
+            }
+        }
+        assert_eq!((connecting, connected), (1, 1));
+    }
+
+    #[test]
+    fn sends_beyond_the_queue_limit_are_reported_unsent_while_connecting() {
+        let (mut peer1, ev_rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_lifecycle_events(true)
+            .with_send_queue_limit(1, 1024)
+            .build());
+        peer2.send(peer1_info.clone().into(), From::from(&b"queued"[..]));
+        peer2.send(peer1_info.clone().into(), From::from(&b"dropped"[..]));
+
+        let received = ev_rx1.iter().find_map(|event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(received, Some(From::from(&b"queued"[..])));
+
+        let mut queued = 0;
+        let mut unsent = Vec::new();
+        let mut flushed = None;
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(500)) {
+            match event {
+                Event::SendsQueued { peer_addr } => {
+                    assert_eq!(peer_addr, peer1_info.peer_addr);
+                    queued += 1;
+                }
+                Event::UnsentUserMessage { msg, reason, .. } => unsent.push((msg, reason)),
+                Event::QueuedSendsFlushed { peer_addr, count } => {
+                    assert_eq!(peer_addr, peer1_info.peer_addr);
+                    flushed = Some(count);
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(queued, 1);
+        assert_eq!(
+            unsent,
+            vec![(From::from(&b"dropped"[..]), UnsentReason::QueueFull)]
+        );
+        assert_eq!(flushed, Some(1));
+    }
+
+    #[test]
+    fn dials_time_out_before_the_idle_timeout() {
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                idle_timeout_msec: Some(30_000),
+                connect_timeout_msec: Some(200),
+                ..Config::with_default_cert()
+            })
+            .build());
+
+        let dead = rand_node_info();
+        let started = Instant::now();
+        peer.send(dead.into(), From::from(&b"waiting"[..]));
+        match unwrap!(rx.recv_timeout(Duration::from_secs(10))) {
+            Event::UnsentUserMessage { msg, reason, .. } => {
+                assert_eq!(&msg[..], b"waiting");
+                assert_eq!(reason, UnsentReason::Failed);
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn aborted_connects_report_their_queued_msgs() {
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .build());
+
+        // Nobody answers there, so the attempt is in flight until we abort it
+        let dead = rand_node_info();
+        peer.send_with_token(dead.clone().into(), From::from(&b"waiting"[..]), 3);
+        peer.abort_connect(dead.peer_addr);
+
+        match unwrap!(rx.recv_timeout(Duration::from_secs(5))) {
+            Event::UnsentUserMessage {
+                msg, token, reason, ..
+            } => {
+                assert_eq!(&msg[..], b"waiting");
+                assert_eq!(token, 3);
+                assert_eq!(reason, UnsentReason::Aborted);
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        // The message isn't reported again once the attempt is over
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn sticky_peers_are_reconnected_to() {
+        let (mut node, node_rx) = new_random_qp2p(false, Default::default());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let (tx, peer_rx) = mpmc::unbounded();
+        let mut peer = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_reconnect_policy(ReconnectPolicy {
+                initial_backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_millis(200),
+            })
+            .build());
+        let peer_addr = unwrap!(peer.our_connection_info()).peer_addr;
+        peer.make_sticky(node_info.clone());
+        peer.connect_to(node_info.clone());
+
+        for event in peer_rx.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+        for event in node_rx.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        // Dropped by the node, the peer dials it again on its own
+        node.disconnect_from(peer_addr);
+        match unwrap!(peer_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ConnectionClosed { peer_addr, .. } => {
+                assert_eq!(peer_addr, node_info.peer_addr)
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(peer_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::Reconnecting {
+                peer_addr,
+                attempt,
+                after,
+            } => {
+                assert_eq!(peer_addr, node_info.peer_addr);
+                assert_eq!(attempt, 1);
+                assert!(after <= Duration::from_millis(50));
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(peer_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ConnectedTo { peer, .. } => assert_eq!(peer.peer_addr(), node_info.peer_addr),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        match unwrap!(peer_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::Reconnected {
+                peer_addr,
+                attempts,
+            } => {
+                assert_eq!(peer_addr, node_info.peer_addr);
+                assert_eq!(attempts, 1);
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        // Unstuck, the peer lets the connection go
+        peer.unstick(node_info.peer_addr);
+        node.disconnect_from(peer_addr);
+        match unwrap!(peer_rx.recv_timeout(Duration::from_secs(5))) {
+            Event::ConnectionClosed { .. } => (),
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+        assert!(peer_rx.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    #[test]
+    fn peer_disconnecting_is_reported_as_graceful_close() {
+        let (mut peer1, ev_rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (mut peer2, ev_rx2) = new_random_qp2p(false, Default::default());
+        let peer2_addr = unwrap!(peer2.our_connection_info()).peer_addr;
+        peer2.connect_to(peer1_info.clone());
+
+        for event in ev_rx2.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+        for event in ev_rx1.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        peer2.disconnect_from(peer1_info.peer_addr);
+
+        match unwrap!(ev_rx1.recv()) {
+            Event::ConnectionClosed {
+                peer_addr,
+                reason: CloseReason::PeerClosed { error_code, .. },
+            } => {
+                assert_eq!(peer_addr, peer2_addr);
+                assert_eq!(error_code, 0);
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+
+        // We closed it ourselves so it's not reported as closed by the peer
+        match unwrap!(ev_rx2.recv()) {
+            Event::ConnectionFailure { peer_addr, .. } => {
+                assert_eq!(peer_addr, peer1_info.peer_addr)
+            }
+            ev => panic!("Unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn msg_inspector_observes_sent_and_received_messages() {
+        use crate::wire_msg::Direction;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static OUTGOING_USER_MSGS: AtomicUsize = AtomicUsize::new(0);
+        static INCOMING_USER_MSGS: AtomicUsize = AtomicUsize::new(0);
+
+        fn inspector(direction: &Direction, wire_msg: &WireMsg) {
+            if let WireMsg::UserMsg(ref m) = *wire_msg {
+                if m[..] != [1, 2, 3][..] {
+                    return;
+                }
+                let counter = match *direction {
+                    Direction::Outgoing(_) => &OUTGOING_USER_MSGS,
+                    Direction::Incoming(_) => &INCOMING_USER_MSGS,
+                };
+                let _ = counter.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (tx0, rx0) = mpmc::unbounded();
+        let mut qp2p0 = unwrap!(Builder::new(tx0)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .with_msg_inspector(inspector)
+            .build());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+
+        let (mut qp2p1, rx1) = new_random_qp2p(false, Default::default());
+        let qp2p1_info = unwrap!(qp2p1.our_connection_info());
+
+        qp2p1.send(qp2p0_info.into(), bytes::Bytes::from(vec![1, 2, 3]));
+        qp2p0.send(qp2p1_info.into(), bytes::Bytes::from(vec![1, 2, 3]));
+
+        for rx in &[rx0, rx1] {
+            for event in rx.iter() {
+                if let Event::NewMessage { .. } = event {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(OUTGOING_USER_MSGS.load(Ordering::SeqCst), 1);
+        assert_eq!(INCOMING_USER_MSGS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn wire_encoding_is_negotiated_per_connection() {
+        let (tx0, rx0) = mpmc::unbounded();
+        let mut qp2p0 = unwrap!(Builder::new(tx0)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .with_wire_encoding(WireEncoding::MessagePack)
+            .build());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+
+        let (mut qp2p1, rx1) = new_random_qp2p(false, Default::default());
+        let qp2p1_info = unwrap!(qp2p1.our_connection_info());
+
+        let msg = bytes::Bytes::from(vec![1, 2, 3]);
+        qp2p1.send(qp2p0_info.clone().into(), msg.clone());
+        qp2p0.send(qp2p1_info.clone().into(), msg.clone());
+
+        for rx in &[rx0, rx1] {
+            for event in rx.iter() {
+                if let Event::NewMessage { msg: received, .. } = event {
+                    assert_eq!(received, msg);
+                    break;
+                }
+            }
+        }
+
+        // The accepting peer's preference wins
+        fn to_peer_encoding(
+            c: &HashMap<SocketAddr, Connection>,
+            peer_addr: SocketAddr,
+        ) -> WireEncoding {
+            match c[&peer_addr].to_peer {
+                ToPeer::Established { ref q_conn, .. } => q_conn.encoding(),
+                ref x => panic!("Unexpected state: {:?}", x),
+            }
+        }
+        let peer_addr = qp2p0_info.peer_addr;
+        assert_eq!(
+            unwrap!(qp2p1.connections(move |c| to_peer_encoding(c, peer_addr))),
+            WireEncoding::MessagePack
+        );
+        let peer_addr = qp2p1_info.peer_addr;
+        assert_eq!(
+            unwrap!(qp2p0.connections(move |c| to_peer_encoding(c, peer_addr))),
+            WireEncoding::Tagged
+        );
+    }
+
+    #[test]
+    fn wire_msgs_are_displayed() {
+        assert_eq!(
+            format!("{}", WireMsg::Ping { nonce: 3 }),
+            "WireMsg::Ping { nonce: 3 }"
+        );
+        assert_eq!(
+            format!(
+                "{}",
+                WireMsg::ChannelMsg {
+                    channel: 2,
+                    msg: From::from(&b"hi"[..]),
+                }
+            ),
+            "WireMsg::ChannelMsg { channel: 2, msg: [ [104, 105] ] }"
+        );
+    }
+
+    #[test]
+    fn peers_exchange_messages_in_every_encoding() {
+        let encodings = vec![
+            WireEncoding::Tagged,
+            WireEncoding::Bincode,
+            WireEncoding::MessagePack,
+            WireEncoding::Cbor,
+            #[cfg(feature = "proto")]
+            WireEncoding::Protobuf,
+        ];
+        for encoding in encodings {
+            exchange_messages(encoding);
+        }
+    }
+
+    fn exchange_messages(encoding: WireEncoding) {
+        let new_qp2p = || {
+            let (tx, rx) = mpmc::unbounded();
+            let qp2p = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Default::default()
+                })
+                .with_persistent_identity(false)
+                .with_proxies(Default::default(), true)
+                .with_wire_encoding(encoding)
+                .build());
+            (qp2p, rx)
+        };
+        let (mut qp2p0, rx0) = new_qp2p();
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+        let (mut qp2p1, rx1) = new_qp2p();
+        let qp2p1_info = unwrap!(qp2p1.our_connection_info());
+
+        let small_msg = bytes::Bytes::from(vec![1, 2, 3]);
+        let big_msg = bytes::Bytes::from(vec![4; 4096]);
+        qp2p1.send(qp2p0_info.into(), small_msg.clone());
+        qp2p0.send(qp2p1_info.into(), big_msg.clone());
+
+        for (rx, msg) in &[(rx0, small_msg), (rx1, big_msg)] {
+            for event in rx.iter() {
+                if let Event::NewMessage { msg: received, .. } = event {
+                    assert_eq!(received, *msg);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stats_account_for_received_messages() {
+        let (mut qp2p0, rx0) = new_random_qp2p(false, Default::default());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+        let (mut qp2p1, _rx1) = new_random_qp2p(false, Default::default());
+        let qp2p1_addr = unwrap!(qp2p1.our_connection_info()).peer_addr;
+
+        let msg = bytes::Bytes::from(vec![1, 2, 3]);
+        qp2p1.send(qp2p0_info.into(), msg.clone());
+
+        for event in rx0.iter() {
+            if let Event::NewMessage { .. } = event {
+                break;
+            }
+        }
+
+        let stats = unwrap!(qp2p0.stats());
+        let conn_stats = unwrap!(stats.connections.get(&qp2p1_addr));
+        assert!(conn_stats.streams_received >= 1);
+        assert!(conn_stats.bytes_received >= msg.len() as u64);
+    }
+
+    #[test]
+    fn peer_stats_tell_the_byte_rates_of_a_peer() {
+        let (mut qp2p0, rx0) = new_random_qp2p(false, Default::default());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+        let (mut qp2p1, _rx1) = new_random_qp2p(false, Default::default());
+        let qp2p1_addr = unwrap!(qp2p1.our_connection_info()).peer_addr;
+
+        qp2p1.connect_to(qp2p0_info.clone());
+        for event in rx0.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+        assert_eq!(unwrap!(qp2p0.peer_stats(rand_node_info().peer_addr)), None);
+
+        // Once sampled, the bytes of the message count towards every window
+        std::thread::sleep(Duration::from_millis(1_200));
+        let msg = bytes::Bytes::from(vec![7; 64 * 1024]);
+        qp2p1.send(qp2p0_info.clone().into(), msg.clone());
+        for event in rx0.iter() {
+            if let Event::NewMessage { .. } = event {
+                break;
+            }
+        }
+
+        let received = unwrap!(unwrap!(qp2p0.peer_stats(qp2p1_addr)));
+        assert!(received.connection.bytes_received >= msg.len() as u64);
+        for rates in &[
+            received.bandwidth.last_second,
+            received.bandwidth.last_minute,
+            received.bandwidth.last_15_minutes,
+        ] {
+            assert!(rates.received >= msg.len() as u64 / 3);
+        }
+        let sent = unwrap!(unwrap!(qp2p1.peer_stats(qp2p0_info.peer_addr)));
+        assert!(sent.bandwidth.last_minute.sent >= msg.len() as u64 / 3);
+
+        let stats = unwrap!(qp2p0.stats());
+        let bandwidth = unwrap!(stats.bandwidth.get(&qp2p1_addr));
+        assert!(bandwidth.last_minute.received > 0);
+    }
+
+    #[cfg(feature = "unstable-quinn")]
+    #[test]
+    fn quinn_endpoint_and_connections_are_reachable() {
+        let (mut qp2p0, rx0) = new_random_qp2p(false, Default::default());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+        let (mut qp2p1, _rx1) = new_random_qp2p(false, Default::default());
+        let qp2p1_addr = unwrap!(qp2p1.our_connection_info()).peer_addr;
+
+        let local_addr = unwrap!(qp2p0.with_quinn_endpoint(|ep| ep.local_addr().ok()));
+        assert_eq!(local_addr, Some(qp2p0_info.peer_addr));
+        let remote = |conn: &quinn::Connection| conn.remote_address();
+        assert_eq!(
+            unwrap!(qp2p0.with_quinn_connection(qp2p1_addr, remote)),
+            None
+        );
+
+        qp2p1.connect_to(qp2p0_info.clone());
+        for event in rx0.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+        assert_eq!(
+            unwrap!(qp2p0.with_quinn_connection(qp2p1_addr, remote)),
+            Some(qp2p1_addr)
+        );
+        assert_eq!(
+            unwrap!(qp2p1.with_quinn_connection(qp2p0_info.peer_addr, remote)),
+            Some(qp2p0_info.peer_addr)
+        );
+    }
+
+    #[test]
+    fn dump_state_captures_connections_and_bootstrap_cache() {
+        let (mut qp2p0, rx0) = new_random_qp2p(false, Default::default());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+        let (mut qp2p1, _rx1) = new_random_qp2p(false, Default::default());
+        let qp2p1_addr = unwrap!(qp2p1.our_connection_info()).peer_addr;
+
+        qp2p1.connect_to(qp2p0_info.clone());
+        for event in rx0.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        let dump = unwrap!(qp2p1.dump_state());
+        assert_eq!(dump.our_type, OurType::Node);
+        assert!(dump.pending_connects.is_empty());
+        assert_eq!(dump.connections.len(), 1);
+        assert_eq!(dump.connections[0].peer_addr, qp2p0_info.peer_addr);
+        assert_eq!(dump.connections[0].to_peer, "Established");
+        assert_eq!(
+            dump.bootstrap_cache.cached_peers,
+            vec![qp2p0_info.peer_addr]
+        );
+
+        let dump = unwrap!(qp2p0.dump_state());
+        assert_eq!(dump.connections[0].peer_addr, qp2p1_addr);
+        assert_eq!(dump.connections[0].from_peer, "Established");
+        assert!(!dump.connections[0].we_contacted_peer);
+
+        let json = unwrap!(serde_json::to_string(&dump));
+        assert_eq!(unwrap!(serde_json::from_str::<StateDump>(&json)), dump);
+    }
+
+    #[test]
+    fn heartbeats_are_fired_for_quiet_connections() {
+        let (mut peer1, _rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .with_heartbeat_interval(Duration::from_millis(20))
+            .build());
+        peer2.connect_to(peer1_info.clone());
+
+        for event in rx.iter() {
+            if let Event::PeerHeartbeat {
+                peer_addr,
+                handshake_duration,
+            } = event
+            {
+                assert_eq!(peer_addr, peer1_info.peer_addr);
+                assert!(handshake_duration.is_some());
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn peers_held_up_writing_to_are_reported_slow_until_they_resume() {
+        let (mut peer1, _rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_stall_detection(Duration::from_millis(200))
+            .build());
+        peer2.connect_to(peer1_info.clone());
+        unwrap!(rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        }));
+
+        // Reading nothing while its event loop is busy, peer1 lets no more than a window through
+        peer1.el.post(|| std::thread::sleep(Duration::from_secs(1)));
+        peer2.send(peer1_info.clone().into(), vec![0; 20 * 1024 * 1024].into());
+
+        let mut events = rx.iter().filter(|event| match event {
+            Event::PeerSlow { .. } | Event::PeerResumed { .. } => true,
+            _ => false,
+        });
+        match unwrap!(events.next()) {
+            Event::PeerSlow {
+                peer_addr,
+                stalled_for,
+            } => {
+                assert_eq!(peer_addr, peer1_info.peer_addr);
+                assert!(stalled_for >= Duration::from_millis(200));
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        match unwrap!(events.next()) {
+            Event::PeerResumed { peer_addr } => assert_eq!(peer_addr, peer1_info.peer_addr),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn peers_whose_event_loop_stops_answering_probes_are_disconnected() {
+        let (mut peer1, _rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_probes(Duration::from_millis(50), 3)
+            .build());
+        peer2.connect_to(peer1_info.clone());
+        match unwrap!(rx.iter().find(|event| match event {
+            Event::PeerRtt { .. } => true,
+            _ => false,
+        })) {
+            Event::PeerRtt { peer_addr, rtt } => {
+                assert_eq!(peer_addr, peer1_info.peer_addr);
+                assert!(rtt < Duration::from_millis(50));
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+
+        // The transport of peer1 keeps answering meanwhile
+        peer1.el.post(|| std::thread::sleep(Duration::from_secs(1)));
+        match unwrap!(rx.iter().find(|event| match event {
+            Event::ConnectionFailure { .. } => true,
+            _ => false,
+        })) {
+            Event::ConnectionFailure {
+                peer_addr,
+                err: Error::ProbesUnanswered(3),
+            } => assert_eq!(peer_addr, peer1_info.peer_addr),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn quiet_peers_failing_liveness_probes_are_disconnected() {
+        let (mut peer1, _rx1) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+
+        let (tx, rx) = mpmc::unbounded();
+        let mut peer2 = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                node_liveness_msec: Some(200),
+                liveness_probes: Some(2),
+                ..Config::with_default_cert()
+            })
+            .build());
+        peer2.connect_to(peer1_info.clone());
+        for event in rx.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        // Quiet but answering its probes, peer1 stays connected
+        std::thread::sleep(Duration::from_millis(800));
+        assert!(rx.try_iter().all(|event| match event {
+            Event::ConnectionFailure { .. } => false,
+            _ => true,
+        }));
+
+        // The transport of peer1 keeps answering meanwhile
+        peer1.el.post(|| std::thread::sleep(Duration::from_secs(2)));
+        match unwrap!(rx.recv_timeout(Duration::from_secs(2))) {
+            Event::ConnectionFailure {
+                peer_addr,
+                err: Error::ProbesUnanswered(2),
+            } => assert_eq!(peer_addr, peer1_info.peer_addr),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+    }
+
+    #[test]
+    fn pushed_msgs_arrive_in_order() {
+        for &encoding in &[WireEncoding::Tagged, WireEncoding::Bincode] {
+            let (tx, node_rx) = mpmc::unbounded();
+            let mut node = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Default::default()
+                })
+                .with_persistent_identity(false)
+                .with_proxies(Default::default(), true)
+                .with_wire_encoding(encoding)
+                .build());
+            let node_info = unwrap!(node.our_connection_info());
+
+            let (tx, client_rx) = mpmc::unbounded();
+            let mut client = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    our_type: OurType::Client,
+                    ..Config::with_default_cert()
+                })
+                .with_wire_encoding(encoding)
+                .build());
+            client.send(node_info.into(), From::from(&b"subscribe"[..]));
+            let client_addr = unwrap!(node_rx.iter().find_map(|event| match event {
+                Event::NewMessage { peer_addr, .. } => Some(peer_addr),
+                _ => None,
+            }));
+
+            // Including messages too big for bincode to encode
+            let msgs: Vec<bytes::Bytes> = (0..20u8)
+                .map(|i| vec![i; 1 + usize::from(i) * 200].into())
+                .collect();
+            for msg in &msgs {
+                node.push(client_addr, msg.clone());
+            }
+
+            let mut pushed: Vec<_> = client_rx
+                .iter()
+                .filter_map(|event| match event {
+                    Event::NewMessage { msg, .. } => Some(msg),
+                    _ => None,
+                })
+                .take(msgs.len())
+                .collect();
+            if !encoding.offers_push_streams() {
+                // Sent on a stream each, which may overtake each other
+                pushed.sort_by_key(|msg| msg.len());
+            }
+            assert_eq!(pushed, msgs);
+
+            let stranger = rand_node_info().peer_addr;
+            node.push(stranger, From::from(&b"news"[..]));
+            let unsent = node_rx.iter().find_map(|event| match event {
+                Event::UnsentUserMessage { peer, msg, .. } => Some((peer, msg)),
+                _ => None,
+            });
+            assert_eq!(
+                unsent,
+                Some((
+                    Peer::Client {
+                        peer_addr: stranger
+                    },
+                    From::from(&b"news"[..])
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn ordered_delivery_keeps_user_msgs_in_order() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        sender.connect_to_with_delivery(receiver_info.clone(), true);
+        unwrap!(sender_rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        }));
+
+        // Big enough ones first, for later ones to overtake them on streams of their own
+        let msgs: Vec<bytes::Bytes> = (0..20u8)
+            .map(|i| vec![i; 1 + usize::from(20 - i) * 5000].into())
+            .collect();
+        for msg in &msgs {
+            sender.send(receiver_info.clone().into(), msg.clone());
+        }
+
+        let received: Vec<_> = receiver_rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::NewMessage { msg, .. } => Some(msg),
+                _ => None,
+            })
+            .take(msgs.len())
+            .collect();
+        assert_eq!(received, msgs);
+    }
+
+    #[test]
+    fn channels_keep_their_msgs_in_order_of_their_own() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        sender.connect_to_with_delivery(receiver_info.clone(), true);
+        unwrap!(sender_rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        }));
+
+        // Big ones on the one channel, which the small ones on the others needn't wait for
+        let bulk: Vec<bytes::Bytes> = (0..10u8).map(|i| vec![i; 100_000].into()).collect();
+        let control: Vec<bytes::Bytes> = (0..10u8).map(|i| vec![i].into()).collect();
+        for (bulk_msg, control_msg) in bulk.iter().zip(&control) {
+            let peer: Peer = receiver_info.clone().into();
+            sender.send_on_channel(peer.clone(), 1, bulk_msg.clone(), 0);
+            sender.send_on_channel(peer.clone(), 2, control_msg.clone(), 0);
+            sender.send(peer, control_msg.clone());
+        }
+
+        let mut received: HashMap<Channel, Vec<bytes::Bytes>> = HashMap::new();
+        let msgs = receiver_rx.iter().filter_map(|event| match event {
+            Event::NewMessage { msg, channel, .. } => Some((channel, msg)),
+            _ => None,
+        });
+        for (channel, msg) in msgs.take(bulk.len() * 3) {
+            received.entry(channel).or_default().push(msg);
+        }
+        assert_eq!(received.remove(&1), Some(bulk));
+        assert_eq!(received.remove(&2), Some(control.clone()));
+        assert_eq!(received.remove(&DEFAULT_CHANNEL), Some(control));
+    }
+
+    #[test]
+    fn queued_msgs_are_drained_newest_first_if_asked() {
+        let (tx, _rx) = mpmc::unbounded();
+        let mut sender = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_drain_order(DrainOrder::Lifo)
+            .build());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        // All queued up while the first of them has us connect to the receiver
+        let msgs: Vec<bytes::Bytes> = (0..10u8).map(|i| vec![i].into()).collect();
+        for msg in &msgs {
+            sender.send_with_delivery(
+                receiver_info.clone().into(),
+                msg.clone(),
+                DeliveryMode::Ordered,
+            );
+        }
+
+        let received: Vec<_> = receiver_rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::NewMessage { msg, .. } => Some(msg),
+                _ => None,
+            })
+            .take(msgs.len())
+            .collect();
+        assert_eq!(received, msgs.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delivery_modes_are_chosen_per_msg() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        let msgs: Vec<bytes::Bytes> = (0..20u8)
+            .map(|i| vec![i; 1 + usize::from(20 - i) * 5000].into())
+            .collect();
+        for msg in &msgs {
+            sender.send_with_delivery(
+                receiver_info.clone().into(),
+                msg.clone(),
+                DeliveryMode::Ordered,
+            );
+        }
+        let received: Vec<_> = receiver_rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::NewMessage { msg, .. } => Some(msg),
+                _ => None,
+            })
+            .take(msgs.len())
+            .collect();
+        assert_eq!(received, msgs);
+
+        let stranger = rand_node_info().peer_addr;
+        let peer = Peer::Client {
+            peer_addr: stranger,
+        };
+        sender.send_with_delivery(peer, From::from(&b"missed"[..]), DeliveryMode::Unordered);
+        let unsent = sender_rx.iter().find_map(|event| match event {
+            Event::UnsentUserMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(unsent, Some(From::from(&b"missed"[..])));
+    }
+
+    #[test]
+    fn duplicate_msg_ids_are_suppressed() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        // Ordered, so that the last message marks the end of those before it
+        sender.connect_to_with_delivery(receiver_info.clone(), true);
+        unwrap!(sender_rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        }));
+
+        let peer: Peer = receiver_info.into();
+        sender.send_with_id(peer.clone(), From::from(&b"first"[..]), 1);
+        sender.send_with_id(peer.clone(), From::from(&b"retry"[..]), 1);
+        sender.send_with_id(peer.clone(), From::from(&b"second"[..]), 2);
+        sender.send(peer, From::from(&b"end"[..]));
+
+        let received: Vec<bytes::Bytes> = receiver_rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::NewMessage { msg, .. } => Some(msg),
+                _ => None,
+            })
+            .take_while(|msg| &msg[..] != b"end")
+            .collect();
+        let expected: Vec<bytes::Bytes> =
+            vec![From::from(&b"first"[..]), From::from(&b"second"[..])];
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn unsent_user_msgs_are_resent_as_the_policy_asks() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let policy = ResendPolicy {
+            max_resends: 2,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        let stranger = Peer::Client {
+            peer_addr: rand_node_info().peer_addr,
+        };
+        let started = Instant::now();
+        sender.send_with_resend(stranger, From::from(&b"missed"[..]), policy);
+        let unsent = sender_rx.iter().find_map(|event| match event {
+            Event::UnsentUserMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(unsent, Some(From::from(&b"missed"[..])));
+        // Reported once, after backing off 100ms and then 200ms
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(sender_rx
+            .recv_timeout(Duration::from_millis(500))
+            .map(|event| match event {
+                Event::UnsentUserMessage { .. } => false,
+                _ => true,
+            })
+            .unwrap_or(true));
+    }
+
+    #[test]
+    fn send_options_combine() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let policy = ResendPolicy {
+            max_resends: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        let stranger = Peer::Client {
+            peer_addr: rand_node_info().peer_addr,
+        };
+        let opts = SendOptions::default()
+            .with_id(5)
+            .with_token(9)
+            .with_resend_policy(policy);
+        let started = Instant::now();
+        unwrap!(sender.send_with(stranger.clone(), From::from(&b"missed"[..]), opts));
+        let unsent = sender_rx.iter().find_map(|event| match event {
+            Event::UnsentUserMessage { msg, token, .. } => Some((msg, token)),
+            _ => None,
+        });
+        assert_eq!(unsent, Some((From::from(&b"missed"[..]), 9)));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let opts = SendOptions::default().with_id(5).with_channel(1);
+        match sender.send_with(stranger, From::from(&b"never"[..]), opts) {
+            Err(Error::ConflictingSettings { setting, .. }) => assert_eq!(setting, "with_id"),
+            res => panic!("Unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unsent_user_msgs_are_collected_as_dead_letters() {
+        let (tx, rx) = mpmc::unbounded();
+        let mut sender = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_dead_letters(10, None)
+            .build());
+
+        let stranger = rand_node_info().peer_addr;
+        sender.send(
+            Peer::Client {
+                peer_addr: stranger,
+            },
+            From::from(&b"missed"[..]),
+        );
+
+        let mut dead_letters = Vec::new();
+        for _ in 0..50 {
+            dead_letters = unwrap!(sender.drain_dead_letters());
+            if !dead_letters.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(
+            dead_letters,
+            vec![DeadLetter {
+                peer_addr: stranger,
+                msg: From::from(&b"missed"[..]),
+                token: 0,
+            }]
+        );
+        assert!(unwrap!(sender.drain_dead_letters()).is_empty());
+        assert!(rx.try_iter().all(|event| match event {
+            Event::UnsentUserMessage { .. } => false,
+            _ => true,
+        }));
+    }
+
+    #[test]
+    fn user_msg_outcomes_carry_the_peer_and_token() {
+        let (tx, sender_rx) = mpmc::unbounded();
+        let mut sender = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_sent_events(true)
+            .build());
+        let (mut receiver, _receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        let peer: Peer = receiver_info.clone().into();
+        sender.send_with_token(peer.clone(), From::from(&b"hello"[..]), 7);
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::SentUserMessage { peer, msg, token } => Some((peer, msg, token)),
+            Event::UnsentUserMessage { .. } => panic!("Unexpected event {:?}", event),
+            _ => None,
+        });
+        assert_eq!(outcome, Some((peer, From::from(&b"hello"[..]), 7)));
+
+        // Fails before a connection is made, as the node doesn't have this certificate
+        let (mut other, _other_rx) = new_random_qp2p(false, Default::default());
+        let impostor: Peer = NodeInfo {
+            peer_cert_der: rand_node_info().peer_cert_der,
+            ..unwrap!(other.our_connection_info())
+        }
+        .into();
+        sender.send_with_token(impostor.clone(), From::from(&b"lost"[..]), 8);
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::UnsentUserMessage {
+                peer,
+                msg,
+                token,
+                reason,
+            } => Some((peer, msg, token, reason)),
+            _ => None,
+        });
+        assert_eq!(
+            outcome,
+            Some((impostor, From::from(&b"lost"[..]), 8, UnsentReason::Failed))
+        );
+    }
+
+    #[test]
+    fn messages_past_their_ttl_are_reported_as_expired() {
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+
+        // Queued while we connect to a node which never answers
+        let started_at = Instant::now();
+        sender.send_with_ttl(
+            rand_node_info().into(),
+            From::from(&b"stale"[..]),
+            1,
+            Duration::from_millis(100),
+        );
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::UnsentUserMessage {
+                msg, token, reason, ..
+            } => Some((msg, token, reason)),
+            _ => None,
+        });
+        assert_eq!(
+            outcome,
+            Some((From::from(&b"stale"[..]), 1, UnsentReason::Expired))
+        );
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        let fresh = bytes::Bytes::from(&b"fresh"[..]);
+        let receiver_info = unwrap!(receiver.our_connection_info());
+        sender.send_with_ttl(
+            receiver_info.into(),
+            fresh.clone(),
+            2,
+            Duration::from_secs(10),
+        );
+        let received = receiver_rx.iter().find_map(|event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(received, Some(fresh));
+        while let Ok(event) = sender_rx.recv_timeout(Duration::from_millis(300)) {
+            if let Event::UnsentUserMessage { .. } = event {
+                panic!("Unexpected event {:?}", event);
+            }
+        }
+    }
+
+    #[test]
+    fn ttls_are_told_by_the_clock() {
+        let clock = ManualClock::new();
+        let (tx, sender_rx) = mpmc::unbounded();
+        let mut sender = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_clock(Arc::new(clock.clone()))
+            .build());
+
+        // Queued while we connect to a node which never answers
+        sender.send_with_ttl(
+            rand_node_info().into(),
+            From::from(&b"stale"[..]),
+            1,
+            Duration::from_secs(3_600),
+        );
+        // Returns once the send was handled by the event loop, and so its deadline set
+        let _ = unwrap!(sender.attempted_connections());
+
+        let started = Instant::now();
+        clock.advance(Duration::from_secs(3_600));
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::UnsentUserMessage { token, reason, .. } => Some((token, reason)),
+            _ => None,
+        });
+        assert_eq!(outcome, Some((1, UnsentReason::Expired)));
+        // Checked every second, however long the TTL
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn app_acks_hold_back_sent_events_until_the_peer_got_the_message() {
+        let new_sender = || {
+            let (tx, rx) = mpmc::unbounded();
+            let sender = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Config::with_default_cert()
+                })
+                .with_sent_events(true)
+                .with_app_acks(true)
+                .build());
+            (sender, rx)
+        };
+
+        let (mut sender, sender_rx) = new_sender();
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let peer: Peer = unwrap!(receiver.our_connection_info()).into();
+        sender.send_with_token(peer.clone(), From::from(&b"hello"[..]), 1);
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::SentUserMessage { token, .. } => Some(token),
+            Event::UnsentUserMessage { .. } => panic!("Unexpected event {:?}", event),
+            _ => None,
+        });
+        assert_eq!(outcome, Some(1));
+        assert!(receiver_rx.try_iter().any(|event| match event {
+            Event::NewMessage { msg, .. } => msg == b"hello"[..],
+            _ => false,
+        }));
+
+        // The peer drops the message, its channel being full of the event of our connection
+        let (mut sender, sender_rx) = new_sender();
+        let (tx, _full_rx) = mpmc::bounded(1);
+        let mut receiver = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_event_policy(EventPolicy::Drop)
+            .build());
+        let peer: Peer = unwrap!(receiver.our_connection_info()).into();
+        sender.send_with_ttl(peer, From::from(&b"dropped"[..]), 2, Duration::from_secs(1));
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::SentUserMessage { .. } => panic!("Unexpected event {:?}", event),
+            Event::UnsentUserMessage { token, reason, .. } => Some((token, reason)),
+            _ => None,
+        });
+        assert_eq!(outcome, Some((2, UnsentReason::Expired)));
+    }
+
+    #[test]
+    fn files_are_streamed_from_and_to_disk() {
+        let dir = tmp_rand_dir();
+        unwrap!(std::fs::create_dir_all(&dir));
+        let src = dir.join("chunk");
+        let contents: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        unwrap!(std::fs::write(&src, &contents));
+
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+        let peer: Peer = receiver_info.clone().into();
+
+        // Files are only sent over connections made before
+        sender.send_file(peer.clone(), src.clone(), 1);
+        match sender_rx.iter().find(|event| match event {
+            Event::UnsentFile { .. } => true,
+            _ => false,
+        }) {
+            Some(Event::UnsentFile {
+                token: 1,
+                err: Error::FileTransfer(_),
+                ..
+            }) => (),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        sender.connect_to(receiver_info);
+        let _ = sender_rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        });
+        sender.send_file(peer.clone(), src.clone(), 2);
+        let (id, name, len) = unwrap!(receiver_rx.iter().find_map(|event| match event {
+            Event::IncomingFile { id, name, len, .. } => Some((id, name, len)),
+            _ => None,
+        }));
+        assert_eq!(name, "chunk");
+        assert_eq!(len, contents.len() as u64);
+
+        let dest = dir.join("replica");
+        assert_eq!(unwrap!(receiver.receive_file(id, &dest)), len);
+        assert_eq!(unwrap!(std::fs::read(&dest)), contents);
+        let outcome = sender_rx.iter().find_map(|event| match event {
+            Event::SentFile { peer, path, token } => Some((peer, path, token)),
+            Event::UnsentFile { .. } => panic!("Unexpected event {:?}", event),
+            _ => None,
+        });
+        assert_eq!(outcome, Some((peer, src, 2)));
+
+        // Taken already
+        match receiver.receive_file(id, &dir.join("again")) {
+            Err(Error::FileTransfer(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(!dir.join("again").exists());
+        match receiver.reject_file(id) {
+            Err(Error::FileTransfer(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn cancelled_file_transfers_are_reported_at_both_ends() {
+        let dir = tmp_rand_dir();
+        unwrap!(std::fs::create_dir_all(&dir));
+        let src = dir.join("chunk");
+        unwrap!(std::fs::write(&src, vec![7; 8 * 1024 * 1024]));
+
+        let (mut sender, sender_rx) = new_random_qp2p(false, Default::default());
+        let (mut receiver, receiver_rx) = new_random_qp2p(false, Default::default());
+        let receiver_info = unwrap!(receiver.our_connection_info());
+        sender.connect_to(receiver_info.clone());
+        let _ = sender_rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        });
+
+        match sender.cancel_stream(4) {
+            Err(Error::FileTransfer(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        // Held up by the receiver not taking the file yet
+        sender.send_file(receiver_info.into(), src, 4);
+        let id = unwrap!(receiver_rx.iter().find_map(|event| match event {
+            Event::IncomingFile { id, .. } => Some(id),
+            _ => None,
+        }));
+        unwrap!(sender.cancel_stream(4));
+        match sender_rx.iter().find(|event| match event {
+            Event::SentFile { .. } | Event::UnsentFile { .. } => true,
+            _ => false,
+        }) {
+            Some(Event::UnsentFile {
+                token: 4,
+                err: Error::TransferCancelled,
+                ..
+            }) => (),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Noticed without the receiver taking the file, which is dropped
+        let cancelled = receiver_rx.iter().find_map(|event| match event {
+            Event::IncomingFileCancelled { id, .. } => Some(id),
+            _ => None,
+        });
+        assert_eq!(cancelled, Some(id));
+        let dest = dir.join("replica");
+        match receiver.receive_file(id, &dest) {
+            Err(Error::FileTransfer(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn progress_of_large_files_is_reported() {
+        let new_qp2p = || {
+            let (tx, rx) = mpmc::unbounded();
+            let qp2p = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Config::with_default_cert()
+                })
+                .with_progress_events(100 * 1024)
+                .build());
+            (qp2p, rx)
+        };
+        let (mut sender, sender_rx) = new_qp2p();
+        let (mut receiver, receiver_rx) = new_qp2p();
+        let receiver_info = unwrap!(receiver.our_connection_info());
+
+        let dir = tmp_rand_dir();
+        unwrap!(std::fs::create_dir_all(&dir));
+        let src = dir.join("chunk");
+        let total = 300 * 1024;
+        unwrap!(std::fs::write(&src, vec![7; total as usize]));
+
+        sender.connect_to(receiver_info.clone());
+        let _ = sender_rx.iter().find(|event| match event {
+            Event::ConnectedTo { .. } => true,
+            _ => false,
+        });
+        sender.send_file(receiver_info.into(), src, 3);
+        let id = unwrap!(receiver_rx.iter().find_map(|event| match event {
+            Event::IncomingFile { id, .. } => Some(id),
+            _ => None,
+        }));
+        assert_eq!(
+            unwrap!(receiver.receive_file(id, &dir.join("replica"))),
+            total
+        );
+
+        let mut sent = Vec::new();
+        for event in sender_rx.iter() {
+            match event {
+                Event::SendProgress {
+                    token,
+                    bytes_sent,
+                    total,
+                } => sent.push((token, bytes_sent, total)),
+                Event::SentFile { .. } => break,
+                _ => (),
+            }
+        }
+        assert_eq!(sent.last(), Some(&(3, total, total)));
+        let received = receiver_rx.iter().find_map(|event| match event {
+            Event::ReceiveProgress {
+                id,
+                bytes_received,
+                total,
+            } if bytes_received == total => Some((id, total)),
+            _ => None,
+        });
+        assert_eq!(received, Some((id, total)));
+    }
+
+    #[test]
+    fn topic_messages_reach_each_subscriber_once() {
+        let (mut a, a_rx) = new_random_qp2p(false, Default::default());
+        let (mut b, b_rx) = new_random_qp2p(false, Default::default());
+        let (mut c, c_rx) = new_random_qp2p(false, Default::default());
+        b.subscribe("chunks".to_string());
+        c.subscribe("chunks".to_string());
+
+        // A triangle, so C gets the message both from A and forwarded by B
+        let b_info = unwrap!(b.our_connection_info());
+        let c_info = unwrap!(c.our_connection_info());
+        a.connect_to(b_info);
+        a.connect_to(c_info.clone());
+        b.connect_to(c_info);
+        for rx in &[&a_rx, &a_rx, &b_rx] {
+            let _ = rx.iter().find(|event| match event {
+                Event::ConnectedTo { .. } => true,
+                _ => false,
+            });
+        }
+        // Let the subscriptions reach the peers
+        std::thread::sleep(Duration::from_millis(200));
+
+        let msg = bytes::Bytes::from(&b"chunk stored"[..]);
+        a.publish("chunks".to_string(), msg.clone());
+        for rx in &[&b_rx, &c_rx] {
+            let got = rx.iter().find_map(|event| match event {
+                Event::TopicMessage { topic, msg, .. } => Some((topic, msg)),
+                _ => None,
+            });
+            assert_eq!(got, Some(("chunks".to_string(), msg.clone())));
+        }
+        for rx in &[&a_rx, &b_rx, &c_rx] {
+            while let Ok(event) = rx.recv_timeout(Duration::from_millis(300)) {
+                if let Event::TopicMessage { .. } = event {
+                    panic!("Unexpected event {:?}", event);
+                }
+            }
+        }
+
+        c.unsubscribe("chunks".to_string());
+        std::thread::sleep(Duration::from_millis(200));
+        a.publish("chunks".to_string(), msg.clone());
+        let got = b_rx.iter().find_map(|event| match event {
+            Event::TopicMessage { msg, peer_addr, .. } => Some((msg, peer_addr)),
+            _ => None,
+        });
+        assert_eq!(got, Some((msg, unwrap!(a.our_connection_info()).peer_addr)));
+        while let Ok(event) = c_rx.recv_timeout(Duration::from_millis(300)) {
+            if let Event::TopicMessage { .. } = event {
+                panic!("Unexpected event {:?}", event);
+            }
+        }
+    }
+
+    #[test]
+    fn gossip_reaches_every_peer_once() {
+        let new_qp2p = || {
+            let (tx, rx) = mpmc::unbounded();
+            let qp2p = unwrap!(Builder::new(tx)
+                .with_config(Config {
+                    port: Some(0),
+                    ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    ..Config::with_default_cert()
+                })
+                .with_gossip(2, Duration::from_millis(100))
+                .build());
+            (qp2p, rx)
+        };
+        let (mut a, a_rx) = new_qp2p();
+        let (mut b, b_rx) = new_qp2p();
+        let (mut c, c_rx) = new_qp2p();
+
+        // A triangle, so every peer gets the message on two paths
+        let b_info = unwrap!(b.our_connection_info());
+        let c_info = unwrap!(c.our_connection_info());
+        a.connect_to(b_info);
+        a.connect_to(c_info.clone());
+        b.connect_to(c_info.clone());
+        for rx in &[&a_rx, &a_rx, &b_rx] {
+            let _ = rx.iter().find(|event| match event {
+                Event::ConnectedTo { .. } => true,
+                _ => false,
+            });
+        }
+
+        let msg = bytes::Bytes::from(&b"node joined"[..]);
+        a.gossip(msg.clone());
+        for rx in &[&b_rx, &c_rx] {
+            let got = rx.iter().find_map(|event| match event {
+                Event::GossipMessage { msg, .. } => Some(msg),
+                _ => None,
+            });
+            assert_eq!(got, Some(msg.clone()));
+        }
+
+        // A peer joining later catches up through anti-entropy
+        let (mut d, d_rx) = new_qp2p();
+        d.connect_to(c_info);
+        let got = d_rx.iter().find_map(|event| match event {
+            Event::GossipMessage { peer_addr, msg } => Some((peer_addr, msg)),
+            _ => None,
+        });
+        assert_eq!(got, Some((unwrap!(c.our_connection_info()).peer_addr, msg)));
+
+        for rx in &[&a_rx, &b_rx, &c_rx, &d_rx] {
+            while let Ok(event) = rx.recv_timeout(Duration::from_millis(300)) {
+                if let Event::GossipMessage { .. } = event {
+                    panic!("Unexpected event {:?}", event);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn idle_clients_are_evicted() {
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .with_client_idle_timeout(Duration::from_millis(500))
+            .build());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let (tx, _client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+        let started = Instant::now();
+        client.send(node_info.into(), From::from(&b"ping"[..]));
+        let client_addr = unwrap!(node_rx.iter().find_map(|event| match event {
+            Event::NewMessage { peer_addr, .. } => Some(peer_addr),
+            _ => None,
+        }));
+
+        let clients = unwrap!(node.clients());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].peer_addr, client_addr);
+        assert!(clients[0].connected_at >= started);
+        assert!(clients[0].last_activity >= clients[0].connected_at);
+        assert!(clients[0].bytes_received >= 4);
+
+        let evicted = node_rx.iter().find_map(|event| match event {
+            Event::ConnectionFailure { peer_addr, err } => Some((peer_addr, err)),
+            _ => None,
+        });
+        match evicted {
+            Some((peer_addr, Error::IdleClientEvicted)) => assert_eq!(peer_addr, client_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(unwrap!(node.clients()).is_empty());
+    }
+
+    #[test]
+    fn idle_clients_are_evicted_by_the_clock() {
+        let clock = ManualClock::new();
+        let (tx, node_rx) = mpmc::unbounded();
+        let mut node = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Default::default()
+            })
+            .with_persistent_identity(false)
+            .with_proxies(Default::default(), true)
+            .with_client_idle_timeout(Duration::from_secs(3_600))
+            .with_clock(Arc::new(clock.clone()))
+            .build());
+        let node_info = unwrap!(node.our_connection_info());
+
+        let (tx, _client_rx) = mpmc::unbounded();
+        let mut client = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type: OurType::Client,
+                ..Config::with_default_cert()
+            })
+            .build());
+        client.send(node_info.into(), From::from(&b"ping"[..]));
+        let client_addr = unwrap!(node_rx.iter().find_map(|event| match event {
+            Event::NewMessage { peer_addr, .. } => Some(peer_addr),
+            _ => None,
+        }));
+        let clients = unwrap!(node.clients());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].connected_at, clock.now());
+
+        let started = Instant::now();
+        clock.advance(Duration::from_secs(3_600));
+        let evicted = node_rx.iter().find_map(|event| match event {
+            Event::ConnectionFailure { peer_addr, err } => Some((peer_addr, err)),
+            _ => None,
+        });
+        match evicted {
+            Some((peer_addr, Error::IdleClientEvicted)) => assert_eq!(peer_addr, client_addr),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // Checked every second, however long the timeout
+        assert!(started.elapsed() < Duration::from_secs(30));
+        assert!(unwrap!(node.clients()).is_empty());
+    }
+
+    #[cfg(feature = "chaos")]
+    fn new_chaotic_qp2p(chaos: Chaos) -> (QuicP2p, mpmc::Receiver<Event>) {
+        let (tx, rx) = mpmc::unbounded();
+        let qp2p = unwrap!(Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                ..Config::with_default_cert()
+            })
+            .with_chaos(chaos)
+            .build());
+        (qp2p, rx)
+    }
+
+    #[cfg(feature = "chaos")]
+    fn find_event<T>(rx: &mpmc::Receiver<Event>, f: impl Fn(Event) -> Option<T>) -> Option<T> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if let Some(found) = rx.recv_timeout(timeout).ok().and_then(&f) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn handshakes_are_delayed_and_connections_killed_in_chaos() {
+        let (mut peer1, _rx) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+        let (mut peer2, ev_rx) = new_chaotic_qp2p(Chaos {
+            delay_handshakes: 1.0,
+            handshake_delay: Duration::from_millis(500),
+            kill_connections: 1.0,
+            kill_interval: Duration::from_millis(100),
+            ..Default::default()
+        });
+
+        let started = Instant::now();
+        peer2.connect_to(peer1_info.clone());
+        let connected = find_event(&ev_rx, |event| match event {
+            Event::ConnectedTo { peer, .. } => Some(peer.peer_addr()),
+            _ => None,
+        });
+        assert_eq!(connected, Some(peer1_info.peer_addr));
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        let killed = find_event(&ev_rx, |event| match event {
+            Event::ConnectionFailure { peer_addr, err } => Some((peer_addr, err)),
+            _ => None,
+        });
+        match killed {
+            Some((peer_addr, Error::InjectedFault(_))) => {
+                assert_eq!(peer_addr, peer1_info.peer_addr)
+            }
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn sends_fail_in_chaos() {
+        let (mut peer1, _rx) = new_random_qp2p(false, Default::default());
+        let peer1_info = unwrap!(peer1.our_connection_info());
+        let (mut peer2, ev_rx) = new_chaotic_qp2p(Chaos {
+            fail_sends: 1.0,
+            ..Default::default()
+        });
+
+        peer2.send(peer1_info.into(), From::from(&b"ping"[..]));
+        let unsent = find_event(&ev_rx, |event| match event {
+            Event::UnsentUserMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(unsent.as_ref().map(|msg| &msg[..]), Some(&b"ping"[..]));
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn events_are_dropped_until_chaos_is_set_off() {
+        let (mut peer1, ev_rx) = new_chaotic_qp2p(Chaos {
+            drop_events: 1.0,
+            ..Default::default()
+        });
+        let peer1_info = unwrap!(peer1.our_connection_info());
+        let (mut peer2, _rx) = new_random_qp2p(false, Default::default());
+
+        peer2.send(peer1_info.clone().into(), From::from(&b"ping"[..]));
+        assert!(ev_rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        match peer1.set_chaos(Chaos {
+            drop_events: -0.5,
+            ..Default::default()
+        }) {
+            Err(Error::InvalidSetting { setting, .. }) => assert_eq!(setting, "set_chaos"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        unwrap!(peer1.set_chaos(Default::default()));
+        peer2.send(peer1_info.into(), From::from(&b"pong"[..]));
+        let received = find_event(&ev_rx, |event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(received.as_ref().map(|msg| &msg[..]), Some(&b"pong"[..]));
+    }
+
+    #[test]
+    fn is_hard_coded_contact() {
+        let contact0 = rand_node_info();
+        let contact1 = rand_node_info();
+
+        let (peer, _) = new_random_qp2p(false, iter::once(contact0.clone()).collect());
+
+        assert!(peer.is_hard_coded_contact(&contact0));
+        assert!(!peer.is_hard_coded_contact(&contact1));
+    }
+
+    #[test]
+    fn contacts_changed_at_runtime_are_saved() {
+        let contact0 = rand_node_info();
+        let contact1 = rand_node_info();
+        let (mut peer, _) = new_random_qp2p(false, iter::once(contact0.clone()).collect());
+
+        peer.add_contact(contact1.clone());
+        assert!(peer.remove_contact(contact0.peer_addr));
+
+        let path = tmp_rand_dir().join("config.json");
+        unwrap!(std::fs::create_dir_all(unwrap!(path.parent())));
+        unwrap!(peer.save_config(&path, Format::Json));
+        let saved = unwrap!(Config::from_file(&path));
+        assert_eq!(saved.hard_coded_contacts, iter::once(contact1).collect());
+    }
+
+    #[test]
+    fn contacts_are_removed_by_their_ipv4_mapped_addr() {
+        let contact = rand_node_info();
+        let (mut peer, _) = new_random_qp2p(false, iter::once(contact.clone()).collect());
+
+        let mapped_addr = SocketAddr::new(
+            IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+            contact.peer_addr.port(),
+        );
+        assert!(peer.remove_contact(mapped_addr));
+        assert!(!peer.is_hard_coded_contact(&contact));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn sends_are_traced_under_the_callers_span() {
+        use opentelemetry::trace::{Span, TraceContextExt, Tracer};
+        use opentelemetry_sdk::testing::trace::InMemorySpanExporter;
+        use opentelemetry_sdk::trace::TracerProvider;
+
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let _ = opentelemetry::global::set_tracer_provider(provider);
+
+        let (mut qp2p0, rx0) = new_random_qp2p(false, Default::default());
+        let qp2p0_info = unwrap!(qp2p0.our_connection_info());
+        let (mut qp2p1, _rx1) = new_random_qp2p(false, Default::default());
+
+        // Sends buffered while still connecting aren't written out on behalf of the caller
+        qp2p1.connect_to(qp2p0_info.clone());
+        for event in rx0.iter() {
+            if let Event::ConnectedTo { .. } = event {
+                break;
+            }
+        }
+
+        let parent = opentelemetry::global::tracer("test").start("parent");
+        let parent_id = parent.span_context().span_id();
+        {
+            let _guard = opentelemetry::Context::current_with_span(parent).attach();
+            qp2p1.send(qp2p0_info.into(), bytes::Bytes::from(vec![1, 2, 3]));
+        }
+
+        for event in rx0.iter() {
+            if let Event::NewMessage { .. } = event {
+                break;
+            }
+        }
+
+        let is_our_send = |span: &opentelemetry_sdk::export::trace::SpanData| {
+            span.name == "quic_p2p.send" && span.parent_span_id == parent_id
+        };
+        for _ in 0..50 {
+            let spans = unwrap!(exporter.get_finished_spans());
+            if spans.iter().any(is_our_send) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        panic!("No send span was recorded under the caller's span");
+    }
+}
+
+#[cfg(all(test, feature = "client-only"))]
+mod client_only_tests {
+    use super::*;
+
+    fn build(our_type: OurType) -> R<QuicP2p> {
+        let (tx, _rx) = mpmc::unbounded();
+        Builder::new(tx)
+            .with_config(Config {
+                port: Some(0),
+                ip: Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                our_type,
+                ..Config::with_default_cert()
+            })
+            .build()
+    }
+
+    #[test]
+    fn only_clients_can_be_built() {
+        match build(OurType::Node) {
+            Err(Error::Config { field, .. }) => assert_eq!(field, "our_type"),
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+
+        let mut client = unwrap!(build(OurType::Client));
+        let our_addr = unwrap!(client.our_connection_info()).peer_addr;
+        assert!(our_addr.ip().is_loopback());
     }
 }
@@ -8,21 +8,35 @@
 // Software.
 
 use crate::communicate;
+use crate::config::{DuplicateConnectionPolicy, OurType};
+use crate::connect;
 use crate::connection::{BootstrapGroupRef, Connection, FromPeer, QConn, ToPeer};
-use crate::context::ctx_mut;
-use crate::event::Event;
+use crate::context::{ctx_mut, Context};
+use crate::event::{ConnectionDirection, DroppedConnection, Event};
+use crate::transport::{Incoming, NewConn};
 use crate::utils;
+use crate::wire_msg::CONNECTION_LIMIT_CODE;
 use crate::Error;
 use crate::NodeInfo;
+use std::net::{IpAddr, SocketAddr};
 use tokio::prelude::{Future, Stream};
 use tokio::runtime::current_thread;
 
+/// Most connections peers of a subnet may have to us, see `Config::max_incoming_per_v4_subnet`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubnetLimits {
+    /// Limit of the /24 IPv4 subnets.
+    pub v4: Option<u32>,
+    /// Limit of the /48 IPv6 subnets.
+    pub v6: Option<u32>,
+}
+
 /// Start listening
-pub fn listen(incoming_connections: quinn::Incoming) {
+pub fn listen(incoming_connections: Incoming) {
     let leaf = incoming_connections
-        .map_err(|()| warn!("ERROR: Listener errored out"))
-        .for_each(move |(conn_driver, q_conn, incoming)| {
-            handle_new_conn(conn_driver, q_conn, incoming);
+        .map_err(|e| warn!("ERROR: Listener errored out: {}", e))
+        .for_each(move |new_conn| {
+            handle_new_conn(new_conn);
             Ok(())
         });
 
@@ -30,43 +44,81 @@ pub fn listen(incoming_connections: quinn::Incoming) {
 }
 
 enum Action {
+    RefuseSubnetFull(QConn),
     HandleDuplicate(QConn),
     HandleAlreadyBootstrapped,
     Continue(Option<BootstrapGroupRef>),
 }
 
-fn handle_new_conn(
-    conn_driver: quinn::ConnectionDriver,
-    q_conn: quinn::Connection,
-    incoming_streams: quinn::IncomingStreams,
-) {
-    let q_conn = QConn::from(q_conn);
+fn handle_new_conn(new_conn: NewConn) {
+    let NewConn {
+        conn,
+        driver,
+        incoming_msgs,
+    } = new_conn;
+    let q_conn = QConn::from(conn);
+    let stats = q_conn.stats().clone();
+    let encoding = q_conn.encoding();
 
-    let peer_addr = q_conn.remote_address();
+    let peer_addr = utils::normalise_addr(q_conn.remote_address());
 
-    current_thread::spawn(conn_driver.map_err(move |e| {
-        utils::handle_communication_err(peer_addr, &From::from(e), "Driver failed", None);
+    current_thread::spawn(driver.map_err(move |e| {
+        utils::handle_communication_err(peer_addr, &e, "Driver failed", None);
     }));
 
     let state = ctx_mut(|c| {
+        if is_subnet_full(c, peer_addr) {
+            return Action::RefuseSubnetFull(q_conn);
+        }
+        let is_duplicate = match c.connections.get_mut(&peer_addr) {
+            Some(conn) if conn.from_peer.is_established() => {
+                if c.duplicate_connection_policy == DuplicateConnectionPolicy::KeepBoth {
+                    conn.duplicate_from_peer.push(q_conn);
+                    return Action::Continue(None);
+                }
+                true
+            }
+            _ => false,
+        };
+        if is_duplicate && new_conn_wins(c, peer_addr) {
+            debug!(
+                "Replacing the connection from peer {} with its new one",
+                peer_addr
+            );
+            let event = Event::DuplicateConnectionDropped {
+                peer_addr,
+                dropped: DroppedConnection::Existing,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+            let _ = c.connections.remove(&peer_addr);
+        }
+
         let event_tx = c.event_tx.clone();
         let conn = c
             .connections
             .entry(peer_addr)
             .or_insert_with(|| Connection::new(peer_addr, event_tx, None));
-        if conn.from_peer.is_no_connection() {
+        // A node we connected to as a client reverse connecting to us after we were promoted to a
+        // node. The user was told about the node back then already.
+        let is_after_promotion = conn.from_peer.is_not_needed() && c.our_type == OurType::Node;
+        if conn.from_peer.is_no_connection() || is_after_promotion {
             conn.from_peer = FromPeer::Established {
                 q_conn,
                 pending_reads: Default::default(),
             };
 
-            let bootstrap_group = if let ToPeer::Established {
+            let bootstrap_group = if is_after_promotion {
+                None
+            } else if let ToPeer::Established {
                 ref peer_cert_der, ..
             } = conn.to_peer
             {
                 let node_info = NodeInfo {
                     peer_addr,
                     peer_cert_der: peer_cert_der.clone(),
+                    alt_addrs: Vec::new(),
                 };
                 let mut bootstrap_group = None;
 
@@ -76,17 +128,25 @@ fn handle_new_conn(
                     if bootstrap_group_ref.is_bootstrap_successful_yet() {
                         return Action::HandleAlreadyBootstrapped;
                     }
+                    // Our connection to the peer is established, so its handshake has been timed
+                    let handshake_duration = conn.handshake_duration.unwrap_or_default();
+                    let event = bootstrap_group_ref.bootstrapped_to(node_info, handshake_duration);
                     bootstrap_group = Some(bootstrap_group_ref);
-                    Event::BootstrappedTo { node: node_info }
+                    event
                 } else {
                     Event::ConnectedTo {
                         peer: node_info.into(),
+                        direction: ConnectionDirection::from_we_contacted_peer(
+                            conn.we_contacted_peer,
+                        ),
+                        capabilities: conn.peer_capabilities.clone(),
                     }
                 };
 
                 if let Err(e) = c.event_tx.send(event) {
                     info!("ERROR in informing user about a new peer: {:?} - {}", e, e);
                 }
+                connect::established_later(peer_addr);
 
                 bootstrap_group
             } else {
@@ -94,11 +154,27 @@ fn handle_new_conn(
             };
             Action::Continue(bootstrap_group)
         } else {
+            if conn.from_peer.is_established() {
+                let event = Event::DuplicateConnectionDropped {
+                    peer_addr,
+                    dropped: DroppedConnection::New,
+                };
+                if let Err(e) = c.event_tx.send(event) {
+                    info!("Could not fire event: {:?}", e);
+                }
+            }
             Action::HandleDuplicate(q_conn)
         }
     });
 
     match state {
+        Action::RefuseSubnetFull(q_conn) => {
+            info!(
+                "Refusing peer {} as its subnet has as many connections to us as allowed",
+                peer_addr
+            );
+            q_conn.close_with(CONNECTION_LIMIT_CODE, b"");
+        }
         Action::HandleDuplicate(_q_conn) => {
             debug!("Not allowing duplicate connection from peer: {}", peer_addr);
         }
@@ -112,7 +188,91 @@ fn handle_new_conn(
             if let Some(bootstrap_group_ref) = bootstrap_group {
                 bootstrap_group_ref.terminate_group(true);
             }
-            communicate::read_from_peer(peer_addr, incoming_streams);
+            communicate::read_from_peer(peer_addr, incoming_msgs, stats, encoding);
+        }
+    }
+}
+
+/// Whether a new connection from a peer replaces its existing one following our
+/// `DuplicateConnectionPolicy`.
+fn new_conn_wins(c: &Context, peer_addr: SocketAddr) -> bool {
+    match c.duplicate_connection_policy {
+        DuplicateConnectionPolicy::KeepExisting | DuplicateConnectionPolicy::KeepBoth => false,
+        DuplicateConnectionPolicy::KeepIncoming => true,
+        DuplicateConnectionPolicy::KeepLowestAddress => {
+            let mut our_addr = match c.transport.local_addr() {
+                Ok(addr) => utils::normalise_addr(addr),
+                Err(e) => {
+                    info!("Could not obtain our address: {:?} - {}", e, e);
+                    return false;
+                }
+            };
+            // Listening on all interfaces we don't know the IP the peer reached us at, leaving it
+            // to the ports to decide
+            if our_addr.ip().is_unspecified() {
+                our_addr.set_ip(peer_addr.ip());
+            }
+            peer_addr < our_addr
         }
     }
 }
+
+/// Subnet the limits of `SubnetLimits` apply to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Subnet {
+    V4([u8; 3]),
+    V6([u16; 3]),
+}
+
+impl Subnet {
+    fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let octets = ip.octets();
+                Subnet::V4([octets[0], octets[1], octets[2]])
+            }
+            IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                Subnet::V6([segments[0], segments[1], segments[2]])
+            }
+        }
+    }
+}
+
+/// Whether the subnet of a peer new to us has as many connections to us as allowed already.
+/// Peers we know of, e.g. nodes reverse connecting to us, are never refused.
+fn is_subnet_full(c: &Context, peer_addr: SocketAddr) -> bool {
+    if c.connections.contains_key(&peer_addr) {
+        return false;
+    }
+    let subnet = Subnet::of(peer_addr.ip());
+    let limit = match subnet {
+        Subnet::V4(_) => c.subnet_limits.v4,
+        Subnet::V6(_) => c.subnet_limits.v6,
+    };
+    let limit = match limit {
+        Some(limit) => limit as usize,
+        None => return false,
+    };
+    let connected = c
+        .connections
+        .iter()
+        .filter(|(addr, conn)| conn.from_peer.is_established() && Subnet::of(addr.ip()) == subnet)
+        .count();
+    connected >= limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_grouped_by_their_subnet() {
+        let subnet = |ip: &str| Subnet::of(unwrap!(ip.parse()));
+        assert_eq!(subnet("203.0.113.7"), subnet("203.0.113.250"));
+        assert_ne!(subnet("203.0.113.7"), subnet("203.0.114.7"));
+        assert_eq!(subnet("2001:db8:1::1"), subnet("2001:db8:1:ffff::2"));
+        assert_ne!(subnet("2001:db8:1::1"), subnet("2001:db8:2::1"));
+        assert_ne!(subnet("0.0.0.1"), subnet("::1"));
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Moving the connections of a client over to a new local socket when its network changes, e.g.
+//! from Wi-Fi to cellular.
+//!
+//! QUIC connections aren't tied to the address they were made from: the node notices packets of a
+//! connection arriving from a new address, validates the new path and carries on. We just have to
+//! send from a socket which is usable on the new network and find out which connections made it.
+
+use crate::config::OurType;
+use crate::connection::ToPeer;
+use crate::context::ctx;
+use crate::error::Error;
+use crate::event::Event;
+use crate::utils;
+use crate::wire_msg::WireMsg;
+use crate::R;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::prelude::{Future, FutureExt, Stream};
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// Time a connection has to acknowledge a probe sent from the new socket.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check every `interval` which local IP the OS routes our traffic to the nodes through and
+/// migrate our connections once it changes.
+pub fn start_detection(interval: Duration) {
+    let mut last_ip = None;
+    let leaf = Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| info!("Error in network change detection interval: {:?}", e))
+        .for_each(move |_| {
+            if let Some(ip) = route_ip() {
+                if last_ip.is_some() && last_ip != Some(ip) {
+                    info!("Local IP changed to {}, migrating our connections", ip);
+                    if let Err(e) = migrate(Some(ip)) {
+                        info!("Could not migrate our connections: {}", e);
+                    }
+                }
+                last_ip = Some(ip);
+            }
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+/// Move our endpoint over to a fresh socket, on `new_ip` if we are bound to a specific IP, and
+/// probe each of the connections we made from it. Fires `Event::ConnectionMigrated` for the
+/// connections which carry on and `Event::MigrationFailed` for the ones which don't.
+///
+/// Returns the address we are bound to from then on.
+pub fn migrate(new_ip: Option<IpAddr>) -> R<SocketAddr> {
+    let transport = ctx(|c| c.transport.clone());
+    let local_addr = transport.rebind(new_ip)?;
+    debug!("Rebound to {}", local_addr);
+
+    let probes: Vec<_> = ctx(|c| {
+        c.connections
+            .iter()
+            .filter_map(|(peer_addr, conn)| match conn.to_peer {
+                ToPeer::Established { ref q_conn, .. } => {
+                    let probe = WireMsg::EndpointEchoReq.encode(q_conn.encoding());
+                    Some((*peer_addr, q_conn.send(probe)))
+                }
+                _ => None,
+            })
+            .collect()
+    });
+
+    for (peer_addr, probe) in probes {
+        let leaf = probe.timeout(PROBE_TIMEOUT).then(move |r| {
+            match r {
+                Ok(()) => ctx(|c| {
+                    let _ = c.event_tx.send(Event::ConnectionMigrated { peer_addr });
+                }),
+                Err(e) => {
+                    let e = e.into_inner().unwrap_or_else(|| {
+                        Error::Io(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "Migration probe timed out",
+                        ))
+                    });
+                    ctx(|c| {
+                        let _ = c.event_tx.send(Event::MigrationFailed { peer_addr });
+                    });
+                    utils::handle_communication_err(peer_addr, &e, "Migration probe", None);
+                }
+            }
+            Ok(())
+        });
+        current_thread::spawn(leaf);
+    }
+
+    Ok(local_addr)
+}
+
+/// Local IP the OS would send from to one of the nodes we are connected to, if we are a client
+/// connected to any.
+fn route_ip() -> Option<IpAddr> {
+    let peer_addr = ctx(|c| {
+        if c.our_type != OurType::Client {
+            return None;
+        }
+        c.connections
+            .iter()
+            .find(|(_, conn)| conn.to_peer.is_established())
+            .map(|(peer_addr, _)| *peer_addr)
+    })?;
+
+    match utils::route_ip(peer_addr) {
+        Ok(ip) => Some(ip),
+        Err(e) => {
+            trace!("Could not find the route to {}: {}", peer_addr, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Conversions between socket addresses and multiaddrs of the form `/ip4/<ip>/udp/<port>/quic`.
+
+use crate::error::Error;
+use crate::R;
+use multiaddr::{Multiaddr, Protocol};
+use std::net::{IpAddr, SocketAddr};
+
+/// Multiaddr of the QUIC endpoint at the given address, e.g. `/ip4/127.0.0.1/udp/5000/quic`.
+pub fn to_multiaddr(addr: SocketAddr) -> Multiaddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => Protocol::Ip4(ip),
+        IpAddr::V6(ip) => Protocol::Ip6(ip),
+    };
+    Multiaddr::empty()
+        .with(ip)
+        .with(Protocol::Udp(addr.port()))
+        .with(Protocol::Quic)
+}
+
+/// Address of the QUIC endpoint the multiaddr points to. Both `/quic` and `/quic-v1` are
+/// accepted, any other protocols are not.
+pub fn from_multiaddr(multiaddr: &Multiaddr) -> R<SocketAddr> {
+    let mut protocols = multiaddr.iter();
+    let invalid = || Error::InvalidMultiaddr(multiaddr.to_string());
+
+    let ip = match protocols.next() {
+        Some(Protocol::Ip4(ip)) => IpAddr::V4(ip),
+        Some(Protocol::Ip6(ip)) => IpAddr::V6(ip),
+        _ => return Err(invalid()),
+    };
+    let port = match protocols.next() {
+        Some(Protocol::Udp(port)) => port,
+        _ => return Err(invalid()),
+    };
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Quic), None) | (Some(Protocol::QuicV1), None) => (),
+        _ => return Err(invalid()),
+    }
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Parse a multiaddr string into the address of the QUIC endpoint it points to.
+pub fn parse(s: &str) -> R<SocketAddr> {
+    let multiaddr: Multiaddr = s
+        .parse()
+        .map_err(|_| Error::InvalidMultiaddr(s.to_string()))?;
+    from_multiaddr(&multiaddr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_addrs_round_trip_through_multiaddrs() {
+        for &(addr, multiaddr) in &[
+            ("127.0.0.1:5000", "/ip4/127.0.0.1/udp/5000/quic"),
+            ("[2001:db8::1]:443", "/ip6/2001:db8::1/udp/443/quic"),
+        ] {
+            let addr: SocketAddr = unwrap!(addr.parse());
+            assert_eq!(to_multiaddr(addr).to_string(), multiaddr);
+            assert_eq!(unwrap!(parse(multiaddr)), addr);
+        }
+
+        assert_eq!(
+            unwrap!(parse("/ip4/10.0.0.1/udp/1234/quic-v1")),
+            unwrap!("10.0.0.1:1234".parse::<SocketAddr>())
+        );
+    }
+
+    #[test]
+    fn non_quic_multiaddrs_are_rejected() {
+        for s in &[
+            "/ip4/127.0.0.1/tcp/5000",
+            "/ip4/127.0.0.1/udp/5000",
+            "/ip4/127.0.0.1/udp/5000/quic/p2p-circuit",
+            "/dns4/example.com/udp/5000/quic",
+            "not a multiaddr",
+        ] {
+            match parse(s) {
+                Err(Error::InvalidMultiaddr(_)) => (),
+                r => panic!("Unexpected result for {}: {:?}", s, r),
+            }
+        }
+    }
+}
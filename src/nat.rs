@@ -0,0 +1,268 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Classifying the NAT we are behind from the addresses peers see us at, see
+//! `QuicP2p::detect_nat`.
+//!
+//! The terms follow RFC 4787: a NAT maps our endpoint to a public one either once for all
+//! destinations or anew for each destination IP address, or even for each destination port.
+//! Peers can reach us at a mapping made for someone else only with the former, so only then does
+//! hole punching stand a chance.
+
+use crate::context::{ctx, ctx_mut};
+use crate::error::Error;
+use crate::reachability;
+use crate::utils;
+use crate::NodeInfo;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::prelude::{Future, FutureExt};
+use tokio::runtime::current_thread;
+
+/// Time the peers have to tell us which address they see us at.
+pub const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How our NAT maps our endpoint to the addresses our peers see, see `NatReport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMapping {
+    /// Peers see us at the address we are bound to.
+    NoNat,
+    /// Peers see us at the same address whoever they are.
+    EndpointIndependent,
+    /// Peers on different IP addresses see us at different addresses, peers on the same IP
+    /// address at the same one.
+    AddressDependent,
+    /// Peers see us at different addresses even if they only differ in port. Also reported when
+    /// no two of the peers share an IP address, so that the two kinds can't be told apart.
+    AddressAndPortDependent,
+    /// Too few peers answered to tell, i.e. none, or one seeing us behind a NAT.
+    Unknown,
+}
+
+/// Result of `QuicP2p::detect_nat`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatReport {
+    /// Address each peer which answered sees us at, by the address of the peer.
+    pub observed: Vec<(SocketAddr, SocketAddr)>,
+    /// How our NAT maps our endpoint, judging by `observed`.
+    pub mapping: NatMapping,
+    /// Whether we could reach ourselves at the address a peer sees us at. Only checked for nodes
+    /// behind a NAT, as clients accept no connections.
+    pub hairpinning: Option<bool>,
+}
+
+impl NatReport {
+    /// Whether peers can only reach us through a node relaying for us: our NAT maps us anew for
+    /// each peer, so the address one peer sees us at is of no use to the others.
+    pub fn needs_relay(&self) -> bool {
+        match self.mapping {
+            NatMapping::AddressDependent | NatMapping::AddressAndPortDependent => true,
+            _ => false,
+        }
+    }
+
+    /// Whether peers can reach us by hole punching: we are behind a NAT which doesn't let them in
+    /// on its own but shows all of them the same address.
+    pub fn needs_hole_punching(&self) -> bool {
+        self.mapping == NatMapping::EndpointIndependent
+    }
+}
+
+/// Pass the address a peer sees us at on to `QuicP2p::detect_nat`, if it is waiting for that.
+pub fn echoed(peer_addr: SocketAddr, our_addr: SocketAddr) {
+    ctx(|c| {
+        if let Some(ref tx) = c.nat_echo_tx {
+            let _ = tx.send((peer_addr, utils::normalise_addr(our_addr)));
+        }
+    });
+}
+
+/// Stop waiting for echoes and send the report on what the peers saw to `tx`. Nodes pass their
+/// certificate, so that hairpinning is checked by dialing ourselves at an address a peer sees.
+pub fn report(
+    observed: Vec<(SocketAddr, SocketAddr)>,
+    our_cert_der: Option<Vec<u8>>,
+    tx: mpsc::Sender<NatReport>,
+) {
+    ctx_mut(|c| c.nat_echo_tx = None);
+
+    let mapped: Vec<_> = observed
+        .iter()
+        .filter_map(|&(peer_addr, seen)| {
+            local_addr_towards(peer_addr).map(|local| (peer_addr, local, seen))
+        })
+        .collect();
+    let mapping = classify(&mapped);
+    let public_addr = mapped
+        .iter()
+        .find(|&&(_, local, seen)| local != seen)
+        .map(|&(_, _, seen)| seen);
+
+    let mut report = NatReport {
+        observed,
+        mapping,
+        hairpinning: None,
+    };
+    let (public_addr, our_cert_der) = match (public_addr, our_cert_der) {
+        (Some(addr), Some(cert_der)) => (addr, cert_der),
+        _ => {
+            let _ = tx.send(report);
+            return;
+        }
+    };
+
+    let us = NodeInfo {
+        peer_addr: public_addr,
+        peer_cert_der: our_cert_der,
+        alt_addrs: Vec::new(),
+    };
+    let attempt: Box<dyn Future<Item = (), Error = Error>> = match reachability::probe(&us) {
+        Ok(attempt) => Box::new(attempt),
+        Err(e) => {
+            info!("Could not check hairpinning: {}", e);
+            let _ = tx.send(report);
+            return;
+        }
+    };
+    let leaf = attempt
+        .timeout(reachability::DIAL_BACK_TIMEOUT)
+        .then(move |r| {
+            report.hairpinning = Some(r.is_ok());
+            let _ = tx.send(report);
+            Ok(())
+        });
+    current_thread::spawn(leaf);
+}
+
+/// Classify the mapping seen by each peer in `observed`, given the address we are bound to
+/// locally when talking to it.
+pub fn classify(observed: &[(SocketAddr, SocketAddr, SocketAddr)]) -> NatMapping {
+    if observed.is_empty() {
+        return NatMapping::Unknown;
+    }
+    if observed.iter().all(|&(_, local, seen)| local == seen) {
+        return NatMapping::NoNat;
+    }
+    if observed.len() < 2 {
+        return NatMapping::Unknown;
+    }
+    if observed.iter().all(|&(_, _, seen)| seen == observed[0].2) {
+        return NatMapping::EndpointIndependent;
+    }
+
+    // Only peers sharing an IP address tell whether the port matters
+    let mut port_tested = false;
+    for (i, &(peer_a, _, seen_a)) in observed.iter().enumerate() {
+        for &(peer_b, _, seen_b) in &observed[i + 1..] {
+            if peer_a.ip() == peer_b.ip() {
+                if seen_a != seen_b {
+                    return NatMapping::AddressAndPortDependent;
+                }
+                port_tested = true;
+            }
+        }
+    }
+    if port_tested {
+        NatMapping::AddressDependent
+    } else {
+        NatMapping::AddressAndPortDependent
+    }
+}
+
+/// Address we send from to the given peer as far as we know: the IP the OS routes our packets to
+/// it from if we are bound to an unspecified IP.
+fn local_addr_towards(peer_addr: SocketAddr) -> Option<SocketAddr> {
+    let local_addr = match ctx(|c| c.transport.clone()).local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            info!("Could not get our local address: {}", e);
+            return None;
+        }
+    };
+    if !local_addr.ip().is_unspecified() {
+        return Some(local_addr);
+    }
+    match utils::route_ip(peer_addr) {
+        Ok(ip) => Some(utils::normalise_addr(SocketAddr::new(
+            ip,
+            local_addr.port(),
+        ))),
+        Err(e) => {
+            trace!("Could not find the route to {}: {}", peer_addr, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(ip: [u8; 4], port: u16) -> SocketAddr {
+        (ip, port).into()
+    }
+
+    #[test]
+    fn mappings_are_classified_by_what_the_peers_see() {
+        let local = addr([192, 168, 1, 2], 5000);
+        let peer_a = addr([198, 51, 100, 1], 6000);
+        let peer_a2 = addr([198, 51, 100, 1], 6001);
+        let peer_b = addr([198, 51, 100, 2], 6000);
+        let public = |port| addr([203, 0, 113, 1], port);
+
+        assert_eq!(classify(&[]), NatMapping::Unknown);
+        assert_eq!(classify(&[(peer_a, local, local)]), NatMapping::NoNat);
+        assert_eq!(
+            classify(&[(peer_a, local, public(7000))]),
+            NatMapping::Unknown
+        );
+        assert_eq!(
+            classify(&[(peer_a, local, public(7000)), (peer_b, local, public(7000))]),
+            NatMapping::EndpointIndependent
+        );
+        assert_eq!(
+            classify(&[
+                (peer_a, local, public(7000)),
+                (peer_a2, local, public(7000)),
+                (peer_b, local, public(7001)),
+            ]),
+            NatMapping::AddressDependent
+        );
+        assert_eq!(
+            classify(&[
+                (peer_a, local, public(7000)),
+                (peer_a2, local, public(7001)),
+                (peer_b, local, public(7002)),
+            ]),
+            NatMapping::AddressAndPortDependent
+        );
+        assert_eq!(
+            classify(&[(peer_a, local, public(7000)), (peer_b, local, public(7001))]),
+            NatMapping::AddressAndPortDependent
+        );
+    }
+
+    #[test]
+    fn relaying_is_needed_for_destination_dependent_mappings() {
+        let report = |mapping| NatReport {
+            observed: Vec::new(),
+            mapping,
+            hairpinning: None,
+        };
+
+        assert!(!report(NatMapping::NoNat).needs_relay());
+        assert!(!report(NatMapping::NoNat).needs_hole_punching());
+        assert!(report(NatMapping::EndpointIndependent).needs_hole_punching());
+        assert!(!report(NatMapping::EndpointIndependent).needs_relay());
+        assert!(report(NatMapping::AddressDependent).needs_relay());
+        assert!(report(NatMapping::AddressAndPortDependent).needs_relay());
+        assert!(!report(NatMapping::Unknown).needs_relay());
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Export of traces and metrics through the OpenTelemetry API.
+//!
+//! We only talk to the global tracer and meter providers, so nothing is exported unless the
+//! application installs providers of its own. Every connection gets a span lasting as long as the
+//! connection itself and every send a span of its own. The public `QuicP2p` methods carry the
+//! caller's current OpenTelemetry context over to the event loop, so the spans they lead to
+//! become children of whatever span the caller had active. Sends buffered while the connection
+//! is still being established are written out once it is, so their spans have no parent.
+
+use crate::event::CloseReason;
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry::metrics::Counter;
+use opentelemetry::trace::{Span, Status, Tracer};
+use opentelemetry::KeyValue;
+use std::net::SocketAddr;
+
+const INSTRUMENTATION_NAME: &str = "quic-p2p";
+
+thread_local! {
+    static METRICS: Metrics = Metrics::new();
+}
+
+struct Metrics {
+    connections_opened: Counter<u64>,
+    connections_closed: Counter<u64>,
+    msgs_sent: Counter<u64>,
+    bytes_sent: Counter<u64>,
+    sends_failed: Counter<u64>,
+    msgs_received: Counter<u64>,
+    bytes_received: Counter<u64>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let meter = global::meter(INSTRUMENTATION_NAME);
+        let counter = |name: &'static str, description: &'static str| {
+            meter.u64_counter(name).with_description(description).init()
+        };
+
+        Self {
+            connections_opened: counter(
+                "quic_p2p.connections.opened",
+                "Connections to or from peers we started tracking",
+            ),
+            connections_closed: counter(
+                "quic_p2p.connections.closed",
+                "Connections to or from peers we stopped tracking",
+            ),
+            msgs_sent: counter("quic_p2p.messages.sent", "Messages sent to peers"),
+            bytes_sent: counter("quic_p2p.bytes.sent", "Bytes sent to peers"),
+            sends_failed: counter(
+                "quic_p2p.messages.failed",
+                "Messages which could not be sent to peers",
+            ),
+            msgs_received: counter("quic_p2p.messages.received", "Messages received from peers"),
+            bytes_received: counter("quic_p2p.bytes.received", "Bytes received from peers"),
+        }
+    }
+}
+
+/// Connections can be dropped while the thread is shutting down, by when `METRICS` might already
+/// be gone. Nothing is recorded then.
+fn with_metrics<F: FnOnce(&Metrics)>(f: F) {
+    let _ = METRICS.try_with(f);
+}
+
+/// Start the span covering the lifetime of our connection to the given peer.
+pub fn connection_span(peer_addr: SocketAddr) -> BoxedSpan {
+    with_metrics(|m| m.connections_opened.add(1, &[]));
+
+    let mut span = global::tracer(INSTRUMENTATION_NAME).start("quic_p2p.connection");
+    span.set_attribute(KeyValue::new("net.peer.addr", peer_addr.to_string()));
+    span
+}
+
+/// End the span of a connection which is being dropped.
+pub fn end_connection_span(span: &mut BoxedSpan, was_complete: bool, reason: Option<&CloseReason>) {
+    with_metrics(|m| m.connections_closed.add(1, &[]));
+
+    span.set_attribute(KeyValue::new("quic_p2p.connection.complete", was_complete));
+    match reason {
+        Some(CloseReason::PeerClosed { error_code, .. }) => {
+            span.set_attribute(KeyValue::new(
+                "quic_p2p.close.error_code",
+                i64::from(*error_code),
+            ));
+            span.set_status(Status::Ok);
+        }
+        Some(CloseReason::TimedOut) => span.set_status(Status::error("timed out")),
+        Some(CloseReason::TransportError(e)) => span.set_status(Status::error(e.to_string())),
+        None if !was_complete => span.set_status(Status::error("never completed")),
+        None => span.set_status(Status::Ok),
+    }
+    span.end();
+}
+
+/// Start the span covering a single message sent to the given peer.
+pub fn send_span(peer_addr: SocketAddr, len: u64) -> BoxedSpan {
+    let mut span = global::tracer(INSTRUMENTATION_NAME).start("quic_p2p.send");
+    span.set_attribute(KeyValue::new("net.peer.addr", peer_addr.to_string()));
+    span.set_attribute(KeyValue::new("quic_p2p.message.size", len as i64));
+    span
+}
+
+/// End the span of a send once the message was either written out or failed to be.
+pub fn end_send_span(mut span: BoxedSpan, len: u64, is_ok: bool) {
+    with_metrics(|m| {
+        if is_ok {
+            m.msgs_sent.add(1, &[]);
+            m.bytes_sent.add(len, &[]);
+        } else {
+            m.sends_failed.add(1, &[]);
+        }
+    });
+
+    span.set_status(if is_ok {
+        Status::Ok
+    } else {
+        Status::error("send failed")
+    });
+    span.end();
+}
+
+/// Record a message received from a peer.
+pub fn record_received(len: u64) {
+    with_metrics(|m| {
+        m.msgs_received.add(1, &[]);
+        m.bytes_received.add(len, &[]);
+    });
+}
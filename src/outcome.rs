@@ -0,0 +1,277 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Telling the application which of its sends the outcome of a user message is about, see
+//! `Event::SentUserMessage` and `Event::UnsentUserMessage`.
+//!
+//! Only the bytes of a message travel down to where its outcome is known, so the peer and token it
+//! was sent with are looked up by the address of the peer and the bytes of the message. Knowing
+//! the outcome also settles the message for `quorum`, `resend`, `expiry` and `app_ack`, and
+//! queues it as a dead letter if it failed and those are collected.
+
+use crate::context::{ctx_mut, Context};
+use crate::dead_letter::DeadLetter;
+use crate::error::Error;
+use crate::event::{Event, Token, UnsentReason};
+use crate::resend::{self, Verdict};
+use crate::utils;
+use crate::wire_msg::WireMsg;
+use crate::Peer;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+/// Number of the latest user messages awaiting an outcome remembered, in case some are never
+/// resolved.
+pub const WINDOW: usize = 4096;
+
+/// User messages sent, awaiting their outcome.
+#[derive(Default)]
+pub struct Outcomes {
+    sends: VecDeque<Send>,
+}
+
+struct Send {
+    peer: Peer,
+    msg: bytes::Bytes,
+    token: Token,
+}
+
+impl Outcomes {
+    /// Track the user message sent to the peer with the token. Other wire messages are ignored.
+    pub fn start(&mut self, peer: &Peer, wire_msg: &WireMsg, token: Token) {
+        let msg = match wire_msg.user_msg() {
+            Some(msg) => msg.clone(),
+            None => return,
+        };
+        if self.sends.len() >= WINDOW {
+            let _ = self.sends.pop_front();
+        }
+        self.sends.push_back(Send {
+            peer: peer.clone(),
+            msg,
+            token,
+        });
+    }
+
+    /// Stop tracking the oldest send of the message to the peer, returning the peer and token it
+    /// was sent with. Messages we didn't send ourselves, e.g. those relayed for a client, aren't
+    /// tracked.
+    pub fn resolve(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> Option<(Peer, Token)> {
+        let i = self
+            .sends
+            .iter()
+            .position(|send| send.peer.peer_addr() == peer_addr && send.msg == *msg)?;
+        self.sends
+            .remove(i)
+            .map(|Send { peer, token, .. }| (peer, token))
+    }
+}
+
+/// Take note of the user message written to the peer in full, which is delivered unless its
+/// acknowledgment is awaited.
+pub fn written(c: &mut Context, peer_addr: SocketAddr, msg: &bytes::Bytes) {
+    if !c.acks.written(peer_addr, msg) {
+        delivered(c, peer_addr, msg);
+    }
+}
+
+/// Take note of the user message the peer acknowledged as delivered.
+pub fn acked(c: &mut Context, peer_addr: SocketAddr, id: u64) {
+    match c.acks.acked(peer_addr, id) {
+        Some(msg) => delivered(c, peer_addr, &msg),
+        None => trace!("Acknowledgment {} from {} not awaited", id, peer_addr),
+    }
+}
+
+/// Report the user message as not sent to the peer, unless it is to be resent.
+pub fn unsent(c: &mut Context, peer_addr: SocketAddr, msg: bytes::Bytes) {
+    c.acks.resolve(peer_addr, &msg);
+    match c.resends.failed(peer_addr, &msg) {
+        Verdict::Report => (),
+        Verdict::Ignore => return,
+        Verdict::Resend(pending) => return resend::schedule(pending),
+    }
+    let _ = c.expiries.resolve(peer_addr, &msg);
+    report_unsent(c, peer_addr, msg, UnsentReason::Failed);
+}
+
+/// Report the user message as expired, unless its outcome is known already.
+pub fn expired(c: &mut Context, peer_addr: SocketAddr, msg: bytes::Bytes) {
+    if c.expiries.resolve(peer_addr, &msg) {
+        give_up(c, peer_addr, msg, UnsentReason::Expired);
+    }
+}
+
+/// Report the user message to the peer as unsent, as the queue of messages waiting for the
+/// connection to it is full.
+pub fn queue_full(c: &mut Context, peer_addr: SocketAddr, msg: bytes::Bytes) {
+    let _ = c.expiries.resolve(peer_addr, &msg);
+    give_up(c, peer_addr, msg, UnsentReason::QueueFull);
+}
+
+/// Report the user message to the peer as unsent, as the attempt to connect to it was aborted.
+pub fn aborted(c: &mut Context, peer_addr: SocketAddr, msg: bytes::Bytes) {
+    let _ = c.expiries.resolve(peer_addr, &msg);
+    give_up(c, peer_addr, msg, UnsentReason::Aborted);
+}
+
+/// Fail the user messages in flight to the peer now that our connection to it ended, resending
+/// those which are to be. Those to peers we disconnected from ourselves, or which moved to another
+/// address, are forgotten rather than resent. Called once the end was reported, so that the
+/// quorum sends it resolves are reported after it.
+pub fn connection_ended(c: &mut Context, peer_addr: SocketAddr, forget: bool) {
+    // Those to be resent are taken care of below
+    let unacked: Vec<_> = {
+        let resends = &c.resends;
+        c.acks
+            .fail_peer(peer_addr)
+            .into_iter()
+            .filter(|msg| !resends.is_tracked(peer_addr, msg))
+            .collect()
+    };
+    let (pending, given_up) = c.resends.fail_peer(peer_addr, forget);
+    for pending in pending {
+        resend::schedule(pending);
+    }
+    for event in c.quorums.fail_peer(peer_addr) {
+        let _ = c.event_tx.send(event);
+    }
+    for msg in given_up.into_iter().chain(unacked) {
+        unsent(c, peer_addr, msg);
+    }
+}
+
+/// Whether our connection to a peer failing with the error was us ending it, in which case the
+/// messages in flight to the peer are forgotten rather than resent.
+pub fn is_ended_by_us(err: &Error) -> bool {
+    match *err {
+        Error::ConnectionCancelled => true,
+        _ => false,
+    }
+}
+
+/// Report the user messages as not sent to the peer like `unsent`, once the context is released.
+pub fn unsent_later(peer_addr: SocketAddr, msgs: Vec<bytes::Bytes>) {
+    if !msgs.is_empty() {
+        utils::later(move || {
+            ctx_mut(|c| {
+                for msg in msgs {
+                    unsent(c, peer_addr, msg);
+                }
+            })
+        });
+    }
+}
+
+/// Take note of the acknowledgment like `acked`, once the context is released.
+pub fn acked_later(peer_addr: SocketAddr, id: u64) {
+    utils::later(move || ctx_mut(|c| acked(c, peer_addr, id)));
+}
+
+/// Fail the user messages in flight to the peer like `connection_ended`, once the context is
+/// released.
+pub fn connection_ended_later(peer_addr: SocketAddr, forget: bool) {
+    utils::later(move || ctx_mut(|c| connection_ended(c, peer_addr, forget)));
+}
+
+fn delivered(c: &mut Context, peer_addr: SocketAddr, msg: &bytes::Bytes) {
+    let _ = c.expiries.resolve(peer_addr, msg);
+    c.resends.delivered(peer_addr, msg);
+    let resolved = c.quorums.record(peer_addr, msg, true);
+    let sent = c.outcomes.resolve(peer_addr, msg);
+    if let (Some((peer, token)), true) = (sent, c.sent_events) {
+        let _ = c.event_tx.send(Event::SentUserMessage {
+            peer,
+            msg: msg.clone(),
+            token,
+        });
+    }
+    if let Some(event) = resolved {
+        let _ = c.event_tx.send(event);
+    }
+}
+
+fn give_up(c: &mut Context, peer_addr: SocketAddr, msg: bytes::Bytes, reason: UnsentReason) {
+    c.resends.cancel(peer_addr, &msg);
+    c.acks.resolve(peer_addr, &msg);
+    report_unsent(c, peer_addr, msg, reason);
+}
+
+fn report_unsent(c: &mut Context, peer_addr: SocketAddr, msg: bytes::Bytes, reason: UnsentReason) {
+    let resolved = c.quorums.record(peer_addr, &msg, false);
+    let (peer, token) = c
+        .outcomes
+        .resolve(peer_addr, &msg)
+        .unwrap_or((Peer::Client { peer_addr }, 0));
+    match c.dead_letters {
+        Some(ref mut dead_letters) => dead_letters.push(DeadLetter {
+            peer_addr,
+            msg,
+            token,
+        }),
+        None => {
+            let _ = c.event_tx.send(Event::UnsentUserMessage {
+                peer,
+                msg,
+                token,
+                reason,
+            });
+        }
+    }
+    if let Some(event) = resolved {
+        let _ = c.event_tx.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_are_resolved_oldest_first() {
+        let peer_addr = ([203, 0, 113, 1], 1000).into();
+        let peer = Peer::Client { peer_addr };
+        let msg = bytes::Bytes::from(&b"msg"[..]);
+        let mut outcomes = Outcomes::default();
+
+        outcomes.start(&peer, &WireMsg::UserMsg(msg.clone()), 1);
+        outcomes.start(
+            &peer,
+            &WireMsg::IdentifiedUserMsg {
+                id: 7,
+                msg: msg.clone(),
+            },
+            2,
+        );
+        outcomes.start(&peer, &WireMsg::EndpointEchoReq, 3);
+
+        assert_eq!(outcomes.resolve(peer_addr, &msg), Some((peer.clone(), 1)));
+        assert_eq!(outcomes.resolve(peer_addr, &msg), Some((peer, 2)));
+        assert_eq!(outcomes.resolve(peer_addr, &msg), None);
+    }
+
+    #[test]
+    fn only_the_latest_sends_are_remembered() {
+        let peer_addr = ([203, 0, 113, 1], 1000).into();
+        let peer = Peer::Client { peer_addr };
+        let mut outcomes = Outcomes::default();
+
+        for token in 0..=WINDOW as u64 {
+            let msg = bytes::Bytes::from(token.to_le_bytes().to_vec());
+            outcomes.start(&peer, &WireMsg::UserMsg(msg), token);
+        }
+        let oldest = bytes::Bytes::from(0u64.to_le_bytes().to_vec());
+        assert_eq!(outcomes.resolve(peer_addr, &oldest), None);
+        let newest = bytes::Bytes::from((WINDOW as u64).to_le_bytes().to_vec());
+        assert_eq!(
+            outcomes.resolve(peer_addr, &newest),
+            Some((peer, WINDOW as u64))
+        );
+    }
+}
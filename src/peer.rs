@@ -7,9 +7,19 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use crate::error::Error;
+#[cfg(feature = "multiaddr")]
+use crate::multi_addr;
 use crate::utils;
+#[cfg(feature = "multiaddr")]
+use multiaddr::Multiaddr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+
+/// Start of `NodeInfo` URLs, see `NodeInfo::to_url`.
+const URL_PREFIX: &str = "quic-p2p://";
 
 /// Representation of a peer to us.
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
@@ -47,17 +57,152 @@ impl Peer {
             Peer::Client { .. } => None,
         }
     }
+
+    /// The peer with its endpoint normalised, see `utils::normalise_addr`.
+    pub(crate) fn normalised(self) -> Self {
+        match self {
+            Peer::Node { mut node_info } => {
+                node_info.peer_addr = utils::normalise_addr(node_info.peer_addr);
+                Peer::Node { node_info }
+            }
+            Peer::Client { peer_addr } => Peer::Client {
+                peer_addr: utils::normalise_addr(peer_addr),
+            },
+        }
+    }
+
+    /// Get peer's Endpoint as a multiaddr, e.g. `/ip4/127.0.0.1/udp/5000/quic`.
+    #[cfg(feature = "multiaddr")]
+    pub fn peer_multiaddr(&self) -> Multiaddr {
+        multi_addr::to_multiaddr(self.peer_addr())
+    }
 }
 
 /// Information for a peer of type `Peer::Node`.
 ///
 /// This is a necessary information needed to connect to someone.
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NodeInfo {
     /// Endpoint of the node
     pub peer_addr: SocketAddr,
-    /// Certificate of the node
+    /// Certificate of the node. Human readable formats like TOML and JSON config files store it
+    /// as base64 and accept PEM text or an array of bytes as well.
     pub peer_cert_der: Vec<u8>,
+    /// Other endpoints the node is reachable at, e.g. its private address behind the NAT whose
+    /// public address is `peer_addr`. Alternates on one of our subnets are tried before
+    /// `peer_addr` when connecting, avoiding hairpinning through the NAT for co-located nodes.
+    ///
+    /// Only human readable formats and the URL form carry them. Binary forms like the bootstrap
+    /// cache and the messages on the wire keep the layout of older versions and drop them.
+    pub alt_addrs: Vec<SocketAddr>,
+}
+
+/// `NodeInfo` as human readable formats store it.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "NodeInfo")]
+struct TextNodeInfo {
+    peer_addr: SocketAddr,
+    #[serde(with = "crate::der_text")]
+    peer_cert_der: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    alt_addrs: Vec<SocketAddr>,
+}
+
+/// `NodeInfo` as binary formats store it, which can't tell a missing field.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "NodeInfo")]
+struct BinaryNodeInfo {
+    peer_addr: SocketAddr,
+    #[serde(with = "crate::der_text")]
+    peer_cert_der: Vec<u8>,
+}
+
+impl Serialize for NodeInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node_info = self.clone();
+        if serializer.is_human_readable() {
+            TextNodeInfo {
+                peer_addr: node_info.peer_addr,
+                peer_cert_der: node_info.peer_cert_der,
+                alt_addrs: node_info.alt_addrs,
+            }
+            .serialize(serializer)
+        } else {
+            BinaryNodeInfo {
+                peer_addr: node_info.peer_addr,
+                peer_cert_der: node_info.peer_cert_der,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let node_info = TextNodeInfo::deserialize(deserializer)?;
+            Ok(NodeInfo {
+                peer_addr: node_info.peer_addr,
+                peer_cert_der: node_info.peer_cert_der,
+                alt_addrs: node_info.alt_addrs,
+            })
+        } else {
+            let node_info = BinaryNodeInfo::deserialize(deserializer)?;
+            Ok(NodeInfo {
+                peer_addr: node_info.peer_addr,
+                peer_cert_der: node_info.peer_cert_der,
+                alt_addrs: Vec::new(),
+            })
+        }
+    }
+}
+
+impl NodeInfo {
+    /// `NodeInfo` as a URL of the form `quic-p2p://<peer_addr>?cert=<base64url encoded
+    /// peer_cert_der>`, e.g. `quic-p2p://127.0.0.1:5000?cert=MIIBXzCCAQWgAwIBAgIB...`, followed
+    /// by an `&alt=<addr>` parameter for each of `alt_addrs`. Suitable for invite links and QR
+    /// codes, it can be parsed back with `str::parse` like the other textual forms. Parsed URLs
+    /// may name the host by a DNS name which is then resolved on the calling thread.
+    pub fn to_url(&self) -> String {
+        let mut url = format!(
+            "{}{}?cert={}",
+            URL_PREFIX,
+            self.peer_addr,
+            base64::encode_config(&self.peer_cert_der, base64::URL_SAFE_NO_PAD)
+        );
+        for alt_addr in &self.alt_addrs {
+            url.push_str(&format!("&alt={}", alt_addr));
+        }
+        url
+    }
+
+    /// The node with its endpoints normalised, see `utils::normalise_addr`.
+    pub(crate) fn normalised(mut self) -> Self {
+        self.peer_addr = utils::normalise_addr(self.peer_addr);
+        for alt_addr in &mut self.alt_addrs {
+            *alt_addr = utils::normalise_addr(*alt_addr);
+        }
+        self
+    }
+}
+
+#[cfg(feature = "multiaddr")]
+impl NodeInfo {
+    /// Endpoint of the node as a multiaddr, e.g. `/ip4/127.0.0.1/udp/5000/quic`.
+    pub fn peer_multiaddr(&self) -> Multiaddr {
+        multi_addr::to_multiaddr(self.peer_addr)
+    }
+
+    /// Like the compact textual form of `Display` but with the endpoint as a multiaddr, e.g.
+    /// `/ip4/127.0.0.1/udp/5000/quic/MIIBXzCCAQWgAwIBAgIB...`. It can be parsed back with
+    /// `str::parse` too.
+    pub fn to_multiaddr_string(&self) -> String {
+        format!(
+            "{}/{}",
+            self.peer_multiaddr(),
+            base64::encode(&self.peer_cert_der)
+        )
+    }
 }
 
 impl Into<Peer> for NodeInfo {
@@ -66,13 +211,333 @@ impl Into<Peer> for NodeInfo {
     }
 }
 
+/// Services a peer advertises in its handshake, see `Builder::with_capabilities`. Their meaning
+/// is entirely up to the application.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct Capabilities {
+    /// Application-defined flags.
+    pub flags: u64,
+    /// Application-defined role, e.g. `"archive"`. Empty if the peer has none.
+    pub role: String,
+}
+
+impl Capabilities {
+    /// Whether no flags and no role are advertised.
+    pub fn is_empty(&self) -> bool {
+        self.flags == 0 && self.role.is_empty()
+    }
+
+    /// Whether all the given flags are advertised.
+    pub fn has_flags(&self, flags: u64) -> bool {
+        self.flags & flags == flags
+    }
+}
+
+/// `NodeInfo` in its compact textual form `<peer_addr>/<base64 encoded peer_cert_der>`, e.g.
+/// `127.0.0.1:5000/MIIBXzCCAQWgAwIBAgIB...`. It can be parsed back with `str::parse`.
 impl fmt::Display for NodeInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "NodeInfo {{ peer_addr: {}, peer_cert_der: {} }}",
+            "{}/{}",
             self.peer_addr,
-            utils::bin_data_format(&self.peer_cert_der)
+            base64::encode(&self.peer_cert_der)
         )
     }
 }
+
+impl FromStr for NodeInfo {
+    type Err = Error;
+
+    /// Parse `NodeInfo` from its compact textual form, see the `Display` implementation, or from
+    /// the URL form of `to_url`. With the `multiaddr` feature the form of `to_multiaddr_string`
+    /// is accepted as well.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(url) = strip_url_scheme(s) {
+            return from_url_str(url);
+        }
+
+        #[cfg(feature = "multiaddr")]
+        {
+            if s.starts_with('/') {
+                return from_multiaddr_str(s);
+            }
+        }
+
+        let mut parts = s.splitn(2, '/');
+        let peer_addr = unwrap!(parts.next())
+            .parse()
+            .map_err(|_| Error::InvalidNodeInfo("invalid peer address"))?;
+        let peer_cert_der = match parts.next() {
+            Some(cert) if !cert.is_empty() => base64::decode(cert)?,
+            _ => return Err(Error::InvalidNodeInfo("missing certificate")),
+        };
+
+        Ok(Self {
+            peer_addr,
+            peer_cert_der,
+            alt_addrs: Vec::new(),
+        })
+    }
+}
+
+/// The part of the given string following `quic-p2p://`, if it starts with that.
+fn strip_url_scheme(s: &str) -> Option<&str> {
+    match s.get(..URL_PREFIX.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(URL_PREFIX) => Some(&s[URL_PREFIX.len()..]),
+        _ => None,
+    }
+}
+
+/// Parse `<host>:<port>[/]?cert=<base64 encoded peer_cert_der>[&alt=<addr>...][&...][#...]`, the
+/// URL form without its scheme. Other query parameters are ignored. The certificate may be
+/// base64url or, percent encoded or not, standard base64.
+fn from_url_str(s: &str) -> Result<NodeInfo, Error> {
+    let s = s.split('#').next().unwrap_or_default();
+    let mut parts = s.splitn(2, '?');
+    let authority = unwrap!(parts.next()).trim_end_matches('/');
+    let params: Vec<(&str, &str)> = parts
+        .next()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => Some((k, v)),
+                _ => None,
+            }
+        })
+        .collect();
+    let cert = params
+        .iter()
+        .find(|(k, _)| *k == "cert")
+        .map(|(_, v)| *v)
+        .ok_or(Error::InvalidNodeInfo("missing certificate"))?;
+    if cert.is_empty() {
+        return Err(Error::InvalidNodeInfo("missing certificate"));
+    }
+
+    let peer_addr = match authority.parse() {
+        Ok(peer_addr) => peer_addr,
+        Err(_) if authority.contains(':') => authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::InvalidNodeInfo("host resolves to no address"))?,
+        Err(_) => return Err(Error::InvalidNodeInfo("invalid peer address")),
+    };
+
+    let cert = percent_decode(cert)?;
+    let peer_cert_der = if cert.contains(['+', '/']) {
+        base64::decode(&cert)?
+    } else {
+        base64::decode_config(cert.trim_end_matches('='), base64::URL_SAFE_NO_PAD)?
+    };
+
+    let alt_addrs = params
+        .iter()
+        .filter(|(k, _)| *k == "alt")
+        .map(|(_, v)| {
+            percent_decode(v)?
+                .parse()
+                .map_err(|_| Error::InvalidNodeInfo("invalid alternate address"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(NodeInfo {
+        peer_addr,
+        peer_cert_der,
+        alt_addrs,
+    })
+}
+
+/// Decode the `%XX` escapes of a URL component.
+fn percent_decode(s: &str) -> Result<String, Error> {
+    let invalid = || Error::InvalidNodeInfo("invalid percent encoding");
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Parse `<multiaddr>/<base64 encoded peer_cert_der>`. The multiaddr ends at its `quic` protocol.
+#[cfg(feature = "multiaddr")]
+fn from_multiaddr_str(s: &str) -> Result<NodeInfo, Error> {
+    let (addr, cert) = ["/quic/", "/quic-v1/"]
+        .iter()
+        .filter_map(|quic| s.find(quic).map(|i| s.split_at(i + quic.len())))
+        .min_by_key(|(addr, _)| addr.len())
+        .ok_or(Error::InvalidNodeInfo("missing certificate"))?;
+    if cert.is_empty() {
+        return Err(Error::InvalidNodeInfo("missing certificate"));
+    }
+
+    Ok(NodeInfo {
+        peer_addr: multi_addr::parse(addr.trim_end_matches('/'))?,
+        peer_cert_der: base64::decode(cert)?,
+        alt_addrs: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::rand_node_info;
+
+    #[test]
+    fn node_info_round_trips_through_its_textual_form() {
+        let node_info = rand_node_info();
+        let s = node_info.to_string();
+
+        assert!(s.starts_with(&format!("{}/", node_info.peer_addr)));
+        assert_eq!(unwrap!(s.parse::<NodeInfo>()), node_info);
+
+        let v6 = NodeInfo {
+            peer_addr: unwrap!("[::1]:5000".parse()),
+            peer_cert_der: vec![1, 2, 3],
+            alt_addrs: Vec::new(),
+        };
+        assert_eq!(v6.to_string(), "[::1]:5000/AQID");
+        assert_eq!(unwrap!("[::1]:5000/AQID".parse::<NodeInfo>()), v6);
+    }
+
+    #[test]
+    fn malformed_node_info_is_rejected() {
+        for s in &["", "127.0.0.1:5000", "127.0.0.1:5000/", "127.0.0.1/AQID"] {
+            match s.parse::<NodeInfo>() {
+                Err(Error::InvalidNodeInfo(_)) => (),
+                r => panic!("Unexpected result for {:?}: {:?}", s, r),
+            }
+        }
+        match "127.0.0.1:5000/not*base64".parse::<NodeInfo>() {
+            Err(Error::Base64(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn node_info_round_trips_through_its_url_form() {
+        let node_info = NodeInfo {
+            peer_addr: unwrap!("127.0.0.1:5000".parse()),
+            peer_cert_der: vec![0xfb, 0xff, 0xfe],
+            alt_addrs: Vec::new(),
+        };
+        let url = node_info.to_url();
+
+        assert_eq!(url, "quic-p2p://127.0.0.1:5000?cert=-__-");
+        assert_eq!(unwrap!(url.parse::<NodeInfo>()), node_info);
+        for s in &[
+            "QUIC-P2P://127.0.0.1:5000/?cert=-__-",
+            "quic-p2p://127.0.0.1:5000?name=alice&cert=%2B%2F%2F%2B#invite",
+            "quic-p2p://127.0.0.1:5000?cert=+//+",
+            "quic-p2p://localhost:5000?cert=-__-",
+        ] {
+            let parsed = unwrap!(s.parse::<NodeInfo>());
+            assert_eq!(parsed.peer_cert_der, node_info.peer_cert_der);
+            assert_eq!(parsed.peer_addr.port(), 5000);
+            assert!(parsed.peer_addr.ip().is_loopback());
+        }
+
+        let node_info = rand_node_info();
+        assert_eq!(unwrap!(node_info.to_url().parse::<NodeInfo>()), node_info);
+        let v6 = NodeInfo {
+            peer_addr: unwrap!("[::1]:5000".parse()),
+            peer_cert_der: vec![1, 2, 3],
+            alt_addrs: Vec::new(),
+        };
+        assert_eq!(v6.to_url(), "quic-p2p://[::1]:5000?cert=AQID");
+        assert_eq!(unwrap!(v6.to_url().parse::<NodeInfo>()), v6);
+    }
+
+    #[test]
+    fn alternate_addresses_are_kept_by_human_readable_forms_only() {
+        let lan: SocketAddr = unwrap!("10.0.0.2:5000".parse());
+        let v6: SocketAddr = unwrap!("[fd00::2]:5000".parse());
+        let node_info = NodeInfo {
+            alt_addrs: vec![lan, v6],
+            ..rand_node_info()
+        };
+
+        let url = node_info.to_url();
+        assert!(url.ends_with("&alt=10.0.0.2:5000&alt=[fd00::2]:5000"));
+        assert_eq!(unwrap!(url.parse::<NodeInfo>()), node_info);
+        let json = unwrap!(serde_json::to_string(&node_info));
+        assert_eq!(unwrap!(serde_json::from_str::<NodeInfo>(&json)), node_info);
+
+        let bytes = unwrap!(bincode::serialize(&node_info));
+        let plain = NodeInfo {
+            alt_addrs: Vec::new(),
+            ..node_info.clone()
+        };
+        assert_eq!(bytes, unwrap!(bincode::serialize(&plain)));
+        assert_eq!(unwrap!(bincode::deserialize::<NodeInfo>(&bytes)), plain);
+
+        // Without alternates the human readable forms look like those of older versions
+        let json = unwrap!(serde_json::to_string(&plain));
+        assert!(!json.contains("alt_addrs"));
+        match "quic-p2p://127.0.0.1:5000?cert=AQID&alt=nowhere".parse::<NodeInfo>() {
+            Err(Error::InvalidNodeInfo(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn malformed_node_info_urls_are_rejected() {
+        for s in &[
+            "quic-p2p://127.0.0.1:5000",
+            "quic-p2p://127.0.0.1:5000?cert=",
+            "quic-p2p://127.0.0.1:5000?certificate=AQID",
+            "quic-p2p://127.0.0.1?cert=AQID",
+            "quic-p2p://127.0.0.1:5000?cert=%zz",
+        ] {
+            match s.parse::<NodeInfo>() {
+                Err(Error::InvalidNodeInfo(_)) => (),
+                r => panic!("Unexpected result for {:?}: {:?}", s, r),
+            }
+        }
+        match "quic-p2p://127.0.0.1:5000?cert=not*base64".parse::<NodeInfo>() {
+            Err(Error::Base64(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "multiaddr")]
+    #[test]
+    fn node_info_round_trips_through_its_multiaddr_form() {
+        let node_info = NodeInfo {
+            peer_addr: unwrap!("127.0.0.1:5000".parse()),
+            peer_cert_der: vec![1, 2, 3],
+            alt_addrs: Vec::new(),
+        };
+        let s = node_info.to_multiaddr_string();
+
+        assert_eq!(s, "/ip4/127.0.0.1/udp/5000/quic/AQID");
+        assert_eq!(unwrap!(s.parse::<NodeInfo>()), node_info);
+        assert_eq!(
+            unwrap!("/ip4/127.0.0.1/udp/5000/quic-v1/AQID".parse::<NodeInfo>()),
+            node_info
+        );
+
+        let node_info = rand_node_info();
+        assert_eq!(
+            unwrap!(node_info.to_multiaddr_string().parse::<NodeInfo>()),
+            node_info
+        );
+
+        match "/ip4/127.0.0.1/udp/5000/quic".parse::<NodeInfo>() {
+            Err(Error::InvalidNodeInfo(_)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+}
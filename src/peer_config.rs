@@ -8,6 +8,7 @@
 // Software.
 
 use crate::context::ctx;
+use crate::wire_msg::WireEncoding;
 use crate::R;
 use std::sync::Arc;
 
@@ -33,7 +34,9 @@ pub fn new_client_cfg(peer_cert_der: &[u8]) -> R<quinn::ClientConfig> {
 
         quinn::ClientConfigBuilder::new(client_cfg)
     };
-    let _ = peer_cfg_builder.add_certificate_authority(peer_cert)?;
+    let _ = peer_cfg_builder
+        .add_certificate_authority(peer_cert)?
+        .protocols(&WireEncoding::alpn_protocols(ctx(|c| c.wire_encoding)));
 
     Ok(peer_cfg_builder.build())
 }
@@ -43,6 +46,7 @@ pub fn new_our_cfg(
     keep_alive_interval_msec: u32,
     our_cert: quinn::Certificate,
     our_key: quinn::PrivateKey,
+    wire_encoding: WireEncoding,
 ) -> R<quinn::ServerConfig> {
     let mut our_cfg_builder = {
         let mut our_cfg = quinn::ServerConfig::default();
@@ -55,7 +59,8 @@ pub fn new_our_cfg(
     };
     let _ = our_cfg_builder
         .certificate(quinn::CertificateChain::from_certs(vec![our_cert]), our_key)?
-        .use_stateless_retry(true);
+        .use_stateless_retry(true)
+        .protocols(&WireEncoding::alpn_protocols(wire_encoding));
 
     Ok(our_cfg_builder.build())
 }
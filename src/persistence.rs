@@ -0,0 +1,270 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Reading and writing our binary files, e.g. the bootstrap cache.
+//!
+//! Every file starts with a header: the magic bytes `QP2P`, the format version as a little endian
+//! `u16`, then the length and the CRC32 (IEEE) of the bincode payload following the header as a
+//! little endian `u64` and `u32`. Files are written to a temporary file first, synced to the disk,
+//! which then replaces the target, so that a crash mid-write doesn't leave a truncated file
+//! behind. Files which mustn't be lost, e.g. the bootstrap cache, also keep the copy they replace
+//! as a `.bak` backup to fall back to should they still turn out corrupt.
+
+use crate::error::Error;
+use crate::utils::R;
+use crc::crc32;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"QP2P";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 4 + 2 + 8 + 4;
+
+/// Try reading from the disk into the given structure.
+///
+/// Files written before headers were introduced are read as plain bincode.
+#[cfg_attr(feature = "client-only", allow(dead_code))]
+pub fn read_from_disk<D>(file_path: &Path) -> R<D>
+where
+    D: DeserializeOwned,
+{
+    match read_payload(file_path)? {
+        Payload::Checked(payload) => Ok(bincode::deserialize(&payload)?),
+        Payload::Legacy(data) => bincode::deserialize(&data).map_err(|_| Error::CorruptFile {
+            path: file_path.to_path_buf(),
+            reason: "missing header",
+        }),
+    }
+}
+
+/// Try writing the given structure to the disk.
+#[cfg_attr(feature = "client-only", allow(dead_code))]
+pub fn write_to_disk<S>(file_path: &Path, s: &S) -> R<()>
+where
+    S: Serialize,
+{
+    write_payload(file_path, &bincode::serialize(s)?)
+}
+
+/// Try reading from the disk like `read_from_disk`, falling back to the backup `write_with_backup`
+/// kept should the file be unreadable.
+pub fn read_with_backup<D>(file_path: &Path) -> R<D>
+where
+    D: DeserializeOwned,
+{
+    let backup_path = backup_path(file_path);
+    match read_from_disk(file_path) {
+        Err(e @ Error::CorruptFile { .. }) | Err(e @ Error::Bincode(_)) | Err(e @ Error::Io(_))
+            if backup_path.exists() =>
+        {
+            warn!("Reading the backup of {}: {}", file_path.display(), e);
+            read_from_disk(&backup_path).map_err(|_| e)
+        }
+        r => r,
+    }
+}
+
+/// Try writing the given structure to the disk like `write_to_disk`, keeping the file it replaces
+/// as a backup provided that is intact.
+#[cfg_attr(feature = "client-only", allow(dead_code))]
+pub fn write_with_backup<S>(file_path: &Path, s: &S) -> R<()>
+where
+    S: Serialize,
+{
+    if let Ok(Payload::Checked(_)) = read_payload(file_path) {
+        let backup_path = backup_path(file_path);
+        let tmp_path = tmp_path(&backup_path);
+        let _ = fs::copy(file_path, &tmp_path)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &backup_path)?;
+    }
+    write_to_disk(file_path, s)
+}
+
+fn backup_path(file_path: &Path) -> PathBuf {
+    file_path.with_extension("bak")
+}
+
+/// Temporary file the given one is written to first, named after the whole file name so that
+/// files differing only in their extension don't share it.
+fn tmp_path(file_path: &Path) -> PathBuf {
+    let mut file_name = file_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".tmp");
+    file_path.with_file_name(file_name)
+}
+
+/// Contents of a file following its header.
+pub enum Payload {
+    /// Payload of a file with a header, its length and checksum verified.
+    Checked(Vec<u8>),
+    /// Whole contents of a file written before headers were introduced.
+    Legacy(Vec<u8>),
+}
+
+/// Read the payload of the given file, for callers which decode it themselves.
+pub fn read_payload(file_path: &Path) -> R<Payload> {
+    let data = fs::read(file_path)?;
+    let corrupt = |reason| Error::CorruptFile {
+        path: file_path.to_path_buf(),
+        reason,
+    };
+
+    if !data.starts_with(MAGIC) {
+        return Ok(Payload::Legacy(data));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(corrupt("truncated header"));
+    }
+
+    let version = u16::from_le_bytes(unwrap!(data[4..6].try_into()));
+    if version != VERSION {
+        return Err(corrupt("unsupported version"));
+    }
+    let len = u64::from_le_bytes(unwrap!(data[6..14].try_into()));
+    let crc = u32::from_le_bytes(unwrap!(data[14..HEADER_LEN].try_into()));
+
+    let payload = &data[HEADER_LEN..];
+    if payload.len() as u64 != len {
+        return Err(corrupt("truncated payload"));
+    }
+    if crc32::checksum_ieee(payload) != crc {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    Ok(Payload::Checked(payload.to_vec()))
+}
+
+/// Write the given payload to the disk, preceded by a header.
+pub fn write_payload(file_path: &Path, payload: &[u8]) -> R<()> {
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(&crc32::checksum_ieee(payload).to_le_bytes());
+    data.extend_from_slice(payload);
+
+    let tmp_path = tmp_path(file_path);
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, file_path)?;
+    sync_parent_dir(file_path);
+
+    Ok(())
+}
+
+/// Make the rename of a file stick across crashes. Directories can't be synced everywhere, so
+/// this is best effort.
+fn sync_parent_dir(file_path: &Path) {
+    #[cfg(unix)]
+    {
+        if let Some(dir) = file_path.parent().and_then(|dir| File::open(dir).ok()) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = file_path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tmp_rand_dir;
+
+    #[test]
+    fn corruption_is_detected() {
+        let dir = tmp_rand_dir();
+        unwrap!(fs::create_dir_all(&dir));
+        let path = dir.join("file");
+        let value = vec![1u32, 2, 3];
+
+        unwrap!(write_to_disk(&path, &value));
+        assert_eq!(unwrap!(read_from_disk::<Vec<u32>>(&path)), value);
+
+        let data = unwrap!(fs::read(&path));
+
+        let mut flipped = data.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0xff;
+        unwrap!(fs::write(&path, &flipped));
+        match read_from_disk::<Vec<u32>>(&path) {
+            Err(Error::CorruptFile { reason, .. }) => assert_eq!(reason, "checksum mismatch"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+
+        unwrap!(fs::write(&path, &data[..data.len() - 1]));
+        match read_from_disk::<Vec<u32>>(&path) {
+            Err(Error::CorruptFile { reason, .. }) => assert_eq!(reason, "truncated payload"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn corrupt_files_are_read_from_their_backup() {
+        let dir = tmp_rand_dir();
+        unwrap!(fs::create_dir_all(&dir));
+        let path = dir.join("file");
+
+        unwrap!(write_with_backup(&path, &vec![1u32]));
+        assert!(!backup_path(&path).exists());
+        unwrap!(write_with_backup(&path, &vec![1u32, 2]));
+        assert_eq!(unwrap!(read_with_backup::<Vec<u32>>(&path)), [1, 2]);
+
+        let mut data = unwrap!(fs::read(&path));
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        unwrap!(fs::write(&path, &data));
+        assert_eq!(unwrap!(read_with_backup::<Vec<u32>>(&path)), [1]);
+
+        // A corrupt file is not backed up over the intact copy
+        unwrap!(write_with_backup(&path, &vec![1u32, 2, 3]));
+        assert_eq!(
+            unwrap!(read_from_disk::<Vec<u32>>(&backup_path(&path))),
+            [1]
+        );
+
+        unwrap!(fs::remove_file(backup_path(&path)));
+        unwrap!(fs::write(&path, &data));
+        match read_with_backup::<Vec<u32>>(&path) {
+            Err(Error::CorruptFile { reason, .. }) => assert_eq!(reason, "checksum mismatch"),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn files_differing_in_extension_have_their_own_tmp_file() {
+        let dir = tmp_rand_dir();
+        assert_eq!(
+            tmp_path(&dir.join("config.toml")),
+            dir.join("config.toml.tmp")
+        );
+        assert_eq!(
+            tmp_path(&dir.join("config.json")),
+            dir.join("config.json.tmp")
+        );
+        assert_eq!(tmp_path(&dir.join("config")), dir.join("config.tmp"));
+    }
+
+    #[test]
+    fn files_without_header_are_read_as_bincode() {
+        let dir = tmp_rand_dir();
+        unwrap!(fs::create_dir_all(&dir));
+        let path = dir.join("file");
+        let value = vec![1u32, 2, 3];
+
+        unwrap!(fs::write(&path, unwrap!(bincode::serialize(&value))));
+        assert_eq!(unwrap!(read_from_disk::<Vec<u32>>(&path)), value);
+    }
+}
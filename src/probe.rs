@@ -0,0 +1,331 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Probing that the event loops of our peers still answer, see `Builder::with_probes`, and
+//! probing the peers which went quiet, see `Config::node_liveness_msec`.
+//!
+//! QUIC keep-alives are answered by the transport of the peer, even with its event loop stuck.
+//! A `WireMsg::Ping` is only answered with a `WireMsg::Pong` by the event loop itself, which gives
+//! the round trip time as it is seen by the application too.
+//!
+//! Both kinds of probes share the nonces and the misses of a connection. Probes of
+//! `Builder::with_probes` go to every peer at the interval given. Quiet peers are only probed once
+//! we heard nothing from them for longer than the threshold of their type: our own probes don't
+//! count, but the `WireMsg::Pong` answering one does. A peer still quiet once the quarter of the
+//! threshold after its last probe passed is probed again, and disconnected with
+//! `Error::ProbesUnanswered` after `Config::liveness_probes` probes.
+
+use crate::communicate;
+use crate::context::ctx_mut;
+use crate::error::Error;
+use crate::event::Event;
+use crate::wire_msg::WireMsg;
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::Stream;
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// Number of probes a quiet peer has to leave unanswered by default, see
+/// `Config::liveness_probes`.
+pub const DEFAULT_LIVENESS_PROBES: u32 = 3;
+
+/// How long peers may stay quiet before they are probed, of `Config::node_liveness_msec` and
+/// the fields following it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LivenessThresholds {
+    /// Threshold of the nodes, if they are probed.
+    pub node: Option<Duration>,
+    /// Threshold of the clients connected to us, if they are probed.
+    pub client: Option<Duration>,
+    /// Number of probes to leave unanswered to be disconnected.
+    pub probes: u32,
+}
+
+/// When to probe our peers.
+#[derive(Debug, Clone, Copy)]
+pub struct Schedule {
+    /// Interval and most probes in a row to miss of `Builder::with_probes`.
+    pub every: Option<(Duration, u32)>,
+    /// Thresholds of the quiet peers.
+    pub quiet: LivenessThresholds,
+}
+
+/// What to do about a quiet peer as of a check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Alive,
+    Probe,
+    Unresponsive,
+}
+
+/// The probes of a connection.
+#[derive(Debug, Default)]
+pub struct Probe {
+    next_nonce: u64,
+    unanswered: Option<(u64, Instant)>,
+    misses: u32,
+    streams_received: u64,
+    /// When we first looked or last noticed streams received from the peer.
+    quiet_since: Option<Instant>,
+    quiet_probes: u32,
+}
+
+impl Probe {
+    /// Nonce of the next probe sent at `now`, counting the previous one as missed if it is still
+    /// unanswered.
+    pub fn next(&mut self, now: Instant) -> u64 {
+        if self.unanswered.is_some() {
+            self.misses += 1;
+        }
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.unanswered = Some((nonce, now));
+        nonce
+    }
+
+    /// Number of probes in a row that went unanswered.
+    pub fn misses(&self) -> u32 {
+        self.misses
+    }
+
+    /// Round trip time of the probe answered at `at`, unless it isn't the latest one.
+    pub fn answered(&mut self, nonce: u64, at: Instant) -> Option<Duration> {
+        match self.unanswered {
+            Some((sent_nonce, sent_at)) if sent_nonce == nonce => {
+                self.unanswered = None;
+                self.misses = 0;
+                Some(at.duration_since(sent_at))
+            }
+            _ => None,
+        }
+    }
+
+    fn check_quiet(
+        &mut self,
+        streams_received: u64,
+        now: Instant,
+        threshold: Duration,
+        probes: u32,
+    ) -> Verdict {
+        let since = match self.quiet_since {
+            Some(since) if streams_received == self.streams_received => since,
+            _ => {
+                self.streams_received = streams_received;
+                self.quiet_since = Some(now);
+                self.quiet_probes = 0;
+                return Verdict::Alive;
+            }
+        };
+        let quiet_for = now.duration_since(since);
+        if quiet_for < threshold {
+            return Verdict::Alive;
+        }
+
+        // The probes due by now, a quarter of the threshold apart
+        let spacing = quiet_spacing(threshold);
+        let due = (quiet_for - threshold).as_nanos() / spacing.as_nanos() + 1;
+        if u128::from(self.quiet_probes) >= due {
+            Verdict::Alive
+        } else if self.quiet_probes < probes {
+            self.quiet_probes += 1;
+            Verdict::Probe
+        } else {
+            Verdict::Unresponsive
+        }
+    }
+}
+
+fn quiet_spacing(threshold: Duration) -> Duration {
+    cmp::max(threshold / 4, Duration::from_millis(1))
+}
+
+/// Probe the peers as scheduled, dropping them with `Error::ProbesUnanswered` once they left
+/// too many probes unanswered. Probes of `Builder::with_probes` have to be missed at least once.
+///
+/// The peers are checked every quarter of the lower quiet threshold, or at the interval of
+/// `Builder::with_probes` if that is shorter.
+pub fn start(schedule: Schedule) {
+    let every = schedule
+        .every
+        .map(|(interval, max_misses)| (interval, cmp::max(max_misses, 1)));
+    let quiet = schedule.quiet;
+    let quiet_period = match (quiet.node, quiet.client) {
+        (Some(node), Some(client)) => Some(quiet_spacing(cmp::min(node, client))),
+        (Some(threshold), None) | (None, Some(threshold)) => Some(quiet_spacing(threshold)),
+        (None, None) => None,
+    };
+    let period = match (every.map(|(interval, _)| interval), quiet_period) {
+        (Some(interval), Some(quiet_period)) => cmp::min(interval, quiet_period),
+        (Some(period), None) | (None, Some(period)) => period,
+        (None, None) => return,
+    };
+
+    let now = Instant::now();
+    let mut next_round = every.map(|(interval, _)| now + interval);
+    let leaf = Interval::new(now + period, period)
+        .map_err(|e| info!("Error in probe interval: {:?}", e))
+        .for_each(move |deadline| {
+            // Whether all peers are due a probe of `Builder::with_probes`
+            let max_misses = match (every, next_round) {
+                (Some((interval, max_misses)), Some(round)) if deadline >= round => {
+                    let mut round = round;
+                    while round <= deadline {
+                        round += interval;
+                    }
+                    next_round = Some(round);
+                    Some(max_misses)
+                }
+                _ => None,
+            };
+            probe(max_misses, quiet);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn probe(max_misses: Option<u32>, quiet: LivenessThresholds) {
+    let now = Instant::now();
+    let pings = ctx_mut(|c| {
+        let clock_now = c.clock.now();
+        let mut pings = Vec::new();
+        let mut unresponsive = Vec::new();
+        for (peer_addr, conn) in c.connections.iter_mut() {
+            if !conn.is_complete() {
+                continue;
+            }
+            let threshold = if conn.client_session.is_some() {
+                quiet.client
+            } else {
+                quiet.node
+            };
+            let verdict = match threshold {
+                Some(threshold) => {
+                    let streams_received = conn.stats().streams_received;
+                    conn.probe
+                        .check_quiet(streams_received, clock_now, threshold, quiet.probes)
+                }
+                None => Verdict::Alive,
+            };
+            if verdict == Verdict::Unresponsive {
+                debug!(
+                    "Peer {} stayed quiet through {} probes",
+                    peer_addr, quiet.probes
+                );
+                unresponsive.push((*peer_addr, quiet.probes));
+                continue;
+            }
+            if verdict == Verdict::Alive && max_misses.is_none() {
+                continue;
+            }
+
+            let nonce = conn.probe.next(now);
+            match max_misses {
+                Some(max_misses) if conn.probe.misses() >= max_misses => {
+                    debug!("Peer {} missed {} probes in a row", peer_addr, max_misses);
+                    unresponsive.push((*peer_addr, max_misses));
+                }
+                _ => pings.push((*peer_addr, nonce)),
+            }
+        }
+        for (peer_addr, probes) in unresponsive {
+            if let Some(mut conn) = c.connections.remove(&peer_addr) {
+                // Reported as the connection is dropped
+                conn.cancel_reason = Some(Error::ProbesUnanswered(probes));
+            }
+        }
+        pings
+    });
+
+    for (peer_addr, nonce) in pings {
+        trace!("Probing peer {}", peer_addr);
+        communicate::write_to_peer(peer_addr, WireMsg::Ping { nonce });
+    }
+}
+
+/// Take note of the peer answering the probe at `at`, firing `Event::PeerRtt`.
+pub fn answered(peer_addr: SocketAddr, nonce: u64, at: Instant) {
+    ctx_mut(|c| {
+        let rtt = match c.connections.get_mut(&peer_addr) {
+            Some(conn) => conn.probe.answered(nonce, at),
+            None => None,
+        };
+        let rtt = match rtt {
+            Some(rtt) => rtt,
+            None => return trace!("Ignoring stale pong {} from peer {}", nonce, peer_addr),
+        };
+        if let Err(e) = c.event_tx.send(Event::PeerRtt { peer_addr, rtt }) {
+            info!("Could not fire event: {:?}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misses_count_until_the_latest_probe_is_answered() {
+        let start = Instant::now();
+        let mut probe = Probe::default();
+
+        let first = probe.next(start);
+        assert_eq!(probe.misses(), 0);
+        let second = probe.next(start + Duration::from_secs(1));
+        assert_eq!(probe.misses(), 1);
+
+        // Too late, as another probe was sent since
+        assert_eq!(probe.answered(first, start + Duration::from_secs(2)), None);
+        assert_eq!(probe.misses(), 1);
+
+        assert_eq!(
+            probe.answered(second, start + Duration::from_secs(3)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(probe.misses(), 0);
+        assert_eq!(probe.answered(second, start + Duration::from_secs(3)), None);
+
+        let _ = probe.next(start + Duration::from_secs(4));
+        assert_eq!(probe.misses(), 0);
+    }
+
+    #[test]
+    fn quiet_peers_are_probed_until_they_answer() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let threshold = Duration::from_millis(400);
+        let mut probe = Probe::default();
+
+        assert_eq!(probe.check_quiet(0, at(0), threshold, 2), Verdict::Alive);
+        assert_eq!(probe.check_quiet(0, at(399), threshold, 2), Verdict::Alive);
+        assert_eq!(probe.check_quiet(0, at(400), threshold, 2), Verdict::Probe);
+        // Probed already, with the next probe due a quarter of the threshold later
+        assert_eq!(probe.check_quiet(0, at(450), threshold, 2), Verdict::Alive);
+        assert_eq!(probe.check_quiet(0, at(500), threshold, 2), Verdict::Probe);
+
+        // Answered, so quiet from then on
+        assert_eq!(probe.check_quiet(1, at(550), threshold, 2), Verdict::Alive);
+        assert_eq!(probe.check_quiet(1, at(900), threshold, 2), Verdict::Alive);
+        assert_eq!(probe.check_quiet(1, at(950), threshold, 2), Verdict::Probe);
+        assert_eq!(
+            probe.check_quiet(1, at(1_050), threshold, 2),
+            Verdict::Probe
+        );
+        assert_eq!(
+            probe.check_quiet(1, at(1_100), threshold, 2),
+            Verdict::Alive
+        );
+        assert_eq!(
+            probe.check_quiet(1, at(1_150), threshold, 2),
+            Verdict::Unresponsive
+        );
+    }
+}
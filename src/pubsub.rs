@@ -0,0 +1,166 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Publishing messages to string topics, see `QuicP2p::subscribe` and `QuicP2p::publish`.
+//!
+//! Peers tell each other which topics they are subscribed to as soon as they are connected and
+//! whenever that changes. A message published is sent to the connected peers subscribed to its
+//! topic, each forwarding it on to the subscribers they are connected to in turn until `MAX_HOPS`
+//! is reached. Every message is given a random ID by its publisher, so subscribers drop the copies
+//! reaching them on other paths.
+
+use crate::communicate;
+use crate::context::{ctx, ctx_mut};
+use crate::dedup::SeenMsgIds;
+use crate::event::Event;
+use crate::wire_msg::WireMsg;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use tokio::prelude::future;
+use tokio::runtime::current_thread;
+
+/// Number of times a message published is forwarded at most.
+pub const MAX_HOPS: u8 = 6;
+
+/// Topics we are subscribed to and the IDs of the latest messages published to them we saw.
+#[derive(Default)]
+pub struct Topics {
+    subscribed: HashSet<String>,
+    seen: SeenMsgIds,
+}
+
+/// Subscribe to the topic or unsubscribe from it, telling all the peers we are connected to.
+pub fn subscribe(topic: String, subscribed: bool) {
+    let peers = ctx_mut(|c| {
+        let changed = if subscribed {
+            c.topics.subscribed.insert(topic.clone())
+        } else {
+            c.topics.subscribed.remove(&topic)
+        };
+        if changed {
+            c.connections.keys().cloned().collect()
+        } else {
+            Vec::new()
+        }
+    });
+    for peer_addr in peers {
+        let msg = WireMsg::Subscription {
+            topic: topic.clone(),
+            subscribed,
+        };
+        communicate::write_to_peer(peer_addr, msg);
+    }
+}
+
+/// Tell the peer we just connected to which topics we are subscribed to, once the context is
+/// released.
+pub fn announce_later(peer_addr: SocketAddr) {
+    current_thread::spawn(future::lazy(move || {
+        announce(peer_addr);
+        Ok(())
+    }));
+}
+
+fn announce(peer_addr: SocketAddr) {
+    let topics: Vec<String> = ctx(|c| c.topics.subscribed.iter().cloned().collect());
+    for topic in topics {
+        let msg = WireMsg::Subscription {
+            topic,
+            subscribed: true,
+        };
+        communicate::write_to_peer(peer_addr, msg);
+    }
+}
+
+/// Remember whether the peer is subscribed to the topic.
+pub fn handle_subscription(peer_addr: SocketAddr, topic: String, subscribed: bool) {
+    ctx_mut(|c| {
+        let conn = match c.connections.get_mut(&peer_addr) {
+            Some(conn) => conn,
+            None => return trace!("Subscription from an unknown peer: {}", peer_addr),
+        };
+        if subscribed {
+            let _ = conn.topics.insert(topic);
+        } else {
+            let _ = conn.topics.remove(&topic);
+        }
+    })
+}
+
+/// Send the message to the peers subscribed to the topic.
+pub fn publish(topic: String, msg: bytes::Bytes) {
+    let id = ctx_mut(|c| {
+        let id = c.rng.next_u64();
+        let _ = c.topics.seen.insert(id);
+        id
+    });
+    send_to_subscribers(None, topic, id, MAX_HOPS, msg);
+}
+
+/// Deliver the message if we are subscribed to its topic and haven't seen it before, then forward
+/// it on to the other subscribers we are connected to while it has hops left.
+pub fn handle_topic_msg(
+    peer_addr: SocketAddr,
+    topic: String,
+    id: u64,
+    hops_left: u8,
+    msg: bytes::Bytes,
+) {
+    let is_new = ctx_mut(|c| {
+        if !c.topics.seen.insert(id) {
+            return false;
+        }
+        if c.topics.subscribed.contains(&topic) {
+            let event = Event::TopicMessage {
+                topic: topic.clone(),
+                peer_addr,
+                msg: msg.clone(),
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+        true
+    });
+    if !is_new {
+        return trace!(
+            "Dropping duplicate topic message {} from peer {}",
+            id,
+            peer_addr
+        );
+    }
+    if hops_left > 0 {
+        send_to_subscribers(Some(peer_addr), topic, id, hops_left - 1, msg);
+    }
+}
+
+fn send_to_subscribers(
+    from: Option<SocketAddr>,
+    topic: String,
+    id: u64,
+    hops_left: u8,
+    msg: bytes::Bytes,
+) {
+    let subscribers: Vec<SocketAddr> = ctx(|c| {
+        c.connections
+            .iter()
+            .filter(|&(&addr, conn)| Some(addr) != from && conn.topics.contains(&topic))
+            .map(|(&addr, _)| addr)
+            .collect()
+    });
+    for peer_addr in subscribers {
+        let msg = WireMsg::TopicMsg {
+            topic: topic.clone(),
+            id,
+            hops_left,
+            msg: msg.clone(),
+        };
+        communicate::write_to_peer(peer_addr, msg);
+    }
+}
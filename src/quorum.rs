@@ -0,0 +1,179 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Tracking of the messages sent with `QuicP2p::send_to_quorum` until enough of their deliveries
+//! are confirmed, or too many failed for that to happen.
+//!
+//! A delivery is confirmed once the message has been written to the peer in full, or queued for
+//! writing to peers connected over TCP or WebSocket. It fails if we report the message as unsent
+//! or the connection to the peer fails or closes first.
+
+use crate::event::Event;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+
+/// Identifies a message sent with `QuicP2p::send_to_quorum` in the `Event::QuorumSendResolved`
+/// fired for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuorumSendId(pub(crate) u64);
+
+impl QuorumSendId {
+    /// The id as a number, unique among the quorum sends of a `QuicP2p` instance.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Quorum sends awaiting their outcome.
+#[derive(Default)]
+pub struct Quorums {
+    sends: Vec<QuorumSend>,
+}
+
+struct QuorumSend {
+    id: QuorumSendId,
+    msg: bytes::Bytes,
+    required: usize,
+    pending: HashSet<SocketAddr>,
+    delivered: Vec<SocketAddr>,
+    failed: Vec<SocketAddr>,
+}
+
+impl Quorums {
+    /// Track the delivery of `msg` to each of `peers`, `required` of which need to succeed.
+    pub fn start(
+        &mut self,
+        id: QuorumSendId,
+        peers: HashSet<SocketAddr>,
+        msg: bytes::Bytes,
+        required: usize,
+    ) {
+        self.sends.push(QuorumSend {
+            id,
+            msg,
+            required,
+            pending: peers,
+            delivered: Vec::new(),
+            failed: Vec::new(),
+        });
+    }
+
+    /// Take note of the outcome of delivering `msg` to the peer, returning the event to fire if
+    /// that decided a quorum send.
+    pub fn record(
+        &mut self,
+        peer_addr: SocketAddr,
+        msg: &bytes::Bytes,
+        delivered: bool,
+    ) -> Option<Event> {
+        let i = self
+            .sends
+            .iter()
+            .position(|send| send.pending.contains(&peer_addr) && send.msg == *msg)?;
+        self.settle(i, peer_addr, delivered)
+    }
+
+    /// Fail the deliveries still pending to the peer, e.g. as our connection to it is gone.
+    pub fn fail_peer(&mut self, peer_addr: SocketAddr) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut i = 0;
+        while i < self.sends.len() {
+            if !self.sends[i].pending.contains(&peer_addr) {
+                i += 1;
+                continue;
+            }
+            match self.settle(i, peer_addr, false) {
+                Some(event) => events.push(event),
+                None => i += 1,
+            }
+        }
+        events
+    }
+
+    /// Settle the delivery to the peer for the `i`th send, removing the send if it is decided.
+    fn settle(&mut self, i: usize, peer_addr: SocketAddr, delivered: bool) -> Option<Event> {
+        let send = &mut self.sends[i];
+        let _ = send.pending.remove(&peer_addr);
+        if delivered {
+            send.delivered.push(peer_addr);
+        } else {
+            send.failed.push(peer_addr);
+        }
+
+        let reached = send.delivered.len() >= send.required;
+        if !reached && send.delivered.len() + send.pending.len() >= send.required {
+            return None;
+        }
+
+        let send = self.sends.remove(i);
+        Some(Event::QuorumSendResolved {
+            id: send.id,
+            reached,
+            delivered: send.delivered,
+            failed: send.failed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        ([10, 0, 0, 1], port).into()
+    }
+
+    #[test]
+    fn resolves_once_the_outcome_is_decided() {
+        let mut quorums = Quorums::default();
+        let msg = bytes::Bytes::from(&b"replicate me"[..]);
+        let other_msg = bytes::Bytes::from(&b"something else"[..]);
+        let peers = (1..=3).map(peer).collect();
+        quorums.start(QuorumSendId(7), peers, msg.clone(), 2);
+
+        assert!(quorums.record(peer(1), &other_msg, true).is_none());
+        assert!(quorums.record(peer(1), &msg, false).is_none());
+        assert!(quorums.record(peer(2), &msg, true).is_none());
+        match quorums.record(peer(3), &msg, true) {
+            Some(Event::QuorumSendResolved {
+                id,
+                reached,
+                delivered,
+                failed,
+            }) => {
+                assert_eq!(id, QuorumSendId(7));
+                assert!(reached);
+                assert_eq!(delivered, vec![peer(2), peer(3)]);
+                assert_eq!(failed, vec![peer(1)]);
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert!(quorums.sends.is_empty());
+    }
+
+    #[test]
+    fn fails_once_too_few_peers_are_left() {
+        let mut quorums = Quorums::default();
+        let msg = bytes::Bytes::from(&b"replicate me"[..]);
+        let peers = (1..=3).map(peer).collect();
+        quorums.start(QuorumSendId(1), peers, msg, 2);
+
+        assert!(quorums.fail_peer(peer(1)).is_empty());
+        match &quorums.fail_peer(peer(2))[..] {
+            [Event::QuorumSendResolved {
+                reached, failed, ..
+            }] => {
+                assert!(!reached);
+                assert_eq!(*failed, vec![peer(1), peer(2)]);
+            }
+            e => panic!("Unexpected events {:?}", e),
+        }
+        assert!(quorums.fail_peer(peer(3)).is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Dialing peers back at the address they advertise on their request, see
+//! `QuicP2p::check_reachability`.
+//!
+//! We dial from a throwaway endpoint rather than our own: the peer has talked to our endpoint
+//! already, so a NAT in front of it would let us in from there even if nobody else gets through.
+
+use crate::communicate;
+use crate::error::Error;
+use crate::peer_config;
+use crate::wire_msg::WireMsg;
+use crate::{NodeInfo, R};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::prelude::{future, Future, FutureExt};
+use tokio::runtime::current_thread;
+
+/// Time the peer has to accept our connection.
+pub const DIAL_BACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Dial `node_info` on behalf of `requester` and tell it whether we got through.
+pub fn dial_back(requester: SocketAddr, node_info: NodeInfo) {
+    // Otherwise anyone could have us dial whoever they like
+    if node_info.peer_addr.ip() != requester.ip() {
+        return info!(
+            "Ignoring request from {} to dial a different host: {}",
+            requester, node_info.peer_addr
+        );
+    }
+
+    let addr = node_info.peer_addr;
+    let attempt: Box<dyn Future<Item = (), Error = Error>> = match probe(&node_info) {
+        Ok(attempt) => Box::new(attempt),
+        Err(e) => Box::new(future::err(e)),
+    };
+    let leaf = attempt.timeout(DIAL_BACK_TIMEOUT).then(move |r| {
+        if let Err(ref e) = r {
+            debug!(
+                "Could not dial {} back as requested by {}: {:?}",
+                addr, requester, e
+            );
+        }
+        let reachable = r.is_ok();
+        communicate::write_to_peer(requester, WireMsg::ReachabilityResp { addr, reachable });
+        Ok(())
+    });
+
+    current_thread::spawn(leaf);
+}
+
+/// Connect to the node from a fresh endpoint, closing the connection again as soon as it is made.
+pub fn probe(node_info: &NodeInfo) -> R<impl Future<Item = (), Error = Error>> {
+    let peer_cfg = peer_config::new_client_cfg(&node_info.peer_cert_der)?;
+    let unspecified = if node_info.peer_addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+
+    let (driver, ep, _incoming) = quinn::Endpoint::builder().bind((unspecified, 0))?;
+    current_thread::spawn(driver.map_err(|e| warn!("Error in dial back quinn Driver: {:?}", e)));
+
+    let connecting = ep.connect_with(peer_cfg, &node_info.peer_addr, "MaidSAFE.net")?;
+    Ok(connecting
+        .map_err(Error::from)
+        .map(move |(conn_driver, q_conn, _incoming_streams)| {
+            current_thread::spawn(conn_driver.map_err(|_| ()));
+            q_conn.close(0, b"");
+            // The endpoint goes once the connection is closed
+            drop(ep);
+        }))
+}
@@ -0,0 +1,228 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Dialing sticky peers again once their connection drops, see `QuicP2p::make_sticky`.
+//!
+//! A connection to a sticky peer closing or failing is reported via `Event::Reconnecting` and the
+//! peer dialed once the backoff of the attempt passed. Should the dial fail, the next attempt is
+//! made after twice the backoff, up to the `ReconnectPolicy::max_backoff`. Each backoff is
+//! jittered so that peers losing the same node don't all dial it again at once. Connecting to the
+//! peer again, whoever dialed, is reported via `Event::Reconnected`.
+
+use crate::connect;
+use crate::context::{ctx_mut, Context};
+use crate::event::Event;
+use crate::rng::Rng;
+use crate::utils::{later, spawn};
+use crate::NodeInfo;
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::Future;
+use tokio::timer::Delay;
+
+/// How soon to dial a sticky peer again once its connection dropped, see
+/// `Builder::with_reconnect_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Time to wait before the first attempt, doubled for each further one.
+    pub initial_backoff: Duration,
+    /// Longest time to wait before an attempt.
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Time to wait before the given attempt, counting from 0, leaving the jitter aside.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// The sticky peers and how far along reconnecting to each of them is.
+#[derive(Default)]
+pub struct Reconnects {
+    policy: ReconnectPolicy,
+    sticky: HashMap<SocketAddr, Sticky>,
+}
+
+struct Sticky {
+    node_info: NodeInfo,
+    /// Attempts made since the connection dropped.
+    attempts: u32,
+    /// Set while waiting for the backoff to pass, when further drops don't count.
+    is_waiting: bool,
+}
+
+impl Reconnects {
+    /// Take the given policy for the attempts from now on.
+    pub fn set_policy(&mut self, policy: ReconnectPolicy) {
+        self.policy = policy;
+    }
+
+    /// Reconnect to the node should its connection drop.
+    pub fn stick(&mut self, node_info: NodeInfo) {
+        let _ = self.sticky.insert(
+            node_info.peer_addr,
+            Sticky {
+                node_info,
+                attempts: 0,
+                is_waiting: false,
+            },
+        );
+    }
+
+    /// Stop reconnecting to the peer, returning whether it was sticky.
+    pub fn unstick(&mut self, peer_addr: &SocketAddr) -> bool {
+        self.sticky.remove(peer_addr).is_some()
+    }
+}
+
+/// Dial the peer again should it be sticky, once the context is released.
+pub fn dropped_later(peer_addr: SocketAddr) {
+    later(move || ctx_mut(|c| schedule(c, peer_addr)));
+}
+
+/// Take note of connecting to the peer, reporting it as reconnected if it is sticky and we were
+/// reconnecting to it, once the context is released.
+pub fn connected_later(peer_addr: SocketAddr) {
+    later(move || {
+        ctx_mut(|c| {
+            let attempts = match c.reconnects.sticky.get_mut(&peer_addr) {
+                Some(sticky) if sticky.attempts > 0 => {
+                    sticky.is_waiting = false;
+                    mem::replace(&mut sticky.attempts, 0)
+                }
+                _ => return,
+            };
+            let _ = c.event_tx.send(Event::Reconnected {
+                peer_addr,
+                attempts,
+            });
+        })
+    });
+}
+
+/// Take note of dialing the peer having failed, trying again if we are reconnecting to it.
+/// Failed dials of sticky peers we never connected to are left to the application.
+pub fn dial_failed(c: &mut Context, peer_addr: SocketAddr) {
+    if c.reconnects
+        .sticky
+        .get(&peer_addr)
+        .map_or(false, |sticky| sticky.attempts > 0)
+    {
+        schedule(c, peer_addr);
+    }
+}
+
+/// Dial the sticky peer once the backoff of the next attempt passed, unless we are waiting for
+/// one already or are connected to it meanwhile.
+fn schedule(c: &mut Context, peer_addr: SocketAddr) {
+    if c.connections
+        .get(&peer_addr)
+        .map_or(false, |conn| conn.is_complete())
+    {
+        return;
+    }
+    let policy = c.reconnects.policy;
+    let sticky = match c.reconnects.sticky.get_mut(&peer_addr) {
+        Some(sticky) if !sticky.is_waiting => sticky,
+        _ => return,
+    };
+    let after = jittered(policy.backoff(sticky.attempts), &mut c.rng);
+    sticky.attempts += 1;
+    sticky.is_waiting = true;
+    let attempt = sticky.attempts;
+    trace!(
+        "Reconnecting to {} in {:?}, attempt {}",
+        peer_addr,
+        after,
+        attempt
+    );
+    let _ = c.event_tx.send(Event::Reconnecting {
+        peer_addr,
+        attempt,
+        after,
+    });
+
+    let leaf = Delay::new(Instant::now() + after)
+        .map_err(|e| debug!("Error in reconnect timer: {:?}", e))
+        .map(move |()| redial(peer_addr));
+    spawn(leaf);
+}
+
+fn redial(peer_addr: SocketAddr) {
+    let node_info = ctx_mut(|c| {
+        let sticky = c.reconnects.sticky.get_mut(&peer_addr)?;
+        sticky.is_waiting = false;
+        Some(sticky.node_info.clone())
+    });
+    // Unless it was unstuck meanwhile
+    if let Some(node_info) = node_info {
+        if let Err(e) = connect::connect_to(node_info, None, None) {
+            debug!("Could not reconnect to {}: {}", peer_addr, e);
+        }
+    }
+}
+
+/// Somewhere from half the backoff to all of it.
+fn jittered(backoff: Duration, rng: &mut Rng) -> Duration {
+    let half = backoff / 2;
+    let spread = half.as_nanos() as u64 + 1;
+    half + Duration::from_nanos(rng.next_u64() % spread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoffs_double_up_to_the_max() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        };
+        let backoffs: Vec<_> = (0..5).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            backoffs,
+            [100, 200, 400, 500, 500]
+                .iter()
+                .map(|&ms| Duration::from_millis(ms))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(policy.backoff(u32::MAX), policy.max_backoff);
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_the_backoff() {
+        let mut rng = Rng::seeded(5);
+        let backoff = Duration::from_millis(400);
+        let afters: Vec<_> = (0..100).map(|_| jittered(backoff, &mut rng)).collect();
+        assert!(afters
+            .iter()
+            .all(|&after| after >= backoff / 2 && after <= backoff));
+        assert!(afters.iter().any(|&after| after != afters[0]));
+        assert_eq!(
+            jittered(Duration::from_secs(0), &mut rng),
+            Duration::from_secs(0)
+        );
+    }
+}
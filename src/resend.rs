@@ -0,0 +1,415 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Resending the user messages which couldn't be sent, see `ResendPolicy`.
+//!
+//! A message is tracked from when the user sends it until its delivery is confirmed, in the sense
+//! of `quorum`. Should it be reported as unsent, or the connection to the peer fail or close
+//! first, it is sent again once its backoff has passed, connecting to the peer anew if it is a
+//! node. Only once the resends of the policy are used up is it reported as unsent. Messages to
+//! peers we disconnect from ourselves, or which move to another address, are forgotten.
+
+use crate::app_ack;
+use crate::communicate::{self, DeliveryMode};
+use crate::context::ctx_mut;
+use crate::wire_msg::WireMsg;
+use crate::Peer;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::prelude::Future;
+use tokio::runtime::current_thread;
+use tokio::timer::Delay;
+
+/// How often, and how soon, to send a user message again which couldn't be sent, before giving
+/// up on it with `Event::UnsentUserMessage`. See `Builder::with_resend_policy` and
+/// `QuicP2p::send_with_resend`.
+///
+/// The peer may get the message more than once, e.g. if the connection closed after the message
+/// arrived but before we learnt of it. Sending it with `QuicP2p::send_with_id` lets the peer
+/// suppress the duplicates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResendPolicy {
+    /// Number of times to send the message again before giving up on it.
+    pub max_resends: u32,
+    /// Time to wait before the first resend, doubled for each further one.
+    pub initial_backoff: Duration,
+    /// Longest time to wait before a resend.
+    pub max_backoff: Duration,
+}
+
+impl Default for ResendPolicy {
+    fn default() -> Self {
+        Self {
+            max_resends: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ResendPolicy {
+    /// Time to wait before the given resend, counting from 0.
+    pub fn backoff(&self, resend: u32) -> Duration {
+        let factor = 1u32.checked_shl(resend).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Send the user message like `communicate::try_write_to_peer_with`, resending it as `policy` or
+/// else the default policy asks, if any.
+pub fn send(
+    peer: Peer,
+    wire_msg: WireMsg,
+    mode: Option<DeliveryMode>,
+    policy: Option<ResendPolicy>,
+) {
+    ctx_mut(|c| {
+        if let Some(policy) = policy.or(c.resend_policy) {
+            c.resends.start(peer.clone(), &wire_msg, mode, policy);
+        }
+    });
+    let wire_msg = app_ack::ask(peer.peer_addr(), wire_msg);
+    communicate::try_write_to_peer_with(peer, wire_msg, mode);
+}
+
+/// Send the message again once its backoff has passed, unless it was delivered meanwhile.
+pub fn schedule(pending: Pending) {
+    let Pending {
+        key,
+        after,
+        peer,
+        wire_msg,
+        mode,
+    } = pending;
+    trace!("Resending a message to {} in {:?}", peer.peer_addr(), after);
+
+    let leaf = Delay::new(Instant::now() + after)
+        .map_err(|e| debug!("Error in resend timer: {:?}", e))
+        .map(move |()| {
+            if ctx_mut(|c| c.resends.resending(key)) {
+                let wire_msg = app_ack::ask_again(peer.peer_addr(), wire_msg);
+                communicate::try_write_to_peer_with(peer, wire_msg, mode);
+            }
+        });
+    current_thread::spawn(leaf);
+}
+
+/// Messages which are to be sent again should they fail, until their delivery is confirmed.
+#[derive(Default)]
+pub struct Resends {
+    next_key: u64,
+    sends: Vec<Resend>,
+}
+
+struct Resend {
+    key: u64,
+    peer: Peer,
+    msg: bytes::Bytes,
+    id: Option<u64>,
+    mode: Option<DeliveryMode>,
+    policy: ResendPolicy,
+    resends: u32,
+    /// Set while waiting for the backoff to pass, when failures of earlier sends don't count.
+    is_waiting: bool,
+}
+
+/// A message to be sent again, see `schedule`.
+pub struct Pending {
+    key: u64,
+    after: Duration,
+    peer: Peer,
+    wire_msg: WireMsg,
+    mode: Option<DeliveryMode>,
+}
+
+/// What to do about the report of a message as unsent.
+pub enum Verdict {
+    /// Pass the report on to the user: the message isn't tracked or it was given up on.
+    Report,
+    /// Drop the report, which is of a send the message is being resent for already.
+    Ignore,
+    /// Send the message again.
+    Resend(Pending),
+}
+
+impl Resends {
+    /// Track the user message sent to the peer. Other wire messages are ignored.
+    pub fn start(
+        &mut self,
+        peer: Peer,
+        wire_msg: &WireMsg,
+        mode: Option<DeliveryMode>,
+        policy: ResendPolicy,
+    ) {
+        let (msg, id) = match *wire_msg {
+            WireMsg::UserMsg(ref msg) => (msg.clone(), None),
+            WireMsg::IdentifiedUserMsg { id, ref msg } => (msg.clone(), Some(id)),
+            _ => return,
+        };
+        self.sends.push(Resend {
+            key: self.next_key,
+            peer,
+            msg,
+            id,
+            mode,
+            policy,
+            resends: 0,
+            is_waiting: false,
+        });
+        self.next_key += 1;
+    }
+
+    /// Stop tracking the message now that it reached the peer, cancelling any resend of it.
+    pub fn delivered(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) {
+        if let Some(i) = self.find(peer_addr, msg) {
+            let _ = self.sends.remove(i);
+        }
+    }
+
+    /// Stop tracking the message given up on, cancelling any resend of it.
+    pub fn cancel(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) {
+        self.delivered(peer_addr, msg)
+    }
+
+    /// Whether the message is waiting for its backoff to pass before it is sent again.
+    pub fn is_waiting(&self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> bool {
+        self.find(peer_addr, msg)
+            .map_or(false, |i| self.sends[i].is_waiting)
+    }
+
+    /// Whether the message to the peer is tracked, to be resent should it fail.
+    pub fn is_tracked(&self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> bool {
+        self.find(peer_addr, msg).is_some()
+    }
+
+    /// Decide what to do about the message being reported as unsent.
+    pub fn failed(&mut self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> Verdict {
+        match self.find(peer_addr, msg) {
+            Some(i) if self.sends[i].is_waiting => Verdict::Ignore,
+            Some(i) => match self.retry(i) {
+                Some(pending) => Verdict::Resend(pending),
+                None => Verdict::Report,
+            },
+            None => Verdict::Report,
+        }
+    }
+
+    /// Fail the messages being sent to the peer now that our connection to it ended, returning
+    /// those to send again and those we give up on, to report as unsent. The messages are
+    /// forgotten instead if we ended the connection ourselves or the peer moved.
+    pub fn fail_peer(
+        &mut self,
+        peer_addr: SocketAddr,
+        forget: bool,
+    ) -> (Vec<Pending>, Vec<bytes::Bytes>) {
+        let mut resends = Vec::new();
+        let mut given_up = Vec::new();
+        let mut i = 0;
+        while i < self.sends.len() {
+            let send = &self.sends[i];
+            if send.peer.peer_addr() != peer_addr || send.is_waiting {
+                i += 1;
+                continue;
+            }
+            if forget {
+                let _ = self.sends.remove(i);
+                continue;
+            }
+            let msg = send.msg.clone();
+            match self.retry(i) {
+                Some(pending) => {
+                    resends.push(pending);
+                    i += 1;
+                }
+                None => given_up.push(msg),
+            }
+        }
+        (resends, given_up)
+    }
+
+    /// The backoff of the message passed: failures of its sends count again. Returns whether it is
+    /// still to be sent.
+    pub fn resending(&mut self, key: u64) -> bool {
+        match self.sends.iter_mut().find(|send| send.key == key) {
+            Some(send) => {
+                send.is_waiting = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The message to the peer, preferring one we aren't waiting to resend.
+    fn find(&self, peer_addr: SocketAddr, msg: &bytes::Bytes) -> Option<usize> {
+        let is_match = |send: &Resend| send.peer.peer_addr() == peer_addr && send.msg == *msg;
+        self.sends
+            .iter()
+            .position(|send| is_match(send) && !send.is_waiting)
+            .or_else(|| self.sends.iter().position(is_match))
+    }
+
+    /// Send the `i`th message again if its policy allows, or else stop tracking it.
+    fn retry(&mut self, i: usize) -> Option<Pending> {
+        let send = &mut self.sends[i];
+        if send.resends >= send.policy.max_resends {
+            let _ = self.sends.remove(i);
+            return None;
+        }
+
+        let after = send.policy.backoff(send.resends);
+        send.resends += 1;
+        send.is_waiting = true;
+        let wire_msg = match send.id {
+            Some(id) => WireMsg::IdentifiedUserMsg {
+                id,
+                msg: send.msg.clone(),
+            },
+            None => WireMsg::UserMsg(send.msg.clone()),
+        };
+        Some(Pending {
+            key: send.key,
+            after,
+            peer: send.peer.clone(),
+            wire_msg,
+            mode: send.mode,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> Peer {
+        Peer::Client {
+            peer_addr: ([10, 0, 0, 1], port).into(),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = ResendPolicy {
+            max_resends: 40,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(39), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn failures_are_reported_once_the_resends_are_used_up() {
+        let mut resends = Resends::default();
+        let msg = bytes::Bytes::from(&b"try again"[..]);
+        let peer_addr = peer(1).peer_addr();
+        let policy = ResendPolicy {
+            max_resends: 2,
+            ..Default::default()
+        };
+        resends.start(peer(1), &WireMsg::UserMsg(msg.clone()), None, policy);
+
+        let key = match resends.failed(peer_addr, &msg) {
+            Verdict::Resend(pending) => {
+                assert_eq!(pending.after, policy.initial_backoff);
+                pending.key
+            }
+            _ => panic!("Expected a resend"),
+        };
+        // A late report of the same send while we wait
+        match resends.failed(peer_addr, &msg) {
+            Verdict::Ignore => (),
+            _ => panic!("Expected the report to be ignored"),
+        }
+        assert!(resends.resending(key));
+
+        match resends.failed(peer_addr, &msg) {
+            Verdict::Resend(pending) => assert_eq!(pending.after, policy.backoff(1)),
+            _ => panic!("Expected a resend"),
+        }
+        assert!(resends.resending(key));
+        match resends.failed(peer_addr, &msg) {
+            Verdict::Report => (),
+            _ => panic!("Expected the failure to be reported"),
+        }
+        assert!(resends.sends.is_empty());
+        assert!(!resends.resending(key));
+    }
+
+    #[test]
+    fn delivered_msgs_are_not_resent() {
+        let mut resends = Resends::default();
+        let msg = bytes::Bytes::from(&b"arrives"[..]);
+        resends.start(
+            peer(1),
+            &WireMsg::UserMsg(msg.clone()),
+            None,
+            Default::default(),
+        );
+        resends.start(
+            peer(2),
+            &WireMsg::UserMsg(msg.clone()),
+            None,
+            Default::default(),
+        );
+
+        resends.delivered(peer(1).peer_addr(), &msg);
+        match resends.failed(peer(1).peer_addr(), &msg) {
+            Verdict::Report => (),
+            _ => panic!("Expected an untracked message"),
+        }
+
+        let key = match resends.failed(peer(2).peer_addr(), &msg) {
+            Verdict::Resend(pending) => pending.key,
+            _ => panic!("Expected a resend"),
+        };
+        resends.delivered(peer(2).peer_addr(), &msg);
+        assert!(!resends.resending(key));
+    }
+
+    #[test]
+    fn msgs_to_peers_we_disconnect_from_are_forgotten() {
+        let mut resends = Resends::default();
+        let msg = bytes::Bytes::from(&b"in flight"[..]);
+        let peer_addr = peer(1).peer_addr();
+        resends.start(
+            peer(1),
+            &WireMsg::UserMsg(msg.clone()),
+            None,
+            Default::default(),
+        );
+        resends.start(
+            peer(1),
+            &WireMsg::IdentifiedUserMsg {
+                id: 3,
+                msg: msg.clone(),
+            },
+            None,
+            ResendPolicy {
+                max_resends: 0,
+                ..Default::default()
+            },
+        );
+
+        let (pending, given_up) = resends.fail_peer(peer_addr, false);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(given_up, vec![msg.clone()]);
+
+        assert!(resends.resending(pending[0].key));
+        let (pending, given_up) = resends.fail_peer(peer_addr, true);
+        assert!(pending.is_empty());
+        assert!(given_up.is_empty());
+        assert!(resends.sends.is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! The state a restarted instance resumes with, see `Builder::restore`.
+//!
+//! Identity and bootstrap cache are kept together in a single `state` file of the keys
+//! directory, as it holds our private key. It is replaced as a whole whenever the cache is
+//! synced, so the two never come from different runs. A missing bundle is put together from the
+//! separate identity and bootstrap cache files of earlier runs.
+
+use crate::config::{self, SerialisableCertificate};
+use crate::dirs::Dirs;
+use crate::error::Error;
+use crate::persistence;
+use crate::utils::{self, R};
+use crate::NodeInfo;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+/// Version of the contents of the bundle, raised whenever state is added to it.
+const BUNDLE_VERSION: u16 = 1;
+
+/// Everything restored on startup.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct StateBundle {
+    version: u16,
+    /// Our certificate and private key.
+    pub identity: SerialisableCertificate,
+    /// Peers of the bootstrap cache, most recently added last.
+    pub bootstrap_cache: VecDeque<NodeInfo>,
+}
+
+impl StateBundle {
+    pub fn new(identity: SerialisableCertificate, bootstrap_cache: VecDeque<NodeInfo>) -> Self {
+        Self {
+            version: BUNDLE_VERSION,
+            identity,
+            bootstrap_cache,
+        }
+    }
+}
+
+/// Path of the bundle within the given directories or the project ones.
+pub fn bundle_path(user_override: Option<&Dirs>) -> R<PathBuf> {
+    let path = |dir: &Dirs| dir.keys_dir().join("state");
+    user_override.map_or_else(|| Ok(path(&utils::project_dir()?)), |d| Ok(path(d)))
+}
+
+/// Read the bundle, putting it together from the files of earlier runs if there is none yet.
+pub fn load(user_override: Option<&Dirs>) -> R<StateBundle> {
+    let path = bundle_path(user_override)?;
+    if path.exists() {
+        let bundle: StateBundle = persistence::read_with_backup(&path)?;
+        if bundle.version > BUNDLE_VERSION {
+            return Err(Error::CorruptFile {
+                path,
+                reason: "saved by a newer version",
+            });
+        }
+        return Ok(bundle);
+    }
+
+    let identity = config::read_or_create_identity(user_override)?;
+    let bootstrap_cache = legacy_bootstrap_cache(user_override);
+    let bundle = StateBundle::new(identity, bootstrap_cache);
+    persistence::write_to_disk(&path, &bundle)?;
+    info!("Put the state to restore together at {}", path.display());
+    Ok(bundle)
+}
+
+/// Peers of the separate bootstrap cache file, should it be readable.
+fn legacy_bootstrap_cache(user_override: Option<&Dirs>) -> VecDeque<NodeInfo> {
+    let path = |dir: &Dirs| dir.cache_dir().join("bootstrap_cache");
+    let path = match user_override
+        .map_or_else(|| utils::project_dir().map(|d| path(&d)), |d| Ok(path(d)))
+    {
+        Ok(path) => path,
+        Err(_) => return Default::default(),
+    };
+    if !path.exists() {
+        return Default::default();
+    }
+    persistence::read_from_disk(&path).unwrap_or_else(|e| {
+        warn!("Not restoring the unreadable bootstrap cache: {}", e);
+        Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{rand_node_info, test_dirs};
+    use std::fs;
+
+    #[test]
+    fn earlier_files_are_bundled_once() {
+        let dirs = test_dirs();
+        let identity = unwrap!(config::read_or_create_identity(Some(&dirs)));
+        let peers: VecDeque<NodeInfo> = (0..3).map(|_| rand_node_info()).collect();
+        unwrap!(fs::create_dir_all(dirs.cache_dir()));
+        unwrap!(persistence::write_to_disk(
+            &dirs.cache_dir().join("bootstrap_cache"),
+            &peers
+        ));
+
+        let bundle = unwrap!(load(Some(&dirs)));
+        assert!(bundle.identity == identity);
+        assert_eq!(bundle.bootstrap_cache, peers);
+
+        // Read back as it was bundled, whatever becomes of the separate files
+        unwrap!(fs::remove_file(dirs.cache_dir().join("bootstrap_cache")));
+        assert!(unwrap!(load(Some(&dirs))) == bundle);
+    }
+
+    #[test]
+    fn bundles_of_newer_versions_are_rejected() {
+        let dirs = test_dirs();
+        let path = unwrap!(bundle_path(Some(&dirs)));
+        unwrap!(fs::create_dir_all(dirs.keys_dir()));
+        let bundle = StateBundle {
+            version: BUNDLE_VERSION + 1,
+            ..StateBundle::new(Default::default(), Default::default())
+        };
+        unwrap!(persistence::write_to_disk(&path, &bundle));
+
+        match load(Some(&dirs)) {
+            Err(Error::CorruptFile { reason, .. }) => {
+                assert_eq!(reason, "saved by a newer version")
+            }
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! The random decisions of an instance, see `Builder::with_rng_seed`.
+//!
+//! Unseeded, the generator is seeded from the entropy of the OS; seeded, the same seed repeats the
+//! same decisions with the same version of `rand`.
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{FromEntropy, RngCore, SeedableRng};
+use std::net::SocketAddr;
+
+/// Generator of the random numbers, IDs and picks of peers of an instance.
+pub struct Rng {
+    inner: StdRng,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self {
+            inner: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl Rng {
+    /// Generator taking the same decisions for the same seed.
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            inner: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.inner.next_u64()
+    }
+
+    /// Whether something happening with the given probability, from 0 to 1, happens this time.
+    #[cfg(feature = "chaos")]
+    pub fn happens(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            false
+        } else if probability >= 1.0 {
+            true
+        } else {
+            (self.next_u64() as f64) < probability * u64::MAX as f64
+        }
+    }
+
+    /// The peers in random order, whatever order they were given in.
+    pub fn shuffle<T>(&mut self, peers: &mut [T], peer_addr: impl Fn(&T) -> SocketAddr) {
+        peers.sort_by_key(|peer| peer_addr(peer));
+        peers.shuffle(&mut self.inner);
+    }
+
+    /// Up to `count` of the peers picked at random.
+    pub fn pick(&mut self, mut peers: Vec<SocketAddr>, count: usize) -> Vec<SocketAddr> {
+        self.shuffle(&mut peers, |&peer_addr| peer_addr);
+        peers.truncate(count);
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn the_same_seed_takes_the_same_decisions() {
+        let peers: Vec<SocketAddr> = (1..=20)
+            .map(|port| ([203, 0, 113, 1], port).into())
+            .collect();
+        let mut reversed = peers.clone();
+        reversed.reverse();
+
+        let (mut rng0, mut rng1) = (Rng::seeded(7), Rng::seeded(7));
+        assert_eq!(rng0.next_u64(), rng1.next_u64());
+        // Regardless of the order the peers are given in
+        assert_eq!(rng0.pick(peers.clone(), 5), rng1.pick(reversed, 5));
+
+        let mut other = Rng::seeded(8);
+        let _ = other.next_u64();
+        assert_ne!(rng0.pick(peers.clone(), 20), other.pick(peers, 20));
+    }
+
+    #[test]
+    fn numbers_are_distinct() {
+        let mut rng = Rng::default();
+        let numbers: HashSet<u64> = (0..1000).map(|_| rng.next_u64()).collect();
+        assert_eq!(numbers.len(), 1000);
+    }
+}
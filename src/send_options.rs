@@ -0,0 +1,135 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Options of a user message combined into one `SendOptions`, see `QuicP2p::send_with`.
+
+use crate::communicate::DeliveryMode;
+use crate::error::Error;
+use crate::event::{Channel, Token, DEFAULT_CHANNEL};
+use crate::resend::ResendPolicy;
+use crate::utils::R;
+use crate::wire_msg::WireMsg;
+use std::time::Duration;
+
+/// How to send a user message with `QuicP2p::send_with`. The default sends it like
+/// `QuicP2p::send`, and each option can be combined with the others, except that messages with an
+/// ID can't be sent on a channel other than the default one.
+#[derive(Debug, Clone, Default)]
+pub struct SendOptions {
+    token: Token,
+    delivery: Option<DeliveryMode>,
+    resend_policy: Option<ResendPolicy>,
+    ttl: Option<Duration>,
+    id: Option<u64>,
+    channel: Channel,
+}
+
+impl SendOptions {
+    /// Token the `Event::SentUserMessage` or `Event::UnsentUserMessage` of the message carries,
+    /// see `QuicP2p::send_with_token`. Defaults to 0.
+    pub fn with_token(mut self, token: Token) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Mode to send the message in rather than the one the connection defaults to, see
+    /// `QuicP2p::send_with_delivery`.
+    pub fn with_delivery(mut self, mode: DeliveryMode) -> Self {
+        self.delivery = Some(mode);
+        self
+    }
+
+    /// Policy to resend the message with rather than the one of `Builder::with_resend_policy`,
+    /// see `QuicP2p::send_with_resend`.
+    pub fn with_resend_policy(mut self, policy: ResendPolicy) -> Self {
+        self.resend_policy = Some(policy);
+        self
+    }
+
+    /// Time after which to give up on the message, see `QuicP2p::send_with_ttl`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// ID for the peer to deliver the message only once by, see `QuicP2p::send_with_id`.
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Channel of the connection to send the message on, see `QuicP2p::send_on_channel`.
+    pub fn with_channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub(crate) fn token(&self) -> Token {
+        self.token
+    }
+
+    pub(crate) fn delivery(&self) -> Option<DeliveryMode> {
+        self.delivery
+    }
+
+    pub(crate) fn resend_policy(&self) -> Option<ResendPolicy> {
+        self.resend_policy
+    }
+
+    pub(crate) fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// The wire message carrying the user message as asked.
+    pub(crate) fn wire_msg(&self, msg: bytes::Bytes) -> R<WireMsg> {
+        Ok(match (self.id, self.channel) {
+            (None, DEFAULT_CHANNEL) => WireMsg::UserMsg(msg),
+            (Some(id), DEFAULT_CHANNEL) => WireMsg::IdentifiedUserMsg { id, msg },
+            (None, channel) => WireMsg::ChannelMsg { channel, msg },
+            (Some(_), _) => {
+                return Err(Error::ConflictingSettings {
+                    setting: "with_id",
+                    conflicts_with: "with_channel",
+                    reason: "Messages with an ID are only sent on the default channel".to_string(),
+                })
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_pick_the_wire_msg() {
+        let msg = bytes::Bytes::from(vec![1, 2, 3]);
+
+        match unwrap!(SendOptions::default().wire_msg(msg.clone())) {
+            WireMsg::UserMsg(m) => assert_eq!(m, msg),
+            wire_msg => panic!("Unexpected {:?}", wire_msg),
+        }
+        match unwrap!(SendOptions::default().with_id(7).wire_msg(msg.clone())) {
+            WireMsg::IdentifiedUserMsg { id, .. } => assert_eq!(id, 7),
+            wire_msg => panic!("Unexpected {:?}", wire_msg),
+        }
+        match unwrap!(SendOptions::default().with_channel(3).wire_msg(msg.clone())) {
+            WireMsg::ChannelMsg { channel, .. } => assert_eq!(channel, 3),
+            wire_msg => panic!("Unexpected {:?}", wire_msg),
+        }
+        match SendOptions::default()
+            .with_id(7)
+            .with_channel(3)
+            .wire_msg(msg)
+        {
+            Err(Error::ConflictingSettings { setting, .. }) => assert_eq!(setting, "with_id"),
+            res => panic!("Unexpected {:?}", res.map(|_| ())),
+        }
+    }
+}
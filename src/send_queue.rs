@@ -0,0 +1,157 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Queuing the messages sent to a peer while we connect to it, see
+//! `Builder::with_send_queue_limit`.
+//!
+//! Only user messages count towards the limit. Our own messages, e.g. those telling the peer our
+//! topics, are always queued.
+
+use crate::communicate::DeliveryMode;
+use crate::event::Event;
+use crate::event_sender::EventSender;
+use crate::wire_msg::WireMsg;
+use std::net::SocketAddr;
+
+/// Messages queued while we connect to a peer, see `ToPeer::Initiated`.
+pub type PendingSends = Vec<(WireMsg, Option<DeliveryMode>)>;
+
+/// Where queuing messages may report to.
+pub struct Queuing<'a> {
+    /// Most user messages and bytes of them queued per peer, if limited.
+    pub limit: Option<(usize, usize)>,
+    /// Whether to fire `Event::SendsQueued` and `Event::QueuedSendsFlushed`.
+    pub lifecycle_events: bool,
+    /// Fires the events.
+    pub event_tx: &'a EventSender,
+}
+
+impl<'a> Queuing<'a> {
+    /// Queue the message to send to the peer once connected, unless the queue is full, in which
+    /// case the user message is returned to be reported via `outcome::queue_full`.
+    pub fn push(
+        &self,
+        peer_addr: SocketAddr,
+        pending_sends: &mut PendingSends,
+        pending_send: (WireMsg, Option<DeliveryMode>),
+    ) -> Option<bytes::Bytes> {
+        let msg_len = match pending_send.0.user_msg() {
+            Some(msg) => msg.len(),
+            None => {
+                pending_sends.push(pending_send);
+                return None;
+            }
+        };
+        let (queued_msgs, queued_bytes) = queued(pending_sends);
+        if let Some((max_msgs, max_bytes)) = self.limit {
+            if queued_msgs >= max_msgs || queued_bytes + msg_len > max_bytes {
+                debug!("Queue of messages to peer {} is full", peer_addr);
+                return pending_send.0.user_msg().cloned();
+            }
+        }
+
+        pending_sends.push(pending_send);
+        if queued_msgs == 0 && self.lifecycle_events {
+            self.fire(Event::SendsQueued { peer_addr });
+        }
+        None
+    }
+
+    /// Take note of the queued messages being sent now that we are connected.
+    pub fn flushed(
+        &self,
+        peer_addr: SocketAddr,
+        pending_sends: &[(WireMsg, Option<DeliveryMode>)],
+    ) {
+        let (count, _) = queued(pending_sends);
+        if count > 0 && self.lifecycle_events {
+            self.fire(Event::QueuedSendsFlushed { peer_addr, count });
+        }
+    }
+
+    fn fire(&self, event: Event) {
+        if let Err(e) = self.event_tx.send(event) {
+            info!("Could not fire event: {:?}", e);
+        }
+    }
+}
+
+/// Number of user messages queued, and their bytes.
+fn queued(pending_sends: &[(WireMsg, Option<DeliveryMode>)]) -> (usize, usize) {
+    pending_sends
+        .iter()
+        .filter_map(|(wire_msg, _)| wire_msg.user_msg())
+        .fold((0, 0), |(msgs, bytes), msg| (msgs + 1, bytes + msg.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_sender::EventPolicy;
+    use crossbeam_channel as mpmc;
+
+    #[test]
+    fn msgs_beyond_the_limit_are_rejected() {
+        let peer_addr = ([203, 0, 113, 1], 1000).into();
+        let (tx, rx) = mpmc::unbounded();
+        let event_tx = EventSender::new(tx, EventPolicy::Block);
+        let queuing = Queuing {
+            limit: Some((2, 5)),
+            lifecycle_events: true,
+            event_tx: &event_tx,
+        };
+        let mut pending_sends = PendingSends::new();
+
+        let rejected = queuing.push(
+            peer_addr,
+            &mut pending_sends,
+            (WireMsg::UserMsg(From::from("abc")), None),
+        );
+        assert_eq!(rejected, None);
+        match unwrap!(rx.try_recv()) {
+            Event::SendsQueued { .. } => (),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        // Too many bytes, though our own messages don't count
+        let rejected = queuing.push(
+            peer_addr,
+            &mut pending_sends,
+            (WireMsg::UserMsg(From::from("def")), None),
+        );
+        assert_eq!(rejected, Some(From::from("def")));
+        let rejected = queuing.push(
+            peer_addr,
+            &mut pending_sends,
+            (WireMsg::EndpointEchoReq, None),
+        );
+        assert_eq!(rejected, None);
+        let rejected = queuing.push(
+            peer_addr,
+            &mut pending_sends,
+            (WireMsg::UserMsg(From::from("gh")), None),
+        );
+        assert_eq!(rejected, None);
+        // Too many messages
+        let rejected = queuing.push(
+            peer_addr,
+            &mut pending_sends,
+            (WireMsg::UserMsg(From::from("i")), None),
+        );
+        assert_eq!(rejected, Some(From::from("i")));
+        assert_eq!(pending_sends.len(), 3);
+        assert!(rx.try_recv().is_err());
+
+        queuing.flushed(peer_addr, &pending_sends);
+        match unwrap!(rx.try_recv()) {
+            Event::QueuedSendsFlushed { count, .. } => assert_eq!(count, 2),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Binding the UDP sockets of our endpoints.
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// Sockets to run our endpoints on.
+pub struct Sockets {
+    /// Socket bound to the requested address.
+    pub main: UdpSocket,
+    /// Whether `main` is an IPv6 socket which takes IPv4 traffic as well, via IPv4-mapped IPv6
+    /// addresses.
+    pub dual_stack: bool,
+    /// Separate IPv4 socket bound to the same port as `main`, if `main` was asked to listen on
+    /// `::` but can't take IPv4 traffic.
+    pub v4: Option<UdpSocket>,
+}
+
+/// Bind to the given address. Binding to `::` listens on both IPv4 and IPv6: via a dual-stack
+/// socket where the platform supports it or else via separate IPv4 and IPv6 sockets. Without IPv6
+/// support we listen on `0.0.0.0` instead.
+pub fn bind(ip: IpAddr, port: u16) -> io::Result<Sockets> {
+    if ip != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return Ok(Sockets {
+            main: UdpSocket::bind((ip, port))?,
+            dual_stack: false,
+            v4: None,
+        });
+    }
+
+    let v6 = match Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp())) {
+        Ok(v6) => v6,
+        Err(e) => {
+            info!("IPv6 unavailable ({}), listening on IPv4 only", e);
+            return bind(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+        }
+    };
+    let dual_stack = v6.set_only_v6(false).is_ok();
+    if !dual_stack {
+        // Make sure we don't end up with a dual-stack socket anyway, as it would take the port
+        // of the separate IPv4 socket
+        v6.set_only_v6(true)?;
+    }
+    v6.bind(&SockAddr::from(SocketAddr::new(ip, port)))?;
+    let main = v6.into_udp_socket();
+
+    let v4 = if dual_stack {
+        None
+    } else {
+        let port = main.local_addr()?.port();
+        Some(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?)
+    };
+
+    Ok(Sockets {
+        main,
+        dual_stack,
+        v4,
+    })
+}
+
+impl Sockets {
+    /// Have the sockets only take the traffic of the network interface with the given name, via
+    /// `SO_BINDTODEVICE`. Not being allowed to is logged and otherwise ignored, the sockets being
+    /// bound to an address of the interface already.
+    #[cfg(target_os = "linux")]
+    pub fn bind_to_device(self, name: &str) -> io::Result<Self> {
+        let name = CString::new(name)?;
+        let bind = |udp: UdpSocket| -> UdpSocket {
+            let socket = Socket::from(udp);
+            if let Err(e) = socket.bind_device(Some(&name)) {
+                warn!("Could not bind to device {:?}: {}", name, e);
+            }
+            socket.into_udp_socket()
+        };
+        Ok(Self {
+            main: bind(self.main),
+            dual_stack: self.dual_stack,
+            v4: self.v4.map(bind),
+        })
+    }
+
+    /// Binding to a device is only possible on Linux, elsewhere binding to the addresses of the
+    /// interface has to do.
+    #[cfg(not(target_os = "linux"))]
+    pub fn bind_to_device(self, _name: &str) -> io::Result<Self> {
+        Ok(self)
+    }
+}
+
+/// Addresses of the network interface with the given name, IPv4 ones first. Link-local IPv6
+/// addresses are left out, as they can't be bound to without the scope of the interface.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+pub fn interface_addrs(name: &str) -> io::Result<Vec<IpAddr>> {
+    let mut found = false;
+    let mut addrs = Vec::new();
+    for_each_ifaddr(|ifaddr| {
+        if unsafe { std::ffi::CStr::from_ptr(ifaddr.ifa_name) }.to_bytes() != name.as_bytes() {
+            return;
+        }
+        found = true;
+        if let Some(ip) = unsafe { ip_of(ifaddr.ifa_addr) } {
+            addrs.push(ip);
+        }
+    })?;
+
+    if !found {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no network interface named {}", name),
+        ));
+    }
+    addrs.retain(|ip| match ip {
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) != 0xfe80,
+        IpAddr::V4(_) => true,
+    });
+    addrs.sort_by_key(IpAddr::is_ipv6);
+    Ok(addrs)
+}
+
+/// Whether the IP is on the subnet of one of our network interfaces, i.e. reachable without
+/// going through a router. IPv4-mapped IPv6 addresses have to be normalised first. `false` if
+/// the interfaces can't be looked up.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+pub fn on_local_subnet(ip: IpAddr) -> bool {
+    let mut on_subnet = false;
+    let res = for_each_ifaddr(|ifaddr| {
+        let (addr, mask) = unsafe { (ip_of(ifaddr.ifa_addr), ip_of(ifaddr.ifa_netmask)) };
+        if let (Some(addr), Some(mask)) = (addr, mask) {
+            on_subnet |= same_subnet(ip, addr, mask);
+        }
+    });
+    if let Err(e) = res {
+        debug!("Could not look up our network interfaces: {}", e);
+    }
+    on_subnet
+}
+
+/// Looking up subnets is only implemented for Unix.
+#[cfg(not(unix))]
+pub fn on_local_subnet(_ip: IpAddr) -> bool {
+    false
+}
+
+/// Whether both IPs are of the same family and equal under the netmask.
+#[cfg(unix)]
+fn same_subnet(ip: IpAddr, other: IpAddr, mask: IpAddr) -> bool {
+    match (ip, other, mask) {
+        (IpAddr::V4(ip), IpAddr::V4(other), IpAddr::V4(mask)) => {
+            u32::from(ip) & u32::from(mask) == u32::from(other) & u32::from(mask)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(other), IpAddr::V6(mask)) => {
+            u128::from(ip) & u128::from(mask) == u128::from(other) & u128::from(mask)
+        }
+        _ => false,
+    }
+}
+
+/// Call `f` with each of the entries `getifaddrs` lists for our network interfaces.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+fn for_each_ifaddr<F: FnMut(&libc::ifaddrs)>(mut f: F) -> io::Result<()> {
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cursor = ifaddrs;
+    while !cursor.is_null() {
+        // Safe as `getifaddrs` hands out a well formed list, which we free only after the loop
+        let ifaddr = unsafe { &*cursor };
+        cursor = ifaddr.ifa_next;
+        f(ifaddr);
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(())
+}
+
+/// Looking up interfaces is only implemented for Unix.
+#[cfg(not(unix))]
+pub fn interface_addrs(_name: &str) -> io::Result<Vec<IpAddr>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "looking up network interfaces by name is not supported on this platform",
+    ))
+}
+
+/// IP address of the socket address, unless it is of neither family or missing.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+unsafe fn ip_of(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match i32::from((*addr).sa_family) {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                addr.sin_addr.s_addr,
+            ))))
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unspecified_v6_takes_both_families() {
+        let sockets = unwrap!(bind(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0));
+        let port = unwrap!(sockets.main.local_addr()).port();
+        let v4_socket = sockets.v4.as_ref().unwrap_or(&sockets.main);
+
+        for &(ip, socket) in &[
+            (IpAddr::V4(Ipv4Addr::LOCALHOST), v4_socket),
+            (IpAddr::V6(Ipv6Addr::LOCALHOST), &sockets.main),
+        ] {
+            let sender = unwrap!(UdpSocket::bind((ip, 0)));
+            unwrap!(sender.send_to(b"hi", (ip, port)));
+
+            let mut buf = [0; 2];
+            let (len, _) = unwrap!(socket.recv_from(&mut buf));
+            assert_eq!(&buf[..len], b"hi");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn interfaces_are_looked_up_by_name() {
+        let addrs = unwrap!(interface_addrs("lo"));
+        assert_eq!(addrs.first(), Some(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+
+        match interface_addrs("no-such-interface") {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn subnets_of_our_interfaces_are_local() {
+        assert!(on_local_subnet(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))));
+        // TEST-NET-3 is reserved for documentation, so no interface is on it
+        assert!(!on_local_subnet(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))));
+    }
+}
@@ -0,0 +1,412 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Relaying our outgoing connections through a SOCKS5 proxy (RFC 1928, `UDP ASSOCIATE` without
+//! authentication).
+//!
+//! quinn sends straight to the address it dials, so each peer we connect to gets a local shim
+//! socket which we dial instead. Datagrams our endpoint sends to a shim are wrapped in a SOCKS5
+//! UDP header addressed to the peer and sent to the proxy's relay from the one socket of the
+//! association, and datagrams from the relay are unwrapped and handed to our endpoint by the shim
+//! of the peer they came from. All of it runs on the event loop, the handshake with the proxy
+//! included.
+
+use crate::error::Error;
+use crate::R;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::prelude::future::{self, Either, Shared};
+use tokio::prelude::{stream, Async, Future, FutureExt, Stream};
+use tokio::runtime::current_thread;
+use tokio::sync::oneshot;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// The UDP association in the making, failing with the reason it couldn't be made.
+type Associating = Shared<Box<dyn Future<Item = Rc<Association>, Error = String>>>;
+type Shims = Rc<RefCell<HashMap<SocketAddr, Weak<Shim>>>>;
+
+/// UDP association with a SOCKS5 proxy, made when we first connect to a peer.
+pub struct Socks5Relay {
+    proxy_addr: SocketAddr,
+    /// Address of our endpoint as seen from the shims.
+    ep_addr: SocketAddr,
+    association: RefCell<Option<Associating>>,
+    shims: Shims,
+}
+
+/// Local socket we dial a peer at instead of the peer itself. Relaying between the two stops once
+/// it is dropped.
+pub struct Shim {
+    sock: Rc<RefCell<UdpSocket>>,
+    addr: SocketAddr,
+    _stop: oneshot::Sender<()>,
+}
+
+struct Association {
+    /// The association lasts as long as this connection stays open.
+    _control: TcpStream,
+    relay_sock: Rc<RefCell<UdpSocket>>,
+    relay_addr: SocketAddr,
+    /// Stops handing datagrams from the relay to the shims once dropped.
+    _stop: oneshot::Sender<()>,
+}
+
+impl Socks5Relay {
+    /// Relay through the proxy at `proxy_addr` for our endpoint bound to `ep_addr`.
+    pub fn new(proxy_addr: SocketAddr, ep_addr: SocketAddr) -> Self {
+        let ep_ip = match ep_addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        Self {
+            proxy_addr,
+            ep_addr: SocketAddr::new(ep_ip, ep_addr.port()),
+            association: Default::default(),
+            shims: Default::default(),
+        }
+    }
+
+    /// Resolves once the association with the proxy is made, making it unless it is made or in
+    /// the making already.
+    pub fn associated(&self) -> impl Future<Item = (), Error = Error> {
+        self.association()
+            .map(|_| ())
+            .map_err(|reason| proxy_err((*reason).clone()))
+    }
+
+    /// Shim to dial the peer at so that the connection is relayed by the proxy. Datagrams are
+    /// relayed once the association is made, see `associated`.
+    pub fn shim(&self, peer_addr: SocketAddr) -> R<Rc<Shim>> {
+        if let Some(shim) = self.shims.borrow().get(&peer_addr).and_then(Weak::upgrade) {
+            return Ok(shim);
+        }
+
+        let sock = Rc::new(RefCell::new(UdpSocket::bind(&SocketAddr::new(
+            self.ep_addr.ip(),
+            0,
+        ))?));
+        let addr = sock.borrow().local_addr()?;
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let shim = Rc::new(Shim {
+            sock: sock.clone(),
+            addr,
+            _stop: stop_tx,
+        });
+
+        let ep_addr = self.ep_addr;
+        let header = encode_udp_header(peer_addr);
+        let leaf = self
+            .association()
+            .map_err(|_| ())
+            .and_then(move |association| {
+                let association = Rc::clone(&*association);
+                recv_from(sock).for_each(move |(payload, from)| {
+                    if from == ep_addr {
+                        let mut datagram = Vec::with_capacity(header.len() + payload.len());
+                        datagram.extend_from_slice(&header);
+                        datagram.extend_from_slice(&payload);
+                        send_to(&association.relay_sock, &datagram, association.relay_addr);
+                    }
+                    Ok(())
+                })
+            })
+            .select(stop_rx.then(|_| Ok(())))
+            .then(|_| Ok(()));
+        current_thread::spawn(leaf);
+
+        let mut shims = self.shims.borrow_mut();
+        shims.retain(|_, shim| shim.upgrade().is_some());
+        let _ = shims.insert(peer_addr, Rc::downgrade(&shim));
+
+        Ok(shim)
+    }
+
+    fn association(&self) -> Associating {
+        let mut association = self.association.borrow_mut();
+        let failed = match *association {
+            Some(ref associating) => match associating.peek() {
+                Some(Err(_)) => true,
+                _ => false,
+            },
+            None => true,
+        };
+        // Failed associations are retried with the next connection
+        if failed {
+            let associating: Box<dyn Future<Item = Rc<Association>, Error = String>> = Box::new(
+                Association::new(self.proxy_addr, self.ep_addr, Rc::clone(&self.shims))
+                    .map(Rc::new)
+                    .map_err(|e| match e {
+                        Error::Socks5Proxy(reason) => reason,
+                        e => e.to_string(),
+                    }),
+            );
+            *association = Some(associating.shared());
+        }
+        unwrap!(association.clone())
+    }
+}
+
+impl Shim {
+    /// Address to dial the peer at.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Association {
+    fn new(
+        proxy_addr: SocketAddr,
+        ep_addr: SocketAddr,
+        shims: Shims,
+    ) -> impl Future<Item = Self, Error = Error> {
+        let relay_ip = match proxy_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        let relay_sock = UdpSocket::bind(&SocketAddr::new(relay_ip, 0))
+            .and_then(|relay_sock| {
+                let port = relay_sock.local_addr()?.port();
+                Ok((relay_sock, port))
+            })
+            .map_err(Error::from);
+
+        future::result(relay_sock)
+            .and_then(move |(relay_sock, port)| {
+                associate(proxy_addr, port)
+                    .timeout(HANDSHAKE_TIMEOUT)
+                    .map_err(|e| {
+                        e.into_inner()
+                            .unwrap_or_else(|| proxy_err("timed out waiting for the proxy"))
+                    })
+                    .map(move |(control, relay_addr)| (control, relay_sock, relay_addr))
+            })
+            .map(move |(control, relay_sock, mut relay_addr)| {
+                // Proxies may leave it to us to find the relay
+                if relay_addr.ip().is_unspecified() {
+                    relay_addr.set_ip(proxy_addr.ip());
+                }
+                let relay_sock = Rc::new(RefCell::new(relay_sock));
+                let (stop_tx, stop_rx) = oneshot::channel();
+                spawn_relay_reader(Rc::clone(&relay_sock), relay_addr, ep_addr, shims, stop_rx);
+
+                Self {
+                    _control: control,
+                    relay_sock,
+                    relay_addr,
+                    _stop: stop_tx,
+                }
+            })
+    }
+}
+
+/// Hand datagrams from the relay to our endpoint via the shim of the peer they came from.
+fn spawn_relay_reader(
+    relay_sock: Rc<RefCell<UdpSocket>>,
+    relay_addr: SocketAddr,
+    ep_addr: SocketAddr,
+    shims: Shims,
+    stop_rx: oneshot::Receiver<()>,
+) {
+    let leaf = recv_from(relay_sock)
+        .for_each(move |(datagram, from)| {
+            if from != relay_addr {
+                return Ok(());
+            }
+            let (peer_addr, payload) = match decode_udp_header(&datagram) {
+                Some(decoded) => decoded,
+                None => {
+                    debug!("Dropping malformed datagram from SOCKS5 relay");
+                    return Ok(());
+                }
+            };
+            let shim = shims.borrow().get(&peer_addr).and_then(Weak::upgrade);
+            if let Some(shim) = shim {
+                send_to(&shim.sock, payload, ep_addr);
+            }
+            Ok(())
+        })
+        .select(stop_rx.then(|_| Ok(())))
+        .then(|_| Ok(()));
+
+    current_thread::spawn(leaf);
+}
+
+/// Datagrams received on the socket along with their senders.
+fn recv_from(
+    sock: Rc<RefCell<UdpSocket>>,
+) -> impl Stream<Item = (Vec<u8>, SocketAddr), Error = ()> {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    stream::poll_fn(move || loop {
+        match sock.borrow_mut().poll_recv_from(&mut buf) {
+            Ok(Async::Ready((len, from))) => {
+                return Ok(Async::Ready(Some((buf[..len].to_vec(), from))))
+            }
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            // Reported on some platforms for datagrams we sent earlier
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => (),
+            Err(e) => {
+                debug!("Could not receive datagram: {}", e);
+                return Err(());
+            }
+        }
+    })
+}
+
+/// Send the datagram unless the socket is busy. Lost datagrams are resent by QUIC.
+fn send_to(sock: &RefCell<UdpSocket>, datagram: &[u8], to: SocketAddr) {
+    match sock.borrow_mut().poll_send_to(datagram, &to) {
+        Ok(Async::Ready(_)) => (),
+        Ok(Async::NotReady) => trace!("Dropping datagram to {} as the socket is busy", to),
+        Err(e) => debug!("Could not relay datagram to {}: {}", to, e),
+    }
+}
+
+/// Negotiate no authentication and ask for a UDP association for datagrams from `port`. Resolves
+/// to the control connection, which the association lasts as long as, and the address of the
+/// proxy's relay.
+fn associate(
+    proxy_addr: SocketAddr,
+    port: u16,
+) -> impl Future<Item = (TcpStream, SocketAddr), Error = Error> {
+    TcpStream::connect(&proxy_addr)
+        .map_err(move |e| proxy_err(format!("could not connect to {}: {}", proxy_addr, e)))
+        .and_then(|control| {
+            tokio::io::write_all(control, [VERSION, 1, NO_AUTH]).map_err(Error::from)
+        })
+        .and_then(|(control, _)| tokio::io::read_exact(control, [0; 2]).map_err(Error::from))
+        .and_then(move |(control, choice)| {
+            if choice != [VERSION, NO_AUTH] {
+                return Either::A(future::err(proxy_err("proxy requires authentication")));
+            }
+            let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0];
+            request.extend_from_slice(&encode_addr(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                port,
+            )));
+            Either::B(tokio::io::write_all(control, request).map_err(Error::from))
+        })
+        .and_then(|(control, _)| tokio::io::read_exact(control, [0; 4]).map_err(Error::from))
+        .and_then(|(control, reply)| {
+            if reply[0] != VERSION {
+                return Either::A(future::err(proxy_err("not a SOCKS5 proxy")));
+            }
+            if reply[1] != REPLY_SUCCEEDED {
+                return Either::A(future::err(proxy_err(format!(
+                    "UDP association refused with reply code {}",
+                    reply[1]
+                ))));
+            }
+            let ip_len = match reply[3] {
+                ATYP_IPV4 => 4,
+                ATYP_IPV6 => 16,
+                _ => return Either::A(future::err(proxy_err("unsupported relay address type"))),
+            };
+            // The IP followed by the port
+            Either::B(tokio::io::read_exact(control, vec![0; ip_len + 2]).map_err(Error::from))
+        })
+        .map(|(control, addr)| {
+            let (ip, port) = addr.split_at(addr.len() - 2);
+            let ip = if ip.len() == 4 {
+                let octets: [u8; 4] = unwrap!(ip.try_into());
+                IpAddr::V4(octets.into())
+            } else {
+                let octets: [u8; 16] = unwrap!(ip.try_into());
+                IpAddr::V6(octets.into())
+            };
+            let port = u16::from_be_bytes(unwrap!(port.try_into()));
+            (control, SocketAddr::new(ip, port))
+        })
+}
+
+fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(19);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            encoded.push(ATYP_IPV4);
+            encoded.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            encoded.push(ATYP_IPV6);
+            encoded.extend_from_slice(&ip.octets());
+        }
+    }
+    encoded.extend_from_slice(&addr.port().to_be_bytes());
+    encoded
+}
+
+/// Header of datagrams to and from the relay: reserved bytes, fragment number and the address.
+pub(crate) fn encode_udp_header(addr: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0, 0, 0];
+    header.extend_from_slice(&encode_addr(addr));
+    header
+}
+
+/// Address and payload of a datagram from the relay. Fragments aren't supported.
+pub(crate) fn decode_udp_header(datagram: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+        return None;
+    }
+    let (ip, rest) = match datagram[3] {
+        ATYP_IPV4 if datagram.len() >= 10 => {
+            let octets: [u8; 4] = unwrap!(datagram[4..8].try_into());
+            (IpAddr::V4(octets.into()), &datagram[8..])
+        }
+        ATYP_IPV6 if datagram.len() >= 22 => {
+            let octets: [u8; 16] = unwrap!(datagram[4..20].try_into());
+            (IpAddr::V6(octets.into()), &datagram[20..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(unwrap!(rest[..2].try_into()));
+
+    Some((SocketAddr::new(ip, port), &rest[2..]))
+}
+
+fn proxy_err<S: Into<String>>(reason: S) -> Error {
+    Error::Socks5Proxy(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_headers_round_trip() {
+        for addr in &["127.0.0.1:5000", "[2001:db8::1]:443"] {
+            let addr: SocketAddr = unwrap!(addr.parse());
+            let mut datagram = encode_udp_header(addr);
+            datagram.extend_from_slice(b"payload");
+            assert_eq!(decode_udp_header(&datagram), Some((addr, &b"payload"[..])));
+        }
+
+        // Fragments and truncated headers are dropped
+        let mut fragment = encode_udp_header(unwrap!("127.0.0.1:5000".parse()));
+        fragment[2] = 1;
+        assert_eq!(decode_udp_header(&fragment), None);
+        assert_eq!(decode_udp_header(&[0, 0, 0, ATYP_IPV4, 127]), None);
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Telling the application about the peers we are held up writing to, see
+//! `Builder::with_stall_detection`.
+//!
+//! Writing to a QUIC stream blocks once the peer's flow control, or the congestion control of
+//! the connection, lets no more data through. A connection is stalled for as long as one of its
+//! streams stays blocked, and its peer is reported slow once that lasts beyond the threshold.
+
+use crate::context::ctx_mut;
+use crate::event::Event;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::prelude::{Poll, Stream};
+use tokio::runtime::current_thread;
+use tokio::timer::Interval;
+
+/// When the streams of a connection which are blocked writing got blocked.
+#[derive(Default)]
+pub struct Stalls {
+    next_id: Cell<u64>,
+    blocked_since: RefCell<HashMap<u64, Instant>>,
+}
+
+impl Stalls {
+    /// Note down when writing to the stream blocks.
+    pub fn watch<W: AsyncWrite>(self: &Rc<Self>, stream: W) -> Watched<W> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        Watched {
+            stream,
+            id,
+            stalls: self.clone(),
+        }
+    }
+
+    /// When the stream blocked the longest got blocked, if any is.
+    pub fn since(&self) -> Option<Instant> {
+        self.blocked_since.borrow().values().min().cloned()
+    }
+
+    fn block(&self, id: u64) {
+        let _ = self
+            .blocked_since
+            .borrow_mut()
+            .entry(id)
+            .or_insert_with(Instant::now);
+    }
+
+    fn unblock(&self, id: u64) {
+        let _ = self.blocked_since.borrow_mut().remove(&id);
+    }
+}
+
+/// A stream written to, whose blocking is noted down in `Stalls`.
+pub struct Watched<W> {
+    stream: W,
+    id: u64,
+    stalls: Rc<Stalls>,
+}
+
+impl<W: Write> Write for Watched<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let r = self.stream.write(buf);
+        match r {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.stalls.block(self.id),
+            _ => self.stalls.unblock(self.id),
+        }
+        r
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for Watched<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+impl<W> Drop for Watched<W> {
+    fn drop(&mut self) {
+        self.stalls.unblock(self.id);
+    }
+}
+
+/// Fire `Event::PeerSlow` for each connection stalled for longer than `threshold`, and
+/// `Event::PeerResumed` once it no longer is.
+pub fn start(threshold: Duration) {
+    let period = cmp::max(threshold / 4, Duration::from_millis(1));
+    let leaf = Interval::new(Instant::now() + period, period)
+        .map_err(|e| info!("Error in stall detection interval: {:?}", e))
+        .for_each(move |_| {
+            check(threshold);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn check(threshold: Duration) {
+    ctx_mut(|c| {
+        for (peer_addr, conn) in c.connections.iter_mut() {
+            let stalled_for = conn.stalled_since().map(|since| since.elapsed());
+            let event = match stalled_for {
+                Some(stalled_for) if stalled_for >= threshold => {
+                    if conn.is_slow {
+                        continue;
+                    }
+                    conn.is_slow = true;
+                    Event::PeerSlow {
+                        peer_addr: *peer_addr,
+                        stalled_for,
+                    }
+                }
+                _ if conn.is_slow => {
+                    conn.is_slow = false;
+                    Event::PeerResumed {
+                        peer_addr: *peer_addr,
+                    }
+                }
+                _ => continue,
+            };
+            if let Err(e) = c.event_tx.send(event) {
+                info!("Could not fire event: {:?}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts a byte per write, blocking every other one.
+    struct Choppy(bool);
+
+    impl Write for Choppy {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            self.0 = !self.0;
+            if self.0 {
+                Err(io::ErrorKind::WouldBlock.into())
+            } else {
+                Ok(1)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Choppy {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(().into())
+        }
+    }
+
+    #[test]
+    fn streams_are_stalled_while_blocked() {
+        let stalls = Rc::new(Stalls::default());
+        let mut first = stalls.watch(Choppy(false));
+        let mut second = stalls.watch(Choppy(false));
+        assert_eq!(stalls.since(), None);
+
+        assert!(first.write(b"a").is_err());
+        let first_since = unwrap!(stalls.since());
+        assert!(second.write(b"a").is_err());
+        assert_eq!(stalls.since(), Some(first_since));
+
+        // Still stalled for as long as the other stream is blocked
+        assert!(first.write(b"a").is_ok());
+        let second_since = unwrap!(stalls.since());
+        assert!(second_since >= first_since);
+
+        drop(second);
+        assert_eq!(stalls.since(), None);
+    }
+}
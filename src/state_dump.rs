@@ -0,0 +1,181 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::config::OurType;
+use crate::connection::{Connection, FromPeer, ToPeer};
+use crate::context::{Context, CTX};
+use crate::stats::ConnectionStats;
+use crate::utils;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::net::SocketAddr;
+use std::panic;
+use std::sync::Once;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static INSTALL_PANIC_HOOK: Once = Once::new();
+
+/// Snapshot of the internal state of a `QuicP2p` instance, for debugging.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDump {
+    /// Whether we are a client or a node.
+    pub our_type: OurType,
+    /// Every peer we have (or are setting up) connections with, ordered by address.
+    pub connections: Vec<ConnectionDump>,
+    /// Peers we initiated a connection to which is not established yet.
+    pub pending_connects: Vec<SocketAddr>,
+    /// Contents of the bootstrap cache.
+    pub bootstrap_cache: BootstrapCacheDump,
+}
+
+/// State of the connections to and from a single peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionDump {
+    /// Peer address.
+    pub peer_addr: SocketAddr,
+    /// Stage of the connection from us to the peer.
+    pub to_peer: String,
+    /// Stage of the connection from the peer to us.
+    pub from_peer: String,
+    /// Messages queued up until the connection to the peer is established.
+    pub pending_sends: usize,
+    /// Messages received but queued up until the connection to the peer is established.
+    pub pending_reads: usize,
+    /// Whether the upper layer ever contacted this peer.
+    pub we_contacted_peer: bool,
+    /// Whether this connection is an attempt of an ongoing bootstrap.
+    pub is_bootstrap_attempt: bool,
+    /// Statistics of the connections to and from the peer.
+    pub stats: ConnectionStats,
+}
+
+/// Summary of the bootstrap cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapCacheDump {
+    /// Addresses of the cached peers, most recently added last.
+    pub cached_peers: Vec<SocketAddr>,
+    /// Number of hard-coded contacts.
+    pub hard_coded_contacts: usize,
+}
+
+/// Capture the current state.
+pub fn dump(c: &Context) -> StateDump {
+    let mut connections: Vec<_> = c
+        .connections
+        .iter()
+        .map(|(peer_addr, conn)| dump_connection(*peer_addr, conn))
+        .collect();
+    connections.sort_by_key(|conn| conn.peer_addr);
+
+    let pending_connects = connections
+        .iter()
+        .filter(|conn| conn.to_peer == "Initiated")
+        .map(|conn| conn.peer_addr)
+        .collect();
+
+    StateDump {
+        our_type: c.our_type,
+        connections,
+        pending_connects,
+        bootstrap_cache: BootstrapCacheDump {
+            cached_peers: c
+                .bootstrap_cache
+                .peers()
+                .iter()
+                .map(|peer| peer.peer_addr)
+                .collect(),
+            hard_coded_contacts: c.bootstrap_cache.hard_coded_contacts().len(),
+        },
+    }
+}
+
+fn dump_connection(peer_addr: SocketAddr, conn: &Connection) -> ConnectionDump {
+    let (to_peer, pending_sends) = match conn.to_peer {
+        ToPeer::NoConnection => ("NoConnection", 0),
+        ToPeer::NotNeeded => ("NotNeeded", 0),
+        ToPeer::Initiated {
+            ref pending_sends, ..
+        } => ("Initiated", pending_sends.len()),
+        ToPeer::Established { .. } => ("Established", 0),
+    };
+    let (from_peer, pending_reads) = match conn.from_peer {
+        FromPeer::NoConnection => ("NoConnection", 0),
+        FromPeer::NotNeeded => ("NotNeeded", 0),
+        FromPeer::Established {
+            ref pending_reads, ..
+        } => ("Established", pending_reads.len()),
+    };
+
+    ConnectionDump {
+        peer_addr,
+        to_peer: to_peer.to_string(),
+        from_peer: from_peer.to_string(),
+        pending_sends,
+        pending_reads,
+        we_contacted_peer: conn.we_contacted_peer,
+        is_bootstrap_attempt: conn.bootstrap_group_ref.is_some(),
+        stats: conn.stats(),
+    }
+}
+
+/// Write a state dump into the project logs directory if the event loop of an instance which
+/// asked for it via `Context::dump_state_on_panic` panics.
+pub fn install_panic_hook() {
+    INSTALL_PANIC_HOOK.call_once(|| {
+        let prev_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            write_dump_of_current_thread();
+            prev_hook(info);
+        }));
+    });
+}
+
+fn write_dump_of_current_thread() {
+    // The panic might have happened while the `Context` was borrowed, or on a thread which does
+    // not run an event loop at all - we can't dump anything in either case.
+    let dump = CTX.try_with(|ctx_refcell| {
+        let ctx = ctx_refcell.try_borrow().ok()?;
+        let ctx = ctx.as_ref()?;
+        if ctx.dump_state_on_panic {
+            Some((dump(ctx), ctx.logs_dir.clone()))
+        } else {
+            None
+        }
+    });
+    let (dump, logs_dir) = match dump {
+        Ok(Some(dump)) => dump,
+        _ => return,
+    };
+
+    let dir = match logs_dir {
+        Some(dir) => dir,
+        None => match utils::project_dir() {
+            Ok(dirs) => dirs.logs_dir().to_path_buf(),
+            Err(e) => {
+                warn!("Could not find the directory for the state dump: {}", e);
+                return;
+            }
+        },
+    };
+    let unix_time_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000 + u64::from(d.subsec_millis()))
+        .unwrap_or(0);
+    let path = dir.join(format!("state-dump-{}.json", unix_time_ms));
+
+    let r = fs::create_dir_all(&dir)
+        .and_then(|()| File::create(&path))
+        .and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &dump).map_err(io::Error::from)
+        });
+    match r {
+        Ok(()) => warn!("Wrote state dump to {}", path.display()),
+        Err(e) => warn!("Could not write state dump to {}: {}", path.display(), e),
+    }
+}
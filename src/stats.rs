@@ -0,0 +1,158 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use crate::bandwidth::Bandwidth;
+use crate::bootstrap::{AttemptOutcome, BootstrapReport, ContactSource};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Counts of the streams and bytes exchanged over the connections to a peer, as seen by us rather
+/// than by QUIC: every message is sent on its own uni-directional stream. These are no transport
+/// statistics, e.g. they say nothing of lost packets or the round trip time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    /// Number of streams we successfully wrote to the peer. Each message pushed to the peer
+    /// counts as a stream of its own.
+    pub streams_sent: u64,
+    /// Bytes written to the peer on successful streams.
+    pub bytes_sent: u64,
+    /// Number of streams to the peer that failed to be opened, written or finished.
+    pub streams_failed: u64,
+    /// Number of streams we completely read from the peer, counting pushed messages likewise.
+    pub streams_received: u64,
+    /// Bytes read from the peer.
+    pub bytes_received: u64,
+}
+
+impl ConnectionStats {
+    /// Accumulate the statistics of another connection to the same peer into ours.
+    pub fn merge(&mut self, other: &ConnectionStats) {
+        self.streams_sent += other.streams_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.streams_failed += other.streams_failed;
+        self.streams_received += other.streams_received;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+/// Distribution of the ages of bootstrap cache entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AgeHistogram {
+    /// Entries younger than an hour.
+    pub under_an_hour: u64,
+    /// Entries between an hour and a day old.
+    pub under_a_day: u64,
+    /// Entries between a day and a week old.
+    pub under_a_week: u64,
+    /// Entries older than a week.
+    pub older: u64,
+    /// Entries of unknown age, e.g. read from the cache file.
+    pub unknown: u64,
+}
+
+impl AgeHistogram {
+    /// Count an entry of the given age.
+    pub fn record(&mut self, age: Option<Duration>) {
+        const HOUR_SECS: u64 = 60 * 60;
+        let bucket = match age.map(|age| age.as_secs()) {
+            None => &mut self.unknown,
+            Some(secs) if secs < HOUR_SECS => &mut self.under_an_hour,
+            Some(secs) if secs < 24 * HOUR_SECS => &mut self.under_a_day,
+            Some(secs) if secs < 7 * 24 * HOUR_SECS => &mut self.under_a_week,
+            Some(_) => &mut self.older,
+        };
+        *bucket += 1;
+    }
+}
+
+/// Outcomes of the bootstrap runs of this `QuicP2p` instance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapStats {
+    /// Runs bootstrapped off a peer from the bootstrap cache.
+    pub cache_hits: u64,
+    /// Runs bootstrapped off a hard-coded contact.
+    pub hard_coded_hits: u64,
+    /// Runs in which no contact could be bootstrapped off.
+    pub failures: u64,
+    /// Ages of the cached peers bootstrapped off at the time of bootstrapping.
+    pub cache_hit_ages: AgeHistogram,
+}
+
+impl BootstrapStats {
+    /// Account for the outcome of a bootstrap run.
+    pub fn record(&mut self, report: &BootstrapReport) {
+        let hit = report
+            .attempts
+            .iter()
+            .find(|attempt| attempt.outcome == AttemptOutcome::Succeeded);
+        match hit {
+            Some(attempt) if attempt.source == ContactSource::Cache => {
+                self.cache_hits += 1;
+                self.cache_hit_ages.record(attempt.cache_age);
+            }
+            Some(_) => self.hard_coded_hits += 1,
+            None => self.failures += 1,
+        }
+    }
+}
+
+/// Statistics of this `QuicP2p` instance.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Statistics of the connections to each peer we currently know of.
+    pub connections: HashMap<SocketAddr, ConnectionStats>,
+    /// Rates of the bytes exchanged with each peer we currently know of.
+    pub bandwidth: HashMap<SocketAddr, Bandwidth>,
+    /// Outcomes of our bootstrap runs so far.
+    pub bootstrap: BootstrapStats,
+    /// Current ages of the entries in the bootstrap cache.
+    pub bootstrap_cache_ages: AgeHistogram,
+}
+
+/// Statistics of the connections to a single peer, see `QuicP2p::peer_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStats {
+    /// Totals of the connections to the peer.
+    pub connection: ConnectionStats,
+    /// Rates of the bytes exchanged with the peer, e.g. to account for its share of our traffic.
+    pub bandwidth: Bandwidth,
+}
+
+/// Statistics of a single QUIC connection, shared between the connection and the futures reading
+/// from and writing to it.
+pub type StatsHandle = Rc<RefCell<ConnectionStats>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages_are_bucketed() {
+        let mut histogram = AgeHistogram::default();
+        histogram.record(None);
+        histogram.record(Some(Duration::from_secs(59)));
+        histogram.record(Some(Duration::from_secs(60 * 60)));
+        histogram.record(Some(Duration::from_secs(3 * 24 * 60 * 60)));
+        histogram.record(Some(Duration::from_secs(30 * 24 * 60 * 60)));
+
+        assert_eq!(
+            histogram,
+            AgeHistogram {
+                under_an_hour: 1,
+                under_a_day: 1,
+                under_a_week: 1,
+                older: 1,
+                unknown: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Conditions of a link between two instances on a `MemoryTransport`, applying to the messages
+/// sent in one direction.
+///
+/// The default is a perfect link: no delay, no loss and no reordering.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Time every message takes to reach the peer.
+    pub latency: Duration,
+    /// Upper bound of the random delay added to `latency` for every message.
+    pub jitter: Duration,
+    /// Probability of a message getting lost, from 0 to 1.
+    pub loss: f64,
+    /// Probability of a message not waiting for the ones sent before it, from 0 to 1. With jitter
+    /// it may then arrive before them.
+    pub reordering: f64,
+}
+
+/// What happens to a message sent over a link.
+pub(super) enum Fate {
+    Lost,
+    Delivered {
+        delay: Duration,
+        /// Whether the message has to wait for the ones sent before it.
+        in_order: bool,
+    },
+}
+
+/// Conditions of all the links of a network.
+pub(super) struct Conditions {
+    default: LinkConditions,
+    links: HashMap<(SocketAddr, SocketAddr), LinkConditions>,
+    /// Pairs of groups of instances which can't reach each other.
+    partitions: Vec<(HashSet<SocketAddr>, HashSet<SocketAddr>)>,
+    rng: StdRng,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Conditions {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            default: Default::default(),
+            links: Default::default(),
+            partitions: Default::default(),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn set_default(&mut self, conditions: LinkConditions) {
+        self.default = conditions;
+    }
+
+    pub fn set_link(&mut self, from: SocketAddr, to: SocketAddr, conditions: LinkConditions) {
+        let _ = self.links.insert((from, to), conditions);
+    }
+
+    pub fn partition(&mut self, side_a: &[SocketAddr], side_b: &[SocketAddr]) {
+        self.partitions.push((
+            side_a.iter().cloned().collect(),
+            side_b.iter().cloned().collect(),
+        ));
+    }
+
+    pub fn heal(&mut self) {
+        self.partitions.clear();
+    }
+
+    pub fn is_partitioned(&self, from: SocketAddr, to: SocketAddr) -> bool {
+        self.partitions.iter().any(|(a, b)| {
+            (a.contains(&from) && b.contains(&to)) || (b.contains(&from) && a.contains(&to))
+        })
+    }
+
+    /// Time a handshake between the two takes, leaving jitter aside.
+    pub fn round_trip(&self, from: SocketAddr, to: SocketAddr) -> Duration {
+        self.link(from, to).latency + self.link(to, from).latency
+    }
+
+    pub fn fate(&mut self, from: SocketAddr, to: SocketAddr) -> Fate {
+        if self.is_partitioned(from, to) {
+            return Fate::Lost;
+        }
+
+        let link = self.link(from, to);
+        if link.loss > 0.0 && self.rng.gen_bool(link.loss) {
+            return Fate::Lost;
+        }
+        let jitter = match link.jitter.as_nanos() as u64 {
+            0 => Duration::from_secs(0),
+            max => Duration::from_nanos(self.rng.gen_range(0, max + 1)),
+        };
+        let in_order = link.reordering <= 0.0 || !self.rng.gen_bool(link.reordering);
+
+        Fate::Delivered {
+            delay: link.latency + jitter,
+            in_order,
+        }
+    }
+
+    fn link(&self, from: SocketAddr, to: SocketAddr) -> LinkConditions {
+        self.links.get(&(from, to)).cloned().unwrap_or(self.default)
+    }
+}
@@ -7,12 +7,19 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+// Most of these are for tests which need nodes
+#![cfg_attr(feature = "client-only", allow(dead_code))]
+
 use crate::config::{Config, SerialisableCertificate};
 use crate::connection::{Connection, FromPeer, QConn, ToPeer};
 use crate::context::ctx;
-use crate::context::Context;
 use crate::dirs::{Dirs, OverRide};
 use crate::event::Event;
+use crate::socks5;
+#[cfg(feature = "test-utils")]
+use crate::test_utils::MemoryTransport;
+use crate::transport::quic::QuicConn;
+use crate::transport::QuicTransport;
 use crate::utils::R;
 use crate::wire_msg::WireMsg;
 use crate::{communicate, Builder, NodeInfo, Peer, QuicP2p};
@@ -20,12 +27,14 @@ use crossbeam_channel as mpmc;
 use rand::Rng;
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
 use std::path::PathBuf;
 use std::sync::mpsc;
 use std::{
     env,
     ops::Deref,
+    thread,
     time::{Duration, Instant},
 };
 use tokio::prelude::{future::Either, Future};
@@ -94,6 +103,12 @@ impl<'a> EndpointWrap<'a> {
         quinn::ConnectError,
     > {
         test_ctx_mut(|ctx| ctx.attempted_connections.push(addr.clone()));
+        let black_hole = test_ctx(|ctx| {
+            ctx.udp_black_hole
+                .as_ref()
+                .map(|socket| unwrap!(socket.local_addr()))
+        });
+        let addr = black_hole.as_ref().unwrap_or(addr);
 
         let connecting_res = self.0.connect_with(config, addr, server_name)?;
         let delay_ms = test_ctx(|ctx| ctx.connect_delay);
@@ -116,9 +131,9 @@ impl<'a> Deref for EndpointWrap<'a> {
     }
 }
 
-impl Context {
-    pub(crate) fn quic_ep(&self) -> EndpointWrap {
-        EndpointWrap(&self.quic_ep)
+impl QuicTransport {
+    pub(crate) fn ep_for(&self, peer_addr: SocketAddr) -> EndpointWrap {
+        EndpointWrap(self.dialing_ep(peer_addr))
     }
 }
 
@@ -126,6 +141,8 @@ impl Context {
 pub(crate) struct TestContext {
     connect_delay: u64,
     attempted_connections: Vec<SocketAddr>,
+    /// Socket swallowing our QUIC connection attempts instead of the peers, as if UDP was blocked.
+    udp_black_hole: Option<UdpSocket>,
 }
 
 /// Extend `QuicP2p` with test functions.
@@ -135,6 +152,15 @@ impl QuicP2p {
             .post(move || test_ctx_mut(|ctx| ctx.connect_delay = delay_ms));
     }
 
+    /// Make our QUIC connection attempts time out as if UDP was blocked.
+    #[cfg(feature = "tcp-fallback")]
+    pub(crate) fn block_udp(&mut self) {
+        self.el.post(move || {
+            let black_hole = unwrap!(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)));
+            test_ctx_mut(|ctx| ctx.udp_black_hole = Some(black_hole))
+        });
+    }
+
     /// Get a list of attempted connections.
     pub(crate) fn attempted_connections(&mut self) -> R<Vec<SocketAddr>> {
         let (tx, rx) = mpsc::channel();
@@ -178,10 +204,11 @@ pub(crate) fn rand_node_info() -> NodeInfo {
     NodeInfo {
         peer_addr,
         peer_cert_der,
+        alt_addrs: Vec::new(),
     }
 }
 
-fn tmp_rand_dir() -> PathBuf {
+pub(crate) fn tmp_rand_dir() -> PathBuf {
     let fname = format!("quic_p2p_tests_{:016x}", rand::random::<u64>());
     let mut path = env::temp_dir();
     path.push(fname);
@@ -207,11 +234,77 @@ pub(crate) fn new_random_qp2p(
     (qp2p, rx)
 }
 
+/// Creates a new `QuicP2p` instance for testing, bound to the given in-memory network.
+#[cfg(feature = "test-utils")]
+pub(crate) fn new_qp2p_on(network: &MemoryTransport) -> (QuicP2p, mpmc::Receiver<Event>) {
+    let (tx, rx) = mpmc::unbounded();
+    let mut cfg = Config::with_default_cert();
+    cfg.port = Some(0);
+    cfg.ip = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let qp2p = unwrap!(Builder::new(tx)
+        .with_config(cfg)
+        .with_memory_transport(network.clone())
+        .build());
+
+    (qp2p, rx)
+}
+
+/// Run a minimal SOCKS5 proxy on localhost which relays UDP for a single association. Returns the
+/// address to reach it at.
+pub(crate) fn spawn_socks5_proxy() -> SocketAddr {
+    let listener = unwrap!(TcpListener::bind((Ipv4Addr::LOCALHOST, 0)));
+    let proxy_addr = unwrap!(listener.local_addr());
+
+    let _ = thread::spawn(move || {
+        let (mut control, _) = unwrap!(listener.accept());
+        let mut greeting = [0; 3];
+        unwrap!(control.read_exact(&mut greeting));
+        unwrap!(control.write_all(&[5, 0]));
+        // UDP ASSOCIATE request for an IPv4 address
+        let mut request = [0; 10];
+        unwrap!(control.read_exact(&mut request));
+
+        let relay = unwrap!(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)));
+        let relay_addr = unwrap!(relay.local_addr());
+        let mut reply = vec![5, 0, 0, 1];
+        reply.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        reply.extend_from_slice(&relay_addr.port().to_be_bytes());
+        unwrap!(control.write_all(&reply));
+
+        let _ = thread::spawn(move || {
+            let mut client_addr = None;
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let (len, from) = unwrap!(relay.recv_from(&mut buf));
+                if client_addr.is_none() || client_addr == Some(from) {
+                    client_addr = Some(from);
+                    if let Some((target, payload)) = socks5::decode_udp_header(&buf[..len]) {
+                        let _ = relay.send_to(payload, target);
+                    }
+                } else if let Some(client_addr) = client_addr {
+                    let mut datagram = socks5::encode_udp_header(from);
+                    datagram.extend_from_slice(&buf[..len]);
+                    let _ = relay.send_to(&datagram, client_addr);
+                }
+            }
+        });
+
+        // The association ends with the control connection
+        let _ = control.read(&mut [0; 1]);
+    });
+
+    proxy_addr
+}
+
 /// Connect and open a bi-directional stream.
 /// This will fail if we don't have a connection to the peer or if the peer is in an invalid state
 /// to be sent a message to.
 pub(crate) fn write_to_bi_stream(peer_addr: SocketAddr, wire_msg: WireMsg) {
     fn write_to_bi(conn: &QConn, wire_msg: WireMsg) {
+        let conn = match conn.as_any().downcast_ref::<QuicConn>() {
+            Some(conn) => &conn.0,
+            None => panic!("Bi-directional streams are only to be had over QUIC"),
+        };
         let leaf = conn
             .open_bi()
             .map_err(move |e| panic!("Open-Bidirectional: {:?} {}", e, e))
@@ -0,0 +1,530 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::conditions::{Conditions, Fate, LinkConditions};
+use crate::context::ctx;
+use crate::error::Error;
+use crate::transport::{Connecting, Incoming, NewConn, Transport, TransportConn};
+use crate::wire_msg::WireEncoding;
+use crate::R;
+use bytes::Bytes;
+use quinn_proto::coding::Codec;
+#[cfg(test)]
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::prelude::future::{self, Either};
+use tokio::prelude::{Future, Stream};
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc;
+use tokio::timer::Delay;
+
+/// In-process network of `QuicP2p` instances, handed to each of them with
+/// `Builder::with_memory_transport`.
+///
+/// Instances sharing a `MemoryTransport` reach each other through channels instead of sockets, so
+/// tests using it are fast and hermetic. An instance is bound to the IP and port of its `Config`
+/// as usual, except that an unspecified IP is replaced with the loopback one and a port which is
+/// 0 or taken with the lowest free one. Connecting to an address no node is bound to fails right
+/// away with a `TimedOut` connection error, rather than once the idle timeout passed.
+///
+/// Messages are delivered as soon as they are sent unless delivery is paused, in which case they
+/// queue up until they are let through with `deliver_next` or `resume`.
+///
+/// Links can be made to add latency and jitter, lose and reorder messages with
+/// `set_conditions` and `set_link_conditions`, and groups of instances can be cut off from each
+/// other with `partition`. Messages and connection attempts across a partition are lost, but
+/// connections already established aren't torn down: that is up to the heartbeats or timeouts of
+/// the code under test. Random decisions are taken by a generator seeded with 0 unless the
+/// network was made by `with_seed`, so that a run can be reproduced.
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    network: Arc<Mutex<Network>>,
+}
+
+#[derive(Default)]
+struct Network {
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    is_paused: bool,
+    pending_msgs: VecDeque<Packet>,
+    conditions: Conditions,
+}
+
+struct Endpoint {
+    cert_der: Vec<u8>,
+    /// ALPN protocols, most preferred first.
+    protocols: Vec<Vec<u8>>,
+    /// Set once the endpoint accepts connections.
+    incoming_tx: Option<mpsc::UnboundedSender<HalfConn>>,
+    /// Whether connection attempts time out as if nobody was bound here, see
+    /// `TestPeer::accept_connections`.
+    refuses_connections: bool,
+}
+
+/// A message on its way to the peer.
+struct Packet {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    raw: Bytes,
+}
+
+/// A message to deliver later on, as its link has a delay.
+struct Delayed {
+    packet: Packet,
+    at: Instant,
+    /// Whether the message has to wait for the ones sent before it.
+    in_order: bool,
+}
+
+impl MemoryTransport {
+    /// Create a network nobody is bound to yet.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a network taking its random decisions with a generator seeded with the given value.
+    pub fn with_seed(seed: u64) -> Self {
+        let network = Network {
+            conditions: Conditions::new(seed),
+            ..Default::default()
+        };
+        Self {
+            network: Arc::new(Mutex::new(network)),
+        }
+    }
+
+    /// Conditions of every link which wasn't given its own with `set_link_conditions`.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        unwrap!(self.network.lock())
+            .conditions
+            .set_default(conditions);
+    }
+
+    /// Conditions of the messages sent from one address to another.
+    pub fn set_link_conditions(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        conditions: LinkConditions,
+    ) {
+        unwrap!(self.network.lock())
+            .conditions
+            .set_link(from, to, conditions);
+    }
+
+    /// Cut the instances bound to the addresses on one side off from those on the other side,
+    /// both ways. Partitions add up until they are healed.
+    pub fn partition(&self, side_a: &[SocketAddr], side_b: &[SocketAddr]) {
+        unwrap!(self.network.lock())
+            .conditions
+            .partition(side_a, side_b);
+    }
+
+    /// Lift all partitions.
+    pub fn heal(&self) {
+        unwrap!(self.network.lock()).conditions.heal();
+    }
+
+    /// Hold messages back from now on.
+    pub fn pause(&self) {
+        unwrap!(self.network.lock()).is_paused = true;
+    }
+
+    /// Deliver the messages held back, in the order they were sent, and the ones sent from now on
+    /// straight away.
+    pub fn resume(&self) {
+        let mut network = unwrap!(self.network.lock());
+        network.is_paused = false;
+        while let Some(packet) = network.pending_msgs.pop_front() {
+            let _ = packet.deliver();
+        }
+    }
+
+    /// Deliver the oldest message held back. Returns whether there was one.
+    pub fn deliver_next(&self) -> bool {
+        match unwrap!(self.network.lock()).pending_msgs.pop_front() {
+            Some(packet) => {
+                let _ = packet.deliver();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of messages held back.
+    pub fn pending_msgs(&self) -> usize {
+        unwrap!(self.network.lock()).pending_msgs.len()
+    }
+
+    /// Bind an instance authenticated by the given certificate to the network.
+    pub(crate) fn bind(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        cert_der: Vec<u8>,
+        wire_encoding: WireEncoding,
+    ) -> MemoryEndpoint {
+        let ip = match ip {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+
+        let mut network = unwrap!(self.network.lock());
+        let addr = unwrap!(iter::once(port)
+            .filter(|&port| port != 0)
+            .chain(1..=u16::MAX)
+            .map(|port| SocketAddr::new(ip, port))
+            .find(|addr| !network.endpoints.contains_key(addr)));
+        let endpoint = Endpoint {
+            cert_der,
+            protocols: WireEncoding::alpn_protocols(wire_encoding)
+                .into_iter()
+                .map(|p| p.to_vec())
+                .collect(),
+            incoming_tx: None,
+            refuses_connections: false,
+        };
+        let _ = network.endpoints.insert(addr, endpoint);
+
+        MemoryEndpoint {
+            network: self.clone(),
+            addr,
+        }
+    }
+
+    /// Let connection attempts to the instance bound to `addr` time out, or stop doing so.
+    pub(crate) fn refuse_connections(&self, addr: SocketAddr, refuse: bool) {
+        if let Some(endpoint) = unwrap!(self.network.lock()).endpoints.get_mut(&addr) {
+            endpoint.refuses_connections = refuse;
+        }
+    }
+
+    /// Hand the message over to the peer, unless delivery is paused or the link loses or delays
+    /// it. Returns the message back if it has to be delivered later on.
+    fn send(
+        &self,
+        from: SocketAddr,
+        to: SocketAddr,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        raw: Bytes,
+    ) -> R<Option<Delayed>> {
+        let packet = Packet { tx, raw };
+        let mut network = unwrap!(self.network.lock());
+        let (delay, in_order) = match network.conditions.fate(from, to) {
+            Fate::Lost => return Ok(None),
+            Fate::Delivered { delay, in_order } => (delay, in_order),
+        };
+
+        if network.is_paused {
+            network.pending_msgs.push_back(packet);
+            Ok(None)
+        } else if delay > Duration::from_secs(0) {
+            Ok(Some(Delayed {
+                packet,
+                at: Instant::now() + delay,
+                in_order,
+            }))
+        } else if packet.deliver() {
+            Ok(None)
+        } else {
+            Err(peer_closed())
+        }
+    }
+}
+
+impl Packet {
+    /// Returns `false` if the peer has closed the connection.
+    fn deliver(self) -> bool {
+        let Packet { mut tx, raw } = self;
+        tx.try_send(raw.to_vec()).is_ok()
+    }
+}
+
+/// Transport of one instance bound to a `MemoryTransport`.
+pub(crate) struct MemoryEndpoint {
+    network: MemoryTransport,
+    addr: SocketAddr,
+}
+
+impl MemoryEndpoint {
+    /// Start connecting to the instance bound to the given address, offering the given ALPN
+    /// protocols, most preferred first.
+    pub(crate) fn dial(
+        &self,
+        peer_addr: SocketAddr,
+        peer_cert_der: &[u8],
+        our_protocols: &[&[u8]],
+    ) -> Connecting {
+        let mut network = unwrap!(self.network.network.lock());
+        if network.conditions.is_partitioned(self.addr, peer_addr) {
+            return Box::new(future::err(timed_out()));
+        }
+        let round_trip = network.conditions.round_trip(self.addr, peer_addr);
+        let peer = match network.endpoints.get_mut(&peer_addr) {
+            Some(peer) if !peer.refuses_connections => peer,
+            _ => return Box::new(future::err(timed_out())),
+        };
+        if peer.cert_der != peer_cert_der {
+            return Box::new(future::err(bad_certificate()));
+        }
+        // Like TLS, go by the preference of the accepting side
+        let protocol = peer
+            .protocols
+            .iter()
+            .find(|p| our_protocols.contains(&&p[..]))
+            .cloned();
+
+        let (ours, theirs) = HalfConn::pair(&self.network, self.addr, peer_addr, protocol);
+        let is_accepted = match peer.incoming_tx {
+            Some(ref mut incoming_tx) => incoming_tx.try_send(theirs).is_ok(),
+            None => false,
+        };
+        if !is_accepted {
+            return Box::new(future::err(timed_out()));
+        }
+
+        let new_conn = ours.into_new_conn();
+        if round_trip == Duration::from_secs(0) {
+            return Box::new(future::ok(new_conn));
+        }
+        let connecting = Delay::new(Instant::now() + round_trip).then(move |_| Ok(new_conn));
+
+        Box::new(connecting)
+    }
+}
+
+impl Transport for MemoryEndpoint {
+    fn local_addr(&self) -> R<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn can_dial(&self, _peer_addr: SocketAddr) -> bool {
+        true
+    }
+
+    fn connect(&self, peer_addr: SocketAddr, peer_cert_der: &[u8]) -> R<Connecting> {
+        let our_protocols = WireEncoding::alpn_protocols(ctx(|c| c.wire_encoding));
+        Ok(self.dial(peer_addr, peer_cert_der, &our_protocols))
+    }
+
+    fn incoming(&self) -> Option<Incoming> {
+        let mut network = unwrap!(self.network.network.lock());
+        let endpoint = network.endpoints.get_mut(&self.addr)?;
+        if endpoint.incoming_tx.is_some() {
+            return None;
+        }
+
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        endpoint.incoming_tx = Some(incoming_tx);
+        let incoming = incoming_rx
+            .map_err(|_| Error::ConnectionCancelled)
+            .map(HalfConn::into_new_conn);
+
+        Some(Box::new(incoming))
+    }
+}
+
+impl Drop for MemoryEndpoint {
+    fn drop(&mut self) {
+        let _ = unwrap!(self.network.network.lock())
+            .endpoints
+            .remove(&self.addr);
+    }
+}
+
+/// One end of a connection, on its way to the event loop it belongs to.
+struct HalfConn {
+    network: MemoryTransport,
+    addr: SocketAddr,
+    peer_addr: SocketAddr,
+    protocol: Option<Vec<u8>>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Dropped when this end closes the connection.
+    close_tx: mpsc::UnboundedSender<()>,
+    /// Ends when the other end closes the connection.
+    peer_close_rx: mpsc::UnboundedReceiver<()>,
+}
+
+impl HalfConn {
+    fn pair(
+        network: &MemoryTransport,
+        addr_a: SocketAddr,
+        addr_b: SocketAddr,
+        protocol: Option<Vec<u8>>,
+    ) -> (Self, Self) {
+        let (tx_a, rx_b) = mpsc::unbounded_channel();
+        let (tx_b, rx_a) = mpsc::unbounded_channel();
+        let (close_tx_a, close_rx_a) = mpsc::unbounded_channel();
+        let (close_tx_b, close_rx_b) = mpsc::unbounded_channel();
+
+        let a = HalfConn {
+            network: network.clone(),
+            addr: addr_a,
+            peer_addr: addr_b,
+            protocol: protocol.clone(),
+            tx: tx_a,
+            rx: rx_a,
+            close_tx: close_tx_a,
+            peer_close_rx: close_rx_b,
+        };
+        let b = HalfConn {
+            network: network.clone(),
+            addr: addr_b,
+            peer_addr: addr_a,
+            protocol,
+            tx: tx_b,
+            rx: rx_b,
+            close_tx: close_tx_b,
+            peer_close_rx: close_rx_a,
+        };
+
+        (a, b)
+    }
+
+    fn into_new_conn(self) -> NewConn {
+        let (local_close_tx, local_close_rx) = mpsc::unbounded_channel::<()>();
+
+        // Closing our end ourselves ends the driver quietly, like quinn's
+        let driver = self
+            .peer_close_rx
+            .into_future()
+            .select2(local_close_rx.into_future())
+            .then(|r| match r {
+                Ok(Either::A(_)) | Err(Either::A(_)) => Err(peer_closed()),
+                Ok(Either::B(_)) | Err(Either::B(_)) => Ok(()),
+            });
+        let incoming_msgs = self.rx.map_err(|_| Error::ConnectionCancelled);
+        let conn = MemoryConn {
+            network: self.network,
+            addr: self.addr,
+            peer_addr: self.peer_addr,
+            protocol: self.protocol,
+            tx: RefCell::new(Some(self.tx)),
+            close_txs: RefCell::new(Some((self.close_tx, local_close_tx))),
+            delay_line: Default::default(),
+        };
+
+        NewConn {
+            conn: Box::new(conn),
+            driver: Box::new(driver),
+            incoming_msgs: Box::new(incoming_msgs),
+        }
+    }
+}
+
+struct MemoryConn {
+    network: MemoryTransport,
+    addr: SocketAddr,
+    peer_addr: SocketAddr,
+    protocol: Option<Vec<u8>>,
+    tx: RefCell<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    /// Dropped to signal to the peer and to our driver that we closed the connection.
+    close_txs: RefCell<Option<(mpsc::UnboundedSender<()>, mpsc::UnboundedSender<()>)>>,
+    /// Delivers delayed messages in the order they were sent. Started with the first of them.
+    delay_line: RefCell<Option<mpsc::UnboundedSender<Delayed>>>,
+}
+
+impl MemoryConn {
+    /// Deliver the message once its delay is up. Must be called from within the event loop.
+    fn deliver_later(&self, delayed: Delayed) {
+        if !delayed.in_order {
+            let Delayed { packet, at, .. } = delayed;
+            current_thread::spawn(Delay::new(at).then(move |_| {
+                let _ = packet.deliver();
+                Ok(())
+            }));
+            return;
+        }
+
+        let mut delay_line = self.delay_line.borrow_mut();
+        let delay_line = delay_line.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded_channel::<Delayed>();
+            let leaf = rx.map_err(|_| ()).for_each(|delayed| {
+                let Delayed { packet, at, .. } = delayed;
+                Delay::new(at).then(move |_| {
+                    let _ = packet.deliver();
+                    Ok(())
+                })
+            });
+            current_thread::spawn(leaf);
+            tx
+        });
+        let _ = delay_line.try_send(delayed);
+    }
+}
+
+impl TransportConn for MemoryConn {
+    fn remote_address(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn protocol(&self) -> Option<Box<[u8]>> {
+        self.protocol.clone().map(Vec::into_boxed_slice)
+    }
+
+    fn send(&self, raw: Bytes) -> Box<dyn Future<Item = (), Error = Error>> {
+        let r = match *self.tx.borrow() {
+            Some(ref tx) => self
+                .network
+                .send(self.addr, self.peer_addr, tx.clone(), raw),
+            None => Err(Error::Connection(quinn::ConnectionError::LocallyClosed)),
+        };
+        let r = r.map(|delayed| {
+            if let Some(delayed) = delayed {
+                self.deliver_later(delayed);
+            }
+        });
+        Box::new(future::result(r))
+    }
+
+    fn close(&self) {
+        let _ = self.tx.borrow_mut().take();
+        let _ = self.close_txs.borrow_mut().take();
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// What quinn reports when the peer closed the connection the way `QConn` does.
+fn peer_closed() -> Error {
+    Error::Connection(quinn::ConnectionError::ApplicationClosed {
+        reason: quinn_proto::ApplicationClose {
+            error_code: 0,
+            reason: Bytes::new(),
+        },
+    })
+}
+
+fn timed_out() -> Error {
+    Error::Connection(quinn::ConnectionError::TimedOut)
+}
+
+/// What quinn reports when the peer's certificate isn't the one we expected: the TLS alert
+/// `bad_certificate` (42) as a crypto error.
+fn bad_certificate() -> Error {
+    let code = unwrap!(quinn_proto::TransportErrorCode::decode(&mut Cursor::new([
+        0x01, 42
+    ])));
+    Error::Connection(quinn::ConnectionError::TransportError(
+        quinn_proto::TransportError {
+            code,
+            frame: None,
+            reason: "invalid peer certificate".to_string(),
+        },
+    ))
+}
@@ -0,0 +1,30 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Utilities for testing networking logic built on quic-p2p. Enabled by the `test-utils` feature.
+
+pub use self::clock::ManualClock;
+#[cfg(feature = "test-utils")]
+pub use self::conditions::LinkConditions;
+#[cfg(test)]
+pub(crate) use self::internal::*;
+#[cfg(feature = "test-utils")]
+pub use self::memory::MemoryTransport;
+#[cfg(feature = "test-utils")]
+pub use self::test_peer::TestPeer;
+
+mod clock;
+#[cfg(feature = "test-utils")]
+mod conditions;
+#[cfg(test)]
+mod internal;
+#[cfg(feature = "test-utils")]
+mod memory;
+#[cfg(feature = "test-utils")]
+mod test_peer;
@@ -0,0 +1,538 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::memory::{MemoryEndpoint, MemoryTransport};
+use crate::config::SerialisableCertificate;
+use crate::transport::{NewConn, Transport, TransportConn};
+use crate::wire_msg::{Handshake, WireEncoding, WireMsg, PROTOCOL_VERSION};
+use crate::NodeInfo;
+use bytes::Bytes;
+use crossbeam_channel as mpmc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::prelude::{future, Future, Stream};
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc;
+use tokio::timer::Delay;
+
+/// What corrupted replies are sent as: a frame no encoding can decode.
+const CORRUPT_FRAME: &[u8] = &[0xc1];
+
+/// Decides the reply to a wire message from a peer, if any.
+type Replier = Box<dyn Fn(SocketAddr, &WireMsg) -> Option<WireMsg> + Send>;
+/// Work for the event loop of the peer.
+type Job = Box<dyn FnOnce(&Rc<RefCell<State>>) + Send>;
+
+/// A peer on a `MemoryTransport` which misbehaves as scripted, for testing how the code under
+/// test copes with peers refusing connections, answering late or answering garbage.
+///
+/// The peer acts as a node speaking the wire protocol of quic-p2p: it accepts connections, connects
+/// back to the nodes connecting to it and answers `EndpointEchoReq` and `Ping` like a node would,
+/// unless told otherwise by `reply_with`. Every wire message it receives, handshakes included, is
+/// kept for the test to assert on with `next_msg` or `expect_msg`.
+pub struct TestPeer {
+    network: MemoryTransport,
+    node_info: NodeInfo,
+    script: Arc<Mutex<Script>>,
+    msgs_rx: mpmc::Receiver<(SocketAddr, WireMsg)>,
+    jobs_tx: Option<mpsc::UnboundedSender<Job>>,
+    j: Option<JoinHandle<()>>,
+}
+
+struct Script {
+    reply_delay: Duration,
+    corrupt_replies: bool,
+    replier: Replier,
+}
+
+struct State {
+    endpoint: MemoryEndpoint,
+    our_cert_der: Vec<u8>,
+    script: Arc<Mutex<Script>>,
+    msgs_tx: mpmc::Sender<(SocketAddr, WireMsg)>,
+    /// Connections we made, which are the ones nodes read from.
+    dialed: HashMap<SocketAddr, Rc<Conn>>,
+    /// Connections peers made to us.
+    accepted: HashMap<SocketAddr, Rc<Conn>>,
+}
+
+struct Conn {
+    conn: Box<dyn TransportConn>,
+    encoding: WireEncoding,
+}
+
+impl TestPeer {
+    /// Bind a peer to the network on the loopback IP, accepting connections and answering like a
+    /// node.
+    pub fn new(network: &MemoryTransport) -> Self {
+        let our_cert = SerialisableCertificate::default();
+        let endpoint = network.bind(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            0,
+            our_cert.cert_der.clone(),
+            Default::default(),
+        );
+        let node_info = NodeInfo {
+            peer_addr: unwrap!(endpoint.local_addr()),
+            peer_cert_der: our_cert.cert_der.clone(),
+            alt_addrs: Vec::new(),
+        };
+        let script = Arc::new(Mutex::new(Script {
+            reply_delay: Duration::from_secs(0),
+            corrupt_replies: false,
+            replier: Box::new(reply_as_node),
+        }));
+        let (msgs_tx, msgs_rx) = mpmc::unbounded();
+        let (jobs_tx, jobs_rx) = mpsc::unbounded_channel::<Job>();
+
+        let state_script = script.clone();
+        let j = unwrap!(thread::Builder::new()
+            .name("QuicP2p-Test-Peer".into())
+            .spawn(move || {
+                let state = Rc::new(RefCell::new(State {
+                    endpoint,
+                    our_cert_der: our_cert.cert_der,
+                    script: state_script,
+                    msgs_tx,
+                    dialed: Default::default(),
+                    accepted: Default::default(),
+                }));
+                let mut runtime = unwrap!(current_thread::Runtime::new());
+                let _ = runtime.block_on(future::lazy(move || {
+                    accept(&state);
+                    jobs_rx.map_err(|_| ()).for_each(move |job| {
+                        job(&state);
+                        Ok(())
+                    })
+                }));
+                // Dropping the runtime drops the connections it was still driving
+            }));
+
+        Self {
+            network: network.clone(),
+            node_info,
+            script,
+            msgs_rx,
+            jobs_tx: Some(jobs_tx),
+            j: Some(j),
+        }
+    }
+
+    /// Contact info of the peer, to connect or bootstrap to it.
+    pub fn node_info(&self) -> NodeInfo {
+        self.node_info.clone()
+    }
+
+    /// Accept the connections peers make to us from now on, or let their attempts time out as if
+    /// we were behind a firewall. Connections already made aren't affected. On by default.
+    pub fn accept_connections(&self, accept: bool) {
+        self.network
+            .refuse_connections(self.node_info.peer_addr, !accept);
+    }
+
+    /// Send the replies only once `delay` passed since the messages they answer arrived. None by
+    /// default.
+    pub fn delay_replies(&self, delay: Duration) {
+        unwrap!(self.script.lock()).reply_delay = delay;
+    }
+
+    /// Send bytes no encoding can decode in place of the replies from now on. Off by default.
+    pub fn corrupt_replies(&self, corrupt: bool) {
+        unwrap!(self.script.lock()).corrupt_replies = corrupt;
+    }
+
+    /// Answer the wire messages of peers as decided by `reply`, given the address of the peer and
+    /// its message, from now on. Messages answered with `None` go unanswered.
+    pub fn reply_with<F>(&self, reply: F)
+    where
+        F: Fn(SocketAddr, &WireMsg) -> Option<WireMsg> + Send + 'static,
+    {
+        unwrap!(self.script.lock()).replier = Box::new(reply);
+    }
+
+    /// Connect to the given node, introducing ourselves as a node.
+    pub fn connect_to(&self, peer: NodeInfo) {
+        self.post(move |state| connect(state, peer.peer_addr, &peer.peer_cert_der));
+    }
+
+    /// Send the wire message to the peer we are connected to at `peer_addr`, unsolicited.
+    pub fn send(&self, peer_addr: SocketAddr, msg: WireMsg) {
+        self.post(move |state| send_now(state, peer_addr, Some(msg)));
+    }
+
+    /// Close our connections with the peer at `peer_addr`.
+    pub fn disconnect(&self, peer_addr: SocketAddr) {
+        self.post(move |state| {
+            let mut state = state.borrow_mut();
+            let dialed = state.dialed.remove(&peer_addr);
+            let accepted = state.accepted.remove(&peer_addr);
+            for conn in dialed.iter().chain(accepted.iter()) {
+                conn.conn.close();
+            }
+        });
+    }
+
+    /// Next wire message received, with the address of its sender, unless none arrives within
+    /// `timeout`.
+    pub fn next_msg(&self, timeout: Duration) -> Option<(SocketAddr, WireMsg)> {
+        self.msgs_rx.recv_timeout(timeout).ok()
+    }
+
+    /// Next wire message received which `matches`, with the address of its sender, skipping the
+    /// others.
+    ///
+    /// # Panics
+    ///
+    /// If no such message arrives within `timeout`, listing the messages skipped.
+    pub fn expect_msg<F>(&self, timeout: Duration, matches: F) -> (SocketAddr, WireMsg)
+    where
+        F: Fn(&WireMsg) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        let mut skipped = Vec::new();
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.msgs_rx.recv_timeout(left) {
+                Ok((peer_addr, msg)) => {
+                    if matches(&msg) {
+                        return (peer_addr, msg);
+                    }
+                    skipped.push((peer_addr, msg));
+                }
+                Err(_) => panic!(
+                    "No matching wire message within {:?}, received {:?}",
+                    timeout, skipped
+                ),
+            }
+        }
+    }
+
+    fn post<F>(&self, job: F)
+    where
+        F: FnOnce(&Rc<RefCell<State>>) + Send + 'static,
+    {
+        if let Some(ref jobs_tx) = self.jobs_tx {
+            if jobs_tx.clone().try_send(Box::new(job)).is_err() {
+                warn!("Error posting to the test peer: its event loop has exited");
+            }
+        }
+    }
+}
+
+impl Drop for TestPeer {
+    fn drop(&mut self) {
+        // Ends the event loop of the peer
+        let _ = self.jobs_tx.take();
+        if let Some(j) = self.j.take() {
+            if let Err(e) = j.join() {
+                warn!("Error joining the test peer thread: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Answer like a node would.
+fn reply_as_node(peer_addr: SocketAddr, msg: &WireMsg) -> Option<WireMsg> {
+    match *msg {
+        WireMsg::EndpointEchoReq => Some(WireMsg::EndpointEchoResp(peer_addr)),
+        WireMsg::Ping { nonce } => Some(WireMsg::Pong { nonce }),
+        _ => None,
+    }
+}
+
+fn accept(state: &Rc<RefCell<State>>) {
+    let incoming = match state.borrow().endpoint.incoming() {
+        Some(incoming) => incoming,
+        None => return,
+    };
+    let state = state.clone();
+    let leaf = incoming
+        .map_err(|e| debug!("Test peer stopped accepting connections: {}", e))
+        .for_each(move |new_conn| {
+            handle_new_conn(&state, new_conn, false);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn connect(state: &Rc<RefCell<State>>, peer_addr: SocketAddr, peer_cert_der: &[u8]) {
+    let our_protocols = WireEncoding::alpn_protocols(Default::default());
+    let connecting = state
+        .borrow()
+        .endpoint
+        .dial(peer_addr, peer_cert_der, &our_protocols);
+    let state = state.clone();
+    let leaf = connecting.then(move |r| {
+        match r {
+            Ok(new_conn) => {
+                handle_new_conn(&state, new_conn, true);
+                let handshake = WireMsg::Handshake(Handshake::Node {
+                    cert_der: state.borrow().our_cert_der.clone(),
+                    capabilities: Default::default(),
+                    protocol_version: PROTOCOL_VERSION,
+                });
+                send_now(&state, peer_addr, Some(handshake));
+            }
+            Err(e) => debug!("Test peer could not connect to {}: {}", peer_addr, e),
+        }
+        Ok(())
+    });
+
+    current_thread::spawn(leaf);
+}
+
+fn handle_new_conn(state: &Rc<RefCell<State>>, new_conn: NewConn, dialed: bool) {
+    let NewConn {
+        conn,
+        driver,
+        incoming_msgs,
+    } = new_conn;
+    let peer_addr = conn.remote_address();
+    let encoding = WireEncoding::from_alpn_protocol(conn.protocol().as_deref());
+    let conn = Rc::new(Conn { conn, encoding });
+    {
+        let mut state = state.borrow_mut();
+        let conns = if dialed {
+            &mut state.dialed
+        } else {
+            &mut state.accepted
+        };
+        let _ = conns.insert(peer_addr, conn.clone());
+    }
+
+    let driver_state = state.clone();
+    let conn = Rc::downgrade(&conn);
+    current_thread::spawn(driver.then(move |_| {
+        let mut state = driver_state.borrow_mut();
+        let conns = if dialed {
+            &mut state.dialed
+        } else {
+            &mut state.accepted
+        };
+        let is_current = match (conns.get(&peer_addr), conn.upgrade()) {
+            (Some(current), Some(conn)) => Rc::ptr_eq(current, &conn),
+            _ => false,
+        };
+        if is_current {
+            let _ = conns.remove(&peer_addr);
+        }
+        Ok(())
+    }));
+
+    let state = state.clone();
+    let leaf = incoming_msgs
+        .map_err(move |e| trace!("Test peer connection with {} ended: {}", peer_addr, e))
+        .for_each(move |raw| {
+            handle_raw(&state, peer_addr, raw, encoding);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+}
+
+fn handle_raw(
+    state: &Rc<RefCell<State>>,
+    peer_addr: SocketAddr,
+    raw: Vec<u8>,
+    encoding: WireEncoding,
+) {
+    let msg = match WireMsg::decode(raw, encoding) {
+        Ok(msg) => msg,
+        Err(e) => {
+            return debug!(
+                "Test peer could not decode message from {}: {}",
+                peer_addr, e
+            )
+        }
+    };
+
+    if let WireMsg::Handshake(Handshake::Node { ref cert_der, .. }) = msg {
+        let is_connected = state.borrow().dialed.contains_key(&peer_addr);
+        if !is_connected {
+            connect(state, peer_addr, cert_der);
+        }
+    }
+
+    let (reply, delay, corrupt) = {
+        let state = state.borrow();
+        let script = unwrap!(state.script.lock());
+        let reply = (script.replier)(peer_addr, &msg);
+        let _ = state.msgs_tx.send((peer_addr, msg));
+        (reply, script.reply_delay, script.corrupt_replies)
+    };
+    let reply = match reply {
+        Some(reply) => reply,
+        None => return,
+    };
+    let reply = if corrupt { None } else { Some(reply) };
+    if delay == Duration::from_secs(0) {
+        return send_now(state, peer_addr, reply);
+    }
+
+    let state = state.clone();
+    current_thread::spawn(Delay::new(Instant::now() + delay).then(move |_| {
+        send_now(&state, peer_addr, reply);
+        Ok(())
+    }));
+}
+
+/// Send the message to the peer, preferring the connection we made, or a corrupt frame if there's
+/// no message.
+fn send_now(state: &Rc<RefCell<State>>, peer_addr: SocketAddr, msg: Option<WireMsg>) {
+    let conn = {
+        let state = state.borrow();
+        state
+            .dialed
+            .get(&peer_addr)
+            .or_else(|| state.accepted.get(&peer_addr))
+            .cloned()
+    };
+    let conn = match conn {
+        Some(conn) => conn,
+        None => return debug!("Test peer isn't connected to {}", peer_addr),
+    };
+    let raw = match msg {
+        Some(msg) => msg.encode(conn.encoding),
+        None => Bytes::from_static(CORRUPT_FRAME),
+    };
+
+    current_thread::spawn(
+        conn.conn
+            .send(raw)
+            .map_err(move |e| debug!("Test peer could not send to {}: {}", peer_addr, e)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::new_qp2p_on;
+    use crate::{Error, Event, QuicP2p};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn find_event<T>(rx: &mpmc::Receiver<Event>, f: impl Fn(Event) -> Option<T>) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if let Some(found) = f(unwrap!(rx.recv_timeout(left))) {
+                return found;
+            }
+        }
+    }
+
+    fn connected(node: &mut QuicP2p, rx: &mpmc::Receiver<Event>, peer: &TestPeer) {
+        node.connect_to(peer.node_info());
+        find_event(rx, |event| match event {
+            Event::ConnectedTo { .. } => Some(()),
+            _ => None,
+        });
+    }
+
+    #[test]
+    fn nodes_are_connected_back_and_talked_to() {
+        let network = MemoryTransport::new();
+        let peer = TestPeer::new(&network);
+        let (mut node, rx) = new_qp2p_on(&network);
+        let node_addr = unwrap!(node.our_connection_info()).peer_addr;
+
+        connected(&mut node, &rx, &peer);
+        let (from, _) = peer.expect_msg(TIMEOUT, |msg| match *msg {
+            WireMsg::Handshake(Handshake::Node { .. }) => true,
+            _ => false,
+        });
+        assert_eq!(from, node_addr);
+
+        node.send(peer.node_info().into(), From::from(&b"ping"[..]));
+        match peer.expect_msg(TIMEOUT, |msg| msg.user_msg().is_some()) {
+            (_, WireMsg::UserMsg(msg)) => assert_eq!(&msg[..], b"ping"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        peer.send(node_addr, WireMsg::UserMsg(From::from(&b"pong"[..])));
+        let msg = find_event(&rx, |event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(&msg[..], b"pong");
+    }
+
+    #[test]
+    fn connections_are_refused_as_scripted() {
+        let network = MemoryTransport::new();
+        let peer = TestPeer::new(&network);
+        peer.accept_connections(false);
+        let (mut node, rx) = new_qp2p_on(&network);
+
+        node.send(peer.node_info().into(), From::from(&b"ping"[..]));
+        let peer_addr = find_event(&rx, |event| match event {
+            Event::UnsentUserMessage { peer, .. } => Some(peer.peer_addr()),
+            Event::ConnectedTo { .. } => panic!("Unexpected event {:?}", event),
+            _ => None,
+        });
+        assert_eq!(peer_addr, peer.node_info().peer_addr);
+        assert!(peer.next_msg(Duration::from_millis(100)).is_none());
+
+        peer.accept_connections(true);
+        connected(&mut node, &rx, &peer);
+    }
+
+    #[test]
+    fn replies_are_delayed_and_corrupted_as_scripted() {
+        let network = MemoryTransport::new();
+        let peer = TestPeer::new(&network);
+        peer.reply_with(|_, msg| msg.user_msg().cloned().map(WireMsg::UserMsg));
+        peer.delay_replies(Duration::from_millis(300));
+        let (mut node, rx) = new_qp2p_on(&network);
+        connected(&mut node, &rx, &peer);
+
+        let sent_at = Instant::now();
+        node.send(peer.node_info().into(), From::from(&b"echo"[..]));
+        let msg = find_event(&rx, |event| match event {
+            Event::NewMessage { msg, .. } => Some(msg),
+            _ => None,
+        });
+        assert_eq!(&msg[..], b"echo");
+        assert!(sent_at.elapsed() >= Duration::from_millis(300));
+
+        peer.delay_replies(Duration::from_secs(0));
+        peer.corrupt_replies(true);
+        node.send(peer.node_info().into(), From::from(&b"echo"[..]));
+        let err = find_event(&rx, |event| match event {
+            Event::ConnectionFailure { err, .. } => Some(err),
+            Event::NewMessage { .. } => panic!("Unexpected event {:?}", event),
+            _ => None,
+        });
+        // Dropped by us for the garbage, not closed by the peer
+        if let Error::Connection(_) = err {
+            panic!("Unexpected error {:?}", err);
+        }
+    }
+
+    #[test]
+    fn corrupt_frames_decode_with_no_encoding() {
+        let encodings = [
+            WireEncoding::Tagged,
+            WireEncoding::Bincode,
+            WireEncoding::MessagePack,
+            WireEncoding::Cbor,
+            #[cfg(feature = "proto")]
+            WireEncoding::Protobuf,
+        ];
+        for &encoding in &encodings {
+            assert!(WireMsg::decode(CORRUPT_FRAME.to_vec(), encoding).is_err());
+        }
+    }
+}
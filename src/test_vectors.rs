@@ -0,0 +1,431 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Canonical encodings of every wire message in every wire encoding.
+//!
+//! Alternative implementations can check their encoders and decoders against these, and we check
+//! ours against the fixtures in `tests/fixtures/wire_test_vectors.txt` so that changes to the
+//! wire format can't go unnoticed. The `wire_test_vectors` example writes and verifies fixture
+//! files.
+//!
+//! Fixtures are text files with one vector per line: the name of the encoding, the name of the
+//! message and the hex encoded bytes, separated by a space. Empty lines and lines starting with
+//! `#` are ignored.
+
+use crate::wire_msg::{Handshake, WireEncoding, WireMsg, PROTOCOL_VERSION};
+use crate::{Capabilities, NodeInfo};
+use std::fmt::Write;
+use std::net::{Ipv6Addr, SocketAddr};
+
+/// Encoding of a sample message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    /// Encoding used.
+    pub encoding: WireEncoding,
+    /// Name of the sample message.
+    pub msg: String,
+    /// The encoded message.
+    pub raw: Vec<u8>,
+}
+
+/// Sample of every wire message, by name.
+pub fn samples() -> Vec<(&'static str, WireMsg)> {
+    vec![
+        (
+            "handshake_node",
+            WireMsg::Handshake(Handshake::Node {
+                cert_der: vec![0x30, 0x82, 0x01, 0x0a, 0x02, 0x82, 0x01, 0x01],
+                capabilities: Default::default(),
+                protocol_version: PROTOCOL_VERSION,
+            }),
+        ),
+        (
+            "handshake_node_capabilities",
+            WireMsg::Handshake(Handshake::Node {
+                cert_der: vec![0x30, 0x82, 0x01, 0x0a, 0x02, 0x82, 0x01, 0x01],
+                capabilities: Capabilities {
+                    flags: 0x0102,
+                    role: "archive".to_string(),
+                },
+                protocol_version: PROTOCOL_VERSION,
+            }),
+        ),
+        (
+            "handshake_client",
+            WireMsg::Handshake(Handshake::Client {
+                capabilities: Default::default(),
+                protocol_version: PROTOCOL_VERSION,
+            }),
+        ),
+        ("endpoint_echo_req", WireMsg::EndpointEchoReq),
+        (
+            "endpoint_echo_resp_v4",
+            WireMsg::EndpointEchoResp(([192, 168, 1, 20], 5483).into()),
+        ),
+        (
+            "endpoint_echo_resp_v6",
+            WireMsg::EndpointEchoResp(SocketAddr::new(
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+                443,
+            )),
+        ),
+        ("user_msg_empty", WireMsg::UserMsg(Default::default())),
+        (
+            "user_msg_small",
+            WireMsg::UserMsg(From::from(&b"hello quic-p2p"[..])),
+        ),
+        // Big enough for bincode and MessagePack to send it raw
+        (
+            "user_msg_large",
+            WireMsg::UserMsg(From::from(
+                (0..1100).map(|i| (i % 251) as u8).collect::<Vec<_>>(),
+            )),
+        ),
+        (
+            "connect_back_req",
+            WireMsg::ConnectBackReq(NodeInfo {
+                peer_addr: ([203, 0, 113, 7], 5000).into(),
+                peer_cert_der: vec![0x30, 0x82, 0x01, 0x0a, 0x02, 0x82, 0x01, 0x01],
+                alt_addrs: Vec::new(),
+            }),
+        ),
+        (
+            "forward_req",
+            WireMsg::ForwardReq {
+                to: ([198, 51, 100, 2], 40123).into(),
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+        (
+            "forwarded_msg",
+            WireMsg::ForwardedMsg {
+                from: ([198, 51, 100, 3], 40124).into(),
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+        (
+            "reachability_req",
+            WireMsg::ReachabilityReq(NodeInfo {
+                peer_addr: ([203, 0, 113, 7], 5000).into(),
+                peer_cert_der: vec![0x30, 0x82, 0x01, 0x0a, 0x02, 0x82, 0x01, 0x01],
+                alt_addrs: Vec::new(),
+            }),
+        ),
+        (
+            "reachability_resp",
+            WireMsg::ReachabilityResp {
+                addr: ([203, 0, 113, 7], 5000).into(),
+                reachable: true,
+            },
+        ),
+        (
+            "identified_user_msg",
+            WireMsg::IdentifiedUserMsg {
+                id: 0x0102_0304_0506_0708,
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+        (
+            "subscription",
+            WireMsg::Subscription {
+                topic: "chunks".to_string(),
+                subscribed: true,
+            },
+        ),
+        (
+            "topic_msg",
+            WireMsg::TopicMsg {
+                topic: "chunks".to_string(),
+                id: 0x0102_0304_0506_0708,
+                hops_left: 6,
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+        (
+            "gossip",
+            WireMsg::Gossip {
+                id: 0x0102_0304_0506_0708,
+                ttl: 4,
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+        (
+            "gossip_digest",
+            WireMsg::GossipDigest {
+                ids: vec![1, 0x0102_0304_0506_0708],
+                reply: false,
+            },
+        ),
+        (
+            "acked_user_msg",
+            WireMsg::AckedUserMsg {
+                id: 0x0102_0304_0506_0708,
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+        (
+            "user_msg_ack",
+            WireMsg::UserMsgAck {
+                id: 0x0102_0304_0506_0708,
+            },
+        ),
+        (
+            "ping",
+            WireMsg::Ping {
+                nonce: 0x0102_0304_0506_0708,
+            },
+        ),
+        (
+            "pong",
+            WireMsg::Pong {
+                nonce: 0x0102_0304_0506_0708,
+            },
+        ),
+        (
+            "channel_msg",
+            WireMsg::ChannelMsg {
+                channel: 0x0102,
+                msg: From::from(&b"hello quic-p2p"[..]),
+            },
+        ),
+    ]
+}
+
+/// Vectors of all the samples in all the encodings we support.
+pub fn generate() -> Vec<TestVector> {
+    let mut vectors = Vec::new();
+    for &encoding in ENCODINGS {
+        for (msg, wire_msg) in samples() {
+            vectors.push(TestVector {
+                encoding,
+                msg: msg.to_string(),
+                raw: wire_msg.encode(encoding).to_vec(),
+            });
+        }
+    }
+    vectors
+}
+
+/// Check that each vector decodes and that re-encoding the decoded message as well as encoding
+/// the sample it is named after yield the very same bytes. Returns a description of every failed
+/// vector.
+pub fn verify(vectors: &[TestVector]) -> Result<(), Vec<String>> {
+    let samples = samples();
+    let mut failures = Vec::new();
+
+    for v in vectors {
+        let sample = match samples.iter().find(|(name, _)| *name == v.msg) {
+            Some((_, sample)) => sample,
+            None => {
+                failures.push(format!("{} {}: unknown message", name(v.encoding), v.msg));
+                continue;
+            }
+        };
+        let expected = clone(sample).encode(v.encoding);
+        if v.raw[..] != expected[..] {
+            failures.push(format!(
+                "{} {}: expected {}, got {}",
+                name(v.encoding),
+                v.msg,
+                to_hex(&expected),
+                to_hex(&v.raw)
+            ));
+            continue;
+        }
+        match WireMsg::decode(v.raw.clone(), v.encoding) {
+            Ok(decoded) => {
+                if decoded.encode(v.encoding)[..] != v.raw[..] {
+                    failures.push(format!(
+                        "{} {}: re-encoding differs",
+                        name(v.encoding),
+                        v.msg
+                    ));
+                }
+            }
+            Err(e) => failures.push(format!("{} {}: {}", name(v.encoding), v.msg, e)),
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Write the vectors in the fixture format.
+pub fn to_fixture(vectors: &[TestVector]) -> String {
+    let mut fixture = String::from(
+        "# quic-p2p wire test vectors: <encoding> <message> <hex>\n\
+         # Regenerate with `cargo run --all-features --example wire_test_vectors`.\n",
+    );
+    for v in vectors {
+        unwrap!(writeln!(
+            fixture,
+            "{} {} {}",
+            name(v.encoding),
+            v.msg,
+            to_hex(&v.raw)
+        ));
+    }
+    fixture
+}
+
+/// Parse vectors in the fixture format. Vectors of encodings not enabled in this build are
+/// skipped.
+pub fn from_fixture(fixture: &str) -> Result<Vec<TestVector>, String> {
+    let mut vectors = Vec::new();
+    for (i, line) in fixture.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |reason: &str| format!("line {}: {}", i + 1, reason);
+
+        let mut parts = line.split(' ');
+        let (encoding, msg, hex) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(encoding), Some(msg), Some(hex), None) => (encoding, msg, hex),
+            _ => return Err(err("expected 3 fields")),
+        };
+        let encoding = match ENCODINGS.iter().find(|&&e| name(e) == encoding) {
+            Some(&encoding) => encoding,
+            None if KNOWN_ENCODINGS.contains(&encoding) => continue,
+            None => return Err(err("unknown encoding")),
+        };
+
+        vectors.push(TestVector {
+            encoding,
+            msg: msg.to_string(),
+            raw: from_hex(hex).ok_or_else(|| err("invalid hex"))?,
+        });
+    }
+    Ok(vectors)
+}
+
+const ENCODINGS: &[WireEncoding] = &[
+    WireEncoding::Tagged,
+    WireEncoding::Bincode,
+    WireEncoding::MessagePack,
+    WireEncoding::Cbor,
+    #[cfg(feature = "proto")]
+    WireEncoding::Protobuf,
+];
+
+/// Names of all encodings, including the ones behind features.
+const KNOWN_ENCODINGS: &[&str] = &["tagged", "bincode", "msgpack", "cbor", "protobuf"];
+
+fn name(encoding: WireEncoding) -> &'static str {
+    match encoding {
+        WireEncoding::Tagged => "tagged",
+        WireEncoding::Bincode => "bincode",
+        WireEncoding::MessagePack => "msgpack",
+        WireEncoding::Cbor => "cbor",
+        #[cfg(feature = "proto")]
+        WireEncoding::Protobuf => "protobuf",
+    }
+}
+
+// `WireMsg` isn't `Clone` as copying user messages around is costly elsewhere
+fn clone(wire_msg: &WireMsg) -> WireMsg {
+    match *wire_msg {
+        WireMsg::Handshake(ref handshake) => WireMsg::Handshake(handshake.clone()),
+        WireMsg::EndpointEchoReq => WireMsg::EndpointEchoReq,
+        WireMsg::EndpointEchoResp(addr) => WireMsg::EndpointEchoResp(addr),
+        WireMsg::UserMsg(ref m) => WireMsg::UserMsg(m.clone()),
+        WireMsg::ConnectBackReq(ref node_info) => WireMsg::ConnectBackReq(node_info.clone()),
+        WireMsg::ForwardReq { to, ref msg } => WireMsg::ForwardReq {
+            to,
+            msg: msg.clone(),
+        },
+        WireMsg::ForwardedMsg { from, ref msg } => WireMsg::ForwardedMsg {
+            from,
+            msg: msg.clone(),
+        },
+        WireMsg::ReachabilityReq(ref node_info) => WireMsg::ReachabilityReq(node_info.clone()),
+        WireMsg::ReachabilityResp { addr, reachable } => {
+            WireMsg::ReachabilityResp { addr, reachable }
+        }
+        WireMsg::IdentifiedUserMsg { id, ref msg } => WireMsg::IdentifiedUserMsg {
+            id,
+            msg: msg.clone(),
+        },
+        WireMsg::Subscription {
+            ref topic,
+            subscribed,
+        } => WireMsg::Subscription {
+            topic: topic.clone(),
+            subscribed,
+        },
+        WireMsg::TopicMsg {
+            ref topic,
+            id,
+            hops_left,
+            ref msg,
+        } => WireMsg::TopicMsg {
+            topic: topic.clone(),
+            id,
+            hops_left,
+            msg: msg.clone(),
+        },
+        WireMsg::Gossip { id, ttl, ref msg } => WireMsg::Gossip {
+            id,
+            ttl,
+            msg: msg.clone(),
+        },
+        WireMsg::GossipDigest { ref ids, reply } => WireMsg::GossipDigest {
+            ids: ids.clone(),
+            reply,
+        },
+        WireMsg::AckedUserMsg { id, ref msg } => WireMsg::AckedUserMsg {
+            id,
+            msg: msg.clone(),
+        },
+        WireMsg::UserMsgAck { id } => WireMsg::UserMsgAck { id },
+        WireMsg::Ping { nonce } => WireMsg::Ping { nonce },
+        WireMsg::Pong { nonce } => WireMsg::Pong { nonce },
+        WireMsg::ChannelMsg { channel, ref msg } => WireMsg::ChannelMsg {
+            channel,
+            msg: msg.clone(),
+        },
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings_match_the_fixtures() {
+        let fixture = include_str!("../tests/fixtures/wire_test_vectors.txt");
+        let vectors = unwrap!(from_fixture(fixture));
+
+        if let Err(failures) = verify(&vectors) {
+            panic!("Wire format changed:\n{}", failures.join("\n"));
+        }
+        // Every sample in every encoding of this build is covered
+        assert_eq!(vectors, generate());
+    }
+
+    #[test]
+    fn fixtures_round_trip() {
+        let vectors = generate();
+        assert_eq!(unwrap!(from_fixture(&to_fixture(&vectors))), vectors);
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! The transports our connections run over.
+//!
+//! `connect`, `listener` and `communicate` only deal with the `Transport` of the `Context`, so
+//! that another transport can be slotted in without touching them. QUIC via quinn is the default,
+//! see `QuicTransport`. `TcpFallback` and `WebSocketBridge` wrap it to make and accept
+//! connections over TCP and WebSocket as well.
+//!
+//! The `Context` shares its transport, so that transports may use the `Context` themselves: they
+//! are never called from within `ctx` or `ctx_mut`.
+
+pub use self::quic::QuicTransport;
+#[cfg(feature = "tcp-fallback")]
+pub use self::tcp::TcpFallback;
+#[cfg(feature = "websocket")]
+pub use self::websocket::WebSocketBridge;
+
+use crate::{Channel, Error, R};
+#[cfg(test)]
+use std::any::Any;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use tokio::io::AsyncWrite;
+use tokio::prelude::{future, Future, Stream};
+
+pub(crate) mod quic;
+#[cfg(feature = "tcp-fallback")]
+pub(crate) mod tcp;
+#[cfg(feature = "websocket")]
+pub(crate) mod websocket;
+
+/// Connection attempt to a peer, resolving once the handshake is done.
+pub type Connecting = Box<dyn Future<Item = NewConn, Error = Error>>;
+/// Connections peers make to us.
+pub type Incoming = Box<dyn Stream<Item = NewConn, Error = Error>>;
+/// Future driving a connection. It fails when the connection is lost.
+pub type Driver = Box<dyn Future<Item = (), Error = Error>>;
+/// Messages received on a connection, each in its encoded form.
+pub type IncomingMsgs = Box<dyn Stream<Item = Vec<u8>, Error = Error>>;
+
+/// Something we can make and accept connections over.
+pub trait Transport {
+    /// Address we are bound to.
+    fn local_addr(&self) -> R<SocketAddr>;
+
+    /// Addresses we accept connections on, starting with `local_addr`.
+    #[cfg_attr(feature = "client-only", allow(dead_code))]
+    fn listen_addrs(&self) -> R<Vec<SocketAddr>> {
+        Ok(vec![self.local_addr()?])
+    }
+
+    /// Whether we are able to reach the given peer at all. Connecting to peers we can't reach
+    /// fails with `Error::UnreachableAddressFamily` before anything is attempted.
+    fn can_dial(&self, peer_addr: SocketAddr) -> bool;
+
+    /// Start connecting to the node at the given address, authenticating it by its certificate.
+    fn connect(&self, peer_addr: SocketAddr, peer_cert_der: &[u8]) -> R<Connecting>;
+
+    /// Take the connections peers make to us. Returns `None` if they have been taken before.
+    #[cfg_attr(feature = "client-only", allow(dead_code))]
+    fn incoming(&self) -> Option<Incoming>;
+
+    /// Move over to a fresh socket on a port the OS picks, bound to `ip` if we are bound to a
+    /// specific IP and to the IP we are bound to now otherwise. Returns the address we are bound
+    /// to from then on. Connections we made carry on from the new socket where the peer lets
+    /// them, see `migration`.
+    ///
+    /// Transports which can't move fail with `Error::OperationNotAllowed`.
+    fn rebind(&self, _ip: Option<IpAddr>) -> R<SocketAddr> {
+        Err(Error::OperationNotAllowed)
+    }
+
+    /// The quinn endpoint of our main socket, see `QuicP2p::with_quinn_endpoint`.
+    ///
+    /// Transports other than QUIC have none.
+    #[cfg(feature = "unstable-quinn")]
+    fn quinn_endpoint(&self) -> Option<&quinn::Endpoint> {
+        None
+    }
+}
+
+/// A connection with a peer made by a `Transport`.
+pub trait TransportConn {
+    /// Address of the peer.
+    #[cfg_attr(feature = "client-only", allow(dead_code))]
+    fn remote_address(&self) -> SocketAddr;
+
+    /// Application protocol agreed on with the peer during the handshake, if any.
+    fn protocol(&self) -> Option<Box<[u8]>>;
+
+    /// Send one encoded message, resolving once it has been handed over entirely.
+    fn send(&self, raw: bytes::Bytes) -> Box<dyn Future<Item = (), Error = Error>>;
+
+    /// Send one encoded message on a stream kept open for all the messages pushed to the peer on
+    /// the channel, which receives them in the order they were pushed in. Messages on other
+    /// channels don't wait for them. Resolves once the message has been written to the stream.
+    ///
+    /// Transports without such streams send the message like any other.
+    fn push(
+        &self,
+        _channel: Channel,
+        raw: bytes::Bytes,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.send(raw)
+    }
+
+    /// Open a stream of its own to write raw bytes to, e.g. the contents of a file, which the peer
+    /// reads as they arrive rather than as one message. Shutting the stream down finishes it.
+    ///
+    /// Transports without such streams fail with `Error::OperationNotAllowed`.
+    fn open_stream(&self) -> Box<dyn Future<Item = Box<dyn AsyncWrite>, Error = Error>> {
+        Box::new(future::err(Error::OperationNotAllowed))
+    }
+
+    /// When the stream blocked writing the longest got blocked, if any is, see
+    /// `Builder::with_stall_detection`.
+    ///
+    /// Transports whose writes never block report none.
+    fn stalled_since(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Close the connection. Messages still being sent are lost.
+    fn close(&self);
+
+    /// Close the connection telling the peer why, with an application error code and reason
+    /// it sees in `CloseReason::PeerClosed`.
+    ///
+    /// Transports without close codes close as `close` does.
+    fn close_with(&self, _error_code: u16, _reason: &[u8]) {
+        self.close()
+    }
+
+    /// The quinn connection underneath, see `QuicP2p::with_quinn_connection`.
+    ///
+    /// Transports other than QUIC have none.
+    #[cfg(feature = "unstable-quinn")]
+    fn quinn_connection(&self) -> Option<&quinn::Connection> {
+        None
+    }
+
+    /// Access to the concrete connection, for tests which need to misbehave.
+    #[cfg(test)]
+    #[cfg_attr(feature = "client-only", allow(dead_code))]
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// An established connection.
+pub struct NewConn {
+    pub conn: Box<dyn TransportConn>,
+    /// Has to be spawned for the connection to make progress.
+    pub driver: Driver,
+    pub incoming_msgs: IncomingMsgs,
+}
+
+/// What quinn reports when the peer closed the connection with the given application error code
+/// and reason, for transports without quinn to report the same.
+#[cfg(any(feature = "tcp-fallback", feature = "websocket"))]
+fn peer_closed(error_code: u16, reason: bytes::Bytes) -> Error {
+    Error::Connection(quinn::ConnectionError::ApplicationClosed {
+        reason: quinn_proto::ApplicationClose { error_code, reason },
+    })
+}
@@ -0,0 +1,605 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+use super::{Connecting, Incoming, NewConn, Transport, TransportConn};
+use crate::context::ctx;
+use crate::error::Error;
+use crate::file_transfer::{self, FILE_STREAM_MAGIC};
+use crate::peer_config;
+use crate::sockets::{self, Sockets};
+use crate::socks5::{Shim, Socks5Relay};
+use crate::stall::Stalls;
+use crate::utils;
+use crate::wire_msg::WireEncoding;
+use crate::{Channel, R};
+#[cfg(test)]
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::iter;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Instant;
+use tokio::io::AsyncWrite;
+use tokio::prelude::future::{self, Either, Loop};
+use tokio::prelude::{stream, Future, Stream};
+use tokio::reactor::Handle;
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc;
+
+/// Number of streams of a connection read at the same time.
+const MAX_CONCURRENT_READS: usize = 32;
+
+/// First bytes of a push stream, see `QuicConn::push`. None of the encodings offering push
+/// streams starts a message with `0xff`.
+const PUSH_STREAM_MAGIC: &[u8] = b"\xffqp2p-push";
+
+/// A message to push and where to report the outcome of writing it.
+type Push = (bytes::Bytes, mpsc::UnboundedSender<R<()>>);
+
+/// QUIC via quinn. Every message travels on a unidirectional stream of its own, except for the
+/// messages pushed to a peer, which share one.
+pub struct QuicTransport {
+    pub(crate) ep: quinn::Endpoint,
+    /// Whether `ep` is on a dual-stack IPv6 socket, reaching IPv4 peers via IPv4-mapped
+    /// addresses.
+    dual_stack: bool,
+    /// Endpoint for IPv4 peers when listening on `::` without a dual-stack socket.
+    pub(crate) ep_v4: Option<quinn::Endpoint>,
+    /// Endpoints on `Config::additional_listen_addrs`.
+    pub(crate) additional_eps: Vec<quinn::Endpoint>,
+    /// Index into `additional_eps` of the endpoint each peer connected to us on, if it was one of
+    /// them, for us to connect back from the address the peer knows us by.
+    accepted_on: Rc<RefCell<HashMap<SocketAddr, usize>>>,
+    /// Relay for our outgoing connections if we connect through a SOCKS5 proxy.
+    pub(crate) socks5: Option<Socks5Relay>,
+    #[cfg_attr(feature = "client-only", allow(dead_code))]
+    incoming: RefCell<Option<Incoming>>,
+}
+
+impl QuicTransport {
+    /// Run endpoints made by `ep_builder` on the sockets, the main ones and those of any additional
+    /// listeners. Must be called from within the event loop, which drives the endpoints from then
+    /// on.
+    pub fn new<F>(
+        sockets: Sockets,
+        additional: Vec<Sockets>,
+        ep_builder: F,
+        socks5_proxy: Option<SocketAddr>,
+    ) -> R<Self>
+    where
+        F: Fn() -> quinn::EndpointBuilder<'static>,
+    {
+        let (dr, ep, incoming) = ep_builder().with_socket(sockets.main)?;
+        current_thread::spawn(dr.map_err(|e| warn!("Error in quinn Driver: {:?}", e)));
+        let mut incoming: Incoming = Box::new(accept(incoming));
+
+        let ep_v4 = match sockets.v4 {
+            Some(udp) => {
+                let (dr_v4, ep_v4, incoming_v4) = ep_builder().with_socket(udp)?;
+                current_thread::spawn(
+                    dr_v4.map_err(|e| warn!("Error in quinn Driver (IPv4): {:?}", e)),
+                );
+                incoming = Box::new(incoming.select(accept(incoming_v4)));
+                Some(ep_v4)
+            }
+            None => None,
+        };
+
+        let mut additional_eps = Vec::new();
+        let accepted_on = Rc::new(RefCell::new(HashMap::new()));
+        for sockets in additional {
+            for udp in iter::once(sockets.main).chain(sockets.v4) {
+                let (dr, ep, ep_incoming) = ep_builder().with_socket(udp)?;
+                current_thread::spawn(
+                    dr.map_err(|e| warn!("Error in quinn Driver (additional listener): {:?}", e)),
+                );
+                let ep_index = additional_eps.len();
+                let accepted_on = Rc::clone(&accepted_on);
+                incoming =
+                    Box::new(incoming.select(accept(ep_incoming).map(move |new_conn| {
+                        remember_accepted_on(&accepted_on, new_conn, ep_index)
+                    })));
+                additional_eps.push(ep);
+            }
+        }
+
+        let socks5 = match socks5_proxy {
+            Some(proxy_addr) => Some(Socks5Relay::new(proxy_addr, ep.local_addr()?)),
+            None => None,
+        };
+
+        Ok(Self {
+            ep,
+            dual_stack: sockets.dual_stack,
+            ep_v4,
+            additional_eps,
+            accepted_on,
+            socks5,
+            incoming: RefCell::new(Some(incoming)),
+        })
+    }
+
+    /// Address to dial the given peer at from an endpoint bound to `from`.
+    fn dial_addr(peer_addr: SocketAddr, from: SocketAddr) -> SocketAddr {
+        match peer_addr.ip() {
+            // Only dual-stack sockets are IPv6 ones we may dial IPv4 peers from
+            IpAddr::V4(ip) if from.is_ipv6() => {
+                SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), peer_addr.port())
+            }
+            _ => peer_addr,
+        }
+    }
+
+    /// Endpoint to connect to the given peer from: the additional listener the peer connected to
+    /// us on if any, else an additional listener bound to the IP the OS routes our packets to the
+    /// peer from, unless the main endpoint is bound to that IP as well.
+    pub(crate) fn dialing_ep(&self, peer_addr: SocketAddr) -> &quinn::Endpoint {
+        // The shims are bound next to the main endpoint
+        if self.socks5.is_some() {
+            return &self.ep;
+        }
+        if let Some(&ep_index) = self.accepted_on.borrow().get(&peer_addr) {
+            return &self.additional_eps[ep_index];
+        }
+        if !self.additional_eps.is_empty() {
+            if let Some(ep) = self.additional_ep_routing_to(peer_addr) {
+                return ep;
+            }
+        }
+        match self.ep_v4 {
+            Some(ref ep_v4) if peer_addr.is_ipv4() => ep_v4,
+            _ => &self.ep,
+        }
+    }
+
+    fn additional_ep_routing_to(&self, peer_addr: SocketAddr) -> Option<&quinn::Endpoint> {
+        let ip = match utils::route_ip(peer_addr) {
+            Ok(ip) => ip,
+            Err(e) => {
+                trace!("Could not find the route to {}: {}", peer_addr, e);
+                return None;
+            }
+        };
+        let bound_to = |ep: &quinn::Endpoint| ep.local_addr().map(|addr| addr.ip()).ok();
+        match bound_to(&self.ep) {
+            Some(main_ip) if main_ip == ip || main_ip.is_unspecified() => return None,
+            _ => (),
+        }
+        self.additional_eps
+            .iter()
+            .find(|ep| bound_to(ep) == Some(ip))
+    }
+
+    /// Endpoint to connect to the given peer from.
+    #[cfg(not(test))]
+    fn ep_for(&self, peer_addr: SocketAddr) -> &quinn::Endpoint {
+        self.dialing_ep(peer_addr)
+    }
+}
+
+impl Transport for QuicTransport {
+    fn local_addr(&self) -> R<SocketAddr> {
+        Ok(self.ep.local_addr()?)
+    }
+
+    /// Sending to an address of the other family takes down the endpoint, so this must be checked
+    /// before connecting.
+    fn can_dial(&self, peer_addr: SocketAddr) -> bool {
+        if self.socks5.is_some() {
+            // The proxy reaches the peer for us
+            return true;
+        }
+        let is_ipv6_ep = self.ep.local_addr().map(|a| a.is_ipv6()).unwrap_or(false);
+        if peer_addr.is_ipv4() {
+            !is_ipv6_ep || self.dual_stack || self.ep_v4.is_some()
+        } else {
+            is_ipv6_ep
+        }
+    }
+
+    fn connect(&self, peer_addr: SocketAddr, peer_cert_der: &[u8]) -> R<Connecting> {
+        let peer_cfg = peer_config::new_client_cfg(peer_cert_der)?;
+
+        let ep = self.ep_for(peer_addr);
+        // Through a SOCKS5 proxy we dial the shim of the peer, which the connection keeps open
+        let (dial_addr, shim) = match self.socks5 {
+            Some(ref socks5) => {
+                let shim = socks5.shim(peer_addr)?;
+                (shim.addr(), Some(shim))
+            }
+            None => (Self::dial_addr(peer_addr, ep.local_addr()?), None),
+        };
+        let connecting = ep
+            .connect_with(peer_cfg, &dial_addr, "MaidSAFE.net")?
+            .map_err(Error::from)
+            .map(move |(conn_driver, q_conn, incoming_streams)| {
+                new_conn(conn_driver, q_conn, incoming_streams, shim)
+            });
+
+        match self.socks5 {
+            Some(ref socks5) => Ok(Box::new(socks5.associated().and_then(move |()| connecting))),
+            None => Ok(Box::new(connecting)),
+        }
+    }
+
+    #[cfg(feature = "unstable-quinn")]
+    fn quinn_endpoint(&self) -> Option<&quinn::Endpoint> {
+        Some(&self.ep)
+    }
+
+    fn listen_addrs(&self) -> R<Vec<SocketAddr>> {
+        let mut addrs = vec![self.ep.local_addr()?];
+        for ep in &self.additional_eps {
+            addrs.push(ep.local_addr()?);
+        }
+        Ok(addrs)
+    }
+
+    fn incoming(&self) -> Option<Incoming> {
+        self.incoming.borrow_mut().take()
+    }
+
+    /// quinn keeps the connections of the endpoints, so only the sockets are swapped. Not
+    /// possible when relaying through a SOCKS5 proxy, which knows us by our old address.
+    fn rebind(&self, ip: Option<IpAddr>) -> R<SocketAddr> {
+        if self.socks5.is_some() {
+            return Err(Error::OperationNotAllowed);
+        }
+        let current = self.ep.local_addr()?;
+        let ip = match ip {
+            Some(ip) if !current.ip().is_unspecified() => ip,
+            _ => current.ip(),
+        };
+
+        let sockets = sockets::bind(ip, 0)?;
+        let reactor = Handle::default();
+        if let (Some(ref ep_v4), Some(udp)) = (&self.ep_v4, sockets.v4) {
+            ep_v4.rebind(udp, &reactor)?;
+        }
+        self.ep.rebind(sockets.main, &reactor)?;
+
+        Ok(self.ep.local_addr()?)
+    }
+}
+
+/// A QUIC connection, with the writers of the push streams of the channels messages were pushed
+/// on, the streams blocked writing and the SOCKS5 shim the connection is relayed through, if any.
+pub struct QuicConn(
+    pub(crate) quinn::Connection,
+    RefCell<HashMap<Channel, mpsc::UnboundedSender<Push>>>,
+    Rc<Stalls>,
+    Option<Rc<Shim>>,
+);
+
+impl TransportConn for QuicConn {
+    fn remote_address(&self) -> SocketAddr {
+        self.0.remote_address()
+    }
+
+    fn protocol(&self) -> Option<Box<[u8]>> {
+        self.0.protocol()
+    }
+
+    fn send(&self, raw: bytes::Bytes) -> Box<dyn Future<Item = (), Error = Error>> {
+        let stalls = self.2.clone();
+        let leaf = self
+            .0
+            .open_uni()
+            .map_err(Error::from)
+            .and_then(move |o_stream| {
+                tokio::io::write_all(stalls.watch(o_stream), raw).map_err(Error::from)
+            })
+            .and_then(|(o_stream, _)| tokio::io::shutdown(o_stream).map_err(Error::from))
+            .map(|_| ());
+
+        Box::new(leaf)
+    }
+
+    /// The push stream of a channel is opened along with the first message pushed on it:
+    /// `PUSH_STREAM_MAGIC` followed by the messages, each prefixed with its length as a big endian
+    /// `u32`. Should the stream fail, the next message is pushed on a new one. The channel is
+    /// carried by the messages themselves, so the peer reads all push streams alike.
+    fn push(
+        &self,
+        channel: Channel,
+        raw: bytes::Bytes,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let (done_tx, done_rx) = mpsc::unbounded_channel();
+        let pushed = self
+            .1
+            .borrow_mut()
+            .entry(channel)
+            .or_insert_with(|| spawn_pusher(self.0.clone(), self.2.clone()))
+            .try_send((raw, done_tx));
+        if pushed.is_err() {
+            return Box::new(future::err(Error::ConnectionCancelled));
+        }
+
+        let leaf = done_rx
+            .into_future()
+            .map_err(|_| Error::ConnectionCancelled)
+            .and_then(|(r, _)| r.unwrap_or(Err(Error::ConnectionCancelled)));
+
+        Box::new(leaf)
+    }
+
+    fn open_stream(&self) -> Box<dyn Future<Item = Box<dyn AsyncWrite>, Error = Error>> {
+        let stalls = self.2.clone();
+        let leaf = self
+            .0
+            .open_uni()
+            .map_err(Error::from)
+            .map(move |o_stream| -> Box<dyn AsyncWrite> { Box::new(stalls.watch(o_stream)) });
+
+        Box::new(leaf)
+    }
+
+    fn stalled_since(&self) -> Option<Instant> {
+        self.2.since()
+    }
+
+    fn close(&self) {
+        self.0.close(0, &[]);
+    }
+
+    fn close_with(&self, error_code: u16, reason: &[u8]) {
+        self.0.close(error_code, reason);
+    }
+
+    #[cfg(feature = "unstable-quinn")]
+    fn quinn_connection(&self) -> Option<&quinn::Connection> {
+        Some(&self.0)
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Write the messages pushed to the connection, in order, see `QuicConn::push`.
+fn spawn_pusher(conn: quinn::Connection, stalls: Rc<Stalls>) -> mpsc::UnboundedSender<Push> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let leaf = rx
+        .map_err(|e| debug!("Error in push channel: {:?}", e))
+        .fold(None, move |o_stream, (raw, mut done_tx): Push| {
+            let o_stream = match o_stream {
+                Some(o_stream) => Either::A(future::ok(o_stream)),
+                None => {
+                    let stalls = stalls.clone();
+                    Either::B(
+                        conn.open_uni()
+                            .map_err(Error::from)
+                            .and_then(move |o_stream| {
+                                tokio::io::write_all(stalls.watch(o_stream), PUSH_STREAM_MAGIC)
+                                    .map_err(Error::from)
+                            })
+                            .map(|(o_stream, _)| o_stream),
+                    )
+                }
+            };
+            let mut frame = Vec::with_capacity(4 + raw.len());
+            frame.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&raw);
+
+            o_stream
+                .and_then(|o_stream| tokio::io::write_all(o_stream, frame).map_err(Error::from))
+                .then(move |r| {
+                    let (o_stream, r) = match r {
+                        Ok((o_stream, _)) => (Some(o_stream), Ok(())),
+                        Err(e) => (None, Err(e)),
+                    };
+                    let _ = done_tx.try_send(r);
+                    Ok(o_stream)
+                })
+        })
+        .map(|_| ());
+
+    current_thread::spawn(leaf);
+    tx
+}
+
+/// Note down which additional endpoint the peer connected to us on for as long as the connection
+/// lasts, see `QuicTransport::accepted_on`.
+fn remember_accepted_on(
+    accepted_on: &Rc<RefCell<HashMap<SocketAddr, usize>>>,
+    new_conn: NewConn,
+    ep_index: usize,
+) -> NewConn {
+    let peer_addr = utils::normalise_addr(new_conn.conn.remote_address());
+    let _ = accepted_on.borrow_mut().insert(peer_addr, ep_index);
+
+    let accepted_on = Rc::clone(accepted_on);
+    let driver = new_conn.driver.then(move |r| {
+        let _ = accepted_on.borrow_mut().remove(&peer_addr);
+        r
+    });
+    NewConn {
+        driver: Box::new(driver),
+        ..new_conn
+    }
+}
+
+fn accept(incoming: quinn::Incoming) -> impl Stream<Item = NewConn, Error = Error> {
+    incoming.map_err(|()| Error::ConnectionCancelled).map(
+        |(conn_driver, q_conn, incoming_streams)| {
+            new_conn(conn_driver, q_conn, incoming_streams, None)
+        },
+    )
+}
+
+fn new_conn(
+    conn_driver: quinn::ConnectionDriver,
+    q_conn: quinn::Connection,
+    incoming_streams: quinn::IncomingStreams,
+    shim: Option<Rc<Shim>>,
+) -> NewConn {
+    let peer_addr = utils::normalise_addr(q_conn.remote_address());
+    let max_msg_size = ctx(|c| c.max_msg_size_allowed);
+    let encoding = WireEncoding::from_alpn_protocol(q_conn.protocol().as_deref());
+
+    let (push_tx, push_rx) = mpsc::unbounded_channel();
+    let msgs = incoming_streams
+        .map_err(Error::from)
+        .map(move |quic_stream| match quic_stream {
+            quinn::NewStream::Bi(_, _) => {
+                Either::A(future::err(Error::BiDirectionalStreamAttempted(peer_addr)))
+            }
+            quinn::NewStream::Uni(i_stream) => Either::B(read_stream(
+                peer_addr,
+                i_stream,
+                max_msg_size,
+                encoding,
+                push_tx.clone(),
+            )),
+        })
+        .buffer_unordered(MAX_CONCURRENT_READS)
+        .filter_map(|raw| raw)
+        .map(Some)
+        // Marks the end of the connection, which the push streams don't outlive
+        .chain(stream::once(Ok(None)));
+    let pushed_msgs = push_rx
+        .map_err(|_| Error::ConnectionCancelled)
+        .and_then(|r| r)
+        .map(Some);
+    let incoming_msgs = msgs
+        .select(pushed_msgs)
+        .take_while(|raw| Ok(raw.is_some()))
+        .filter_map(|raw| raw);
+
+    NewConn {
+        conn: Box::new(QuicConn(
+            q_conn,
+            Default::default(),
+            Default::default(),
+            shim,
+        )),
+        driver: Box::new(conn_driver.map_err(Error::from)),
+        incoming_msgs: Box::new(incoming_msgs),
+    }
+}
+
+/// Read the message a stream of the peer carries. Push streams are read on their own instead,
+/// into `push_tx`, and file streams are held for the application, both resolving to `None` right
+/// away.
+fn read_stream(
+    peer_addr: SocketAddr,
+    i_stream: quinn::RecvStream,
+    max_msg_size: usize,
+    encoding: WireEncoding,
+    push_tx: mpsc::UnboundedSender<R<Vec<u8>>>,
+) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = Error>> {
+    if !encoding.offers_push_streams() {
+        let leaf = i_stream
+            .read_to_end(max_msg_size)
+            .map(|(_i_stream, raw)| Some(raw))
+            .map_err(Error::from);
+        return Box::new(leaf);
+    }
+
+    let leaf = read_prefix(i_stream, PUSH_STREAM_MAGIC.len()).and_then(
+        move |(i_stream, prefix, is_finished)| {
+            if prefix == PUSH_STREAM_MAGIC {
+                current_thread::spawn(read_pushed_msgs(i_stream, max_msg_size, push_tx));
+                return Either::A(future::ok(None));
+            }
+            if prefix == FILE_STREAM_MAGIC {
+                current_thread::spawn(file_transfer::accept(peer_addr, i_stream));
+                return Either::A(future::ok(None));
+            }
+            if is_finished {
+                return Either::A(future::ok(Some(prefix)));
+            }
+            // Reads past the prefix land at their offsets in the stream
+            Either::B(
+                i_stream
+                    .read_to_end(max_msg_size)
+                    .map(move |(_i_stream, mut raw)| {
+                        if raw.len() < prefix.len() {
+                            return Some(prefix);
+                        }
+                        raw[..prefix.len()].copy_from_slice(&prefix);
+                        Some(raw)
+                    })
+                    .map_err(Error::from),
+            )
+        },
+    );
+
+    Box::new(leaf)
+}
+
+/// Read up to `len` bytes off the start of the stream, and whether the stream finished already.
+fn read_prefix(
+    i_stream: quinn::RecvStream,
+    len: usize,
+) -> impl Future<Item = (quinn::RecvStream, Vec<u8>, bool), Error = Error> {
+    future::loop_fn(
+        (i_stream, Vec::with_capacity(len)),
+        move |(i_stream, mut prefix)| {
+            let buf = vec![0; len - prefix.len()];
+            tokio::io::read(i_stream, buf)
+                .map_err(Error::from)
+                .map(move |(i_stream, buf, n)| {
+                    prefix.extend_from_slice(&buf[..n]);
+                    if n == 0 {
+                        Loop::Break((i_stream, prefix, true))
+                    } else if prefix.len() == len {
+                        Loop::Break((i_stream, prefix, false))
+                    } else {
+                        Loop::Continue((i_stream, prefix))
+                    }
+                })
+        },
+    )
+}
+
+/// Read the messages of a push stream past its magic until the stream finishes.
+fn read_pushed_msgs(
+    i_stream: quinn::RecvStream,
+    max_msg_size: usize,
+    push_tx: mpsc::UnboundedSender<R<Vec<u8>>>,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn((i_stream, push_tx), move |(i_stream, mut push_tx)| {
+        read_prefix(i_stream, 4)
+            .and_then(move |(i_stream, len, is_finished)| {
+                if is_finished && len.is_empty() {
+                    return Either::A(future::ok(None));
+                }
+                if is_finished {
+                    let e = io::Error::from(io::ErrorKind::UnexpectedEof);
+                    return Either::A(future::err(Error::from(e)));
+                }
+                let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+                if len > max_msg_size {
+                    // As `read_to_end` fails for messages over the limit
+                    return Either::A(future::err(Error::Read(quinn::ReadError::Finished)));
+                }
+                Either::B(
+                    tokio::io::read_exact(i_stream, vec![0; len])
+                        .map(|(i_stream, raw)| Some((i_stream, raw)))
+                        .map_err(Error::from),
+                )
+            })
+            .then(move |r| match r {
+                Ok(Some((i_stream, raw))) => match push_tx.try_send(Ok(raw)) {
+                    Ok(()) => Ok(Loop::Continue((i_stream, push_tx))),
+                    // The connection is gone
+                    Err(_) => Ok(Loop::Break(())),
+                },
+                Ok(None) => Ok(Loop::Break(())),
+                Err(e) => {
+                    let _ = push_tx.try_send(Err(e));
+                    Ok(Loop::Break(()))
+                }
+            })
+    })
+}
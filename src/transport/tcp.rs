@@ -0,0 +1,336 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Fallback to TLS over TCP for networks which drop UDP.
+//!
+//! Nodes accept TCP connections on the port of their QUIC endpoint. A client whose QUIC
+//! connection attempt to a node fails tries TCP to the same address before giving up. Each wire
+//! message travels in its own frame, prefixed by its length as a big endian `u32`, the way it
+//! would travel in its own QUIC stream. A single TCP connection carries the messages both ways,
+//! just like the QUIC connection of a client does. Nodes need connections both ways to each other
+//! so they don't fall back. Client-only builds leave the accepting side out.
+
+use super::{peer_closed, Connecting, Incoming, NewConn, Transport, TransportConn};
+use crate::config::OurType;
+#[cfg(not(feature = "client-only"))]
+use crate::config::SerialisableCertificate;
+use crate::context::ctx;
+use crate::error::Error;
+#[cfg(not(feature = "client-only"))]
+use crate::utils;
+use crate::wire_msg::WireEncoding;
+use crate::R;
+use bytes::Bytes;
+use rustls::Session;
+#[cfg(test)]
+use std::any::Any;
+use std::cell::RefCell;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::codec::length_delimited;
+#[cfg(not(feature = "client-only"))]
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::prelude::future::{self, Either};
+use tokio::prelude::{AsyncRead, AsyncWrite, Future, FutureExt, Sink, Stream};
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc;
+use tokio::timer::timeout;
+use tokio_rustls::webpki::DNSNameRef;
+#[cfg(not(feature = "client-only"))]
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::TlsConnector;
+
+/// Time to give the TCP and TLS handshakes to complete.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Another transport, normally `QuicTransport`, with TLS over TCP to fall back to.
+pub struct TcpFallback {
+    inner: Rc<dyn Transport>,
+}
+
+impl TcpFallback {
+    /// Fall back to TCP should connecting over `inner` fail.
+    pub fn new(inner: Rc<dyn Transport>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Transport for TcpFallback {
+    fn local_addr(&self) -> R<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn listen_addrs(&self) -> R<Vec<SocketAddr>> {
+        self.inner.listen_addrs()
+    }
+
+    fn can_dial(&self, peer_addr: SocketAddr) -> bool {
+        self.inner.can_dial(peer_addr)
+    }
+
+    /// Clients try TCP once the connection attempt of the inner transport failed. Should TCP fail
+    /// as well, the attempt fails with the error of the inner transport.
+    fn connect(&self, peer_addr: SocketAddr, peer_cert_der: &[u8]) -> R<Connecting> {
+        let peer_cert_der = peer_cert_der.to_vec();
+        let connecting = self
+            .inner
+            .connect(peer_addr, &peer_cert_der)?
+            .or_else(move |e| {
+                if ctx(|c| c.our_type) != OurType::Client {
+                    return Either::A(future::err(e));
+                }
+                debug!(
+                    "QUIC connection to {} failed ({}), falling back to TCP",
+                    peer_addr, e
+                );
+                Either::B(dial(peer_addr, &peer_cert_der).map_err(move |tcp_err| {
+                    debug!("TCP fallback to {} failed as well: {}", peer_addr, tcp_err);
+                    e
+                }))
+            });
+
+        Ok(Box::new(connecting))
+    }
+
+    /// Accepts TCP connections on the address of the inner transport along with its own.
+    fn incoming(&self) -> Option<Incoming> {
+        let incoming = self.inner.incoming()?;
+        #[cfg(not(feature = "client-only"))]
+        {
+            let our_addr = match self.inner.local_addr() {
+                Ok(our_addr) => our_addr,
+                Err(e) => {
+                    warn!("Could not accept TCP connections: {}", e);
+                    return Some(incoming);
+                }
+            };
+            match accept(our_addr) {
+                Ok(tcp_incoming) => return Some(Box::new(incoming.select(tcp_incoming))),
+                Err(e) => warn!("Could not accept TCP connections on {}: {}", our_addr, e),
+            }
+        }
+        Some(incoming)
+    }
+
+    /// Connections over TCP stay on the socket they were made on.
+    fn rebind(&self, ip: Option<IpAddr>) -> R<SocketAddr> {
+        self.inner.rebind(ip)
+    }
+
+    #[cfg(feature = "unstable-quinn")]
+    fn quinn_endpoint(&self) -> Option<&quinn::Endpoint> {
+        self.inner.quinn_endpoint()
+    }
+}
+
+/// A TLS over TCP connection to or from a peer.
+pub struct TcpConn {
+    peer_addr: SocketAddr,
+    protocol: Option<Vec<u8>>,
+    /// Frames to write to the peer. Dropped when we close the connection.
+    tx: RefCell<Option<mpsc::UnboundedSender<Bytes>>>,
+    /// Dropped when we close the connection, which ends the driver.
+    close_tx: RefCell<Option<mpsc::UnboundedSender<()>>>,
+}
+
+impl TransportConn for TcpConn {
+    fn remote_address(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn protocol(&self) -> Option<Box<[u8]>> {
+        self.protocol.clone().map(Vec::into_boxed_slice)
+    }
+
+    /// Resolves once the message is queued to be written, as TCP keeps the order of all messages
+    /// anyway.
+    fn send(&self, raw: Bytes) -> Box<dyn Future<Item = (), Error = Error>> {
+        let is_queued = match *self.tx.borrow_mut() {
+            Some(ref mut tx) => tx.try_send(raw).is_ok(),
+            None => false,
+        };
+        if !is_queued {
+            debug!("TCP connection to peer {} is gone", self.peer_addr);
+            return Box::new(future::err(Error::ConnectionCancelled));
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn close(&self) {
+        let _ = self.tx.borrow_mut().take();
+        let _ = self.close_tx.borrow_mut().take();
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Connect to the node at the given address over TLS over TCP.
+fn dial(peer_addr: SocketAddr, peer_cert_der: &[u8]) -> Connecting {
+    let peer_cfg = match peer_cfg(peer_cert_der) {
+        Ok(cfg) => cfg,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let server_name = unwrap!(DNSNameRef::try_from_ascii_str("MaidSAFE.net")).to_owned();
+
+    let connecting = TcpStream::connect(&peer_addr)
+        .and_then(move |stream| {
+            TlsConnector::from(Arc::new(peer_cfg)).connect(server_name.as_ref(), stream)
+        })
+        .timeout(HANDSHAKE_TIMEOUT)
+        .map_err(handshake_err)
+        .map(move |stream| {
+            let protocol = stream.get_ref().1.get_alpn_protocol().map(|p| p.to_vec());
+            new_conn(peer_addr, stream, protocol)
+        });
+
+    Box::new(connecting)
+}
+
+/// Accept TCP connections at the given address, normally the one of our QUIC endpoint.
+#[cfg(not(feature = "client-only"))]
+fn accept(addr: SocketAddr) -> R<Incoming> {
+    let listener = TcpListener::bind(&addr)?;
+    let acceptor = TlsAcceptor::from(Arc::new(ctx(|c| {
+        our_cfg(&c.our_complete_cert, c.wire_encoding)
+    })?));
+    let (conns_tx, conns_rx) = mpsc::unbounded_channel();
+
+    let leaf = listener
+        .incoming()
+        .map_err(|e| warn!("ERROR: TCP listener errored out: {}", e))
+        .for_each(move |stream| {
+            let peer_addr = match stream.peer_addr() {
+                Ok(peer_addr) => utils::normalise_addr(peer_addr),
+                Err(e) => {
+                    debug!("Dropping TCP connection of unknown origin: {}", e);
+                    return Ok(());
+                }
+            };
+            let mut conns_tx = conns_tx.clone();
+            let leaf = acceptor
+                .accept(stream)
+                .timeout(HANDSHAKE_TIMEOUT)
+                .then(move |r| {
+                    match r {
+                        Ok(stream) => {
+                            let protocol =
+                                stream.get_ref().1.get_alpn_protocol().map(|p| p.to_vec());
+                            let _ = conns_tx.try_send(new_conn(peer_addr, stream, protocol));
+                        }
+                        Err(e) => debug!("TLS handshake with {} failed: {:?}", peer_addr, e),
+                    }
+                    Ok(())
+                });
+            current_thread::spawn(leaf);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+
+    Ok(Box::new(conns_rx.map_err(|_| Error::ConnectionCancelled)))
+}
+
+/// Run the writes of the connection. Its driver runs the reads, ending with an error when the peer
+/// closes the connection.
+fn new_conn<S>(peer_addr: SocketAddr, stream: S, protocol: Option<Vec<u8>>) -> NewConn
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let max_msg_size = ctx(|c| c.max_msg_size_allowed);
+    let (frames_tx, frames) = length_delimited::Builder::new()
+        .max_frame_length(max_msg_size)
+        .new_framed(stream)
+        .split();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (msgs_tx, msgs_rx) = mpsc::unbounded_channel();
+    let (close_tx, close_rx) = mpsc::unbounded_channel::<()>();
+
+    let writer = frames_tx
+        .send_all(rx.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe)))
+        .then(move |r| {
+            if let Err(e) = r {
+                debug!("Error writing to peer {} over TCP: {}", peer_addr, e);
+            }
+            Ok(())
+        });
+    current_thread::spawn(writer);
+
+    let reader = frames
+        .map_err(Error::from)
+        .fold(msgs_tx, |mut msgs_tx, frame| {
+            let _ = msgs_tx.try_send(frame.to_vec());
+            Ok::<_, Error>(msgs_tx)
+        })
+        .and_then(|_| Err::<(), _>(peer_closed(0, Bytes::new())));
+    // Closing the connection ourselves ends the driver quietly, like quinn's
+    let driver = reader.select2(close_rx.into_future()).then(|r| match r {
+        Err(Either::A((e, _))) => Err(e),
+        _ => Ok(()),
+    });
+
+    NewConn {
+        conn: Box::new(TcpConn {
+            peer_addr,
+            protocol,
+            tx: RefCell::new(Some(tx)),
+            close_tx: RefCell::new(Some(close_tx)),
+        }),
+        driver: Box::new(driver),
+        incoming_msgs: Box::new(msgs_rx.map_err(|_| Error::ConnectionCancelled)),
+    }
+}
+
+fn handshake_err(e: timeout::Error<io::Error>) -> Error {
+    e.into_inner().map(Error::from).unwrap_or_else(|| {
+        Error::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "TCP and TLS handshakes timed out after {:?}",
+                HANDSHAKE_TIMEOUT
+            ),
+        ))
+    })
+}
+
+fn alpn_protocols(preferred: WireEncoding) -> Vec<Vec<u8>> {
+    WireEncoding::alpn_protocols(preferred)
+        .into_iter()
+        .map(|p| p.to_vec())
+        .collect()
+}
+
+fn peer_cfg(peer_cert_der: &[u8]) -> R<rustls::ClientConfig> {
+    let mut cfg = rustls::ClientConfig::new();
+    cfg.root_store
+        .add(&rustls::Certificate(peer_cert_der.to_vec()))
+        .map_err(rustls::TLSError::WebPKIError)?;
+    cfg.set_protocols(&alpn_protocols(ctx(|c| c.wire_encoding)));
+    Ok(cfg)
+}
+
+#[cfg(not(feature = "client-only"))]
+fn our_cfg(
+    our_complete_cert: &SerialisableCertificate,
+    wire_encoding: WireEncoding,
+) -> R<rustls::ServerConfig> {
+    let mut cfg = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    cfg.set_single_cert(
+        vec![rustls::Certificate(our_complete_cert.cert_der.clone())],
+        rustls::PrivateKey(our_complete_cert.key_der.clone()),
+    )?;
+    cfg.set_protocols(&alpn_protocols(wire_encoding));
+    Ok(cfg)
+}
@@ -0,0 +1,316 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Bridge for browser clients, which can't speak QUIC to us.
+//!
+//! Nodes accept WebSocket connections on `Config::websocket_port` and take them like QUIC
+//! connections of clients. Browsers don't take part in our protocol, so the bridge speaks it for
+//! them: it introduces the browser as a client once the WebSocket handshake completes, turns every
+//! binary (or text) WebSocket message into a user message, acknowledges the user messages written
+//! to the browser and carries our probes as WebSocket pings. User messages sent to the browser go
+//! out as binary WebSocket messages, the other wire messages have no counterpart for browsers and
+//! are dropped. The close code and reason of the browser are reported like those of QUIC peers.
+//!
+//! The WebSocket is plain `ws://`. Pages served over HTTPS may only open `wss://` connections, for
+//! which a reverse proxy terminating TLS has to be put in front of the port.
+
+use super::{peer_closed, Connecting, Incoming, NewConn, Transport, TransportConn};
+use crate::context::ctx;
+use crate::error::Error;
+use crate::utils;
+use crate::wire_msg::{Handshake, WireEncoding, WireMsg, PROTOCOL_VERSION};
+use crate::R;
+use bytes::Bytes;
+#[cfg(test)]
+use std::any::Any;
+use std::cell::RefCell;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::future::{self, Either, Loop};
+use tokio::prelude::{Future, FutureExt, Sink, Stream};
+use tokio::runtime::current_thread;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::WebSocketStream;
+
+/// Time to give the WebSocket handshake to complete.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Another transport, normally `QuicTransport`, accepting WebSocket connections of browser clients
+/// as well.
+pub struct WebSocketBridge {
+    inner: Rc<dyn Transport>,
+    addr: SocketAddr,
+}
+
+impl WebSocketBridge {
+    /// Accept WebSocket connections at the given address.
+    pub fn new(inner: Rc<dyn Transport>, addr: SocketAddr) -> Self {
+        Self { inner, addr }
+    }
+}
+
+impl Transport for WebSocketBridge {
+    fn local_addr(&self) -> R<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn listen_addrs(&self) -> R<Vec<SocketAddr>> {
+        self.inner.listen_addrs()
+    }
+
+    fn can_dial(&self, peer_addr: SocketAddr) -> bool {
+        self.inner.can_dial(peer_addr)
+    }
+
+    /// Browsers are never dialed, so this is left to the inner transport.
+    fn connect(&self, peer_addr: SocketAddr, peer_cert_der: &[u8]) -> R<Connecting> {
+        self.inner.connect(peer_addr, peer_cert_der)
+    }
+
+    fn incoming(&self) -> Option<Incoming> {
+        let incoming = self.inner.incoming()?;
+        match accept(self.addr) {
+            Ok(ws_incoming) => Some(Box::new(incoming.select(ws_incoming))),
+            Err(e) => {
+                warn!(
+                    "Could not accept WebSocket connections on {}: {}",
+                    self.addr, e
+                );
+                Some(incoming)
+            }
+        }
+    }
+
+    fn rebind(&self, ip: Option<IpAddr>) -> R<SocketAddr> {
+        self.inner.rebind(ip)
+    }
+
+    #[cfg(feature = "unstable-quinn")]
+    fn quinn_endpoint(&self) -> Option<&quinn::Endpoint> {
+        self.inner.quinn_endpoint()
+    }
+}
+
+/// A WebSocket connection from a browser client.
+pub struct WsConn {
+    peer_addr: SocketAddr,
+    /// WebSocket messages to write to the browser. Dropped when we close the connection.
+    tx: RefCell<Option<mpsc::UnboundedSender<Message>>>,
+    /// Wire messages we answer ourselves on behalf of the browser.
+    answers_tx: RefCell<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    /// Dropped when we close the connection, which ends the driver.
+    close_tx: RefCell<Option<mpsc::UnboundedSender<()>>>,
+}
+
+impl TransportConn for WsConn {
+    fn remote_address(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn protocol(&self) -> Option<Box<[u8]>> {
+        None
+    }
+
+    /// Queue the message to be written to the browser, if it means anything to browsers.
+    fn send(&self, raw: Bytes) -> Box<dyn Future<Item = (), Error = Error>> {
+        let wire_msg = match WireMsg::decode(raw.to_vec(), encoding()) {
+            Ok(wire_msg) => wire_msg,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (msg, answer) = match wire_msg {
+            WireMsg::UserMsg(msg) | WireMsg::IdentifiedUserMsg { msg, .. } => {
+                (Message::Binary(msg.to_vec()), None)
+            }
+            // Taken as delivered once written
+            WireMsg::AckedUserMsg { id, msg } => (
+                Message::Binary(msg.to_vec()),
+                Some(WireMsg::UserMsgAck { id }),
+            ),
+            // Browsers answer pings by themselves
+            WireMsg::Ping { nonce } => (Message::Ping(nonce.to_be_bytes().to_vec()), None),
+            // Browsers know of no channels but the default one
+            WireMsg::ChannelMsg { .. } => {
+                debug!(
+                    "Can't send on a channel to browser client {}",
+                    self.peer_addr
+                );
+                return Box::new(future::err(Error::OperationNotAllowed));
+            }
+            wire_msg => {
+                trace!(
+                    "Not sending {:?} to browser client {}",
+                    wire_msg,
+                    self.peer_addr
+                );
+                return Box::new(future::ok(()));
+            }
+        };
+
+        let is_queued = match *self.tx.borrow_mut() {
+            Some(ref mut tx) => tx.try_send(msg).is_ok(),
+            None => false,
+        };
+        if !is_queued {
+            debug!("WebSocket connection to peer {} is gone", self.peer_addr);
+            return Box::new(future::err(Error::ConnectionCancelled));
+        }
+        if let Some(answer) = answer {
+            if let Some(ref mut answers_tx) = *self.answers_tx.borrow_mut() {
+                let _ = answers_tx.try_send(answer.encode(encoding()).to_vec());
+            }
+        }
+        Box::new(future::ok(()))
+    }
+
+    fn close(&self) {
+        let _ = self.tx.borrow_mut().take();
+        let _ = self.answers_tx.borrow_mut().take();
+        let _ = self.close_tx.borrow_mut().take();
+    }
+
+    #[cfg(test)]
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Accept WebSocket connections at the given address.
+fn accept(addr: SocketAddr) -> R<Incoming> {
+    let listener = TcpListener::bind(&addr)?;
+    let max_msg_size = ctx(|c| c.max_msg_size_allowed);
+    let (conns_tx, conns_rx) = mpsc::unbounded_channel();
+
+    let leaf = listener
+        .incoming()
+        .map_err(|e| warn!("ERROR: WebSocket listener errored out: {}", e))
+        .for_each(move |stream| {
+            let peer_addr = match stream.peer_addr() {
+                Ok(peer_addr) => utils::normalise_addr(peer_addr),
+                Err(e) => {
+                    debug!("Dropping WebSocket connection of unknown origin: {}", e);
+                    return Ok(());
+                }
+            };
+            let ws_cfg = WebSocketConfig {
+                max_send_queue: None,
+                max_message_size: Some(max_msg_size),
+                max_frame_size: Some(max_msg_size),
+            };
+            let mut conns_tx = conns_tx.clone();
+            let leaf = tokio_tungstenite::accept_async_with_config(stream, Some(ws_cfg))
+                .timeout(HANDSHAKE_TIMEOUT)
+                .then(move |r| {
+                    match r {
+                        Ok(ws) => {
+                            let _ = conns_tx.try_send(new_conn(peer_addr, ws));
+                        }
+                        Err(e) => debug!("WebSocket handshake with {} failed: {:?}", peer_addr, e),
+                    }
+                    Ok(())
+                });
+            current_thread::spawn(leaf);
+            Ok(())
+        });
+
+    current_thread::spawn(leaf);
+
+    Ok(Box::new(conns_rx.map_err(|_| Error::ConnectionCancelled)))
+}
+
+/// Run the writes of the connection. Its driver runs the reads, ending with an error when the
+/// browser closes the connection.
+fn new_conn(peer_addr: SocketAddr, ws: WebSocketStream<TcpStream>) -> NewConn {
+    let (ws_tx, ws_rx) = ws.split();
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (mut msgs_tx, msgs_rx) = mpsc::unbounded_channel();
+    let (close_tx, close_rx) = mpsc::unbounded_channel::<()>();
+
+    // Ending the stream closes the WebSocket
+    let writer = ws_tx
+        .send_all(rx.map_err(|_| WsError::AlreadyClosed))
+        .then(move |r| {
+            if let Err(e) = r {
+                debug!("Error writing to peer {} over WebSocket: {}", peer_addr, e);
+            }
+            Ok(())
+        });
+    current_thread::spawn(writer);
+
+    // Browser clients don't take part in our handshake
+    let handshake = WireMsg::Handshake(Handshake::Client {
+        capabilities: Default::default(),
+        protocol_version: PROTOCOL_VERSION,
+    });
+    let _ = msgs_tx.try_send(handshake.encode(encoding()).to_vec());
+    let answers_tx = msgs_tx.clone();
+
+    // The browser is done once it sent its close frame, so stop reading then
+    let reader = future::loop_fn((ws_rx, msgs_tx), |(ws_rx, mut msgs_tx)| {
+        ws_rx
+            .into_future()
+            .then(move |r| -> Result<Loop<(), _>, Error> {
+                let (msg, ws_rx) = match r {
+                    Ok((Some(msg), ws_rx)) => (msg, ws_rx),
+                    Ok((None, _)) | Err((WsError::ConnectionClosed, _)) => {
+                        return Err(peer_closed(0, Bytes::new()))
+                    }
+                    Err((e, _)) => return Err(Error::WebSocket(e.to_string())),
+                };
+                let wire_msg = match msg {
+                    Message::Binary(msg) => WireMsg::UserMsg(From::from(msg)),
+                    Message::Text(msg) => WireMsg::UserMsg(From::from(msg.into_bytes())),
+                    Message::Pong(ref nonce) if nonce.len() == 8 => {
+                        let mut buf = [0; 8];
+                        buf.copy_from_slice(nonce);
+                        WireMsg::Pong {
+                            nonce: u64::from_be_bytes(buf),
+                        }
+                    }
+                    Message::Ping(_) | Message::Pong(_) => {
+                        return Ok(Loop::Continue((ws_rx, msgs_tx)))
+                    }
+                    Message::Close(Some(frame)) => {
+                        return Err(peer_closed(
+                            frame.code.into(),
+                            From::from(frame.reason.into_owned()),
+                        ))
+                    }
+                    Message::Close(None) => return Err(peer_closed(0, Bytes::new())),
+                };
+                let _ = msgs_tx.try_send(wire_msg.encode(encoding()).to_vec());
+                Ok(Loop::Continue((ws_rx, msgs_tx)))
+            })
+    });
+    // Closing the connection ourselves ends the driver quietly, like quinn's
+    let driver = reader.select2(close_rx.into_future()).then(|r| match r {
+        Err(Either::A((e, _))) => Err(e),
+        _ => Ok(()),
+    });
+
+    NewConn {
+        conn: Box::new(WsConn {
+            peer_addr,
+            tx: RefCell::new(Some(tx)),
+            answers_tx: RefCell::new(Some(answers_tx)),
+            close_tx: RefCell::new(Some(close_tx)),
+        }),
+        driver: Box::new(driver),
+        incoming_msgs: Box::new(msgs_rx.map_err(|_| Error::ConnectionCancelled)),
+    }
+}
+
+/// Encoding of the wire messages of browser clients, the one taken for connections without an
+/// application protocol.
+fn encoding() -> WireEncoding {
+    WireEncoding::from_alpn_protocol(None)
+}
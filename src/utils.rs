@@ -10,13 +10,12 @@
 use crate::ctx_mut;
 use crate::dirs::Dirs;
 use crate::error::Error;
-use crate::event::Event;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
-use std::net::SocketAddr;
-use std::path::Path;
+use crate::outcome;
+use crate::reconnect;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use tokio::prelude::{future, Future};
+use tokio::runtime::current_thread::TaskExecutor;
 
 /// Result used by `QuicP2p`.
 pub type R<T> = Result<T, Error>;
@@ -28,6 +27,21 @@ pub fn connect_terminator() -> (ConnectTerminator, tokio::sync::mpsc::Receiver<(
     tokio::sync::mpsc::channel(1)
 }
 
+/// Run `f` on the event loop once the context is released, e.g. when called from `Drop`.
+pub fn later<F: FnOnce() + 'static>(f: F) {
+    spawn(future::lazy(move || {
+        f();
+        Ok(())
+    }));
+}
+
+/// Unlike `current_thread::spawn`, connections dropping as the event loop shuts down don't panic.
+pub fn spawn<F: Future<Item = (), Error = ()> + 'static>(leaf: F) {
+    if let Err(e) = TaskExecutor::current().spawn_local(Box::new(leaf)) {
+        debug!("Not spawning as the event loop is gone: {:?}", e);
+    }
+}
+
 /// Get the project directory
 #[cfg(any(
     all(
@@ -59,6 +73,32 @@ pub fn project_dir() -> R<Dirs> {
     ))
 }
 
+/// Turn IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) into plain IPv4 ones, so that a peer is
+/// known by the same address whichever family of socket it reaches us on.
+#[inline]
+pub fn normalise_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Local IP the OS would send packets to the given peer from.
+pub fn route_ip(peer_addr: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified = if peer_addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    // Connecting a UDP socket sends nothing, it only makes the OS pick the route
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect(peer_addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
 /// Convert binary data to a diplay-able format
 #[inline]
 pub fn bin_data_format(data: &[u8]) -> String {
@@ -93,35 +133,17 @@ pub fn handle_communication_err(
         peer_addr, e, e, details
     );
     ctx_mut(|c| {
-        let _ = c.connections.remove(&peer_addr);
+        if let Some(mut conn) = c.connections.remove(&peer_addr) {
+            conn.ended_by(e);
+            if let Some(ref bootstrap_group_ref) = conn.bootstrap_group_ref {
+                bootstrap_group_ref.record_failure(e);
+            }
+            if !conn.is_complete() {
+                reconnect::dial_failed(c, peer_addr);
+            }
+        }
         if let Some(m) = unsent_user_msg {
-            let _ = c
-                .event_tx
-                .send(Event::UnsentUserMessage { peer_addr, msg: m });
+            outcome::unsent(c, peer_addr, m);
         }
     });
 }
-
-/// Try reading from the disk into the given structure.
-pub fn read_from_disk<D>(file_path: &Path) -> R<D>
-where
-    D: DeserializeOwned,
-{
-    Ok(File::open(file_path)
-        .map_err(Into::into)
-        .map(BufReader::new)
-        .and_then(|mut rdr| bincode::deserialize_from(&mut rdr))?)
-}
-
-/// Try writing the given structure to the disk.
-pub fn write_to_disk<S>(file_path: &Path, s: &S) -> R<()>
-where
-    S: Serialize,
-{
-    File::create(file_path)
-        .map_err(Into::into)
-        .map(BufWriter::new)
-        .and_then(|mut rdr| bincode::serialize_into(&mut rdr, s))?;
-
-    Ok(())
-}
@@ -0,0 +1,434 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! CBOR encoding of wire messages following the schema documented on `WireEncoding::Cbor`.
+//!
+//! The schema is written out by hand rather than derived from `WireMsg` so that it doesn't change
+//! by accident when the Rust types do.
+
+use crate::error::Error;
+use crate::wire_msg::{Handshake, WireMsg, UNADVERTISED_PROTOCOL_VERSION};
+use crate::{Capabilities, NodeInfo, R};
+use ciborium::value::Value;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const HANDSHAKE_NODE: u64 = 0;
+const HANDSHAKE_CLIENT: u64 = 1;
+const ENDPOINT_ECHO_REQ: u64 = 2;
+const ENDPOINT_ECHO_RESP: u64 = 3;
+const USER_MSG: u64 = 4;
+const CONNECT_BACK_REQ: u64 = 5;
+const FORWARD_REQ: u64 = 6;
+const FORWARDED_MSG: u64 = 7;
+const REACHABILITY_REQ: u64 = 8;
+const REACHABILITY_RESP: u64 = 9;
+const IDENTIFIED_USER_MSG: u64 = 10;
+const SUBSCRIPTION: u64 = 11;
+const TOPIC_MSG: u64 = 12;
+const GOSSIP: u64 = 13;
+const GOSSIP_DIGEST: u64 = 14;
+const ACKED_USER_MSG: u64 = 15;
+const USER_MSG_ACK: u64 = 16;
+const PING: u64 = 17;
+const PONG: u64 = 18;
+const CHANNEL_MSG: u64 = 19;
+
+/// Encode the wire message as a CBOR array.
+pub fn encode(wire_msg: &WireMsg) -> Vec<u8> {
+    let tag = |t: u64| Value::Integer(t.into());
+    let frame = match *wire_msg {
+        WireMsg::Handshake(Handshake::Node {
+            ref cert_der,
+            ref capabilities,
+            protocol_version,
+        }) => {
+            let mut frame = vec![tag(HANDSHAKE_NODE), Value::Bytes(cert_der.clone())];
+            frame.extend(handshake_fields(capabilities, protocol_version));
+            frame
+        }
+        WireMsg::Handshake(Handshake::Client {
+            ref capabilities,
+            protocol_version,
+        }) => {
+            let mut frame = vec![tag(HANDSHAKE_CLIENT)];
+            frame.extend(handshake_fields(capabilities, protocol_version));
+            frame
+        }
+        WireMsg::EndpointEchoReq => vec![tag(ENDPOINT_ECHO_REQ)],
+        WireMsg::EndpointEchoResp(addr) => {
+            let mut frame = vec![tag(ENDPOINT_ECHO_RESP)];
+            frame.extend_from_slice(&addr_fields(addr));
+            frame
+        }
+        WireMsg::UserMsg(ref m) => vec![tag(USER_MSG), Value::Bytes(m.to_vec())],
+        WireMsg::ConnectBackReq(ref node_info) => {
+            let mut frame = vec![tag(CONNECT_BACK_REQ)];
+            frame.extend_from_slice(&addr_fields(node_info.peer_addr));
+            frame.push(Value::Bytes(node_info.peer_cert_der.clone()));
+            frame
+        }
+        WireMsg::ForwardReq { to, ref msg } => {
+            let mut frame = vec![tag(FORWARD_REQ)];
+            frame.extend_from_slice(&addr_fields(to));
+            frame.push(Value::Bytes(msg.to_vec()));
+            frame
+        }
+        WireMsg::ForwardedMsg { from, ref msg } => {
+            let mut frame = vec![tag(FORWARDED_MSG)];
+            frame.extend_from_slice(&addr_fields(from));
+            frame.push(Value::Bytes(msg.to_vec()));
+            frame
+        }
+        WireMsg::ReachabilityReq(ref node_info) => {
+            let mut frame = vec![tag(REACHABILITY_REQ)];
+            frame.extend_from_slice(&addr_fields(node_info.peer_addr));
+            frame.push(Value::Bytes(node_info.peer_cert_der.clone()));
+            frame
+        }
+        WireMsg::ReachabilityResp { addr, reachable } => {
+            let mut frame = vec![tag(REACHABILITY_RESP)];
+            frame.extend_from_slice(&addr_fields(addr));
+            frame.push(Value::Bool(reachable));
+            frame
+        }
+        WireMsg::IdentifiedUserMsg { id, ref msg } => vec![
+            tag(IDENTIFIED_USER_MSG),
+            Value::Integer(id.into()),
+            Value::Bytes(msg.to_vec()),
+        ],
+        WireMsg::Subscription {
+            ref topic,
+            subscribed,
+        } => vec![
+            tag(SUBSCRIPTION),
+            Value::Text(topic.clone()),
+            Value::Bool(subscribed),
+        ],
+        WireMsg::TopicMsg {
+            ref topic,
+            id,
+            hops_left,
+            ref msg,
+        } => vec![
+            tag(TOPIC_MSG),
+            Value::Text(topic.clone()),
+            Value::Integer(id.into()),
+            Value::Integer(hops_left.into()),
+            Value::Bytes(msg.to_vec()),
+        ],
+        WireMsg::Gossip { id, ttl, ref msg } => vec![
+            tag(GOSSIP),
+            Value::Integer(id.into()),
+            Value::Integer(ttl.into()),
+            Value::Bytes(msg.to_vec()),
+        ],
+        WireMsg::GossipDigest { ref ids, reply } => vec![
+            tag(GOSSIP_DIGEST),
+            Value::Array(ids.iter().map(|&id| Value::Integer(id.into())).collect()),
+            Value::Bool(reply),
+        ],
+        WireMsg::AckedUserMsg { id, ref msg } => vec![
+            tag(ACKED_USER_MSG),
+            Value::Integer(id.into()),
+            Value::Bytes(msg.to_vec()),
+        ],
+        WireMsg::UserMsgAck { id } => vec![tag(USER_MSG_ACK), Value::Integer(id.into())],
+        WireMsg::Ping { nonce } => vec![tag(PING), Value::Integer(nonce.into())],
+        WireMsg::Pong { nonce } => vec![tag(PONG), Value::Integer(nonce.into())],
+        WireMsg::ChannelMsg { channel, ref msg } => vec![
+            tag(CHANNEL_MSG),
+            Value::Integer(channel.into()),
+            Value::Bytes(msg.to_vec()),
+        ],
+    };
+
+    let mut raw = Vec::new();
+    unwrap!(ciborium::ser::into_writer(&Value::Array(frame), &mut raw));
+    raw
+}
+
+/// Decode a wire message from a CBOR array.
+pub fn decode(raw: &[u8]) -> R<WireMsg> {
+    let value: Value = ciborium::de::from_reader(raw).map_err(|e| invalid(e.to_string()))?;
+    let mut fields = match value {
+        Value::Array(fields) => fields.into_iter(),
+        _ => return Err(invalid("frame is not an array")),
+    };
+
+    let wire_msg = match uint(fields.next())? {
+        HANDSHAKE_NODE => WireMsg::Handshake(Handshake::Node {
+            cert_der: bytes(fields.next())?,
+            capabilities: capabilities(&mut fields)?,
+            protocol_version: protocol_version(&mut fields)?,
+        }),
+        HANDSHAKE_CLIENT => WireMsg::Handshake(Handshake::Client {
+            capabilities: capabilities(&mut fields)?,
+            protocol_version: protocol_version(&mut fields)?,
+        }),
+        ENDPOINT_ECHO_REQ => WireMsg::EndpointEchoReq,
+        ENDPOINT_ECHO_RESP => WireMsg::EndpointEchoResp(addr(&mut fields)?),
+        USER_MSG => WireMsg::UserMsg(From::from(bytes(fields.next())?)),
+        CONNECT_BACK_REQ => WireMsg::ConnectBackReq(NodeInfo {
+            peer_addr: addr(&mut fields)?,
+            peer_cert_der: bytes(fields.next())?,
+            alt_addrs: Vec::new(),
+        }),
+        FORWARD_REQ => WireMsg::ForwardReq {
+            to: addr(&mut fields)?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        FORWARDED_MSG => WireMsg::ForwardedMsg {
+            from: addr(&mut fields)?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        REACHABILITY_REQ => WireMsg::ReachabilityReq(NodeInfo {
+            peer_addr: addr(&mut fields)?,
+            peer_cert_der: bytes(fields.next())?,
+            alt_addrs: Vec::new(),
+        }),
+        REACHABILITY_RESP => WireMsg::ReachabilityResp {
+            addr: addr(&mut fields)?,
+            reachable: boolean(fields.next())?,
+        },
+        IDENTIFIED_USER_MSG => WireMsg::IdentifiedUserMsg {
+            id: uint(fields.next())?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        SUBSCRIPTION => WireMsg::Subscription {
+            topic: text(fields.next())?,
+            subscribed: boolean(fields.next())?,
+        },
+        TOPIC_MSG => WireMsg::TopicMsg {
+            topic: text(fields.next())?,
+            id: uint(fields.next())?,
+            hops_left: u8::try_from(uint(fields.next())?)
+                .map_err(|_| invalid("hops left out of range"))?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        GOSSIP => WireMsg::Gossip {
+            id: uint(fields.next())?,
+            ttl: u8::try_from(uint(fields.next())?).map_err(|_| invalid("TTL out of range"))?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        GOSSIP_DIGEST => WireMsg::GossipDigest {
+            ids: match fields.next() {
+                Some(Value::Array(ids)) => {
+                    ids.into_iter().map(|id| uint(Some(id))).collect::<R<_>>()?
+                }
+                _ => return Err(invalid("expected an array")),
+            },
+            reply: boolean(fields.next())?,
+        },
+        ACKED_USER_MSG => WireMsg::AckedUserMsg {
+            id: uint(fields.next())?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        USER_MSG_ACK => WireMsg::UserMsgAck {
+            id: uint(fields.next())?,
+        },
+        PING => WireMsg::Ping {
+            nonce: uint(fields.next())?,
+        },
+        PONG => WireMsg::Pong {
+            nonce: uint(fields.next())?,
+        },
+        CHANNEL_MSG => WireMsg::ChannelMsg {
+            channel: u16::try_from(uint(fields.next())?)
+                .map_err(|_| invalid("channel out of range"))?,
+            msg: From::from(bytes(fields.next())?),
+        },
+        t => return Err(invalid(format!("unknown message type {}", t))),
+    };
+
+    if fields.next().is_some() {
+        return Err(invalid("unexpected trailing fields"));
+    }
+
+    Ok(wire_msg)
+}
+
+/// The IP address as a byte string followed by the port.
+fn addr_fields(addr: SocketAddr) -> [Value; 2] {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    [Value::Bytes(ip), Value::Integer(addr.port().into())]
+}
+
+fn addr<I: Iterator<Item = Value>>(fields: &mut I) -> R<SocketAddr> {
+    let ip = bytes(fields.next())?;
+    let ip = if let Ok(octets) = <[u8; 4]>::try_from(&ip[..]) {
+        IpAddr::V4(Ipv4Addr::from(octets))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(&ip[..]) {
+        IpAddr::V6(Ipv6Addr::from(octets))
+    } else {
+        return Err(invalid("IP address is neither 4 nor 16 bytes long"));
+    };
+    let port = u16::try_from(uint(fields.next())?).map_err(|_| invalid("port out of range"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// The flags followed by the role, unless there are no capabilities.
+/// The flags and role, followed by the protocol version unless it's the one of peers not
+/// advertising any. Left out altogether if there is neither.
+fn handshake_fields(capabilities: &Capabilities, protocol_version: u16) -> Vec<Value> {
+    let advertises_version = protocol_version != UNADVERTISED_PROTOCOL_VERSION;
+    if capabilities.is_empty() && !advertises_version {
+        return Vec::new();
+    }
+    let mut fields = vec![
+        Value::Integer(capabilities.flags.into()),
+        Value::Text(capabilities.role.clone()),
+    ];
+    if advertises_version {
+        fields.push(Value::Integer(protocol_version.into()));
+    }
+    fields
+}
+
+/// Capabilities written by `handshake_fields`, if any.
+fn capabilities<I: Iterator<Item = Value>>(fields: &mut I) -> R<Capabilities> {
+    let flags = match fields.next() {
+        Some(flags) => uint(Some(flags))?,
+        None => return Ok(Default::default()),
+    };
+    let role = match fields.next() {
+        Some(Value::Text(role)) => role,
+        _ => return Err(invalid("expected a text string")),
+    };
+    Ok(Capabilities { flags, role })
+}
+
+/// Protocol version written by `handshake_fields`.
+fn protocol_version<I: Iterator<Item = Value>>(fields: &mut I) -> R<u16> {
+    match fields.next() {
+        Some(version) => {
+            u16::try_from(uint(Some(version))?).map_err(|_| invalid("protocol version too big"))
+        }
+        None => Ok(UNADVERTISED_PROTOCOL_VERSION),
+    }
+}
+
+fn uint(field: Option<Value>) -> R<u64> {
+    match field {
+        Some(Value::Integer(i)) => u64::try_from(i).map_err(|_| invalid("negative integer")),
+        _ => Err(invalid("expected an unsigned integer")),
+    }
+}
+
+fn bytes(field: Option<Value>) -> R<Vec<u8>> {
+    match field {
+        Some(Value::Bytes(b)) => Ok(b),
+        _ => Err(invalid("expected a byte string")),
+    }
+}
+
+fn text(field: Option<Value>) -> R<String> {
+    match field {
+        Some(Value::Text(t)) => Ok(t),
+        _ => Err(invalid("expected a text string")),
+    }
+}
+
+fn boolean(field: Option<Value>) -> R<bool> {
+    match field {
+        Some(Value::Bool(b)) => Ok(b),
+        _ => Err(invalid("expected a boolean")),
+    }
+}
+
+fn invalid<S: Into<String>>(reason: S) -> Error {
+    Error::Cbor(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_follow_the_schema() {
+        // [3, h'7f000001', 443]
+        let raw = [0x83, 0x03, 0x44, 0x7f, 0x00, 0x00, 0x01, 0x19, 0x01, 0xbb];
+        let addr: SocketAddr = ([127, 0, 0, 1], 443).into();
+
+        assert_eq!(encode(&WireMsg::EndpointEchoResp(addr)), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::EndpointEchoResp(a) => assert_eq!(a, addr),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // [4, h'010203']
+        let raw = [0x82, 0x04, 0x43, 0x01, 0x02, 0x03];
+        let msg = WireMsg::UserMsg(From::from(vec![1, 2, 3]));
+        assert_eq!(encode(&msg), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::UserMsg(m) => assert_eq!(&m[..], &[1, 2, 3]),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // [5, h'0a000001', 5000, h'3082']
+        let raw = [
+            0x84, 0x05, 0x44, 0x0a, 0x00, 0x00, 0x01, 0x19, 0x13, 0x88, 0x42, 0x30, 0x82,
+        ];
+        let node_info = NodeInfo {
+            peer_addr: ([10, 0, 0, 1], 5000).into(),
+            peer_cert_der: vec![0x30, 0x82],
+            alt_addrs: Vec::new(),
+        };
+        assert_eq!(encode(&WireMsg::ConnectBackReq(node_info.clone())), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::ConnectBackReq(n) => assert_eq!(n, node_info),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // [1, 5, "db"]
+        let raw = [0x83, 0x01, 0x05, 0x62, 0x64, 0x62];
+        let capabilities = Capabilities {
+            flags: 5,
+            role: "db".to_string(),
+        };
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: capabilities.clone(),
+            protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(Handshake::Client {
+                capabilities: c, ..
+            }) => assert_eq!(c, capabilities),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // [1, 0, "", 2]
+        let raw = [0x84, 0x01, 0x00, 0x60, 0x02];
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: Default::default(),
+            protocol_version: 2,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(h) => assert_eq!(h.protocol_version(), 2),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        // Not an array
+        assert!(decode(&[0x04]).is_err());
+        // Unknown message type
+        assert!(decode(&[0x81, 0x09]).is_err());
+        // User message without payload
+        assert!(decode(&[0x81, 0x04]).is_err());
+        // Echo request with a trailing field
+        assert!(decode(&[0x82, 0x02, 0x00]).is_err());
+    }
+}
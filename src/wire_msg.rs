@@ -7,50 +7,510 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
-use crate::{utils, R};
+use crate::event::{Channel, DEFAULT_CHANNEL};
+use crate::{utils, wire_cbor, wire_tagged, Capabilities, NodeInfo, R};
+use std::cell::Cell;
 use std::fmt;
 use std::net::SocketAddr;
 
 const MAX_MESSAGE_SIZE_FOR_SERIALISATION: usize = 1024; // 1 KiB
 
+/// Version of the wire protocol we speak, advertised in our handshakes. Peers only accept the
+/// versions within their `Config::min_protocol_version` and `Config::max_protocol_version`.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Application close code of connections refused for the protocol version their handshake
+/// advertised, see `Config::min_protocol_version`. The reason bytes give the versions accepted as
+/// `<min>-<max>`, e.g. `1-3`.
+pub const PROTOCOL_VERSION_REFUSED_CODE: u16 = 1;
+
+/// Application close code of connections refused as the subnet of the peer has as many
+/// connections to us as allowed already, see `Config::max_incoming_per_v4_subnet`.
+pub const CONNECTION_LIMIT_CODE: u16 = 2;
+
+/// Version of the wire protocol of peers not advertising one, e.g. older versions of quic-p2p
+/// and those talked to in bincode or MessagePack. Handshakes leave the version out if it's this.
+pub(crate) const UNADVERTISED_PROTOCOL_VERSION: u16 = 1;
+
+thread_local! {
+    static MSG_INSPECTOR: Cell<Option<MsgInspector>> = Cell::new(None);
+}
+
+/// Debug hook invoked for every wire message we encode to send or decode after receiving.
+///
+/// Useful for protocol fuzzers, conformance tests and traffic recorders. It's called from the
+/// event loop so it should return quickly.
+pub type MsgInspector = fn(&Direction, &WireMsg);
+
+/// Direction of a wire message given to the `MsgInspector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The message is about to be sent to this peer.
+    Outgoing(SocketAddr),
+    /// The message was received from this peer.
+    Incoming(SocketAddr),
+}
+
+/// Set the inspector for the current event loop thread.
+pub fn set_msg_inspector(inspector: Option<MsgInspector>) {
+    MSG_INSPECTOR.with(|i| i.set(inspector));
+}
+
+/// Pass the message to the inspector of the current event loop thread, if any.
+pub fn inspect(direction: &Direction, wire_msg: &WireMsg) {
+    if let Some(inspector) = MSG_INSPECTOR.with(Cell::get) {
+        inspector(direction, wire_msg);
+    }
+}
+
+/// Encoding of the wire messages exchanged over a connection.
+///
+/// The encoding is negotiated per connection via ALPN: the connecting peer offers the encodings
+/// it supports and the accepting peer picks the one it prefers. Peers not taking part in the
+/// negotiation, i.e. older versions of quic-p2p, are talked to in bincode. With bincode and
+/// MessagePack, user messages bigger than 1 KiB are sent as the raw bytes given to us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireEncoding {
+    /// Tagged frames which peers running different versions of quic-p2p can exchange, used by
+    /// default. ALPN protocol `qp2p-tagged`.
+    ///
+    /// Every stream carries a single frame: the frame version (currently 1) and the message type
+    /// as a byte each, followed by the fields of the message. Each field is a tag byte, the length
+    /// of the value as a little endian `u32` and the value. Decoders skip fields with tags they
+    /// don't know and ignore frames of message types they don't know, so new messages and fields
+    /// can be added without breaking older peers.
+    ///
+    /// | Message             | Type | Fields                                                 |
+    /// |---------------------|------|--------------------------------------------------------|
+    /// | Node handshake      | 0    | 1: DER encoded certificate, 2: flags (`u64`), 3: role  |
+    /// | Client handshake    | 1    | 2: flags, 3: role (UTF-8)                              |
+    /// | Endpoint echo req.  | 2    |                                                        |
+    /// | Endpoint echo resp. | 3    | 1: IP address (4 or 16 bytes), 2: port (`u16`)         |
+    /// | User message        | 4    | 1: payload                                             |
+    /// | Connect back req.   | 5    | 1: IP address, 2: port, 3: DER encoded cert.           |
+    /// | Forward req.        | 6    | 1: IP address, 2: port, 3: payload                     |
+    /// | Forwarded message   | 7    | 1: IP address, 2: port, 3: payload                     |
+    /// | Reachability req.   | 8    | 1: IP address, 2: port, 3: DER encoded cert.           |
+    /// | Reachability resp.  | 9    | 1: IP address, 2: port, 3: reachable (0 or 1, 1 byte)  |
+    /// | Identified user msg | 10   | 1: ID (`u64`), 2: payload                              |
+    /// | Subscription        | 11   | 1: topic (UTF-8), 2: subscribed (0 or 1, 1 byte)       |
+    /// | Topic message       | 12   | 1: topic, 2: ID, 3: hops left (1 byte), 4: payload     |
+    /// | Gossip              | 13   | 1: ID, 2: TTL (1 byte), 3: payload                     |
+    /// | Gossip digest       | 14   | 1: IDs (8 bytes each), 2: reply (0 or 1, 1 byte)       |
+    /// | Acked user message  | 15   | 1: ID, 2: payload                                      |
+    /// | User message ack.   | 16   | 1: ID                                                  |
+    /// | Ping                | 17   | 1: nonce                                               |
+    /// | Pong                | 18   | 1: nonce                                               |
+    /// | Channel message     | 19   | 1: channel (`u16`), 2: payload                         |
+    ///
+    /// The flags and role of handshakes are only written if the peer advertises capabilities.
+    /// Handshakes carry the protocol version (`u16`) as field 4, only written if it's other than 1.
+    Tagged,
+    /// bincode, used with peers not supporting the negotiation of the encoding.
+    Bincode,
+    /// MessagePack with structs encoded as maps, for interop with peers not written in Rust.
+    MessagePack,
+    /// CBOR following a fixed schema, for conformant implementations in other languages. ALPN
+    /// protocol `qp2p-cbor`.
+    ///
+    /// Every stream carries a single message: a CBOR array whose first element is the message
+    /// type, followed by the fields of that type. User messages are opaque byte strings.
+    ///
+    /// | Message             | Frame                                                    |
+    /// |---------------------|----------------------------------------------------------|
+    /// | Node handshake      | `[0, cert_der: bstr, ? (flags: uint, role: tstr)]`       |
+    /// | Client handshake    | `[1, ? (flags: uint, role: tstr)]`                       |
+    /// | Endpoint echo req.  | `[2]`                                                    |
+    /// | Endpoint echo resp. | `[3, ip: bstr .size (4 / 16), port: uint]`               |
+    /// | User message        | `[4, payload: bstr]`                                     |
+    /// | Connect back req.   | `[5, ip: bstr, port: uint, cert_der: bstr]`              |
+    /// | Forward req.        | `[6, ip: bstr, port: uint, payload: bstr]`               |
+    /// | Forwarded message   | `[7, ip: bstr, port: uint, payload: bstr]`               |
+    /// | Reachability req.   | `[8, ip: bstr, port: uint, cert_der: bstr]`              |
+    /// | Reachability resp.  | `[9, ip: bstr, port: uint, reachable: bool]`             |
+    /// | Identified user msg | `[10, id: uint, payload: bstr]`                          |
+    /// | Subscription        | `[11, topic: tstr, subscribed: bool]`                    |
+    /// | Topic message       | `[12, topic: tstr, id: uint, hops: uint, payload: bstr]` |
+    /// | Gossip              | `[13, id: uint, ttl: uint, payload: bstr]`               |
+    /// | Gossip digest       | `[14, ids: [* uint], reply: bool]`                       |
+    /// | Acked user message  | `[15, id: uint, payload: bstr]`                          |
+    /// | User message ack.   | `[16, id: uint]`                                         |
+    /// | Ping                | `[17, nonce: uint]`                                      |
+    /// | Pong                | `[18, nonce: uint]`                                      |
+    /// | Channel message     | `[19, channel: uint, payload: bstr]`                     |
+    ///
+    /// The flags and role of handshakes are only written if the peer advertises capabilities.
+    /// Handshakes of a protocol version other than 1 follow them with the version as a `uint`,
+    /// writing the flags and role in any case.
+    Cbor,
+    /// Protobuf as defined by `proto/wire_msg.proto`, for conformant implementations in other
+    /// languages. ALPN protocol `qp2p-proto`.
+    #[cfg(feature = "proto")]
+    Protobuf,
+}
+
+impl WireEncoding {
+    const TAGGED_ALPN: &'static [u8] = b"qp2p-tagged";
+    const BINCODE_ALPN: &'static [u8] = b"qp2p-bincode";
+    const MESSAGE_PACK_ALPN: &'static [u8] = b"qp2p-msgpack";
+    const CBOR_ALPN: &'static [u8] = b"qp2p-cbor";
+    #[cfg(feature = "proto")]
+    const PROTOBUF_ALPN: &'static [u8] = b"qp2p-proto";
+    const ALL: &'static [WireEncoding] = &[
+        WireEncoding::Tagged,
+        WireEncoding::Bincode,
+        WireEncoding::MessagePack,
+        WireEncoding::Cbor,
+        #[cfg(feature = "proto")]
+        WireEncoding::Protobuf,
+    ];
+
+    /// ALPN protocols to advertise, most preferred first.
+    pub fn alpn_protocols(preferred: WireEncoding) -> Vec<&'static [u8]> {
+        let mut protocols = vec![preferred.alpn_protocol()];
+        protocols.extend(
+            Self::ALL
+                .iter()
+                .filter(|&&e| e != preferred)
+                .map(|e| e.alpn_protocol()),
+        );
+        protocols
+    }
+
+    /// Encoding corresponding to the ALPN protocol negotiated for a connection.
+    pub fn from_alpn_protocol(protocol: Option<&[u8]>) -> WireEncoding {
+        match protocol {
+            Some(p) if p == Self::TAGGED_ALPN => WireEncoding::Tagged,
+            Some(p) if p == Self::MESSAGE_PACK_ALPN => WireEncoding::MessagePack,
+            Some(p) if p == Self::CBOR_ALPN => WireEncoding::Cbor,
+            #[cfg(feature = "proto")]
+            Some(p) if p == Self::PROTOBUF_ALPN => WireEncoding::Protobuf,
+            _ => WireEncoding::Bincode,
+        }
+    }
+
+    fn sends_big_user_msgs_raw(self) -> bool {
+        match self {
+            WireEncoding::Bincode | WireEncoding::MessagePack => true,
+            _ => false,
+        }
+    }
+
+    /// Whether messages can be pushed on a stream kept open for several of them, see
+    /// `TransportConn::push`. Not with encodings sending big user messages raw, as those could be
+    /// mistaken for the start of a push stream.
+    pub(crate) fn offers_push_streams(self) -> bool {
+        !self.sends_big_user_msgs_raw()
+    }
+
+    fn alpn_protocol(self) -> &'static [u8] {
+        match self {
+            WireEncoding::Tagged => Self::TAGGED_ALPN,
+            WireEncoding::Bincode => Self::BINCODE_ALPN,
+            WireEncoding::MessagePack => Self::MESSAGE_PACK_ALPN,
+            WireEncoding::Cbor => Self::CBOR_ALPN,
+            #[cfg(feature = "proto")]
+            WireEncoding::Protobuf => Self::PROTOBUF_ALPN,
+        }
+    }
+}
+
+impl Default for WireEncoding {
+    fn default() -> Self {
+        WireEncoding::Tagged
+    }
+}
+
 /// Final type serialised and sent on the wire by QuicP2p
 #[derive(Serialize, Deserialize, Debug)]
 pub enum WireMsg {
+    /// Introduction of the peer, sent first thing on a new connection.
     Handshake(Handshake),
+    /// Request asking the peer which address it sees us connecting from.
     EndpointEchoReq,
+    /// Response to `EndpointEchoReq` with the address the peer sees for us.
     EndpointEchoResp(SocketAddr),
+    /// Message of the user.
     UserMsg(bytes::Bytes),
+    /// Request asking the peer to connect to us, as we can't be connected to directly but can
+    /// accept a connection from a peer we contacted ourselves. See `QuicP2p::request_connect_back`.
+    ///
+    /// Peers of older versions ignore it with the tagged encoding and drop the connection with the
+    /// others.
+    ConnectBackReq(NodeInfo),
+    /// Request asking a node to relay a message of the user to another client of it, see
+    /// `QuicP2p::forward`.
+    ForwardReq {
+        /// Address of the receiving client as seen by the node.
+        to: SocketAddr,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
+    /// Message of the user relayed by a node from another client of it.
+    ForwardedMsg {
+        /// Address of the sending client as seen by the node.
+        from: SocketAddr,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
+    /// Request asking the peer to dial us at the given address from a socket we have never heard
+    /// from, see `QuicP2p::check_reachability`.
+    ReachabilityReq(NodeInfo),
+    /// Response to `ReachabilityReq` telling whether the peer could dial us.
+    ReachabilityResp {
+        /// Address the peer dialed.
+        addr: SocketAddr,
+        /// Whether the connection was made.
+        reachable: bool,
+    },
+    /// Message of the user with an ID of the sender's choosing, which the receiver delivers only
+    /// once however often it arrives, see `QuicP2p::send_with_id`.
+    ///
+    /// Peers of older versions ignore it with the tagged encoding and drop the connection with the
+    /// others.
+    IdentifiedUserMsg {
+        /// ID of the message, unique among those the sender sends us.
+        id: u64,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
+    /// Tells the peer whether we are subscribed to a topic, on connecting and whenever it
+    /// changes. See `QuicP2p::subscribe`.
+    ///
+    /// Peers of older versions ignore it with the tagged encoding and drop the connection with the
+    /// others.
+    Subscription {
+        /// The topic.
+        topic: String,
+        /// Whether we subscribed to the topic or unsubscribed from it.
+        subscribed: bool,
+    },
+    /// Message published to a topic the peer is subscribed to, see `QuicP2p::publish`.
+    TopicMsg {
+        /// The topic.
+        topic: String,
+        /// ID the publisher picked for the message, for subscribers to drop the copies of it
+        /// reaching them on other paths.
+        id: u64,
+        /// Number of times the message may still be forwarded to further subscribers.
+        hops_left: u8,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
+    /// Message gossiped to some of the peers we are connected to, see `QuicP2p::gossip`.
+    Gossip {
+        /// ID the originator picked for the message, for peers to drop the copies of it reaching
+        /// them on other paths.
+        id: u64,
+        /// Number of times the message may still be gossiped on.
+        ttl: u8,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
+    /// IDs of the gossip messages we hold, for the peer to send us those it holds and we lack,
+    /// see `Builder::with_gossip`.
+    GossipDigest {
+        /// The IDs.
+        ids: Vec<u64>,
+        /// Set if this answers a digest of the peer, in which case no digest is sent back.
+        reply: bool,
+    },
+    /// Message of the user the peer is to acknowledge with `UserMsgAck` once it handed it to the
+    /// application, see `Builder::with_app_acks`.
+    ///
+    /// Peers of older versions ignore it with the tagged encoding and drop the connection with the
+    /// others.
+    AckedUserMsg {
+        /// ID of the message, unique among those the sender awaits acknowledgments of from us.
+        id: u64,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
+    /// Acknowledges that the `AckedUserMsg` with the ID was handed to the application.
+    UserMsgAck {
+        /// ID of the message.
+        id: u64,
+    },
+    /// Probe of the event loop of the peer, which answers with a `Pong` carrying the same nonce,
+    /// see `Builder::with_probes`.
+    ///
+    /// Peers of older versions ignore it with the tagged encoding and drop the connection with the
+    /// others.
+    Ping {
+        /// Tells the answer apart from those to earlier probes.
+        nonce: u64,
+    },
+    /// Answers the `Ping` with the nonce.
+    Pong {
+        /// Nonce of the probe.
+        nonce: u64,
+    },
+    /// Message of the user on a channel other than the default one, see
+    /// `QuicP2p::send_on_channel`.
+    ///
+    /// Peers of older versions ignore it with the tagged encoding and drop the connection with the
+    /// others.
+    ChannelMsg {
+        /// The channel.
+        channel: Channel,
+        /// Message of the user.
+        msg: bytes::Bytes,
+    },
 }
 
 impl Into<bytes::Bytes> for WireMsg {
     fn into(self) -> bytes::Bytes {
+        self.encode(WireEncoding::Bincode)
+    }
+}
+
+impl WireMsg {
+    /// The message of the user this carries to the peer, if any.
+    pub fn user_msg(&self) -> Option<&bytes::Bytes> {
+        match *self {
+            WireMsg::UserMsg(ref msg)
+            | WireMsg::IdentifiedUserMsg { ref msg, .. }
+            | WireMsg::AckedUserMsg { ref msg, .. }
+            | WireMsg::ChannelMsg { ref msg, .. } => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// The channel of the user message this carries, the default one for messages of our own.
+    pub fn channel(&self) -> Channel {
+        match *self {
+            WireMsg::ChannelMsg { channel, .. } => channel,
+            _ => DEFAULT_CHANNEL,
+        }
+    }
+
+    /// Decode a bincode encoded wire message from the bytes read off a stream.
+    pub fn from_raw(raw: Vec<u8>) -> R<Self> {
+        Self::decode(raw, WireEncoding::Bincode)
+    }
+
+    /// Encode the wire message to be written to a stream.
+    pub fn encode(self, encoding: WireEncoding) -> bytes::Bytes {
         if let WireMsg::UserMsg(ref m) = self {
-            if m.len() > MAX_MESSAGE_SIZE_FOR_SERIALISATION {
+            if m.len() > MAX_MESSAGE_SIZE_FOR_SERIALISATION && encoding.sends_big_user_msgs_raw() {
                 return m.clone();
             }
         }
 
-        From::from(unwrap!(bincode::serialize(&self)))
+        From::from(match encoding {
+            WireEncoding::Tagged => wire_tagged::encode(&self),
+            WireEncoding::Bincode => unwrap!(bincode::serialize(&self)),
+            WireEncoding::MessagePack => unwrap!(rmp_serde::to_vec_named(&self)),
+            WireEncoding::Cbor => wire_cbor::encode(&self),
+            #[cfg(feature = "proto")]
+            WireEncoding::Protobuf => crate::wire_proto::encode(&self),
+        })
     }
-}
 
-impl WireMsg {
-    pub fn from_raw(raw: Vec<u8>) -> R<Self> {
-        if raw.len() > MAX_MESSAGE_SIZE_FOR_SERIALISATION {
+    /// Decode a wire message from the bytes read off a stream.
+    pub fn decode(raw: Vec<u8>, encoding: WireEncoding) -> R<Self> {
+        if raw.len() > MAX_MESSAGE_SIZE_FOR_SERIALISATION && encoding.sends_big_user_msgs_raw() {
             return Ok(WireMsg::UserMsg(From::from(raw)));
         }
 
-        Ok(bincode::deserialize(&raw)?)
+        Ok(match encoding {
+            WireEncoding::Tagged => wire_tagged::decode(&raw)?,
+            WireEncoding::Bincode => bincode::deserialize(&raw)?,
+            WireEncoding::MessagePack => rmp_serde::from_slice(&raw)?,
+            WireEncoding::Cbor => wire_cbor::decode(&raw)?,
+            #[cfg(feature = "proto")]
+            WireEncoding::Protobuf => crate::wire_proto::decode(&raw)?,
+        })
     }
 }
 
 impl fmt::Display for WireMsg {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            WireMsg::Handshake(ref h) => write!(f, "WireMsg::Handshake({})", h),
+            WireMsg::EndpointEchoReq => write!(f, "WireMsg::EndpointEchoReq"),
+            WireMsg::EndpointEchoResp(addr) => write!(f, "WireMsg::EndpointEchoResp({})", addr),
             WireMsg::UserMsg(ref m) => {
                 write!(f, "WireMsg::UserMsg({})", utils::bin_data_format(&*m))
             }
-            ref w => write!(f, "{}", w),
+            WireMsg::ConnectBackReq(ref node_info) => {
+                write!(f, "WireMsg::ConnectBackReq({})", node_info)
+            }
+            WireMsg::ForwardReq { to, ref msg } => write!(
+                f,
+                "WireMsg::ForwardReq {{ to: {}, msg: {} }}",
+                to,
+                utils::bin_data_format(&*msg)
+            ),
+            WireMsg::ForwardedMsg { from, ref msg } => write!(
+                f,
+                "WireMsg::ForwardedMsg {{ from: {}, msg: {} }}",
+                from,
+                utils::bin_data_format(&*msg)
+            ),
+            WireMsg::ReachabilityReq(ref node_info) => {
+                write!(f, "WireMsg::ReachabilityReq({})", node_info)
+            }
+            WireMsg::ReachabilityResp { addr, reachable } => write!(
+                f,
+                "WireMsg::ReachabilityResp {{ addr: {}, reachable: {} }}",
+                addr, reachable
+            ),
+            WireMsg::IdentifiedUserMsg { id, ref msg } => write!(
+                f,
+                "WireMsg::IdentifiedUserMsg {{ id: {}, msg: {} }}",
+                id,
+                utils::bin_data_format(&*msg)
+            ),
+            WireMsg::Subscription {
+                ref topic,
+                subscribed,
+            } => write!(
+                f,
+                "WireMsg::Subscription {{ topic: {}, subscribed: {} }}",
+                topic, subscribed
+            ),
+            WireMsg::TopicMsg {
+                ref topic,
+                id,
+                hops_left,
+                ref msg,
+            } => write!(
+                f,
+                "WireMsg::TopicMsg {{ topic: {}, id: {}, hops_left: {}, msg: {} }}",
+                topic,
+                id,
+                hops_left,
+                utils::bin_data_format(&*msg)
+            ),
+            WireMsg::Gossip { id, ttl, ref msg } => write!(
+                f,
+                "WireMsg::Gossip {{ id: {}, ttl: {}, msg: {} }}",
+                id,
+                ttl,
+                utils::bin_data_format(&*msg)
+            ),
+            WireMsg::GossipDigest { ref ids, reply } => write!(
+                f,
+                "WireMsg::GossipDigest {{ ids: {} IDs, reply: {} }}",
+                ids.len(),
+                reply
+            ),
+            WireMsg::AckedUserMsg { id, ref msg } => write!(
+                f,
+                "WireMsg::AckedUserMsg {{ id: {}, msg: {} }}",
+                id,
+                utils::bin_data_format(&*msg)
+            ),
+            WireMsg::UserMsgAck { id } => write!(f, "WireMsg::UserMsgAck {{ id: {} }}", id),
+            WireMsg::Ping { nonce } => write!(f, "WireMsg::Ping {{ nonce: {} }}", nonce),
+            WireMsg::Pong { nonce } => write!(f, "WireMsg::Pong {{ nonce: {} }}", nonce),
+            WireMsg::ChannelMsg { channel, ref msg } => write!(
+                f,
+                "WireMsg::ChannelMsg {{ channel: {}, msg: {} }}",
+                channel,
+                utils::bin_data_format(&*msg)
+            ),
         }
     }
 }
@@ -64,24 +524,102 @@ impl fmt::Display for WireMsg {
 /// passive connection from a peer will allow only incoming uni-directional streams from it.
 ///
 /// Depending on the handshake we will categorise the peer and give this information to the user.
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// The capabilities and protocol version of the peer are only carried by the tagged, CBOR and
+/// protobuf encodings, and only written if there are any capabilities or if the version is other
+/// than 1: bincode and MessagePack keep the shape peers of older versions expect.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "LegacyHandshake", into = "LegacyHandshake")]
 pub enum Handshake {
     /// The connecting peer is a node. Certificate is needed for allowing connection back to the
     /// peer
-    Node { cert_der: Vec<u8> },
-    /// The connecting peer is a client. No need for a reverse connection.
-    Client,
+    Node {
+        /// DER encoded certificate of the node.
+        cert_der: Vec<u8>,
+        /// Capabilities the node advertises.
+        capabilities: Capabilities,
+        /// Version of the wire protocol the node speaks.
+        protocol_version: u16,
+    },
+    /// The connecting peer is a client. No need for a reverse connection. Nodes reply to it with
+    /// one of their own, for the client to learn their version of the wire protocol.
+    Client {
+        /// Capabilities the client advertises.
+        capabilities: Capabilities,
+        /// Version of the wire protocol the client speaks.
+        protocol_version: u16,
+    },
+}
+
+impl Handshake {
+    /// Version of the wire protocol the peer speaks.
+    pub fn protocol_version(&self) -> u16 {
+        match *self {
+            Handshake::Node {
+                protocol_version, ..
+            }
+            | Handshake::Client {
+                protocol_version, ..
+            } => protocol_version,
+        }
+    }
 }
 
 impl fmt::Display for Handshake {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Handshake::Node { ref cert_der } => write!(
+            Handshake::Node {
+                ref cert_der,
+                ref capabilities,
+                protocol_version,
+            } => write!(
+                f,
+                "Handshake::Node {{ cert_der: {}, capabilities: {:?}, protocol_version: {} }}",
+                utils::bin_data_format(cert_der),
+                capabilities,
+                protocol_version
+            ),
+            Handshake::Client {
+                ref capabilities,
+                protocol_version,
+            } => write!(
                 f,
-                "Handshake::Node {{ cert_der: {} }}",
-                utils::bin_data_format(cert_der)
+                "Handshake::Client {{ capabilities: {:?}, protocol_version: {} }}",
+                capabilities, protocol_version
             ),
-            Handshake::Client => write!(f, "Handshake::Client"),
+        }
+    }
+}
+
+/// `Handshake` as serialised by serde, from before capabilities were advertised.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Handshake")]
+enum LegacyHandshake {
+    Node { cert_der: Vec<u8> },
+    Client,
+}
+
+impl From<LegacyHandshake> for Handshake {
+    fn from(handshake: LegacyHandshake) -> Self {
+        match handshake {
+            LegacyHandshake::Node { cert_der } => Handshake::Node {
+                cert_der,
+                capabilities: Default::default(),
+                protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+            },
+            LegacyHandshake::Client => Handshake::Client {
+                capabilities: Default::default(),
+                protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+            },
+        }
+    }
+}
+
+impl From<Handshake> for LegacyHandshake {
+    fn from(handshake: Handshake) -> Self {
+        match handshake {
+            Handshake::Node { cert_der, .. } => LegacyHandshake::Node { cert_der },
+            Handshake::Client { .. } => LegacyHandshake::Client,
         }
     }
 }
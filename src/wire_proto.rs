@@ -0,0 +1,560 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Protobuf encoding of wire messages as defined by `proto/wire_msg.proto`.
+//!
+//! The prost types below mirror the schema by hand so that building doesn't require `protoc`.
+//! Any change to them must be made to the schema as well, and vice versa.
+
+use crate::error::Error;
+use crate::wire_msg::{Handshake, WireMsg, UNADVERTISED_PROTOCOL_VERSION};
+use crate::{Capabilities, NodeInfo, R};
+use prost::Message;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+mod schema {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WireMsg {
+        #[prost(
+            oneof = "Msg",
+            tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19"
+        )]
+        pub msg: Option<Msg>,
+    }
+
+    // Variants are named after the fields of the schema
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Msg {
+        #[prost(message, tag = "1")]
+        Handshake(Handshake),
+        #[prost(message, tag = "2")]
+        EndpointEchoReq(EndpointEchoReq),
+        #[prost(message, tag = "3")]
+        EndpointEchoResp(SocketAddr),
+        #[prost(bytes, tag = "4")]
+        UserMsg(Vec<u8>),
+        #[prost(message, tag = "5")]
+        ConnectBackReq(ConnectBackReq),
+        #[prost(message, tag = "6")]
+        ForwardReq(ForwardReq),
+        #[prost(message, tag = "7")]
+        ForwardedMsg(ForwardedMsg),
+        #[prost(message, tag = "8")]
+        ReachabilityReq(ReachabilityReq),
+        #[prost(message, tag = "9")]
+        ReachabilityResp(ReachabilityResp),
+        #[prost(message, tag = "10")]
+        IdentifiedUserMsg(IdentifiedUserMsg),
+        #[prost(message, tag = "11")]
+        Subscription(Subscription),
+        #[prost(message, tag = "12")]
+        TopicMsg(TopicMsg),
+        #[prost(message, tag = "13")]
+        Gossip(Gossip),
+        #[prost(message, tag = "14")]
+        GossipDigest(GossipDigest),
+        #[prost(message, tag = "15")]
+        AckedUserMsg(AckedUserMsg),
+        #[prost(message, tag = "16")]
+        UserMsgAck(UserMsgAck),
+        #[prost(message, tag = "17")]
+        Ping(Probe),
+        #[prost(message, tag = "18")]
+        Pong(Probe),
+        #[prost(message, tag = "19")]
+        ChannelMsg(ChannelMsg),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Handshake {
+        #[prost(oneof = "Peer", tags = "1, 2")]
+        pub peer: Option<Peer>,
+        #[prost(uint32, tag = "3")]
+        pub protocol_version: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Peer {
+        #[prost(message, tag = "1")]
+        Node(NodeHandshake),
+        #[prost(message, tag = "2")]
+        Client(ClientHandshake),
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct NodeHandshake {
+        #[prost(bytes, tag = "1")]
+        pub cert_der: Vec<u8>,
+        #[prost(message, optional, tag = "2")]
+        pub capabilities: Option<Capabilities>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ClientHandshake {
+        #[prost(message, optional, tag = "1")]
+        pub capabilities: Option<Capabilities>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Capabilities {
+        #[prost(uint64, tag = "1")]
+        pub flags: u64,
+        #[prost(string, tag = "2")]
+        pub role: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct EndpointEchoReq {}
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SocketAddr {
+        #[prost(bytes, tag = "1")]
+        pub ip: Vec<u8>,
+        #[prost(uint32, tag = "2")]
+        pub port: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ConnectBackReq {
+        #[prost(message, optional, tag = "1")]
+        pub addr: Option<SocketAddr>,
+        #[prost(bytes, tag = "2")]
+        pub cert_der: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ForwardReq {
+        #[prost(message, optional, tag = "1")]
+        pub to: Option<SocketAddr>,
+        #[prost(bytes, tag = "2")]
+        pub msg: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ForwardedMsg {
+        #[prost(message, optional, tag = "1")]
+        pub from: Option<SocketAddr>,
+        #[prost(bytes, tag = "2")]
+        pub msg: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReachabilityReq {
+        #[prost(message, optional, tag = "1")]
+        pub addr: Option<SocketAddr>,
+        #[prost(bytes, tag = "2")]
+        pub cert_der: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ReachabilityResp {
+        #[prost(message, optional, tag = "1")]
+        pub addr: Option<SocketAddr>,
+        #[prost(bool, tag = "2")]
+        pub reachable: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct IdentifiedUserMsg {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(bytes, tag = "2")]
+        pub msg: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Subscription {
+        #[prost(string, tag = "1")]
+        pub topic: String,
+        #[prost(bool, tag = "2")]
+        pub subscribed: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TopicMsg {
+        #[prost(string, tag = "1")]
+        pub topic: String,
+        #[prost(uint64, tag = "2")]
+        pub id: u64,
+        #[prost(uint32, tag = "3")]
+        pub hops_left: u32,
+        #[prost(bytes, tag = "4")]
+        pub msg: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Gossip {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint32, tag = "2")]
+        pub ttl: u32,
+        #[prost(bytes, tag = "3")]
+        pub msg: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct GossipDigest {
+        #[prost(uint64, repeated, tag = "1")]
+        pub ids: Vec<u64>,
+        #[prost(bool, tag = "2")]
+        pub reply: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AckedUserMsg {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(bytes, tag = "2")]
+        pub msg: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct UserMsgAck {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Probe {
+        #[prost(uint64, tag = "1")]
+        pub nonce: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ChannelMsg {
+        #[prost(uint32, tag = "1")]
+        pub channel: u32,
+        #[prost(bytes, tag = "2")]
+        pub msg: Vec<u8>,
+    }
+}
+
+/// Encode the wire message as a protobuf `WireMsg`.
+pub fn encode(wire_msg: &WireMsg) -> Vec<u8> {
+    let msg = match *wire_msg {
+        WireMsg::Handshake(ref h) => {
+            let peer = match *h {
+                Handshake::Node {
+                    ref cert_der,
+                    ref capabilities,
+                    ..
+                } => schema::Peer::Node(schema::NodeHandshake {
+                    cert_der: cert_der.clone(),
+                    capabilities: to_schema_capabilities(capabilities),
+                }),
+                Handshake::Client {
+                    ref capabilities, ..
+                } => schema::Peer::Client(schema::ClientHandshake {
+                    capabilities: to_schema_capabilities(capabilities),
+                }),
+            };
+            // Left unset, i.e. 0, for the version of peers not advertising any
+            let protocol_version = match h.protocol_version() {
+                UNADVERTISED_PROTOCOL_VERSION => 0,
+                version => u32::from(version),
+            };
+            schema::Msg::Handshake(schema::Handshake {
+                peer: Some(peer),
+                protocol_version,
+            })
+        }
+        WireMsg::EndpointEchoReq => schema::Msg::EndpointEchoReq(schema::EndpointEchoReq {}),
+        WireMsg::EndpointEchoResp(addr) => schema::Msg::EndpointEchoResp(to_schema_addr(addr)),
+        WireMsg::UserMsg(ref m) => schema::Msg::UserMsg(m.to_vec()),
+        WireMsg::ConnectBackReq(ref node_info) => {
+            schema::Msg::ConnectBackReq(schema::ConnectBackReq {
+                addr: Some(to_schema_addr(node_info.peer_addr)),
+                cert_der: node_info.peer_cert_der.clone(),
+            })
+        }
+        WireMsg::ForwardReq { to, ref msg } => schema::Msg::ForwardReq(schema::ForwardReq {
+            to: Some(to_schema_addr(to)),
+            msg: msg.to_vec(),
+        }),
+        WireMsg::ForwardedMsg { from, ref msg } => {
+            schema::Msg::ForwardedMsg(schema::ForwardedMsg {
+                from: Some(to_schema_addr(from)),
+                msg: msg.to_vec(),
+            })
+        }
+        WireMsg::ReachabilityReq(ref node_info) => {
+            schema::Msg::ReachabilityReq(schema::ReachabilityReq {
+                addr: Some(to_schema_addr(node_info.peer_addr)),
+                cert_der: node_info.peer_cert_der.clone(),
+            })
+        }
+        WireMsg::ReachabilityResp { addr, reachable } => {
+            schema::Msg::ReachabilityResp(schema::ReachabilityResp {
+                addr: Some(to_schema_addr(addr)),
+                reachable,
+            })
+        }
+        WireMsg::IdentifiedUserMsg { id, ref msg } => {
+            schema::Msg::IdentifiedUserMsg(schema::IdentifiedUserMsg {
+                id,
+                msg: msg.to_vec(),
+            })
+        }
+        WireMsg::Subscription {
+            ref topic,
+            subscribed,
+        } => schema::Msg::Subscription(schema::Subscription {
+            topic: topic.clone(),
+            subscribed,
+        }),
+        WireMsg::TopicMsg {
+            ref topic,
+            id,
+            hops_left,
+            ref msg,
+        } => schema::Msg::TopicMsg(schema::TopicMsg {
+            topic: topic.clone(),
+            id,
+            hops_left: u32::from(hops_left),
+            msg: msg.to_vec(),
+        }),
+        WireMsg::Gossip { id, ttl, ref msg } => schema::Msg::Gossip(schema::Gossip {
+            id,
+            ttl: u32::from(ttl),
+            msg: msg.to_vec(),
+        }),
+        WireMsg::GossipDigest { ref ids, reply } => {
+            schema::Msg::GossipDigest(schema::GossipDigest {
+                ids: ids.clone(),
+                reply,
+            })
+        }
+        WireMsg::AckedUserMsg { id, ref msg } => schema::Msg::AckedUserMsg(schema::AckedUserMsg {
+            id,
+            msg: msg.to_vec(),
+        }),
+        WireMsg::UserMsgAck { id } => schema::Msg::UserMsgAck(schema::UserMsgAck { id }),
+        WireMsg::Ping { nonce } => schema::Msg::Ping(schema::Probe { nonce }),
+        WireMsg::Pong { nonce } => schema::Msg::Pong(schema::Probe { nonce }),
+        WireMsg::ChannelMsg { channel, ref msg } => schema::Msg::ChannelMsg(schema::ChannelMsg {
+            channel: u32::from(channel),
+            msg: msg.to_vec(),
+        }),
+    };
+
+    schema::WireMsg { msg: Some(msg) }.encode_to_vec()
+}
+
+/// Decode a wire message from a protobuf `WireMsg`.
+pub fn decode(raw: &[u8]) -> R<WireMsg> {
+    let msg = schema::WireMsg::decode(raw)
+        .map_err(|e| invalid(&e.to_string()))?
+        .msg
+        .ok_or_else(|| invalid("no message set"))?;
+
+    Ok(match msg {
+        schema::Msg::Handshake(h) => {
+            let protocol_version = match h.protocol_version {
+                0 => UNADVERTISED_PROTOCOL_VERSION,
+                version => {
+                    u16::try_from(version).map_err(|_| invalid("protocol version too big"))?
+                }
+            };
+            match h.peer.ok_or_else(|| invalid("no peer type set"))? {
+                schema::Peer::Node(n) => WireMsg::Handshake(Handshake::Node {
+                    cert_der: n.cert_der,
+                    capabilities: from_schema_capabilities(n.capabilities),
+                    protocol_version,
+                }),
+                schema::Peer::Client(cl) => WireMsg::Handshake(Handshake::Client {
+                    capabilities: from_schema_capabilities(cl.capabilities),
+                    protocol_version,
+                }),
+            }
+        }
+        schema::Msg::EndpointEchoReq(_) => WireMsg::EndpointEchoReq,
+        schema::Msg::EndpointEchoResp(addr) => WireMsg::EndpointEchoResp(from_schema_addr(addr)?),
+        schema::Msg::UserMsg(m) => WireMsg::UserMsg(From::from(m)),
+        schema::Msg::ConnectBackReq(req) => WireMsg::ConnectBackReq(NodeInfo {
+            peer_addr: from_schema_addr(req.addr.ok_or_else(|| invalid("no address set"))?)?,
+            peer_cert_der: req.cert_der,
+            alt_addrs: Vec::new(),
+        }),
+        schema::Msg::ForwardReq(req) => WireMsg::ForwardReq {
+            to: from_schema_addr(req.to.ok_or_else(|| invalid("no address set"))?)?,
+            msg: From::from(req.msg),
+        },
+        schema::Msg::ForwardedMsg(m) => WireMsg::ForwardedMsg {
+            from: from_schema_addr(m.from.ok_or_else(|| invalid("no address set"))?)?,
+            msg: From::from(m.msg),
+        },
+        schema::Msg::ReachabilityReq(req) => WireMsg::ReachabilityReq(NodeInfo {
+            peer_addr: from_schema_addr(req.addr.ok_or_else(|| invalid("no address set"))?)?,
+            peer_cert_der: req.cert_der,
+            alt_addrs: Vec::new(),
+        }),
+        schema::Msg::ReachabilityResp(resp) => WireMsg::ReachabilityResp {
+            addr: from_schema_addr(resp.addr.ok_or_else(|| invalid("no address set"))?)?,
+            reachable: resp.reachable,
+        },
+        schema::Msg::IdentifiedUserMsg(m) => WireMsg::IdentifiedUserMsg {
+            id: m.id,
+            msg: From::from(m.msg),
+        },
+        schema::Msg::Subscription(s) => WireMsg::Subscription {
+            topic: s.topic,
+            subscribed: s.subscribed,
+        },
+        schema::Msg::TopicMsg(m) => WireMsg::TopicMsg {
+            topic: m.topic,
+            id: m.id,
+            hops_left: u8::try_from(m.hops_left).map_err(|_| invalid("hops left out of range"))?,
+            msg: From::from(m.msg),
+        },
+        schema::Msg::Gossip(m) => WireMsg::Gossip {
+            id: m.id,
+            ttl: u8::try_from(m.ttl).map_err(|_| invalid("TTL out of range"))?,
+            msg: From::from(m.msg),
+        },
+        schema::Msg::GossipDigest(d) => WireMsg::GossipDigest {
+            ids: d.ids,
+            reply: d.reply,
+        },
+        schema::Msg::AckedUserMsg(m) => WireMsg::AckedUserMsg {
+            id: m.id,
+            msg: From::from(m.msg),
+        },
+        schema::Msg::UserMsgAck(a) => WireMsg::UserMsgAck { id: a.id },
+        schema::Msg::Ping(p) => WireMsg::Ping { nonce: p.nonce },
+        schema::Msg::Pong(p) => WireMsg::Pong { nonce: p.nonce },
+        schema::Msg::ChannelMsg(m) => WireMsg::ChannelMsg {
+            channel: u16::try_from(m.channel).map_err(|_| invalid("channel out of range"))?,
+            msg: From::from(m.msg),
+        },
+    })
+}
+
+fn to_schema_addr(addr: SocketAddr) -> schema::SocketAddr {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    schema::SocketAddr {
+        ip,
+        port: u32::from(addr.port()),
+    }
+}
+
+fn from_schema_addr(addr: schema::SocketAddr) -> R<SocketAddr> {
+    let ip = if let Ok(octets) = <[u8; 4]>::try_from(&addr.ip[..]) {
+        IpAddr::V4(Ipv4Addr::from(octets))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(&addr.ip[..]) {
+        IpAddr::V6(Ipv6Addr::from(octets))
+    } else {
+        return Err(invalid("IP address is neither 4 nor 16 bytes long"));
+    };
+    let port = u16::try_from(addr.port).map_err(|_| invalid("port out of range"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// `None` if there are no capabilities, so that handshakes stay as they were before them.
+fn to_schema_capabilities(capabilities: &Capabilities) -> Option<schema::Capabilities> {
+    if capabilities.is_empty() {
+        return None;
+    }
+    Some(schema::Capabilities {
+        flags: capabilities.flags,
+        role: capabilities.role.clone(),
+    })
+}
+
+fn from_schema_capabilities(capabilities: Option<schema::Capabilities>) -> Capabilities {
+    capabilities
+        .map(|c| Capabilities {
+            flags: c.flags,
+            role: c.role,
+        })
+        .unwrap_or_default()
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Protobuf(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings_match_the_schema() {
+        // endpoint_echo_resp { ip: 7f000001, port: 443 }
+        let raw = [
+            0x1a, 0x09, 0x0a, 0x04, 0x7f, 0x00, 0x00, 0x01, 0x10, 0xbb, 0x03,
+        ];
+        let addr: SocketAddr = ([127, 0, 0, 1], 443).into();
+        assert_eq!(encode(&WireMsg::EndpointEchoResp(addr)), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::EndpointEchoResp(a) => assert_eq!(a, addr),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // handshake { client {} }
+        let raw = [0x0a, 0x02, 0x12, 0x00];
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: Default::default(),
+            protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(Handshake::Client { capabilities, .. }) => {
+                assert!(capabilities.is_empty())
+            }
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // handshake { client {} protocol_version: 2 }
+        let raw = [0x0a, 0x04, 0x12, 0x00, 0x18, 0x02];
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: Default::default(),
+            protocol_version: 2,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(h) => assert_eq!(h.protocol_version(), 2),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // handshake { client { capabilities { flags: 5, role: "db" } } }
+        let raw = [
+            0x0a, 0x0a, 0x12, 0x08, 0x0a, 0x06, 0x08, 0x05, 0x12, 0x02, 0x64, 0x62,
+        ];
+        let capabilities = Capabilities {
+            flags: 5,
+            role: "db".to_string(),
+        };
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: capabilities.clone(),
+            protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(Handshake::Client {
+                capabilities: c, ..
+            }) => assert_eq!(c, capabilities),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        // user_msg: 010203
+        let raw = [0x22, 0x03, 0x01, 0x02, 0x03];
+        assert_eq!(encode(&WireMsg::UserMsg(From::from(vec![1, 2, 3]))), raw);
+    }
+
+    #[test]
+    fn messages_without_payload_are_rejected() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[0x0a, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,551 @@
+// Copyright 2019 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Tagged encoding of wire messages, see `WireEncoding::Tagged` for the frame layout.
+
+use crate::error::Error;
+use crate::wire_msg::{Handshake, WireMsg, UNADVERTISED_PROTOCOL_VERSION};
+use crate::{Capabilities, NodeInfo, R};
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Version of the frames we write.
+const VERSION: u8 = 1;
+
+const HANDSHAKE_NODE: u8 = 0;
+const HANDSHAKE_CLIENT: u8 = 1;
+const ENDPOINT_ECHO_REQ: u8 = 2;
+const ENDPOINT_ECHO_RESP: u8 = 3;
+const USER_MSG: u8 = 4;
+const CONNECT_BACK_REQ: u8 = 5;
+const FORWARD_REQ: u8 = 6;
+const FORWARDED_MSG: u8 = 7;
+const REACHABILITY_REQ: u8 = 8;
+const REACHABILITY_RESP: u8 = 9;
+const IDENTIFIED_USER_MSG: u8 = 10;
+const SUBSCRIPTION: u8 = 11;
+const TOPIC_MSG: u8 = 12;
+const GOSSIP: u8 = 13;
+const GOSSIP_DIGEST: u8 = 14;
+const ACKED_USER_MSG: u8 = 15;
+const USER_MSG_ACK: u8 = 16;
+const PING: u8 = 17;
+const PONG: u8 = 18;
+const CHANNEL_MSG: u8 = 19;
+
+/// Encode the wire message as a tagged frame.
+pub fn encode(wire_msg: &WireMsg) -> Vec<u8> {
+    let mut frame = Frame::new(match *wire_msg {
+        WireMsg::Handshake(Handshake::Node { .. }) => HANDSHAKE_NODE,
+        WireMsg::Handshake(Handshake::Client { .. }) => HANDSHAKE_CLIENT,
+        WireMsg::EndpointEchoReq => ENDPOINT_ECHO_REQ,
+        WireMsg::EndpointEchoResp(_) => ENDPOINT_ECHO_RESP,
+        WireMsg::UserMsg(_) => USER_MSG,
+        WireMsg::ConnectBackReq(_) => CONNECT_BACK_REQ,
+        WireMsg::ForwardReq { .. } => FORWARD_REQ,
+        WireMsg::ForwardedMsg { .. } => FORWARDED_MSG,
+        WireMsg::ReachabilityReq(_) => REACHABILITY_REQ,
+        WireMsg::ReachabilityResp { .. } => REACHABILITY_RESP,
+        WireMsg::IdentifiedUserMsg { .. } => IDENTIFIED_USER_MSG,
+        WireMsg::Subscription { .. } => SUBSCRIPTION,
+        WireMsg::TopicMsg { .. } => TOPIC_MSG,
+        WireMsg::Gossip { .. } => GOSSIP,
+        WireMsg::GossipDigest { .. } => GOSSIP_DIGEST,
+        WireMsg::AckedUserMsg { .. } => ACKED_USER_MSG,
+        WireMsg::UserMsgAck { .. } => USER_MSG_ACK,
+        WireMsg::Ping { .. } => PING,
+        WireMsg::Pong { .. } => PONG,
+        WireMsg::ChannelMsg { .. } => CHANNEL_MSG,
+    });
+
+    match *wire_msg {
+        WireMsg::Handshake(Handshake::Node {
+            ref cert_der,
+            ref capabilities,
+            protocol_version,
+        }) => {
+            frame.field(1, cert_der);
+            frame.capability_fields(capabilities);
+            frame.protocol_version_field(protocol_version);
+        }
+        WireMsg::Handshake(Handshake::Client {
+            ref capabilities,
+            protocol_version,
+        }) => {
+            frame.capability_fields(capabilities);
+            frame.protocol_version_field(protocol_version);
+        }
+        WireMsg::EndpointEchoReq => (),
+        WireMsg::EndpointEchoResp(addr) => frame.addr_fields(addr),
+        WireMsg::UserMsg(ref m) => frame.field(1, m),
+        WireMsg::ConnectBackReq(ref node_info) | WireMsg::ReachabilityReq(ref node_info) => {
+            frame.addr_fields(node_info.peer_addr);
+            frame.field(3, &node_info.peer_cert_der);
+        }
+        WireMsg::ForwardReq { to: addr, ref msg }
+        | WireMsg::ForwardedMsg {
+            from: addr,
+            ref msg,
+        } => {
+            frame.addr_fields(addr);
+            frame.field(3, msg);
+        }
+        WireMsg::ReachabilityResp { addr, reachable } => {
+            frame.addr_fields(addr);
+            frame.field(3, &[reachable as u8]);
+        }
+        WireMsg::IdentifiedUserMsg { id, ref msg } | WireMsg::AckedUserMsg { id, ref msg } => {
+            frame.field(1, &id.to_le_bytes());
+            frame.field(2, msg);
+        }
+        WireMsg::UserMsgAck { id: nonce } | WireMsg::Ping { nonce } | WireMsg::Pong { nonce } => {
+            frame.field(1, &nonce.to_le_bytes())
+        }
+        WireMsg::ChannelMsg { channel, ref msg } => {
+            frame.field(1, &channel.to_le_bytes());
+            frame.field(2, msg);
+        }
+        WireMsg::Subscription {
+            ref topic,
+            subscribed,
+        } => {
+            frame.field(1, topic.as_bytes());
+            frame.field(2, &[subscribed as u8]);
+        }
+        WireMsg::TopicMsg {
+            ref topic,
+            id,
+            hops_left,
+            ref msg,
+        } => {
+            frame.field(1, topic.as_bytes());
+            frame.field(2, &id.to_le_bytes());
+            frame.field(3, &[hops_left]);
+            frame.field(4, msg);
+        }
+        WireMsg::Gossip { id, ttl, ref msg } => {
+            frame.field(1, &id.to_le_bytes());
+            frame.field(2, &[ttl]);
+            frame.field(3, msg);
+        }
+        WireMsg::GossipDigest { ref ids, reply } => {
+            let ids: Vec<u8> = ids
+                .iter()
+                .flat_map(|id| id.to_le_bytes().to_vec())
+                .collect();
+            frame.field(1, &ids);
+            frame.field(2, &[reply as u8]);
+        }
+    }
+
+    frame.0
+}
+
+/// Decode a wire message from a tagged frame.
+///
+/// Fields we don't know of are skipped. Frames of message types we don't know of yield
+/// `Error::UnknownWireMsg`.
+pub fn decode(raw: &[u8]) -> R<WireMsg> {
+    if raw.len() < 2 {
+        return Err(invalid("frame too short"));
+    }
+    // Later versions only ever add message types and fields, so there's nothing to check the
+    // version against yet
+    let _version = raw[0];
+    let msg_type = raw[1];
+    let mut fields = Fields(&raw[2..]);
+
+    Ok(match msg_type {
+        HANDSHAKE_NODE => WireMsg::Handshake(Handshake::Node {
+            cert_der: fields.get(1)?.to_vec(),
+            capabilities: fields.capabilities()?,
+            protocol_version: fields.protocol_version()?,
+        }),
+        HANDSHAKE_CLIENT => WireMsg::Handshake(Handshake::Client {
+            capabilities: fields.capabilities()?,
+            protocol_version: fields.protocol_version()?,
+        }),
+        ENDPOINT_ECHO_REQ => WireMsg::EndpointEchoReq,
+        ENDPOINT_ECHO_RESP => WireMsg::EndpointEchoResp(fields.addr()?),
+        USER_MSG => WireMsg::UserMsg(From::from(fields.get(1)?)),
+        CONNECT_BACK_REQ => WireMsg::ConnectBackReq(NodeInfo {
+            peer_addr: fields.addr()?,
+            peer_cert_der: fields.get(3)?.to_vec(),
+            alt_addrs: Vec::new(),
+        }),
+        FORWARD_REQ => WireMsg::ForwardReq {
+            to: fields.addr()?,
+            msg: From::from(fields.get(3)?),
+        },
+        FORWARDED_MSG => WireMsg::ForwardedMsg {
+            from: fields.addr()?,
+            msg: From::from(fields.get(3)?),
+        },
+        REACHABILITY_REQ => WireMsg::ReachabilityReq(NodeInfo {
+            peer_addr: fields.addr()?,
+            peer_cert_der: fields.get(3)?.to_vec(),
+            alt_addrs: Vec::new(),
+        }),
+        REACHABILITY_RESP => WireMsg::ReachabilityResp {
+            addr: fields.addr()?,
+            reachable: match fields.get(3)? {
+                [0] => false,
+                [1] => true,
+                _ => return Err(invalid("reachability is neither 0 nor 1")),
+            },
+        },
+        IDENTIFIED_USER_MSG => WireMsg::IdentifiedUserMsg {
+            id: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("ID is not 8 bytes long"))?,
+            ),
+            msg: From::from(fields.get(2)?),
+        },
+        SUBSCRIPTION => WireMsg::Subscription {
+            topic: fields.topic()?,
+            subscribed: match fields.get(2)? {
+                [0] => false,
+                [1] => true,
+                _ => return Err(invalid("subscribed is neither 0 nor 1")),
+            },
+        },
+        TOPIC_MSG => WireMsg::TopicMsg {
+            topic: fields.topic()?,
+            id: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(2)?)
+                    .map_err(|_| invalid("ID is not 8 bytes long"))?,
+            ),
+            hops_left: match fields.get(3)? {
+                [hops_left] => *hops_left,
+                _ => return Err(invalid("hops left is not 1 byte long")),
+            },
+            msg: From::from(fields.get(4)?),
+        },
+        GOSSIP => WireMsg::Gossip {
+            id: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("ID is not 8 bytes long"))?,
+            ),
+            ttl: match fields.get(2)? {
+                [ttl] => *ttl,
+                _ => return Err(invalid("TTL is not 1 byte long")),
+            },
+            msg: From::from(fields.get(3)?),
+        },
+        GOSSIP_DIGEST => WireMsg::GossipDigest {
+            ids: {
+                let ids = fields.get(1)?;
+                if ids.len() % 8 != 0 {
+                    return Err(invalid("IDs are not 8 bytes long each"));
+                }
+                ids.chunks(8)
+                    .map(|id| u64::from_le_bytes(unwrap!(<[u8; 8]>::try_from(id))))
+                    .collect()
+            },
+            reply: match fields.get(2)? {
+                [0] => false,
+                [1] => true,
+                _ => return Err(invalid("reply is neither 0 nor 1")),
+            },
+        },
+        ACKED_USER_MSG => WireMsg::AckedUserMsg {
+            id: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("ID is not 8 bytes long"))?,
+            ),
+            msg: From::from(fields.get(2)?),
+        },
+        USER_MSG_ACK => WireMsg::UserMsgAck {
+            id: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("ID is not 8 bytes long"))?,
+            ),
+        },
+        PING => WireMsg::Ping {
+            nonce: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("nonce is not 8 bytes long"))?,
+            ),
+        },
+        PONG => WireMsg::Pong {
+            nonce: u64::from_le_bytes(
+                <[u8; 8]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("nonce is not 8 bytes long"))?,
+            ),
+        },
+        CHANNEL_MSG => WireMsg::ChannelMsg {
+            channel: u16::from_le_bytes(
+                <[u8; 2]>::try_from(fields.get(1)?)
+                    .map_err(|_| invalid("channel is not 2 bytes long"))?,
+            ),
+            msg: From::from(fields.get(2)?),
+        },
+        msg_type => return Err(Error::UnknownWireMsg(msg_type)),
+    })
+}
+
+struct Frame(Vec<u8>);
+
+impl Frame {
+    fn new(msg_type: u8) -> Self {
+        Frame(vec![VERSION, msg_type])
+    }
+
+    fn field(&mut self, tag: u8, value: &[u8]) {
+        self.0.push(tag);
+        self.0
+            .extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.0.extend_from_slice(value);
+    }
+
+    /// The IP address as field 1 and the port as field 2.
+    fn addr_fields(&mut self, addr: SocketAddr) {
+        match addr.ip() {
+            IpAddr::V4(ip) => self.field(1, &ip.octets()),
+            IpAddr::V6(ip) => self.field(1, &ip.octets()),
+        }
+        self.field(2, &addr.port().to_le_bytes());
+    }
+
+    /// The flags as field 2 and the role as field 3, unless there are no capabilities.
+    fn capability_fields(&mut self, capabilities: &Capabilities) {
+        if !capabilities.is_empty() {
+            self.field(2, &capabilities.flags.to_le_bytes());
+            self.field(3, capabilities.role.as_bytes());
+        }
+    }
+
+    /// The protocol version as field 4, unless it's the one of peers not advertising any.
+    fn protocol_version_field(&mut self, protocol_version: u16) {
+        if protocol_version != UNADVERTISED_PROTOCOL_VERSION {
+            self.field(4, &protocol_version.to_le_bytes());
+        }
+    }
+}
+
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    /// Value of the field with the given tag, skipping any fields before it. Fields have to be
+    /// asked for in the order they are written in.
+    fn get(&mut self, tag: u8) -> R<&'a [u8]> {
+        self.opt(tag)?.ok_or_else(|| invalid("missing field"))
+    }
+
+    /// Like `get` but `None` if there's no field with the given tag, in which case no fields are
+    /// skipped either.
+    fn opt(&mut self, tag: u8) -> R<Option<&'a [u8]>> {
+        let mut rest = self.0;
+        while !rest.is_empty() {
+            if rest.len() < 5 {
+                return Err(invalid("truncated field header"));
+            }
+            let field_tag = rest[0];
+            let len = u32::from_le_bytes(unwrap!(<[u8; 4]>::try_from(&rest[1..5]))) as usize;
+            if rest.len() - 5 < len {
+                return Err(invalid("truncated field"));
+            }
+            let (value, after) = rest[5..].split_at(len);
+            rest = after;
+
+            if field_tag == tag {
+                self.0 = rest;
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Address written by `Frame::addr_fields`.
+    fn addr(&mut self) -> R<SocketAddr> {
+        let ip = self.get(1)?;
+        let ip = if let Ok(octets) = <[u8; 4]>::try_from(ip) {
+            IpAddr::V4(Ipv4Addr::from(octets))
+        } else if let Ok(octets) = <[u8; 16]>::try_from(ip) {
+            IpAddr::V6(Ipv6Addr::from(octets))
+        } else {
+            return Err(invalid("IP address is neither 4 nor 16 bytes long"));
+        };
+        let port =
+            <[u8; 2]>::try_from(self.get(2)?).map_err(|_| invalid("port is not 2 bytes long"))?;
+        Ok(SocketAddr::new(ip, u16::from_le_bytes(port)))
+    }
+
+    /// Topic written as field 1.
+    fn topic(&mut self) -> R<String> {
+        String::from_utf8(self.get(1)?.to_vec()).map_err(|_| invalid("topic is not UTF-8"))
+    }
+
+    /// Capabilities written by `Frame::capability_fields`, if any.
+    fn capabilities(&mut self) -> R<Capabilities> {
+        let flags = match self.opt(2)? {
+            Some(flags) => {
+                <[u8; 8]>::try_from(flags).map_err(|_| invalid("flags are not 8 bytes long"))?
+            }
+            None => return Ok(Default::default()),
+        };
+        let role =
+            String::from_utf8(self.get(3)?.to_vec()).map_err(|_| invalid("role is not UTF-8"))?;
+        Ok(Capabilities {
+            flags: u64::from_le_bytes(flags),
+            role,
+        })
+    }
+
+    /// Protocol version written by `Frame::protocol_version_field`.
+    fn protocol_version(&mut self) -> R<u16> {
+        match self.opt(4)? {
+            Some(version) => Ok(u16::from_le_bytes(
+                <[u8; 2]>::try_from(version)
+                    .map_err(|_| invalid("protocol version is not 2 bytes long"))?,
+            )),
+            None => Ok(UNADVERTISED_PROTOCOL_VERSION),
+        }
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::Tagged(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_follow_the_layout() {
+        let addr: SocketAddr = ([127, 0, 0, 1], 443).into();
+        let raw = [
+            1, 3, // version, message type
+            1, 4, 0, 0, 0, 127, 0, 0, 1, // field 1: IP
+            2, 2, 0, 0, 0, 0xbb, 0x01, // field 2: port
+        ];
+
+        assert_eq!(encode(&WireMsg::EndpointEchoResp(addr)), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::EndpointEchoResp(a) => assert_eq!(a, addr),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn connect_back_requests_carry_addr_and_cert() {
+        let node_info = NodeInfo {
+            peer_addr: ([10, 0, 0, 1], 5000).into(),
+            peer_cert_der: vec![0x30, 0x82],
+            alt_addrs: Vec::new(),
+        };
+        let raw = [
+            1, 5, // version, message type
+            1, 4, 0, 0, 0, 10, 0, 0, 1, // field 1: IP
+            2, 2, 0, 0, 0, 0x88, 0x13, // field 2: port
+            3, 2, 0, 0, 0, 0x30, 0x82, // field 3: certificate
+        ];
+
+        assert_eq!(encode(&WireMsg::ConnectBackReq(node_info.clone())), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::ConnectBackReq(n) => assert_eq!(n, node_info),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn handshakes_carry_capabilities_if_any() {
+        let raw = [
+            1, 1, // version, message type
+            2, 8, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, // field 2: flags
+            3, 2, 0, 0, 0, b'd', b'b', // field 3: role
+        ];
+        let capabilities = Capabilities {
+            flags: 5,
+            role: "db".to_string(),
+        };
+
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: capabilities.clone(),
+            protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(Handshake::Client {
+                capabilities: c, ..
+            }) => assert_eq!(c, capabilities),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: Default::default(),
+            protocol_version: UNADVERTISED_PROTOCOL_VERSION,
+        });
+        assert_eq!(encode(&handshake), [1, 1]);
+        match unwrap!(decode(&[1, 1])) {
+            WireMsg::Handshake(Handshake::Client { capabilities, .. }) => {
+                assert!(capabilities.is_empty())
+            }
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn handshakes_carry_later_protocol_versions() {
+        let raw = [
+            1, 1, // version, message type
+            4, 2, 0, 0, 0, 2, 0, // field 4: protocol version
+        ];
+
+        let handshake = WireMsg::Handshake(Handshake::Client {
+            capabilities: Default::default(),
+            protocol_version: 2,
+        });
+        assert_eq!(encode(&handshake), raw);
+        match unwrap!(decode(&raw)) {
+            WireMsg::Handshake(h) => assert_eq!(h.protocol_version(), 2),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+        match unwrap!(decode(&[1, 1])) {
+            WireMsg::Handshake(h) => {
+                assert_eq!(h.protocol_version(), UNADVERTISED_PROTOCOL_VERSION)
+            }
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let raw = [
+            2, 4, // a later version's user message
+            9, 1, 0, 0, 0, 0xff, // unknown field before
+            1, 3, 0, 0, 0, 1, 2, 3, // field 1: payload
+            7, 2, 0, 0, 0, 0xff, 0xff, // unknown field after
+        ];
+
+        match unwrap!(decode(&raw)) {
+            WireMsg::UserMsg(m) => assert_eq!(&m[..], &[1, 2, 3]),
+            x => panic!("Unexpected message: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn unknown_message_types_are_reported() {
+        match decode(&[2, 42, 1, 0, 0, 0, 0]) {
+            Err(Error::UnknownWireMsg(42)) => (),
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        // Too short
+        assert!(decode(&[1]).is_err());
+        // Missing payload
+        assert!(decode(&[1, 4]).is_err());
+        // Truncated payload
+        assert!(decode(&[1, 4, 1, 3, 0, 0, 0, 1]).is_err());
+    }
+}
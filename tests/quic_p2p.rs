@@ -1,3 +1,6 @@
+// The tests connect nodes to each other
+#![cfg(not(feature = "client-only"))]
+
 use crossbeam_channel as mpmc;
 use quic_p2p::{Builder, Config, Event, NodeInfo, OurType, Peer, QuicP2p};
 use std::collections::HashSet;
@@ -7,7 +10,7 @@ use unwrap::unwrap;
 /// Waits for `Event::ConnectedTo`.
 fn wait_till_connected(ev_rx: mpmc::Receiver<Event>) -> Peer {
     for event in ev_rx.iter() {
-        if let Event::ConnectedTo { peer } = event {
+        if let Event::ConnectedTo { peer, .. } = event {
             return peer;
         }
     }
@@ -33,7 +36,9 @@ fn test_peer_with_hcc(
             ..Default::default()
         })
         // Make sure we start with an empty cache. Otherwise, we might get into unexpected state.
-        .with_proxies(Default::default(), true);
+        .with_proxies(Default::default(), true)
+        // Nodes running side by side mustn't share an identity
+        .with_persistent_identity(false);
     (unwrap!(builder.build()), ev_rx)
 }
 